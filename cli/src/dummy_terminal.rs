@@ -1,4 +1,3 @@
-use std::str;
 use std::io::{stdout, Write};
 
 use riscv_emu_rust::terminal::Terminal;
@@ -17,22 +16,25 @@ impl DummyTerminal {
 	
 impl Terminal for DummyTerminal {
 	fn put_byte(&mut self, value: u8) {
-		let str = vec![value];
-		match str::from_utf8(&str) {
-			Ok(s) => {
-				print!("{}", s);
-			},
-			Err(_e) => {}
-		};
-		match stdout().flush() {
-			_ => {} // Ignoring error so far
-		};
+		// The host terminal reassembles multibyte characters
+		self.put_bytes(&[value]);
 	}
 	
+	fn put_bytes(&mut self, values: &[u8]) {
+		// Flushes once for the bulk
+		let mut out = stdout();
+		// Ignoring error so far
+		let _ = out.write_all(values).and_then(|_| out.flush());
+	}
+
 	fn get_input(&mut self) -> u8 {
 		0
 	}
 
+	fn has_input(&mut self) -> bool {
+		false
+	}
+
 	// Wasm specific methods. No use.
 
 	fn put_input(&mut self, _value: u8) {
@@ -1,36 +1,282 @@
 extern crate getopts;
+extern crate log;
 extern crate riscv_emu_rust;
 
 mod popup_terminal;
 mod dummy_terminal;
+mod tcp_terminal;
+mod websocket_terminal;
+mod stderr_logger;
 
-use riscv_emu_rust::Emulator;
+use riscv_emu_rust::{Emulator, StopReason};
 use riscv_emu_rust::cpu::Xlen;
-use riscv_emu_rust::terminal::Terminal;
+use riscv_emu_rust::instruction_trace::{InstructionTrace, TraceFormat};
+use riscv_emu_rust::instruction_history::InstructionHistory;
+use riscv_emu_rust::call_trace::CallTrace;
+use riscv_emu_rust::syscall_trace::SyscallTrace;
+use riscv_emu_rust::coverage::{Coverage, CoverageModule};
+use riscv_emu_rust::trap_log::TrapLog;
+use riscv_emu_rust::profiler::Profiler;
+use riscv_emu_rust::speed_meter::SpeedMeter;
+use riscv_emu_rust::protection_overhead::{CostModel, ProtectionOverhead};
+use riscv_emu_rust::cache_stats::CacheStats;
+use riscv_emu_rust::config::{EmulatorConfig, MachineType, get_console_type, get_interrupt_controller_type, get_machine_type, get_timer_source, get_virtio_transport};
+use riscv_emu_rust::terminal::{self, Terminal};
+use riscv_emu_rust::logging_terminal::LoggingTerminal;
+use riscv_emu_rust::throttled_terminal::ThrottledTerminal;
+use riscv_emu_rust::terminal_mux::TerminalMux;
+use riscv_emu_rust::device::uart::MAX_SERIAL_PORT_NUM;
+use riscv_emu_rust::replay_terminal::ReplayTerminal;
+use riscv_emu_rust::scripted_terminal::{ScriptedTerminal, ScriptResult, parse_script};
+use riscv_emu_rust::batch_runner::{BatchOutcome, BatchRunner};
+use riscv_emu_rust::bench::{Benchmark, BenchmarkKind};
+use riscv_emu_rust::control::ControlServer;
+use riscv_emu_rust::net_backend::NetBackend;
+use riscv_emu_rust::block_backend::{BlockBackend, FileBlockBackend, OverlayBlockBackend, SECTOR_SIZE};
+use riscv_emu_rust::qcow2_block_backend::{Qcow2BlockBackend, open_disk_image};
+use riscv_emu_rust::user_net_backend::UserNetBackend;
+use riscv_emu_rust::tap_net_backend::TapNetBackend;
+use riscv_emu_rust::pty_terminal::PtyTerminal;
+use riscv_emu_rust::raw_terminal::{RawTerminal, restore_terminal};
+use riscv_emu_rust::gdb_stub::GdbStub;
 use popup_terminal::PopupTerminal;
 use dummy_terminal::DummyTerminal;
+use tcp_terminal::TcpTerminal;
+use websocket_terminal::WebSocketTerminal;
+use stderr_logger::StderrLogger;
 
+use std::cell::Cell;
 use std::env;
-use std::fs::File;
-use std::io::Read;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::time::Duration;
 
 use getopts::Options;
 
+// Times an instruction runs in a row until the guest is regarded as stalled
+const STALL_THRESHOLD: u64 = 1000000;
+
 enum TerminalType {
-	PopupTerminal,
-	DummyTerminal
+	Popup,
+	Dummy,
+	Null,
+	Tcp(String),
+	WebSocket(String),
+	Pty,
+	Raw
+}
+
+// Where and how the coverage is written on exit
+struct CoverageOutput {
+	writer: BufWriter<File>,
+	json: bool,
+	modules: Vec<CoverageModule>
+}
+
+// Where and how the cache statistics are written on exit
+struct CacheStatsOutput {
+	writer: BufWriter<File>,
+	json: bool
+}
+
+// What is written on exit
+struct ExitOutput {
+	coverage: Option<CoverageOutput>,
+	// Folded stacks of the profiler
+	profile: Option<BufWriter<File>>,
+	cache_stats: Option<CacheStatsOutput>,
+	// Number of the hottest basic blocks printed
+	hot_blocks: usize
+}
+
+// Disk image given with --drive
+struct Drive {
+	path: PathBuf,
+	// Detected from the content if not given
+	format: Option<String>,
+	// Whether the changes are discarded on exit
+	snapshot: bool
 }
 
 fn print_usage(program: &str, opts: Options) {
-	let usage = format!("Usage: {} program_file [options]", program);
+	let usage = format!("Usage: {} [program_file] [options]", program);
 	print!("{}", opts.usage(&usage));
 }
 
-fn get_terminal(terminal_type: TerminalType) -> Box<dyn Terminal> {
-	match terminal_type {
-		TerminalType::PopupTerminal => Box::new(PopupTerminal::new()),
-		TerminalType::DummyTerminal => Box::new(DummyTerminal::new()),
+// Parses QEMU style drive options like file=fs.img,format=raw,snapshot=on.
+// A value without a key is the file
+fn parse_drive(value: &str) -> Option<Drive> {
+	let mut drive = Drive {
+		path: PathBuf::new(),
+		format: None,
+		snapshot: false
+	};
+	for option in value.split(',') {
+		match option.split_once('=') {
+			Some(("file", path)) => drive.path = PathBuf::from(path),
+			Some(("format", format)) => match format {
+				"raw" | "qcow2" => drive.format = Some(format.to_string()),
+				_ => return None
+			},
+			Some(("snapshot", snapshot)) => match snapshot {
+				"on" => drive.snapshot = true,
+				"off" => drive.snapshot = false,
+				_ => return None
+			},
+			// The block device is the only one disk images can be attached to
+			Some(("if", "virtio")) => {},
+			Some(_) => return None,
+			None => drive.path = PathBuf::from(option)
+		};
+	}
+	match drive.path.as_os_str().is_empty() {
+		true => None,
+		false => Some(drive)
+	}
+}
+
+fn open_drive(drive: &Drive) -> io::Result<Box<dyn BlockBackend>> {
+	let writable = !drive.snapshot;
+	let backend: Box<dyn BlockBackend> = match drive.format.as_deref() {
+		Some("qcow2") => Box::new(Qcow2BlockBackend::open(&drive.path, writable)?),
+		Some(_) => Box::new(FileBlockBackend::new(OpenOptions::new().read(true).write(writable).open(&drive.path)?)?),
+		None => open_disk_image(&drive.path, writable)?
+	};
+	Ok(match drive.snapshot {
+		true => Box::new(OverlayBlockBackend::new(backend)),
+		false => backend
+	})
+}
+
+fn parse_milliseconds(value: Option<String>, default: u64) -> Option<Duration> {
+	match value {
+		Some(value) => value.parse::<u64>().ok().map(Duration::from_millis),
+		None => Some(Duration::from_millis(default))
+	}
+}
+
+// Parses a hexadecimal address range like 80000000-80001000
+fn parse_address_range(value: &str) -> Option<(u64, u64)> {
+	let (start, end) = value.split_once('-')?;
+	let parse = |value: &str| u64::from_str_radix(value.trim_start_matches("0x"), 16).ok();
+	Some((parse(start)?, parse(end)?))
+}
+
+// Flushes what the emulator writes to files and writes the coverage, the
+// profile, and the reports. Call this before exit, which doesn't drop
+// the emulator.
+fn finish(emulator: &mut Emulator, exit_output: &mut ExitOutput) {
+	if let Some(trace) = emulator.get_mut_instruction_trace() {
+		trace.stop();
+	}
+	if let Some(trace) = emulator.get_mut_call_trace() {
+		trace.stop();
+	}
+	if let Some(trace) = emulator.get_mut_syscall_trace() {
+		trace.stop();
+	}
+	if let (Some(coverage), Some(output)) = (emulator.get_mut_coverage(), exit_output.coverage.as_mut()) {
+		let result = match output.json {
+			true => coverage.write_json(&mut output.writer),
+			false => coverage.write_drcov(&mut output.writer, &output.modules)
+		};
+		if result.is_err() {
+			println!("Failed to write coverage");
+		}
+	}
+	if let (Some(profiler), Some(writer)) = (emulator.get_mut_profiler(), exit_output.profile.as_mut()) {
+		if profiler.write_folded(writer).is_err() {
+			println!("Failed to write profile");
+		}
+	}
+	if let (Some(cache_stats), Some(output)) = (emulator.take_cache_stats(), exit_output.cache_stats.as_mut()) {
+		let result = match output.json {
+			true => cache_stats.write_json(&mut output.writer),
+			false => cache_stats.write_csv(&mut output.writer)
+		};
+		if result.is_err() {
+			println!("Failed to write cache statistics");
+		}
+	}
+	restore_terminal();
+	if let Some(trap_log) = emulator.get_mut_trap_log() {
+		println!("Traps:");
+		for count in trap_log.get_counts() {
+			let kind = match count.interrupt {
+				true => "interrupt",
+				false => "exception"
+			};
+			println!("  {} ({} {}): {}", count.name, kind, count.code, count.count);
+		}
+	}
+	if let Some(speed_meter) = emulator.get_mut_speed_meter() {
+		let speed = speed_meter.get_speed();
+		println!("Speed: {} instructions in {:.3} seconds, {:.2} MIPS",
+			speed.instructions, speed.elapsed.as_secs_f64(), speed.average_mips);
+	}
+	if let Some(protection_overhead) = emulator.get_protection_overhead() {
+		println!("Protection overhead:\n{}", protection_overhead.get_report());
+	}
+	if exit_output.hot_blocks > 0 {
+		println!("Hot blocks:");
+		for block in emulator.get_hot_blocks(exit_output.hot_blocks) {
+			println!("  0x{:x} ({} bytes) executed {} times", block.start, block.size, block.executions);
+			for (address, disassembly) in block.instructions.iter() {
+				println!("    0x{:x} {}", address, disassembly);
+			}
+		}
+	}
+}
+
+// Prints the last instructions kept by the instruction history if set
+fn print_instruction_history(emulator: &mut Emulator) {
+	if let Some(history) = emulator.get_mut_instruction_history() {
+		println!("Last instructions:");
+		if history.write(&mut io::stdout()).is_err() {
+			println!("Failed to print instruction history");
+		}
+	}
+}
+
+// Finishes and exits with the message and the instruction history when
+// the guest can't go on
+fn exit_with_error(emulator: &mut Emulator, exit_output: &mut ExitOutput, message: &str) -> ! {
+	finish(emulator, exit_output);
+	println!("\n{}", message);
+	print_instruction_history(emulator);
+	std::process::exit(1);
+}
+
+// Becomes true when the user requests to quit on the terminal
+type QuitRequest = Rc<Cell<bool>>;
+
+/// Returns the terminal and the handle of the quit request from the user
+/// if the terminal supports it.
+fn get_terminal(terminal_type: TerminalType) -> std::io::Result<(Box<dyn Terminal>, Option<QuitRequest>)> {
+	if let TerminalType::Raw = terminal_type {
+		let terminal = RawTerminal::new()?;
+		let quit_request = terminal.get_quit_request();
+		return Ok((Box::new(terminal), Some(quit_request)));
 	}
+	let terminal: Box<dyn Terminal> = match terminal_type {
+		TerminalType::Popup => Box::new(PopupTerminal::new()),
+		TerminalType::Dummy => Box::new(DummyTerminal::new()),
+		TerminalType::Null => Box::new(terminal::DummyTerminal::new()),
+		TerminalType::Tcp(address) => Box::new(TcpTerminal::new(&address)?),
+		TerminalType::WebSocket(address) => Box::new(WebSocketTerminal::new(&address)?),
+		TerminalType::Pty => {
+			let terminal = PtyTerminal::new()?;
+			println!("Console is on {}. Attach with screen or minicom.", terminal.get_path());
+			Box::new(terminal)
+		},
+		TerminalType::Raw => unreachable!()
+	};
+	Ok((terminal, None))
 }
 
 
@@ -40,11 +286,68 @@ fn main () -> std::io::Result<()> {
 
 	let mut opts = Options::new();
 	opts.optopt("x", "xlen", "Set bit mode. Default is auto detect from elf file", "32|64");
-	opts.optopt("f", "fs", "File system image file", "xv6/fs.img");
+	opts.optopt("", "config", "Machine configuration file in TOML describing the memory, the ISA, the devices, and the images. Command line options override it", "machine.toml");
+	opts.optopt("", "machine", "Machine model deciding the address map, the devices, and the device tree. Default is virt. spike implies --htif", "virt|spike|sifive_u");
+	opts.optopt("", "kernel", "Program file to run, instead of the first argument", "linux/image");
+	opts.optopt("", "initrd", "Load the file as initial ramdisk at the top of memory and tell the kernel where it is in the device tree", "rootfs.cpio");
+	opts.optopt("f", "fs", "File system image file. Raw or qcow2", "xv6/fs.img");
+	opts.optopt("", "drive", "File system image like -drive of QEMU. Changes are written back to the file unless snapshot=on. Can't be used with -f", "file=fs.img[,format=raw|qcow2][,snapshot=on|off]");
+	opts.optflag("w", "fs_write", "Write file system changes back to the image file. By default changes are discarded on exit");
+	opts.optopt("", "fs_overlay", "Keep file system changes in a qcow2 overlay file on top of the image file. The overlay is created if not exists", "overlay.qcow2");
 	opts.optopt("d", "dtb", "Device tree file", "linux/dtb");
+	opts.optmulti("o", "overlay", "Device tree overlay file. Can be specified multiple times", "custom.dtbo");
+	opts.optopt("c", "console", "Console UART model. Default is ns16550a", "ns16550a|sifive");
+	opts.optopt("", "irqchip", "Interrupt controller. Default is plic", "plic|aia");
+	opts.optflag("", "aclint", "Describe CLINT as ACLINT devices and add SSWI device");
+	opts.optopt("", "timer", "How the timer advances. Default is cycles, one tick per instruction. cycles:1/8 ticks once per eight instructions, making the timer interrupts less frequent. host follows the host clock so the guest time is stable regardless of the emulation speed", "cycles|cycles:<ticks>/<cycles>|host");
+	opts.optopt("", "timebase", "Timer ticks per second told to the guest in the device tree. Default is 10000000", "10000000");
+	opts.optopt("", "virtio", "Virtio MMIO transport of all virtio devices. Default is legacy", "legacy|modern");
+	opts.optopt("", "balloon", "Add virtio memory balloon device and ask the guest to give up the size of memory in MiB", "0");
+	opts.optopt("m", "memory", "Main memory size in MiB. Default is 128", "128");
+	opts.optopt("", "net", "Connect virtio network device to user-mode NAT or host TAP device", "user|tap:tap0");
 	opts.optflag("n", "no_terminal", "No popup terminal");
+	opts.optopt("", "serial_ports", "Add NS16550A serial ports besides the console, up to 4. Their output is interleaved on the terminal and Ctrl-a followed by the port number switches the input", "1");
+	opts.optopt("", "baud", "Limit the console output rate to the baud rate of a serial line", "115200");
+	opts.optopt("", "replay", "Type the content of the file to the console", "input.txt");
+	opts.optopt("", "replay_delay", "Milliseconds to wait before replaying the input. Default is 0", "3000");
+	opts.optopt("", "replay_interval", "Milliseconds between replayed input bytes. Default is 10", "10");
+	opts.optflag("", "virtio_console", "Add virtio console device, an hvc device in Linux, which tells the guest the terminal window size. It shares the terminal like serial ports");
+	opts.optopt("", "script", "Drive the console with an expect-style script and exit with the result", "boot.script");
+	opts.optflag("", "batch", "Run headless as a test executor. The console output goes to the standard output and the exit code tells the outcome: the status code the program exits or the machine powers off with, 0 for a passed script, 1 for a failed script or an error, 2 for a panic, and 124 for the timeout");
+	opts.optopt("", "timeout", "Give up after the seconds of wall-clock time. Implies --batch", "600");
+	opts.optmulti("", "panic", "Regular expression of the console lines ending the run as a panic. Can be specified multiple times. Implies --batch", "Kernel panic");
+	opts.optopt("", "console_log", "Log console input and output with timestamps to the file", "console.log");
+	opts.optopt("", "serial", "Connect the console to a TCP port, a WebSocket port, a pseudo-terminal or the standard input and output in raw mode instead of popup terminal. null discards the output for benchmarking", "tcp:127.0.0.1:4321|ws:127.0.0.1:8080|pty|stdio|null");
+	opts.optopt("", "system_map", "Kernel symbols for the traces, the profile, and the trap log, from System.map or /proc/kallsyms", "System.map");
+	opts.optopt("", "trace", "Write a record per instruction with PC, raw bits, and disassembly to the file", "trace.log");
+	opts.optmulti("", "trace_range", "Trace only the instructions in the hexadecimal PC range, end exclusive. Can be specified multiple times", "80000000-80001000");
+	opts.optflag("", "trace_registers", "Record the registers each traced instruction writes");
+	opts.optopt("", "trace_format", "Trace format. qemu writes logs like -d in_asm,exec of QEMU chunked per translation block", "default|qemu|qemu_in_asm|qemu_exec");
+	opts.optopt("", "call_trace", "Write the function calls and returns indented by the call depth to the file, with the names in the symbols of the program", "calls.log");
+	opts.optopt("", "syscall_trace", "Write the system calls Linux user programs make with the arguments and return values to the file like strace", "syscalls.log");
+	opts.optopt("", "syscall_filter", "Trace only the system calls of the comma separated names", "openat,execve");
+	opts.optopt("", "coverage", "Write the basic blocks executed to the file on exit", "coverage.drcov");
+	opts.optopt("", "coverage_format", "Coverage format. drcov is for coverage viewers like Lighthouse, json lists the addresses", "drcov|json");
+	opts.optflag("", "coverage_edges", "Record the edges between the basic blocks too. Written only in json");
+	opts.optopt("", "profile", "Sample the guest PC and write the samples by function in folded stack format for flamegraph tools on exit", "profile.folded");
+	opts.optopt("", "profile_interval", "Instructions between profile samples. Default is 1000", "1000");
+	opts.optopt("", "profile_stack_depth", "Callers recorded per profile sample by walking the frame pointers. Default is 0", "8");
+	opts.optopt("", "cache_stats", "Sample the decode cache and address translation page cache counters periodically and write the samples to the file on exit", "cache.csv");
+	opts.optopt("", "cache_stats_interval", "Instructions per cache statistics sample. Default is 1000000", "1000000");
+	opts.optopt("", "cache_stats_format", "Cache statistics format. Default is csv", "csv|json");
+	opts.optopt("", "hot_blocks", "Print the most executed basic blocks with the disassembly on exit", "10");
+	opts.optflag("", "trap_stats", "Print the number of traps taken by cause on exit");
+	opts.optflag("", "speed", "Print the emulation speed in MIPS on exit");
+	opts.optflag("", "overhead", "Print the instructions and the cycles the return address protection instructions add on exit, with the MAC latency of the configured algorithm");
+	opts.optopt("", "bench", "Run the benchmark program headless through HTIF and print its score with the emulation speed. --timeout applies", "dhrystone|coremark|embench");
+	opts.optopt("", "history", "Keep the last instructions run and print them if the emulator panics or the guest stalls at an instruction, e.g. in a trap loop", "100");
+	opts.optopt("", "gdb_port", "Wait for GDB to connect to the port on localhost and run the program under its control", "1234");
+	opts.optopt("", "control", "Serve the JSON control protocol like QMP on a TCP port or a Unix socket to pause, resume, snapshot, type to, read memory of, and hotplug a disk to the running emulator", "tcp:127.0.0.1:4444|unix:emu.sock");
+	opts.optopt("", "log", "Write the emulator diagnostics at the levels to the standard error, per target cpu, mmu, plic, virtio, and emulator. Default is warn", "info|warn,virtio=debug,cpu=trace");
 	opts.optflag("h", "help", "Show this help menu");
 	opts.optflag("p", "page_cache", "Enable experimental page cache optimization");
+	opts.optflag("", "htif", "Proxy system calls of riscv-pk and HTIF programs to the host. Arguments after -- are passed to the program");
+	opts.optflag("", "user", "Run a static Linux program in user mode without kernel. Arguments after -- are passed to the program");
 
 	let matches = match opts.parse(&args[1..]) {
 		Ok(m) => m,
@@ -67,18 +370,84 @@ fn main () -> std::io::Result<()> {
 		return Ok(());
 	}
 
-	let fs_contents = match matches.opt_str("f") {
-		Some(path) => {
-			let mut file = File::open(path)?;
-			let mut contents = vec![];
-			file.read_to_end(&mut contents)?;
-			contents
+	let logger = match StderrLogger::new(&matches.opt_str("log").unwrap_or_default()) {
+		Ok(logger) => logger,
+		Err(()) => {
+			println!("Invalid log levels {}", matches.opt_str("log").unwrap_or_default());
+			return Ok(());
+		}
+	};
+	log::set_max_level(logger.get_max_level());
+	let _ = log::set_boxed_logger(Box::new(logger));
+
+	let mut config = match matches.opt_str("config") {
+		Some(path) => match EmulatorConfig::from_file(&path) {
+			Ok(config) => config,
+			Err(error) => {
+				println!("Failed to load {}: {}", path, error);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		},
+		None => EmulatorConfig::default()
+	};
+	if let Some(name) = matches.opt_str("machine") {
+		match get_machine_type(&name) {
+			Some(machine) => {
+				let preset = EmulatorConfig::new_with_machine(machine);
+				config.machine = preset.machine;
+				config.console = preset.console;
+			},
+			None => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+
+	let fs_backend: Option<Box<dyn BlockBackend>> = match (matches.opt_str("f"), matches.opt_str("drive")) {
+		(Some(_), Some(_)) => {
+			println!("--drive can't be used with -f");
+			print_usage(&program, opts);
+			// @TODO: throw error?
+			return Ok(());
+		},
+		(None, Some(drive)) => match parse_drive(&drive) {
+			Some(drive) => Some(open_drive(&drive)?),
+			None => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		},
+		(Some(path), None) => match (matches.opt_str("fs_overlay"), matches.opt_present("w")) {
+			(Some(overlay_path), _) => match fs::metadata(&overlay_path).is_ok() {
+				true => Some(Box::new(Qcow2BlockBackend::open(&overlay_path, true)?)),
+				false => {
+					let base = open_disk_image(&path, false)?;
+					let base_path = fs::canonicalize(&path)?;
+					let file = OpenOptions::new().read(true).write(true).create_new(true).open(&overlay_path)?;
+					let mut overlay = Qcow2BlockBackend::create(file, base.get_sector_num() * SECTOR_SIZE, base_path.to_str())?;
+					overlay.set_backing(base);
+					Some(Box::new(overlay))
+				}
+			},
+			(None, true) => Some(open_disk_image(path, true)?),
+			(None, false) => Some(Box::new(OverlayBlockBackend::new(open_disk_image(path, false)?)))
+		},
+		(None, None) => match &config.images.disk {
+			Some(path) => Some(open_drive(&Drive {
+				path: path.clone(),
+				format: None,
+				snapshot: config.images.disk_snapshot
+			})?),
+			None => None
 		}
-		None => vec![]
 	};
 
 	let mut has_dtb = false;
-	let dtb_contents = match matches.opt_str("d") {
+	let dtb_contents = match matches.opt_str("d").map(PathBuf::from).or_else(|| config.images.dtb.clone()) {
 		Some(path) => {
 			has_dtb = true;
 			let mut file = File::open(path)?;
@@ -89,31 +458,326 @@ fn main () -> std::io::Result<()> {
 		None => vec![]
 	};
 
-	let elf_filename = args[1].clone();
-	let mut elf_file = File::open(elf_filename)?;
+	// Arguments passed to the program with --htif and --user, starting with
+	// the program file
+	let kernel = matches.opt_str("kernel")
+		.or_else(|| config.images.kernel.as_ref().filter(|_| matches.free.is_empty()).map(|path| path.to_string_lossy().to_string()));
+	let (elf_filename, program_args) = match kernel {
+		Some(path) => {
+			let mut program_args = vec![path.clone()];
+			program_args.extend(matches.free.iter().cloned());
+			(path, program_args)
+		},
+		None => match matches.free.first() {
+			Some(path) => (path.clone(), matches.free.clone()),
+			None => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		}
+	};
+	let mut elf_file = File::open(&elf_filename)?;
 	let mut elf_contents = vec![];
 	elf_file.read_to_end(&mut elf_contents)?;
 
-	let terminal_type = match matches.opt_present("n") {
-		true => {
-			println!("No popup terminal mode. Output will be flushed on your terminal but you can not input.");
-			TerminalType::DummyTerminal
+	let mut benchmark = match matches.opt_str("bench") {
+		Some(name) => match BenchmarkKind::from_name(&name) {
+			Ok(kind) => Some(Benchmark::new(kind)),
+			Err(error) => {
+				println!("{}", error);
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		},
+		None => None
+	};
+	let batch = benchmark.is_none() && (matches.opt_present("batch") || matches.opt_present("timeout") || matches.opt_present("panic"));
+	let terminal_type = match (matches.opt_str("serial"), matches.opt_present("n") || batch || benchmark.is_some()) {
+		(Some(serial), _) => match serial.as_str() {
+			"pty" => TerminalType::Pty,
+			"stdio" => TerminalType::Raw,
+			"null" => TerminalType::Null,
+			_ if serial.starts_with("tcp:") => {
+				println!("Console is served on {}. Attach with telnet or nc.", &serial[4..]);
+				TerminalType::Tcp(serial[4..].to_string())
+			},
+			_ if serial.starts_with("ws:") => {
+				println!("Console is served on ws://{}/. Attach with a WebSocket client such as xterm.js.", &serial[3..]);
+				TerminalType::WebSocket(serial[3..].to_string())
+			},
+			_ => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		},
+		(None, true) => {
+			if !batch && benchmark.is_none() {
+				println!("No popup terminal mode. Output will be flushed on your terminal but you can not input.");
+			}
+			TerminalType::Dummy
 		},
-		false => TerminalType::PopupTerminal
+		(None, false) => TerminalType::Popup
 	};
 
-	let mut emulator = Emulator::new(get_terminal(terminal_type));
-	emulator.setup_program(elf_contents);
+	if let Some(name) = matches.opt_str("c") {
+		match get_console_type(&name) {
+			Some(console) => config.console = console,
+			None => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+	if let Some(name) = matches.opt_str("irqchip") {
+		match get_interrupt_controller_type(&name) {
+			Some(interrupt_controller) => config.interrupt_controller = interrupt_controller,
+			None => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+	if matches.opt_present("aclint") {
+		config.aclint = true;
+	}
+	if let Some(name) = matches.opt_str("timer") {
+		match get_timer_source(&name) {
+			Some(source) => config.timer.source = source,
+			None => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+	if let Some(frequency) = matches.opt_str("timebase") {
+		match frequency.parse::<u32>() {
+			Ok(frequency) if frequency > 0 => config.timer.frequency = frequency,
+			_ => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+	if matches.opt_present("virtio_console") {
+		config.virtio_console = true;
+	}
+	if let Some(name) = matches.opt_str("virtio") {
+		match get_virtio_transport(&name) {
+			Some(transport) => {
+				config.virtio_block_transport = transport.clone();
+				config.virtio_net_transport = transport.clone();
+				config.virtio_snd_transport = transport.clone();
+				config.virtio_balloon_transport = transport.clone();
+				config.virtio_console_transport = transport;
+			},
+			None => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+	if let Some(num) = matches.opt_str("serial_ports") {
+		match num.parse::<usize>() {
+			Ok(num) if num <= MAX_SERIAL_PORT_NUM => config.serial_port_num = num,
+			_ => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+	if let Some(size) = matches.opt_str("m") {
+		match size.parse::<u64>() {
+			Ok(size) => config.memory_capacity = size * 1024 * 1024,
+			Err(_) => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+	let balloon_size = match matches.opt_str("balloon") {
+		Some(size) => match size.parse::<u64>() {
+			Ok(size) => {
+				config.balloon = true;
+				size * 1024 * 1024
+			},
+			Err(_) => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		},
+		None => 0
+	};
+
+	let (mut terminal, quit_request) = get_terminal(terminal_type)?;
+	if let Some(baud) = matches.opt_str("baud") {
+		match baud.parse::<u64>() {
+			// 10 bits per byte with start and stop bits
+			Ok(baud) => terminal = Box::new(ThrottledTerminal::new(terminal, baud / 10)),
+			Err(_) => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+	if let Some(path) = matches.opt_str("replay") {
+		let delay = parse_milliseconds(matches.opt_str("replay_delay"), 0);
+		let interval = parse_milliseconds(matches.opt_str("replay_interval"), 10);
+		match (delay, interval) {
+			(Some(delay), Some(interval)) => {
+				terminal = Box::new(ReplayTerminal::new(terminal, fs::read(path)?, delay, interval));
+			},
+			_ => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+	let mut script_result = None;
+	if let Some(path) = matches.opt_str("script") {
+		match parse_script(&fs::read_to_string(&path)?) {
+			Ok(steps) => {
+				let scripted_terminal = ScriptedTerminal::new(terminal, steps);
+				script_result = Some(scripted_terminal.get_result());
+				terminal = Box::new(scripted_terminal);
+			},
+			Err(line) => {
+				println!("Invalid script {} at line {}", path, line);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+	let mut batch_runner = match batch {
+		true => Some(BatchRunner::new()),
+		false => None
+	};
+	if let Some(batch_runner) = &mut batch_runner {
+		if let Some(seconds) = matches.opt_str("timeout") {
+			match seconds.parse::<u64>() {
+				Ok(seconds) => batch_runner.set_timeout(Duration::from_secs(seconds)),
+				Err(_) => {
+					print_usage(&program, opts);
+					// @TODO: throw error?
+					return Ok(());
+				}
+			};
+		}
+		for pattern in matches.opt_strs("panic") {
+			if let Err(error) = batch_runner.add_panic_pattern(&pattern) {
+				println!("{}: {}", error, pattern);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		}
+		if let Some(script_result) = &script_result {
+			batch_runner.set_script_result(script_result.clone());
+		}
+		terminal = batch_runner.watch_terminal(terminal);
+	}
+	if let Some(benchmark) = &mut benchmark {
+		if let Some(seconds) = matches.opt_str("timeout") {
+			match seconds.parse::<u64>() {
+				Ok(seconds) => benchmark.set_timeout(Duration::from_secs(seconds)),
+				Err(_) => {
+					print_usage(&program, opts);
+					// @TODO: throw error?
+					return Ok(());
+				}
+			};
+		}
+		terminal = benchmark.watch_terminal(terminal);
+	}
+	if let Some(path) = matches.opt_str("console_log") {
+		terminal = Box::new(LoggingTerminal::new(terminal, Box::new(File::create(path)?)));
+	}
+	// Port 0 is the console, port n is ttySn, and the last one is the virtio console
+	let mut serial_port_terminals = vec![];
+	let mut virtio_console_terminal = None;
+	if config.serial_port_num > 0 || config.virtio_console {
+		let mut mux = TerminalMux::new(terminal);
+		terminal = mux.create_port("");
+		for i in 0..config.serial_port_num {
+			serial_port_terminals.push(mux.create_port(&format!("[ttyS{}] ", i + 1)));
+		}
+		if config.virtio_console {
+			virtio_console_terminal = Some(mux.create_port("[hvc] "));
+		}
+	}
+	let config_initrd = config.images.initrd.clone();
+	let config_machine = config.machine.clone();
+	let mut emulator = Emulator::new_with_config(terminal, config);
+	for (i, serial_port_terminal) in serial_port_terminals.into_iter().enumerate() {
+		let _ = emulator.set_serial_port_terminal(i, serial_port_terminal);
+	}
+	if let Some(virtio_console_terminal) = virtio_console_terminal {
+		emulator.set_virtio_console_terminal(virtio_console_terminal);
+	}
+	emulator.set_balloon_size(balloon_size);
+	// Spike talks to the host only through HTIF
+	if matches.opt_present("htif") || config_machine == MachineType::Spike || benchmark.is_some() {
+		emulator.setup_htif(program_args.clone());
+	}
+	// DrCov block offsets are relative to the program
+	let coverage_module = match matches.opt_present("coverage") {
+		true => CoverageModule::from_elf(&elf_filename, elf_contents.clone()),
+		false => None
+	};
+	let result = match matches.opt_present("user") {
+		true => emulator.setup_linux_user_program(elf_contents, program_args),
+		false => emulator.setup_program(elf_contents)
+	};
+	if let Err(error) = result {
+		match error.source() {
+			Some(source) => println!("Failed to load {}: {}: {}", elf_filename, error, source),
+			None => println!("Failed to load {}: {}", elf_filename, error)
+		};
+		// @TODO: throw error?
+		return Ok(());
+	}
+	if let Some(path) = matches.opt_str("initrd").map(PathBuf::from).or_else(|| config_initrd.clone()) {
+		let mut file = File::open(&path)?;
+		let mut contents = vec![];
+		file.read_to_end(&mut contents)?;
+		if let Err(error) = emulator.setup_initrd(contents) {
+			match error.source() {
+				Some(source) => println!("Failed to load initial ramdisk {}: {}", path.display(), source),
+				None => println!("Failed to load initial ramdisk {}", path.display())
+			};
+			// @TODO: throw error?
+			return Ok(());
+		}
+	}
 	
 	match matches.opt_str("x") {
 		Some(x) => match x.as_str() {
 			"32" => {
 				println!("Force to 32-bit mode.");
-				emulator.update_xlen(Xlen::Bit32);
+				if emulator.update_xlen(Xlen::Bit32).is_err() {
+					println!("Failed to update device tree for 32-bit mode");
+					// @TODO: throw error?
+					return Ok(());
+				}
 			},
 			"64" => {
 				println!("Force to 64-bit mode.");
-				emulator.update_xlen(Xlen::Bit64);
+				if emulator.update_xlen(Xlen::Bit64).is_err() {
+					println!("Failed to update device tree for 64-bit mode");
+					// @TODO: throw error?
+					return Ok(());
+				}
 			},
 			_ => {
 				print_usage(&program, opts);
@@ -124,13 +788,322 @@ fn main () -> std::io::Result<()> {
 		None => {}
 	};
 
-	emulator.setup_filesystem(fs_contents);
-	if has_dtb {
-		emulator.setup_dtb(dtb_contents);
+	if let Some(backend) = fs_backend {
+		emulator.setup_filesystem(backend);
+	}
+	if has_dtb && emulator.setup_dtb(dtb_contents).is_err() {
+		println!("Failed to apply initial ramdisk location to device tree");
+		// @TODO: throw error?
+		return Ok(());
+	}
+	for path in matches.opt_strs("o") {
+		let mut file = File::open(&path)?;
+		let mut contents = vec![];
+		file.read_to_end(&mut contents)?;
+		if emulator.add_dtb_overlay(contents).is_err() {
+			println!("Failed to apply device tree overlay {}", path);
+			// @TODO: throw error?
+			return Ok(());
+		}
+	}
+	if let Some(net) = matches.opt_str("net") {
+		let backend: Box<dyn NetBackend> = match net.as_str() {
+			"user" => Box::new(UserNetBackend::new()),
+			_ if net.starts_with("tap:") => Box::new(TapNetBackend::new(&net[4..])?),
+			_ => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+		if emulator.setup_network(backend).is_err() {
+			println!("Failed to add network device to device tree");
+			// @TODO: throw error?
+			return Ok(());
+		}
 	}
 	if matches.opt_present("p") {
 		emulator.enable_page_cache(true);
 	}
-	emulator.run();
+	if let Some(path) = matches.opt_str("system_map") {
+		if emulator.load_system_map(&fs::read_to_string(&path)?).is_err() {
+			println!("Failed to load kernel symbols from {}", path);
+			// @TODO: throw error?
+			return Ok(());
+		}
+	}
+	if let Some(path) = matches.opt_str("trace") {
+		let mut trace = InstructionTrace::new(Box::new(BufWriter::new(File::create(path)?)));
+		for range in matches.opt_strs("trace_range") {
+			match parse_address_range(&range) {
+				Some((start, end)) => trace.add_range(start, end),
+				None => {
+					print_usage(&program, opts);
+					// @TODO: throw error?
+					return Ok(());
+				}
+			};
+		}
+		trace.enable_register_writes(matches.opt_present("trace_registers"));
+		match matches.opt_str("trace_format").as_deref() {
+			None | Some("default") => {},
+			Some("qemu") => trace.set_format(TraceFormat::Qemu { in_asm: true, exec: true }),
+			Some("qemu_in_asm") => trace.set_format(TraceFormat::Qemu { in_asm: true, exec: false }),
+			Some("qemu_exec") => trace.set_format(TraceFormat::Qemu { in_asm: false, exec: true }),
+			Some(_) => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+		emulator.set_instruction_trace(trace);
+	}
+	if let Some(path) = matches.opt_str("call_trace") {
+		emulator.set_call_trace(CallTrace::new(Box::new(BufWriter::new(File::create(path)?))));
+	}
+	if let Some(path) = matches.opt_str("syscall_trace") {
+		let mut trace = SyscallTrace::new(Box::new(BufWriter::new(File::create(path)?)));
+		if let Some(names) = matches.opt_str("syscall_filter") {
+			if let Err(error) = trace.set_filter(&names.split(',').collect::<Vec<&str>>()) {
+				println!("{}", error);
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		}
+		emulator.set_syscall_trace(trace);
+	}
+	let coverage_output = match matches.opt_str("coverage") {
+		Some(path) => {
+			let json = match matches.opt_str("coverage_format").as_deref() {
+				None | Some("drcov") => false,
+				Some("json") => true,
+				Some(_) => {
+					print_usage(&program, opts);
+					// @TODO: throw error?
+					return Ok(());
+				}
+			};
+			emulator.set_coverage(Coverage::new(matches.opt_present("coverage_edges")));
+			Some(CoverageOutput {
+				writer: BufWriter::new(File::create(path)?),
+				json,
+				modules: coverage_module.into_iter().collect()
+			})
+		},
+		None => None
+	};
+	let profile_output = match matches.opt_str("profile") {
+		Some(path) => {
+			let interval = matches.opt_str("profile_interval").map(|interval| interval.parse::<u64>());
+			let depth = matches.opt_str("profile_stack_depth").map(|depth| depth.parse::<usize>());
+			let mut profiler = match interval {
+				None => Profiler::new(1000),
+				Some(Ok(interval)) if interval > 0 => Profiler::new(interval),
+				Some(_) => {
+					print_usage(&program, opts);
+					// @TODO: throw error?
+					return Ok(());
+				}
+			};
+			match depth {
+				None => {},
+				Some(Ok(depth)) => profiler.set_stack_depth(depth),
+				Some(Err(_)) => {
+					print_usage(&program, opts);
+					// @TODO: throw error?
+					return Ok(());
+				}
+			};
+			emulator.set_profiler(profiler);
+			Some(BufWriter::new(File::create(path)?))
+		},
+		None => None
+	};
+	let cache_stats_output = match matches.opt_str("cache_stats") {
+		Some(path) => {
+			let json = match matches.opt_str("cache_stats_format").as_deref() {
+				None | Some("csv") => false,
+				Some("json") => true,
+				Some(_) => {
+					print_usage(&program, opts);
+					// @TODO: throw error?
+					return Ok(());
+				}
+			};
+			let interval = match matches.opt_str("cache_stats_interval").map(|interval| interval.parse::<u64>()) {
+				None => 1000000,
+				Some(Ok(interval)) if interval > 0 => interval,
+				Some(_) => {
+					print_usage(&program, opts);
+					// @TODO: throw error?
+					return Ok(());
+				}
+			};
+			emulator.set_cache_stats(CacheStats::new(interval));
+			Some(CacheStatsOutput {
+				writer: BufWriter::new(File::create(path)?),
+				json
+			})
+		},
+		None => None
+	};
+	let hot_blocks = match matches.opt_str("hot_blocks") {
+		Some(num) => match num.parse::<usize>() {
+			Ok(num) => {
+				if emulator.get_mut_coverage().is_none() {
+					emulator.set_coverage(Coverage::new(false));
+				}
+				num
+			},
+			Err(_) => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		},
+		None => 0
+	};
+	let mut exit_output = ExitOutput {
+		coverage: coverage_output,
+		profile: profile_output,
+		cache_stats: cache_stats_output,
+		hot_blocks
+	};
+	if matches.opt_present("trap_stats") {
+		emulator.set_trap_log(TrapLog::new(0));
+	}
+	if matches.opt_present("speed") {
+		emulator.set_speed_meter(SpeedMeter::new());
+	}
+	if matches.opt_present("overhead") {
+		let cost_model = CostModel::new(emulator.get_cpu().get_zipper_mac().get_algorithm());
+		emulator.set_protection_overhead(ProtectionOverhead::new(cost_model));
+	}
+	if let Some(capacity) = matches.opt_str("history") {
+		match capacity.parse::<usize>() {
+			Ok(capacity) => {
+				let mut history = InstructionHistory::new(capacity);
+				history.set_stall_threshold(STALL_THRESHOLD);
+				emulator.set_instruction_history(history);
+			},
+			Err(_) => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+	}
+	if let Some(port) = matches.opt_str("gdb_port") {
+		let port = match port.parse::<u16>() {
+			Ok(port) => port,
+			Err(_) => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+		let listener = TcpListener::bind(("127.0.0.1", port))?;
+		println!("Waiting for GDB on 127.0.0.1:{}. Connect with target remote :{}", port, port);
+		let (stream, _) = listener.accept()?;
+		GdbStub::new(stream).serve(&mut emulator)?;
+		finish(&mut emulator, &mut exit_output);
+		if let Some(code) = emulator.get_exit_code() {
+			std::process::exit(code as i32);
+		}
+		return Ok(());
+	}
+	if let Some(benchmark) = benchmark {
+		let result = benchmark.run(&mut emulator);
+		finish(&mut emulator, &mut exit_output);
+		match result {
+			Ok(report) => println!("\n{}", report),
+			Err(error) => {
+				println!("\n{}", error);
+				std::process::exit(1);
+			}
+		};
+		return Ok(());
+	}
+	if let Some(control) = matches.opt_str("control") {
+		let control_server = match (control.strip_prefix("tcp:"), control.strip_prefix("unix:")) {
+			(Some(address), _) => ControlServer::bind_tcp(address),
+			(None, Some(path)) => ControlServer::bind_unix(path),
+			_ => {
+				print_usage(&program, opts);
+				// @TODO: throw error?
+				return Ok(());
+			}
+		};
+		let mut control_server = control_server?;
+		println!("Control protocol is served on {}.", control);
+		let reason = control_server.run(&mut emulator);
+		finish(&mut emulator, &mut exit_output);
+		if let Some(StopReason::Error(error)) = reason {
+			println!("Emulator error: {}", error);
+			std::process::exit(1);
+		}
+		if let Some(code) = emulator.get_exit_code() {
+			std::process::exit(code as i32);
+		}
+		return Ok(());
+	}
+	let history_enabled = emulator.get_mut_instruction_history().is_some();
+	let result = panic::catch_unwind(AssertUnwindSafe(|| match (batch_runner, script_result, quit_request, history_enabled) {
+		(Some(mut batch_runner), _, _, _) => {
+			let outcome = batch_runner.run(&mut emulator);
+			finish(&mut emulator, &mut exit_output);
+			println!("\n{}", outcome);
+			if let BatchOutcome::Stopped(_) = outcome {
+				print_instruction_history(&mut emulator);
+			}
+			std::process::exit(outcome.get_exit_code());
+		},
+		(None, None, None, false) => {
+			if let Err(error) = emulator.run() {
+				exit_with_error(&mut emulator, &mut exit_output, &format!("Emulator error: {}", error));
+			}
+		},
+		(None, script_result, quit_request, _) => loop {
+			// Checks the requests once in a while not to slow down
+			for _ in 0..0x10000 {
+				emulator.tick();
+			}
+			if quit_request.as_ref().is_some_and(|quit| quit.get()) {
+				break;
+			}
+			if let Some(address) = emulator.get_mut_instruction_history().and_then(|history| history.take_stall()) {
+				exit_with_error(&mut emulator, &mut exit_output, &format!("Stalled at 0x{:x}", address));
+			}
+			if let Some(error) = emulator.get_mut_cpu().take_error() {
+				exit_with_error(&mut emulator, &mut exit_output, &format!("Emulator error: {}", error));
+			}
+			let code = match script_result.as_ref().map(|result| result.borrow().clone()) {
+				Some(ScriptResult::Passed) => {
+					println!("\nScript passed");
+					0
+				},
+				Some(ScriptResult::Failed(message)) => {
+					println!("\nScript failed: {}", message);
+					1
+				},
+				_ => match emulator.get_exit_code() {
+					Some(code) => code as i32,
+					None => continue
+				}
+			};
+			finish(&mut emulator, &mut exit_output);
+			std::process::exit(code);
+		}
+	}));
+	if let Err(payload) = result {
+		restore_terminal();
+		print_instruction_history(&mut emulator);
+		panic::resume_unwind(payload);
+	}
+	finish(&mut emulator, &mut exit_output);
+	if let Some(code) = emulator.get_exit_code() {
+		std::process::exit(code as i32);
+	}
 	Ok(())
 }
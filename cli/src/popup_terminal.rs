@@ -1,13 +1,13 @@
 extern crate pancurses;
 
-use riscv_emu_rust::terminal::Terminal;
-use std::str;
+use riscv_emu_rust::terminal::{Terminal, Utf8Decoder};
 use self::pancurses::*;
 
 /// Popup `Terminal` used for desktop program.
 pub struct PopupTerminal {
 	window: Window,
-	in_escape_sequence: bool
+	in_escape_sequence: bool,
+	decoder: Utf8Decoder
 }
 
 impl PopupTerminal {
@@ -20,13 +20,12 @@ impl PopupTerminal {
 		curs_set(0);
 		PopupTerminal {
 			window: window,
-			in_escape_sequence: false
+			in_escape_sequence: false,
+			decoder: Utf8Decoder::new()
 		}
 	}
-}
-	
-impl Terminal for PopupTerminal {
-	fn put_byte(&mut self, value: u8) {
+
+	fn print_byte(&mut self, value: u8) {
 		// Cutting off escape sequence so far
 		// @TODO: Implement properly
 		if !self.in_escape_sequence {
@@ -40,8 +39,24 @@ impl Terminal for PopupTerminal {
 			}
 			return;
 		}
-		let str = vec![value];
-		self.window.printw(str::from_utf8(&str).unwrap());
+		let window = &self.window;
+		self.decoder.decode(&[value], |text| {
+			window.printw(text);
+		});
+	}
+}
+	
+impl Terminal for PopupTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.print_byte(value);
+		self.window.refresh();
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		// Refreshes once for the bulk
+		for value in values.iter() {
+			self.print_byte(*value);
+		}
 		self.window.refresh();
 	}
 	
@@ -0,0 +1,67 @@
+use std::io::{stderr, Write};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// `Log` writing the emulator diagnostics to the standard error, apart
+/// from the guest output on the standard output. The level is set per
+/// target, e.g. `cpu`, `mmu`, `plic`, and `virtio`.
+pub struct StderrLogger {
+	default_level: LevelFilter,
+	target_levels: Vec<(String, LevelFilter)>
+}
+
+impl StderrLogger {
+	/// Creates a new `StderrLogger` from the comma separated levels like
+	/// `RUST_LOG` of env_logger, e.g. `warn,virtio=debug,cpu=trace`.
+	/// An entry without target sets the level of the other targets.
+	/// Returns `Err` if the levels are invalid.
+	///
+	/// # Arguments
+	/// * `levels`
+	pub fn new(levels: &str) -> Result<Self, ()> {
+		let mut logger = StderrLogger {
+			default_level: LevelFilter::Warn,
+			target_levels: vec![]
+		};
+		for entry in levels.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+			match entry.find('=') {
+				Some(pos) => {
+					let level = entry[pos + 1..].parse::<LevelFilter>().map_err(|_| ())?;
+					logger.target_levels.push((entry[..pos].to_string(), level));
+				},
+				None => logger.default_level = entry.parse::<LevelFilter>().map_err(|_| ())?
+			};
+		}
+		Ok(logger)
+	}
+
+	/// Returns the most verbose level of the targets, to be passed to
+	/// `log::set_max_level()`.
+	pub fn get_max_level(&self) -> LevelFilter {
+		self.target_levels.iter().map(|(_, level)| *level).fold(self.default_level, |a, b| a.max(b))
+	}
+
+	fn get_level(&self, target: &str) -> LevelFilter {
+		match self.target_levels.iter().find(|(name, _)| name == target) {
+			Some((_, level)) => *level,
+			None => self.default_level
+		}
+	}
+}
+
+impl Log for StderrLogger {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		metadata.level() <= self.get_level(metadata.target())
+	}
+
+	fn log(&self, record: &Record) {
+		if self.enabled(record.metadata()) {
+			// The console may be in raw mode, so returns the carriage explicitly
+			let _ = write!(stderr(), "[{} {}] {}\r\n", record.level(), record.target(), record.args());
+		}
+	}
+
+	fn flush(&self) {
+		let _ = stderr().flush();
+	}
+}
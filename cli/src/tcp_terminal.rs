@@ -0,0 +1,234 @@
+use riscv_emu_rust::terminal::Terminal;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Output kept while the client can't receive. Older data is dropped beyond this.
+const OUTPUT_BUFFER_CAPACITY: usize = 0x10000;
+
+// Guest output is held while more than this is waiting for the client
+const OUTPUT_READY_THRESHOLD: usize = 0x1000;
+
+// Telnet commands. Refer to RFC 854 and RFC 857.
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const OPTION_ECHO: u8 = 1;
+const OPTION_SUPPRESS_GO_AHEAD: u8 = 3;
+
+// Where the parser is in the telnet stream
+enum TelnetState {
+	Data,
+	// After IAC
+	Command,
+	// After IAC WILL/WONT/DO/DONT
+	Option,
+	// In IAC SB ... IAC SE
+	Subnegotiation,
+	// After IAC in subnegotiation
+	SubnegotiationCommand,
+	// After CR. Telnet sends CR NUL or CR LF for return key
+	CarriageReturn
+}
+
+/// `Terminal` served on a TCP port like `-serial tcp` of QEMU. The guest
+/// console can be attached from another process with `telnet` or `nc`,
+/// detached, and attached again while the guest keeps running. One client
+/// is served at a time. Output while no client is attached is discarded.
+/// A client slow to receive holds back the guest output.
+pub struct TcpTerminal {
+	listener: TcpListener,
+	stream: Option<TcpStream>,
+	telnet_state: TelnetState,
+	input_data: VecDeque<u8>,
+	output_data: VecDeque<u8>
+}
+
+impl TcpTerminal {
+	/// Creates a new `TcpTerminal` listening on the address.
+	///
+	/// # Arguments
+	/// * `address` For example `127.0.0.1:4321`
+	pub fn new(address: &str) -> std::io::Result<Self> {
+		let listener = TcpListener::bind(address)?;
+		listener.set_nonblocking(true)?;
+		Ok(TcpTerminal {
+			listener,
+			stream: None,
+			telnet_state: TelnetState::Data,
+			input_data: VecDeque::new(),
+			output_data: VecDeque::new()
+		})
+	}
+
+	/// Accepts a client if none is attached. Asks telnet clients for
+	/// character mode without local echo because the guest echoes.
+	fn accept(&mut self) {
+		if self.stream.is_some() {
+			return;
+		}
+		if let Ok((stream, _)) = self.listener.accept() {
+			if stream.set_nonblocking(true).is_err() {
+				return;
+			}
+			let _ = stream.set_nodelay(true);
+			self.telnet_state = TelnetState::Data;
+			self.output_data.clear();
+			self.output_data.extend(&[IAC, WILL, OPTION_ECHO, IAC, WILL, OPTION_SUPPRESS_GO_AHEAD]);
+			self.stream = Some(stream);
+		}
+	}
+
+	fn disconnect(&mut self) {
+		self.stream = None;
+		self.output_data.clear();
+	}
+
+	/// Reads the data the client has sent so far without blocking.
+	fn receive(&mut self) {
+		let mut buffer = [0; 256];
+		loop {
+			let result = match self.stream.as_mut() {
+				Some(stream) => stream.read(&mut buffer),
+				None => return
+			};
+			match result {
+				Ok(0) => return self.disconnect(),
+				Ok(size) => {
+					for value in buffer[..size].iter() {
+						self.handle_telnet(*value);
+					}
+				},
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+				Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+				Err(_) => return self.disconnect()
+			};
+		}
+	}
+
+	/// Sends as much buffered output as the client can receive.
+	fn send(&mut self) {
+		while !self.output_data.is_empty() {
+			let result = match self.stream.as_mut() {
+				Some(stream) => stream.write(self.output_data.as_slices().0),
+				None => return self.output_data.clear()
+			};
+			match result {
+				Ok(0) => return self.disconnect(),
+				Ok(size) => {
+					self.output_data.drain(..size);
+				},
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+				Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+				Err(_) => return self.disconnect()
+			};
+		}
+	}
+
+	/// Strips telnet commands from the received data. Raw TCP clients
+	/// like `nc` rarely send the IAC byte so they are handled as well.
+	fn handle_telnet(&mut self, value: u8) {
+		self.telnet_state = match self.telnet_state {
+			TelnetState::Data | TelnetState::CarriageReturn => {
+				let after_cr = matches!(self.telnet_state, TelnetState::CarriageReturn);
+				match value {
+					IAC => TelnetState::Command,
+					0 | b'\n' if after_cr => TelnetState::Data,
+					b'\r' => {
+						self.input_data.push_back(value);
+						TelnetState::CarriageReturn
+					},
+					_ => {
+						self.input_data.push_back(value);
+						TelnetState::Data
+					}
+				}
+			},
+			TelnetState::Command => match value {
+				// Escaped 0xff data byte
+				IAC => {
+					self.input_data.push_back(value);
+					TelnetState::Data
+				},
+				WILL..=DONT => TelnetState::Option,
+				SB => TelnetState::Subnegotiation,
+				_ => TelnetState::Data
+			},
+			TelnetState::Option => TelnetState::Data,
+			TelnetState::Subnegotiation => match value {
+				IAC => TelnetState::SubnegotiationCommand,
+				_ => TelnetState::Subnegotiation
+			},
+			TelnetState::SubnegotiationCommand => match value {
+				SE => TelnetState::Data,
+				_ => TelnetState::Subnegotiation
+			}
+		};
+	}
+
+	fn poll(&mut self) {
+		self.accept();
+		self.receive();
+		self.send();
+	}
+}
+
+impl Terminal for TcpTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.put_bytes(&[value]);
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		if self.stream.is_none() {
+			return;
+		}
+		for value in values.iter() {
+			// 0xff must be escaped in telnet
+			if *value == IAC {
+				self.output_data.push_back(IAC);
+			}
+			self.output_data.push_back(*value);
+		}
+		while self.output_data.len() > OUTPUT_BUFFER_CAPACITY {
+			self.output_data.pop_front();
+		}
+		self.send();
+	}
+
+	fn get_input(&mut self) -> u8 {
+		self.poll();
+		self.input_data.pop_front().unwrap_or(0)
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.poll();
+		let size = buffer.len().min(self.input_data.len());
+		for (i, value) in self.input_data.drain(..size).enumerate() {
+			buffer[i] = value;
+		}
+		size
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.poll();
+		!self.input_data.is_empty()
+	}
+
+	/// Backpressures the guest while the client is slow to receive.
+	/// Always ready while no client is attached.
+	fn is_output_ready(&mut self) -> bool {
+		self.send();
+		self.output_data.len() < OUTPUT_READY_THRESHOLD
+	}
+
+	// Wasm specific methods. No use.
+
+	fn put_input(&mut self, _value: u8) {
+	}
+
+	fn get_output(&mut self) -> u8 {
+		0
+	}
+}
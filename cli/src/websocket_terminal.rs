@@ -0,0 +1,481 @@
+extern crate sha1_smol;
+
+use riscv_emu_rust::terminal::Terminal;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use self::sha1_smol::Sha1;
+
+// Output kept while the client can't receive. Newer output is dropped beyond this.
+const OUTPUT_BUFFER_CAPACITY: usize = 0x10000;
+
+// Guest output is held while more than this is waiting for the client
+const OUTPUT_READY_THRESHOLD: usize = 0x1000;
+
+// Clients sending a bigger handshake request or frame are disconnected
+const MAX_REQUEST_SIZE: usize = 0x2000;
+const MAX_PAYLOAD_SIZE: usize = 0x10000;
+
+// Refer to RFC 6455 for the protocol.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+const FIN_BIT: u8 = 0x80;
+const MASK_BIT: u8 = 0x80;
+
+// A frame parsed from the received data
+struct Frame {
+	opcode: u8,
+	payload: Vec<u8>
+}
+
+/// `Terminal` served as a WebSocket server for browser frontends such as
+/// xterm.js. A page attaches to the guest console with
+/// `new WebSocket("ws://127.0.0.1:8080/")`, and detaches and attaches again
+/// while the guest keeps running. One client is served at a time. The input
+/// is taken from both text and binary frames. The output is sent in binary
+/// frames because the guest output isn't always valid UTF-8. Output while
+/// no client is attached is discarded. A client slow to receive holds back
+/// the guest output. Plain `ws://` only, no TLS.
+pub struct WebSocketTerminal {
+	listener: TcpListener,
+	stream: Option<TcpStream>,
+	// Whether the handshake has been done with the client
+	open: bool,
+	received_data: Vec<u8>,
+	input_data: VecDeque<u8>,
+	output_data: VecDeque<u8>
+}
+
+impl WebSocketTerminal {
+	/// Creates a new `WebSocketTerminal` listening on the address.
+	///
+	/// # Arguments
+	/// * `address` For example `127.0.0.1:8080`
+	pub fn new(address: &str) -> std::io::Result<Self> {
+		let listener = TcpListener::bind(address)?;
+		listener.set_nonblocking(true)?;
+		Ok(WebSocketTerminal {
+			listener,
+			stream: None,
+			open: false,
+			received_data: vec![],
+			input_data: VecDeque::new(),
+			output_data: VecDeque::new()
+		})
+	}
+
+	/// Accepts a client if none is attached. The handshake follows
+	/// when the client sends the request.
+	fn accept(&mut self) {
+		if self.stream.is_some() {
+			return;
+		}
+		if let Ok((stream, _)) = self.listener.accept() {
+			if stream.set_nonblocking(true).is_err() {
+				return;
+			}
+			let _ = stream.set_nodelay(true);
+			self.open = false;
+			self.received_data.clear();
+			self.output_data.clear();
+			self.stream = Some(stream);
+		}
+	}
+
+	fn disconnect(&mut self) {
+		self.stream = None;
+		self.open = false;
+		self.received_data.clear();
+		self.output_data.clear();
+	}
+
+	/// Reads the data the client has sent so far without blocking.
+	fn receive(&mut self) {
+		let mut buffer = [0; 256];
+		loop {
+			let result = match self.stream.as_mut() {
+				Some(stream) => stream.read(&mut buffer),
+				None => return
+			};
+			match result {
+				Ok(0) => return self.disconnect(),
+				Ok(size) => self.received_data.extend_from_slice(&buffer[..size]),
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+				Err(_) => return self.disconnect()
+			};
+		}
+		match self.open {
+			true => self.handle_frames(),
+			false => self.handle_handshake()
+		};
+	}
+
+	/// Sends as much buffered output as the client can receive.
+	fn send(&mut self) {
+		while !self.output_data.is_empty() {
+			let result = match self.stream.as_mut() {
+				Some(stream) => stream.write(self.output_data.as_slices().0),
+				None => return self.output_data.clear()
+			};
+			match result {
+				Ok(0) => return self.disconnect(),
+				Ok(size) => {
+					self.output_data.drain(..size);
+				},
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+				Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+				Err(_) => return self.disconnect()
+			};
+		}
+	}
+
+	/// Answers the opening handshake request once it is complete.
+	fn handle_handshake(&mut self) {
+		let end = match self.received_data.windows(4).position(|window| window == b"\r\n\r\n") {
+			Some(position) => position + 4,
+			None => {
+				if self.received_data.len() > MAX_REQUEST_SIZE {
+					self.disconnect();
+				}
+				return;
+			}
+		};
+		let request = String::from_utf8_lossy(&self.received_data[..end]).into_owned();
+		self.received_data.drain(..end);
+		let key = request.lines()
+			.filter_map(|line| line.split_once(':'))
+			.find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-key"))
+			.map(|(_, value)| value.trim().to_string());
+		let response = match &key {
+			Some(key) => format!(
+				"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+				get_accept_key(key)
+			),
+			None => "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+		};
+		self.output_data.extend(response.as_bytes());
+		self.send();
+		match key.is_some() {
+			true => {
+				self.open = true;
+				// The client may have sent frames right after the request
+				self.handle_frames();
+			},
+			false => self.disconnect()
+		};
+	}
+
+	fn handle_frames(&mut self) {
+		while let Some(frame) = self.parse_frame() {
+			match frame.opcode {
+				OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => self.input_data.extend(frame.payload),
+				OPCODE_PING => self.push_frame(OPCODE_PONG, &frame.payload),
+				OPCODE_CLOSE => {
+					self.push_frame(OPCODE_CLOSE, &[]);
+					self.send();
+					return self.disconnect();
+				},
+				// OPCODE_PONG and unknown ones
+				_ => {}
+			};
+		}
+	}
+
+	/// Takes a frame from the received data. Returns `None` if no frame
+	/// is complete yet.
+	fn parse_frame(&mut self) -> Option<Frame> {
+		let data = &self.received_data;
+		if data.len() < 2 {
+			return None;
+		}
+		let opcode = data[0] & 0xf;
+		let masked = (data[1] & MASK_BIT) != 0;
+		let (length, mut position) = match data[1] & 0x7f {
+			126 if data.len() >= 4 => (u16::from_be_bytes([data[2], data[3]]) as usize, 4),
+			127 if data.len() >= 10 => {
+				let mut bytes = [0; 8];
+				bytes.copy_from_slice(&data[2..10]);
+				(u64::from_be_bytes(bytes).min(usize::MAX as u64) as usize, 10)
+			},
+			126 | 127 => return None,
+			length => (length as usize, 2)
+		};
+		// Client frames must be masked
+		if !masked || length > MAX_PAYLOAD_SIZE {
+			self.disconnect();
+			return None;
+		}
+		if data.len() < position + 4 + length {
+			return None;
+		}
+		let mask = [data[position], data[position + 1], data[position + 2], data[position + 3]];
+		position += 4;
+		let payload = data[position..position + length].iter().enumerate()
+			.map(|(i, value)| value ^ mask[i % 4])
+			.collect();
+		self.received_data.drain(..position + length);
+		Some(Frame {
+			opcode,
+			payload
+		})
+	}
+
+	/// Buffers an unmasked frame to send.
+	fn push_frame(&mut self, opcode: u8, payload: &[u8]) {
+		self.output_data.push_back(FIN_BIT | opcode);
+		match payload.len() {
+			0..=125 => self.output_data.push_back(payload.len() as u8),
+			126..=0xffff => {
+				self.output_data.push_back(126);
+				self.output_data.extend(&(payload.len() as u16).to_be_bytes());
+			},
+			_ => {
+				self.output_data.push_back(127);
+				self.output_data.extend(&(payload.len() as u64).to_be_bytes());
+			}
+		};
+		self.output_data.extend(payload);
+	}
+
+	fn poll(&mut self) {
+		self.accept();
+		self.receive();
+		self.send();
+	}
+}
+
+/// Returns Sec-WebSocket-Accept value for Sec-WebSocket-Key.
+fn get_accept_key(key: &str) -> String {
+	let mut sha1 = Sha1::new();
+	sha1.update(key.as_bytes());
+	sha1.update(HANDSHAKE_GUID.as_bytes());
+	encode_base64(&sha1.digest().bytes())
+}
+
+fn encode_base64(data: &[u8]) -> String {
+	const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+	let mut text = String::new();
+	for chunk in data.chunks(3) {
+		let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, value)| bits | (*value as u32) << (16 - i * 8));
+		for i in 0..4 {
+			match i <= chunk.len() {
+				true => text.push(TABLE[((bits >> (18 - i * 6)) & 0x3f) as usize] as char),
+				false => text.push('=')
+			};
+		}
+	}
+	text
+}
+
+impl Terminal for WebSocketTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.put_bytes(&[value]);
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		// Drops the output rather than a part of a frame not to break
+		// the stream
+		if !self.open || self.output_data.len() > OUTPUT_BUFFER_CAPACITY {
+			return;
+		}
+		self.push_frame(OPCODE_BINARY, values);
+		self.send();
+	}
+
+	fn get_input(&mut self) -> u8 {
+		self.poll();
+		self.input_data.pop_front().unwrap_or(0)
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.poll();
+		let size = buffer.len().min(self.input_data.len());
+		for (i, value) in self.input_data.drain(..size).enumerate() {
+			buffer[i] = value;
+		}
+		size
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.poll();
+		!self.input_data.is_empty()
+	}
+
+	/// Backpressures the guest while the client is slow to receive.
+	/// Always ready while no client is attached.
+	fn is_output_ready(&mut self) -> bool {
+		self.send();
+		self.output_data.len() < OUTPUT_READY_THRESHOLD
+	}
+
+	// Wasm specific methods. No use.
+
+	fn put_input(&mut self, _value: u8) {
+	}
+
+	fn get_output(&mut self) -> u8 {
+		0
+	}
+}
+
+#[cfg(test)]
+mod test_websocket_terminal {
+	use super::*;
+	use std::thread;
+	use std::time::Duration;
+
+	const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+	// Polls the terminal until the condition holds
+	fn poll_until<F: Fn(&WebSocketTerminal) -> bool>(terminal: &mut WebSocketTerminal, condition: F) {
+		for _ in 0..1000 {
+			terminal.poll();
+			if condition(terminal) {
+				return;
+			}
+			thread::sleep(Duration::from_millis(1));
+		}
+		panic!("Timed out");
+	}
+
+	fn read_exact(client: &mut TcpStream, size: usize) -> Vec<u8> {
+		let mut data = vec![0; size];
+		client.read_exact(&mut data).unwrap();
+		data
+	}
+
+	// Reads the data the terminal sends while polling it
+	fn receive(terminal: &mut WebSocketTerminal, client: &mut TcpStream, size: usize) -> Vec<u8> {
+		client.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+		let mut data = vec![];
+		for _ in 0..1000 {
+			terminal.poll();
+			let mut buffer = [0; 256];
+			if let Ok(length) = client.read(&mut buffer[..size - data.len()]) {
+				data.extend_from_slice(&buffer[..length]);
+			}
+			if data.len() == size {
+				break;
+			}
+		}
+		client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		data
+	}
+
+	// Returns the terminal and the client the handshake has been done with
+	fn connect() -> (WebSocketTerminal, TcpStream) {
+		let mut terminal = WebSocketTerminal::new("127.0.0.1:0").unwrap();
+		let mut client = TcpStream::connect(terminal.listener.local_addr().unwrap()).unwrap();
+		client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		client.write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+		poll_until(&mut terminal, |terminal| terminal.open);
+		let mut response = vec![];
+		while !response.ends_with(b"\r\n\r\n") {
+			response.extend(read_exact(&mut client, 1));
+		}
+		let response = String::from_utf8(response).unwrap();
+		assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+		assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+		(terminal, client)
+	}
+
+	// Client frames are masked
+	fn create_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+		let mut frame = vec![first];
+		match payload.len() {
+			0..=125 => frame.push(MASK_BIT | payload.len() as u8),
+			_ => {
+				frame.push(MASK_BIT | 126);
+				frame.extend(&(payload.len() as u16).to_be_bytes());
+			}
+		};
+		frame.extend(&MASK);
+		frame.extend(payload.iter().enumerate().map(|(i, value)| value ^ MASK[i % 4]));
+		frame
+	}
+
+	fn get_input_bytes(terminal: &mut WebSocketTerminal, size: usize) -> Vec<u8> {
+		poll_until(terminal, |terminal| terminal.input_data.len() >= size);
+		let mut buffer = vec![0; size];
+		assert_eq!(size, terminal.get_input_bytes(&mut buffer));
+		buffer
+	}
+
+	#[test]
+	fn accept_key() {
+		// The example in RFC 6455
+		assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", get_accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+		assert_eq!("Zm9vYg==", encode_base64(b"foob"));
+		assert_eq!("Zm9vYmE=", encode_base64(b"fooba"));
+	}
+
+	#[test]
+	fn text_and_binary_frames() {
+		let (mut terminal, mut client) = connect();
+		client.write_all(&create_frame(FIN_BIT | OPCODE_TEXT, b"ls\r")).unwrap();
+		assert_eq!(b"ls\r".to_vec(), get_input_bytes(&mut terminal, 3));
+		client.write_all(&create_frame(FIN_BIT | OPCODE_BINARY, &[0xff, 0x00, 0x1b])).unwrap();
+		assert_eq!(vec![0xff, 0x00, 0x1b], get_input_bytes(&mut terminal, 3));
+
+		// The output is sent in unmasked binary frames
+		terminal.put_bytes(&[b'$', 0xe3]);
+		assert_eq!(vec![FIN_BIT | OPCODE_BINARY, 2, b'$', 0xe3], read_exact(&mut client, 4));
+		let output = vec![b'x'; 300];
+		terminal.put_bytes(&output);
+		assert_eq!(vec![FIN_BIT | OPCODE_BINARY, 126, 0x01, 0x2c], read_exact(&mut client, 4));
+		assert_eq!(output, read_exact(&mut client, 300));
+	}
+
+	#[test]
+	fn partial_and_fragmented_frames() {
+		let (mut terminal, mut client) = connect();
+		// A frame split over the stream is taken once complete
+		let payload = vec![b'a'; 200];
+		let frame = create_frame(FIN_BIT | OPCODE_TEXT, &payload);
+		client.write_all(&frame[..3]).unwrap();
+		poll_until(&mut terminal, |terminal| terminal.received_data.len() == 3);
+		assert!(!terminal.has_input());
+		client.write_all(&frame[3..]).unwrap();
+		assert_eq!(payload, get_input_bytes(&mut terminal, 200));
+
+		// A message fragmented into continuation frames
+		client.write_all(&create_frame(OPCODE_TEXT, b"ec")).unwrap();
+		client.write_all(&create_frame(OPCODE_CONTINUATION, b"ho")).unwrap();
+		client.write_all(&create_frame(FIN_BIT | OPCODE_CONTINUATION, b" hi")).unwrap();
+		assert_eq!(b"echo hi".to_vec(), get_input_bytes(&mut terminal, 7));
+	}
+
+	#[test]
+	fn ping_and_close() {
+		let (mut terminal, mut client) = connect();
+		client.write_all(&create_frame(FIN_BIT | OPCODE_PING, b"beat")).unwrap();
+		assert_eq!(vec![FIN_BIT | OPCODE_PONG, 4, b'b', b'e', b'a', b't'], receive(&mut terminal, &mut client, 6));
+		// Pong frames from the client are ignored
+		client.write_all(&create_frame(FIN_BIT | OPCODE_PONG, b"beat")).unwrap();
+		client.write_all(&create_frame(FIN_BIT | OPCODE_CLOSE, &[0x03, 0xe8])).unwrap();
+		poll_until(&mut terminal, |terminal| terminal.stream.is_none());
+		assert!(terminal.input_data.is_empty());
+		assert_eq!(vec![FIN_BIT | OPCODE_CLOSE, 0], read_exact(&mut client, 2));
+		assert_eq!(0, client.read(&mut [0; 1]).unwrap());
+		// Output without a client is discarded
+		terminal.put_bytes(b"lost");
+		assert!(terminal.output_data.is_empty());
+		assert!(terminal.is_output_ready());
+	}
+
+	#[test]
+	fn unmasked_frame() {
+		let (mut terminal, mut client) = connect();
+		client.write_all(&[FIN_BIT | OPCODE_TEXT, 1, b'a']).unwrap();
+		poll_until(&mut terminal, |terminal| terminal.stream.is_none());
+		assert!(!terminal.has_input());
+		assert_eq!(0, client.read(&mut [0; 1]).unwrap());
+	}
+}
@@ -0,0 +1,67 @@
+/*
+ * C API of riscv_emu_rust, implemented by the riscv_emu_rust_ffi library.
+ * Keep this in sync with ffi/src/lib.rs. The test in ffi checks every
+ * exported function is declared here.
+ *
+ * Functions returning int return zero on success and -1 on failure
+ * unless stated otherwise. A handle must not be used from more than one
+ * thread at a time.
+ */
+#ifndef RISCV_EMU_H
+#define RISCV_EMU_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Bumped on incompatible changes. Compare with riscv_emu_api_version() */
+#define RISCV_EMU_API_VERSION 1
+
+/* Emulator handle */
+typedef struct RiscvEmu RiscvEmu;
+
+/* Called with console output bytes, valid only during the call */
+typedef void (*RiscvEmuConsoleCallback)(void *user_data, const uint8_t *data, size_t length);
+
+uint32_t riscv_emu_api_version(void);
+
+/* memory_capacity in bytes, zero for the default. Returns NULL on failure */
+RiscvEmu *riscv_emu_create(uint64_t memory_capacity);
+void riscv_emu_destroy(RiscvEmu *emu);
+
+/* Loads an ELF kernel or program. Call it once, before the disk */
+int riscv_emu_load_kernel(RiscvEmu *emu, const uint8_t *data, size_t length);
+/* Attaches a copy of a disk image as the Virtio block device */
+int riscv_emu_load_disk(RiscvEmu *emu, const uint8_t *data, size_t length);
+/* Attaches a disk image file, written by the guest unless read_only */
+int riscv_emu_load_disk_file(RiscvEmu *emu, const char *path, int read_only);
+/* Overrides the generated device tree */
+int riscv_emu_load_dtb(RiscvEmu *emu, const uint8_t *data, size_t length);
+
+/* Runs up to cycles cycles and calls the console callback. Returns
+ * the cycles run, fewer if the program has exited */
+uint64_t riscv_emu_step(RiscvEmu *emu, uint64_t cycles);
+/* Returns 1 and sets exit_code if the program has exited, otherwise 0 */
+int riscv_emu_get_exit_code(const RiscvEmu *emu, uint64_t *exit_code);
+
+uint64_t riscv_emu_read_pc(const RiscvEmu *emu);
+void riscv_emu_write_pc(RiscvEmu *emu, uint64_t value);
+/* reg is 0 to 31 */
+uint64_t riscv_emu_read_register(const RiscvEmu *emu, uint32_t reg);
+int riscv_emu_write_register(RiscvEmu *emu, uint32_t reg, uint64_t value);
+/* Virtual addresses as the CPU in the current mode sees them */
+int riscv_emu_read_memory(RiscvEmu *emu, uint64_t address, uint8_t *buffer, size_t length);
+int riscv_emu_write_memory(RiscvEmu *emu, uint64_t address, const uint8_t *data, size_t length);
+
+/* callback can be NULL. user_data is passed to it as is */
+void riscv_emu_set_console_callback(RiscvEmu *emu, RiscvEmuConsoleCallback callback, void *user_data);
+void riscv_emu_put_input(RiscvEmu *emu, const uint8_t *data, size_t length);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RISCV_EMU_H */
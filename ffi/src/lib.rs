@@ -0,0 +1,421 @@
+extern crate riscv_emu_rust;
+
+use std::ffi::CStr;
+use std::fs::OpenOptions;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic;
+use std::ptr;
+use std::slice;
+
+use riscv_emu_rust::Emulator;
+use riscv_emu_rust::block_backend::{FileBlockBackend, MemoryBlockBackend};
+use riscv_emu_rust::config::EmulatorConfig;
+use riscv_emu_rust::default_terminal::DefaultTerminal;
+
+/// Version of the C API, bumped on incompatible changes. Matches
+/// `RISCV_EMU_API_VERSION` in `include/riscv_emu.h`.
+pub const API_VERSION: u32 = 1;
+
+// Output bytes passed to the console callback at once at most
+const OUTPUT_CHUNK_SIZE: usize = 4096;
+
+/// Called with console output bytes after `riscv_emu_step()`. The bytes
+/// are valid only during the call.
+pub type ConsoleCallback = extern "C" fn(user_data: *mut c_void, data: *const u8, length: usize);
+
+/// Emulator handle passed to the C API functions, opaque to C.
+///
+/// ```c
+/// // C code
+/// RiscvEmu *emu = riscv_emu_create(0);
+/// riscv_emu_load_kernel(emu, kernel, kernel_size);
+/// riscv_emu_load_disk(emu, disk, disk_size);
+/// riscv_emu_set_console_callback(emu, on_output, NULL);
+/// while (1) {
+///   riscv_emu_step(emu, 0x100000);
+/// }
+/// riscv_emu_destroy(emu);
+/// ```
+pub struct RiscvEmu {
+	emulator: Emulator,
+	console_callback: Option<ConsoleCallback>,
+	user_data: *mut c_void
+}
+
+impl RiscvEmu {
+	fn flush_output(&mut self) {
+		let callback = match self.console_callback {
+			Some(callback) => callback,
+			None => return
+		};
+		let mut buffer = [0; OUTPUT_CHUNK_SIZE];
+		loop {
+			let length = self.emulator.get_mut_terminal().get_output_bytes(&mut buffer);
+			if length == 0 {
+				break;
+			}
+			callback(self.user_data, buffer.as_ptr(), length);
+		}
+	}
+}
+
+// Converts a status to the return value of the C API, zero on success
+fn to_status(result: Result<(), ()>) -> c_int {
+	match result {
+		Ok(()) => 0,
+		Err(()) => -1
+	}
+}
+
+// Copies C bytes. Null is accepted for zero length
+unsafe fn to_vec(data: *const u8, length: usize) -> Result<Vec<u8>, ()> {
+	match data.is_null() {
+		true => match length {
+			0 => Ok(vec![]),
+			_ => Err(())
+		},
+		false => Ok(slice::from_raw_parts(data, length).to_vec())
+	}
+}
+
+/// Returns `API_VERSION`, for checking the header matches the library.
+#[no_mangle]
+pub extern "C" fn riscv_emu_api_version() -> u32 {
+	API_VERSION
+}
+
+/// Creates an emulator with the default machine configuration. Returns
+/// null on failure. Release it with `riscv_emu_destroy()`.
+///
+/// # Arguments
+/// * `memory_capacity` Main memory capacity in bytes. Zero for the default
+#[no_mangle]
+pub extern "C" fn riscv_emu_create(memory_capacity: u64) -> *mut RiscvEmu {
+	let mut config = EmulatorConfig::default();
+	if memory_capacity != 0 {
+		config.memory_capacity = memory_capacity;
+	}
+	match panic::catch_unwind(|| Emulator::new_with_config(Box::new(DefaultTerminal::new()), config)) {
+		Ok(emulator) => Box::into_raw(Box::new(RiscvEmu {
+			emulator,
+			console_callback: None,
+			user_data: ptr::null_mut()
+		})),
+		Err(_) => ptr::null_mut()
+	}
+}
+
+/// Releases an emulator created by `riscv_emu_create()`. Null is ignored.
+///
+/// # Safety
+/// `emu` must be null or returned by `riscv_emu_create()` and not
+/// destroyed yet.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_destroy(emu: *mut RiscvEmu) {
+	if !emu.is_null() {
+		drop(Box::from_raw(emu));
+	}
+}
+
+/// Loads an ELF kernel or program and sets the PC to its entry. Call it
+/// once, before the other loading functions except `riscv_emu_load_dtb()`.
+/// Returns zero on success, or -1 if the data isn't a RISC-V ELF file.
+///
+/// # Safety
+/// `emu` must be valid and `data` must point to `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_load_kernel(emu: *mut RiscvEmu, data: *const u8, length: usize) -> c_int {
+	let emu = &mut *emu;
+	let data = match to_vec(data, length) {
+		Ok(data) => data,
+		Err(()) => return -1
+	};
+	to_status(emu.emulator.setup_program(data).map_err(|_| ()))
+}
+
+/// Attaches a disk image in memory as the Virtio block device. The data
+/// is copied, and guest writes don't go back to it. Returns zero on
+/// success.
+///
+/// # Safety
+/// `emu` must be valid and `data` must point to `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_load_disk(emu: *mut RiscvEmu, data: *const u8, length: usize) -> c_int {
+	let emu = &mut *emu;
+	match to_vec(data, length) {
+		Ok(data) => {
+			emu.emulator.setup_filesystem(Box::new(MemoryBlockBackend::new(data)));
+			0
+		},
+		Err(()) => -1
+	}
+}
+
+/// Attaches a disk image file as the Virtio block device, read and written
+/// on demand. Guest writes go to the file unless `read_only` is nonzero.
+/// Returns zero on success, or -1 if the file can't be opened.
+///
+/// # Safety
+/// `emu` must be valid and `path` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_load_disk_file(emu: *mut RiscvEmu, path: *const c_char, read_only: c_int) -> c_int {
+	let emu = &mut *emu;
+	if path.is_null() {
+		return -1;
+	}
+	let path = match CStr::from_ptr(path).to_str() {
+		Ok(path) => path,
+		Err(_) => return -1
+	};
+	let backend = OpenOptions::new()
+		.read(true)
+		.write(read_only == 0)
+		.open(path)
+		.and_then(FileBlockBackend::new);
+	match backend {
+		Ok(backend) => {
+			emu.emulator.setup_filesystem(Box::new(backend));
+			0
+		},
+		Err(_) => -1
+	}
+}
+
+/// Overrides the device tree generated from the machine configuration.
+/// Returns zero on success.
+///
+/// # Safety
+/// `emu` must be valid and `data` must point to `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_load_dtb(emu: *mut RiscvEmu, data: *const u8, length: usize) -> c_int {
+	let emu = &mut *emu;
+	match to_vec(data, length) {
+		Ok(data) => match emu.emulator.setup_dtb(data) {
+			Ok(()) => 0,
+			Err(_) => -1
+		},
+		Err(()) => -1
+	}
+}
+
+/// Runs up to `cycles` cycles, stopping early if the program has exited,
+/// then passes the console output to the callback. Returns the number of
+/// cycles run.
+///
+/// # Safety
+/// `emu` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_step(emu: *mut RiscvEmu, cycles: u64) -> u64 {
+	let emu = &mut *emu;
+	let mut count = 0;
+	while count < cycles && emu.emulator.get_exit_code().is_none() {
+		emu.emulator.tick();
+		count += 1;
+	}
+	emu.flush_output();
+	count
+}
+
+/// Returns 1 and stores the status code to `exit_code` if the program has
+/// exited, otherwise 0. Only riscv-tests programs exit.
+///
+/// # Safety
+/// `emu` must be valid and `exit_code` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_get_exit_code(emu: *const RiscvEmu, exit_code: *mut u64) -> c_int {
+	let emu = &*emu;
+	match emu.emulator.get_exit_code() {
+		Some(code) => {
+			if !exit_code.is_null() {
+				*exit_code = code;
+			}
+			1
+		},
+		None => 0
+	}
+}
+
+/// Returns the program counter.
+///
+/// # Safety
+/// `emu` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_read_pc(emu: *const RiscvEmu) -> u64 {
+	(*emu).emulator.get_cpu().read_pc()
+}
+
+/// Sets the program counter.
+///
+/// # Safety
+/// `emu` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_write_pc(emu: *mut RiscvEmu, value: u64) {
+	(*emu).emulator.get_mut_cpu().update_pc(value);
+}
+
+/// Returns the integer register, or zero if `reg` is out of range.
+///
+/// # Safety
+/// `emu` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_read_register(emu: *const RiscvEmu, reg: u32) -> u64 {
+	match reg {
+		0..=31 => (*emu).emulator.get_cpu().read_register(reg as u8) as u64,
+		_ => 0
+	}
+}
+
+/// Sets the integer register. Writes to x0 are ignored. Returns zero on
+/// success, or -1 if `reg` is out of range.
+///
+/// # Safety
+/// `emu` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_write_register(emu: *mut RiscvEmu, reg: u32, value: u64) -> c_int {
+	match reg {
+		0 => 0,
+		1..=31 => {
+			(*emu).emulator.get_mut_cpu().write_register(reg as u8, value as i64);
+			0
+		},
+		_ => -1
+	}
+}
+
+/// Reads memory at the virtual address as the CPU in the current mode
+/// sees it. Returns zero on success, or -1 if an address faults or isn't
+/// mapped, in which case the buffer may be partially filled.
+///
+/// # Safety
+/// `emu` must be valid and `buffer` must point to `length` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_read_memory(emu: *mut RiscvEmu, address: u64, buffer: *mut u8, length: usize) -> c_int {
+	if buffer.is_null() && length > 0 {
+		return -1;
+	}
+	let mmu = (*emu).emulator.get_mut_cpu().get_mut_mmu();
+	for i in 0..length {
+		let v_address = address.wrapping_add(i as u64);
+		if mmu.validate_address(v_address) != Ok(true) {
+			return -1;
+		}
+		match mmu.load(v_address) {
+			Ok(value) => *buffer.add(i) = value,
+			Err(_) => return -1
+		};
+	}
+	0
+}
+
+/// Writes memory at the virtual address as the CPU in the current mode
+/// sees it. Returns zero on success, or -1 if an address faults or isn't
+/// mapped, in which case the data may be partially written.
+///
+/// # Safety
+/// `emu` must be valid and `data` must point to `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_write_memory(emu: *mut RiscvEmu, address: u64, data: *const u8, length: usize) -> c_int {
+	if data.is_null() && length > 0 {
+		return -1;
+	}
+	let mmu = (*emu).emulator.get_mut_cpu().get_mut_mmu();
+	for i in 0..length {
+		let v_address = address.wrapping_add(i as u64);
+		if mmu.validate_address(v_address) != Ok(true) {
+			return -1;
+		}
+		if mmu.store(v_address, *data.add(i)).is_err() {
+			return -1;
+		}
+	}
+	0
+}
+
+/// Sets the function called with console output after `riscv_emu_step()`.
+/// Null stops the calls, and the output is buffered until it's set again.
+///
+/// # Safety
+/// `emu` must be valid. `user_data` is passed to the callback as is.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_set_console_callback(emu: *mut RiscvEmu, callback: Option<ConsoleCallback>, user_data: *mut c_void) {
+	let emu = &mut *emu;
+	emu.console_callback = callback;
+	emu.user_data = user_data;
+}
+
+/// Sends input bytes to the console, e.g. key strokes.
+///
+/// # Safety
+/// `emu` must be valid and `data` must point to `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn riscv_emu_put_input(emu: *mut RiscvEmu, data: *const u8, length: usize) {
+	if let Ok(data) = to_vec(data, length) {
+		(*emu).emulator.get_mut_terminal().put_input_bytes(&data);
+	}
+}
+
+#[cfg(test)]
+mod test_ffi {
+	use super::*;
+	use std::fs;
+
+	extern "C" fn collect_output(user_data: *mut c_void, data: *const u8, length: usize) {
+		let output = unsafe { &mut *(user_data as *mut Vec<u8>) };
+		output.extend_from_slice(unsafe { slice::from_raw_parts(data, length) });
+	}
+
+	#[test]
+	fn header_declares_exports() {
+		let header = include_str!("../include/riscv_emu.h");
+		let source = include_str!("lib.rs");
+		let mut count = 0;
+		for line in source.lines().filter(|line| line.starts_with("pub ") && line.contains("extern \"C\" fn ")) {
+			let name = line.split("fn ").nth(1).unwrap().split('(').next().unwrap();
+			assert!(header.contains(&format!(" {}(", name)) || header.contains(&format!("*{}(", name)), "{} isn't declared", name);
+			count += 1;
+		}
+		assert_eq!(17, count);
+		assert!(header.contains(&format!("#define RISCV_EMU_API_VERSION {}\n", API_VERSION)));
+	}
+
+	#[test]
+	fn run_xv6() {
+		let kernel = match fs::read("../resources/xv6/kernel") {
+			Ok(kernel) => kernel,
+			Err(_) => panic!("Failed to read xv6 kernel")
+		};
+		unsafe {
+			let emu = riscv_emu_create(0);
+			assert!(!emu.is_null());
+			assert_eq!(-1, riscv_emu_load_kernel(emu, b"not elf".as_ptr(), 7));
+			riscv_emu_destroy(emu);
+
+			let emu = riscv_emu_create(0);
+			assert_eq!(0, riscv_emu_load_kernel(emu, kernel.as_ptr(), kernel.len()));
+			assert_eq!(0x80000000, riscv_emu_read_pc(emu));
+			let mut output: Vec<u8> = vec![];
+			riscv_emu_set_console_callback(emu, Some(collect_output), &mut output as *mut Vec<u8> as *mut c_void);
+			for _ in 0..100 {
+				assert_eq!(0x10000, riscv_emu_step(emu, 0x10000));
+				if String::from_utf8_lossy(&output).contains("xv6 kernel is booting") {
+					break;
+				}
+			}
+			assert!(String::from_utf8_lossy(&output).contains("xv6 kernel is booting"));
+			assert_eq!(0, riscv_emu_get_exit_code(emu, ptr::null_mut()));
+
+			assert_eq!(0, riscv_emu_write_register(emu, 10, 0x1234));
+			assert_eq!(0x1234, riscv_emu_read_register(emu, 10));
+			assert_eq!(0, riscv_emu_write_register(emu, 0, 0x1234));
+			assert_eq!(0, riscv_emu_read_register(emu, 0));
+			assert_eq!(-1, riscv_emu_write_register(emu, 32, 0));
+			let data = [1, 2, 3, 4];
+			let mut buffer = [0; 4];
+			assert_eq!(0, riscv_emu_write_memory(emu, 0x80100000, data.as_ptr(), 4));
+			assert_eq!(0, riscv_emu_read_memory(emu, 0x80100000, buffer.as_mut_ptr(), 4));
+			assert_eq!(data, buffer);
+			assert_eq!(-1, riscv_emu_read_memory(emu, 0x1000_0000_0000, buffer.as_mut_ptr(), 4));
+			riscv_emu_destroy(emu);
+		}
+	}
+}
@@ -0,0 +1,37 @@
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use net_backend::NetBackend;
+
+/// `NetBackend` exchanging Ethernet frames with async tasks through
+/// tokio channels, e.g. a task bridging the guest to a switch over
+/// a socket, while the emulator runs with `Emulator::run_program_async()`
+/// on the same runtime. Frames sent while the tasks have dropped
+/// the receiving end are discarded.
+pub struct AsyncNetBackend {
+	incoming: UnboundedReceiver<Vec<u8>>,
+	outgoing: UnboundedSender<Vec<u8>>
+}
+
+impl AsyncNetBackend {
+	/// Creates a new `AsyncNetBackend`.
+	///
+	/// # Arguments
+	/// * `incoming` Receives frames to the guest
+	/// * `outgoing` Sends frames from the guest
+	pub fn new(incoming: UnboundedReceiver<Vec<u8>>, outgoing: UnboundedSender<Vec<u8>>) -> Self {
+		AsyncNetBackend {
+			incoming,
+			outgoing
+		}
+	}
+}
+
+impl NetBackend for AsyncNetBackend {
+	fn send(&mut self, frame: &[u8]) {
+		let _ = self.outgoing.send(frame.to_vec());
+	}
+
+	fn poll(&mut self) -> Option<Vec<u8>> {
+		self.incoming.try_recv().ok()
+	}
+}
@@ -0,0 +1,90 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use {Emulator, StopReason};
+
+/// Future returned by `Emulator::run_program_async()`. Each poll runs
+/// the program up to the slice of cycles and, unless it has stopped,
+/// wakes the task again and yields, so the other tasks on the runtime,
+/// e.g. the ones feeding `AsyncTerminal` and `AsyncNetBackend`, get
+/// their turns in between.
+///
+/// `Emulator` isn't `Send`, so the future runs on the thread it's
+/// created on, e.g. in `tokio::task::spawn_local()` of a `LocalSet`
+/// or with `block_on()` of a current thread runtime.
+pub struct RunProgram<'a> {
+	emulator: &'a mut Emulator,
+	slice_cycles: u64
+}
+
+impl<'a> RunProgram<'a> {
+	/// Creates a new `RunProgram`.
+	///
+	/// # Arguments
+	/// * `emulator`
+	/// * `slice_cycles` Cycles run in a poll. Must be more than zero
+	pub fn new(emulator: &'a mut Emulator, slice_cycles: u64) -> Self {
+		RunProgram {
+			emulator,
+			slice_cycles
+		}
+	}
+}
+
+impl<'a> Future for RunProgram<'a> {
+	type Output = StopReason;
+
+	fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<StopReason> {
+		let slice_cycles = self.slice_cycles;
+		match self.emulator.run_program_cycles(slice_cycles) {
+			Some(reason) => Poll::Ready(reason),
+			None => {
+				context.waker().wake_by_ref();
+				Poll::Pending
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test_async_runner {
+	use tokio::runtime::Builder;
+	use tokio::sync::mpsc::unbounded_channel;
+
+	use async_terminal::AsyncTerminal;
+	use mmu::DRAM_BASE;
+	use terminal::Terminal;
+	use super::*;
+
+	#[test]
+	fn run_program_async() {
+		let (input_sender, input_receiver) = unbounded_channel();
+		let (output_sender, _output_receiver) = unbounded_channel();
+		let mut terminal = AsyncTerminal::new(input_receiver, output_sender);
+		input_sender.send(b"ls".to_vec()).unwrap();
+		assert_eq!(b'l', terminal.get_input());
+
+		let mut emu = Emulator::new(Box::new(terminal));
+		let cpu = emu.get_mut_cpu();
+		cpu.get_mut_mmu().init_memory(0x100);
+		let code = [
+			0xfff50513, // loop: addi a0, a0, -1
+			0xfe051ee3 // bne a0, zero, loop
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		cpu.write_register(10, 100);
+		cpu.update_pc(DRAM_BASE);
+		emu.add_breakpoint(DRAM_BASE + 8);
+
+		// Stops in the middle of a slice
+		let runtime = Builder::new_current_thread().build().unwrap();
+		let reason = runtime.block_on(emu.run_program_async(30));
+		assert_eq!(StopReason::Breakpoint(DRAM_BASE + 8), reason);
+		assert_eq!(0, emu.get_cpu().read_register(10));
+	}
+}
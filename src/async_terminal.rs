@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use terminal::Terminal;
+
+/// `Terminal` connected to async tasks through tokio channels, for
+/// servers running the emulator with `Emulator::run_program_async()`.
+/// A task awaits the output channel and forwards the bytes, e.g. to
+/// a TCP connection, and sends what it reads from there to the input
+/// channel.
+///
+/// ```ignore
+/// let (input_sender, input_receiver) = tokio::sync::mpsc::unbounded_channel();
+/// let (output_sender, mut output_receiver) = tokio::sync::mpsc::unbounded_channel();
+/// let mut emulator = Emulator::new(Box::new(AsyncTerminal::new(input_receiver, output_sender)));
+/// // Set up the emulator
+/// tokio::task::spawn_local(async move {
+///   while let Some(output) = output_receiver.recv().await {
+///     // Write output to the connection
+///   }
+/// });
+/// let reason = emulator.run_program_async(0x10000).await;
+/// ```
+///
+/// The emulator keeps running after the tasks drop their ends.
+/// Input is no longer received and output is discarded then.
+pub struct AsyncTerminal {
+	input: UnboundedReceiver<Vec<u8>>,
+	output: UnboundedSender<Vec<u8>>,
+	input_data: VecDeque<u8>
+}
+
+impl AsyncTerminal {
+	/// Creates a new `AsyncTerminal`.
+	///
+	/// # Arguments
+	/// * `input` Receives input bytes from the tasks
+	/// * `output` Sends output bytes to the tasks
+	pub fn new(input: UnboundedReceiver<Vec<u8>>, output: UnboundedSender<Vec<u8>>) -> Self {
+		AsyncTerminal {
+			input,
+			output,
+			input_data: VecDeque::new()
+		}
+	}
+
+	/// Moves the input the tasks have sent so far to the buffer.
+	fn receive(&mut self) {
+		while let Ok(data) = self.input.try_recv() {
+			self.input_data.extend(data);
+		}
+	}
+}
+
+impl Terminal for AsyncTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.put_bytes(&[value]);
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		let _ = self.output.send(values.to_vec());
+	}
+
+	fn get_input(&mut self) -> u8 {
+		self.receive();
+		self.input_data.pop_front().unwrap_or(0)
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.receive();
+		let size = buffer.len().min(self.input_data.len());
+		for (i, value) in self.input_data.drain(..size).enumerate() {
+			buffer[i] = value;
+		}
+		size
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.receive();
+		!self.input_data.is_empty()
+	}
+
+	/// Puts input from the emulator side, in addition to the channel.
+	fn put_input(&mut self, data: u8) {
+		self.input_data.push_back(data);
+	}
+
+	/// Output goes to the channel. This method always returns zero.
+	fn get_output(&mut self) -> u8 {
+		0
+	}
+}
@@ -0,0 +1,465 @@
+extern crate rand;
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use self::rand::rngs::StdRng;
+use self::rand::{Rng, SeedableRng};
+use cpu::{Cpu, Xlen};
+use random::create_rng;
+use zipper_stack::RETURN_ADDRESS_MASK;
+
+const JAL_OPCODE: u32 = 0x6f;
+const JALR_OPCODE: u32 = 0x67;
+const STORE_OPCODE: u32 = 0x23;
+
+// UNZIP of Zipper Stack, custom-0 with funct3 1
+const UNZIP_MASK: u32 = 0xfffff07f;
+const UNZIP_DATA: u32 = 0x0000100b;
+
+// SSPOPCHK of Zicfiss with rs1 x1 or x5
+const SSPOPCHK_MASK: u32 = 0xfff07fff;
+const SSPOPCHK_DATA: u32 = 0xcdc04073;
+
+// Frames kept at most. The oldest are dropped beyond this, e.g. when
+// the guest switches stacks without returning
+const MAX_FRAMES: usize = 0x1000;
+
+/// When `AttackSimulator` corrupts a saved return address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttackTrigger {
+	/// The return address saved at the call depth, counted from 1 for
+	/// the functions called after the simulator is set
+	Depth(usize),
+	/// Each saved return address with the probability from 0.0 to 1.0
+	Random(f64)
+}
+
+/// How `AttackSimulator` corrupts a saved return address. Bits above
+/// the address, e.g. a Zipper Stack tag, are kept as an attacker not
+/// knowing the key would.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Corruption {
+	/// Overwrites the address with another, e.g. of a gadget
+	Redirect(u64),
+	/// Flips a random bit of the address
+	FlipBit
+}
+
+/// Return address protection schemes which can detect corruption.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtectionScheme {
+	/// `UNZIP` has raised an illegal instruction exception on the tag
+	/// mismatch
+	ZipperStack,
+	/// `SSPOPCHK` has raised a software check exception on the mismatch
+	/// with the shadow stack
+	ShadowStack
+}
+
+/// What has become of a corrupted return address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttackOutcome {
+	/// The function hasn't returned yet
+	Pending,
+	/// A protection scheme has detected the corruption
+	Detected {
+		scheme: ProtectionScheme,
+		/// Virtual address of the instruction detecting it
+		pc: u64,
+		/// Instructions run since the corruption
+		latency: u64
+	},
+	/// The function has returned to a wrong address undetected
+	Hijacked {
+		target: u64,
+		/// Instructions run since the corruption
+		latency: u64
+	},
+	/// The function has returned to the right address, e.g. without
+	/// reloading the saved return address
+	Masked
+}
+
+/// A corruption `AttackSimulator` has made.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttackRecord {
+	/// Call depth of the function
+	pub depth: usize,
+	/// Virtual address of the stack slot the return address is saved in
+	pub slot_address: u64,
+	/// Slot content before the corruption
+	pub original: u64,
+	/// Slot content after the corruption
+	pub corrupted: u64,
+	/// Instructions run before the corruption since the simulator is set
+	pub injected_at: u64,
+	pub outcome: AttackOutcome
+}
+
+/// Number of the attacks by the outcome, returned by
+/// `AttackSimulator::get_summary()`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AttackSummary {
+	pub injected: usize,
+	pub detected: usize,
+	pub hijacked: usize,
+	pub masked: usize,
+	pub pending: usize
+}
+
+impl fmt::Display for AttackSummary {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} attacks: {} detected, {} hijacked, {} masked, {} pending",
+			self.injected, self.detected, self.hijacked, self.masked, self.pending)
+	}
+}
+
+struct Frame {
+	return_address: u64,
+	slot_address: Option<u64>,
+	// Index of the record if the slot has been corrupted
+	attack: Option<usize>
+}
+
+/// Corrupts return addresses the guest saves on the stack while it runs,
+/// and records whether and when the protection schemes detect each, to
+/// evaluate them against a standard attack. Set it to `Emulator` with
+/// `set_attack_simulator()`.
+///
+/// Calls and returns are followed like `CallTrace`. A saved return
+/// address is the first store of `ra` with SW or SD, SD on RV64, in each
+/// function, and it's corrupted in memory right after the store. Traps and
+/// context switches aren't followed, so run the target in one context,
+/// e.g. a bare-metal program or a Linux user program.
+///
+/// ```ignore
+/// let mut simulator = AttackSimulator::new(AttackTrigger::Depth(2), Corruption::Redirect(gadget));
+/// simulator.set_seed(1);
+/// emulator.set_attack_simulator(simulator);
+/// emulator.run_program();
+/// println!("{}", emulator.get_attack_simulator().unwrap().get_summary());
+/// ```
+pub struct AttackSimulator {
+	trigger: AttackTrigger,
+	corruption: Corruption,
+	max_attacks: usize,
+	rng: StdRng,
+	frames: Vec<Frame>,
+	instructions: u64,
+	// Slot stored by the last instruction, corrupted before the next
+	stored_slot: Option<u64>,
+	// Scheme, address, and the next address of the checking instruction,
+	// UNZIP or SSPOPCHK, run last with the attack pending
+	check: Option<(ProtectionScheme, u64, u64)>,
+	records: Vec<AttackRecord>
+}
+
+impl AttackSimulator {
+	/// Creates a new `AttackSimulator` making one attack with a random seed.
+	///
+	/// # Arguments
+	/// * `trigger`
+	/// * `corruption`
+	pub fn new(trigger: AttackTrigger, corruption: Corruption) -> Self {
+		AttackSimulator {
+			trigger,
+			corruption,
+			max_attacks: 1,
+			rng: StdRng::seed_from_u64(create_rng().gen()),
+			frames: vec![],
+			instructions: 0,
+			stored_slot: None,
+			check: None,
+			records: vec![]
+		}
+	}
+
+	/// Sets the maximum number of attacks. The simulator stops corrupting
+	/// when it has made them.
+	///
+	/// # Arguments
+	/// * `max_attacks`
+	pub fn set_max_attacks(&mut self, max_attacks: usize) {
+		self.max_attacks = max_attacks;
+	}
+
+	/// Sets the seed of the random numbers to reproduce the attacks.
+	///
+	/// # Arguments
+	/// * `seed`
+	pub fn set_seed(&mut self, seed: u64) {
+		self.rng = StdRng::seed_from_u64(seed);
+	}
+
+	/// Returns the attacks made so far.
+	pub fn get_records(&self) -> &[AttackRecord] {
+		&self.records
+	}
+
+	/// Returns the number of the attacks by the outcome.
+	pub fn get_summary(&self) -> AttackSummary {
+		let mut summary = AttackSummary::default();
+		for record in self.records.iter() {
+			summary.injected += 1;
+			match record.outcome {
+				AttackOutcome::Pending => summary.pending += 1,
+				AttackOutcome::Detected { .. } => summary.detected += 1,
+				AttackOutcome::Hijacked { .. } => summary.hijacked += 1,
+				AttackOutcome::Masked => summary.masked += 1
+			};
+		}
+		summary
+	}
+
+	/// Follows the instruction the CPU is about to run, after checking
+	/// the result of the last one. Call this before every `Cpu::tick()`.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn trace(&mut self, cpu: &mut Cpu) {
+		if cpu.is_waiting_for_interrupt() {
+			return;
+		}
+		let pc = cpu.read_pc();
+		if let Some((scheme, address, next_address)) = self.check.take() {
+			if pc != next_address {
+				self.detect(scheme, address);
+			}
+		}
+		if let Some(slot_address) = self.stored_slot.take() {
+			self.inject(cpu, slot_address);
+		}
+		self.instructions += 1;
+		let (word, length) = match cpu.fetch_next_instruction() {
+			Some(instruction) => instruction,
+			None => return
+		};
+		let rd = ((word >> 7) & 0x1f) as u8;
+		let rs1 = ((word >> 15) & 0x1f) as u8;
+		let rs2 = ((word >> 20) & 0x1f) as u8;
+		let funct3 = (word >> 12) & 0x7;
+		match word & 0x7f {
+			JAL_OPCODE | JALR_OPCODE => {
+				let target = match word & 0x7f {
+					JAL_OPCODE => {
+						let imm = ((word & 0x80000000) as i32 >> 11) as u32 | (word & 0xff000) |
+							((word >> 9) & 0x800) | ((word >> 20) & 0x7fe);
+						pc.wrapping_add(imm as i32 as i64 as u64)
+					},
+					_ => (cpu.read_register(rs1).wrapping_add((word as i32 >> 20) as i64) as u64) & !1
+				};
+				let target = mask_address(target, cpu.get_xlen());
+				if word & 0x7f == JALR_OPCODE && is_link_register(rs1) && rd != rs1 {
+					self.return_to(target);
+				}
+				if is_link_register(rd) {
+					if self.frames.len() >= MAX_FRAMES {
+						self.frames.remove(0);
+					}
+					self.frames.push(Frame {
+						return_address: mask_address(pc.wrapping_add(length), cpu.get_xlen()),
+						slot_address: None,
+						attack: None
+					});
+				}
+			},
+			STORE_OPCODE if rs2 == 1 && funct3 == get_store_funct3(cpu.get_xlen()) => {
+				if let Some(frame) = self.frames.last_mut() {
+					if frame.slot_address.is_none() {
+						let imm = (((word as i32) >> 25) << 5) as i64 | ((word >> 7) & 0x1f) as i64;
+						let address = mask_address(cpu.read_register(rs1).wrapping_add(imm) as u64, cpu.get_xlen());
+						frame.slot_address = Some(address);
+						self.stored_slot = Some(address);
+					}
+				}
+			},
+			_ if word & UNZIP_MASK == UNZIP_DATA && self.get_pending_attack().is_some() => {
+				self.check = Some((ProtectionScheme::ZipperStack, pc, pc.wrapping_add(length)));
+			},
+			_ if word & SSPOPCHK_MASK == SSPOPCHK_DATA && is_link_register(rs1) && self.get_pending_attack().is_some() => {
+				self.check = Some((ProtectionScheme::ShadowStack, pc, pc.wrapping_add(length)));
+			},
+			_ => {}
+		};
+	}
+
+	fn inject(&mut self, cpu: &mut Cpu, slot_address: u64) {
+		if self.records.len() >= self.max_attacks || self.frames.is_empty() {
+			return;
+		}
+		let depth = self.frames.len();
+		let triggered = match self.trigger {
+			AttackTrigger::Depth(target_depth) => depth == target_depth,
+			AttackTrigger::Random(probability) => self.rng.gen::<f64>() < probability
+		};
+		if !triggered {
+			return;
+		}
+		let xlen = cpu.get_xlen().clone();
+		let address_mask = match xlen {
+			Xlen::Bit32 => 0xffffffff,
+			Xlen::Bit64 => RETURN_ADDRESS_MASK
+		};
+		let mmu = cpu.get_mut_mmu();
+		let original = match xlen {
+			Xlen::Bit32 => mmu.load_word(slot_address).map(|value| value as u64),
+			Xlen::Bit64 => mmu.load_doubleword(slot_address)
+		};
+		let original = match original {
+			Ok(value) => value,
+			Err(_) => return
+		};
+		let corrupted = match self.corruption {
+			Corruption::Redirect(address) => (original & !address_mask) | (address & address_mask),
+			Corruption::FlipBit => original ^ (1 << self.rng.gen_range(0..address_mask.count_ones()))
+		};
+		let result = match xlen {
+			Xlen::Bit32 => mmu.store_word(slot_address, corrupted as u32),
+			Xlen::Bit64 => mmu.store_doubleword(slot_address, corrupted)
+		};
+		if result.is_err() {
+			return;
+		}
+		self.records.push(AttackRecord {
+			depth,
+			slot_address,
+			original,
+			corrupted,
+			injected_at: self.instructions,
+			outcome: AttackOutcome::Pending
+		});
+		let index = self.records.len() - 1;
+		if let Some(frame) = self.frames.last_mut() {
+			frame.attack = Some(index);
+		}
+	}
+
+	fn return_to(&mut self, target: u64) {
+		let frame = match self.frames.pop() {
+			Some(frame) => frame,
+			None => return
+		};
+		let index = match frame.attack {
+			Some(index) if self.records[index].outcome == AttackOutcome::Pending => index,
+			_ => return
+		};
+		let latency = self.instructions - self.records[index].injected_at;
+		self.records[index].outcome = match target == frame.return_address {
+			true => AttackOutcome::Masked,
+			false => AttackOutcome::Hijacked {
+				target,
+				latency
+			}
+		};
+	}
+
+	// Index of the record if the current function's return address has
+	// been corrupted and nothing has become of it yet
+	fn get_pending_attack(&self) -> Option<usize> {
+		self.frames.last()
+			.and_then(|frame| frame.attack)
+			.filter(|index| self.records[*index].outcome == AttackOutcome::Pending)
+	}
+
+	fn detect(&mut self, scheme: ProtectionScheme, pc: u64) {
+		if let Some(index) = self.get_pending_attack() {
+			self.records[index].outcome = AttackOutcome::Detected {
+				scheme,
+				pc,
+				latency: self.instructions - self.records[index].injected_at
+			};
+		}
+	}
+}
+
+// ra or t0, the link registers of the standard calling convention
+fn is_link_register(register: u8) -> bool {
+	register == 1 || register == 5
+}
+
+// SW on RV32 and SD on RV64
+fn get_store_funct3(xlen: &Xlen) -> u32 {
+	match xlen {
+		Xlen::Bit32 => 2,
+		Xlen::Bit64 => 3
+	}
+}
+
+fn mask_address(address: u64, xlen: &Xlen) -> u64 {
+	match xlen {
+		Xlen::Bit32 => address & 0xffffffff,
+		Xlen::Bit64 => address
+	}
+}
+
+#[cfg(test)]
+mod test_attack_simulator {
+	use super::*;
+	use alloc::boxed::Box;
+	use mmu::DRAM_BASE;
+	use terminal::DummyTerminal;
+
+	fn run(code: &[u32], simulator: &mut AttackSimulator) {
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x200);
+		cpu.update_pc(DRAM_BASE);
+		cpu.write_register(2, (DRAM_BASE + 0x200) as i64);
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		for _ in 0..code.len() {
+			simulator.trace(&mut cpu);
+			cpu.tick();
+		}
+		simulator.trace(&mut cpu);
+	}
+
+	#[test]
+	fn attack() {
+		let gadget = DRAM_BASE + 0x100;
+		let code = [
+			0x00c000ef, // jal ra, func
+			0x00000013, // nop
+			0x0000006f, // j .
+			0xff010113, // func: addi sp, sp, -16
+			0x0000008b, // zip ra
+			0x00113423, // sd ra, 8(sp)
+			0x00813083, // ld ra, 8(sp)
+			0x0000108b, // unzip ra
+			0x01010113, // addi sp, sp, 16
+			0x00008067 // ret
+		];
+
+		// Zipper Stack detects the corruption at UNZIP
+		let mut simulator = AttackSimulator::new(AttackTrigger::Depth(1), Corruption::Redirect(gadget));
+		run(&code, &mut simulator);
+		let record = &simulator.get_records()[0];
+		assert_eq!(DRAM_BASE + 0x1f8, record.slot_address);
+		assert_eq!(gadget, record.corrupted & RETURN_ADDRESS_MASK);
+		assert_eq!(record.original & !RETURN_ADDRESS_MASK, record.corrupted & !RETURN_ADDRESS_MASK);
+		assert_eq!(AttackOutcome::Detected {
+			scheme: ProtectionScheme::ZipperStack,
+			pc: DRAM_BASE + 28,
+			latency: 2
+		}, record.outcome);
+
+		// Without ZIP and UNZIP, the function returns to the gadget
+		let mut unprotected = code;
+		unprotected[4] = 0x00000013;
+		unprotected[7] = 0x00000013;
+		let mut simulator = AttackSimulator::new(AttackTrigger::Depth(1), Corruption::Redirect(gadget));
+		run(&unprotected, &mut simulator);
+		assert_eq!(AttackOutcome::Hijacked {
+			target: gadget,
+			latency: 4
+		}, simulator.get_records()[0].outcome);
+
+		// No function at the depth
+		let mut simulator = AttackSimulator::new(AttackTrigger::Depth(2), Corruption::FlipBit);
+		run(&code, &mut simulator);
+		assert_eq!(AttackSummary::default(), simulator.get_summary());
+	}
+}
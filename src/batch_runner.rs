@@ -0,0 +1,328 @@
+extern crate regex_lite;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use self::regex_lite::Regex;
+use error::DebugError;
+use scripted_terminal::ScriptResult;
+use terminal::Terminal;
+use {Emulator, StopReason};
+
+/// Process exit code for a failed script and an emulator error
+pub const EXIT_CODE_FAILURE: i32 = 1;
+
+/// Process exit code for a console line matching a panic pattern
+pub const EXIT_CODE_PANIC: i32 = 2;
+
+/// Process exit code for the timeout, same as `timeout` command
+pub const EXIT_CODE_TIMEOUT: i32 = 124;
+
+// Cycles run between the checks of the outcome
+const SLICE_CYCLES: u64 = 0x10000;
+
+// Partial output line kept to match. Older data is dropped beyond this.
+const MAX_LINE_LENGTH: usize = 0x1000;
+
+/// How a batch run has ended, returned by `BatchRunner::run()`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchOutcome {
+	/// The program has exited or the machine has powered off with
+	/// the status code. Tests report pass or fail with it
+	Exited(u64),
+	/// All the steps of the console script have been done
+	ScriptPassed,
+	/// The console script has failed for the reason
+	ScriptFailed(String),
+	/// The console line has matched a panic pattern
+	Panicked(String),
+	/// The wall-clock timeout has expired
+	TimedOut,
+	/// The program has stopped for another reason, e.g. an emulator error
+	Stopped(StopReason)
+}
+
+impl BatchOutcome {
+	/// Returns the process exit code for the outcome. The status code
+	/// of the program, zero for a passed script, `EXIT_CODE_FAILURE` for
+	/// a failed script and the other stops, `EXIT_CODE_PANIC`, or
+	/// `EXIT_CODE_TIMEOUT`.
+	pub fn get_exit_code(&self) -> i32 {
+		match self {
+			BatchOutcome::Exited(code) => *code as i32,
+			BatchOutcome::ScriptPassed => 0,
+			BatchOutcome::ScriptFailed(_) | BatchOutcome::Stopped(_) => EXIT_CODE_FAILURE,
+			BatchOutcome::Panicked(_) => EXIT_CODE_PANIC,
+			BatchOutcome::TimedOut => EXIT_CODE_TIMEOUT
+		}
+	}
+}
+
+impl fmt::Display for BatchOutcome {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			BatchOutcome::Exited(code) => write!(f, "Exited with status code {}", code),
+			BatchOutcome::ScriptPassed => write!(f, "Script passed"),
+			BatchOutcome::ScriptFailed(message) => write!(f, "Script failed: {}", message),
+			BatchOutcome::Panicked(line) => write!(f, "Panicked: {}", line),
+			BatchOutcome::TimedOut => write!(f, "Timed out"),
+			BatchOutcome::Stopped(StopReason::Error(error)) => write!(f, "Emulator error: {}", error),
+			BatchOutcome::Stopped(StopReason::Stalled(address)) => write!(f, "Stalled at 0x{:x}", address),
+			BatchOutcome::Stopped(reason) => write!(f, "Stopped: {:?}", reason)
+		}
+	}
+}
+
+/// Runs a machine headless to an outcome which maps to a process exit
+/// code, so the emulator works as a test executor in CI. The run ends
+/// when the program exits or the machine powers off, the console script
+/// finishes, a console line matches a panic pattern, or the wall-clock
+/// timeout expires.
+///
+/// ```ignore
+/// let mut runner = BatchRunner::new();
+/// runner.set_timeout(Duration::from_secs(300));
+/// runner.add_panic_pattern("Kernel panic").unwrap();
+/// let terminal = runner.watch_terminal(Box::new(DummyTerminal::new()));
+/// let mut emulator = Emulator::new(terminal);
+/// // Set up the emulator
+/// let outcome = runner.run(&mut emulator);
+/// std::process::exit(outcome.get_exit_code());
+/// ```
+pub struct BatchRunner {
+	timeout: Option<Duration>,
+	script_result: Option<Rc<RefCell<ScriptResult>>>,
+	panic_patterns: Vec<Regex>,
+	/// The console line which has matched a panic pattern
+	panic_line: Rc<RefCell<Option<String>>>
+}
+
+impl BatchRunner {
+	/// Creates a new `BatchRunner` without timeout, script, and
+	/// panic patterns.
+	pub fn new() -> Self {
+		BatchRunner {
+			timeout: None,
+			script_result: None,
+			panic_patterns: vec![],
+			panic_line: Rc::new(RefCell::new(None))
+		}
+	}
+
+	/// Sets the wall-clock time the run is given.
+	///
+	/// # Arguments
+	/// * `timeout`
+	pub fn set_timeout(&mut self, timeout: Duration) {
+		self.timeout = Some(timeout);
+	}
+
+	/// Ends the run when the console script finishes.
+	///
+	/// # Arguments
+	/// * `script_result` Handle from `ScriptedTerminal::get_result()`
+	pub fn set_script_result(&mut self, script_result: Rc<RefCell<ScriptResult>>) {
+		self.script_result = Some(script_result);
+	}
+
+	/// Adds a regular expression of the console lines ending the run as
+	/// a panic, e.g. `Kernel panic`. Add the patterns before
+	/// `watch_terminal()`. Returns `Err` if the pattern is invalid.
+	///
+	/// # Arguments
+	/// * `pattern`
+	pub fn add_panic_pattern(&mut self, pattern: &str) -> Result<(), DebugError> {
+		let pattern = Regex::new(pattern)?;
+		self.panic_patterns.push(pattern);
+		Ok(())
+	}
+
+	/// Wraps the console terminal to match its output lines against
+	/// the panic patterns. The output and the input pass through.
+	///
+	/// # Arguments
+	/// * `terminal`
+	pub fn watch_terminal(&self, terminal: Box<dyn Terminal>) -> Box<dyn Terminal> {
+		match self.panic_patterns.is_empty() {
+			true => terminal,
+			false => Box::new(PanicWatchTerminal {
+				terminal,
+				patterns: self.panic_patterns.clone(),
+				line: vec![],
+				panic_line: self.panic_line.clone()
+			})
+		}
+	}
+
+	/// Runs the program until an outcome. The timeout counts from here.
+	///
+	/// # Arguments
+	/// * `emulator`
+	pub fn run(&mut self, emulator: &mut Emulator) -> BatchOutcome {
+		let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+		loop {
+			if let Some(reason) = emulator.run_program_cycles(SLICE_CYCLES) {
+				return match reason {
+					StopReason::Exited(code) => BatchOutcome::Exited(code),
+					reason => BatchOutcome::Stopped(reason)
+				};
+			}
+			if let Some(line) = self.panic_line.borrow_mut().take() {
+				return BatchOutcome::Panicked(line);
+			}
+			match self.script_result.as_ref().map(|result| result.borrow().clone()) {
+				Some(ScriptResult::Passed) => return BatchOutcome::ScriptPassed,
+				Some(ScriptResult::Failed(message)) => return BatchOutcome::ScriptFailed(message),
+				_ => {}
+			};
+			if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+				return BatchOutcome::TimedOut;
+			}
+		}
+	}
+}
+
+impl Default for BatchRunner {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// `Terminal` wrapper recording the first output line matching
+/// the patterns for `BatchRunner`.
+struct PanicWatchTerminal {
+	terminal: Box<dyn Terminal>,
+	patterns: Vec<Regex>,
+	line: Vec<u8>,
+	panic_line: Rc<RefCell<Option<String>>>
+}
+
+impl PanicWatchTerminal {
+	fn handle_output(&mut self, values: &[u8]) {
+		for value in values.iter() {
+			if *value != b'\n' {
+				self.line.push(*value);
+				if self.line.len() > MAX_LINE_LENGTH {
+					self.line.remove(0);
+				}
+				continue;
+			}
+			let line = String::from_utf8_lossy(&self.line).trim_end_matches('\r').to_string();
+			self.line.clear();
+			let mut panic_line = self.panic_line.borrow_mut();
+			if panic_line.is_none() && self.patterns.iter().any(|pattern| pattern.is_match(&line)) {
+				*panic_line = Some(line);
+			}
+		}
+	}
+}
+
+impl Terminal for PanicWatchTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.handle_output(&[value]);
+		self.terminal.put_byte(value);
+	}
+
+	fn get_output(&mut self) -> u8 {
+		self.terminal.get_output()
+	}
+
+	fn put_input(&mut self, data: u8) {
+		self.terminal.put_input(data);
+	}
+
+	fn get_input(&mut self) -> u8 {
+		self.terminal.get_input()
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		self.handle_output(values);
+		self.terminal.put_bytes(values);
+	}
+
+	fn get_output_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.terminal.get_output_bytes(buffer)
+	}
+
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		self.terminal.put_input_bytes(data);
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.terminal.get_input_bytes(buffer)
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.terminal.has_input()
+	}
+
+	fn get_window_size(&mut self) -> Option<(u16, u16)> {
+		self.terminal.get_window_size()
+	}
+
+	fn is_output_ready(&mut self) -> bool {
+		self.terminal.is_output_ready()
+	}
+}
+
+#[cfg(test)]
+mod test_batch_runner {
+	use super::*;
+	use capture_terminal::CaptureTerminal;
+	use config::EmulatorConfig;
+	use mmu::DRAM_BASE;
+
+	// Emulator running the code from the start of DRAM
+	fn create_emu(terminal: Box<dyn Terminal>, code: &[u32]) -> Emulator {
+		let mut emu = Emulator::new_with_config(terminal, EmulatorConfig::default());
+		let cpu = emu.get_mut_cpu();
+		cpu.get_mut_mmu().init_memory(0x100);
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		cpu.update_pc(DRAM_BASE);
+		emu
+	}
+
+	#[test]
+	fn exit_codes() {
+		// Fails with code 3 through the test finisher
+		let code = [
+			0x001005b7, // lui a1, 0x100 (test finisher)
+			0x00033537, // lui a0, 0x33
+			0x33350513, // addi a0, a0, 0x333
+			0x00a5a023 // sw a0, 0(a1)
+		];
+		let mut emu = create_emu(Box::new(CaptureTerminal::new()), &code);
+		let outcome = BatchRunner::new().run(&mut emu);
+		assert_eq!(BatchOutcome::Exited(3), outcome);
+		assert_eq!(3, outcome.get_exit_code());
+
+		// Loops forever
+		let mut emu = create_emu(Box::new(CaptureTerminal::new()), &[0x0000006f]);
+		let mut runner = BatchRunner::new();
+		runner.set_timeout(Duration::from_millis(10));
+		let outcome = runner.run(&mut emu);
+		assert_eq!(BatchOutcome::TimedOut, outcome);
+		assert_eq!(EXIT_CODE_TIMEOUT, outcome.get_exit_code());
+	}
+
+	#[test]
+	fn watch_terminal() {
+		let mut runner = BatchRunner::new();
+		assert!(matches!(runner.add_panic_pattern("panic("), Err(DebugError::InvalidPattern(_))));
+		assert_eq!(Ok(()), runner.add_panic_pattern("^Kernel panic"));
+		let mut terminal = runner.watch_terminal(Box::new(CaptureTerminal::new()));
+		terminal.put_bytes(b"Booting\r\nKernel panic - not syncing: VFS");
+		assert_eq!(None, *runner.panic_line.borrow());
+		terminal.put_bytes(b"\r\n");
+		let mut emu = create_emu(terminal, &[0x0000006f]);
+		let outcome = runner.run(&mut emu);
+		assert_eq!(BatchOutcome::Panicked("Kernel panic - not syncing: VFS".to_string()), outcome);
+		assert_eq!(EXIT_CODE_PANIC, outcome.get_exit_code());
+	}
+}
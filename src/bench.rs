@@ -0,0 +1,334 @@
+extern crate regex_lite;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use self::regex_lite::Regex;
+use error::DebugError;
+use speed_meter::SpeedMeter;
+use terminal::Terminal;
+use thiserror::Error;
+use {Emulator, StopReason};
+
+// Cycles run between the checks of the timeout
+const SLICE_CYCLES: u64 = 0x10000;
+
+// Guest output kept to find the score in. Older data is dropped beyond this.
+const MAX_OUTPUT_LENGTH: usize = 0x10000;
+
+// Scores printed by Dhrystone and CoreMark
+const DHRYSTONE_PATTERN: &str = r"Dhrystones per Second:\s*([0-9.]+)";
+const COREMARK_PATTERN: &str = r"Iterations/Sec\s*:\s*([0-9.]+)";
+
+/// Guest benchmark, telling how its score is read from the output with
+/// the regular expression compiled when the kind is built.
+#[derive(Clone, Debug)]
+pub enum BenchmarkKind {
+	/// Dhrystone of riscv-tests or the original one, printing
+	/// `Dhrystones per Second:`. Built with `dhrystone()`
+	Dhrystone(Regex),
+	/// CoreMark, printing `Iterations/Sec :`. Built with `coremark()`
+	CoreMark(Regex),
+	/// Embench IoT benchmark. It prints nothing and reports the result
+	/// only with the status code, so it has no score and the wall-clock
+	/// time is what to compare
+	Embench,
+	/// Other benchmark with the regular expression whose first capture
+	/// group is the score in the output
+	Custom(Regex)
+}
+
+impl BenchmarkKind {
+	/// Returns the kind of Dhrystone.
+	pub fn dhrystone() -> Self {
+		BenchmarkKind::Dhrystone(Regex::new(DHRYSTONE_PATTERN).unwrap())
+	}
+
+	/// Returns the kind of CoreMark.
+	pub fn coremark() -> Self {
+		BenchmarkKind::CoreMark(Regex::new(COREMARK_PATTERN).unwrap())
+	}
+
+	/// Returns the kind by the name, `dhrystone`, `coremark`, or
+	/// `embench`. Returns `Err` for the other names.
+	///
+	/// # Arguments
+	/// * `name`
+	pub fn from_name(name: &str) -> Result<Self, DebugError> {
+		match name {
+			"dhrystone" => Ok(Self::dhrystone()),
+			"coremark" => Ok(Self::coremark()),
+			"embench" => Ok(BenchmarkKind::Embench),
+			_ => Err(DebugError::UnknownBenchmark(name.to_string()))
+		}
+	}
+
+	/// Returns the kind of a benchmark with the regular expression whose
+	/// first capture group is the score. Returns `Err` if the pattern is
+	/// invalid or has no capture group.
+	///
+	/// # Arguments
+	/// * `pattern` e.g. `Score: ([0-9.]+)`
+	pub fn custom(pattern: &str) -> Result<Self, DebugError> {
+		let pattern = Regex::new(pattern)?;
+		match pattern.captures_len() > 1 {
+			true => Ok(BenchmarkKind::Custom(pattern)),
+			false => Err(DebugError::NoCaptureGroup)
+		}
+	}
+
+	fn get_pattern(&self) -> Option<&Regex> {
+		match self {
+			BenchmarkKind::Dhrystone(pattern) | BenchmarkKind::CoreMark(pattern) | BenchmarkKind::Custom(pattern) => Some(pattern),
+			BenchmarkKind::Embench => None
+		}
+	}
+
+	/// Returns the unit of the score.
+	pub fn get_unit(&self) -> &'static str {
+		match self {
+			BenchmarkKind::Dhrystone(_) => "Dhrystones/s",
+			BenchmarkKind::CoreMark(_) => "iterations/s",
+			BenchmarkKind::Embench | BenchmarkKind::Custom(_) => ""
+		}
+	}
+
+	/// Finds the score in the guest output. Returns the last one if
+	/// the output has several.
+	///
+	/// # Arguments
+	/// * `output`
+	pub fn parse_score(&self, output: &str) -> Option<f64> {
+		let pattern = self.get_pattern()?;
+		pattern.captures_iter(output)
+			.filter_map(|captures| captures.get(1))
+			.filter_map(|score| score.as_str().parse::<f64>().ok())
+			.last()
+	}
+}
+
+/// Why `Benchmark::run()` has failed.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum BenchError {
+	/// The benchmark has exited with the non-zero status code, e.g. on
+	/// failing its self check
+	#[error("Benchmark failed with status code {0}")]
+	Failed(u64),
+	/// The program has stopped before exiting, e.g. on an emulator error
+	#[error("Benchmark stopped: {0:?}")]
+	Stopped(StopReason),
+	/// The wall-clock timeout has expired
+	#[error("Benchmark timed out")]
+	TimedOut,
+	/// The output has no score of the benchmark
+	#[error("No score in the benchmark output")]
+	NoScore
+}
+
+/// Result of a benchmark run, returned by `Benchmark::run()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BenchReport {
+	/// Score the guest has reported. `None` for Embench
+	pub score: Option<f64>,
+	/// Unit of the score
+	pub unit: &'static str,
+	/// Instructions run
+	pub instructions: u64,
+	/// Wall-clock time of the run
+	pub elapsed: Duration,
+	/// Million instructions per second of wall-clock time
+	pub mips: f64
+}
+
+impl fmt::Display for BenchReport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if let Some(score) = self.score {
+			writeln!(f, "Score: {} {}", score, self.unit)?;
+		}
+		write!(f, "{} instructions in {:.3} s, {:.2} MIPS", self.instructions, self.elapsed.as_secs_f64(), self.mips)
+	}
+}
+
+/// Runs a guest benchmark to completion and reports the score it prints
+/// with the host-side speed, to track performance across emulator
+/// changes. The benchmark is a bare-metal or `riscv-pk` program printing
+/// through HTIF and exiting with zero on success, like the benchmarks of
+/// riscv-tests. Build Dhrystone, CoreMark, or Embench for them, or bring
+/// another one with `BenchmarkKind::custom()`.
+///
+/// ```ignore
+/// let benchmark = Benchmark::new(BenchmarkKind::coremark());
+/// let terminal = benchmark.watch_terminal(Box::new(DummyTerminal::new()));
+/// let mut emulator = Emulator::new(terminal);
+/// emulator.setup_htif(vec!["coremark.riscv".to_string()]);
+/// emulator.setup_program(data)?;
+/// println!("{}", benchmark.run(&mut emulator)?);
+/// ```
+pub struct Benchmark {
+	kind: BenchmarkKind,
+	timeout: Option<Duration>,
+	output: Rc<RefCell<Vec<u8>>>
+}
+
+impl Benchmark {
+	/// Creates a new `Benchmark` without timeout.
+	///
+	/// # Arguments
+	/// * `kind`
+	pub fn new(kind: BenchmarkKind) -> Self {
+		Benchmark {
+			kind,
+			timeout: None,
+			output: Rc::new(RefCell::new(vec![]))
+		}
+	}
+
+	/// Sets the wall-clock time the run is given.
+	///
+	/// # Arguments
+	/// * `timeout`
+	pub fn set_timeout(&mut self, timeout: Duration) {
+		self.timeout = Some(timeout);
+	}
+
+	/// Wraps the console terminal to keep the guest output for the score.
+	/// The output and the input pass through.
+	///
+	/// # Arguments
+	/// * `terminal`
+	pub fn watch_terminal(&self, terminal: Box<dyn Terminal>) -> Box<dyn Terminal> {
+		Box::new(BenchWatchTerminal {
+			terminal,
+			output: self.output.clone()
+		})
+	}
+
+	/// Runs the benchmark set up on the emulator until it exits, counting
+	/// the instructions with a new speed meter replacing the emulator's.
+	/// Returns `Err` if the benchmark doesn't exit with zero or prints no
+	/// score.
+	///
+	/// # Arguments
+	/// * `emulator`
+	pub fn run(&self, emulator: &mut Emulator) -> Result<BenchReport, BenchError> {
+		self.output.borrow_mut().clear();
+		emulator.set_speed_meter(SpeedMeter::new());
+		let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+		let reason = loop {
+			if let Some(reason) = emulator.run_program_cycles(SLICE_CYCLES) {
+				break reason;
+			}
+			if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+				return Err(BenchError::TimedOut);
+			}
+		};
+		let speed = emulator.take_speed_meter().unwrap().get_speed();
+		match reason {
+			StopReason::Exited(0) => {},
+			StopReason::Exited(code) => return Err(BenchError::Failed(code)),
+			reason => return Err(BenchError::Stopped(reason))
+		};
+		let score = match self.kind {
+			BenchmarkKind::Embench => None,
+			_ => {
+				let output = String::from_utf8_lossy(&self.output.borrow()).to_string();
+				Some(self.kind.parse_score(&output).ok_or(BenchError::NoScore)?)
+			}
+		};
+		Ok(BenchReport {
+			score,
+			unit: self.kind.get_unit(),
+			instructions: speed.instructions,
+			elapsed: speed.elapsed,
+			mips: speed.average_mips
+		})
+	}
+}
+
+/// `Terminal` wrapper keeping the guest output for `Benchmark`.
+struct BenchWatchTerminal {
+	terminal: Box<dyn Terminal>,
+	output: Rc<RefCell<Vec<u8>>>
+}
+
+impl BenchWatchTerminal {
+	fn handle_output(&mut self, values: &[u8]) {
+		let mut output = self.output.borrow_mut();
+		output.extend_from_slice(values);
+		if output.len() > MAX_OUTPUT_LENGTH {
+			let excess = output.len() - MAX_OUTPUT_LENGTH;
+			output.drain(..excess);
+		}
+	}
+}
+
+impl Terminal for BenchWatchTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.handle_output(&[value]);
+		self.terminal.put_byte(value);
+	}
+
+	fn get_output(&mut self) -> u8 {
+		self.terminal.get_output()
+	}
+
+	fn put_input(&mut self, data: u8) {
+		self.terminal.put_input(data);
+	}
+
+	fn get_input(&mut self) -> u8 {
+		self.terminal.get_input()
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		self.handle_output(values);
+		self.terminal.put_bytes(values);
+	}
+
+	fn get_output_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.terminal.get_output_bytes(buffer)
+	}
+
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		self.terminal.put_input_bytes(data);
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.terminal.get_input_bytes(buffer)
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.terminal.has_input()
+	}
+
+	fn get_window_size(&mut self) -> Option<(u16, u16)> {
+		self.terminal.get_window_size()
+	}
+
+	fn is_output_ready(&mut self) -> bool {
+		self.terminal.is_output_ready()
+	}
+}
+
+#[cfg(test)]
+mod test_bench {
+	use super::*;
+
+	#[test]
+	fn parse_score() {
+		let output = "Microseconds for one run through Dhrystone: 2\r\nDhrystones per Second:      452488\r\n";
+		assert_eq!(Some(452488.0), BenchmarkKind::dhrystone().parse_score(output));
+		let output = "CoreMark Size    : 666\nIterations/Sec   : 1234.567\nIterations       : 10\n";
+		assert_eq!(Some(1234.567), BenchmarkKind::coremark().parse_score(output));
+		assert_eq!(None, BenchmarkKind::coremark().parse_score("Errors detected\n"));
+		assert_eq!(None, BenchmarkKind::Embench.parse_score("Iterations/Sec : 1\n"));
+
+		assert_eq!(Some(DebugError::NoCaptureGroup), BenchmarkKind::custom("Score: [0-9]+").err());
+		let kind = BenchmarkKind::custom(r"Score: ([0-9]+)").unwrap();
+		assert_eq!(Some(2.0), kind.parse_score("Score: 1\nScore: 2\n"));
+
+		assert_eq!(Some(DebugError::UnknownBenchmark("whetstone".to_string())), BenchmarkKind::from_name("whetstone").err());
+	}
+}
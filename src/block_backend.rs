@@ -0,0 +1,298 @@
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+#[cfg(feature = "host")]
+use std::fs::File;
+#[cfg(feature = "host")]
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use collections::FnvHashMap;
+use error::DeviceError;
+
+/// Sector size in bytes. Disk is accessed in sector granularity.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Block backend which stores disk content for the virtio block device.
+/// Buffer length passed to `read()` and `write()` must be multiple of
+/// `SECTOR_SIZE`. Implement this trait to back the disk with your own
+/// storage, e.g. IndexedDB or HTTP range requests on WASM.
+pub trait BlockBackend {
+	/// Returns the disk size in sectors.
+	fn get_sector_num(&self) -> u64;
+
+	/// Reads sectors from disk.
+	///
+	/// # Arguments
+	/// * `sector` The first sector to read
+	/// * `buffer`
+	fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), DeviceError>;
+
+	/// Writes sectors to disk.
+	///
+	/// # Arguments
+	/// * `sector` The first sector to write
+	/// * `data`
+	fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), DeviceError>;
+
+	/// Makes sure written data reaches the underlying storage.
+	fn flush(&mut self) -> Result<(), DeviceError>;
+}
+
+/// Checks whether the access is in the disk range and sector aligned.
+/// Returns `DeviceError::OutOfDisk` if not.
+pub(crate) fn check_access(sector_num: u64, sector: u64, length: usize) -> Result<(), DeviceError> {
+	let valid = (length as u64).is_multiple_of(SECTOR_SIZE) &&
+		sector.checked_add(length as u64 / SECTOR_SIZE)
+			.is_some_and(|end| end <= sector_num);
+	match valid {
+		true => Ok(()),
+		false => Err(DeviceError::OutOfDisk {
+			sector,
+			length: length as u64
+		})
+	}
+}
+
+/// `BlockBackend` holding the whole disk content in memory.
+pub struct MemoryBlockBackend {
+	contents: Vec<u8>
+}
+
+impl MemoryBlockBackend {
+	/// Creates a new `MemoryBlockBackend`. Trailing bytes which don't fill
+	/// a sector are inaccessible.
+	///
+	/// # Arguments
+	/// * `contents` Disk content binary
+	pub fn new(contents: Vec<u8>) -> Self {
+		MemoryBlockBackend {
+			contents
+		}
+	}
+}
+
+impl BlockBackend for MemoryBlockBackend {
+	fn get_sector_num(&self) -> u64 {
+		self.contents.len() as u64 / SECTOR_SIZE
+	}
+
+	fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), DeviceError> {
+		check_access(self.get_sector_num(), sector, buffer.len())?;
+		let start = (sector * SECTOR_SIZE) as usize;
+		buffer.copy_from_slice(&self.contents[start..start + buffer.len()]);
+		Ok(())
+	}
+
+	fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), DeviceError> {
+		check_access(self.get_sector_num(), sector, data.len())?;
+		let start = (sector * SECTOR_SIZE) as usize;
+		self.contents[start..start + data.len()].copy_from_slice(data);
+		Ok(())
+	}
+
+	fn flush(&mut self) -> Result<(), DeviceError> {
+		Ok(())
+	}
+}
+
+/// `BlockBackend` reading and writing a host file on demand so that
+/// large disk images don't need to be loaded into memory. If the file
+/// is opened read-only writes fail. Combine with `OverlayBlockBackend`
+/// to let the guest write without modifying the file.
+#[cfg(feature = "host")]
+pub struct FileBlockBackend {
+	file: File,
+	sector_num: u64
+}
+
+#[cfg(feature = "host")]
+impl FileBlockBackend {
+	/// Creates a new `FileBlockBackend`.
+	///
+	/// # Arguments
+	/// * `file` Disk image file
+	pub fn new(file: File) -> io::Result<Self> {
+		let sector_num = file.metadata()?.len() / SECTOR_SIZE;
+		Ok(FileBlockBackend {
+			file,
+			sector_num
+		})
+	}
+}
+
+#[cfg(feature = "host")]
+impl BlockBackend for FileBlockBackend {
+	fn get_sector_num(&self) -> u64 {
+		self.sector_num
+	}
+
+	fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), DeviceError> {
+		check_access(self.get_sector_num(), sector, buffer.len())?;
+		self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))
+			.and_then(|_| self.file.read_exact(buffer))
+			.map_err(DeviceError::Backend)
+	}
+
+	fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), DeviceError> {
+		check_access(self.get_sector_num(), sector, data.len())?;
+		self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE))
+			.and_then(|_| self.file.write_all(data))
+			.map_err(DeviceError::Backend)
+	}
+
+	fn flush(&mut self) -> Result<(), DeviceError> {
+		self.file.sync_data().map_err(DeviceError::Backend)
+	}
+}
+
+/// `BlockBackend` which never writes to the base backend. Written sectors
+/// are kept in memory and take precedence over the base content on read,
+/// so the guest sees a writable disk while the base image stays intact.
+/// Use `SharedBlockBackend` as the base to let many emulator instances
+/// share one base image, or `Qcow2BlockBackend` with a backing file
+/// to keep the written sectors in a file.
+pub struct OverlayBlockBackend {
+	base: Box<dyn BlockBackend>,
+	/// Sector number -> sector content written by the guest
+	sectors: FnvHashMap<u64, Vec<u8>>
+}
+
+impl OverlayBlockBackend {
+	/// Creates a new `OverlayBlockBackend`.
+	///
+	/// # Arguments
+	/// * `base` Read-only base backend
+	pub fn new(base: Box<dyn BlockBackend>) -> Self {
+		OverlayBlockBackend {
+			base,
+			sectors: FnvHashMap::default()
+		}
+	}
+}
+
+impl BlockBackend for OverlayBlockBackend {
+	fn get_sector_num(&self) -> u64 {
+		self.base.get_sector_num()
+	}
+
+	fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), DeviceError> {
+		check_access(self.get_sector_num(), sector, buffer.len())?;
+		self.base.read(sector, buffer)?;
+		for (i, chunk) in buffer.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+			if let Some(data) = self.sectors.get(&(sector + i as u64)) {
+				chunk.copy_from_slice(data);
+			}
+		}
+		Ok(())
+	}
+
+	fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), DeviceError> {
+		check_access(self.get_sector_num(), sector, data.len())?;
+		for (i, chunk) in data.chunks(SECTOR_SIZE as usize).enumerate() {
+			self.sectors.insert(sector + i as u64, chunk.to_vec());
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self) -> Result<(), DeviceError> {
+		Ok(())
+	}
+}
+
+/// `BlockBackend` shared among multiple owners, e.g. one pristine base
+/// image used by many emulator instances via `OverlayBlockBackend`.
+/// Cloning shares the same storage.
+#[derive(Clone)]
+pub struct SharedBlockBackend {
+	backend: Rc<RefCell<Box<dyn BlockBackend>>>
+}
+
+impl SharedBlockBackend {
+	/// Creates a new `SharedBlockBackend`.
+	///
+	/// # Arguments
+	/// * `backend` Shared storage
+	pub fn new(backend: Box<dyn BlockBackend>) -> Self {
+		SharedBlockBackend {
+			backend: Rc::new(RefCell::new(backend))
+		}
+	}
+}
+
+impl BlockBackend for SharedBlockBackend {
+	fn get_sector_num(&self) -> u64 {
+		self.backend.borrow().get_sector_num()
+	}
+
+	fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), DeviceError> {
+		self.backend.borrow_mut().read(sector, buffer)
+	}
+
+	fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), DeviceError> {
+		self.backend.borrow_mut().write(sector, data)
+	}
+
+	fn flush(&mut self) -> Result<(), DeviceError> {
+		self.backend.borrow_mut().flush()
+	}
+}
+
+#[cfg(test)]
+mod test_block_backend {
+	use super::*;
+
+	#[test]
+	fn memory_block_backend() {
+		let mut backend = MemoryBlockBackend::new(vec![0; 1024 + 100]);
+		assert_eq!(2, backend.get_sector_num());
+		let data = vec![0x55; 512];
+		assert!(backend.write(1, &data).is_ok());
+		let mut buffer = vec![0; 1024];
+		assert!(backend.read(0, &mut buffer).is_ok());
+		assert_eq!(vec![0; 512], buffer[0..512].to_vec());
+		assert_eq!(data, buffer[512..1024].to_vec());
+		// Out of range
+		assert_eq!(Err(DeviceError::OutOfDisk {
+			sector: 2,
+			length: 512
+		}), backend.read(2, &mut buffer[0..512]));
+		assert!(backend.write(1, &buffer).is_err());
+		// Not sector aligned
+		assert!(backend.read(0, &mut buffer[0..100]).is_err());
+	}
+
+	#[test]
+	fn overlay_block_backend() {
+		let mut backend = OverlayBlockBackend::new(Box::new(MemoryBlockBackend::new(vec![0x11; 1536])));
+		assert_eq!(3, backend.get_sector_num());
+		let data = vec![0x22; 512];
+		assert!(backend.write(1, &data).is_ok());
+		let mut buffer = vec![0; 1536];
+		assert!(backend.read(0, &mut buffer).is_ok());
+		assert_eq!(vec![0x11; 512], buffer[0..512].to_vec());
+		assert_eq!(data, buffer[512..1024].to_vec());
+		assert_eq!(vec![0x11; 512], buffer[1024..1536].to_vec());
+		assert!(backend.write(3, &data).is_err());
+		// Base content is intact
+		let mut base_buffer = vec![0; 512];
+		assert!(backend.base.read(1, &mut base_buffer).is_ok());
+		assert_eq!(vec![0x11; 512], base_buffer);
+	}
+
+	#[test]
+	fn shared_block_backend() {
+		let base = SharedBlockBackend::new(Box::new(MemoryBlockBackend::new(vec![0x11; 1024])));
+		let mut backend1 = OverlayBlockBackend::new(Box::new(base.clone()));
+		let mut backend2 = OverlayBlockBackend::new(Box::new(base.clone()));
+		assert!(backend1.write(0, &vec![0x22; 512]).is_ok());
+		assert!(backend2.write(1, &vec![0x33; 512]).is_ok());
+		let mut buffer = vec![0; 1024];
+		assert!(backend1.read(0, &mut buffer).is_ok());
+		assert_eq!(vec![0x22; 512], buffer[0..512].to_vec());
+		assert_eq!(vec![0x11; 512], buffer[512..1024].to_vec());
+		assert!(backend2.read(0, &mut buffer).is_ok());
+		assert_eq!(vec![0x11; 512], buffer[0..512].to_vec());
+		assert_eq!(vec![0x33; 512], buffer[512..1024].to_vec());
+	}
+}
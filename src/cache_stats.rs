@@ -0,0 +1,228 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use io::{self, Write};
+
+use cpu::Cpu;
+
+// Column names of the counters in the order of read_counters()
+const COUNTER_NAMES: [&str; 6] = [
+	"decode_cache_hits",
+	"decode_cache_misses",
+	"page_cache_hits",
+	"page_cache_misses",
+	"page_walks",
+	"page_cache_flushes"
+];
+
+/// Cache counters over a period, kept by `CacheStats`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheSample {
+	/// Instructions run since the statistics are set, at the end of
+	/// the period
+	pub instructions: u64,
+	/// Instructions in the period. The last period can be shorter
+	pub period_instructions: u64,
+	pub decode_cache_hits: u64,
+	pub decode_cache_misses: u64,
+	/// Address translations served by the page cache
+	pub page_cache_hits: u64,
+	/// Counted only while the page cache is enabled
+	pub page_cache_misses: u64,
+	/// Page table walks
+	pub page_walks: u64,
+	pub page_cache_flushes: u64
+}
+
+impl CacheSample {
+	fn get_counters(&self) -> [u64; 6] {
+		[
+			self.decode_cache_hits,
+			self.decode_cache_misses,
+			self.page_cache_hits,
+			self.page_cache_misses,
+			self.page_walks,
+			self.page_cache_flushes
+		]
+	}
+}
+
+/// Samples the counters of the decode cache and the address translation
+/// page cache every interval instructions, so workload phases can be
+/// plotted from the samples instead of one number at the end. Each sample
+/// has the counts over its period. Cycles waiting for interrupts aren't
+/// counted as instructions. Set to `Emulator` with `set_cache_stats()`.
+pub struct CacheStats {
+	interval: u64,
+	instructions: u64,
+	// Counters and instructions at the end of the last period. None
+	// until the first instruction
+	last_counters: Option<[u64; 6]>,
+	last_instructions: u64,
+	samples: Vec<CacheSample>
+}
+
+impl CacheStats {
+	/// Creates a new `CacheStats`.
+	///
+	/// # Arguments
+	/// * `interval` Instructions per sample, one or more
+	pub fn new(interval: u64) -> Self {
+		CacheStats {
+			interval: interval.max(1),
+			instructions: 0,
+			last_counters: None,
+			last_instructions: 0,
+			samples: vec![]
+		}
+	}
+
+	/// Counts the instruction the CPU is about to run and takes a sample
+	/// at the end of a period. Call this before every `Cpu::tick()`.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn sample(&mut self, cpu: &Cpu) {
+		if cpu.is_waiting_for_interrupt() {
+			return;
+		}
+		if self.instructions - self.last_instructions == self.interval || self.last_counters.is_none() {
+			self.push_sample(cpu);
+		}
+		self.instructions += 1;
+	}
+
+	/// Takes a sample of the period ending now even if it's shorter than
+	/// the interval, e.g. on exit. Does nothing if no instruction has run
+	/// since the last sample.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn flush(&mut self, cpu: &Cpu) {
+		if self.instructions > self.last_instructions {
+			self.push_sample(cpu);
+		}
+	}
+
+	fn push_sample(&mut self, cpu: &Cpu) {
+		let counters = read_counters(cpu);
+		if let Some(last_counters) = self.last_counters {
+			let mut deltas = [0; 6];
+			for (i, delta) in deltas.iter_mut().enumerate() {
+				*delta = counters[i] - last_counters[i];
+			}
+			self.samples.push(CacheSample {
+				instructions: self.instructions,
+				period_instructions: self.instructions - self.last_instructions,
+				decode_cache_hits: deltas[0],
+				decode_cache_misses: deltas[1],
+				page_cache_hits: deltas[2],
+				page_cache_misses: deltas[3],
+				page_walks: deltas[4],
+				page_cache_flushes: deltas[5]
+			});
+		}
+		self.last_counters = Some(counters);
+		self.last_instructions = self.instructions;
+	}
+
+	/// Returns the samples taken so far, the oldest first.
+	pub fn get_samples(&self) -> &[CacheSample] {
+		&self.samples
+	}
+
+	/// Writes the samples in CSV with a header line.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn write_csv(&self, writer: &mut dyn Write) -> io::Result<()> {
+		writeln!(writer, "instructions,period_instructions,{}", COUNTER_NAMES.join(","))?;
+		for sample in self.samples.iter() {
+			let counters = sample.get_counters().iter()
+				.map(|count| count.to_string())
+				.collect::<Vec<String>>()
+				.join(",");
+			writeln!(writer, "{},{},{}", sample.instructions, sample.period_instructions, counters)?;
+		}
+		writer.flush()
+	}
+
+	/// Writes the samples in JSON, an array of objects keyed by
+	/// the CSV column names.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn write_json(&self, writer: &mut dyn Write) -> io::Result<()> {
+		let samples = self.samples.iter().map(|sample| {
+			let counters = COUNTER_NAMES.iter().zip(sample.get_counters().iter())
+				.map(|(name, count)| format!("\"{}\":{}", name, count))
+				.collect::<Vec<String>>()
+				.join(",");
+			format!("{{\"instructions\":{},\"period_instructions\":{},{}}}",
+				sample.instructions, sample.period_instructions, counters)
+		}).collect::<Vec<String>>().join(",");
+		writeln!(writer, "[{}]", samples)?;
+		writer.flush()
+	}
+}
+
+fn read_counters(cpu: &Cpu) -> [u64; 6] {
+	let (decode_cache_hits, decode_cache_misses) = cpu.get_decode_cache_stats();
+	let translation_stats = cpu.get_mmu().get_translation_stats();
+	[
+		decode_cache_hits,
+		decode_cache_misses,
+		translation_stats.page_cache_hits,
+		translation_stats.page_cache_misses,
+		translation_stats.page_walks,
+		translation_stats.page_cache_flushes
+	]
+}
+
+#[cfg(test)]
+mod test_cache_stats {
+	use super::*;
+	use alloc::boxed::Box;
+	use mmu::DRAM_BASE;
+	use terminal::DummyTerminal;
+
+	#[test]
+	fn sample() {
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x100);
+		cpu.update_pc(DRAM_BASE);
+		let code = [
+			0x00150513, // addi a0, a0, 1
+			0x00150513, // addi a0, a0, 1
+			0x0000006f // loop: jal zero, loop
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		let mut stats = CacheStats::new(2);
+		for _ in 0..5 {
+			stats.sample(&cpu);
+			cpu.tick();
+		}
+		stats.flush(&cpu);
+		stats.flush(&cpu);
+		let samples = stats.get_samples();
+		assert_eq!(vec![(2, 2), (4, 2), (5, 1)], samples.iter()
+			.map(|sample| (sample.instructions, sample.period_instructions))
+			.collect::<Vec<(u64, u64)>>());
+		// The first addi and jal miss the decode cache
+		assert_eq!((1, 1), (samples[0].decode_cache_hits, samples[0].decode_cache_misses));
+		assert_eq!((1, 1), (samples[1].decode_cache_hits, samples[1].decode_cache_misses));
+		assert_eq!((1, 0), (samples[2].decode_cache_hits, samples[2].decode_cache_misses));
+
+		let mut output = vec![];
+		assert!(stats.write_csv(&mut output).is_ok());
+		assert_eq!("instructions,period_instructions,decode_cache_hits,decode_cache_misses,page_cache_hits,page_cache_misses,page_walks,page_cache_flushes\n2,2,1,1,0,0,0,0\n4,2,1,1,0,0,0,0\n5,1,1,0,0,0,0,0\n",
+			String::from_utf8_lossy(&output));
+		let mut output = vec![];
+		assert!(stats.write_json(&mut output).is_ok());
+		assert!(String::from_utf8_lossy(&output).starts_with("[{\"instructions\":2,\"period_instructions\":2,\"decode_cache_hits\":1,\"decode_cache_misses\":1,"));
+	}
+}
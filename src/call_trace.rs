@@ -0,0 +1,206 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+
+use io::Write;
+
+use cpu::{Cpu, Xlen};
+use symbol_table::SymbolTable;
+
+const JAL_OPCODE: u32 = 0x6f;
+const JALR_OPCODE: u32 = 0x67;
+
+// Indentation per call depth
+const INDENT_WIDTH: usize = 2;
+
+/// Streams a function call and return trace of the guest to a writer,
+/// indented by the call depth like `ftrace` function graph.
+///
+/// ```text
+/// main() {
+///   printf() {
+///     write() {
+///     }
+///   }
+/// }
+/// ```
+///
+/// Calls are JAL and JALR linking to `ra` or `t0`, and returns are JALR
+/// through them, following the hints in the RISC-V spec. Tail calls don't
+/// appear, and the depth doesn't follow traps and context switches. Function names are looked up in the symbols loaded by the time
+/// the trace is set to `Emulator` with `set_call_trace()`. An address
+/// without a symbol is shown in hexadecimal, with the offset from
+/// the nearest symbol before it if any. Errors on writing are ignored
+/// not to disturb the guest.
+pub struct CallTrace {
+	writer: Box<dyn Write>,
+	running: bool,
+	depth: usize,
+	symbol_table: Option<SymbolTable>
+}
+
+impl CallTrace {
+	/// Creates a new `CallTrace`. Tracing starts immediately.
+	///
+	/// # Arguments
+	/// * `writer` Where the trace is written
+	pub fn new(writer: Box<dyn Write>) -> Self {
+		CallTrace {
+			writer,
+			running: true,
+			depth: 0,
+			symbol_table: None
+		}
+	}
+
+	/// Sets the symbols function names are looked up in.
+	///
+	/// # Arguments
+	/// * `symbol_table`
+	pub fn set_symbol_table(&mut self, symbol_table: SymbolTable) {
+		self.symbol_table = Some(symbol_table);
+	}
+
+	/// Starts tracing. The call depth starts from zero again.
+	pub fn start(&mut self) {
+		self.running = true;
+		self.depth = 0;
+	}
+
+	/// Stops tracing and flushes the writer.
+	pub fn stop(&mut self) {
+		self.running = false;
+		let _ = self.writer.flush();
+	}
+
+	/// Returns `true` if tracing is running.
+	pub fn is_running(&self) -> bool {
+		self.running
+	}
+
+	/// Returns the name of the function at the address.
+	///
+	/// # Arguments
+	/// * `address` Virtual address
+	pub fn get_function_name(&self, address: u64) -> String {
+		match self.symbol_table.as_ref().and_then(|symbol_table| symbol_table.lookup(address)) {
+			Some((name, 0)) => name.to_string(),
+			Some((name, offset)) => format!("{}+0x{:x}", name, offset),
+			None => format!("0x{:x}", address)
+		}
+	}
+
+	/// Records the instruction the CPU is about to run if it calls or
+	/// returns from a function. Call this before every `Cpu::tick()`.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn trace(&mut self, cpu: &mut Cpu) {
+		if !self.running || cpu.is_waiting_for_interrupt() {
+			return;
+		}
+		let word = match cpu.fetch_next_instruction() {
+			Some((word, _length)) => word,
+			None => return
+		};
+		let opcode = word & 0x7f;
+		if opcode != JAL_OPCODE && opcode != JALR_OPCODE {
+			return;
+		}
+		let rd = ((word >> 7) & 0x1f) as u8;
+		let rs1 = ((word >> 15) & 0x1f) as u8;
+		let pc = cpu.read_pc();
+		let target = match opcode {
+			JAL_OPCODE => {
+				let imm = ((word & 0x80000000) as i32 >> 11) as u32 | (word & 0xff000) |
+					((word >> 9) & 0x800) | ((word >> 20) & 0x7fe);
+				pc.wrapping_add(imm as i32 as i64 as u64)
+			},
+			_ => {
+				let imm = (word as i32 >> 20) as i64;
+				(cpu.read_register(rs1).wrapping_add(imm) as u64) & !1
+			}
+		};
+		let target = match cpu.get_xlen() {
+			Xlen::Bit32 => target & 0xffffffff,
+			Xlen::Bit64 => target
+		};
+		let is_return = opcode == JALR_OPCODE && is_link_register(rs1) && rd != rs1;
+		if is_return {
+			self.depth = self.depth.saturating_sub(1);
+			self.write_line("}");
+		}
+		if is_link_register(rd) {
+			let line = format!("{}() {{", self.get_function_name(target));
+			self.write_line(&line);
+			self.depth += 1;
+		}
+	}
+
+	fn write_line(&mut self, line: &str) {
+		let _ = writeln!(self.writer, "{:indent$}{}", "", line, indent = self.depth * INDENT_WIDTH);
+	}
+}
+
+// ra or t0, the link registers of the standard calling convention
+fn is_link_register(register: u8) -> bool {
+	register == 1 || register == 5
+}
+
+#[cfg(test)]
+mod test_call_trace {
+	use super::*;
+	use alloc::vec::Vec;
+	use collections::FnvHashMap;
+	use mmu::DRAM_BASE;
+	use core::cell::RefCell;
+	use alloc::rc::Rc;
+	use io;
+	use terminal::DummyTerminal;
+
+	struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for SharedLog {
+		fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+			self.0.borrow_mut().write(data)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn trace() {
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x100);
+		cpu.update_pc(DRAM_BASE);
+		let code = [
+			0x008000ef, // main: jal ra, foo
+			0x00000013, // nop
+			0x008002ef, // foo: jal t0, bar
+			0x00008067, // ret
+			0x00028067 // bar: jalr zero, 0(t0)
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		let mut symbol_map = FnvHashMap::default();
+		symbol_map.insert("main".to_string(), DRAM_BASE);
+		symbol_map.insert("foo".to_string(), DRAM_BASE + 8);
+		symbol_map.insert("bar".to_string(), DRAM_BASE + 16);
+
+		let log = Rc::new(RefCell::new(vec![]));
+		let mut trace = CallTrace::new(Box::new(SharedLog(log.clone())));
+		trace.set_symbol_table(SymbolTable::new(&symbol_map));
+		for _ in 0..4 {
+			trace.trace(&mut cpu);
+			cpu.tick();
+		}
+		assert_eq!(DRAM_BASE + 4, cpu.read_pc());
+		assert_eq!("foo() {\n  bar() {\n  }\n}\n", String::from_utf8_lossy(&log.borrow()));
+		assert_eq!("foo+0x4", trace.get_function_name(DRAM_BASE + 12));
+		assert_eq!("0x1000", trace.get_function_name(0x1000));
+	}
+}
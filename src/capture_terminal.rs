@@ -0,0 +1,76 @@
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use terminal::Terminal;
+
+/// `Terminal` which captures all the output for unit tests asserting on
+/// what the guest prints. Unlike `DefaultTerminal` reading the output
+/// doesn't consume it. The host inspects it through the handle
+/// `get_captured_output()` returns while `Emulator` owns the terminal.
+///
+/// ```ignore
+/// let terminal = CaptureTerminal::new();
+/// let output = terminal.get_captured_output();
+/// let mut emulator = Emulator::new(Box::new(terminal));
+/// // Set up and run the emulator
+/// assert!(String::from_utf8_lossy(&output.borrow()).contains("Hello"));
+/// ```
+pub struct CaptureTerminal {
+	output_data: Rc<RefCell<Vec<u8>>>,
+	input_data: VecDeque<u8>
+}
+
+impl CaptureTerminal {
+	/// Creates a new `CaptureTerminal`.
+	pub fn new() -> Self {
+		CaptureTerminal {
+			output_data: Rc::new(RefCell::new(vec![])),
+			input_data: VecDeque::new()
+		}
+	}
+
+	/// Returns the handle of the output captured so far.
+	/// Clear it to capture only the following output.
+	pub fn get_captured_output(&self) -> Rc<RefCell<Vec<u8>>> {
+		self.output_data.clone()
+	}
+}
+
+impl Default for CaptureTerminal {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Terminal for CaptureTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.output_data.borrow_mut().push(value);
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		self.output_data.borrow_mut().extend_from_slice(values);
+	}
+
+	fn get_input(&mut self) -> u8 {
+		self.input_data.pop_front().unwrap_or(0)
+	}
+
+	fn put_input(&mut self, value: u8) {
+		self.input_data.push_back(value);
+	}
+
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		self.input_data.extend(data);
+	}
+
+	fn has_input(&mut self) -> bool {
+		!self.input_data.is_empty()
+	}
+
+	/// The output is kept in the capture. This method always returns zero.
+	fn get_output(&mut self) -> u8 {
+		0
+	}
+}
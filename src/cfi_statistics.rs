@@ -0,0 +1,103 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use collections::{FnvHashMap, FnvHashSet};
+
+/// Forward-edge control flow integrity statistics of the landing pads of
+/// Zicfilp, collected while landing pad checking is enabled with
+/// `EmulatorConfig.landing_pad`. The landing pads sharing a label form an
+/// equivalence class, the set of targets an indirect call or jump
+/// expecting the label can reach, so the class sizes tell how precise
+/// the labeling of a program is. Label zero matches any expected label.
+#[derive(Clone, Default)]
+pub struct CfiStatistics {
+	indirect_calls: u64,
+	indirect_jumps: u64,
+	violations: u64,
+	// Landing pad addresses reached by label
+	classes: FnvHashMap<u32, FnvHashSet<u64>>
+}
+
+impl CfiStatistics {
+	/// Creates a new empty `CfiStatistics`.
+	pub fn new() -> Self {
+		CfiStatistics::default()
+	}
+
+	/// Returns the number of the checked indirect calls, `JALR` linking
+	/// to `ra` or `t0` through another register.
+	pub fn get_indirect_calls(&self) -> u64 {
+		self.indirect_calls
+	}
+
+	/// Returns the number of the checked indirect jumps, `JALR` linking to
+	/// another register through a register other than `ra`, `t0`, and
+	/// `t2`.
+	pub fn get_indirect_jumps(&self) -> u64 {
+		self.indirect_jumps
+	}
+
+	/// Returns the number of the indirect calls and jumps which haven't
+	/// landed on a landing pad with the expected label.
+	pub fn get_violations(&self) -> u64 {
+		self.violations
+	}
+
+	/// Returns the number of the distinct labels of the landing pads
+	/// reached so far.
+	pub fn get_label_num(&self) -> usize {
+		self.classes.len()
+	}
+
+	/// Returns the equivalence classes as (label, number of the landing
+	/// pads reached with it) in the label order.
+	pub fn get_equivalence_classes(&self) -> Vec<(u32, usize)> {
+		let mut classes = self.classes.iter()
+			.map(|(label, addresses)| (*label, addresses.len()))
+			.collect::<Vec<(u32, usize)>>();
+		classes.sort_unstable();
+		classes
+	}
+
+	/// Returns the landing pad addresses reached with the label, in
+	/// the address order.
+	///
+	/// # Arguments
+	/// * `label`
+	pub fn get_landing_pads(&self, label: u32) -> Vec<u64> {
+		let mut addresses = match self.classes.get(&label) {
+			Some(addresses) => addresses.iter().cloned().collect::<Vec<u64>>(),
+			None => vec![]
+		};
+		addresses.sort_unstable();
+		addresses
+	}
+
+	pub(crate) fn record_branch(&mut self, is_call: bool) {
+		match is_call {
+			true => self.indirect_calls += 1,
+			false => self.indirect_jumps += 1
+		};
+	}
+
+	pub(crate) fn record_landing(&mut self, label: u32, address: u64) {
+		self.classes.entry(label).or_default().insert(address);
+	}
+
+	pub(crate) fn record_violation(&mut self) {
+		self.violations += 1;
+	}
+}
+
+impl fmt::Display for CfiStatistics {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "Indirect calls: {}", self.indirect_calls)?;
+		writeln!(f, "Indirect jumps: {}", self.indirect_jumps)?;
+		writeln!(f, "Violations: {}", self.violations)?;
+		write!(f, "Labels: {}", self.get_label_num())?;
+		for (label, size) in self.get_equivalence_classes() {
+			write!(f, "\n  {:05x}: {} landing pads", label, size)?;
+		}
+		Ok(())
+	}
+}
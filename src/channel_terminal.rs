@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, Sender};
+
+use terminal::Terminal;
+
+/// `Terminal` connected to the host through channels, for GUI frontends
+/// which run the emulator in another thread. The frontend sends input
+/// bytes to the input channel in chunks and receives output bytes from
+/// the output channel one by one as the guest writes them.
+///
+/// ```ignore
+/// let (input_sender, input_receiver) = std::sync::mpsc::channel();
+/// let (output_sender, output_receiver) = std::sync::mpsc::channel();
+/// std::thread::spawn(move || {
+///   let terminal = ChannelTerminal::new(input_receiver, output_sender);
+///   let mut emulator = Emulator::new(Box::new(terminal));
+///   // Set up and run the emulator
+/// });
+/// input_sender.send(b"ls\r".to_vec());
+/// let output: Vec<u8> = output_receiver.try_iter().collect();
+/// // Display output
+/// ```
+///
+/// The emulator keeps running after the frontend drops its ends.
+/// Input is no longer received and output is discarded then.
+pub struct ChannelTerminal {
+	input: Receiver<Vec<u8>>,
+	output: Sender<u8>,
+	input_data: VecDeque<u8>
+}
+
+impl ChannelTerminal {
+	/// Creates a new `ChannelTerminal`.
+	///
+	/// # Arguments
+	/// * `input` Receives input bytes from the frontend
+	/// * `output` Sends output bytes to the frontend
+	pub fn new(input: Receiver<Vec<u8>>, output: Sender<u8>) -> Self {
+		ChannelTerminal {
+			input,
+			output,
+			input_data: VecDeque::new()
+		}
+	}
+
+	/// Moves the input the frontend has sent so far to the buffer.
+	fn receive(&mut self) {
+		for data in self.input.try_iter() {
+			self.input_data.extend(data);
+		}
+	}
+}
+
+impl Terminal for ChannelTerminal {
+	fn put_byte(&mut self, value: u8) {
+		let _ = self.output.send(value);
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		for value in values {
+			self.put_byte(*value);
+		}
+	}
+
+	fn get_input(&mut self) -> u8 {
+		self.receive();
+		self.input_data.pop_front().unwrap_or(0)
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.receive();
+		let size = buffer.len().min(self.input_data.len());
+		for (i, value) in self.input_data.drain(..size).enumerate() {
+			buffer[i] = value;
+		}
+		size
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.receive();
+		!self.input_data.is_empty()
+	}
+
+	/// Puts input from the emulator thread, in addition to the channel.
+	fn put_input(&mut self, data: u8) {
+		self.input_data.push_back(data);
+	}
+
+	/// Output goes to the channel. This method always returns zero.
+	fn get_output(&mut self) -> u8 {
+		0
+	}
+}
+
+#[cfg(test)]
+mod test_channel_terminal {
+	use super::*;
+	use std::sync::mpsc::channel;
+
+	#[test]
+	fn output() {
+		let (_input_sender, input_receiver) = channel();
+		let (output_sender, output_receiver) = channel();
+		let mut terminal = ChannelTerminal::new(input_receiver, output_sender);
+		terminal.put_byte(b'l');
+		terminal.put_bytes(b"s\r");
+		assert_eq!(b"ls\r".to_vec(), output_receiver.try_iter().collect::<Vec<u8>>());
+		assert_eq!(0, terminal.get_output());
+	}
+
+	#[test]
+	fn input() {
+		let (input_sender, input_receiver) = channel();
+		let (output_sender, _output_receiver) = channel();
+		let mut terminal = ChannelTerminal::new(input_receiver, output_sender);
+		assert!(!terminal.has_input());
+		input_sender.send(b"ab".to_vec()).unwrap();
+		input_sender.send(b"cd".to_vec()).unwrap();
+		assert!(terminal.has_input());
+		terminal.put_input(b'e');
+		assert_eq!(b'a', terminal.get_input());
+		let mut buffer = [0; 8];
+		assert_eq!(4, terminal.get_input_bytes(&mut buffer));
+		assert_eq!(b"bcde", &buffer[..4]);
+		assert_eq!(0, terminal.get_input());
+	}
+
+	#[test]
+	fn disconnected() {
+		let (input_sender, input_receiver) = channel();
+		let (output_sender, output_receiver) = channel();
+		let mut terminal = ChannelTerminal::new(input_receiver, output_sender);
+		input_sender.send(b"a".to_vec()).unwrap();
+		drop(input_sender);
+		drop(output_receiver);
+		// Output is discarded and the input sent before is still received
+		terminal.put_bytes(b"ls\r");
+		assert!(terminal.has_input());
+		assert_eq!(b'a', terminal.get_input());
+		assert!(!terminal.has_input());
+		assert_eq!(0, terminal.get_input());
+	}
+}
@@ -0,0 +1,123 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use error::DebugError;
+use Snapshot;
+
+/// Checkpoint kept in `Checkpoints`, returned by `get_checkpoints()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckpointInfo {
+	/// Passed to `Emulator::rollback_to()`. Unique and increasing
+	pub id: usize,
+	/// Instructions run since the checkpoints are set to `Emulator`
+	pub instructions: u64,
+	/// Virtual address of the instruction about to run at the checkpoint
+	pub pc: u64
+}
+
+struct Checkpoint {
+	info: CheckpointInfo,
+	snapshot: Snapshot
+}
+
+/// Takes a snapshot of the guest every interval instructions and keeps
+/// the latest ones, so a faulty window can be replayed, e.g. with tracing
+/// enabled, after rolling back with `Emulator::rollback_to()`. The first
+/// checkpoint is taken before the first instruction. Each checkpoint
+/// copies the main memory pages in use, so keep the count small for large
+/// guests. Devices and the terminal aren't saved, see
+/// `Emulator::take_snapshot()`, so the replay may differ if the guest
+/// depends on them. Cycles waiting for interrupts aren't counted as
+/// instructions. Set to `Emulator` with `set_checkpoints()`.
+pub struct Checkpoints {
+	interval: u64,
+	capacity: usize,
+	instructions: u64,
+	next_id: usize,
+	checkpoints: VecDeque<Checkpoint>
+}
+
+impl Checkpoints {
+	/// Creates a new `Checkpoints`.
+	///
+	/// # Arguments
+	/// * `interval` Instructions between checkpoints, one or more
+	/// * `capacity` How many latest checkpoints are kept, one or more
+	pub fn new(interval: u64, capacity: usize) -> Self {
+		Checkpoints {
+			interval: interval.max(1),
+			capacity: capacity.max(1),
+			instructions: 0,
+			next_id: 0,
+			checkpoints: VecDeque::with_capacity(capacity)
+		}
+	}
+
+	/// Counts an instruction about to run and returns whether to take
+	/// a checkpoint before it.
+	pub(crate) fn count(&mut self) -> bool {
+		// The latest checkpoint is at the instruction right after rollback
+		let due = self.instructions.is_multiple_of(self.interval) &&
+			self.checkpoints.back().map(|checkpoint| checkpoint.info.instructions) != Some(self.instructions);
+		self.instructions += 1;
+		due
+	}
+
+	/// Keeps the snapshot taken when `count()` returns true as the latest
+	/// checkpoint, dropping the oldest one over the capacity.
+	pub(crate) fn push(&mut self, pc: u64, snapshot: Snapshot) {
+		if self.checkpoints.len() == self.capacity {
+			self.checkpoints.pop_front();
+		}
+		self.checkpoints.push_back(Checkpoint {
+			info: CheckpointInfo {
+				id: self.next_id,
+				instructions: self.instructions - 1,
+				pc
+			},
+			snapshot
+		});
+		self.next_id += 1;
+	}
+
+	/// Drops the checkpoints after the one of the ID and returns its
+	/// snapshot to restore, setting the instruction count back to it.
+	/// Returns `Err` if the checkpoint isn't kept.
+	pub(crate) fn rollback(&mut self, id: usize) -> Result<&Snapshot, DebugError> {
+		let index = match self.checkpoints.iter().position(|checkpoint| checkpoint.info.id == id) {
+			Some(index) => index,
+			None => return Err(DebugError::NoCheckpoint)
+		};
+		self.checkpoints.truncate(index + 1);
+		let checkpoint = &self.checkpoints[index];
+		self.instructions = checkpoint.info.instructions;
+		Ok(&checkpoint.snapshot)
+	}
+
+	/// Returns the number of instructions run since the checkpoints are
+	/// set, which goes back on rollback.
+	pub fn get_instructions(&self) -> u64 {
+		self.instructions
+	}
+
+	/// Returns the checkpoints kept, the oldest first.
+	pub fn get_checkpoints(&self) -> Vec<CheckpointInfo> {
+		self.checkpoints.iter().map(|checkpoint| checkpoint.info.clone()).collect()
+	}
+
+	/// Returns the latest checkpoint taken before or at the number of
+	/// instructions, e.g. to bisect the instruction a fault has started.
+	///
+	/// # Arguments
+	/// * `instructions`
+	pub fn find_before(&self, instructions: u64) -> Option<CheckpointInfo> {
+		self.checkpoints.iter().rev()
+			.find(|checkpoint| checkpoint.info.instructions <= instructions)
+			.map(|checkpoint| checkpoint.info.clone())
+	}
+
+	/// Drops all the checkpoints kept. Counting instructions continues.
+	pub fn clear(&mut self) {
+		self.checkpoints.clear();
+	}
+}
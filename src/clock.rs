@@ -0,0 +1,79 @@
+use alloc::rc::Rc;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Host time source read by `SpeedMeter`, the user mode emulation, and
+/// the guest timer following the host clock. The guest timer counts
+/// cycles by default, so otherwise the emulation never reads host time.
+/// Replace `SystemClock` on hosts without `std::time`, e.g.
+/// wasm32-unknown-unknown where `Instant::now()` panics, with one backed
+/// by the host's own API such as `performance.now()` in a web browser.
+pub trait Clock {
+	/// Returns monotonic time elapsed since a fixed point, e.g. when
+	/// the clock is created.
+	fn now(&self) -> Duration;
+
+	/// Returns wall-clock time elapsed since the Unix epoch.
+	fn unix_time(&self) -> Duration;
+}
+
+/// `Clock` backed by `std::time`, the default with std.
+#[cfg(feature = "std")]
+pub struct SystemClock {
+	start: Instant
+}
+
+#[cfg(feature = "std")]
+impl SystemClock {
+	/// Creates a new `SystemClock`. `now()` counts from here.
+	pub fn new() -> Self {
+		SystemClock {
+			start: Instant::now()
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl Default for SystemClock {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+	fn now(&self) -> Duration {
+		self.start.elapsed()
+	}
+
+	fn unix_time(&self) -> Duration {
+		SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+	}
+}
+
+/// `Clock` standing still at zero, the default without std where no host
+/// time is available. Set one backed by the host's timer instead with
+/// `Emulator::set_clock()`.
+pub struct StoppedClock;
+
+impl Clock for StoppedClock {
+	fn now(&self) -> Duration {
+		Duration::ZERO
+	}
+
+	fn unix_time(&self) -> Duration {
+		Duration::ZERO
+	}
+}
+
+// Clock used until one is set, `SystemClock` with std
+#[cfg(feature = "std")]
+pub(crate) fn create_default_clock() -> Rc<dyn Clock> {
+	Rc::new(SystemClock::new())
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn create_default_clock() -> Rc<dyn Clock> {
+	Rc::new(StoppedClock)
+}
@@ -0,0 +1,12 @@
+// Hash maps with FNV hasher, faster than the default SipHash for
+// the integer keys the emulator mostly uses. `fnv` defines them only with
+// std, so without std they are hashbrown's with the same hasher.
+
+#[cfg(feature = "std")]
+pub use fnv::{FnvHashMap, FnvHashSet};
+
+#[cfg(not(feature = "std"))]
+pub type FnvHashMap<K, V> = hashbrown::HashMap<K, V, fnv::FnvBuildHasher>;
+
+#[cfg(not(feature = "std"))]
+pub type FnvHashSet<T> = hashbrown::HashSet<T, fnv::FnvBuildHasher>;
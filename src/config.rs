@@ -0,0 +1,1229 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+
+use cpu::Xlen;
+use device::uart::MAX_SERIAL_PORT_NUM;
+use thiserror::Error;
+
+// Without std there's no file system to resolve image paths against, so
+// they are kept as they are written
+#[cfg(not(feature = "std"))]
+type PathBuf = String;
+
+/// Default main memory capacity. Big enough to run Linux and xv6.
+pub const DEFAULT_MEMORY_CAPACITY: u64 = 1024 * 1024 * 128;
+
+// Single letter extensions which can be named in the ISA string, in
+// canonical order
+#[cfg(feature = "fd")]
+const ISA_EXTENSIONS: &str = "imafdcsu";
+#[cfg(not(feature = "fd"))]
+const ISA_EXTENSIONS: &str = "imacsu";
+
+// Multi-letter extensions which can be named in the ISA string. They are
+// implied by the single letter ones
+const ISA_MULTI_LETTER_EXTENSIONS: [&str; 2] = ["zicsr", "zifencei"];
+
+/// Machine configuration of [`Emulator`](../struct.Emulator.html).
+/// Use `EmulatorConfig::default()` and override the fields you want to change.
+///
+/// ```ignore
+/// let mut config = EmulatorConfig::default();
+/// config.console = ConsoleType::SifiveUart;
+/// let emulator = Emulator::new_with_config(terminal, config);
+/// ```
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(remote = "Self"))]
+pub struct EmulatorConfig {
+	/// Machine model deciding the address map, the devices, and
+	/// the generated device tree. Start from `new_with_machine()` to get
+	/// the devices of the model
+	pub machine: MachineType,
+	/// UART model used for the console
+	pub console: ConsoleType,
+	/// Number of NS16550A serial ports added besides the console, up to 4.
+	/// They are placed every 0x100 bytes from 0x10006000 and connected to
+	/// IRQ 11 and later
+	pub serial_port_num: usize,
+	/// Interrupt controller model the devices are connected to
+	pub interrupt_controller: InterruptControllerType,
+	/// Describes CLINT as ACLINT MSWI and MTIMER devices, whose register
+	/// layouts are compatible, and adds ACLINT SSWI device at 0x2f00000
+	pub aclint: bool,
+	/// Main memory capacity in bytes
+	pub memory_capacity: u64,
+	/// Virtio MMIO transport of the block device
+	pub virtio_block_transport: VirtioTransport,
+	/// Virtio MMIO transport of the network device
+	pub virtio_net_transport: VirtioTransport,
+	/// Virtio MMIO transport of the sound device
+	pub virtio_snd_transport: VirtioTransport,
+	/// Adds virtio memory balloon device at 0x10004000
+	pub balloon: bool,
+	/// Virtio MMIO transport of the memory balloon device
+	pub virtio_balloon_transport: VirtioTransport,
+	/// Adds virtio console device at 0x10007000, an `hvc` device in Linux, which
+	/// tells the guest the terminal window size
+	pub virtio_console: bool,
+	/// Virtio MMIO transport of the console device
+	pub virtio_console_transport: VirtioTransport,
+	/// Linear framebuffer described as simple-framebuffer in the device tree.
+	/// `None` for no framebuffer
+	pub framebuffer: Option<FramebufferConfig>,
+	/// Shared memory region exchanged with the host at 0x40000000.
+	/// `None` for no shared memory
+	pub shared_memory: Option<SharedMemoryConfig>,
+	/// ISA string like "rv64imafdc" which sets XLEN regardless of
+	/// the program and the extensions in `misa` CSR. `None` for XLEN
+	/// detected from the program and all the extensions implemented
+	pub isa: Option<String>,
+	/// Message authentication code of return addresses Zipper Stack
+	/// chains with `ZIP` and `UNZIP` instructions
+	pub zipper_stack: ZipperStackConfig,
+	/// Physical memory region of the hart only the shadow stack
+	/// instructions can access, reserved in the device tree. `None` for
+	/// no region, where the instructions do nothing as Zimop
+	pub shadow_stack: Option<ShadowStackConfig>,
+	/// Checks that indirect calls and jumps land on the landing pads of
+	/// Zicfilp in all the privilege modes, and collects the forward-edge
+	/// CFI statistics
+	pub landing_pad: bool,
+	/// How CLINT `mtime` advances and the timebase frequency told to
+	/// the guest
+	pub timer: TimerConfig,
+	/// Images loaded into the machine. `Emulator` doesn't load them by
+	/// itself, they are for the host program setting it up
+	pub images: ImageConfig
+}
+
+/// Images described in configuration files.
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ImageConfig {
+	/// Program or kernel file for `Emulator::setup_program()`
+	pub kernel: Option<PathBuf>,
+	/// Initial ramdisk file for `Emulator::setup_initrd()`
+	pub initrd: Option<PathBuf>,
+	/// Device tree file for `Emulator::setup_dtb()`
+	pub dtb: Option<PathBuf>,
+	/// Disk image file of the virtio block device
+	pub disk: Option<PathBuf>,
+	/// Discards the guest writes to the disk image instead of writing them
+	/// back to the file
+	pub disk_snapshot: bool
+}
+
+/// Framebuffer resolution. Pixel format is a8r8g8b8.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FramebufferConfig {
+	pub width: u32,
+	pub height: u32
+}
+
+/// Shared memory device configuration.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SharedMemoryConfig {
+	/// Region size in bytes, up to 1GiB
+	pub size: u64,
+	/// Adds doorbell registers at 0x10005000 and the interrupt
+	pub doorbell: bool
+}
+
+/// Zipper Stack configuration, to compare the security of the tag width
+/// and the MAC algorithm with the cost.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ZipperStackConfig {
+	/// MAC algorithm the tags are computed with
+	pub mac: MacAlgorithm,
+	/// Tag width in bits, from 1 up to 25 bits above the 39-bit return
+	/// address
+	pub tag_width: u32,
+	/// Secret key. `None` for a random one
+	pub key: Option<u64>
+}
+
+impl Default for ZipperStackConfig {
+	fn default() -> Self {
+		ZipperStackConfig {
+			mac: MacAlgorithm::Sha3,
+			tag_width: 25,
+			key: None
+		}
+	}
+}
+
+/// Timer configuration. The guest reads the timebase frequency from
+/// the device tree and converts `mtime` to time with it.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TimerConfig {
+	/// How `mtime` advances
+	pub source: TimerSource,
+	/// `mtime` ticks per second in Hz told to the guest as
+	/// `timebase-frequency` of the device tree. 10MHz by default
+	pub frequency: u32
+}
+
+impl Default for TimerConfig {
+	fn default() -> Self {
+		TimerConfig {
+			source: TimerSource::Cycles {
+				ticks: 1,
+				cycles: 1
+			},
+			frequency: 10_000_000
+		}
+	}
+}
+
+/// Time sources CLINT `mtime` can follow.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TimerSource {
+	/// Advances `ticks` every `cycles` instructions, one per instruction
+	/// by default. Runs are reproducible, but the guest time runs as fast
+	/// as the emulation. Fewer ticks make the guest timer interrupts less
+	/// frequent for the instructions run
+	Cycles {
+		ticks: u64,
+		cycles: u64
+	},
+	/// Follows the host monotonic clock at the timebase frequency, so
+	/// the guest time is stable regardless of the emulation speed. Not
+	/// reproducible. See `Emulator::set_clock()`
+	HostClock
+}
+
+/// Shadow stack region configuration. Only one hart is supported so far,
+/// so there is one region.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ShadowStackConfig {
+	/// Physical base address, page aligned
+	pub base: u64,
+	/// Region size in bytes, page aligned. `ssp` CSR starts from the end
+	pub size: u64
+}
+
+/// MAC algorithms selectable for Zipper Stack tags. The output is
+/// truncated to the tag width.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MacAlgorithm {
+	/// SHA3-256 of the key and the tagged return address
+	Sha3,
+	/// SipHash-2-4 keyed with the key and its complement
+	SipHash,
+	/// Toy block cipher in the style of QARMA, a cipher for pointer
+	/// authentication. Not analyzed, only to compare the cost
+	Qarma,
+	/// FNV-1a of the key and the tagged return address. Cheap but
+	/// insecure baseline
+	Fnv
+}
+
+/// Machine models selectable as presets. Guest images are built for
+/// specific platforms, e.g. OpenSBI's `generic` or `fpga/ariane` firmware.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MachineType {
+	/// QEMU virt machine like layout. UART at 0x10000000, virtio MMIO
+	/// devices from 0x10001000, PLIC, and CLINT
+	Virt,
+	/// Spike, the RISC-V ISA simulator. CLINT and HTIF only, without
+	/// PLIC, UARTs, or virtio devices. The console is on HTIF, see
+	/// `Emulator::setup_htif()`
+	Spike,
+	/// SiFive HiFive Unleashed like QEMU sifive_u machine. SiFive UART
+	/// console at 0x10010000, PLIC, and CLINT. Virtio block device at
+	/// 0x10001000 is kept as the only storage
+	SifiveU
+}
+
+/// UART register layouts selectable for the console.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ConsoleType {
+	/// NS16550A compatible UART at 0x10000000 as in QEMU virt machine
+	Ns16550a,
+	/// SiFive UART at 0x10010000 as in HiFive boards
+	SifiveUart
+}
+
+/// Interrupt controller models selectable for the machine.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum InterruptControllerType {
+	/// SiFive PLIC at 0xc000000
+	Plic,
+	/// Advanced Interrupt Architecture as in QEMU virt machine with
+	/// aia=aplic-imsic. APLIC at 0xc000000 (machine) and 0xd000000
+	/// (supervisor) forwards device interrupts to IMSIC at 0x24000000
+	/// (machine) and 0x28000000 (supervisor) as MSIs
+	Aia
+}
+
+/// Virtio MMIO register layouts selectable per Virtio device.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum VirtioTransport {
+	/// Legacy interface, version 1
+	Legacy,
+	/// Version 2 interface requiring VIRTIO_F_VERSION_1 feature
+	Modern
+}
+
+impl Default for EmulatorConfig {
+	fn default() -> Self {
+		EmulatorConfig {
+			machine: MachineType::Virt,
+			console: ConsoleType::Ns16550a,
+			serial_port_num: 0,
+			interrupt_controller: InterruptControllerType::Plic,
+			aclint: false,
+			memory_capacity: DEFAULT_MEMORY_CAPACITY,
+			virtio_block_transport: VirtioTransport::Legacy,
+			virtio_net_transport: VirtioTransport::Legacy,
+			virtio_snd_transport: VirtioTransport::Legacy,
+			balloon: false,
+			virtio_balloon_transport: VirtioTransport::Legacy,
+			virtio_console: false,
+			virtio_console_transport: VirtioTransport::Legacy,
+			framebuffer: None,
+			shared_memory: None,
+			isa: None,
+			zipper_stack: ZipperStackConfig::default(),
+			shadow_stack: None,
+			landing_pad: false,
+			timer: TimerConfig::default(),
+			images: ImageConfig::default()
+		}
+	}
+}
+
+#[cfg(feature = "serde")]
+impl_versioned_serde!(EmulatorConfig, "EmulatorConfig", ::serde_support::CONFIG_FORMAT_VERSION);
+
+impl EmulatorConfig {
+	/// Returns configuration of a machine model. Override the fields to
+	/// customize the machine further.
+	///
+	/// # Arguments
+	/// * `machine`
+	pub fn new_with_machine(machine: MachineType) -> Self {
+		let console = match machine {
+			MachineType::SifiveU => ConsoleType::SifiveUart,
+			_ => ConsoleType::Ns16550a
+		};
+		EmulatorConfig {
+			machine,
+			console,
+			..EmulatorConfig::default()
+		}
+	}
+
+	/// Indicates whether PLIC or AIA, UARTs, virtio devices, and the other
+	/// devices outside of CLINT are mapped. Only Spike doesn't have them.
+	pub fn has_platform_devices(&self) -> bool {
+		self.machine != MachineType::Spike
+	}
+
+	/// Reads machine configuration from a TOML file. Relative image paths
+	/// are resolved against the directory of the file. See `from_toml()`
+	/// for the format.
+	///
+	/// # Arguments
+	/// * `path`
+	#[cfg(feature = "std")]
+	pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+		let text = fs::read_to_string(path.as_ref()).map_err(ConfigError::Io)?;
+		let mut config = Self::from_toml(&text)?;
+		if let Some(directory) = path.as_ref().parent() {
+			let images = &mut config.images;
+			for image in vec![&mut images.kernel, &mut images.initrd, &mut images.dtb, &mut images.disk].into_iter().flatten() {
+				*image = directory.join(&*image);
+			}
+		}
+		Ok(config)
+	}
+
+	/// Parses machine configuration in TOML. Settings not in the text are
+	/// the defaults of the machine model, virt unless `machine` names
+	/// another one. Sizes are in bytes, or strings with K, M, or G suffix.
+	/// Only one hart is supported so far. Each `[[device]]` table adds
+	/// a device or configures a built-in one, with `type` and the keys
+	/// below.
+	///
+	/// ```toml
+	/// # virt, spike, or sifive_u
+	/// machine = "virt"
+	/// memory = "256M"
+	/// harts = 1
+	/// isa = "rv64imafdc"
+	/// # Checks the landing pads of Zicfilp
+	/// landing-pad = true
+	///
+	/// [images]
+	/// kernel = "fw_payload.elf"
+	/// initrd = "rootfs.cpio"
+	/// dtb = "machine.dtb"
+	///
+	/// # ns16550a or sifive
+	/// [[device]]
+	/// type = "console"
+	/// model = "ns16550a"
+	///
+	/// # plic or aia
+	/// [[device]]
+	/// type = "interrupt-controller"
+	/// model = "aia"
+	///
+	/// # A serial port besides the console, up to 4
+	/// [[device]]
+	/// type = "serial"
+	///
+	/// [[device]]
+	/// type = "aclint"
+	///
+	/// # virtio-blk, virtio-net, virtio-snd, virtio-balloon, or
+	/// # virtio-console. transport is legacy or modern
+	/// [[device]]
+	/// type = "virtio-blk"
+	/// transport = "modern"
+	/// file = "rootfs.img"
+	/// snapshot = true
+	///
+	/// [[device]]
+	/// type = "framebuffer"
+	/// width = 640
+	/// height = 480
+	///
+	/// [[device]]
+	/// type = "shared-memory"
+	/// size = "1M"
+	/// doorbell = true
+	///
+	/// # sha3, siphash, qarma, or fnv. key is random if not set
+	/// [zipper-stack]
+	/// mac = "siphash"
+	/// tag-width = 16
+	/// key = "0123456789abcdef"
+	///
+	/// # Region only the shadow stack instructions can access
+	/// [shadow-stack]
+	/// base = 0x87ff0000
+	/// size = "64K"
+	///
+	/// # cycles, cycles:<ticks>/<cycles>, or host
+	/// [timer]
+	/// source = "host"
+	/// frequency = 10000000
+	/// ```
+	///
+	/// Unknown keys are errors so that typos don't go unnoticed. The parser
+	/// supports the subset of TOML above: tables, arrays of tables, and
+	/// strings, integers, booleans, and arrays of them.
+	///
+	/// # Arguments
+	/// * `text`
+	pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+		let document = parse_toml(text)?;
+		// The machine model decides the defaults the others override
+		let mut config = match document.root.get("machine") {
+			Some((value, line)) => EmulatorConfig::new_with_machine(get_machine_type(get_string(value, line)?)
+				.ok_or_else(|| get_invalid_value_error("machine", line))?),
+			None => EmulatorConfig::default()
+		};
+		for (key, value, line) in document.root.entries.iter() {
+			match key.as_str() {
+				"machine" => {},
+				"memory" => config.memory_capacity = get_size(value, *line)?,
+				"harts" => match get_integer(value, *line)? {
+					1 => {},
+					_ => return Err(ConfigError::Invalid(*line, "only one hart is supported".to_string()))
+				},
+				"isa" => {
+					let isa = get_string(value, *line)?;
+					if parse_isa(isa).is_none() {
+						return Err(ConfigError::Invalid(*line, format!("unsupported ISA string {}", isa)));
+					}
+					config.isa = Some(isa.to_string());
+				},
+				"landing-pad" => config.landing_pad = get_boolean(value, *line)?,
+				_ => return Err(get_unknown_key_error(key, *line))
+			};
+		}
+		for (name, table) in document.tables.iter() {
+			match (name.as_str(), table.is_array) {
+				("images", false) => config.parse_images(table)?,
+				("device", true) => config.parse_device(table)?,
+				("zipper-stack", false) => config.parse_zipper_stack(table)?,
+				("shadow-stack", false) => config.parse_shadow_stack(table)?,
+				("timer", false) => config.parse_timer(table)?,
+				_ => return Err(ConfigError::Invalid(table.line, format!("unknown table {}", name)))
+			};
+		}
+		Ok(config)
+	}
+
+	/// Returns XLEN of the ISA string if it's set.
+	pub fn get_isa_xlen(&self) -> Option<Xlen> {
+		self.isa.as_ref().and_then(|isa| parse_isa(isa)).map(|(xlen, _)| xlen)
+	}
+
+	/// Returns the extension bits of `misa` CSR for the ISA string if it's
+	/// set.
+	pub fn get_isa_extensions(&self) -> Option<u64> {
+		self.isa.as_ref().and_then(|isa| parse_isa(isa)).map(|(_, extensions)| extensions)
+	}
+
+	fn parse_images(&mut self, table: &TomlTable) -> Result<(), ConfigError> {
+		for (key, value, line) in table.entries.iter() {
+			let path = Some(PathBuf::from(get_string(value, *line)?));
+			match key.as_str() {
+				"kernel" => self.images.kernel = path,
+				"initrd" => self.images.initrd = path,
+				"dtb" => self.images.dtb = path,
+				_ => return Err(get_unknown_key_error(key, *line))
+			};
+		}
+		Ok(())
+	}
+
+	fn parse_zipper_stack(&mut self, table: &TomlTable) -> Result<(), ConfigError> {
+		for (key, value, line) in table.entries.iter() {
+			match key.as_str() {
+				"mac" => self.zipper_stack.mac = get_mac_algorithm(get_string(value, *line)?)
+					.ok_or_else(|| get_invalid_value_error(key, *line))?,
+				"tag-width" => self.zipper_stack.tag_width = match get_u32(value, *line)? {
+					width if (1..=25).contains(&width) => width,
+					_ => return Err(ConfigError::Invalid(*line, "tag width must be 1 to 25".to_string()))
+				},
+				// Keys with the top bit set don't fit in TOML integers, so
+				// hexadecimal strings are accepted as well
+				"key" => self.zipper_stack.key = Some(match value {
+					TomlValue::String(hex) => u64::from_str_radix(hex.strip_prefix("0x").unwrap_or(hex), 16).ok()
+						.ok_or_else(|| get_invalid_value_error(key, *line))?,
+					_ => get_integer(value, *line)? as u64
+				}),
+				_ => return Err(get_unknown_key_error(key, *line))
+			};
+		}
+		Ok(())
+	}
+
+	fn parse_shadow_stack(&mut self, table: &TomlTable) -> Result<(), ConfigError> {
+		let mut base = None;
+		let mut size = None;
+		for (key, value, line) in table.entries.iter() {
+			let value = match key.as_str() {
+				"base" => get_integer(value, *line)? as u64,
+				"size" => get_size(value, *line)?,
+				_ => return Err(get_unknown_key_error(key, *line))
+			};
+			if value & 0xfff != 0 {
+				return Err(ConfigError::Invalid(*line, format!("{} must be page aligned", key)));
+			}
+			match key.as_str() {
+				"base" => base = Some(value),
+				_ => size = Some(value)
+			};
+		}
+		match (base, size) {
+			(Some(base), Some(size)) => self.shadow_stack = Some(ShadowStackConfig {
+				base,
+				size
+			}),
+			_ => return Err(ConfigError::Invalid(table.line, "shadow-stack needs base and size".to_string()))
+		};
+		Ok(())
+	}
+
+	fn parse_timer(&mut self, table: &TomlTable) -> Result<(), ConfigError> {
+		for (key, value, line) in table.entries.iter() {
+			match key.as_str() {
+				"source" => self.timer.source = get_timer_source(get_string(value, *line)?)
+					.ok_or_else(|| get_invalid_value_error(key, *line))?,
+				"frequency" => self.timer.frequency = match get_u32(value, *line)? {
+					0 => return Err(get_invalid_value_error(key, *line)),
+					frequency => frequency
+				},
+				_ => return Err(get_unknown_key_error(key, *line))
+			};
+		}
+		Ok(())
+	}
+
+	fn parse_device(&mut self, table: &TomlTable) -> Result<(), ConfigError> {
+		let device_type = match table.get("type") {
+			Some((value, line)) => get_string(value, line)?,
+			None => return Err(ConfigError::Invalid(table.line, "device without type".to_string()))
+		};
+		let mut framebuffer = FramebufferConfig {
+			width: 0,
+			height: 0
+		};
+		let mut shared_memory = SharedMemoryConfig {
+			size: 0,
+			doorbell: false
+		};
+		for (key, value, line) in table.entries.iter() {
+			let line = *line;
+			match (device_type, key.as_str()) {
+				(_, "type") => {},
+				("console", "model") => self.console = get_console_type(get_string(value, line)?)
+					.ok_or_else(|| get_invalid_value_error(key, line))?,
+				("interrupt-controller", "model") => self.interrupt_controller = get_interrupt_controller_type(get_string(value, line)?)
+					.ok_or_else(|| get_invalid_value_error(key, line))?,
+				(_, "transport") if device_type.starts_with("virtio-") => {
+					let transport = get_virtio_transport(get_string(value, line)?)
+						.ok_or_else(|| get_invalid_value_error(key, line))?;
+					match device_type {
+						"virtio-blk" => self.virtio_block_transport = transport,
+						"virtio-net" => self.virtio_net_transport = transport,
+						"virtio-snd" => self.virtio_snd_transport = transport,
+						"virtio-balloon" => self.virtio_balloon_transport = transport,
+						"virtio-console" => self.virtio_console_transport = transport,
+						_ => return Err(get_unknown_key_error(key, line))
+					};
+				},
+				("virtio-blk", "file") => self.images.disk = Some(PathBuf::from(get_string(value, line)?)),
+				("virtio-blk", "snapshot") => self.images.disk_snapshot = get_boolean(value, line)?,
+				("framebuffer", "width") => framebuffer.width = get_u32(value, line)?,
+				("framebuffer", "height") => framebuffer.height = get_u32(value, line)?,
+				("shared-memory", "size") => shared_memory.size = get_size(value, line)?,
+				("shared-memory", "doorbell") => shared_memory.doorbell = get_boolean(value, line)?,
+				_ => return Err(get_unknown_key_error(key, line))
+			};
+		}
+		match device_type {
+			"console" | "interrupt-controller" | "virtio-blk" | "virtio-net" | "virtio-snd" => {},
+			"serial" => match self.serial_port_num < MAX_SERIAL_PORT_NUM {
+				true => self.serial_port_num += 1,
+				false => return Err(ConfigError::Invalid(table.line, format!("more than {} serial ports", MAX_SERIAL_PORT_NUM)))
+			},
+			"aclint" => self.aclint = true,
+			"virtio-balloon" => self.balloon = true,
+			"virtio-console" => self.virtio_console = true,
+			"framebuffer" => match framebuffer.width > 0 && framebuffer.height > 0 {
+				true => self.framebuffer = Some(framebuffer),
+				false => return Err(ConfigError::Invalid(table.line, "framebuffer without width and height".to_string()))
+			},
+			"shared-memory" => match shared_memory.size > 0 {
+				true => self.shared_memory = Some(shared_memory),
+				false => return Err(ConfigError::Invalid(table.line, "shared memory without size".to_string()))
+			},
+			_ => return Err(ConfigError::Invalid(table.line, format!("unknown device type {}", device_type)))
+		};
+		Ok(())
+	}
+}
+
+/// Error reading a configuration file, returned by
+/// `EmulatorConfig::from_file()` and `from_toml()`.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+	/// The file can't be read
+	#[cfg(feature = "std")]
+	#[error(transparent)]
+	Io(#[from] io::Error),
+	/// The text isn't TOML the parser supports, at the line number
+	/// starting with one
+	#[error("Syntax error at line {0}")]
+	Syntax(usize),
+	/// The setting at the line number is invalid for the reason
+	#[error("Invalid configuration at line {0}: {1}")]
+	Invalid(usize, String)
+}
+
+// io::Error is neither Clone nor PartialEq. An I/O error is cloned with
+// its kind and message, and compared by its kind as in `DeviceError`.
+impl Clone for ConfigError {
+	fn clone(&self) -> Self {
+		match self {
+			#[cfg(feature = "std")]
+			ConfigError::Io(error) => ConfigError::Io(io::Error::new(error.kind(), error.to_string())),
+			ConfigError::Syntax(line) => ConfigError::Syntax(*line),
+			ConfigError::Invalid(line, reason) => ConfigError::Invalid(*line, reason.clone())
+		}
+	}
+}
+
+impl PartialEq for ConfigError {
+	fn eq(&self, other: &Self) -> bool {
+		match (self, other) {
+			#[cfg(feature = "std")]
+			(ConfigError::Io(error), ConfigError::Io(other_error)) => error.kind() == other_error.kind(),
+			(ConfigError::Syntax(line), ConfigError::Syntax(other_line)) => line == other_line,
+			(ConfigError::Invalid(line, reason), ConfigError::Invalid(other_line, other_reason)) =>
+				line == other_line && reason == other_reason,
+			_ => false
+		}
+	}
+}
+
+/// Returns `MachineType` from its name used in command line or
+/// configuration files.
+///
+/// # Arguments
+/// * `name` "virt", "spike", or "sifive_u"
+pub fn get_machine_type(name: &str) -> Option<MachineType> {
+	match name {
+		"virt" => Some(MachineType::Virt),
+		"spike" => Some(MachineType::Spike),
+		"sifive_u" => Some(MachineType::SifiveU),
+		_ => None
+	}
+}
+
+/// Returns `ConsoleType` from its name used in command line or configuration files.
+///
+/// # Arguments
+/// * `name` "ns16550a" or "sifive"
+pub fn get_console_type(name: &str) -> Option<ConsoleType> {
+	match name {
+		"ns16550a" => Some(ConsoleType::Ns16550a),
+		"sifive" => Some(ConsoleType::SifiveUart),
+		_ => None
+	}
+}
+
+/// Returns `InterruptControllerType` from its name used in command line
+/// or configuration files.
+///
+/// # Arguments
+/// * `name` "plic" or "aia"
+pub fn get_interrupt_controller_type(name: &str) -> Option<InterruptControllerType> {
+	match name {
+		"plic" => Some(InterruptControllerType::Plic),
+		"aia" => Some(InterruptControllerType::Aia),
+		_ => None
+	}
+}
+
+/// Returns `VirtioTransport` from its name used in command line
+/// or configuration files.
+///
+/// # Arguments
+/// * `name` "legacy" or "modern"
+pub fn get_virtio_transport(name: &str) -> Option<VirtioTransport> {
+	match name {
+		"legacy" => Some(VirtioTransport::Legacy),
+		"modern" => Some(VirtioTransport::Modern),
+		_ => None
+	}
+}
+
+/// Returns `MacAlgorithm` from its name used in command line or
+/// configuration files.
+///
+/// # Arguments
+/// * `name` "sha3", "siphash", "qarma", or "fnv"
+pub fn get_mac_algorithm(name: &str) -> Option<MacAlgorithm> {
+	match name {
+		"sha3" => Some(MacAlgorithm::Sha3),
+		"siphash" => Some(MacAlgorithm::SipHash),
+		"qarma" => Some(MacAlgorithm::Qarma),
+		"fnv" => Some(MacAlgorithm::Fnv),
+		_ => None
+	}
+}
+
+/// Returns `TimerSource` from its name used in command line or
+/// configuration files. Ticks and cycles must be non-zero.
+///
+/// # Arguments
+/// * `name` "cycles", "cycles:<ticks>/<cycles>" e.g. "cycles:1/8", or "host"
+pub fn get_timer_source(name: &str) -> Option<TimerSource> {
+	match name {
+		"cycles" => Some(TimerSource::Cycles {
+			ticks: 1,
+			cycles: 1
+		}),
+		"host" => Some(TimerSource::HostClock),
+		_ => {
+			let (ticks, cycles) = name.strip_prefix("cycles:")?.split_once('/')?;
+			match (ticks.parse::<u64>().ok()?, cycles.parse::<u64>().ok()?) {
+				(0, _) | (_, 0) => None,
+				(ticks, cycles) => Some(TimerSource::Cycles {
+					ticks,
+					cycles
+				})
+			}
+		}
+	}
+}
+
+// Returns XLEN and the extension bits of misa CSR for an ISA string like
+// rv64imafdc_zicsr, or None if it names extensions not implemented.
+// g stands for imafd
+fn parse_isa(isa: &str) -> Option<(Xlen, u64)> {
+	let isa = isa.to_lowercase();
+	let xlen = match isa.get(..4)? {
+		"rv32" => Xlen::Bit32,
+		"rv64" => Xlen::Bit64,
+		_ => return None
+	};
+	let mut names = isa[4..].split('_');
+	let mut extensions = 0;
+	for extension in names.next()?.chars() {
+		let letters = match extension {
+			'g' => "imafd".to_string(),
+			_ => extension.to_string()
+		};
+		for letter in letters.chars() {
+			if !ISA_EXTENSIONS.contains(letter) {
+				return None;
+			}
+			extensions |= 1 << (letter as u8 - b'a');
+		}
+	}
+	if extensions & (1 << (b'i' - b'a')) == 0 {
+		return None;
+	}
+	for name in names {
+		if !ISA_MULTI_LETTER_EXTENSIONS.contains(&name) {
+			return None;
+		}
+	}
+	Some((xlen, extensions))
+}
+
+#[derive(Debug, PartialEq)]
+enum TomlValue {
+	String(String),
+	Integer(i64),
+	Boolean(bool),
+	Array(Vec<TomlValue>)
+}
+
+// Keys and values in a table with their line numbers, in order
+struct TomlTable {
+	entries: Vec<(String, TomlValue, usize)>,
+	// Line of the table header
+	line: usize,
+	// Whether it's an element of an array of tables
+	is_array: bool
+}
+
+impl TomlTable {
+	fn get(&self, key: &str) -> Option<(&TomlValue, usize)> {
+		self.entries.iter()
+			.find(|(name, _, _)| name == key)
+			.map(|(_, value, line)| (value, *line))
+	}
+}
+
+struct TomlDocument {
+	root: TomlTable,
+	// [table] and [[array of tables]] elements by name, in order
+	tables: Vec<(String, TomlTable)>
+}
+
+// Parses the subset of TOML used by configuration files: one key and value
+// per line, [table] and [[array of tables]] headers, strings, integers,
+// booleans, and single line arrays of them
+fn parse_toml(text: &str) -> Result<TomlDocument, ConfigError> {
+	let mut document = TomlDocument {
+		root: TomlTable {
+			entries: vec![],
+			line: 0,
+			is_array: false
+		},
+		tables: vec![]
+	};
+	for (i, line) in text.lines().enumerate() {
+		let line_number = i + 1;
+		let mut parser = TomlParser {
+			chars: line.chars().collect(),
+			position: 0
+		};
+		parser.skip_whitespace();
+		match parser.peek() {
+			None | Some('#') => continue,
+			Some('[') => {
+				let is_array = line.trim_start().starts_with("[[");
+				let brackets = match is_array {
+					true => 2,
+					false => 1
+				};
+				parser.position += brackets;
+				let name = parser.parse_key().ok_or(ConfigError::Syntax(line_number))?;
+				for _ in 0..brackets {
+					if parser.next() != Some(']') {
+						return Err(ConfigError::Syntax(line_number));
+					}
+				}
+				parser.expect_end().ok_or(ConfigError::Syntax(line_number))?;
+				if !is_array && document.tables.iter().any(|(table_name, _)| *table_name == name) {
+					return Err(ConfigError::Invalid(line_number, format!("duplicate table {}", name)));
+				}
+				document.tables.push((name, TomlTable {
+					entries: vec![],
+					line: line_number,
+					is_array
+				}));
+			},
+			Some(_) => {
+				let key = parser.parse_key().ok_or(ConfigError::Syntax(line_number))?;
+				parser.skip_whitespace();
+				if parser.next() != Some('=') {
+					return Err(ConfigError::Syntax(line_number));
+				}
+				let value = parser.parse_value().ok_or(ConfigError::Syntax(line_number))?;
+				parser.expect_end().ok_or(ConfigError::Syntax(line_number))?;
+				let table = match document.tables.last_mut() {
+					Some((_, table)) => table,
+					None => &mut document.root
+				};
+				if table.get(&key).is_some() {
+					return Err(ConfigError::Invalid(line_number, format!("duplicate key {}", key)));
+				}
+				table.entries.push((key, value, line_number));
+			}
+		};
+	}
+	Ok(document)
+}
+
+struct TomlParser {
+	chars: Vec<char>,
+	position: usize
+}
+
+impl TomlParser {
+	fn peek(&self) -> Option<char> {
+		self.chars.get(self.position).cloned()
+	}
+
+	fn next(&mut self) -> Option<char> {
+		let c = self.peek();
+		self.position += 1;
+		c
+	}
+
+	fn skip_whitespace(&mut self) {
+		while let Some(' ') | Some('\t') = self.peek() {
+			self.position += 1;
+		}
+	}
+
+	// Accepts the rest of the line only if it's a comment or whitespace
+	fn expect_end(&mut self) -> Option<()> {
+		self.skip_whitespace();
+		match self.peek() {
+			None | Some('#') | Some('\r') => Some(()),
+			_ => None
+		}
+	}
+
+	// Bare or quoted key. Dotted keys are taken as one name
+	fn parse_key(&mut self) -> Option<String> {
+		self.skip_whitespace();
+		let key = match self.peek()? {
+			'"' | '\'' => match self.parse_value()? {
+				TomlValue::String(key) => key,
+				_ => return None
+			},
+			_ => {
+				let start = self.position;
+				while let Some(c) = self.peek() {
+					match c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.' {
+						true => self.position += 1,
+						false => break
+					};
+				}
+				self.chars[start..self.position].iter().collect()
+			}
+		};
+		self.skip_whitespace();
+		match key.is_empty() {
+			true => None,
+			false => Some(key)
+		}
+	}
+
+	fn parse_value(&mut self) -> Option<TomlValue> {
+		self.skip_whitespace();
+		match self.peek()? {
+			'"' => {
+				self.position += 1;
+				let mut value = String::new();
+				loop {
+					match self.next()? {
+						'"' => return Some(TomlValue::String(value)),
+						'\\' => value.push(match self.next()? {
+							'n' => '\n',
+							't' => '\t',
+							'r' => '\r',
+							'"' => '"',
+							'\\' => '\\',
+							'u' => {
+								let digits: String = self.chars.get(self.position..self.position + 4)?.iter().collect();
+								self.position += 4;
+								core::char::from_u32(u32::from_str_radix(&digits, 16).ok()?)?
+							},
+							_ => return None
+						}),
+						c => value.push(c)
+					};
+				}
+			},
+			'\'' => {
+				self.position += 1;
+				let mut value = String::new();
+				loop {
+					match self.next()? {
+						'\'' => return Some(TomlValue::String(value)),
+						c => value.push(c)
+					};
+				}
+			},
+			'[' => {
+				self.position += 1;
+				let mut values = vec![];
+				loop {
+					self.skip_whitespace();
+					if self.peek()? == ']' {
+						self.position += 1;
+						return Some(TomlValue::Array(values));
+					}
+					values.push(self.parse_value()?);
+					self.skip_whitespace();
+					match self.next()? {
+						',' => {},
+						']' => return Some(TomlValue::Array(values)),
+						_ => return None
+					};
+				}
+			},
+			_ => {
+				let start = self.position;
+				while let Some(c) = self.peek() {
+					match c.is_ascii_alphanumeric() || c == '_' || c == '+' || c == '-' {
+						true => self.position += 1,
+						false => break
+					};
+				}
+				let token: String = self.chars[start..self.position].iter().collect();
+				match token.as_str() {
+					"true" => Some(TomlValue::Boolean(true)),
+					"false" => Some(TomlValue::Boolean(false)),
+					_ => parse_integer(&token).map(TomlValue::Integer)
+				}
+			}
+		}
+	}
+}
+
+// Decimal, or hexadecimal, octal, or binary with 0x, 0o, or 0b prefix.
+// Underscores between digits are allowed
+fn parse_integer(token: &str) -> Option<i64> {
+	if token.starts_with('_') || token.ends_with('_') || token.contains("__") {
+		return None;
+	}
+	let token = token.replace('_', "");
+	let (radix, digits) = match token.get(..2) {
+		Some("0x") => (16, &token[2..]),
+		Some("0o") => (8, &token[2..]),
+		Some("0b") => (2, &token[2..]),
+		_ => (10, token.as_str())
+	};
+	if digits.is_empty() || (radix != 10 && digits.starts_with(['+', '-'])) {
+		return None;
+	}
+	i64::from_str_radix(digits, radix).ok()
+}
+
+fn get_unknown_key_error(key: &str, line: usize) -> ConfigError {
+	ConfigError::Invalid(line, format!("unknown key {}", key))
+}
+
+fn get_invalid_value_error(key: &str, line: usize) -> ConfigError {
+	ConfigError::Invalid(line, format!("invalid value of {}", key))
+}
+
+fn get_string(value: &TomlValue, line: usize) -> Result<&str, ConfigError> {
+	match value {
+		TomlValue::String(value) => Ok(value),
+		_ => Err(ConfigError::Invalid(line, "expected a string".to_string()))
+	}
+}
+
+fn get_integer(value: &TomlValue, line: usize) -> Result<i64, ConfigError> {
+	match value {
+		TomlValue::Integer(value) => Ok(*value),
+		_ => Err(ConfigError::Invalid(line, "expected an integer".to_string()))
+	}
+}
+
+fn get_u32(value: &TomlValue, line: usize) -> Result<u32, ConfigError> {
+	match get_integer(value, line)? {
+		value if value >= 0 && value <= u32::MAX as i64 => Ok(value as u32),
+		_ => Err(ConfigError::Invalid(line, "out of range".to_string()))
+	}
+}
+
+fn get_boolean(value: &TomlValue, line: usize) -> Result<bool, ConfigError> {
+	match value {
+		TomlValue::Boolean(value) => Ok(*value),
+		_ => Err(ConfigError::Invalid(line, "expected a boolean".to_string()))
+	}
+}
+
+// Size in bytes, or a string with K, M, or G suffix like "128M"
+fn get_size(value: &TomlValue, line: usize) -> Result<u64, ConfigError> {
+	let size = match value {
+		TomlValue::Integer(size) if *size > 0 => Some(*size as u64),
+		TomlValue::String(size) => {
+			let (digits, unit) = match size.char_indices().last() {
+				Some((i, 'K')) | Some((i, 'k')) => (&size[..i], 1 << 10),
+				Some((i, 'M')) | Some((i, 'm')) => (&size[..i], 1 << 20),
+				Some((i, 'G')) | Some((i, 'g')) => (&size[..i], 1 << 30),
+				_ => (size.as_str(), 1)
+			};
+			digits.parse::<u64>().ok().and_then(|digits| digits.checked_mul(unit)).filter(|size| *size > 0)
+		},
+		_ => None
+	};
+	size.ok_or_else(|| ConfigError::Invalid(line, "invalid size".to_string()))
+}
+
+#[cfg(test)]
+mod test_config {
+	use super::*;
+
+	#[test]
+	fn from_toml() {
+		let config = EmulatorConfig::from_toml(r#"
+# Machine for the tests
+memory = "256M" # Comment after a value
+harts = 1
+isa = "rv32imac_zicsr"
+landing-pad = true
+
+[images]
+kernel = "fw_payload.elf"
+'initrd' = 'rootfs.cpio'
+
+[[device]]
+type = "console"
+model = "sifive"
+
+[[device]]
+type = "serial"
+
+[[device]]
+type = "serial"
+
+[[device]]
+type = "virtio-blk"
+transport = "modern"
+file = "C:\\images\\rootfs.img"
+snapshot = true
+
+[[device]]
+type = "framebuffer"
+width = 640
+height = 0x1e0
+
+[[device]]
+type = "shared-memory"
+size = 1_048_576
+
+[zipper-stack]
+mac = "qarma"
+tag-width = 16
+key = "fedcba9876543210"
+
+[shadow-stack]
+base = 0x87ff0000
+size = "64K"
+
+[timer]
+source = "cycles:1/8"
+frequency = 1_000_000
+"#).unwrap();
+		assert_eq!(256 * 1024 * 1024, config.memory_capacity);
+		assert!(matches!(config.get_isa_xlen(), Some(Xlen::Bit32)));
+		assert_eq!(Some(0x1105), config.get_isa_extensions());
+		assert_eq!(Some(PathBuf::from("fw_payload.elf")), config.images.kernel);
+		assert_eq!(Some(PathBuf::from("rootfs.cpio")), config.images.initrd);
+		assert_eq!(Some(PathBuf::from("C:\\images\\rootfs.img")), config.images.disk);
+		assert!(config.images.disk_snapshot);
+		assert!(config.console == ConsoleType::SifiveUart);
+		assert_eq!(2, config.serial_port_num);
+		assert!(config.virtio_block_transport == VirtioTransport::Modern);
+		assert!(config.virtio_net_transport == VirtioTransport::Legacy);
+		assert_eq!(480, config.framebuffer.unwrap().height);
+		assert_eq!(0x100000, config.shared_memory.unwrap().size);
+		assert_eq!(MacAlgorithm::Qarma, config.zipper_stack.mac);
+		assert_eq!(16, config.zipper_stack.tag_width);
+		assert_eq!(Some(0xfedcba9876543210), config.zipper_stack.key);
+		assert_eq!(0x87ff0000, config.shadow_stack.as_ref().unwrap().base);
+		assert_eq!(0x10000, config.shadow_stack.as_ref().unwrap().size);
+		assert!(config.landing_pad);
+		assert_eq!(TimerSource::Cycles {
+			ticks: 1,
+			cycles: 8
+		}, config.timer.source);
+		assert_eq!(1000000, config.timer.frequency);
+
+		// The machine model decides the defaults wherever it's written
+		let config = EmulatorConfig::from_toml("memory = 0x8000000\nmachine = \"sifive_u\"").unwrap();
+		assert_eq!(MachineType::SifiveU, config.machine);
+		assert!(config.console == ConsoleType::SifiveUart);
+		assert_eq!(0x8000000, config.memory_capacity);
+	}
+
+	#[test]
+	#[cfg(feature = "serde")]
+	fn serialize() {
+		let config = EmulatorConfig::from_toml("machine = \"sifive_u\"\n[images]\nkernel = \"Image\"").unwrap();
+		let json = ::serde_json::to_string(&config).unwrap();
+		assert!(json.starts_with("{\"version\":1,"));
+		let config: EmulatorConfig = ::serde_json::from_str(&json).unwrap();
+		assert_eq!(MachineType::SifiveU, config.machine);
+		assert!(config.console == ConsoleType::SifiveUart);
+		assert_eq!(Some(PathBuf::from("Image")), config.images.kernel);
+	}
+
+	#[test]
+	fn from_toml_errors() {
+		let line = |text: &str| match EmulatorConfig::from_toml(text) {
+			Ok(_) => panic!("Must be an error"),
+			Err(ConfigError::Syntax(line)) | Err(ConfigError::Invalid(line, _)) => line,
+			#[cfg(feature = "std")]
+			Err(ConfigError::Io(_)) => panic!("Unexpected error")
+		};
+		assert_eq!(2, line("memory = 1024\nmemory = 1024"));
+		assert_eq!(1, line("memory = \"128MB\""));
+		assert_eq!(1, line("memroy = 1024"));
+		assert_eq!(1, line("harts = 2"));
+		assert_eq!(1, line("machine = \"pc\""));
+		assert_eq!(1, line("isa = \"rv64imafdv\""));
+		assert_eq!(1, line("isa = \"rv64mafd\""));
+		assert_eq!(2, line("[images]\nkernel = \"a\" \"b\""));
+		assert_eq!(1, line("[images"));
+		assert_eq!(1, line("[device]"));
+		assert_eq!(3, line("[[device]]\ntype = \"console\"\nmodel = \"pl011\""));
+		assert_eq!(1, line("[[device]]\ntype = \"virtio-gpu\""));
+		assert_eq!(3, line("[[device]]\ntype = \"serial\"\ntransport = \"modern\""));
+		assert_eq!(1, line("[[device]]\ntype = \"framebuffer\"\nwidth = 640"));
+		assert_eq!(2, line("[zipper-stack]\ntag-width = 32"));
+		assert_eq!(2, line("[zipper-stack]\nmac = \"md5\""));
+		assert_eq!(2, line("[shadow-stack]\nbase = 0x87ff0100\nsize = 4096"));
+		assert_eq!(1, line("[shadow-stack]\nbase = 0x87ff0000"));
+		assert_eq!(2, line("[timer]\nsource = \"cycles:1/0\""));
+		assert_eq!(3, line("[timer]\nsource = \"host\"\nfrequency = 0"));
+	}
+}
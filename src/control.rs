@@ -0,0 +1,512 @@
+extern crate fnv;
+extern crate serde_json;
+
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::thread;
+use std::time::Duration;
+
+use self::fnv::FnvHashMap;
+use self::serde_json::{json, Value};
+#[cfg(feature = "virtio")]
+use block_backend::OverlayBlockBackend;
+#[cfg(feature = "virtio")]
+use qcow2_block_backend::open_disk_image;
+use {Emulator, Snapshot, StopReason};
+
+// Cycles run between the checks of the commands
+const SLICE_CYCLES: u64 = 0x10000;
+
+// Time to wait for commands while the guest is paused
+const PAUSE_INTERVAL: Duration = Duration::from_millis(10);
+
+// Largest memory read-memory returns
+const MAX_READ_SIZE: u64 = 0x10000;
+
+// Longest command line. A client sending longer one is disconnected.
+const MAX_LINE_LENGTH: usize = 0x10000;
+
+/// Serves a JSON control protocol modeled on QMP of QEMU on a TCP or
+/// Unix socket, so external orchestrators can manage a running emulator
+/// without linking Rust. Each line a client sends is a command like
+/// `{"execute": "stop", "id": 1}`, answered with a line of
+/// `{"return": {...}}` or `{"error": {"class": ..., "desc": ...}}` with
+/// the `id` of the command. Clients are greeted on connection and get
+/// `STOP`, `RESUME`, and `SHUTDOWN` events.
+///
+/// Commands
+/// * `qmp_capabilities` Accepted for QMP clients. Does nothing
+/// * `query-status` Returns `running` and `status`, `running`, `paused`,
+///   or `shutdown`
+/// * `stop`, `cont` Pauses and resumes the guest
+/// * `snapshot-save`, `snapshot-load`, `snapshot-delete` Saves the guest
+///   state in memory with `Emulator::take_snapshot()` under `tag`,
+///   restores, or deletes it
+/// * `send-input` Types the string `data` to the console
+/// * `read-memory` Returns `size` bytes of main memory at the physical
+///   `address` as hex `data`
+/// * `device_add` Attaches the disk image `file` with `driver`
+///   `virtio-blk-device`. `snapshot: true` keeps the guest writes in
+///   memory instead of writing them back
+/// * `device_del` Detaches the disk with `id` `virtio-blk-device`
+/// * `quit` Ends `run()`
+///
+/// ```ignore
+/// let mut server = ControlServer::bind_tcp("127.0.0.1:4444")?;
+/// // Set up the emulator
+/// let reason = server.run(&mut emulator);
+/// ```
+pub struct ControlServer {
+	listener: ControlListener,
+	clients: Vec<ControlClient>,
+	paused: bool,
+	quit: bool,
+	snapshots: FnvHashMap<String, Snapshot>
+}
+
+enum ControlListener {
+	Tcp(TcpListener),
+	/// Listener and the socket path removed on drop
+	#[cfg(unix)]
+	Unix(UnixListener, String)
+}
+
+trait ControlStream: Read + Write {}
+
+impl<T: Read + Write> ControlStream for T {}
+
+struct ControlClient {
+	stream: Box<dyn ControlStream>,
+	input: Vec<u8>,
+	output: Vec<u8>,
+	closed: bool
+}
+
+// Error reply of a command
+struct CommandError {
+	class: &'static str,
+	desc: String
+}
+
+impl CommandError {
+	fn new(desc: &str) -> Self {
+		CommandError {
+			class: "GenericError",
+			desc: desc.to_string()
+		}
+	}
+}
+
+impl ControlServer {
+	/// Listens on the TCP address, e.g. `127.0.0.1:4444`.
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn bind_tcp(address: &str) -> io::Result<Self> {
+		let listener = TcpListener::bind(address)?;
+		listener.set_nonblocking(true)?;
+		Ok(Self::new(ControlListener::Tcp(listener)))
+	}
+
+	/// Listens on the Unix socket at the path, which must not exist.
+	/// The socket is removed when `ControlServer` is dropped.
+	///
+	/// # Arguments
+	/// * `path`
+	#[cfg(unix)]
+	pub fn bind_unix(path: &str) -> io::Result<Self> {
+		let listener = UnixListener::bind(path)?;
+		listener.set_nonblocking(true)?;
+		Ok(Self::new(ControlListener::Unix(listener, path.to_string())))
+	}
+
+	fn new(listener: ControlListener) -> Self {
+		ControlServer {
+			listener,
+			clients: vec![],
+			paused: false,
+			quit: false,
+			snapshots: FnvHashMap::default()
+		}
+	}
+
+	/// Pauses or resumes the guest, e.g. to start paused until
+	/// an orchestrator sends `cont`, like `-S` of QEMU.
+	///
+	/// # Arguments
+	/// * `paused`
+	pub fn set_paused(&mut self, paused: bool) {
+		self.paused = paused;
+	}
+
+	/// Indicates whether the guest is paused.
+	pub fn is_paused(&self) -> bool {
+		self.paused
+	}
+
+	/// Indicates whether a client has sent `quit`.
+	pub fn is_quit_requested(&self) -> bool {
+		self.quit
+	}
+
+	/// Accepts connections and handles the commands received so far.
+	/// Doesn't block. Call this between runs of the guest when driving
+	/// the emulator without `run()`.
+	///
+	/// # Arguments
+	/// * `emulator`
+	pub fn poll(&mut self, emulator: &mut Emulator) {
+		self.accept();
+		for i in 0..self.clients.len() {
+			for line in self.clients[i].read_lines() {
+				let reply = self.handle_line(emulator, &line);
+				self.clients[i].send(&reply);
+			}
+		}
+		self.flush();
+	}
+
+	/// Runs the program while handling the commands until it stops or
+	/// a client sends `quit`. Returns why the program has stopped, or
+	/// `None` for `quit`.
+	///
+	/// # Arguments
+	/// * `emulator`
+	pub fn run(&mut self, emulator: &mut Emulator) -> Option<StopReason> {
+		loop {
+			self.poll(emulator);
+			if self.quit {
+				return None;
+			}
+			if self.paused {
+				thread::sleep(PAUSE_INTERVAL);
+				continue;
+			}
+			if let Some(reason) = emulator.run_program_cycles(SLICE_CYCLES) {
+				match reason {
+					StopReason::Exited(_) => self.send_event("SHUTDOWN"),
+					_ => self.send_event("STOP")
+				};
+				self.flush();
+				return Some(reason);
+			}
+		}
+	}
+
+	fn accept(&mut self) {
+		loop {
+			let stream = match &self.listener {
+				ControlListener::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+					stream.set_nonblocking(true)?;
+					Ok(Box::new(stream) as Box<dyn ControlStream>)
+				}),
+				#[cfg(unix)]
+				ControlListener::Unix(listener, _) => listener.accept().and_then(|(stream, _)| {
+					stream.set_nonblocking(true)?;
+					Ok(Box::new(stream) as Box<dyn ControlStream>)
+				})
+			};
+			let stream = match stream {
+				Ok(stream) => stream,
+				// WouldBlock when no connection is pending
+				Err(_) => return
+			};
+			let mut client = ControlClient {
+				stream,
+				input: vec![],
+				output: vec![],
+				closed: false
+			};
+			client.send(&json!({
+				"QMP": {
+					"version": {
+						"package": format!("riscv_emu_rust {}", env!("CARGO_PKG_VERSION"))
+					},
+					"capabilities": []
+				}
+			}));
+			self.clients.push(client);
+		}
+	}
+
+	fn flush(&mut self) {
+		for client in self.clients.iter_mut() {
+			client.flush();
+		}
+		self.clients.retain(|client| !client.closed);
+	}
+
+	fn send_event(&mut self, event: &str) {
+		for client in self.clients.iter_mut() {
+			client.send(&json!({
+				"event": event
+			}));
+		}
+	}
+
+	fn handle_line(&mut self, emulator: &mut Emulator, line: &str) -> Value {
+		let request = match serde_json::from_str::<Value>(line) {
+			Ok(request) => request,
+			Err(_) => return json!({
+				"error": {
+					"class": "GenericError",
+					"desc": "Invalid JSON"
+				}
+			})
+		};
+		let arguments = request.get("arguments").cloned().unwrap_or_else(|| json!({}));
+		let result = match request.get("execute").and_then(Value::as_str) {
+			Some(command) => self.execute(emulator, command, &arguments),
+			None => Err(CommandError::new("Expected execute"))
+		};
+		let mut reply = match result {
+			Ok(value) => json!({
+				"return": value
+			}),
+			Err(error) => json!({
+				"error": {
+					"class": error.class,
+					"desc": error.desc
+				}
+			})
+		};
+		if let Some(id) = request.get("id") {
+			reply["id"] = id.clone();
+		}
+		reply
+	}
+
+	fn execute(&mut self, emulator: &mut Emulator, command: &str, arguments: &Value) -> Result<Value, CommandError> {
+		match command {
+			"qmp_capabilities" => {},
+			"query-status" => {
+				let status = match (emulator.get_exit_code(), self.paused) {
+					(Some(_), _) => "shutdown",
+					(None, true) => "paused",
+					(None, false) => "running"
+				};
+				return Ok(json!({
+					"running": status == "running",
+					"status": status
+				}));
+			},
+			"stop" => {
+				self.paused = true;
+				self.send_event("STOP");
+			},
+			"cont" => {
+				self.paused = false;
+				self.send_event("RESUME");
+			},
+			"quit" => self.quit = true,
+			"snapshot-save" => {
+				let tag = get_str(arguments, "tag")?;
+				self.snapshots.insert(tag.to_string(), emulator.take_snapshot());
+			},
+			"snapshot-load" => {
+				let tag = get_str(arguments, "tag")?;
+				let snapshot = self.snapshots.get(tag).ok_or_else(|| CommandError::new("No snapshot with the tag"))?;
+				emulator.restore_snapshot(snapshot);
+			},
+			"snapshot-delete" => {
+				let tag = get_str(arguments, "tag")?;
+				self.snapshots.remove(tag).ok_or_else(|| CommandError::new("No snapshot with the tag"))?;
+			},
+			"send-input" => {
+				let data = get_str(arguments, "data")?;
+				emulator.get_mut_terminal().put_input_bytes(data.as_bytes());
+			},
+			"read-memory" => {
+				let address = get_u64(arguments, "address")?;
+				let size = get_u64(arguments, "size")?;
+				if size > MAX_READ_SIZE {
+					return Err(CommandError::new("Size is too large"));
+				}
+				let mut data = vec![0; size as usize];
+				if let Err(error) = emulator.get_mut_cpu().get_mut_mmu().read_main_memory(address, &mut data) {
+					return Err(CommandError::new(&error.to_string()));
+				}
+				let data = data.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+				return Ok(json!({
+					"data": data
+				}));
+			},
+			#[cfg(feature = "virtio")]
+			"device_add" => {
+				if get_str(arguments, "driver")? != "virtio-blk-device" {
+					return Err(CommandError::new("Only virtio-blk-device can be added"));
+				}
+				let file = get_str(arguments, "file")?;
+				let snapshot = arguments.get("snapshot").and_then(Value::as_bool).unwrap_or(false);
+				let backend = match open_disk_image(file, !snapshot) {
+					Ok(backend) => backend,
+					Err(error) => return Err(CommandError::new(&error.to_string()))
+				};
+				match snapshot {
+					true => emulator.attach_disk(Box::new(OverlayBlockBackend::new(backend))),
+					false => emulator.attach_disk(backend)
+				};
+			},
+			#[cfg(feature = "virtio")]
+			"device_del" => {
+				if get_str(arguments, "id")? != "virtio-blk-device" {
+					return Err(CommandError::new("Only virtio-blk-device can be deleted"));
+				}
+				emulator.detach_disk();
+			},
+			_ => return Err(CommandError {
+				class: "CommandNotFound",
+				desc: format!("The command {} has not been found", command)
+			})
+		};
+		Ok(json!({}))
+	}
+}
+
+impl Drop for ControlServer {
+	fn drop(&mut self) {
+		#[cfg(unix)]
+		if let ControlListener::Unix(_, path) = &self.listener {
+			let _ = fs::remove_file(path);
+		}
+	}
+}
+
+impl ControlClient {
+	// Reads the data available and returns the complete lines
+	fn read_lines(&mut self) -> Vec<String> {
+		let mut buffer = [0; 0x1000];
+		loop {
+			match self.stream.read(&mut buffer) {
+				Ok(0) => {
+					self.closed = true;
+					break;
+				},
+				Ok(size) => self.input.extend_from_slice(&buffer[..size]),
+				Err(ref error) if error.kind() == ErrorKind::Interrupted => {},
+				Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+				Err(_) => {
+					self.closed = true;
+					break;
+				}
+			};
+		}
+		let mut lines = vec![];
+		while let Some(position) = self.input.iter().position(|byte| *byte == b'\n') {
+			let line = self.input.drain(..=position).collect::<Vec<u8>>();
+			let line = String::from_utf8_lossy(&line).trim().to_string();
+			if !line.is_empty() {
+				lines.push(line);
+			}
+		}
+		if self.input.len() > MAX_LINE_LENGTH {
+			self.closed = true;
+		}
+		lines
+	}
+
+	fn send(&mut self, message: &Value) {
+		self.output.extend_from_slice(message.to_string().as_bytes());
+		self.output.extend_from_slice(b"\r\n");
+	}
+
+	// Writes as much output as the socket takes without blocking
+	fn flush(&mut self) {
+		while !self.output.is_empty() && !self.closed {
+			match self.stream.write(&self.output) {
+				Ok(0) => self.closed = true,
+				Ok(size) => {
+					self.output.drain(..size);
+				},
+				Err(ref error) if error.kind() == ErrorKind::Interrupted => {},
+				Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+				Err(_) => self.closed = true
+			};
+		}
+	}
+}
+
+fn get_str<'a>(arguments: &'a Value, name: &str) -> Result<&'a str, CommandError> {
+	arguments.get(name).and_then(Value::as_str).ok_or_else(|| CommandError::new(&format!("Expected string {}", name)))
+}
+
+fn get_u64(arguments: &Value, name: &str) -> Result<u64, CommandError> {
+	arguments.get(name).and_then(Value::as_u64).ok_or_else(|| CommandError::new(&format!("Expected number {}", name)))
+}
+
+#[cfg(test)]
+mod test_control {
+	use std::io::{BufRead, BufReader};
+	use std::net::TcpStream;
+
+	use super::*;
+	use capture_terminal::CaptureTerminal;
+	use config::EmulatorConfig;
+	use mmu::DRAM_BASE;
+
+	// Polls the server until it sends a line
+	fn receive(server: &mut ControlServer, emu: &mut Emulator, reader: &mut BufReader<TcpStream>) -> Value {
+		let mut line = String::new();
+		loop {
+			server.poll(emu);
+			if reader.read_line(&mut line).is_ok() && line.ends_with('\n') {
+				return serde_json::from_str(&line).unwrap();
+			}
+		}
+	}
+
+	fn execute(server: &mut ControlServer, emu: &mut Emulator, reader: &mut BufReader<TcpStream>, command: &str) -> Value {
+		let mut stream = reader.get_ref();
+		stream.write_all(command.as_bytes()).unwrap();
+		stream.write_all(b"\n").unwrap();
+		receive(server, emu, reader)
+	}
+
+	#[test]
+	fn commands() {
+		let mut emu = Emulator::new_with_config(Box::new(CaptureTerminal::new()), EmulatorConfig::default());
+		emu.get_mut_cpu().get_mut_mmu().init_memory(0x1000);
+		emu.get_mut_cpu().get_mut_mmu().store_raw(DRAM_BASE, 0xab);
+		let mut server = ControlServer::bind_tcp("127.0.0.1:0").unwrap();
+		let address = match &server.listener {
+			ControlListener::Tcp(listener) => listener.local_addr().unwrap(),
+			#[cfg(unix)]
+			_ => panic!("Not TCP")
+		};
+		let stream = TcpStream::connect(address).unwrap();
+		stream.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+		let reader = &mut BufReader::new(stream);
+
+		assert!(receive(&mut server, &mut emu, reader).get("QMP").is_some());
+		let reply = execute(&mut server, &mut emu, reader, r#"{"execute": "qmp_capabilities", "id": 1}"#);
+		assert_eq!(json!({"return": {}, "id": 1}), reply);
+
+		let reply = execute(&mut server, &mut emu, reader, r#"{"execute": "stop"}"#);
+		assert_eq!(json!({"event": "STOP"}), reply);
+		assert_eq!(json!({"return": {}}), receive(&mut server, &mut emu, reader));
+		assert!(server.is_paused());
+		let reply = execute(&mut server, &mut emu, reader, r#"{"execute": "query-status"}"#);
+		assert_eq!(json!({"return": {"running": false, "status": "paused"}}), reply);
+
+		let command = format!(r#"{{"execute": "read-memory", "arguments": {{"address": {}, "size": 2}}}}"#, DRAM_BASE);
+		let reply = execute(&mut server, &mut emu, reader, &command);
+		assert_eq!(json!({"return": {"data": "ab00"}}), reply);
+		let reply = execute(&mut server, &mut emu, reader, r#"{"execute": "read-memory", "arguments": {"address": 0, "size": 1}}"#);
+		assert_eq!(Some("GenericError"), reply["error"]["class"].as_str());
+
+		let reply = execute(&mut server, &mut emu, reader, r#"{"execute": "snapshot-load", "arguments": {"tag": "boot"}}"#);
+		assert_eq!(Some("No snapshot with the tag"), reply["error"]["desc"].as_str());
+		let reply = execute(&mut server, &mut emu, reader, r#"{"execute": "reset"}"#);
+		assert_eq!(Some("CommandNotFound"), reply["error"]["class"].as_str());
+		let reply = execute(&mut server, &mut emu, reader, "{");
+		assert_eq!(Some("Invalid JSON"), reply["error"]["desc"].as_str());
+
+		let reply = execute(&mut server, &mut emu, reader, r#"{"execute": "quit"}"#);
+		assert_eq!(json!({"return": {}}), reply);
+		assert_eq!(None, server.run(&mut emu));
+	}
+}
@@ -0,0 +1,297 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use io::{self, Write};
+
+use collections::{FnvHashMap, FnvHashSet};
+use cpu::Cpu;
+use elf_analyzer::ElfAnalyzer;
+
+const BRANCH_OPCODE: u32 = 0x63;
+const JAL_OPCODE: u32 = 0x6f;
+const JALR_OPCODE: u32 = 0x67;
+const SYSTEM_OPCODE: u32 = 0x73;
+
+// Program header type of loadable segments
+const PT_LOAD: u32 = 1;
+
+/// Address range of a binary, e.g. the kernel or a user program,
+/// which DrCov block offsets are relative to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoverageModule {
+	/// Path or name coverage viewers match the binary with
+	pub path: String,
+	/// Virtual address the binary is loaded at
+	pub base: u64,
+	/// End virtual address, exclusive
+	pub end: u64
+}
+
+impl CoverageModule {
+	/// Creates a `CoverageModule` covering the loadable segments of
+	/// an ELF file. Returns `None` if it has no loadable segment.
+	///
+	/// # Arguments
+	/// * `path` Path of the ELF file
+	/// * `data` Content of the ELF file
+	pub fn from_elf(path: &str, data: Vec<u8>) -> Option<Self> {
+		let analyzer = ElfAnalyzer::new(data);
+		let header = analyzer.read_header().ok()?;
+		let segments = analyzer.read_program_headers(&header).ok()?.into_iter()
+			.filter(|program_header| program_header.p_type == PT_LOAD && program_header.p_memsz > 0)
+			.map(|program_header| (program_header.p_vaddr, program_header.p_vaddr.saturating_add(program_header.p_memsz)))
+			.collect::<Vec<(u64, u64)>>();
+		Some(CoverageModule {
+			path: path.to_string(),
+			base: segments.iter().map(|(start, _)| *start).min()?,
+			end: segments.iter().map(|(_, end)| *end).max()?
+		})
+	}
+
+	fn contains(&self, address: u64) -> bool {
+		self.base <= address && address < self.end
+	}
+}
+
+/// Basic block with its executions and disassembly, returned by
+/// `Emulator::get_hot_blocks()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct HotBlock {
+	/// Virtual address
+	pub start: u64,
+	/// Size in bytes
+	pub size: u64,
+	pub executions: u64,
+	/// Instructions as (virtual address, disassembly). Stops where
+	/// the code can't be fetched anymore, e.g. the page is unmapped.
+	pub instructions: Vec<(u64, String)>
+}
+
+/// Tracks the basic blocks, and optionally the edges between them, the
+/// guest has executed for coverage-guided fuzzers and coverage viewers.
+/// Blocks are also counted per execution to find hot code.
+/// A basic block ends at a branch, a jump, a system instruction, or where
+/// an interrupt or an exception diverts the control. Blocks and edges are
+/// keyed by virtual address, so different processes mapping code at
+/// the same address are mixed. Exported in DrCov format for viewers such
+/// as Lighthouse, or JSON like
+///
+/// ```json
+/// {"blocks":[{"start":"0x80000000","size":22}],"edges":[{"from":"0x80000000","to":"0x8000001c"}]}
+/// ```
+///
+/// where `size` is in bytes and addresses are hexadecimal strings because
+/// they may not fit in JSON numbers. Set to `Emulator` with `set_coverage()`.
+pub struct Coverage {
+	edges_enabled: bool,
+	// (start, size) -> executions
+	blocks: FnvHashMap<(u64, u64), u64>,
+	// (start of the source block, start of the destination block)
+	edges: FnvHashSet<(u64, u64)>,
+	// Start of the block being executed
+	block_start: Option<u64>,
+	// Address right after the last instruction executed
+	block_end: u64,
+	// Whether the last instruction executed ends the block
+	block_ended: bool
+}
+
+impl Coverage {
+	/// Creates a new `Coverage`.
+	///
+	/// # Arguments
+	/// * `edges_enabled` Whether tracking the edges too
+	pub fn new(edges_enabled: bool) -> Self {
+		Coverage {
+			edges_enabled,
+			blocks: FnvHashMap::default(),
+			edges: FnvHashSet::default(),
+			block_start: None,
+			block_end: 0,
+			block_ended: false
+		}
+	}
+
+	/// Takes the instruction the CPU is about to run. Call this before
+	/// every `Cpu::tick()`.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn trace(&mut self, cpu: &mut Cpu) {
+		if cpu.is_waiting_for_interrupt() {
+			return;
+		}
+		let pc = cpu.read_pc();
+		if let Some(start) = self.block_start {
+			if self.block_ended || pc != self.block_end {
+				*self.blocks.entry((start, self.block_end.wrapping_sub(start))).or_insert(0) += 1;
+				if self.edges_enabled {
+					self.edges.insert((start, pc));
+				}
+				self.block_start = None;
+			}
+		}
+		let (word, length) = match cpu.fetch_next_instruction() {
+			Some(instruction) => instruction,
+			// The instruction isn't executed
+			None => return
+		};
+		if self.block_start.is_none() {
+			self.block_start = Some(pc);
+		}
+		self.block_end = pc.wrapping_add(length);
+		self.block_ended = match word & 0x7f {
+			BRANCH_OPCODE | JAL_OPCODE | JALR_OPCODE => true,
+			// ECALL, EBREAK, xRET, WFI, and SFENCE.VMA but not CSR instructions
+			SYSTEM_OPCODE => ((word >> 12) & 0x7) == 0,
+			_ => false
+		};
+	}
+
+	/// Returns the blocks executed so far as (start address, size in bytes)
+	/// sorted by address. The block being executed is included.
+	pub fn get_blocks(&self) -> Vec<(u64, u64)> {
+		let mut blocks: Vec<(u64, u64)> = self.blocks.keys().cloned().collect();
+		if let Some(start) = self.block_start {
+			if !self.blocks.contains_key(&(start, self.block_end.wrapping_sub(start))) {
+				blocks.push((start, self.block_end.wrapping_sub(start)));
+			}
+		}
+		blocks.sort();
+		blocks
+	}
+
+	/// Returns the most executed blocks as (start address, size in bytes,
+	/// executions), the most executed first. The block being executed
+	/// counts as an execution.
+	///
+	/// # Arguments
+	/// * `num` Maximum number of the blocks returned
+	pub fn get_hot_blocks(&self, num: usize) -> Vec<(u64, u64, u64)> {
+		let mut blocks = self.blocks.clone();
+		if let Some(start) = self.block_start {
+			*blocks.entry((start, self.block_end.wrapping_sub(start))).or_insert(0) += 1;
+		}
+		let mut blocks: Vec<(u64, u64, u64)> = blocks.into_iter()
+			.map(|((start, size), count)| (start, size, count))
+			.collect();
+		blocks.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+		blocks.truncate(num);
+		blocks
+	}
+
+	/// Returns the edges taken so far as (source block start address,
+	/// destination block start address) sorted by address. Empty unless
+	/// enabled.
+	pub fn get_edges(&self) -> Vec<(u64, u64)> {
+		let mut edges: Vec<(u64, u64)> = self.edges.iter().cloned().collect();
+		edges.sort();
+		edges
+	}
+
+	/// Forgets the blocks and edges executed so far, e.g. between fuzzing inputs.
+	pub fn clear(&mut self) {
+		self.blocks.clear();
+		self.edges.clear();
+		self.block_start = None;
+	}
+
+	/// Writes the blocks in DrCov version 2 format. Blocks out of
+	/// the modules are left out.
+	///
+	/// # Arguments
+	/// * `writer`
+	/// * `modules` Binaries the blocks belong to
+	pub fn write_drcov(&self, writer: &mut dyn Write, modules: &[CoverageModule]) -> io::Result<()> {
+		let blocks: Vec<(usize, u64, u64)> = self.get_blocks().into_iter()
+			.filter_map(|(start, size)| modules.iter().position(|module| module.contains(start))
+				.map(|id| (id, start - modules[id].base, size)))
+			.collect();
+		let mut header = String::new();
+		header += "DRCOV VERSION: 2\nDRCOV FLAVOR: drcov\n";
+		header += &format!("Module Table: version 2, count {}\n", modules.len());
+		header += "Columns: id, base, end, entry, checksum, timestamp, path\n";
+		for (id, module) in modules.iter().enumerate() {
+			header += &format!("{:3}, 0x{:016x}, 0x{:016x}, 0x{:016x}, 0x{:08x}, 0x{:08x}, {}\n",
+				id, module.base, module.end, 0, 0, 0, module.path);
+		}
+		header += &format!("BB Table: {} bbs\n", blocks.len());
+		writer.write_all(header.as_bytes())?;
+		for (id, offset, size) in blocks.iter() {
+			writer.write_all(&(*offset as u32).to_le_bytes())?;
+			writer.write_all(&((*size).min(0xffff) as u16).to_le_bytes())?;
+			writer.write_all(&(*id as u16).to_le_bytes())?;
+		}
+		writer.flush()
+	}
+
+	/// Writes the blocks and the edges in JSON.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn write_json(&self, writer: &mut dyn Write) -> io::Result<()> {
+		let blocks = self.get_blocks().iter()
+			.map(|(start, size)| format!("{{\"start\":\"0x{:x}\",\"size\":{}}}", start, size))
+			.collect::<Vec<String>>()
+			.join(",");
+		let edges = self.get_edges().iter()
+			.map(|(from, to)| format!("{{\"from\":\"0x{:x}\",\"to\":\"0x{:x}\"}}", from, to))
+			.collect::<Vec<String>>()
+			.join(",");
+		writeln!(writer, "{{\"blocks\":[{}],\"edges\":[{}]}}", blocks, edges)?;
+		writer.flush()
+	}
+}
+
+#[cfg(test)]
+mod test_coverage {
+	use super::*;
+	use alloc::boxed::Box;
+	use mmu::DRAM_BASE;
+	use terminal::DummyTerminal;
+
+	#[test]
+	fn blocks_and_edges() {
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x100);
+		cpu.update_pc(DRAM_BASE);
+		let code = [
+			0x00150513, // addi a0, a0, 1
+			0x00300593, // addi a1, zero, 3
+			0xfeb51ce3, // bne a0, a1, -8
+			0x0000006f // jal zero, 0
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		let mut coverage = Coverage::new(true);
+		// Loops three times then jumps to itself
+		for _ in 0..11 {
+			coverage.trace(&mut cpu);
+			cpu.tick();
+		}
+		assert_eq!(vec![(DRAM_BASE, 12), (DRAM_BASE + 12, 4)], coverage.get_blocks());
+		assert_eq!(vec![
+			(DRAM_BASE, DRAM_BASE),
+			(DRAM_BASE, DRAM_BASE + 12),
+			(DRAM_BASE + 12, DRAM_BASE + 12)
+		], coverage.get_edges());
+		assert_eq!(vec![(DRAM_BASE, 12, 3), (DRAM_BASE + 12, 4, 2)], coverage.get_hot_blocks(10));
+		assert_eq!(vec![(DRAM_BASE, 12, 3)], coverage.get_hot_blocks(1));
+
+		let mut json = vec![];
+		assert!(coverage.write_json(&mut json).is_ok());
+		assert!(String::from_utf8_lossy(&json).starts_with("{\"blocks\":[{\"start\":\"0x80000000\",\"size\":12},"));
+
+		let mut drcov = vec![];
+		let module = CoverageModule {
+			path: "test".to_string(),
+			base: DRAM_BASE,
+			end: DRAM_BASE + 0x100
+		};
+		assert!(coverage.write_drcov(&mut drcov, &[module]).is_ok());
+		assert_eq!(&[12, 0, 0, 0, 4, 0, 0, 0], &drcov[drcov.len() - 8..]);
+	}
+}
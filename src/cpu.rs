@@ -1,12 +1,30 @@
-extern crate fnv;
-extern crate sha3;
+extern crate once_cell;
 extern crate rand;
 
-use self::fnv::FnvHashMap;
-use self::sha3::{Digest, Sha3_256};
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "fd")]
+use core::cmp::Ordering;
+
+use self::once_cell::race::OnceBox;
+use collections::FnvHashMap;
 use self::rand::Rng;
+use random::create_rng;
 use mmu::{AddressingMode, Mmu};
 use terminal::Terminal;
+use config::EmulatorConfig;
+use trap_log::{TrapLog, TrapRecord};
+use error::{DebugError, ExecError};
+use zipper_stack::{ZipperMac, KEY_CONTROL_LOCK, KEY_CONTROL_MASK, KEY_CONTROL_PER_ASID, KEY_CONTROL_PER_PRIVILEGE,
+	KEY_CONTROL_ROTATE, RETURN_ADDRESS_MASK, get_key_slot};
+use cfi_statistics::CfiStatistics;
+#[cfg(feature = "fd")]
+use softfloat::{get_rounding_mode, RoundingMode, DOUBLE, SINGLE};
+#[cfg(all(feature = "fd", not(feature = "native-float")))]
+use softfloat as float;
+#[cfg(all(feature = "fd", feature = "native-float"))]
+use softfloat::native as float;
 
 const CSR_CAPACITY: usize = 4096;
 
@@ -16,21 +34,25 @@ const CSR_FRM_ADDRESS: u16 = 0x002;
 const CSR_FCSR_ADDRESS: u16 = 0x003;
 const CSR_UIE_ADDRESS: u16 = 0x004;
 const CSR_UTVEC_ADDRESS: u16 = 0x005;
-const _CSR_USCRATCH_ADDRESS: u16 = 0x040;
+const CSR_SSP_ADDRESS: u16 = 0x011;
+const CSR_USCRATCH_ADDRESS: u16 = 0x040;
 const CSR_UEPC_ADDRESS: u16 = 0x041;
 const CSR_UCAUSE_ADDRESS: u16 = 0x042;
 const CSR_UTVAL_ADDRESS: u16 = 0x043;
-const _CSR_UIP_ADDRESS: u16 = 0x044;
+const CSR_UIP_ADDRESS: u16 = 0x044;
 const CSR_SSTATUS_ADDRESS: u16 = 0x100;
 const CSR_SEDELEG_ADDRESS: u16 = 0x102;
 const CSR_SIDELEG_ADDRESS: u16 = 0x103;
 const CSR_SIE_ADDRESS: u16 = 0x104;
 const CSR_STVEC_ADDRESS: u16 = 0x105;
-const _CSR_SSCRATCH_ADDRESS: u16 = 0x140;
+const CSR_SSCRATCH_ADDRESS: u16 = 0x140;
 const CSR_SEPC_ADDRESS: u16 = 0x141;
 const CSR_SCAUSE_ADDRESS: u16 = 0x142;
 const CSR_STVAL_ADDRESS: u16 = 0x143;
 const CSR_SIP_ADDRESS: u16 = 0x144;
+const CSR_SISELECT_ADDRESS: u16 = 0x150;
+const CSR_SIREG_ADDRESS: u16 = 0x151;
+const CSR_STOPEI_ADDRESS: u16 = 0x15c;
 const CSR_SATP_ADDRESS: u16 = 0x180;
 const CSR_MSTATUS_ADDRESS: u16 = 0x300;
 const CSR_MISA_ADDRESS: u16 = 0x301;
@@ -40,25 +62,167 @@ const CSR_MIE_ADDRESS: u16 = 0x304;
 
 
 const CSR_MTVEC_ADDRESS: u16 = 0x305;
-const _CSR_MSCRATCH_ADDRESS: u16 = 0x340;
+const CSR_MSCRATCH_ADDRESS: u16 = 0x340;
 const CSR_MEPC_ADDRESS: u16 = 0x341;
 const CSR_MCAUSE_ADDRESS: u16 = 0x342;
 const CSR_MTVAL_ADDRESS: u16 = 0x343;
 const CSR_MIP_ADDRESS: u16 = 0x344;
-const _CSR_PMPCFG0_ADDRESS: u16 = 0x3a0;
-const _CSR_PMPADDR0_ADDRESS: u16 = 0x3b0;
+const CSR_MISELECT_ADDRESS: u16 = 0x350;
+const CSR_MIREG_ADDRESS: u16 = 0x351;
+const CSR_MTOPEI_ADDRESS: u16 = 0x35c;
+const CSR_PMPCFG0_ADDRESS: u16 = 0x3a0;
+const CSR_PMPADDR0_ADDRESS: u16 = 0x3b0;
+const CSR_MZKEYCTL_ADDRESS: u16 = 0x7c0;
+const CSR_MZKEYSEL_ADDRESS: u16 = 0x7c1;
+const CSR_MZKEY_ADDRESS: u16 = 0x7c2;
 const _CSR_MCYCLE_ADDRESS: u16 = 0xb00;
 const CSR_CYCLE_ADDRESS: u16 = 0xc00;
 const CSR_TIME_ADDRESS: u16 = 0xc01;
 const _CSR_INSERT_ADDRESS: u16 = 0xc02;
-const _CSR_MHARTID_ADDRESS: u16 = 0xf14;
+const CSR_CYCLEH_ADDRESS: u16 = 0xc80;
+const CSR_TIMEH_ADDRESS: u16 = 0xc81;
+const CSR_STOPI_ADDRESS: u16 = 0xdb0;
+const CSR_MHARTID_ADDRESS: u16 = 0xf14;
+const CSR_MTOPI_ADDRESS: u16 = 0xfb0;
+
+// CSRs the host can access with `Cpu::read_csr_as()` and `write_csr_as()`
+// in the number order
+const CSR_NAMES: [(u16, &str); 52] = [
+	(CSR_USTATUS_ADDRESS, "ustatus"),
+	(CSR_FFLAGS_ADDRESS, "fflags"),
+	(CSR_FRM_ADDRESS, "frm"),
+	(CSR_FCSR_ADDRESS, "fcsr"),
+	(CSR_UIE_ADDRESS, "uie"),
+	(CSR_UTVEC_ADDRESS, "utvec"),
+	(CSR_SSP_ADDRESS, "ssp"),
+	(CSR_USCRATCH_ADDRESS, "uscratch"),
+	(CSR_UEPC_ADDRESS, "uepc"),
+	(CSR_UCAUSE_ADDRESS, "ucause"),
+	(CSR_UTVAL_ADDRESS, "utval"),
+	(CSR_UIP_ADDRESS, "uip"),
+	(CSR_SSTATUS_ADDRESS, "sstatus"),
+	(CSR_SEDELEG_ADDRESS, "sedeleg"),
+	(CSR_SIDELEG_ADDRESS, "sideleg"),
+	(CSR_SIE_ADDRESS, "sie"),
+	(CSR_STVEC_ADDRESS, "stvec"),
+	(CSR_SSCRATCH_ADDRESS, "sscratch"),
+	(CSR_SEPC_ADDRESS, "sepc"),
+	(CSR_SCAUSE_ADDRESS, "scause"),
+	(CSR_STVAL_ADDRESS, "stval"),
+	(CSR_SIP_ADDRESS, "sip"),
+	(CSR_SISELECT_ADDRESS, "siselect"),
+	(CSR_SIREG_ADDRESS, "sireg"),
+	(CSR_STOPEI_ADDRESS, "stopei"),
+	(CSR_SATP_ADDRESS, "satp"),
+	(CSR_MSTATUS_ADDRESS, "mstatus"),
+	(CSR_MISA_ADDRESS, "misa"),
+	(CSR_MEDELEG_ADDRESS, "medeleg"),
+	(CSR_MIDELEG_ADDRESS, "mideleg"),
+	(CSR_MIE_ADDRESS, "mie"),
+	(CSR_MTVEC_ADDRESS, "mtvec"),
+	(CSR_MSCRATCH_ADDRESS, "mscratch"),
+	(CSR_MEPC_ADDRESS, "mepc"),
+	(CSR_MCAUSE_ADDRESS, "mcause"),
+	(CSR_MTVAL_ADDRESS, "mtval"),
+	(CSR_MIP_ADDRESS, "mip"),
+	(CSR_MISELECT_ADDRESS, "miselect"),
+	(CSR_MIREG_ADDRESS, "mireg"),
+	(CSR_MTOPEI_ADDRESS, "mtopei"),
+	(CSR_PMPCFG0_ADDRESS, "pmpcfg0"),
+	(CSR_PMPADDR0_ADDRESS, "pmpaddr0"),
+	(CSR_MZKEYCTL_ADDRESS, "mzkeyctl"),
+	(CSR_MZKEYSEL_ADDRESS, "mzkeysel"),
+	(CSR_MZKEY_ADDRESS, "mzkey"),
+	(CSR_CYCLE_ADDRESS, "cycle"),
+	(CSR_TIME_ADDRESS, "time"),
+	(CSR_CYCLEH_ADDRESS, "cycleh"),
+	(CSR_TIMEH_ADDRESS, "timeh"),
+	(CSR_STOPI_ADDRESS, "stopi"),
+	(CSR_MHARTID_ADDRESS, "mhartid"),
+	(CSR_MTOPI_ADDRESS, "mtopi")
+];
 
-const MIP_MEIP: u64 = 0x800;
+// misa at reset, MXL of RV64 and the extensions implemented
+#[cfg(feature = "fd")]
+const MISA_DEFAULT: u64 = 0x800000008014312f;
+#[cfg(not(feature = "fd"))]
+const MISA_DEFAULT: u64 = 0x8000000080143107;
+// Upper bits of f registers holding single precision values, NaN-boxed
+#[cfg(feature = "fd")]
+const SINGLE_NAN_BOX: u64 = 0xffffffff00000000;
+// Extensions field of misa, one bit per letter
+const MISA_EXTENSIONS_MASK: u64 = 0x3ffffff;
+const MISA_A: u64 = 1 << 0;
+const MISA_C: u64 = 1 << 2;
+const MISA_D: u64 = 1 << 3;
+const MISA_F: u64 = 1 << 5;
+const MISA_M: u64 = 1 << 12;
+// Extensions guests can disable and enable again in misa
+const MISA_TOGGLEABLE_MASK: u64 = MISA_A | MISA_C | MISA_D | MISA_F | MISA_M;
+// Writable fields of mstatus: SIE, MIE, SPIE, MPIE, SPP, MPP, FS, MPRV,
+// SUM, MXR, SPELP, and MPELP. The others are read-only zero
+#[cfg(feature = "fd")]
+const MSTATUS_WRITABLE_MASK: u64 = 0x200008e79aa;
+#[cfg(not(feature = "fd"))]
+const MSTATUS_WRITABLE_MASK: u64 = 0x200008e19aa;
+// Dirty state of FS field of mstatus
+const MSTATUS_FS_DIRTY: u64 = 0x6000;
+// UXL and SXL fields of mstatus, read-only XLEN of User and Supervisor modes
+const MSTATUS_XL_MASK: u64 = 0xf00000000;
+// UXL and SXL telling that the modes run with XLEN=64
+const MSTATUS_XL_64: u64 = 0xa00000000;
+// Fields of sstatus, a view of mstatus
+const SSTATUS_MASK: u64 = 0x80000003008de162;
+// Implemented exceptions medeleg can delegate. Environment calls from
+// Machine mode can't be
+const MEDELEG_WRITABLE_MASK: u64 = 0x4b3ff;
+
+pub const MIP_MEIP: u64 = 0x800;
 pub const MIP_MTIP: u64 = 0x080;
 pub const MIP_MSIP: u64 = 0x008;
 pub const MIP_SEIP: u64 = 0x200;
 const MIP_STIP: u64 = 0x020;
-const MIP_SSIP: u64 = 0x002;
+pub const MIP_SSIP: u64 = 0x002;
+
+// Major interrupt numbers in the default priority order, highest first
+const MAJOR_INTERRUPT_PRIORITIES: [u64; 6] = [11, 3, 7, 9, 1, 5];
+
+/// Instruction set extension an instruction belongs to, in
+/// `Cpu::get_instruction_statistics()`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Extension {
+	/// Base integer instructions including ECALL and EBREAK
+	I,
+	M,
+	A,
+	F,
+	D,
+	/// Compressed instructions, counted apart from what they expand to
+	C,
+	Zicsr,
+	Zifencei,
+	/// xRET, WFI, and SFENCE.VMA
+	Privileged,
+	/// Shadow stack instructions
+	Zicfiss,
+	/// Instructions of custom extensions
+	Custom
+}
+
+const EXTENSION_NUM: usize = 11;
+const EXTENSIONS: [Extension; EXTENSION_NUM] = [
+	Extension::I,
+	Extension::M,
+	Extension::A,
+	Extension::F,
+	Extension::D,
+	Extension::C,
+	Extension::Zicsr,
+	Extension::Zifencei,
+	Extension::Privileged,
+	Extension::Zicfiss,
+	Extension::Custom
+];
 
 /// Emulates a RISC-V CPU core
 pub struct Cpu {
@@ -78,11 +242,30 @@ pub struct Cpu {
 	_dump_flag: bool,
 	decode_cache: DecodeCache,
 	unsigned_data_mask: u64,
-	hasher: Sha3_256, //added by ez2take
-	top: u64,         //added by ez2take using upper 25bits
-	key: u64          //added by ez2take
+	zipper_mac: ZipperMac, //added by ez2take
+	top: u64,              //added by ez2take using upper 25bits
+	/// The last exception taken since `take_exception()`
+	exception: Option<Exception>,
+	// Retired instructions indexed by extension and privilege mode
+	// encoding, while enabled
+	instruction_statistics: Option<[[u64; 4]; EXTENSION_NUM]>,
+	// Forward-edge CFI statistics, kept while landing pads are checked
+	cfi_statistics: Option<CfiStatistics>,
+	// ELP of Zicfilp, set by an indirect call or jump until the target
+	// instruction is checked to be a landing pad
+	expected_landing_pad: bool,
+	// Extension bits of misa guests can write, the toggleable ones of
+	// the configuration
+	misa_writable: u64,
+	// Extensions the guest has disabled in misa. Their instructions raise
+	// illegal instruction exceptions
+	disabled_extensions: u64,
+	trap_log: Option<TrapLog>,
+	/// The first internal error since `take_error()`
+	error: Option<ExecError>
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Clone)]
 pub enum Xlen {
 	Bit32,
@@ -90,7 +273,8 @@ pub enum Xlen {
 	// @TODO: Support Bit128
 }
 
-#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
 #[allow(dead_code)]
 pub enum PrivilegeMode {
 	User,
@@ -99,6 +283,129 @@ pub enum PrivilegeMode {
 	Machine
 }
 
+/// Architectural state changed by a step, returned by `Cpu::step_diff()`.
+/// The changes are listed as (register, old value, new value) in
+/// the register or address order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateDiff {
+	/// Program counter before and after the step
+	pub pc: (u64, u64),
+	/// Privilege mode before and after the step if it has changed
+	pub privilege_mode: Option<(PrivilegeMode, PrivilegeMode)>,
+	/// Integer registers
+	pub x: Vec<(u8, i64, i64)>,
+	/// Floating point registers in raw bits, so NaN changes are seen
+	pub f: Vec<(u8, u64, u64)>,
+	/// CSRs as stored. Fields of CSRs which are views of others, e.g.
+	/// `sstatus`, appear under the backing CSR, e.g. `mstatus`.
+	/// `cycle` changes every step.
+	pub csr: Vec<(u16, u64, u64)>,
+	/// Main memory bytes by physical address
+	pub memory: Vec<(u64, u8, u8)>
+}
+
+/// Exception taken by `Cpu`, returned by `Cpu::take_exception()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exception {
+	/// Exception code written to `mcause` or `scause`
+	pub cause: u64,
+	/// Address of the instruction raising the exception
+	pub pc: u64,
+	/// Value written to `mtval` or `stval`, e.g. the faulting address
+	pub value: u64
+}
+
+/// CPU state saved by `Cpu::take_snapshot()`. Includes `mtime` of CLINT
+/// so timer interrupts come at the same timing after restoring.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CpuSnapshot {
+	clock: u64,
+	xlen: Xlen,
+	privilege_mode: PrivilegeMode,
+	wfi: bool,
+	x: [i64; 32],
+	#[cfg_attr(feature = "serde", serde(with = "::serde_support::f64_bits"))]
+	f: [f64; 32],
+	pc: u64,
+	#[cfg_attr(feature = "serde", serde(with = "::serde_support::boxed_array"))]
+	csr: Box<[u64; CSR_CAPACITY]>,
+	mtime: u64
+}
+
+/// Kind of instruction, in `DecodedInstruction`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InstructionClass {
+	/// Integer computation including LUI and AUIPC
+	Alu,
+	/// Integer multiplication and division of M extension
+	MulDiv,
+	/// Memory loads including floating point ones
+	Load,
+	/// Memory stores including floating point ones
+	Store,
+	/// Conditional branches
+	Branch,
+	/// JAL and JALR
+	Jump,
+	/// LR, SC, and AMOs of A extension
+	Atomic,
+	/// Floating point computation and moves between register files
+	FloatingPoint,
+	/// CSR accesses
+	Csr,
+	/// ECALL, EBREAK, xRET, WFI, and SFENCE.VMA
+	System,
+	/// FENCE and FENCE.I
+	Fence,
+	/// Instructions of custom extensions
+	Custom
+}
+
+/// Operand of `DecodedInstruction`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+	/// Integer register number
+	Register(u8),
+	/// Floating point register number
+	FRegister(u8),
+	/// Sign-extended immediate. Branch and jump offsets are relative to
+	/// the instruction, and shift amounts and CSR `uimm` are as is
+	Immediate(i64),
+	/// CSR number
+	Csr(u16)
+}
+
+/// Instruction decoded by `decode()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedInstruction {
+	/// Name of the uncompressed instruction, e.g. `ADDI`
+	pub mnemonic: &'static str,
+	pub class: InstructionClass,
+	/// The destination register if any, the source registers in `rs1`,
+	/// `rs2`, and `rs3` order, then the CSR and the immediate if any
+	pub operands: Vec<Operand>,
+	/// The immediate, also in `operands`
+	pub immediate: Option<i64>,
+	/// Raw bits of the uncompressed instruction
+	pub word: u32,
+	/// Length in bytes, 2 or 4
+	pub length: u64
+}
+
+/// Instruction pointed by Program Counter, returned by
+/// `Cpu::get_next_instruction()`
+pub struct NextInstruction {
+	/// Raw bits. Lower 16 bits for compressed instruction
+	pub word: u32,
+	/// Length in bytes, 2 or 4
+	pub length: u64,
+	/// Name of the uncompressed instruction, e.g. `ADDI`
+	pub name: &'static str,
+	/// Operands without register values, e.g. `a0,a0,24`
+	pub operands: String
+}
+
 pub struct Trap {
 	pub trap_type: TrapType,
 	pub value: u64 // Trap type specific value
@@ -120,6 +427,8 @@ pub enum TrapType {
 	InstructionPageFault,
 	LoadPageFault,
 	StorePageFault,
+	/// Shadow stack fault of Zicfiss with the trap value 3
+	SoftwareCheck,
 	UserSoftwareInterrupt,
 	SupervisorSoftwareInterrupt,
 	MachineSoftwareInterrupt,
@@ -131,6 +440,27 @@ pub enum TrapType {
 	MachineExternalInterrupt
 }
 
+fn has_csr_access_privilege(address: u16, privilege_mode: &PrivilegeMode) -> bool {
+	let privilege = (address >> 8) & 0x3; // the lowest privilege level that can access the CSR
+	privilege as u8 <= get_privilege_encoding(privilege_mode)
+}
+
+/// Returns the name of the CSR, e.g. `mstatus`, if it's implemented.
+///
+/// # Arguments
+/// * `address` CSR number
+pub fn get_csr_name(address: u16) -> Option<&'static str> {
+	CSR_NAMES.iter().find(|(csr, _)| *csr == address).map(|(_, name)| *name)
+}
+
+/// Returns the number of the CSR with the name if it's implemented.
+///
+/// # Arguments
+/// * `name` For example `mstatus`
+pub fn get_csr_address(name: &str) -> Option<u16> {
+	CSR_NAMES.iter().find(|(_, csr_name)| *csr_name == name).map(|(address, _)| *address)
+}
+
 fn _get_privilege_mode_name(mode: &PrivilegeMode) -> &'static str {
 	match mode {
 		PrivilegeMode::User => "User",
@@ -141,7 +471,8 @@ fn _get_privilege_mode_name(mode: &PrivilegeMode) -> &'static str {
 }
 
 // bigger number is higher privilege level
-fn get_privilege_encoding(mode: &PrivilegeMode) -> u8 {
+/// Returns encoded privilege mode bits
+pub fn get_privilege_encoding(mode: &PrivilegeMode) -> u8 {
 	match mode {
 		PrivilegeMode::User => 0,
 		PrivilegeMode::Supervisor => 1,
@@ -160,7 +491,229 @@ pub fn get_privilege_mode(encoding: u64) -> PrivilegeMode {
 	}
 }
 
-fn _get_trap_type_name(trap_type: &TrapType) -> &'static str {
+/// Disassembles an instruction without `Cpu`, e.g. `ADDI a0,a0,1`, so that
+/// tools can disassemble arbitrary buffers. A compressed instruction is
+/// shown as the instruction it expands to. An unknown one is shown
+/// like `.word 0xffffffff` or `.half 0x0000`.
+///
+/// # Arguments
+/// * `bits` Instruction. A compressed instruction in the lower 16 bits
+/// * `pc` Address of the instruction, for branch and jump targets
+/// * `xlen` Compressed instructions differ between 32-bit and 64-bit
+pub fn disassemble(bits: u32, pc: u64, xlen: &Xlen) -> String {
+	let (word, _) = expand_instruction(bits, xlen);
+	match Cpu::decode_and_get_instruction_index(word) {
+		Ok(index) => {
+			let inst = &INSTRUCTIONS[index];
+			format!("{} {}", inst.name, (inst.disassemble)(None, word, pc)).trim_end().to_string()
+		},
+		Err(()) => match (bits & 0x3) == 0x3 {
+			true => format!(".word 0x{:08x}", bits),
+			false => format!(".half 0x{:04x}", bits & 0xffff)
+		}
+	}
+}
+
+/// Decodes an instruction without `Cpu` into machine-readable fields for
+/// analysis tools. A compressed instruction is decoded as the instruction
+/// it expands to. Returns `None` for an unknown instruction.
+///
+/// # Arguments
+/// * `bits` Instruction. A compressed instruction in the lower 16 bits
+/// * `xlen` Compressed instructions differ between 32-bit and 64-bit
+pub fn decode(bits: u32, xlen: &Xlen) -> Option<DecodedInstruction> {
+	let (word, length) = expand_instruction(bits, xlen);
+	let inst = &INSTRUCTIONS[Cpu::decode_and_get_instruction_index(word).ok()?];
+	let (class, operands) = get_class_and_operands(word);
+	let immediate = operands.iter().find_map(|operand| match operand {
+		Operand::Immediate(imm) => Some(*imm),
+		_ => None
+	});
+	Some(DecodedInstruction {
+		mnemonic: inst.name,
+		class,
+		operands,
+		immediate,
+		word,
+		length
+	})
+}
+
+/// Decodes an instruction from the bytes in memory order like `decode()`,
+/// e.g. from a fuzzer input. Like `decode()`, this is a pure function
+/// which never panics on any input. Returns `None` if the bytes are too
+/// short for the instruction or it's unknown.
+///
+/// # Arguments
+/// * `bytes` Two bytes of a compressed instruction or four bytes
+/// * `xlen` Compressed instructions differ between 32-bit and 64-bit
+pub fn decode_bytes(bytes: &[u8], xlen: &Xlen) -> Option<DecodedInstruction> {
+	let halfword = u16::from_le_bytes([*bytes.first()?, *bytes.get(1)?]) as u32;
+	let bits = match (halfword & 0x3) == 0x3 {
+		true => halfword | ((u16::from_le_bytes([*bytes.get(2)?, *bytes.get(3)?]) as u32) << 16),
+		false => halfword
+	};
+	decode(bits, xlen)
+}
+
+/// Expands a compressed instruction to the uncompressed one and returns
+/// it with the length of the original one, 2 or 4 bytes. An uncompressed
+/// one is returned as it is. `Cpu` runs instructions expanded with this,
+/// and an invalid compressed one expands to an invalid one.
+///
+/// # Arguments
+/// * `bits` Instruction. A compressed instruction in the lower 16 bits
+/// * `xlen` Compressed instructions differ between 32-bit and 64-bit
+pub fn expand_instruction(bits: u32, xlen: &Xlen) -> (u32, u64) {
+	match (bits & 0x3) == 0x3 {
+		true => (bits, 4),
+		false => (Cpu::uncompress(bits & 0xffff, xlen), 2)
+	}
+}
+
+// Returns the class and the operands of a valid uncompressed instruction
+fn get_class_and_operands(word: u32) -> (InstructionClass, Vec<Operand>) {
+	let x = |num: usize| Operand::Register(num as u8);
+	let fr = |num: usize| Operand::FRegister(num as u8);
+	let funct3 = (word >> 12) & 0x7;
+	match word & 0x7f {
+		// LUI and AUIPC
+		0x37 | 0x17 => {
+			let f = parse_format_u(word);
+			(InstructionClass::Alu, vec![x(f.rd), Operand::Immediate(f.imm as i64)])
+		},
+		0x6f => {
+			let f = parse_format_j(word);
+			(InstructionClass::Jump, vec![x(f.rd), Operand::Immediate(f.imm as i64)])
+		},
+		0x67 => {
+			let f = parse_format_i(word);
+			(InstructionClass::Jump, vec![x(f.rd), x(f.rs1), Operand::Immediate(f.imm)])
+		},
+		0x63 => {
+			let f = parse_format_b(word);
+			(InstructionClass::Branch, vec![x(f.rs1), x(f.rs2), Operand::Immediate(f.imm as i64)])
+		},
+		0x03 | 0x07 => {
+			let f = parse_format_i(word);
+			let rd = match word & 0x7f {
+				0x03 => x(f.rd),
+				_ => fr(f.rd)
+			};
+			(InstructionClass::Load, vec![rd, x(f.rs1), Operand::Immediate(f.imm)])
+		},
+		0x23 | 0x27 => {
+			let f = parse_format_s(word);
+			let rs2 = match word & 0x7f {
+				0x23 => x(f.rs2),
+				_ => fr(f.rs2)
+			};
+			(InstructionClass::Store, vec![x(f.rs1), rs2, Operand::Immediate(f.imm)])
+		},
+		0x13 | 0x1b => {
+			let f = parse_format_i(word);
+			let imm = match (word & 0x7f, funct3) {
+				// Shift amount
+				(0x13, 1) | (0x13, 5) => ((word >> 20) & 0x3f) as i64,
+				(0x1b, 1) | (0x1b, 5) => ((word >> 20) & 0x1f) as i64,
+				_ => f.imm
+			};
+			(InstructionClass::Alu, vec![x(f.rd), x(f.rs1), Operand::Immediate(imm)])
+		},
+		0x33 | 0x3b => {
+			let f = parse_format_r(word);
+			let class = match word >> 25 {
+				1 => InstructionClass::MulDiv,
+				_ => InstructionClass::Alu
+			};
+			(class, vec![x(f.rd), x(f.rs1), x(f.rs2)])
+		},
+		0x2f => {
+			let f = parse_format_r(word);
+			match word >> 27 {
+				// LR
+				2 => (InstructionClass::Atomic, vec![x(f.rd), x(f.rs1)]),
+				_ => (InstructionClass::Atomic, vec![x(f.rd), x(f.rs1), x(f.rs2)])
+			}
+		},
+		0x0f => (InstructionClass::Fence, vec![]),
+		0x73 => {
+			let f = parse_format_csr(word);
+			match funct3 {
+				// SFENCE.VMA
+				0 if (word >> 25) == 0x09 => (InstructionClass::System, vec![x(f.rs), x(((word >> 20) & 0x1f) as usize)]),
+				0 => (InstructionClass::System, vec![]),
+				1..=3 => (InstructionClass::Csr, vec![x(f.rd), x(f.rs), Operand::Csr(f.csr)]),
+				_ => (InstructionClass::Csr, vec![x(f.rd), Operand::Csr(f.csr), Operand::Immediate(f.rs as i64)])
+			}
+		},
+		0x53 => {
+			let f = parse_format_r(word);
+			let operands = match word >> 27 {
+				// Comparisons
+				0x14 => vec![x(f.rd), fr(f.rs1), fr(f.rs2)],
+				// Conversions to integers, FMV.X.*, and FCLASS
+				0x18 | 0x1c => vec![x(f.rd), fr(f.rs1)],
+				// Conversions from integers and FMV.*.X
+				0x1a | 0x1e => vec![fr(f.rd), x(f.rs1)],
+				// Conversions between precisions and square roots
+				0x08 | 0x0b => vec![fr(f.rd), fr(f.rs1)],
+				_ => vec![fr(f.rd), fr(f.rs1), fr(f.rs2)]
+			};
+			(InstructionClass::FloatingPoint, operands)
+		},
+		// Fused multiply-add
+		0x43 | 0x47 | 0x4b | 0x4f => {
+			let f = parse_format_r2(word);
+			(InstructionClass::FloatingPoint, vec![fr(f.rd), fr(f.rs1), fr(f.rs2), fr(f.rs3)])
+		},
+		// ZIP and UNZIP of custom-0 updating rd in place
+		_ => (InstructionClass::Custom, vec![x(((word >> 7) & 0x1f) as usize)])
+	}
+}
+
+// Returns the extension of a valid instruction
+//
+// # Arguments
+// * `original_word` Instruction as fetched
+// * `word` The uncompressed instruction
+fn get_extension(original_word: u32, word: u32) -> Extension {
+	if (original_word & 0x3) != 0x3 {
+		return Extension::C;
+	}
+	let funct3 = (word >> 12) & 0x7;
+	// fmt field of floating point instructions. 0 for single, 1 for double
+	let fmt = (word >> 25) & 0x3;
+	match word & 0x7f {
+		0x33 | 0x3b if (word >> 25) == 1 => Extension::M,
+		0x2f => Extension::A,
+		0x07 | 0x27 => match funct3 {
+			2 => Extension::F,
+			_ => Extension::D
+		},
+		// FCVT.S.D and FCVT.D.S
+		0x53 if (word >> 27) == 0x08 => Extension::D,
+		0x43 | 0x47 | 0x4b | 0x4f | 0x53 => match fmt {
+			0 => Extension::F,
+			_ => Extension::D
+		},
+		0x0f => match funct3 {
+			1 => Extension::Zifencei,
+			_ => Extension::I
+		},
+		0x73 => match (funct3, word) {
+			// ECALL and EBREAK
+			(0, 0x00000073) | (0, 0x00100073) => Extension::I,
+			(0, _) => Extension::Privileged,
+			(4, _) => Extension::Zicfiss,
+			_ => Extension::Zicsr
+		},
+		0x0b => Extension::Custom,
+		_ => Extension::I
+	}
+}
+
+fn get_trap_type_name(trap_type: &TrapType) -> &'static str {
 	match trap_type {
 		TrapType::InstructionAddressMisaligned => "InstructionAddressMisaligned",
 		TrapType::InstructionAccessFault => "InstructionAccessFault",
@@ -176,6 +729,7 @@ fn _get_trap_type_name(trap_type: &TrapType) -> &'static str {
 		TrapType::InstructionPageFault => "InstructionPageFault",
 		TrapType::LoadPageFault => "LoadPageFault",
 		TrapType::StorePageFault => "StorePageFault",
+		TrapType::SoftwareCheck => "SoftwareCheck",
 		TrapType::UserSoftwareInterrupt => "UserSoftwareInterrupt",
 		TrapType::SupervisorSoftwareInterrupt => "SupervisorSoftwareInterrupt",
 		TrapType::MachineSoftwareInterrupt => "MachineSoftwareInterrupt",
@@ -208,6 +762,7 @@ fn get_trap_cause(trap: &Trap, xlen: &Xlen) -> u64 {
 		TrapType::InstructionPageFault => 12,
 		TrapType::LoadPageFault => 13,
 		TrapType::StorePageFault => 15,
+		TrapType::SoftwareCheck => 18,
 		TrapType::UserSoftwareInterrupt => interrupt_bit,
 		TrapType::SupervisorSoftwareInterrupt => interrupt_bit + 1,
 		TrapType::MachineSoftwareInterrupt => interrupt_bit + 3,
@@ -226,6 +781,19 @@ impl Cpu {
 	/// # Arguments
 	/// * `Terminal`
 	pub fn new(terminal: Box<dyn Terminal>) -> Self {
+		Self::new_with_config(terminal, &EmulatorConfig::default())
+	}
+
+	/// Creates a new `Cpu` with machine configuration.
+	///
+	/// # Arguments
+	/// * `Terminal`
+	/// * `config`
+	pub fn new_with_config(terminal: Box<dyn Terminal>, config: &EmulatorConfig) -> Self {
+		let zipper_mac = ZipperMac::new(&config.zipper_stack);
+		// Builds the decode table at startup rather than on the first
+		// instruction
+		get_decode_table();
 		let mut cpu = Cpu {
 			clock: 0,
 			xlen: Xlen::Bit64,
@@ -235,18 +803,38 @@ impl Cpu {
 			f: [0.0; 32],
 			pc: 0,
 			csr: [0; CSR_CAPACITY],
-			mmu: Mmu::new(Xlen::Bit64, terminal),
+			mmu: Mmu::new(Xlen::Bit64, terminal, config),
 			reservation: 0,
 			is_reservation_set: false,
 			_dump_flag: false,
 			decode_cache: DecodeCache::new(),
 			unsigned_data_mask: 0xffffffffffffffff,
-			hasher: Sha3_256::new(),					//added by ez2take
-			top: rand::thread_rng().gen::<u64>() & !0x7f_ffff_ffffu64,										//added by ez2take
-			key: rand::thread_rng().gen()										//added by ez2take
+			top: create_rng().gen::<u64>() & zipper_mac.get_tag_mask(),	//added by ez2take
+			zipper_mac,
+			exception: None,
+			instruction_statistics: None,
+			cfi_statistics: match config.landing_pad {
+				true => Some(CfiStatistics::new()),
+				false => None
+			},
+			expected_landing_pad: false,
+			misa_writable: 0,
+			disabled_extensions: 0,
+			trap_log: None,
+			error: None
 		};
 		cpu.x[0xb] = 0x1020; // I don't know why but Linux boot seems to require this initialization
-		cpu.write_csr_raw(CSR_MISA_ADDRESS, 0x800000008014312f);
+		let misa = match config.get_isa_extensions() {
+			Some(extensions) => (MISA_DEFAULT & !MISA_EXTENSIONS_MASK) | extensions,
+			None => MISA_DEFAULT
+		};
+		cpu.csr[CSR_MISA_ADDRESS as usize] = misa;
+		cpu.csr[CSR_MSTATUS_ADDRESS as usize] = MSTATUS_XL_64;
+		cpu.misa_writable = misa & MISA_TOGGLEABLE_MASK;
+		// The shadow stack grows down from the end of the region
+		if let Some(shadow_stack) = &config.shadow_stack {
+			cpu.write_csr_raw(CSR_SSP_ADDRESS, shadow_stack.base.wrapping_add(shadow_stack.size));
+		}
 		cpu
 	}
 
@@ -268,6 +856,12 @@ impl Cpu {
 			Xlen::Bit32 => 0xffffffff,
 			Xlen::Bit64 => 0xffffffffffffffff
 		};
+		// RV32 mstatus doesn't have UXL and SXL
+		let status = self.csr[CSR_MSTATUS_ADDRESS as usize] & !MSTATUS_XL_MASK;
+		self.csr[CSR_MSTATUS_ADDRESS as usize] = match xlen {
+			Xlen::Bit32 => status,
+			Xlen::Bit64 => status | MSTATUS_XL_64
+		};
 		self.mmu.update_xlen(xlen.clone());
 	}
 
@@ -283,11 +877,87 @@ impl Cpu {
 		}
 	}
 
+	/// Writes integer register content
+	///
+	/// # Arguments
+	/// * `reg` Register number. Must be 1-31
+	/// * `value`
+	pub fn write_register(&mut self, reg: u8, value: i64) {
+		debug_assert!((1..=31).contains(&reg), "reg must be 1-31. {}", reg);
+		self.x[reg as usize] = value;
+	}
+
+	/// Reads floating point register content in raw bits. Single
+	/// precision values are held sign-extended to 64 bits.
+	///
+	/// # Arguments
+	/// * `reg` Register number. Must be 0-31
+	pub fn read_f_register(&self, reg: u8) -> u64 {
+		debug_assert!(reg <= 31, "reg must be 0-31. {}", reg);
+		self.f[reg as usize].to_bits()
+	}
+
+	/// Writes floating point register content in raw bits
+	///
+	/// # Arguments
+	/// * `reg` Register number. Must be 0-31
+	/// * `value`
+	pub fn write_f_register(&mut self, reg: u8, value: u64) {
+		debug_assert!(reg <= 31, "reg must be 0-31. {}", reg);
+		self.f[reg as usize] = f64::from_bits(value);
+	}
+
 	/// Reads Program counter content
 	pub fn read_pc(&self) -> u64 {
 		self.pc
 	}
 
+	/// Reads the current privilege mode
+	pub fn read_privilege_mode(&self) -> &PrivilegeMode {
+		&self.privilege_mode
+	}
+
+	/// Returns `true` if the CPU is waiting for an interrupt with WFI
+	/// instruction and doesn't run instructions.
+	pub fn is_waiting_for_interrupt(&self) -> bool {
+		self.wfi
+	}
+
+	/// Returns XLEN, 32-bit or 64-bit
+	pub fn get_xlen(&self) -> &Xlen {
+		&self.xlen
+	}
+
+	/// Switches to User mode with address translation by the page table.
+	/// Used to run a user program without kernel. Traps from User mode are
+	/// taken in Machine mode and can be seen with `get_user_mode_trap()`.
+	///
+	/// # Arguments
+	/// * `satp` `satp` CSR value pointing the page table
+	pub fn enter_user_mode(&mut self, satp: u64) {
+		self.write_csr_raw(CSR_SATP_ADDRESS, satp);
+		self.resume_user_mode(self.pc);
+	}
+
+	/// Returns `mcause` and `mepc` CSR values if a trap has been taken
+	/// from User mode entered by `enter_user_mode()`.
+	pub fn get_user_mode_trap(&self) -> Option<(u64, u64)> {
+		match self.privilege_mode {
+			PrivilegeMode::Machine => Some((self.read_csr_raw(CSR_MCAUSE_ADDRESS), self.read_csr_raw(CSR_MEPC_ADDRESS))),
+			_ => None
+		}
+	}
+
+	/// Goes back to User mode after handling a trap.
+	///
+	/// # Arguments
+	/// * `pc` Address to resume
+	pub fn resume_user_mode(&mut self, pc: u64) {
+		self.pc = pc;
+		self.privilege_mode = PrivilegeMode::User;
+		self.mmu.update_privilege_mode(PrivilegeMode::User);
+	}
+
 	/// Runs program one cycle. Fetch, decode, and execution are completed in a cycle so far.
 	pub fn tick(&mut self) {
 		let instruction_address = self.pc;
@@ -305,6 +975,293 @@ impl Cpu {
 		self.write_csr_raw(CSR_CYCLE_ADDRESS, self.clock * 8);
 	}
 
+	/// Runs up to the cycles like calling `tick()` that many times, but
+	/// ticks the devices and checks the interrupts once at the end instead
+	/// of after every instruction. Returns the cycles run, at least one.
+	///
+	/// Interrupts are taken at the same instructions as with `tick()`.
+	/// The batch doesn't start while an interrupt can be taken or the CPU
+	/// waits for one, ends before the CLINT timer interrupt,
+	/// and ends early after an instruction which takes an exception,
+	/// accesses a device, or changes `mip`, `mie`, `mstatus`, `mideleg`,
+	/// or the privilege mode. `mtime` and the cycle counter advance every
+	/// instruction, but the other devices run one cycle per batch, so
+	/// their polling of the terminal and the host backends is that much
+	/// less frequent.
+	///
+	/// # Arguments
+	/// * `max_cycles`
+	pub fn tick_batch(&mut self, max_cycles: u64) -> u64 {
+		let max_cycles = match self.mmu.get_clint().get_cycles_to_timer() {
+			Some(cycles) => max_cycles.min(cycles),
+			None => max_cycles
+		};
+		let interrupt_state = self.get_interrupt_state();
+		let pending = interrupt_state[0] & interrupt_state[1];
+		if max_cycles <= 1 || self.wfi || (pending != 0 && !self.is_interrupt_masked(pending)) {
+			self.tick();
+			return 1;
+		}
+		self.mmu.take_mmio_accessed();
+		let mut cycles = 0;
+		while cycles < max_cycles {
+			let instruction_address = self.pc;
+			cycles += 1;
+			let result = self.tick_operate();
+			// Keeps time current for the next instruction reading it
+			self.mmu.advance_cycle();
+			self.clock = self.clock.wrapping_add(1);
+			self.write_csr_raw(CSR_CYCLE_ADDRESS, self.clock * 8);
+			if let Err(e) = result {
+				self.handle_exception(e, instruction_address);
+				break;
+			}
+			if self.wfi || self.mmu.take_mmio_accessed() || self.get_interrupt_state() != interrupt_state {
+				break;
+			}
+		}
+		self.mmu.tick_devices(&mut self.csr[CSR_MIP_ADDRESS as usize]);
+		self.handle_interrupt(self.pc);
+		cycles
+	}
+
+	// Whether none of the pending and enabled interrupts can be taken in
+	// the current privilege mode, conservatively. Machine mode takes
+	// the interrupts not delegated with mstatus.MIE set, and supervisor
+	// mode always takes them and the delegated ones with SIE set
+	fn is_interrupt_masked(&self, pending: u64) -> bool {
+		let mstatus = self.csr[CSR_MSTATUS_ADDRESS as usize];
+		let not_delegated = pending & !self.csr[CSR_MIDELEG_ADDRESS as usize];
+		match self.privilege_mode {
+			PrivilegeMode::Machine => (mstatus & 0x8) == 0 || not_delegated == 0,
+			PrivilegeMode::Supervisor => (mstatus & 0x2) == 0 && not_delegated == 0,
+			_ => false
+		}
+	}
+
+	// The CSRs and the privilege mode deciding which interrupts are taken
+	fn get_interrupt_state(&self) -> [u64; 5] {
+		[
+			self.csr[CSR_MIP_ADDRESS as usize],
+			self.csr[CSR_MIE_ADDRESS as usize],
+			self.csr[CSR_MSTATUS_ADDRESS as usize],
+			self.csr[CSR_MIDELEG_ADDRESS as usize],
+			get_privilege_encoding(&self.privilege_mode) as u64
+		]
+	}
+
+	/// Runs the step, usually `tick()`, and returns exactly which
+	/// registers, CSRs, and main memory bytes it has changed. Handy for
+	/// instruction level regression tests.
+	///
+	/// # Arguments
+	/// * `step` Called with this `Cpu`
+	pub fn step_diff<F: FnOnce(&mut Cpu)>(&mut self, step: F) -> StateDiff {
+		let pc = self.pc;
+		let privilege_mode = self.privilege_mode.clone();
+		let x = self.x;
+		let f = self.f;
+		let csr = self.csr;
+		self.mmu.start_store_log();
+		step(self);
+		let mut stores = self.mmu.take_store_log();
+		// Keeps the oldest values
+		stores.sort_by_key(|(address, _)| *address);
+		stores.dedup_by_key(|(address, _)| *address);
+		StateDiff {
+			pc: (pc, self.pc),
+			privilege_mode: match privilege_mode == self.privilege_mode {
+				true => None,
+				false => Some((privilege_mode, self.privilege_mode.clone()))
+			},
+			x: (1..32)
+				.filter(|i| x[*i] != self.x[*i])
+				.map(|i| (i as u8, x[i], self.x[i]))
+				.collect(),
+			f: (0..32)
+				.filter(|i| f[*i].to_bits() != self.f[*i].to_bits())
+				.map(|i| (i as u8, f[i].to_bits(), self.f[i].to_bits()))
+				.collect(),
+			csr: (0..CSR_CAPACITY)
+				.filter(|i| csr[*i] != self.csr[*i])
+				.map(|i| (i as u16, csr[i], self.csr[i]))
+				.collect(),
+			memory: stores.into_iter()
+				.map(|(address, value)| (address, value, self.mmu.load_raw(address)))
+				.filter(|(_, old, new)| old != new)
+				.collect()
+		}
+	}
+
+	/// Returns the last exception taken since the last call, if any.
+	/// Interrupts are not included.
+	pub fn take_exception(&mut self) -> Option<Exception> {
+		self.exception.take()
+	}
+
+	/// Returns the first internal error of the CPU, MMU, or devices since
+	/// the last call, if any. The emulator has recovered from it.
+	pub fn take_error(&mut self) -> Option<ExecError> {
+		self.error.take().or_else(|| self.mmu.take_error())
+	}
+
+	// Keeps the error unless another is kept
+	fn record_error(&mut self, error: ExecError) {
+		debug!(target: "cpu", "{}", error);
+		if self.error.is_none() {
+			self.error = Some(error);
+		}
+	}
+
+	/// Saves the registers, CSRs, and the privilege mode. Main memory
+	/// is saved separately with `Mmu::take_memory_snapshot()`.
+	pub fn take_snapshot(&self) -> CpuSnapshot {
+		CpuSnapshot {
+			clock: self.clock,
+			xlen: self.xlen.clone(),
+			privilege_mode: self.privilege_mode.clone(),
+			wfi: self.wfi,
+			x: self.x,
+			f: self.f,
+			pc: self.pc,
+			csr: Box::new(self.csr),
+			mtime: self.mmu.get_clint().read_mtime()
+		}
+	}
+
+	/// Restores the state saved by `take_snapshot()`. The reservation
+	/// by LR and the expected landing pad are cleared.
+	///
+	/// # Arguments
+	/// * `snapshot`
+	pub fn restore_snapshot(&mut self, snapshot: &CpuSnapshot) {
+		self.clock = snapshot.clock;
+		self.update_xlen(snapshot.xlen.clone());
+		self.privilege_mode = snapshot.privilege_mode.clone();
+		self.mmu.update_privilege_mode(self.privilege_mode.clone());
+		self.wfi = snapshot.wfi;
+		self.x = snapshot.x;
+		self.f = snapshot.f;
+		self.pc = snapshot.pc;
+		self.csr = *snapshot.csr;
+		self.disabled_extensions = self.misa_writable & !self.csr[CSR_MISA_ADDRESS as usize];
+		self.mmu.update_mstatus(self.read_csr_raw(CSR_MSTATUS_ADDRESS));
+		self.update_addressing_mode(self.read_csr_raw(CSR_SATP_ADDRESS));
+		self.mmu.get_mut_clint().write_mtime(snapshot.mtime);
+		self.is_reservation_set = false;
+		self.expected_landing_pad = false;
+		self.exception = None;
+	}
+
+	/// Starts or stops counting the retired instructions by extension and
+	/// privilege mode. Instructions raising an exception are not counted.
+	/// Stopping discards the counts.
+	///
+	/// # Arguments
+	/// * `enabled`
+	pub fn enable_instruction_statistics(&mut self, enabled: bool) {
+		self.instruction_statistics = match enabled {
+			true => Some(self.instruction_statistics.unwrap_or([[0; 4]; EXTENSION_NUM])),
+			false => None
+		};
+	}
+
+	/// Returns the retired instruction counts as (extension, privilege
+	/// mode, count) in the extension and then the privilege mode order.
+	/// Zero counts are left out.
+	pub fn get_instruction_statistics(&self) -> Vec<(Extension, PrivilegeMode, u64)> {
+		let statistics = match self.instruction_statistics.as_ref() {
+			Some(statistics) => statistics,
+			None => return vec![]
+		};
+		let mut counts = vec![];
+		for (extension, counts_by_mode) in EXTENSIONS.iter().zip(statistics.iter()) {
+			for (encoding, count) in counts_by_mode.iter().enumerate() {
+				if *count > 0 {
+					counts.push((*extension, get_privilege_mode(encoding as u64), *count));
+				}
+			}
+		}
+		counts
+	}
+
+	/// Sets the retired instruction counts to zero.
+	pub fn reset_instruction_statistics(&mut self) {
+		if let Some(statistics) = self.instruction_statistics.as_mut() {
+			*statistics = [[0; 4]; EXTENSION_NUM];
+		}
+	}
+
+	/// Returns the forward-edge CFI statistics, or `None` if landing
+	/// pads aren't checked.
+	pub fn get_cfi_statistics(&self) -> Option<&CfiStatistics> {
+		self.cfi_statistics.as_ref()
+	}
+
+	/// Clears the forward-edge CFI statistics.
+	pub fn reset_cfi_statistics(&mut self) {
+		if let Some(statistics) = self.cfi_statistics.as_mut() {
+			*statistics = CfiStatistics::new();
+		}
+	}
+
+	/// Sets the trap log recording the traps taken from now.
+	///
+	/// # Arguments
+	/// * `trap_log`
+	pub fn set_trap_log(&mut self, trap_log: TrapLog) {
+		self.trap_log = Some(trap_log);
+	}
+
+	/// Returns the mutable reference to the trap log if set.
+	pub fn get_mut_trap_log(&mut self) -> Option<&mut TrapLog> {
+		self.trap_log.as_mut()
+	}
+
+	/// Removes the trap log and returns it if set.
+	pub fn take_trap_log(&mut self) -> Option<TrapLog> {
+		self.trap_log.take()
+	}
+
+	/// Returns the implemented CSRs as (number, name, value) in
+	/// the number order. Reading doesn't have side effects.
+	pub fn dump_csrs(&self) -> Vec<(u16, &'static str, u64)> {
+		CSR_NAMES.iter()
+			.map(|(address, name)| (*address, *name, self.read_csr_raw(*address)))
+			.collect()
+	}
+
+	/// Reads the CSR on behalf of the host, with the access permission of
+	/// the privilege mode rather than the current one. Returns `Err` if
+	/// the CSR isn't implemented or the mode has no permission.
+	///
+	/// # Arguments
+	/// * `address` CSR number
+	/// * `privilege_mode` Usually `Machine` to access any CSR
+	pub fn read_csr_as(&self, address: u16, privilege_mode: &PrivilegeMode) -> Result<u64, DebugError> {
+		if get_csr_name(address).is_none() || !has_csr_access_privilege(address, privilege_mode) {
+			return Err(DebugError::InaccessibleCsr(address));
+		}
+		Ok(self.read_csr_raw(address))
+	}
+
+	/// Writes the CSR on behalf of the host like an instruction in
+	/// the privilege mode. Returns `Err` if the CSR isn't implemented,
+	/// is read-only, or the mode has no permission.
+	///
+	/// # Arguments
+	/// * `address` CSR number
+	/// * `value`
+	/// * `privilege_mode` Usually `Machine` to access any CSR
+	pub fn write_csr_as(&mut self, address: u16, value: u64, privilege_mode: &PrivilegeMode) -> Result<(), DebugError> {
+		let read_only = ((address >> 10) & 0x3) == 0x3;
+		if get_csr_name(address).is_none() || read_only || !has_csr_access_privilege(address, privilege_mode) {
+			return Err(DebugError::InaccessibleCsr(address));
+		}
+		self.write_csr_raw(address, value);
+		Ok(())
+	}
+
 	// @TODO: Rename?
 	fn tick_operate(&mut self) -> Result<(), Trap> {
 		if self.wfi {
@@ -320,27 +1277,43 @@ impl Cpu {
 			Err(e) => return Err(e)
 		};
 		let instruction_address = self.pc;
-		let word = match (original_word & 0x3) == 0x3 {
-			true => {
-				self.pc = self.pc.wrapping_add(4); // 32-bit length non-compressed instruction
-				original_word
-			},
-			false => {
-				self.pc = self.pc.wrapping_add(2); // 16-bit length compressed instruction
-				self.uncompress(original_word & 0xffff)
-			}
-		};
+		self.mmu.update_mmio_log_pc(instruction_address);
+		let (word, length) = expand_instruction(original_word, &self.xlen);
+		if self.disabled_extensions != 0 {
+			self.check_extension(original_word, word)?;
+		}
+		if self.expected_landing_pad {
+			self.expected_landing_pad = false;
+			self.check_landing_pad(word, length, instruction_address)?;
+		}
+		self.pc = self.pc.wrapping_add(length);
 
+		let privilege_encoding = get_privilege_encoding(&self.privilege_mode) as usize;
 		match self.decode(word) {
 			Ok(inst) => {
 				let result = (inst.operation)(self, word, instruction_address);
 				self.x[0] = 0; // hardwired zero
+				if let (Some(statistics), Ok(())) = (self.instruction_statistics.as_mut(), &result) {
+					let extension = get_extension(original_word, word);
+					statistics[extension as usize][privilege_encoding] += 1;
+				}
 				return result;
 			},
 			Err(()) => {
-				panic!("Unknown instruction PC:{:x} WORD:{:x}", instruction_address, original_word);
+				let bits = match (original_word & 0x3) == 0x3 {
+					true => original_word,
+					false => original_word & 0xffff
+				};
+				self.record_error(ExecError::UnknownInstruction {
+					pc: instruction_address,
+					word: bits
+				});
+				Err(Trap {
+					trap_type: TrapType::IllegalInstruction,
+					value: bits as u64
+				})
 			}
-		};
+		}
 	}
 
 	/// Decodes a word instruction data and returns a reference to
@@ -350,7 +1323,7 @@ impl Cpu {
 	fn decode(&mut self, word: u32) -> Result<&Instruction, ()> {
 		match self.decode_cache.get(word) {
 			Some(index) => return Ok(&INSTRUCTIONS[index]),
-			None => match self.decode_and_get_instruction_index(word) {
+			None => match Self::decode_and_get_instruction_index(word) {
 				Ok(index) => {
 					self.decode_cache.insert(word, index);
 					Ok(&INSTRUCTIONS[index])
@@ -365,7 +1338,7 @@ impl Cpu {
 	/// so if you don't want to pollute the cache you should use this method
 	/// instead of `decode`.
 	fn decode_raw(&self, word: u32) -> Result<&Instruction, ()> {
-		match self.decode_and_get_instruction_index(word) {
+		match Self::decode_and_get_instruction_index(word) {
 			Ok(index) => Ok(&INSTRUCTIONS[index]),
 			Err(()) => Err(())
 		}
@@ -376,11 +1349,11 @@ impl Cpu {
 	///
 	/// # Arguments
 	/// * `word` word instruction data decoded
-	fn decode_and_get_instruction_index(&self, word: u32) -> Result<usize, ()> {
-		for i in 0..INSTRUCTION_NUM {
-			let inst = &INSTRUCTIONS[i];
+	fn decode_and_get_instruction_index(word: u32) -> Result<usize, ()> {
+		for i in get_decode_table()[get_decode_table_key(word)].iter() {
+			let inst = &INSTRUCTIONS[*i as usize];
 			if (word & inst.mask) == inst.data {
-				return Ok(i);
+				return Ok(*i as usize);
 			}
 		}
 		return Err(())
@@ -454,6 +1427,11 @@ impl Cpu {
 	}
 
 	fn handle_exception(&mut self, exception: Trap, instruction_address: u64) {
+		self.exception = Some(Exception {
+			cause: get_trap_cause(&exception, &self.xlen),
+			pc: instruction_address,
+			value: exception.value
+		});
 		self.handle_trap(exception, instruction_address, false);
 	}
 
@@ -600,6 +1578,20 @@ impl Cpu {
 
 		// So, this trap should be taken
 
+		trace!(target: "cpu", "Trap {} PC:{:x} VALUE:{:x} {:?} -> {:?}", get_trap_type_name(&trap.trap_type),
+			instruction_address, trap.value, self.privilege_mode, new_privilege_mode);
+		if let Some(trap_log) = self.trap_log.as_mut() {
+			trap_log.record(TrapRecord {
+				interrupt: is_interrupt,
+				code: pos,
+				name: get_trap_type_name(&trap.trap_type),
+				epc: instruction_address,
+				value: trap.value,
+				from: self.privilege_mode.clone(),
+				to: new_privilege_mode.clone(),
+				clock: self.clock
+			});
+		}
 		self.privilege_mode = new_privilege_mode;
 		self.mmu.update_privilege_mode(self.privilege_mode.clone());
 		let csr_epc_address = match self.privilege_mode {
@@ -641,26 +1633,54 @@ impl Cpu {
 			PrivilegeMode::Machine => {
 				let status = self.read_csr_raw(CSR_MSTATUS_ADDRESS);
 				let mie = (status >> 3) & 1;
-				// clear MIE[3], override MPIE[7] with MIE[3], override MPP[12:11] with current privilege encoding
-				let new_status = (status & !0x1888) | (mie << 7) | (current_privilege_encoding << 11);
+				// clear MIE[3], override MPIE[7] with MIE[3], override MPP[12:11] with current privilege encoding,
+				// override MPELP[41] with ELP
+				let new_status = (status & !0x20000001888) | (mie << 7) | (current_privilege_encoding << 11) |
+					((self.expected_landing_pad as u64) << 41);
 				self.write_csr_raw(CSR_MSTATUS_ADDRESS, new_status);
 			},
 			PrivilegeMode::Supervisor => {
 				let status = self.read_csr_raw(CSR_SSTATUS_ADDRESS);
 				let sie = (status >> 1) & 1;
-				// clear SIE[1], override SPIE[5] with SIE[1], override SPP[8] with current privilege encoding
-				let new_status = (status & !0x122) | (sie << 5) | ((current_privilege_encoding & 1) << 8);
+				// clear SIE[1], override SPIE[5] with SIE[1], override SPP[8] with current privilege encoding,
+				// override SPELP[23] with ELP
+				let new_status = (status & !0x800122) | (sie << 5) | ((current_privilege_encoding & 1) << 8) |
+					((self.expected_landing_pad as u64) << 23);
 				self.write_csr_raw(CSR_SSTATUS_ADDRESS, new_status);
 			},
 			PrivilegeMode::User => {
-				panic!("Not implemented yet");
+				self.record_error(ExecError::Unimplemented("Traps handled in user mode. ustatus isn't updated"));
 			},
 			PrivilegeMode::Reserved => panic!() // shouldn't happen
 		};
-		//println!("Trap! {:x} Clock:{:x}", cause, self.clock);
+		// The handler doesn't start with a landing pad
+		self.expected_landing_pad = false;
 		true
 	}
 
+	// Raises a software check exception unless the instruction an indirect
+	// call or jump has reached is LPAD, AUIPC x0 on a four-byte boundary,
+	// whose label is zero or matches x7[31:12]
+	fn check_landing_pad(&mut self, word: u32, length: u64, address: u64) -> Result<(), Trap> {
+		let label = word >> 12;
+		let is_landing_pad = length == 4 && (word & 0xfff) == 0x017 && (address & 0x3) == 0;
+		let expected_label = ((self.x[7] as u64) >> 12) as u32 & 0xfffff;
+		let statistics = self.cfi_statistics.as_mut().unwrap();
+		match is_landing_pad && (label == 0 || label == expected_label) {
+			true => {
+				statistics.record_landing(label, address);
+				Ok(())
+			},
+			false => {
+				statistics.record_violation();
+				Err(Trap {
+					trap_type: TrapType::SoftwareCheck,
+					value: 2 // Landing pad fault
+				})
+			}
+		}
+	}
+
 	fn fetch(&mut self) -> Result<u32, Trap> {
 		let word = match self.mmu.fetch_word(self.pc) {
 			Ok(word) => word,
@@ -673,8 +1693,7 @@ impl Cpu {
 	}
 
 	fn has_csr_access_privilege(&self, address: u16) -> bool {
-		let privilege = (address >> 8) & 0x3; // the lowest privilege level that can access the CSR
-		privilege as u8 <= get_privilege_encoding(&self.privilege_mode)
+		has_csr_access_privilege(address, &self.privilege_mode)
 	}
 
 	fn read_csr(&mut self, address: u16) -> Result<u64, Trap> {
@@ -697,10 +1716,12 @@ impl Cpu {
 					return Err(Exception::IllegalInstruction);
 				}
 				*/
+				// MSIP, MTIP, and MEIP reflect the devices and are read-only
+				let value = match address {
+					CSR_MIP_ADDRESS => (value & !0x888) | (self.csr[address as usize] & 0x888),
+					_ => value
+				};
 				self.write_csr_raw(address, value);
-				if address == CSR_SATP_ADDRESS {
-					self.update_addressing_mode(value);
-				}
 				Ok(())
 			},
 			false => Err(Trap {
@@ -716,28 +1737,59 @@ impl Cpu {
 			// @TODO: Mask shuld consider of 32-bit mode
 			CSR_FFLAGS_ADDRESS => self.csr[CSR_FCSR_ADDRESS as usize] & 0x1f,
 			CSR_FRM_ADDRESS => (self.csr[CSR_FCSR_ADDRESS as usize] >> 5) & 0x7,
-			CSR_SSTATUS_ADDRESS => self.csr[CSR_MSTATUS_ADDRESS as usize] & 0x80000003000de162,
+			CSR_SSTATUS_ADDRESS => self.csr[CSR_MSTATUS_ADDRESS as usize] & (SSTATUS_MASK | self.get_status_dirty_bit()),
 			CSR_SIE_ADDRESS => self.csr[CSR_MIE_ADDRESS as usize] & 0x222,
 			CSR_SIP_ADDRESS => self.csr[CSR_MIP_ADDRESS as usize] & 0x222,
+			// Bit 1 is masked while C is disabled, to be restored by enabling it
+			CSR_MEPC_ADDRESS | CSR_SEPC_ADDRESS => match (self.disabled_extensions & MISA_C) != 0 {
+				true => self.csr[address as usize] & !0x3,
+				false => self.csr[address as usize]
+			},
 			CSR_TIME_ADDRESS => self.mmu.get_clint().read_mtime(),
+			// Upper halves of the counters for RV32
+			CSR_CYCLEH_ADDRESS => self.csr[CSR_CYCLE_ADDRESS as usize] >> 32,
+			CSR_TIMEH_ADDRESS => self.mmu.get_clint().read_mtime() >> 32,
+			CSR_MIREG_ADDRESS => self.mmu.get_imsic().read_register(&PrivilegeMode::Machine,
+				self.csr[CSR_MISELECT_ADDRESS as usize], &self.xlen),
+			CSR_SIREG_ADDRESS => self.mmu.get_imsic().read_register(&PrivilegeMode::Supervisor,
+				self.csr[CSR_SISELECT_ADDRESS as usize], &self.xlen),
+			CSR_MTOPEI_ADDRESS => self.mmu.get_imsic().get_topei(&PrivilegeMode::Machine),
+			CSR_STOPEI_ADDRESS => self.mmu.get_imsic().get_topei(&PrivilegeMode::Supervisor),
+			// The keys can't be read back
+			CSR_MZKEY_ADDRESS => 0,
+			CSR_MTOPI_ADDRESS => {
+				let mideleg = self.csr[CSR_MIDELEG_ADDRESS as usize];
+				self.get_topi(self.csr[CSR_MIP_ADDRESS as usize] & self.csr[CSR_MIE_ADDRESS as usize] & !mideleg)
+			},
+			CSR_STOPI_ADDRESS => {
+				let mideleg = self.csr[CSR_MIDELEG_ADDRESS as usize];
+				self.get_topi(self.csr[CSR_MIP_ADDRESS as usize] & self.csr[CSR_MIE_ADDRESS as usize] & mideleg)
+			},
 			_ => self.csr[address as usize]
 		}
 	}
 
+	// WARL fields are legalized. Reserved bits and read-only fields keep
+	// zero, and illegal values keep the current ones
 	fn write_csr_raw(&mut self, address: u16, value: u64) {
 		match address {
+			CSR_FCSR_ADDRESS => {
+				self.csr[address as usize] = value & 0xff;
+				self.mark_fs_dirty();
+			},
 			CSR_FFLAGS_ADDRESS => {
 				self.csr[CSR_FCSR_ADDRESS as usize] &= !0x1f;
 				self.csr[CSR_FCSR_ADDRESS as usize] |= value & 0x1f;
+				self.mark_fs_dirty();
 			},
 			CSR_FRM_ADDRESS => {
 				self.csr[CSR_FCSR_ADDRESS as usize] &= !0xe0;
 				self.csr[CSR_FCSR_ADDRESS as usize] |= (value << 5) & 0xe0;
+				self.mark_fs_dirty();
 			},
 			CSR_SSTATUS_ADDRESS => {
-				self.csr[CSR_MSTATUS_ADDRESS as usize] &= !0x80000003000de162;
-				self.csr[CSR_MSTATUS_ADDRESS as usize] |= value & 0x80000003000de162;
-				self.mmu.update_mstatus(self.read_csr_raw(CSR_MSTATUS_ADDRESS));
+				let status = self.csr[CSR_MSTATUS_ADDRESS as usize];
+				self.write_csr_raw(CSR_MSTATUS_ADDRESS, (status & !SSTATUS_MASK) | (value & SSTATUS_MASK));
 			},
 			CSR_SIE_ADDRESS => {
 				self.csr[CSR_MIE_ADDRESS as usize] &= !0x222;
@@ -747,40 +1799,231 @@ impl Cpu {
 				self.csr[CSR_MIP_ADDRESS as usize] &= !0x222;
 				self.csr[CSR_MIP_ADDRESS as usize] |= value & 0x222;
 			},
+			CSR_MIE_ADDRESS | CSR_MIP_ADDRESS => {
+				self.csr[address as usize] = value & 0xaaa;
+			},
 			CSR_MIDELEG_ADDRESS => {
 				self.csr[address as usize] = value & 0x666; // from qemu
 			},
+			CSR_MEDELEG_ADDRESS => {
+				self.csr[address as usize] = value & MEDELEG_WRITABLE_MASK;
+			},
 			CSR_MSTATUS_ADDRESS => {
-				self.csr[address as usize] = value;
+				let status = self.csr[address as usize];
+				// MPP is WARL. The reserved encoding keeps the current value
+				let value = match (value >> 11) & 0x3 {
+					2 => (value & !0x1800) | (status & 0x1800),
+					_ => value
+				};
+				let status = (value & MSTATUS_WRITABLE_MASK) | (status & !(MSTATUS_WRITABLE_MASK | self.get_status_dirty_bit()));
+				// SD summarizes that FS is Dirty
+				self.csr[address as usize] = match (status & MSTATUS_FS_DIRTY) == MSTATUS_FS_DIRTY {
+					true => status | self.get_status_dirty_bit(),
+					false => status
+				};
 				self.mmu.update_mstatus(self.read_csr_raw(CSR_MSTATUS_ADDRESS));
 			},
+			// The reserved modes keep the current mode
+			CSR_MTVEC_ADDRESS | CSR_STVEC_ADDRESS => {
+				let value = match value & 0x3 {
+					0 | 1 => value,
+					_ => (value & !0x3) | (self.csr[address as usize] & 0x3)
+				};
+				self.csr[address as usize] = value;
+			},
+			CSR_MEPC_ADDRESS | CSR_SEPC_ADDRESS => {
+				self.csr[address as usize] = value & !0x1;
+			},
+			// The writes with an unsupported mode have no effect
+			CSR_SATP_ADDRESS => {
+				let supported = match self.xlen {
+					Xlen::Bit32 => true,
+					Xlen::Bit64 => matches!(value >> 60, 0 | 8)
+				};
+				if supported {
+					self.csr[address as usize] = value;
+					self.update_addressing_mode(value);
+				}
+			},
+			// D needs F. Disabling C is ignored unless the next instruction
+			// is aligned to 4 bytes
+			CSR_MISA_ADDRESS => {
+				let mut misa = (self.csr[address as usize] & !self.misa_writable) | (value & self.misa_writable);
+				if (misa & MISA_F) == 0 {
+					misa &= !MISA_D;
+				}
+				if (misa & MISA_C) == 0 && (self.pc & 0x3) != 0 {
+					return;
+				}
+				self.csr[address as usize] = misa;
+				self.disabled_extensions = self.misa_writable & !misa;
+			},
 			CSR_TIME_ADDRESS => {
 				self.mmu.get_mut_clint().write_mtime(value);
 			},
+			CSR_MIREG_ADDRESS => {
+				let select = self.csr[CSR_MISELECT_ADDRESS as usize];
+				self.mmu.get_mut_imsic().write_register(&PrivilegeMode::Machine, select, value, &self.xlen);
+			},
+			CSR_SIREG_ADDRESS => {
+				let select = self.csr[CSR_SISELECT_ADDRESS as usize];
+				self.mmu.get_mut_imsic().write_register(&PrivilegeMode::Supervisor, select, value, &self.xlen);
+			},
+			// Writing *topei claims the interrupt regardless of the value
+			CSR_MTOPEI_ADDRESS => {
+				self.mmu.get_mut_imsic().claim_topei(&PrivilegeMode::Machine);
+			},
+			CSR_STOPEI_ADDRESS => {
+				self.mmu.get_mut_imsic().claim_topei(&PrivilegeMode::Supervisor);
+			},
+			CSR_MTOPI_ADDRESS | CSR_STOPI_ADDRESS => {},
+			// The key CSRs ignore writes once locked, until reset
+			CSR_MZKEYCTL_ADDRESS => {
+				if (self.csr[address as usize] & KEY_CONTROL_LOCK) == 0 {
+					if (value & KEY_CONTROL_ROTATE) != 0 {
+						self.zipper_mac.rotate_slot_key(self.csr[CSR_MZKEYSEL_ADDRESS as usize]);
+					}
+					self.csr[address as usize] = value & KEY_CONTROL_MASK;
+				}
+			},
+			CSR_MZKEYSEL_ADDRESS => {
+				// 16-bit ASID and privilege mode encoding
+				self.csr[address as usize] = value & 0x3ffff;
+			},
+			CSR_MZKEY_ADDRESS => {
+				if (self.csr[CSR_MZKEYCTL_ADDRESS as usize] & KEY_CONTROL_LOCK) == 0 {
+					self.zipper_mac.set_slot_key(self.csr[CSR_MZKEYSEL_ADDRESS as usize], value);
+				}
+			},
 			_ => {
 				self.csr[address as usize] = value;
 			}
 		};
 	}
 
-	fn _set_fcsr_nv(&mut self) {
-		self.csr[CSR_FCSR_ADDRESS as usize] |= 0x10;
+	// Raises an illegal instruction exception for an instruction of an
+	// extension disabled in misa. Compressed ones need C as well as the
+	// extension of the expanded instruction
+	fn check_extension(&self, original_word: u32, word: u32) -> Result<(), Trap> {
+		let compressed = (original_word & 0x3) != 0x3;
+		let mut extensions = match get_extension(word, word) {
+			Extension::M => MISA_M,
+			Extension::A => MISA_A,
+			Extension::F => MISA_F,
+			Extension::D => MISA_D,
+			_ => 0
+		};
+		if compressed {
+			extensions |= MISA_C;
+		}
+		match (extensions & self.disabled_extensions) != 0 {
+			true => Err(Trap {
+				trap_type: TrapType::IllegalInstruction,
+				value: match compressed {
+					true => (original_word & 0xffff) as u64,
+					false => original_word as u64
+				}
+			}),
+			false => Ok(())
+		}
+	}
+
+	// Jumps to the target of a jump or taken branch. Targets not aligned
+	// to 4 bytes raise an exception while C is disabled
+	fn jump(&mut self, target: u64) -> Result<(), Trap> {
+		if (self.disabled_extensions & MISA_C) != 0 && (target & 0x3) != 0 {
+			return Err(Trap {
+				trap_type: TrapType::InstructionAddressMisaligned,
+				value: target
+			});
+		}
+		self.pc = target;
+		Ok(())
+	}
+
+	// Returns *topi CSR value, the highest priority interrupt among
+	// the pending and enabled ones. Interrupt priorities are not
+	// configurable so the priority field is always one.
+	fn get_topi(&self, interrupts: u64) -> u64 {
+		for iid in MAJOR_INTERRUPT_PRIORITIES.iter() {
+			if ((interrupts >> iid) & 1) == 1 {
+				return (iid << 16) | 1;
+			}
+		}
+		0
 	}
 
-	fn set_fcsr_dz(&mut self) {
-		self.csr[CSR_FCSR_ADDRESS as usize] |= 0x8;
+	// Returns the rounding mode of the rm field of a floating point
+	// instruction, or of frm for the dynamic one. The reserved ones raise
+	// an illegal instruction exception
+	#[cfg(feature = "fd")]
+	fn get_rounding_mode(&self, word: u32) -> Result<RoundingMode, Trap> {
+		let encoding = match (word >> 12) & 0x7 {
+			7 => (self.csr[CSR_FCSR_ADDRESS as usize] >> 5) & 0x7,
+			rm => rm as u64
+		};
+		match get_rounding_mode(encoding) {
+			Some(rounding_mode) => Ok(rounding_mode),
+			None => Err(Trap {
+				trap_type: TrapType::IllegalInstruction,
+				value: word as u64
+			})
+		}
 	}
 
-	fn _set_fcsr_of(&mut self) {
-		self.csr[CSR_FCSR_ADDRESS as usize] |= 0x4;
+	// Accrues the exception flags of a floating point operation to fflags
+	#[cfg(feature = "fd")]
+	fn accrue_fflags(&mut self, flags: u8) {
+		if flags != 0 {
+			self.csr[CSR_FCSR_ADDRESS as usize] |= flags as u64;
+			self.mark_fs_dirty();
+		}
 	}
 
-	fn _set_fcsr_uf(&mut self) {
-		self.csr[CSR_FCSR_ADDRESS as usize] |= 0x2;
+	// Sets FS of mstatus to Dirty after the floating point registers or
+	// fcsr are modified, so that kernels save them on context switches.
+	// FS is read-only zero without the F and D extensions
+	fn mark_fs_dirty(&mut self) {
+		let status = self.csr[CSR_MSTATUS_ADDRESS as usize];
+		if (status & MSTATUS_FS_DIRTY) != MSTATUS_FS_DIRTY {
+			self.write_csr_raw(CSR_MSTATUS_ADDRESS, status | MSTATUS_FS_DIRTY);
+		}
 	}
 
-	fn _set_fcsr_nx(&mut self) {
-		self.csr[CSR_FCSR_ADDRESS as usize] |= 0x1;
+	// Returns SD bit of mstatus, the most significant bit for XLEN
+	fn get_status_dirty_bit(&self) -> u64 {
+		match self.xlen {
+			Xlen::Bit32 => 0x80000000,
+			Xlen::Bit64 => 0x8000000000000000
+		}
+	}
+
+	// Returns the single precision value of an f register. Values not
+	// NaN-boxed are the canonical NaN
+	#[cfg(feature = "fd")]
+	fn read_single(&self, reg: usize) -> u64 {
+		let bits = self.f[reg].to_bits();
+		match (bits & SINGLE_NAN_BOX) == SINGLE_NAN_BOX {
+			true => bits as u32 as u64,
+			false => SINGLE.get_canonical_nan()
+		}
+	}
+
+	// Returns the key slot ZIP and UNZIP use, by the privilege mode and
+	// the ASID as mzkeyctl selects
+	fn get_zipper_key_slot(&self) -> u64 {
+		let control = self.csr[CSR_MZKEYCTL_ADDRESS as usize];
+		let privilege_encoding = match (control & KEY_CONTROL_PER_PRIVILEGE) != 0 {
+			true => get_privilege_encoding(&self.privilege_mode),
+			false => 0
+		};
+		let satp = self.csr[CSR_SATP_ADDRESS as usize];
+		let asid = match ((control & KEY_CONTROL_PER_ASID) != 0, &self.xlen) {
+			(false, _) => 0,
+			(true, Xlen::Bit32) => (satp >> 22) & 0x1ff,
+			(true, Xlen::Bit64) => (satp >> 44) & 0xffff
+		};
+		get_key_slot(privilege_encoding, asid as u16)
 	}
 
 	fn update_addressing_mode(&mut self, value: u64) {
@@ -790,13 +2033,8 @@ impl Cpu {
 				_ => AddressingMode::SV32
 			},
 			Xlen::Bit64 => match value >> 60 {
-				0 => AddressingMode::None,
 				8 => AddressingMode::SV39,
-				9 => AddressingMode::SV48,
-				_ => {
-					println!("Unknown addressing_mode {:x}", value >> 60);
-					panic!();
-				}
+				_ => AddressingMode::None
 			}
 		};
 		let ppn = match self.xlen {
@@ -815,6 +2053,14 @@ impl Cpu {
 		}
 	}
 
+	// XLEN in bytes, the size of shadow stack entries
+	fn get_xlen_bytes(&self) -> i64 {
+		match self.xlen {
+			Xlen::Bit32 => 4,
+			Xlen::Bit64 => 8
+		}
+	}
+
 	// @TODO: Rename to better name?
 	fn unsigned_data(&self, value: i64) -> u64 {
 		(value as u64) & self.unsigned_data_mask
@@ -823,13 +2069,13 @@ impl Cpu {
 	// @TODO: Rename to better name?
 	fn most_negative(&self) -> i64 {
 		match self.xlen {
-			Xlen::Bit32 => std::i32::MIN as i64,
-			Xlen::Bit64 => std::i64::MIN
+			Xlen::Bit32 => i32::MIN as i64,
+			Xlen::Bit64 => i64::MIN
 		}
 	}
 
 	// @TODO: Optimize
-	fn uncompress(&self, halfword: u32) -> u32 {
+	fn uncompress(halfword: u32, xlen: &Xlen) -> u32 {
 		let op = halfword & 0x3; // [1:0]
 		let funct3 = (halfword >> 13) & 0x7; // [15:13]
 
@@ -947,8 +2193,12 @@ impl Cpu {
 						// @TODO: Support HINTs
 						// r == 0 and imm != 0 is HINTs
 					},
+					1 if matches!(xlen, Xlen::Bit32) => {
+						// C.JAL
+						// jal x1, imm
+						return get_cj_jal_imm(halfword) | (1 << 7) | 0x6f;
+					},
 					1 => {
-						// @TODO: Support C.JAL in 32-bit mode
 						// C.ADDIW
 						// addiw r, r, imm
 						let r = (halfword >> 7) & 0x1f;
@@ -1010,7 +2260,13 @@ impl Cpu {
 							if nzimm != 0 {
 								return nzimm | (r << 7) | 0x37;
 							}
-							// nzimm == 0 is for reserved instruction
+							// nzimm == 0 is for C.MOP.n. C.MOP.1 and C.MOP.5
+							// are C.SSPUSH x1 and C.SSPOPCHK x5
+							match r {
+								1 => return 0xce104073, // sspush x1
+								5 => return 0xcdc2c073, // sspopchk x5
+								_ => {}
+							};
 						}
 					},
 					4 => {
@@ -1100,28 +2356,10 @@ impl Cpu {
 							_ => {} // not happens
 						};
 					},
-					5 => {
-						// C.J
-						// jal x0, imm
-						let offset =
-							match halfword & 0x1000 {
-								0x1000 => 0xfffff000,
-								_ => 0
-							} | // offset[31:12] <= [12]
-							((halfword >> 1) & 0x800) | // offset[11] <= [12]
-							((halfword >> 7) & 0x10) | // offset[4] <= [11]
-							((halfword >> 1) & 0x300) | // offset[9:8] <= [10:9]
-							((halfword << 2) & 0x400) | // offset[10] <= [8]
-							((halfword >> 1) & 0x40) | // offset[6] <= [7]
-							((halfword << 1) & 0x80) | // offset[7] <= [6]
-							((halfword >> 2) & 0xe) | // offset[3:1] <= [5:3]
-							((halfword << 3) & 0x20); // offset[5] <= [2]
-						let imm =
-							((offset >> 1) & 0x80000) | // imm[19] <= offset[20]
-							((offset << 8) & 0x7fe00) | // imm[18:9] <= offset[10:1]
-							((offset >> 3) & 0x100) | // imm[8] <= offset[11]
-							((offset >> 12) & 0xff); // imm[7:0] <= offset[19:12]
-						return (imm << 12) | 0x6f;
+					5 => {
+						// C.J
+						// jal x0, imm
+						return get_cj_jal_imm(halfword) | 0x6f;
 					},
 					6 => {
 						// C.BEQZ
@@ -1310,6 +2548,45 @@ impl Cpu {
 		0xffffffff // Return invalid value
 	}
 
+	/// Fetches the instruction pointed by Program Counter and returns it
+	/// uncompressed and its length in bytes, or `None` if fetching fails.
+	/// Like disassembling, fetching can make a side effect.
+	pub fn fetch_next_instruction(&mut self) -> Option<(u32, u64)> {
+		let word = match self.mmu.fetch_word(self.pc) {
+			Ok(word) => word,
+			Err(_e) => return None
+		};
+		match (word & 0x3) == 0x3 {
+			true => Some((word, 4)),
+			false => Some((Self::uncompress(word & 0xffff, &self.xlen), 2))
+		}
+	}
+
+	/// Fetches and decodes the instruction pointed by Program Counter.
+	/// Returns `None` if fetching or decoding fails. Like disassembling,
+	/// fetching can make a side effect.
+	pub fn get_next_instruction(&mut self) -> Option<NextInstruction> {
+		let (word, length) = match self.mmu.fetch_word(self.pc) {
+			Ok(word) if (word & 0x3) == 0x3 => (word, 4),
+			Ok(word) => (word & 0xffff, 2),
+			Err(_e) => return None
+		};
+		let uncompressed_word = match length {
+			4 => word,
+			_ => Self::uncompress(word, &self.xlen)
+		};
+		let inst = match self.decode_raw(uncompressed_word) {
+			Ok(inst) => inst,
+			Err(()) => return None
+		};
+		Some(NextInstruction {
+			word,
+			length,
+			name: inst.name,
+			operands: (inst.disassemble)(None, uncompressed_word, self.pc)
+		})
+	}
+
 	/// Disassembles an instruction pointed by Program Counter.
 	pub fn disassemble_next_instruction(&mut self) -> String {
 		// @TODO: Fetching can make a side effect,
@@ -1327,7 +2604,7 @@ impl Cpu {
 			true => original_word,
 			false => {
 				original_word &= 0xffff;
-				self.uncompress(original_word)
+				Self::uncompress(original_word, &self.xlen)
 			}
 		};
 
@@ -1341,18 +2618,54 @@ impl Cpu {
 		let mut s = format!("PC:{:016x} ", self.unsigned_data(self.pc as i64));
 		s += &format!("{:08x} ", original_word);
 		s += &format!("{} ", inst.name);
-		s += &format!("{}", (inst.disassemble)(self, word, self.pc, true));
+		s += &format!("{}", (inst.disassemble)(Some(self), word, self.pc));
 		s
 	}
 
+	/// Returns ISA string, e.g. "rv64imafdcsu", made from XLEN and
+	/// extensions enabled in `misa` CSR. Extensions are in canonical order.
+	pub fn get_isa_string(&self) -> String {
+		let misa = self.read_csr_raw(CSR_MISA_ADDRESS);
+		let mut isa = match self.xlen {
+			Xlen::Bit32 => "rv32".to_string(),
+			Xlen::Bit64 => "rv64".to_string()
+		};
+		for extension in "imafdqcsu".chars() {
+			if (misa >> (extension as u8 - b'a')) & 1 == 1 {
+				isa.push(extension);
+			}
+		}
+		isa
+	}
+
+	/// Returns immutable `Mmu`
+	pub fn get_mmu(&self) -> &Mmu {
+		&self.mmu
+	}
+
 	/// Returns mutable `Mmu`
 	pub fn get_mut_mmu(&mut self) -> &mut Mmu {
 		&mut self.mmu
 	}
 
+	/// Returns immutable `ZipperMac` of Zipper Stack
+	pub fn get_zipper_mac(&self) -> &ZipperMac {
+		&self.zipper_mac
+	}
+
+	/// Returns mutable `ZipperMac` of Zipper Stack
+	pub fn get_mut_zipper_mac(&mut self) -> &mut ZipperMac {
+		&mut self.zipper_mac
+	}
+
+	/// Returns the hit and miss counts of the decode cache.
+	pub fn get_decode_cache_stats(&self) -> (u64, u64) {
+		(self.decode_cache.hit_count, self.decode_cache.miss_count)
+	}
+
 	/// Returns mutable `Terminal`
 	pub fn get_mut_terminal(&mut self) -> &mut Box<dyn Terminal> {
-		self.mmu.get_mut_uart().get_mut_terminal()
+		self.mmu.get_mut_console().get_mut_terminal()
 	}
 }
 
@@ -1361,7 +2674,8 @@ struct Instruction {
 	data: u32, // @TODO: rename
 	name: &'static str,
 	operation: fn(cpu: &mut Cpu, word: u32, address: u64) -> Result<(), Trap>,
-	disassemble: fn(cpu: &mut Cpu, word: u32, address: u64, evaluate: bool) -> String
+	/// Register values are shown if `cpu` is given
+	disassemble: fn(cpu: Option<&Cpu>, word: u32, address: u64) -> String
 }
 
 struct FormatB {
@@ -1386,15 +2700,15 @@ fn parse_format_b(word: u32) -> FormatB {
 	}
 }
 
-fn dump_format_b(cpu: &mut Cpu, word: u32, address: u64, evaluate: bool) -> String {
+fn dump_format_b(cpu: Option<&Cpu>, word: u32, address: u64) -> String {
 	let f = parse_format_b(word);
 	let mut s = String::new();
 	s += &format!("{}", get_register_name(f.rs1));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs1]);
 	}
 	s += &format!(",{}", get_register_name(f.rs2));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs2]);
 	}
 	s += &format!(",{:x}", address.wrapping_add(f.imm));
@@ -1415,20 +2729,20 @@ fn parse_format_csr(word: u32) -> FormatCSR {
 	}
 }
 
-fn dump_format_csr(cpu: &mut Cpu, word: u32, _address: u64, evaluate: bool) -> String {
+fn dump_format_csr(cpu: Option<&Cpu>, word: u32, _address: u64) -> String {
 	let f = parse_format_csr(word);
 	let mut s = String::new();
 	s += &format!("{}", get_register_name(f.rd));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rd]);
 	}
 	// @TODO: Use CSR name
 	s += &format!(",{:x}", f.csr);
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.read_csr_raw(f.csr));
 	}
 	s += &format!(",{}", get_register_name(f.rs));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs]);
 	}
 	s
@@ -1454,30 +2768,30 @@ fn parse_format_i(word: u32) -> FormatI {
 	}
 }
 
-fn dump_format_i(cpu: &mut Cpu, word: u32, _address: u64, evaluate: bool) -> String {
+fn dump_format_i(cpu: Option<&Cpu>, word: u32, _address: u64) -> String {
 	let f = parse_format_i(word);
 	let mut s = String::new();
 	s += &format!("{}", get_register_name(f.rd));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rd]);
 	}
 	s += &format!(",{}", get_register_name(f.rs1));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs1]);
 	}
 	s += &format!(",{:x}", f.imm);
 	s
 }
 
-fn dump_format_i_mem(cpu: &mut Cpu, word: u32, _address: u64, evaluate: bool) -> String {
+fn dump_format_i_mem(cpu: Option<&Cpu>, word: u32, _address: u64) -> String {
 	let f = parse_format_i(word);
 	let mut s = String::new();
 	s += &format!("{}", get_register_name(f.rd));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rd]);
 	}
 	s += &format!(",{:x}({}", f.imm, get_register_name(f.rs1));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs1]);
 	}
 	s += &format!(")");
@@ -1504,11 +2818,11 @@ fn parse_format_j(word: u32) -> FormatJ {
 	}
 }
 
-fn dump_format_j(cpu: &mut Cpu, word: u32, address: u64, evaluate: bool) -> String {
+fn dump_format_j(cpu: Option<&Cpu>, word: u32, address: u64) -> String {
 	let f = parse_format_j(word);
 	let mut s = String::new();
 	s += &format!("{}", get_register_name(f.rd));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rd]);
 	}
 	s += &format!(",{:x}", address.wrapping_add(f.imm));
@@ -1529,19 +2843,19 @@ fn parse_format_r(word: u32) -> FormatR {
 	}
 }
 
-fn dump_format_r(cpu: &mut Cpu, word: u32, _address: u64, evaluate: bool) -> String {
+fn dump_format_r(cpu: Option<&Cpu>, word: u32, _address: u64) -> String {
 	let f = parse_format_r(word);
 	let mut s = String::new();
 	s += &format!("{}", get_register_name(f.rd));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rd]);
 	}
 	s += &format!(",{}", get_register_name(f.rs1));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs1]);
 	}
 	s += &format!(",{}", get_register_name(f.rs2));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs2]);
 	}
 	s
@@ -1564,23 +2878,24 @@ fn parse_format_r2(word: u32) -> FormatR2 {
 	}
 }
 
-fn dump_format_r2(cpu: &mut Cpu, word: u32, _address: u64, evaluate: bool) -> String {
+#[cfg(feature = "fd")]
+fn dump_format_r2(cpu: Option<&Cpu>, word: u32, _address: u64) -> String {
 	let f = parse_format_r2(word);
 	let mut s = String::new();
 	s += &format!("{}", get_register_name(f.rd));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rd]);
 	}
 	s += &format!(",{}", get_register_name(f.rs1));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs1]);
 	}
 	s += &format!(",{}", get_register_name(f.rs2));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs2]);
 	}
 	s += &format!(",{}", get_register_name(f.rs3));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs3]);
 	}
 	s
@@ -1607,15 +2922,15 @@ fn parse_format_s(word: u32) -> FormatS {
 	}
 }
 
-fn dump_format_s(cpu: &mut Cpu, word: u32, _address: u64, evaluate: bool) -> String {
+fn dump_format_s(cpu: Option<&Cpu>, word: u32, _address: u64) -> String {
 	let f = parse_format_s(word);
 	let mut s = String::new();
 	s += &format!("{}", get_register_name(f.rs2));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs2]);
 	}
 	s += &format!(",{:x}({}", f.imm, get_register_name(f.rs1));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rs1]);
 	}
 	s += &format!(")");
@@ -1640,22 +2955,49 @@ fn parse_format_u(word: u32) -> FormatU {
 	}
 }
 
-fn dump_format_u(cpu: &mut Cpu, word: u32, _address: u64, evaluate: bool) -> String {
+fn dump_format_u(cpu: Option<&Cpu>, word: u32, _address: u64) -> String {
 	let f = parse_format_u(word);
 	let mut s = String::new();
 	s += &format!("{}", get_register_name(f.rd));
-	if evaluate {
+	if let Some(cpu) = cpu {
 		s += &format!(":{:x}", cpu.x[f.rd]);
 	}
 	s += &format!(",{:x}", f.imm);
 	s
 }
 
-fn dump_empty(_cpu: &mut Cpu, _word: u32, _address: u64, _evaluate: bool) -> String {
+fn dump_empty(_cpu: Option<&Cpu>, _word: u32, _address: u64) -> String {
 	String::new()
 }
 
-fn get_register_name(num: usize) -> &'static str {
+// Returns the immediate bits of JAL from the offset of C.J and C.JAL
+fn get_cj_jal_imm(halfword: u32) -> u32 {
+	let offset =
+		match halfword & 0x1000 {
+			0x1000 => 0xfffff000,
+			_ => 0
+		} | // offset[31:12] <= [12]
+		((halfword >> 1) & 0x800) | // offset[11] <= [12]
+		((halfword >> 7) & 0x10) | // offset[4] <= [11]
+		((halfword >> 1) & 0x300) | // offset[9:8] <= [10:9]
+		((halfword << 2) & 0x400) | // offset[10] <= [8]
+		((halfword >> 1) & 0x40) | // offset[6] <= [7]
+		((halfword << 1) & 0x80) | // offset[7] <= [6]
+		((halfword >> 2) & 0xe) | // offset[3:1] <= [5:3]
+		((halfword << 3) & 0x20); // offset[5] <= [2]
+	let imm =
+		((offset >> 1) & 0x80000) | // imm[19] <= offset[20]
+		((offset << 8) & 0x7fe00) | // imm[18:9] <= offset[10:1]
+		((offset >> 3) & 0x100) | // imm[8] <= offset[11]
+		((offset >> 12) & 0xff); // imm[7:0] <= offset[19:12]
+	imm << 12
+}
+
+/// Returns the ABI name of the integer register, e.g. `a0`.
+///
+/// # Arguments
+/// * `num` Register number
+pub fn get_register_name(num: usize) -> &'static str {
 	match num {
 		0 => "zero",
 		1 => "ra",
@@ -1693,10 +3035,78 @@ fn get_register_name(num: usize) -> &'static str {
 	}
 }
 
-const INSTRUCTION_NUM: usize = 118;			//modifed by ez2take 116=>118
+const FP_REGISTER_NAMES: [&str; 32] = [
+	"ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7",
+	"fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+	"fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7",
+	"fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11"
+];
+
+/// Returns the number of the integer register named like `x10` or `a0`.
+/// `fp` is an alias of `s0`.
+///
+/// # Arguments
+/// * `name`
+pub fn get_register_number(name: &str) -> Option<u8> {
+	if name == "fp" {
+		return Some(8);
+	}
+	parse_register_number(name, 'x')
+		.or_else(|| (0..32).find(|num| get_register_name(*num) == name).map(|num| num as u8))
+}
+
+/// Returns the number of the floating point register named like `f10` or `fa0`.
+///
+/// # Arguments
+/// * `name`
+pub fn get_f_register_number(name: &str) -> Option<u8> {
+	parse_register_number(name, 'f')
+		.or_else(|| FP_REGISTER_NAMES.iter().position(|fp_name| *fp_name == name).map(|num| num as u8))
+}
+
+// Parses the register name like x10 to 10
+fn parse_register_number(name: &str, prefix: char) -> Option<u8> {
+	let number = name.strip_prefix(prefix)?;
+	// Rejects x010 and x+1
+	if !number.chars().all(|c| c.is_ascii_digit()) || (number.len() > 1 && number.starts_with('0')) {
+		return None;
+	}
+	number.parse::<u8>().ok().filter(|num| *num < 32)
+}
+
+const INSTRUCTION_NUM: usize = INSTRUCTIONS.len();
+
+// Opcode and funct3 bits the decode table is indexed by
+const DECODE_TABLE_KEY_MASK: u32 = 0x707f;
+const DECODE_TABLE_SIZE: usize = 0x400;
+
+// Indices of INSTRUCTIONS which can match the words by their opcode and
+// funct3, in the order of INSTRUCTIONS so that the first match wins as in
+// searching all of them. Built once on first use
+static DECODE_TABLE: OnceBox<Vec<Vec<u16>>> = OnceBox::new();
+
+fn get_decode_table_key(word: u32) -> usize {
+	((((word >> 12) & 0x7) << 7) | (word & 0x7f)) as usize
+}
+
+fn get_decode_table() -> &'static [Vec<u16>] {
+	DECODE_TABLE.get_or_init(|| {
+		let mut table = vec![vec![]; DECODE_TABLE_SIZE];
+		for (key, indices) in table.iter_mut().enumerate() {
+			let bits = (((key as u32) >> 7) << 12) | (key as u32 & 0x7f);
+			for (index, inst) in INSTRUCTIONS.iter().enumerate() {
+				if (bits & inst.mask & DECODE_TABLE_KEY_MASK) == (inst.data & DECODE_TABLE_KEY_MASK) {
+					indices.push(index as u16);
+				}
+			}
+		}
+		Box::new(table)
+	})
+}
 
 // @TODO: Reorder in often used order as 
-const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
+// F and D extension instructions are compiled only with fd feature
+const INSTRUCTIONS: &[Instruction] = &[
 	Instruction {
 		mask: 0xfe00707f,
 		data: 0x00000033,
@@ -1979,7 +3389,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		operation: |cpu, word, address| {
 			let f = parse_format_b(word);
 			if cpu.sign_extend(cpu.x[f.rs1]) == cpu.sign_extend(cpu.x[f.rs2]) {
-				cpu.pc = address.wrapping_add(f.imm);
+				cpu.jump(address.wrapping_add(f.imm))?;
 			}
 			Ok(())
 		},
@@ -1992,7 +3402,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		operation: |cpu, word, address| {
 			let f = parse_format_b(word);
 			if cpu.sign_extend(cpu.x[f.rs1]) >= cpu.sign_extend(cpu.x[f.rs2]) {
-				cpu.pc = address.wrapping_add(f.imm);
+				cpu.jump(address.wrapping_add(f.imm))?;
 			}
 			Ok(())
 		},
@@ -2005,7 +3415,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		operation: |cpu, word, address| {
 			let f = parse_format_b(word);
 			if cpu.unsigned_data(cpu.x[f.rs1]) >= cpu.unsigned_data(cpu.x[f.rs2]) {
-				cpu.pc = address.wrapping_add(f.imm);
+				cpu.jump(address.wrapping_add(f.imm))?;
 			}
 			Ok(())
 		},
@@ -2018,7 +3428,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		operation: |cpu, word, address| {
 			let f = parse_format_b(word);
 			if cpu.sign_extend(cpu.x[f.rs1]) < cpu.sign_extend(cpu.x[f.rs2]) {
-				cpu.pc = address.wrapping_add(f.imm);
+				cpu.jump(address.wrapping_add(f.imm))?;
 			}
 			Ok(())
 		},
@@ -2031,7 +3441,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		operation: |cpu, word, address| {
 			let f = parse_format_b(word);
 			if cpu.unsigned_data(cpu.x[f.rs1]) < cpu.unsigned_data(cpu.x[f.rs2]) {
-				cpu.pc = address.wrapping_add(f.imm);
+				cpu.jump(address.wrapping_add(f.imm))?;
 			}
 			Ok(())
 		},
@@ -2044,7 +3454,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		operation: |cpu, word, address| {
 			let f = parse_format_b(word);
 			if cpu.sign_extend(cpu.x[f.rs1]) != cpu.sign_extend(cpu.x[f.rs2]) {
-				cpu.pc = address.wrapping_add(f.imm);
+				cpu.jump(address.wrapping_add(f.imm))?;
 			}
 			Ok(())
 		},
@@ -2062,10 +3472,14 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			};
 			let tmp = cpu.x[f.rs];
 			cpu.x[f.rd] = cpu.sign_extend(data);
-			match cpu.write_csr(f.csr, (cpu.x[f.rd] & !tmp) as u64) {
-				Ok(()) => {},
-				Err(e) => return Err(e)
-			};
+			// No write if rs1 is x0 because reading some CSRs has no
+			// side effect but writing has, e.g. stopei
+			if f.rs != 0 {
+				match cpu.write_csr(f.csr, (cpu.x[f.rd] & !tmp) as u64) {
+					Ok(()) => {},
+					Err(e) => return Err(e)
+				};
+			}
 			Ok(())
 		},
 		disassemble: dump_format_csr
@@ -2081,10 +3495,12 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 				Err(e) => return Err(e)
 			};
 			cpu.x[f.rd] = cpu.sign_extend(data);
-			match cpu.write_csr(f.csr, (cpu.x[f.rd] & !(f.rs as i64)) as u64) {
-				Ok(()) => {},
-				Err(e) => return Err(e)
-			};
+			if f.rs != 0 {
+				match cpu.write_csr(f.csr, (cpu.x[f.rd] & !(f.rs as i64)) as u64) {
+					Ok(()) => {},
+					Err(e) => return Err(e)
+				};
+			}
 			Ok(())
 		},
 		disassemble: dump_format_csr
@@ -2101,10 +3517,12 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			};
 			let tmp = cpu.x[f.rs];
 			cpu.x[f.rd] = cpu.sign_extend(data);
-			match cpu.write_csr(f.csr, cpu.unsigned_data(cpu.x[f.rd] | tmp)) {
-				Ok(()) => {},
-				Err(e) => return Err(e)
-			};
+			if f.rs != 0 {
+				match cpu.write_csr(f.csr, cpu.unsigned_data(cpu.x[f.rd] | tmp)) {
+					Ok(()) => {},
+					Err(e) => return Err(e)
+				};
+			}
 			Ok(())
 		},
 		disassemble: dump_format_csr
@@ -2120,10 +3538,12 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 				Err(e) => return Err(e)
 			};
 			cpu.x[f.rd] = cpu.sign_extend(data);
-			match cpu.write_csr(f.csr, cpu.unsigned_data(cpu.x[f.rd] | (f.rs as i64))) {
-				Ok(()) => {},
-				Err(e) => return Err(e)
-			};
+			if f.rs != 0 {
+				match cpu.write_csr(f.csr, cpu.unsigned_data(cpu.x[f.rd] | (f.rs as i64))) {
+					Ok(()) => {},
+					Err(e) => return Err(e)
+				};
+			}
 			Ok(())
 		},
 		disassemble: dump_format_csr
@@ -2230,7 +3650,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			let divisor = cpu.x[f.rs2] as i32;
 			if divisor == 0 {
 				cpu.x[f.rd] = -1;
-			} else if dividend == std::i32::MIN && divisor == -1 {
+			} else if dividend == i32::MIN && divisor == -1 {
 				cpu.x[f.rd] = dividend as i32 as i64;
 			} else {
 				cpu.x[f.rd] = dividend.wrapping_div(divisor) as i32 as i64
@@ -2267,104 +3687,131 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		},
 		disassemble: dump_empty
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfe00007f,
 		data: 0x02000053,
 		name: "FADD.D",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = cpu.f[f.rs1] + cpu.f[f.rs2];
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let (result, flags) = float::add(&DOUBLE, cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits(), rounding_mode);
+			cpu.f[f.rd] = f64::from_bits(result);
+			cpu.mark_fs_dirty();
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfff0007f,
 		data: 0xd2200053,
 		name: "FCVT.D.L",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = cpu.x[f.rs1] as f64;
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let value = cpu.x[f.rs1];
+			let (result, flags) = float::from_integer(&DOUBLE, value.unsigned_abs(), value < 0, rounding_mode);
+			cpu.f[f.rd] = f64::from_bits(result);
+			cpu.mark_fs_dirty();
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfff0007f,
 		data: 0x42000053,
 		name: "FCVT.D.S",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			// Is this implementation correct?
-			cpu.f[f.rd] = f32::from_bits(cpu.f[f.rs1].to_bits() as u32) as f64;
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let (result, flags) = float::convert(&SINGLE, &DOUBLE, cpu.read_single(f.rs1), rounding_mode);
+			cpu.f[f.rd] = f64::from_bits(result);
+			cpu.mark_fs_dirty();
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfff0007f,
 		data: 0xd2000053,
 		name: "FCVT.D.W",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = cpu.x[f.rs1] as i32 as f64;
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let value = cpu.x[f.rs1] as i32;
+			let (result, flags) = float::from_integer(&DOUBLE, value.unsigned_abs() as u64, value < 0, rounding_mode);
+			cpu.f[f.rd] = f64::from_bits(result);
+			cpu.mark_fs_dirty();
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfff0007f,
 		data: 0xd2100053,
 		name: "FCVT.D.WU",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = cpu.x[f.rs1] as u32 as f64;
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let (result, flags) = float::from_integer(&DOUBLE, cpu.x[f.rs1] as u32 as u64, false, rounding_mode);
+			cpu.f[f.rd] = f64::from_bits(result);
+			cpu.mark_fs_dirty();
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfff0007f,
 		data: 0x40100053,
 		name: "FCVT.S.D",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			// Is this implementation correct?
-			cpu.f[f.rd] = cpu.f[f.rs1] as f32 as f64;
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let (result, flags) = float::convert(&DOUBLE, &SINGLE, cpu.f[f.rs1].to_bits(), rounding_mode);
+			cpu.f[f.rd] = f64::from_bits(result | SINGLE_NAN_BOX);
+			cpu.mark_fs_dirty();
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfff0007f,
 		data: 0xc2000053,
 		name: "FCVT.W.D",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			// Is this implementation correct?
-			cpu.x[f.rd] = cpu.f[f.rs1] as u32 as i32 as i64;
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let (result, flags) = float::to_integer(&DOUBLE, cpu.f[f.rs1].to_bits(), 32, true, rounding_mode);
+			cpu.x[f.rd] = result;
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfe00007f,
 		data: 0x1a000053,
 		name: "FDIV.D",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			let dividend = cpu.f[f.rs1];
-			let divisor = cpu.f[f.rs2];
-			// Is this implementation correct?
-			if divisor == 0.0 {
-				cpu.f[f.rd] = std::f64::INFINITY;
-				cpu.set_fcsr_dz();
-			} else if divisor == -0.0 {
-				cpu.f[f.rd] = std::f64::NEG_INFINITY;
-				cpu.set_fcsr_dz();
-			} else {
-				cpu.f[f.rd] = dividend / divisor;
-			}
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let (result, flags) = float::div(&DOUBLE, cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits(), rounding_mode);
+			cpu.f[f.rd] = f64::from_bits(result);
+			cpu.mark_fs_dirty();
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
@@ -2389,20 +3836,24 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		},
 		disassemble: dump_empty
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfe00707f,
 		data: 0xa2002053,
 		name: "FEQ.D",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.x[f.rd] = match cpu.f[f.rs1] == cpu.f[f.rs2] {
-				true => 1,
-				false => 0
+			let (ordering, flags) = float::compare(&DOUBLE, cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits(), false);
+			cpu.x[f.rd] = match ordering {
+				Some(Ordering::Equal) => 1,
+				_ => 0
 			};
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_empty
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0x0000707f,
 		data: 0x00003007,
@@ -2413,38 +3864,46 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 				Ok(data) => f64::from_bits(data),
 				Err(e) => return Err(e)
 			};
+			cpu.mark_fs_dirty();
 			Ok(())
 		},
 		disassemble: dump_format_i
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfe00707f,
 		data: 0xa2000053,
 		name: "FLE.D",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.x[f.rd] = match cpu.f[f.rs1] <= cpu.f[f.rs2] {
-				true => 1,
-				false => 0
+			let (ordering, flags) = float::compare(&DOUBLE, cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits(), true);
+			cpu.x[f.rd] = match ordering {
+				Some(Ordering::Less) | Some(Ordering::Equal) => 1,
+				_ => 0
 			};
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfe00707f,
 		data: 0xa2001053,
 		name: "FLT.D",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.x[f.rd] = match cpu.f[f.rs1] < cpu.f[f.rs2] {
-				true => 1,
-				false => 0
+			let (ordering, flags) = float::compare(&DOUBLE, cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits(), true);
+			cpu.x[f.rd] = match ordering {
+				Some(Ordering::Less) => 1,
+				_ => 0
 			};
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0x0000707f,
 		data: 0x00002007,
@@ -2452,37 +3911,48 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		operation: |cpu, word, _address| {
 			let f = parse_format_i(word);
 			cpu.f[f.rd] = match cpu.mmu.load_word(cpu.x[f.rs1].wrapping_add(f.imm) as u64) {
-				Ok(data) => f64::from_bits(data as i32 as i64 as u64),
+				Ok(data) => f64::from_bits(data as u64 | SINGLE_NAN_BOX),
 				Err(e) => return Err(e)
 			};
+			cpu.mark_fs_dirty();
 			Ok(())
 		},
 		disassemble: dump_format_i_mem
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0x0600007f,
 		data: 0x02000043,
 		name: "FMADD.D",
 		operation: |cpu, word, _address| {
-			// @TODO: Update fcsr if needed?
 			let f = parse_format_r2(word);
-			cpu.f[f.rd] = cpu.f[f.rs1] * cpu.f[f.rs2] + cpu.f[f.rs3];
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let (result, flags) = float::fused_multiply_add(&DOUBLE, cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits(),
+				cpu.f[f.rs3].to_bits(), rounding_mode);
+			cpu.f[f.rd] = f64::from_bits(result);
+			cpu.mark_fs_dirty();
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r2
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfe00007f,
 		data: 0x12000053,
 		name: "FMUL.D",
 		operation: |cpu, word, _address| {
-			// @TODO: Update fcsr if needed?
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = cpu.f[f.rs1] * cpu.f[f.rs2];
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let (result, flags) = float::mul(&DOUBLE, cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits(), rounding_mode);
+			cpu.f[f.rd] = f64::from_bits(result);
+			cpu.mark_fs_dirty();
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfff0707f,
 		data: 0xf2000053,
@@ -2490,10 +3960,12 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
 			cpu.f[f.rd] = f64::from_bits(cpu.x[f.rs1] as u64);
+			cpu.mark_fs_dirty();
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfff0707f,
 		data: 0xe2000053,
@@ -2505,6 +3977,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfff0707f,
 		data: 0xe0000053,
@@ -2516,28 +3989,38 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfff0707f,
 		data: 0xf0000053,
 		name: "FMV.W.X",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			cpu.f[f.rd] = f64::from_bits(cpu.x[f.rs1] as u32 as u64);
+			cpu.f[f.rd] = f64::from_bits(cpu.x[f.rs1] as u32 as u64 | SINGLE_NAN_BOX);
+			cpu.mark_fs_dirty();
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0x0600007f,
 		data: 0x0200004b,
 		name: "FNMSUB.D",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r2(word);
-			cpu.f[f.rd] = -(cpu.f[f.rs1] * cpu.f[f.rs2]) + cpu.f[f.rs3];
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			// -(rs1 * rs2) + rs3 with the sign of rs1 flipped
+			let (result, flags) = float::fused_multiply_add(&DOUBLE, cpu.f[f.rs1].to_bits() ^ 0x8000000000000000,
+				cpu.f[f.rs2].to_bits(), cpu.f[f.rs3].to_bits(), rounding_mode);
+			cpu.f[f.rd] = f64::from_bits(result);
+			cpu.mark_fs_dirty();
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r2
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0x0000707f,
 		data: 0x00003027,
@@ -2548,6 +4031,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		},
 		disassemble: dump_format_s
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfe00707f,
 		data: 0x22000053,
@@ -2558,10 +4042,12 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			let rs2_bits = cpu.f[f.rs2].to_bits();
 			let sign_bit = rs2_bits & 0x8000000000000000;
 			cpu.f[f.rd] = f64::from_bits(sign_bit | (rs1_bits & 0x7fffffffffffffff));
+			cpu.mark_fs_dirty();
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfe00707f,
 		data: 0x22002053,
@@ -2572,22 +4058,28 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			let rs2_bits = cpu.f[f.rs2].to_bits();
 			let sign_bit = (rs1_bits ^ rs2_bits) & 0x8000000000000000;
 			cpu.f[f.rd] = f64::from_bits(sign_bit | (rs1_bits & 0x7fffffffffffffff));
+			cpu.mark_fs_dirty();
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0xfe00007f,
 		data: 0x0a000053,
 		name: "FSUB.D",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			// @TODO: Update fcsr if needed?
-			cpu.f[f.rd] = cpu.f[f.rs1] - cpu.f[f.rs2];
+			let rounding_mode = cpu.get_rounding_mode(word)?;
+			let (result, flags) = float::sub(&DOUBLE, cpu.f[f.rs1].to_bits(), cpu.f[f.rs2].to_bits(), rounding_mode);
+			cpu.f[f.rd] = f64::from_bits(result);
+			cpu.mark_fs_dirty();
+			cpu.accrue_fflags(flags);
 			Ok(())
 		},
 		disassemble: dump_format_r
 	},
+	#[cfg(feature = "fd")]
 	Instruction {
 		mask: 0x0000707f,
 		data: 0x00002027,
@@ -2604,8 +4096,9 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		name: "JAL",
 		operation: |cpu, word, address| {
 			let f = parse_format_j(word);
-			cpu.x[f.rd] = cpu.sign_extend(cpu.pc as i64);
-			cpu.pc = address.wrapping_add(f.imm);
+			let tmp = cpu.sign_extend(cpu.pc as i64);
+			cpu.jump(address.wrapping_add(f.imm))?;
+			cpu.x[f.rd] = tmp;
 			Ok(())
 		},
 		disassemble: dump_format_j
@@ -2617,19 +4110,27 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		operation: |cpu, word, _address| {
 			let f = parse_format_i(word);
 			let tmp = cpu.sign_extend(cpu.pc as i64);
-			cpu.pc = (cpu.x[f.rs1] as u64).wrapping_add(f.imm as u64);
+			cpu.jump((cpu.x[f.rs1] as u64).wrapping_add(f.imm as u64) & !0x1)?;
 			cpu.x[f.rd] = tmp;
+			// Returns through ra or t0 and software guarded jumps
+			// through t2 don't need a landing pad
+			if let Some(statistics) = cpu.cfi_statistics.as_mut() {
+				if f.rs1 != 1 && f.rs1 != 5 && f.rs1 != 7 {
+					statistics.record_branch(f.rd == 1 || f.rd == 5);
+					cpu.expected_landing_pad = true;
+				}
+			}
 			Ok(())
 		},
-		disassemble: |cpu, word, _address, evaluate| {
+		disassemble: |cpu, word, _address| {
 			let f = parse_format_i(word);
 			let mut s = String::new();
 			s += &format!("{}", get_register_name(f.rd));
-			if evaluate {
+			if let Some(cpu) = cpu {
 				s += &format!(":{:x}", cpu.x[f.rd]);
 			}
 			s += &format!(",{:x}({}", f.imm, get_register_name(f.rs1));
-			if evaluate {
+			if let Some(cpu) = cpu {
 				s += &format!(":{:x}", cpu.x[f.rs1]);
 			}
 			s += &format!(")");
@@ -2875,9 +4376,10 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 				PrivilegeMode::Machine => (status >> 17) & 1,
 				_ => 0
 			};
-			// Override MIE[3] with MPIE[7], set MPIE[7] to 1, set MPP[12:11] to 0
-			// and override MPRV[17]
-			let new_status = (status & !0x21888) | (mprv << 17) | (mpie << 3) | (1 << 7);
+			// Override MIE[3] with MPIE[7], set MPIE[7] to 1, set MPP[12:11] to 0,
+			// override MPRV[17], and restore ELP from MPELP[41] clearing it
+			let new_status = (status & !0x20000021888) | (mprv << 17) | (mpie << 3) | (1 << 7);
+			cpu.expected_landing_pad = cpu.cfi_statistics.is_some() && ((status >> 41) & 1) == 1;
 			cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, new_status);
 			cpu.privilege_mode = match mpp {
 				0 => PrivilegeMode::User,
@@ -2973,7 +4475,7 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 			let divisor = cpu.x[f.rs2] as i32;
 			if divisor == 0 {
 				cpu.x[f.rd] = dividend as i64;
-			} else if dividend == std::i32::MIN && divisor == -1 {
+			} else if dividend == i32::MIN && divisor == -1 {
 				cpu.x[f.rd] = 0;
 			} else {
 				cpu.x[f.rd] = dividend.wrapping_rem(divisor) as i64;
@@ -3238,8 +4740,9 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 				_ => 0
 			};
 			// Override SIE[1] with SPIE[5], set SPIE[5] to 1, set SPP[8] to 0,
-			// and override MPRV[17]
-			let new_status = (status & !0x20122) | (mprv << 17) | (spie << 1) | (1 << 5);
+			// override MPRV[17], and restore ELP from SPELP[23] clearing it
+			let new_status = (status & !0x820122) | (mprv << 17) | (spie << 1) | (1 << 5);
+			cpu.expected_landing_pad = cpu.cfi_statistics.is_some() && ((status >> 23) & 1) == 1;
 			cpu.write_csr_raw(CSR_SSTATUS_ADDRESS, new_status);
 			cpu.privilege_mode = match spp {
 				0 => PrivilegeMode::User,
@@ -3341,9 +4844,13 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		mask: 0xffffffff,
 		data: 0x00200073,
 		name: "URET",
-		operation: |_cpu, _word, _address| {
+		operation: |cpu, word, _address| {
 			// @TODO: Implement
-			panic!("URET instruction is not implemented yet.");
+			cpu.record_error(ExecError::Unimplemented("URET. It raises an illegal instruction exception"));
+			Err(Trap {
+				trap_type: TrapType::IllegalInstruction,
+				value: word as u64
+			})
 		},
 		disassemble: dump_empty
 	},
@@ -3379,21 +4886,71 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		},
 		disassemble: dump_format_i
 	},
+	// Zicfiss. The shadow stack instructions are may-be-operations doing
+	// nothing, or SSRDP writing zero, without the shadow stack region
+	Instruction {
+		mask: 0xfe0fffff,
+		data: 0xce004073,
+		name: "SSPUSH",
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			if !cpu.mmu.has_shadow_stack() || (f.rs2 != 1 && f.rs2 != 5) {
+				return Ok(());
+			}
+			let ssp = cpu.unsigned_data((cpu.read_csr_raw(CSR_SSP_ADDRESS) as i64).wrapping_sub(cpu.get_xlen_bytes()));
+			let value = cpu.unsigned_data(cpu.x[f.rs2]);
+			cpu.mmu.store_shadow_stack(ssp, value)?;
+			cpu.write_csr_raw(CSR_SSP_ADDRESS, ssp);
+			Ok(())
+		},
+		disassemble: dump_format_r
+	},
+	Instruction {
+		mask: 0xfffff07f,
+		data: 0xcdc04073,
+		name: "SSRDP",
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			cpu.x[f.rd] = match cpu.mmu.has_shadow_stack() {
+				true => cpu.sign_extend(cpu.read_csr_raw(CSR_SSP_ADDRESS) as i64),
+				false => 0
+			};
+			Ok(())
+		},
+		disassemble: dump_format_r
+	},
+	Instruction {
+		mask: 0xfff07fff,
+		data: 0xcdc04073,
+		name: "SSPOPCHK",
+		operation: |cpu, word, _address| {
+			let f = parse_format_r(word);
+			if !cpu.mmu.has_shadow_stack() || (f.rs1 != 1 && f.rs1 != 5) {
+				return Ok(());
+			}
+			let ssp = cpu.read_csr_raw(CSR_SSP_ADDRESS);
+			let value = cpu.mmu.load_shadow_stack(ssp)?;
+			if value != cpu.unsigned_data(cpu.x[f.rs1]) {
+				return Err(Trap {
+					trap_type: TrapType::SoftwareCheck,
+					value: 3 // Shadow stack fault
+				});
+			}
+			cpu.write_csr_raw(CSR_SSP_ADDRESS, cpu.unsigned_data(ssp.wrapping_add(cpu.get_xlen_bytes() as u64) as i64));
+			Ok(())
+		},
+		disassemble: dump_format_r
+	},
 	Instruction {
 		mask: 0xfffff07f,
 		data: 0x0000000b,		//custom-0:inst[6:0]=0b001011 & funct3=0b000 & funct7=0b0000000
 		name: "ZIP",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			let masked_ra = cpu.x[f.rd] as u64 & 0x7fffffffffu64;
+			let masked_ra = cpu.x[f.rd] as u64 & RETURN_ADDRESS_MASK; //rv64 with sv39 uses only lower 39 bits of ra register
 			cpu.x[f.rd] = (cpu.top | masked_ra) as i64;
-			cpu.hasher.update(cpu.key.to_be_bytes());
-			cpu.hasher.update((cpu.top | masked_ra).to_be_bytes()); //rv64 with sv39 uses only lower 39 bits of ra register
-			let hash = cpu.hasher.finalize_reset();
-			cpu.top=((hash[0]as u64) << 24
-					|(hash[1]as u64) << 16
-					|(hash[2]as u64) << 8
-					|(hash[3]as u64)) << 39;
+			let slot = cpu.get_zipper_key_slot();
+			cpu.top = cpu.zipper_mac.compute_with_slot(slot, cpu.top | masked_ra);
 			Ok(())
 		},
 		disassemble: dump_format_r
@@ -3404,16 +4961,11 @@ const INSTRUCTIONS: [Instruction; INSTRUCTION_NUM] = [
 		name: "UNZIP",
 		operation: |cpu, word, _address| {
 			let f = parse_format_r(word);
-			let masked_ra = cpu.x[f.rd] as u64 &  0x7fffffffffu64;
-			let old_hash  = cpu.x[f.rd] as u64 & !0x7fffffffffu64;
+			let masked_ra = cpu.x[f.rd] as u64 &  RETURN_ADDRESS_MASK;
+			let old_hash  = cpu.x[f.rd] as u64 & !RETURN_ADDRESS_MASK;
 
-			cpu.hasher.update(cpu.key.to_be_bytes());
-			cpu.hasher.update(cpu.x[f.rd].to_be_bytes());
-			let hash = cpu.hasher.finalize_reset();
-			let hash =  ((hash[0]as u64) << 24
-						|(hash[1]as u64) << 16
-						|(hash[2]as u64) << 8
-						|(hash[3]as u64)) << 39;
+			let slot = cpu.get_zipper_key_slot();
+			let hash = cpu.zipper_mac.compute_with_slot(slot, cpu.x[f.rd] as u64);
 
 			if cpu.top != hash {
 				return Err(Trap {
@@ -3471,10 +5023,10 @@ struct DecodeCache {
 	/// An index of `entries` pointing to the tail entry in the linked list
 	back_index: usize,
 
-	/// Cache hit count for debugging purpose
+	/// Cache hit count, returned by `Cpu::get_decode_cache_stats()`
 	hit_count: u64,
 
-	/// Cache miss count for debugging purpose
+	/// Cache miss count, returned by `Cpu::get_decode_cache_stats()`
 	miss_count: u64
 }
 
@@ -3617,6 +5169,7 @@ impl DecodeCacheEntry {
 mod test_cpu {
 	use terminal::DummyTerminal;
 	use mmu::DRAM_BASE;
+	use error::MemoryError;
 	use super::*;
 
 	fn create_cpu() -> Cpu {
@@ -3650,6 +5203,15 @@ mod test_cpu {
 		// The test for mmu.xlen should be in Mmu?
 	}
 
+	#[test]
+	#[cfg(feature = "fd")]
+	fn get_isa_string() {
+		let mut cpu = create_cpu();
+		assert_eq!("rv64imafdcsu", cpu.get_isa_string());
+		cpu.update_xlen(Xlen::Bit32);
+		assert_eq!("rv32imafdcsu", cpu.get_isa_string());
+	}
+
 	#[test]
 	fn read_register() {
 		let mut cpu = create_cpu();
@@ -3693,7 +5255,7 @@ mod test_cpu {
 	#[test]
 	fn tick() {
 		let mut cpu = create_cpu();
-		cpu.get_mut_mmu().init_memory(4);
+		cpu.get_mut_mmu().init_memory(8);
 		cpu.update_pc(DRAM_BASE);
 
 		// Write non-compressed "addi x1, x1, 1" instruction
@@ -3718,6 +5280,171 @@ mod test_cpu {
 		assert_eq!(8, cpu.read_register(8));
 	}
 
+	#[test]
+	fn step_diff() {
+		let mut cpu = create_cpu();
+		cpu.get_mut_mmu().init_memory(0x100);
+		cpu.update_pc(DRAM_BASE);
+		// Write "sw x1, 16(x2)" and "addi x1, x1, 1" instructions
+		for (i, word) in [0x00112823, 0x00108093].iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		cpu.x[1] = 0x12345678;
+		cpu.x[2] = DRAM_BASE as i64;
+
+		let diff = cpu.step_diff(|cpu| cpu.tick());
+		assert_eq!((DRAM_BASE, DRAM_BASE + 4), diff.pc);
+		assert_eq!(None, diff.privilege_mode);
+		assert!(diff.x.is_empty());
+		assert!(diff.f.is_empty());
+		assert_eq!(vec![(CSR_CYCLE_ADDRESS, 0, 8)], diff.csr);
+		assert_eq!(vec![
+			(DRAM_BASE + 16, 0, 0x78),
+			(DRAM_BASE + 17, 0, 0x56),
+			(DRAM_BASE + 18, 0, 0x34),
+			(DRAM_BASE + 19, 0, 0x12)
+		], diff.memory);
+
+		let diff = cpu.step_diff(|cpu| cpu.tick());
+		assert_eq!((DRAM_BASE + 4, DRAM_BASE + 8), diff.pc);
+		assert_eq!(vec![(1, 0x12345678, 0x12345679)], diff.x);
+		assert!(diff.memory.is_empty());
+	}
+
+	#[test]
+	fn disassemble() {
+		assert_eq!("ADDI a0,a0,1", super::disassemble(0x00150513, 0, &Xlen::Bit64));
+		assert_eq!("BNE a0,a1,fffffffffffffff8", super::disassemble(0xfeb51ce3, 0, &Xlen::Bit64));
+		assert_eq!("JAL zero,80000010", super::disassemble(0xa801, 0x80000000, &Xlen::Bit64));
+		assert_eq!("ECALL", super::disassemble(0x00000073, 0, &Xlen::Bit64));
+		assert_eq!(".word 0xffffffff", super::disassemble(0xffffffff, 0, &Xlen::Bit64));
+		// C.JAL in 32-bit mode and C.ADDIW in 64-bit mode
+		assert_eq!("JAL ra,80000010", super::disassemble(0x2801, 0x80000000, &Xlen::Bit32));
+		assert_eq!("ADDIW a6,a6,0", super::disassemble(0x2801, 0x80000000, &Xlen::Bit64));
+		assert_eq!(".half 0x0000", super::disassemble(0x0000, 0, &Xlen::Bit64));
+	}
+
+	#[test]
+	#[cfg(feature = "fd")]
+	fn decode_instruction() {
+		let decoded = super::decode(0xff010113, &Xlen::Bit64); // addi sp, sp, -16
+		assert_eq!(Some(DecodedInstruction {
+			mnemonic: "ADDI",
+			class: InstructionClass::Alu,
+			operands: vec![Operand::Register(2), Operand::Register(2), Operand::Immediate(-16)],
+			immediate: Some(-16),
+			word: 0xff010113,
+			length: 4
+		}), decoded);
+		// c.sdsp ra, 8(sp)
+		let decoded = super::decode(0xe406, &Xlen::Bit64).map(|decoded| (decoded.mnemonic, decoded.class, decoded.operands, decoded.length));
+		assert_eq!(Some(("SD", InstructionClass::Store, vec![
+			Operand::Register(2), Operand::Register(1), Operand::Immediate(8)
+		], 2)), decoded);
+		let decoded = super::decode(0xfeb51ce3, &Xlen::Bit64).map(|decoded| (decoded.class, decoded.immediate));
+		assert_eq!(Some((InstructionClass::Branch, Some(-8))), decoded);
+		let decoded = super::decode(0x30002573, &Xlen::Bit64).map(|decoded| (decoded.class, decoded.operands));
+		assert_eq!(Some((InstructionClass::Csr, vec![
+			Operand::Register(10), Operand::Register(0), Operand::Csr(CSR_MSTATUS_ADDRESS)
+		])), decoded); // csrr a0, mstatus
+		let decoded = super::decode(0xe2050553, &Xlen::Bit64).map(|decoded| decoded.operands);
+		assert_eq!(Some(vec![Operand::Register(10), Operand::FRegister(10)]), decoded); // fmv.x.d a0, fa0
+		assert_eq!(None, super::decode(0xffffffff, &Xlen::Bit64));
+	}
+
+	#[test]
+	fn csr_access() {
+		let mut cpu = create_cpu();
+		assert_eq!(Some(CSR_MSTATUS_ADDRESS), get_csr_address("mstatus"));
+		assert_eq!(Some("sstatus"), get_csr_name(CSR_SSTATUS_ADDRESS));
+		assert_eq!(None, get_csr_name(0x7ff));
+
+		assert_eq!(Ok(()), cpu.write_csr_as(CSR_MSTATUS_ADDRESS, 0x2, &PrivilegeMode::Machine));
+		assert_eq!(Ok(0x200000002), cpu.read_csr_as(CSR_SSTATUS_ADDRESS, &PrivilegeMode::Supervisor));
+		assert_eq!(Err(DebugError::InaccessibleCsr(CSR_MSTATUS_ADDRESS)), cpu.read_csr_as(CSR_MSTATUS_ADDRESS, &PrivilegeMode::Supervisor));
+		assert_eq!(Err(DebugError::InaccessibleCsr(CSR_SSTATUS_ADDRESS)), cpu.write_csr_as(CSR_SSTATUS_ADDRESS, 0, &PrivilegeMode::User));
+		// Read-only
+		assert_eq!(Err(DebugError::InaccessibleCsr(CSR_CYCLE_ADDRESS)), cpu.write_csr_as(CSR_CYCLE_ADDRESS, 0, &PrivilegeMode::Machine));
+		// Not implemented
+		assert_eq!(Err(DebugError::InaccessibleCsr(0x7ff)), cpu.read_csr_as(0x7ff, &PrivilegeMode::Machine));
+
+		let csrs = cpu.dump_csrs();
+		assert_eq!(CSR_NAMES.len(), csrs.len());
+		assert!(csrs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+		assert!(csrs.contains(&(CSR_MISA_ADDRESS, "misa", MISA_DEFAULT)));
+	}
+
+	#[test]
+	fn warl_csrs() {
+		let mut cpu = create_cpu();
+		// Reserved and read-only fields read as zero, except UXL and SXL
+		// reading as XLEN=64
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, !MSTATUS_FS_DIRTY);
+		assert_eq!((MSTATUS_WRITABLE_MASK & !MSTATUS_FS_DIRTY) | MSTATUS_XL_64, cpu.read_csr_raw(CSR_MSTATUS_ADDRESS));
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, 0);
+		assert_eq!(2, (cpu.read_csr_raw(CSR_MSTATUS_ADDRESS) >> 32) & 0x3);
+		assert_eq!(2, (cpu.read_csr_raw(CSR_MSTATUS_ADDRESS) >> 34) & 0x3);
+		assert_eq!(2, (cpu.read_csr_raw(CSR_SSTATUS_ADDRESS) >> 32) & 0x3);
+		assert!(cpu.write_csr(CSR_SSTATUS_ADDRESS, 0).is_ok());
+		assert_eq!(2, (cpu.read_csr_raw(CSR_SSTATUS_ADDRESS) >> 32) & 0x3);
+		cpu.update_xlen(Xlen::Bit32);
+		assert_eq!(0, cpu.read_csr_raw(CSR_MSTATUS_ADDRESS) & MSTATUS_XL_MASK);
+		cpu.update_xlen(Xlen::Bit64);
+		cpu.write_csr_raw(CSR_MIE_ADDRESS, 0xffffffffffffffff);
+		assert_eq!(0xaaa, cpu.read_csr_raw(CSR_MIE_ADDRESS));
+		cpu.write_csr_raw(CSR_MEDELEG_ADDRESS, 0xffffffffffffffff);
+		assert_eq!(0, cpu.read_csr_raw(CSR_MEDELEG_ADDRESS) & (1 << 11));
+		cpu.write_csr_raw(CSR_FCSR_ADDRESS, 0xfff);
+		assert_eq!(0xff, cpu.read_csr_raw(CSR_FCSR_ADDRESS));
+		cpu.write_csr_raw(CSR_MEPC_ADDRESS, 0x1003);
+		assert_eq!(0x1002, cpu.read_csr_raw(CSR_MEPC_ADDRESS));
+		// The reserved MPP encoding and mtvec modes keep the current ones
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, 0x800);
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, 0x1000);
+		assert_eq!(0x800, cpu.read_csr_raw(CSR_MSTATUS_ADDRESS) & !MSTATUS_XL_MASK);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, 0x1001);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, 0x2002);
+		assert_eq!(0x2001, cpu.read_csr_raw(CSR_MTVEC_ADDRESS));
+		// Writing satp with an unsupported mode has no effect
+		cpu.write_csr_raw(CSR_SATP_ADDRESS, 0x8000000000000123);
+		cpu.write_csr_raw(CSR_SATP_ADDRESS, 0x9000000000000456);
+		assert_eq!(0x8000000000000123, cpu.read_csr_raw(CSR_SATP_ADDRESS));
+		// Guests can't write the machine level bits of mip
+		assert!(cpu.write_csr(CSR_MIP_ADDRESS, 0xfff).is_ok());
+		assert_eq!(0x222, cpu.read_csr_raw(CSR_MIP_ADDRESS));
+	}
+
+	#[test]
+	fn writable_misa() {
+		let mut cpu = create_cpu();
+		cpu.update_pc(DRAM_BASE + 2);
+		cpu.write_csr_raw(CSR_MEPC_ADDRESS, 0x1002);
+		// Disabling C is ignored at an address not aligned to 4 bytes
+		cpu.write_csr_raw(CSR_MISA_ADDRESS, MISA_DEFAULT & !MISA_C);
+		assert_eq!(MISA_DEFAULT, cpu.read_csr_raw(CSR_MISA_ADDRESS));
+		cpu.update_pc(DRAM_BASE + 4);
+		cpu.write_csr_raw(CSR_MISA_ADDRESS, MISA_DEFAULT & !MISA_C);
+		assert_eq!(MISA_DEFAULT & !MISA_C, cpu.read_csr_raw(CSR_MISA_ADDRESS));
+		assert!(!cpu.get_isa_string().contains('c'));
+		// Compressed instructions and jumps to 2 byte aligned addresses
+		// raise exceptions, and bit 1 of mepc is masked
+		assert!(cpu.check_extension(0x0001, 0x00000013).is_err()); // c.nop
+		assert!(cpu.check_extension(0x00000013, 0x00000013).is_ok()); // nop
+		assert!(cpu.jump(DRAM_BASE + 2).is_err());
+		assert_eq!(0x1000, cpu.read_csr_raw(CSR_MEPC_ADDRESS));
+		// I, S, and U can't be disabled, and D is disabled with F
+		cpu.write_csr_raw(CSR_MISA_ADDRESS, MISA_DEFAULT & !MISA_EXTENSIONS_MASK);
+		assert_eq!(MISA_DEFAULT & !MISA_TOGGLEABLE_MASK, cpu.read_csr_raw(CSR_MISA_ADDRESS));
+		cpu.write_csr_raw(CSR_MISA_ADDRESS, MISA_DEFAULT & !MISA_F);
+		assert_eq!(0, cpu.read_csr_raw(CSR_MISA_ADDRESS) & MISA_D);
+		assert!(cpu.check_extension(0x02b50533, 0x02b50533).is_ok()); // mul a0, a0, a1
+		cpu.write_csr_raw(CSR_MISA_ADDRESS, MISA_DEFAULT);
+		assert!(cpu.jump(DRAM_BASE + 2).is_ok());
+		assert_eq!(0x1002, cpu.read_csr_raw(CSR_MEPC_ADDRESS));
+	}
+
 	#[test]
 	fn tick_operate() {
 		let mut cpu = create_cpu();
@@ -3742,6 +5469,79 @@ mod test_cpu {
 		// @TODO: Test compressed instruction operation
 	}
 
+	#[test]
+	#[cfg(feature = "fd")]
+	fn instruction_statistics() {
+		let mut cpu = create_cpu();
+		cpu.get_mut_mmu().init_memory(0x20);
+		cpu.update_pc(DRAM_BASE);
+		let code = [
+			0x00c50513, // addi a0, a0, 12
+			0x02a50533, // mul a0, a0, a0
+			0x30002573, // csrr a0, mstatus
+			0x05050505, // c.addi a0, 1 twice
+			0x0000100f, // fence.i
+			0x02a57553 // fadd.d fa0, fa0, fa0
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		cpu.tick();
+		assert!(cpu.get_instruction_statistics().is_empty());
+		cpu.enable_instruction_statistics(true);
+		for _ in 0..6 {
+			cpu.tick();
+		}
+		assert_eq!(vec![
+			(Extension::M, PrivilegeMode::Machine, 1),
+			(Extension::D, PrivilegeMode::Machine, 1),
+			(Extension::C, PrivilegeMode::Machine, 2),
+			(Extension::Zicsr, PrivilegeMode::Machine, 1),
+			(Extension::Zifencei, PrivilegeMode::Machine, 1)
+		], cpu.get_instruction_statistics());
+		cpu.reset_instruction_statistics();
+		assert!(cpu.get_instruction_statistics().is_empty());
+	}
+
+	#[test]
+	fn take_error() {
+		let mut cpu = create_cpu();
+		cpu.get_mut_mmu().init_memory(0x100);
+		cpu.update_pc(DRAM_BASE);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, DRAM_BASE + 0x40);
+		let code = [
+			0xffffffff, // unknown
+			0x40000537, // lui a0, 0x40000
+			0x00052583 // lw a1, 0(a0)
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		cpu.tick();
+		// Raises an illegal instruction exception instead of panicking
+		assert_eq!(Some(ExecError::UnknownInstruction {
+			pc: DRAM_BASE,
+			word: 0xffffffff
+		}), cpu.take_error());
+		assert_eq!(None, cpu.take_error());
+		assert_eq!(DRAM_BASE + 0x40, cpu.read_pc());
+		assert_eq!(2, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+		assert_eq!(0xffffffff, cpu.read_csr_raw(CSR_MTVAL_ADDRESS));
+
+		cpu.update_pc(DRAM_BASE + 4);
+		cpu.write_register(11, 1);
+		cpu.tick();
+		cpu.tick();
+		// Reads zero from the address mapped to nothing
+		assert_eq!(Some(ExecError::Memory(MemoryError::Unmapped(0x40000000))), cpu.take_error());
+		assert_eq!(0, cpu.read_register(11));
+		assert_eq!(DRAM_BASE + 12, cpu.read_pc());
+	}
+
 	#[test]
 	fn fetch() {
 		// .fetch() reads four bytes from the memory
@@ -3795,12 +5595,48 @@ mod test_cpu {
 		// @TODO: Should I test all instructions?
 	}
 
+	#[test]
+	fn decode_table() {
+		let linear_search = |word: u32| INSTRUCTIONS.iter().position(|inst| (word & inst.mask) == inst.data);
+		let mut rng = create_rng();
+		let words = INSTRUCTIONS.iter()
+			.map(|inst| inst.data)
+			.chain((0..0x10000).map(|_| rng.gen::<u32>() | 0x3));
+		for word in words {
+			assert_eq!(linear_search(word), Cpu::decode_and_get_instruction_index(word).ok(), "{:08x}", word);
+		}
+	}
+
+	#[test]
+	fn decode_bytes() {
+		assert_eq!(Some("ADDI"), super::decode_bytes(&[0x13, 0, 0, 0], &Xlen::Bit64).map(|inst| inst.mnemonic));
+		// C.NOP
+		assert_eq!(Some(2), super::decode_bytes(&[0x01, 0], &Xlen::Bit64).map(|inst| inst.length));
+		assert_eq!(None, super::decode_bytes(&[0x13, 0], &Xlen::Bit64));
+		assert_eq!(None, super::decode_bytes(&[], &Xlen::Bit64));
+
+		// Never panics on any compressed instruction or sampled word
+		for xlen in [Xlen::Bit32, Xlen::Bit64].iter() {
+			for halfword in 0..=0xffffu16 {
+				super::decode_bytes(&halfword.to_le_bytes(), xlen);
+			}
+			let mut word = 1u32;
+			for _ in 0..0x10000 {
+				word ^= word << 13;
+				word ^= word >> 17;
+				word ^= word << 5;
+				super::decode_bytes(&word.to_le_bytes(), xlen);
+				super::disassemble(word, 0, xlen);
+			}
+		}
+	}
+
 	#[test]
 	fn uncompress() {
 		let mut cpu = create_cpu();
 		// .uncompress() doesn't directly return an instruction but
 		// it returns uncompressed word. Then you need to call .decode().
-		match cpu.decode(cpu.uncompress(0x20)) {
+		match cpu.decode(Cpu::uncompress(0x20, &cpu.xlen)) {
 			Ok(inst) => assert_eq!(inst.name, "ADDI"),
 			Err(_e) => panic!("Failed to decode")
 		};
@@ -3958,6 +5794,207 @@ mod test_cpu {
 		assert_eq!(DRAM_BASE, cpu.read_pc());
 	}
 
+	#[test]
+	fn tick_batch() {
+		let create_looping_cpu = || {
+			let mut cpu = create_cpu();
+			cpu.get_mut_mmu().init_memory(0x1000);
+			let code = [
+				(0, 0x00150513), // addi a0, a0, 1
+				(4, 0xffdff06f), // j -4
+				// Timer interrupt handler
+				(0x100, 0x0200c2b7), // lui t0, 0x200c
+				(0x104, 0xff82b303), // ld t1, -8(t0) reading mtime
+				(0x108, 0x0000006f) // j 0
+			];
+			for (offset, word) in code.iter() {
+				if cpu.get_mut_mmu().store_word(DRAM_BASE + offset, *word).is_err() {
+					panic!("Failed to store");
+				}
+			}
+			cpu.write_csr_raw(CSR_MTVEC_ADDRESS, DRAM_BASE + 0x100);
+			cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, 0x8);
+			cpu.write_csr_raw(CSR_MIE_ADDRESS, MIP_MTIP);
+			cpu.get_mut_mmu().store_doubleword_raw(0x02004000, 1000);
+			cpu.update_pc(DRAM_BASE);
+			cpu
+		};
+		let mut cpu = create_looping_cpu();
+		for _ in 0..3000 {
+			cpu.tick();
+		}
+		let mut batched_cpu = create_looping_cpu();
+		let mut cycles = 0;
+		let mut batches = 0;
+		while cycles < 3000 {
+			cycles += batched_cpu.tick_batch((3000 - cycles).min(256));
+			batches += 1;
+		}
+		assert_eq!(3000, cycles);
+		assert!(batches < 100);
+		// The timer interrupt is taken at the same instruction
+		assert_eq!(cpu.read_csr_raw(CSR_MEPC_ADDRESS), batched_cpu.read_csr_raw(CSR_MEPC_ADDRESS));
+		assert_eq!(cpu.x, batched_cpu.x);
+		assert_eq!(cpu.read_pc(), batched_cpu.read_pc());
+		assert_eq!(cpu.read_csr_raw(CSR_CYCLE_ADDRESS), batched_cpu.read_csr_raw(CSR_CYCLE_ADDRESS));
+		assert_eq!(3000, batched_cpu.get_mmu().get_clint().read_mtime());
+		assert_eq!(1001, batched_cpu.x[6]);
+	}
+
+	#[test]
+	fn landing_pad() {
+		let handler_vector = 0x10000000;
+		let config = EmulatorConfig {
+			landing_pad: true,
+			..EmulatorConfig::default()
+		};
+		let mut cpu = Cpu::new_with_config(Box::new(DummyTerminal::new()), &config);
+		cpu.get_mut_mmu().init_memory(0x1000);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, handler_vector);
+		cpu.update_pc(DRAM_BASE);
+		let code = [
+			0x000123b7, // lui t2, 0x12
+			0x00000517, // auipc a0, 0
+			0x00c50513, // addi a0, a0, 12
+			0x000500e7, // jalr ra, 0(a0)
+			0x00012017, // lpad 0x12
+			0x00c50513, // addi a0, a0, 12
+			0x00050067, // jalr zero, 0(a0)
+			0x00000013 // nop
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		for _ in 0..5 {
+			cpu.tick();
+		}
+		assert_eq!(DRAM_BASE + 20, cpu.read_pc());
+
+		// The jump target isn't a landing pad
+		cpu.tick();
+		cpu.tick();
+		cpu.tick();
+		assert_eq!(handler_vector, cpu.read_pc());
+		assert_eq!(18, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+		assert_eq!(2, cpu.read_csr_raw(CSR_MTVAL_ADDRESS));
+		assert_eq!(DRAM_BASE + 28, cpu.read_csr_raw(CSR_MEPC_ADDRESS));
+
+		let statistics = cpu.get_cfi_statistics().unwrap();
+		assert_eq!(1, statistics.get_indirect_calls());
+		assert_eq!(1, statistics.get_indirect_jumps());
+		assert_eq!(1, statistics.get_violations());
+		assert_eq!(vec![(0x12, 1)], statistics.get_equivalence_classes());
+		assert_eq!(vec![DRAM_BASE + 16], statistics.get_landing_pads(0x12));
+
+		// Not checked by default
+		assert!(create_cpu().get_cfi_statistics().is_none());
+	}
+
+	#[test]
+	#[cfg(all(feature = "fd", not(feature = "native-float")))]
+	fn floating_point() {
+		let handler_vector = 0x10000000;
+		let mut cpu = create_cpu();
+		cpu.get_mut_mmu().init_memory(0x1000);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, handler_vector);
+		cpu.update_pc(DRAM_BASE);
+		let code = [
+			0x1a20f053, // fdiv.d f0, f1, f2 (dynamic rounding mode)
+			0x1a2081d3, // fdiv.d f3, f1, f2, rne
+			0x420202d3, // fcvt.d.s f5, f4
+			0x1a20d053 // fdiv.d f0, f1, f2 with reserved rounding mode 5
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		cpu.f[1] = 1.0;
+		cpu.f[2] = 3.0;
+		// Rounds up
+		cpu.write_csr_raw(CSR_FRM_ADDRESS, 3);
+		// FS is Clean, and set to Dirty with SD by the instruction
+		cpu.write_csr_raw(CSR_MSTATUS_ADDRESS, 0x4000);
+		cpu.tick();
+		assert_eq!(MSTATUS_FS_DIRTY | MSTATUS_XL_64 | 0x8000000000000000, cpu.read_csr_raw(CSR_MSTATUS_ADDRESS));
+		assert_eq!(0x8000000200006000, cpu.read_csr_raw(CSR_SSTATUS_ADDRESS));
+		cpu.tick();
+		assert_eq!(cpu.f[3].to_bits() + 1, cpu.f[0].to_bits());
+		assert_eq!(1.0 / 3.0, cpu.f[3]);
+		assert_eq!(0x1, cpu.read_csr_raw(CSR_FFLAGS_ADDRESS));
+
+		// A single precision value not NaN-boxed is the canonical NaN,
+		// converted without the invalid flag
+		cpu.f[4] = f64::from_bits(0x3f800000);
+		cpu.tick();
+		assert_eq!(DOUBLE.get_canonical_nan(), cpu.f[5].to_bits());
+		assert_eq!(0x1, cpu.read_csr_raw(CSR_FFLAGS_ADDRESS));
+
+		cpu.tick();
+		assert_eq!(handler_vector, cpu.read_pc());
+		assert_eq!(2, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+	}
+
+	#[test]
+	fn shadow_stack() {
+		let handler_vector = 0x10000000;
+		let base = DRAM_BASE + 0x1000;
+		let config = EmulatorConfig {
+			shadow_stack: Some(::config::ShadowStackConfig {
+				base,
+				size: 0x1000
+			}),
+			..EmulatorConfig::default()
+		};
+		let mut cpu = Cpu::new_with_config(Box::new(DummyTerminal::new()), &config);
+		cpu.get_mut_mmu().init_memory(0x2000);
+		cpu.write_csr_raw(CSR_MTVEC_ADDRESS, handler_vector);
+		cpu.x[1] = DRAM_BASE as i64 + 0x100;
+		cpu.update_pc(DRAM_BASE);
+		let code = [
+			0xce104073, // sspush ra
+			0xcdc04573, // ssrdp a0
+			0xcdc0c073, // sspopchk ra
+			0xce104073, // sspush ra
+			0x00408093, // addi ra, ra, 4
+			0xcdc0c073 // sspopchk ra
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		assert_eq!(base + 0x1000, cpu.read_csr_raw(CSR_SSP_ADDRESS));
+		cpu.tick();
+		cpu.tick();
+		assert_eq!(base + 0xff8, cpu.x[10] as u64);
+		cpu.tick();
+		assert_eq!(base + 0x1000, cpu.read_csr_raw(CSR_SSP_ADDRESS));
+
+		// The return address differs from the one on the shadow stack
+		cpu.tick();
+		cpu.tick();
+		cpu.tick();
+		assert_eq!(handler_vector, cpu.read_pc());
+		assert_eq!(18, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+		assert_eq!(3, cpu.read_csr_raw(CSR_MTVAL_ADDRESS));
+		assert_eq!(base + 0xff8, cpu.read_csr_raw(CSR_SSP_ADDRESS));
+
+		// Only the shadow stack accesses reach the region
+		match cpu.get_mut_mmu().load_doubleword(base + 0xff8) {
+			Err(trap) => assert!(matches!(trap.trap_type, TrapType::LoadAccessFault)),
+			Ok(_) => panic!("Loaded from the shadow stack")
+		};
+		assert!(cpu.get_mut_mmu().store_word(base, 0).is_err());
+		assert_eq!(Ok(DRAM_BASE + 0x100), cpu.get_mut_mmu().load_shadow_stack(base + 0xff8).map_err(|_| ()));
+		assert!(cpu.get_mut_mmu().store_shadow_stack(DRAM_BASE, 0).is_err());
+
+		assert_eq!(0xce104073, expand_instruction(0x6081, &Xlen::Bit64).0);
+		assert_eq!(0xcdc2c073, expand_instruction(0x6281, &Xlen::Bit64).0);
+	}
+
 	#[test]
 	fn zipper_stack() {
 		let handler_vector = 0x10000000;
@@ -3985,18 +6022,18 @@ mod test_cpu {
 		};
 		println!("\n------------- before zip ------------");
 		println!("cpu.x : {:x?}",cpu.x);
-		println!("cpu.top : {:x}, cpu.key : {:x}",cpu.top,cpu.key);
+		println!("cpu.top : {:x}, cpu.key : {:x}",cpu.top,cpu.zipper_mac.get_key());
 		
 
 		cpu.tick();
 		println!("\n-------after zip & before unzip ------");
 		println!("cpu.x : {:x?}",cpu.x);
-		println!("cpu.top : {:x}, cpu.key : {:x}",cpu.top,cpu.key);
+		println!("cpu.top : {:x}, cpu.key : {:x}",cpu.top,cpu.zipper_mac.get_key());
 		cpu.tick();
 		cpu.tick();
 		println!("\n------------- after unzip ------------");
 		println!("cpu.x : {:x?}",cpu.x);
-		println!("cpu.pc : {:x}, cpu.top : {:x}, cpu.key : {:x}",cpu.pc,cpu.top,cpu.key);
+		println!("cpu.pc : {:x}, cpu.top : {:x}, cpu.key : {:x}",cpu.pc,cpu.top,cpu.zipper_mac.get_key());
 		
 		let cause = cpu.read_csr_raw(CSR_MCAUSE_ADDRESS);
 		let _trap_value = cpu.read_csr_raw(CSR_MTVAL_ADDRESS);
@@ -4004,6 +6041,43 @@ mod test_cpu {
 		//assert_eq!(cause,2);
 		//assert_eq!(trap_value,DRAM_BASE+8)
 	}
+
+	#[test]
+	fn zipper_stack_keys() {
+		let mut cpu = create_cpu();
+		cpu.get_mut_mmu().init_memory(4);
+		let machine_slot = get_key_slot(3, 0);
+		assert!(cpu.write_csr_as(CSR_MZKEYSEL_ADDRESS, machine_slot, &PrivilegeMode::Machine).is_ok());
+		assert!(cpu.write_csr_as(CSR_MZKEY_ADDRESS, 0x1111, &PrivilegeMode::Machine).is_ok());
+		assert_eq!(0x1111, cpu.get_zipper_mac().get_slot_key(machine_slot));
+		assert_eq!(Ok(0), cpu.read_csr_as(CSR_MZKEY_ADDRESS, &PrivilegeMode::Machine));
+		assert_eq!(0, cpu.get_zipper_key_slot());
+
+		cpu.write_csr_raw(CSR_MZKEYCTL_ADDRESS, KEY_CONTROL_PER_PRIVILEGE);
+		assert_eq!(machine_slot, cpu.get_zipper_key_slot());
+		cpu.write_csr_raw(CSR_MZKEYCTL_ADDRESS, KEY_CONTROL_PER_PRIVILEGE | KEY_CONTROL_ROTATE);
+		assert_ne!(0x1111, cpu.get_zipper_mac().get_slot_key(machine_slot));
+		assert_eq!(KEY_CONTROL_PER_PRIVILEGE, cpu.read_csr_raw(CSR_MZKEYCTL_ADDRESS));
+
+		// Locked until reset
+		let key = cpu.get_zipper_mac().get_slot_key(machine_slot);
+		cpu.write_csr_raw(CSR_MZKEYCTL_ADDRESS, KEY_CONTROL_PER_PRIVILEGE | KEY_CONTROL_LOCK);
+		cpu.write_csr_raw(CSR_MZKEY_ADDRESS, 0x2222);
+		cpu.write_csr_raw(CSR_MZKEYCTL_ADDRESS, KEY_CONTROL_ROTATE);
+		assert_eq!(key, cpu.get_zipper_mac().get_slot_key(machine_slot));
+		assert_eq!(KEY_CONTROL_PER_PRIVILEGE | KEY_CONTROL_LOCK, cpu.read_csr_raw(CSR_MZKEYCTL_ADDRESS));
+
+		// Machine mode only
+		cpu.privilege_mode = PrivilegeMode::Supervisor;
+		cpu.update_pc(DRAM_BASE);
+		// csrrs a0, mzkeyctl, zero
+		if cpu.get_mut_mmu().store_word(DRAM_BASE, 0x7c002573).is_err() {
+			panic!("Failed to store");
+		}
+		cpu.tick();
+		assert_eq!(2, cpu.read_csr_raw(CSR_MCAUSE_ADDRESS));
+		assert_eq!(0, cpu.x[10]);
+	}
 }
 
 #[cfg(test)]
@@ -1,40 +1,100 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
 use terminal::Terminal;
 
 /// Standard `Terminal`.
 pub struct DefaultTerminal {
-	input_data: Vec<u8>,
-	output_data: Vec<u8>
+	input_data: VecDeque<u8>,
+	output_data: VecDeque<u8>
 }
 
 impl DefaultTerminal {
 	pub fn new() -> Self {
 		DefaultTerminal {
-			input_data: vec![],
-			output_data: vec![]
+			input_data: VecDeque::new(),
+			output_data: VecDeque::new()
 		}
 	}
 }
 
+fn drain(data: &mut VecDeque<u8>, buffer: &mut [u8]) -> usize {
+	let size = buffer.len().min(data.len());
+	for (i, value) in data.drain(..size).enumerate() {
+		buffer[i] = value;
+	}
+	size
+}
+
 impl Terminal for DefaultTerminal {
 	fn put_byte(&mut self, value: u8) {
-		self.output_data.push(value);
+		self.output_data.push_back(value);
 	}
 	
 	fn get_input(&mut self) -> u8 {
-		match self.input_data.len() > 0 {
-			true => self.input_data.remove(0),
-			false => 0
-		}
+		self.input_data.pop_front().unwrap_or(0)
 	}
 	
 	fn put_input(&mut self, value: u8) {
-		self.input_data.push(value);
+		self.input_data.push_back(value);
 	}
 	
 	fn get_output(&mut self) -> u8 {
-		match self.output_data.len() > 0 {
-			true => self.output_data.remove(0),
-			false => 0
-		}
+		self.output_data.pop_front().unwrap_or(0)
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		self.output_data.extend(values);
+	}
+
+	fn get_output_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		drain(&mut self.output_data, buffer)
+	}
+
+	fn read_available(&mut self, data: &mut Vec<u8>) -> usize {
+		let size = self.output_data.len();
+		data.extend(self.output_data.drain(..));
+		size
 	}
-}
\ No newline at end of file
+
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		self.input_data.extend(data);
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		drain(&mut self.input_data, buffer)
+	}
+
+	fn has_input(&mut self) -> bool {
+		!self.input_data.is_empty()
+	}
+}
+
+#[cfg(test)]
+mod test_default_terminal {
+	use super::*;
+
+	#[test]
+	fn bulk_transfer() {
+		let mut terminal = DefaultTerminal::new();
+		assert!(!terminal.has_input());
+		terminal.put_input_bytes(b"abc");
+		assert!(terminal.has_input());
+		let mut buffer = [0; 2];
+		assert_eq!(2, terminal.get_input_bytes(&mut buffer));
+		assert_eq!(b"ab", &buffer);
+		assert_eq!(b'c', terminal.get_input());
+		assert!(!terminal.has_input());
+
+		terminal.put_bytes(b"xyz");
+		let mut buffer = [0; 8];
+		assert_eq!(3, terminal.get_output_bytes(&mut buffer));
+		assert_eq!(b"xyz", &buffer[..3]);
+		assert_eq!(0, terminal.get_output_bytes(&mut buffer));
+		terminal.put_bytes(b"uvw");
+		terminal.put_byte(b'\n');
+		let mut output = b"t".to_vec();
+		assert_eq!(4, terminal.read_available(&mut output));
+		assert_eq!(b"tuvw\n".to_vec(), output);
+	}
+}
@@ -0,0 +1,558 @@
+use alloc::vec::Vec;
+
+use cpu::PrivilegeMode;
+use device::imsic::{IMSIC_MACHINE_BASE, IMSIC_SUPERVISOR_BASE};
+use device::msi::Msi;
+
+// Based on The RISC-V Advanced Interrupt Architecture, Chapter 4
+// https://github.com/riscv/riscv-aia
+
+/// Base address of machine-level `Aplic` interrupt domain, the root domain
+pub const APLIC_MACHINE_BASE: u64 = 0x0c000000;
+
+/// Base address of supervisor-level `Aplic` interrupt domain, the child
+/// of the root domain
+pub const APLIC_SUPERVISOR_BASE: u64 = 0x0d000000;
+
+/// Size of an interrupt domain memory region
+pub const APLIC_DOMAIN_SIZE: u64 = 0x8000;
+
+/// The number of interrupt sources. Source 0 is not used so
+/// the valid sources are 1-63.
+pub const APLIC_SOURCE_NUM: u32 = 64;
+
+const DOMAINCFG: u64 = 0x0000;
+const SOURCECFG_BASE: u64 = 0x0004;
+const SOURCECFG_END: u64 = 0x0ffc;
+// mmsiaddrcfg, mmsiaddrcfgh, smsiaddrcfg, and smsiaddrcfgh
+const MSIADDRCFG_BASE: u64 = 0x1bc0;
+const MSIADDRCFG_END: u64 = 0x1bcc;
+const SETIP_BASE: u64 = 0x1c00;
+const SETIP_END: u64 = 0x1c7c;
+const SETIPNUM: u64 = 0x1cdc;
+const IN_CLRIP_BASE: u64 = 0x1d00;
+const IN_CLRIP_END: u64 = 0x1d7c;
+const CLRIPNUM: u64 = 0x1ddc;
+const SETIE_BASE: u64 = 0x1e00;
+const SETIE_END: u64 = 0x1e7c;
+const SETIENUM: u64 = 0x1edc;
+const CLRIE_BASE: u64 = 0x1f00;
+const CLRIE_END: u64 = 0x1f7c;
+const CLRIENUM: u64 = 0x1fdc;
+const SETIPNUM_LE: u64 = 0x2000;
+const SETIPNUM_BE: u64 = 0x2004;
+const GENMSI: u64 = 0x3000;
+const TARGET_BASE: u64 = 0x3004;
+const TARGET_END: u64 = 0x3ffc;
+
+const DOMAINCFG_IE: u32 = 0x100;
+// Only MSI delivery mode is supported so DM bit is hardwired to one
+const DOMAINCFG_DM: u32 = 0x4;
+const SOURCECFG_D: u32 = 0x400;
+const GENMSI_BUSY: u32 = 0x1000;
+const MSIADDRCFGH_L: u32 = 0x80000000;
+
+// Source modes
+const SOURCE_MODE_INACTIVE: u32 = 0;
+const SOURCE_MODE_DETACHED: u32 = 1;
+const SOURCE_MODE_EDGE1: u32 = 4;
+const SOURCE_MODE_EDGE0: u32 = 5;
+const SOURCE_MODE_LEVEL1: u32 = 6;
+const SOURCE_MODE_LEVEL0: u32 = 7;
+
+/// Interrupt domain which is a set of registers the hart at a privilege
+/// level controls the interrupt sources via.
+struct InterruptDomain {
+	domaincfg: u32,
+	sourcecfg: [u32; APLIC_SOURCE_NUM as usize],
+	target: [u32; APLIC_SOURCE_NUM as usize],
+	ip: u64,
+	ie: u64,
+	genmsi: u32,
+	/// Register content being written byte by byte
+	write_buffer: u32
+}
+
+impl InterruptDomain {
+	fn new() -> Self {
+		InterruptDomain {
+			domaincfg: 0,
+			sourcecfg: [0; APLIC_SOURCE_NUM as usize],
+			target: [0; APLIC_SOURCE_NUM as usize],
+			ip: 0,
+			ie: 0,
+			genmsi: 0,
+			write_buffer: 0
+		}
+	}
+
+	fn get_source_mode(&self, source: u32) -> u32 {
+		match (self.sourcecfg[source as usize] & SOURCECFG_D) != 0 {
+			true => SOURCE_MODE_INACTIVE,
+			false => self.sourcecfg[source as usize] & 0x7
+		}
+	}
+
+	fn is_active(&self, source: u32) -> bool {
+		self.get_source_mode(source) != SOURCE_MODE_INACTIVE
+	}
+
+	/// Returns the input value inverted for the active low sources.
+	fn get_rectified_input(&self, source: u32, levels: u64) -> bool {
+		let level = ((levels >> source) & 1) == 1;
+		match self.get_source_mode(source) {
+			SOURCE_MODE_EDGE1 | SOURCE_MODE_LEVEL1 => level,
+			SOURCE_MODE_EDGE0 | SOURCE_MODE_LEVEL0 => !level,
+			_ => false
+		}
+	}
+
+	/// Software can't make level-sensitive source pending while
+	/// its rectified input is low.
+	fn set_pending(&mut self, source: u32, levels: u64) {
+		if source == 0 || source >= APLIC_SOURCE_NUM || !self.is_active(source) {
+			return;
+		}
+		let pendable = match self.get_source_mode(source) {
+			SOURCE_MODE_LEVEL1 | SOURCE_MODE_LEVEL0 => self.get_rectified_input(source, levels),
+			_ => true
+		};
+		if pendable {
+			self.ip |= 1 << source;
+		}
+	}
+
+	fn get_active_mask(&self) -> u64 {
+		let mut mask = 0;
+		for source in 1..APLIC_SOURCE_NUM {
+			if self.is_active(source) {
+				mask |= 1 << source;
+			}
+		}
+		mask
+	}
+
+	fn get_rectified_inputs(&self, levels: u64) -> u64 {
+		let mut inputs = 0;
+		for source in 1..APLIC_SOURCE_NUM {
+			if self.get_rectified_input(source, levels) {
+				inputs |= 1 << source;
+			}
+		}
+		inputs
+	}
+}
+
+/// Reads a 32-bit unit of 64 bits source bit array.
+fn read_array(bits: u64, offset: u64) -> u32 {
+	match offset / 4 {
+		0 => bits as u32,
+		1 => (bits >> 32) as u32,
+		_ => 0
+	}
+}
+
+fn get_array_bits(value: u32, offset: u64) -> u64 {
+	match offset / 4 {
+		0 => value as u64 & !1,
+		1 => (value as u64) << 32,
+		_ => 0
+	}
+}
+
+// Computes the address of the interrupt file an MSI is sent to
+// from msiaddrcfg registers. Refer to the AIA specification 4.9.3.
+fn get_msi_address(msiaddrcfg: &[u32; 4], mode: &PrivilegeMode, target: u32) -> u64 {
+	let hart_index = (target >> 18) as u64;
+	let guest_index = ((target >> 12) & 0x3f) as u64;
+	// Hart index fields layout is common to both levels
+	let hhxs = (msiaddrcfg[1] >> 24) & 0x1f;
+	let hhxw = (msiaddrcfg[1] >> 16) & 0x7;
+	let lhxw = (msiaddrcfg[1] >> 12) & 0xf;
+	let (low, high, guest_index) = match mode {
+		PrivilegeMode::Machine => (msiaddrcfg[0], msiaddrcfg[1], 0),
+		_ => (msiaddrcfg[2], msiaddrcfg[3], guest_index)
+	};
+	let lhxs = (high >> 20) & 0x7;
+	let group = (hart_index >> lhxw) & ((1 << hhxw) - 1);
+	let hart = hart_index & ((1 << lhxw) - 1);
+	let ppn = (((high & 0xfff) as u64) << 32) | low as u64;
+	(ppn | (group << (hhxs + 12)) | (hart << lhxs) | guest_index) << 12
+}
+
+/// Emulates Advanced Platform-Level Interrupt Controller (APLIC) of AIA.
+/// It takes wired interrupts from devices and forwards them to `Imsic`
+/// as MSIs written to its interrupt files. It has the machine-level root interrupt domain and
+/// the supervisor-level child domain. The root domain can delegate
+/// sources to the child domain. Only MSI delivery mode is supported.
+pub struct Aplic {
+	machine: InterruptDomain,
+	supervisor: InterruptDomain,
+	/// mmsiaddrcfg, mmsiaddrcfgh, smsiaddrcfg, and smsiaddrcfgh
+	msiaddrcfg: [u32; 4],
+	/// Input levels of the sources
+	levels: u64,
+	levels_cache: u64,
+	needs_update: bool
+}
+
+impl Aplic {
+	/// Creates a new `Aplic`.
+	pub fn new() -> Self {
+		Aplic {
+			machine: InterruptDomain::new(),
+			supervisor: InterruptDomain::new(),
+			// Preset to the interrupt files of the machine so that MSIs
+			// reach them even if the firmware doesn't set the registers
+			msiaddrcfg: [
+				(IMSIC_MACHINE_BASE >> 12) as u32,
+				0,
+				(IMSIC_SUPERVISOR_BASE >> 12) as u32,
+				0
+			],
+			levels: 0,
+			levels_cache: 0,
+			needs_update: false
+		}
+	}
+
+	/// Takes an interrupt signal from a device. Expected to be called
+	/// before `tick()`.
+	///
+	/// # Arguments
+	/// * `source` Must be less than 64
+	/// * `level`
+	pub fn update_level(&mut self, source: u32, level: bool) {
+		debug_assert!(source > 0 && source < APLIC_SOURCE_NUM, "source must be 1-63. {}", source);
+		match level {
+			true => self.levels |= 1 << source,
+			false => self.levels &= !(1 << source)
+		};
+	}
+
+	/// Runs one cycle. Updates pending bits with the input levels and
+	/// forwards pending and enabled interrupts as MSIs. The caller is
+	/// responsible for writing the MSIs to the bus.
+	///
+	/// # Arguments
+	/// * `msis` MSIs to send are pushed
+	pub fn tick(&mut self, msis: &mut Vec<Msi>) {
+		if !self.needs_update && self.levels == self.levels_cache {
+			return;
+		}
+		self.needs_update = false;
+		let levels = self.levels;
+		let levels_cache = self.levels_cache;
+		self.levels_cache = levels;
+		for (domain, mode) in [
+			(&mut self.machine, PrivilegeMode::Machine),
+			(&mut self.supervisor, PrivilegeMode::Supervisor)
+		] {
+			for source in 1..APLIC_SOURCE_NUM {
+				let input = domain.get_rectified_input(source, levels);
+				let rise = input && !domain.get_rectified_input(source, levels_cache);
+				match domain.get_source_mode(source) {
+					SOURCE_MODE_EDGE1 | SOURCE_MODE_EDGE0 if rise => domain.ip |= 1 << source,
+					SOURCE_MODE_LEVEL1 | SOURCE_MODE_LEVEL0 if rise => domain.ip |= 1 << source,
+					SOURCE_MODE_LEVEL1 | SOURCE_MODE_LEVEL0 if !input => domain.ip &= !(1 << source),
+					_ => {}
+				};
+			}
+
+			if (domain.genmsi & GENMSI_BUSY) != 0 {
+				domain.genmsi &= !GENMSI_BUSY;
+				msis.push(Msi::new(get_msi_address(&self.msiaddrcfg, &mode, domain.genmsi), domain.genmsi & 0x7ff));
+			}
+
+			if (domain.domaincfg & DOMAINCFG_IE) == 0 {
+				continue;
+			}
+			let interrupts = domain.ip & domain.ie;
+			for source in 1..APLIC_SOURCE_NUM {
+				if ((interrupts >> source) & 1) == 1 {
+					domain.ip &= !(1 << source);
+					let target = domain.target[source as usize];
+					msis.push(Msi::new(get_msi_address(&self.msiaddrcfg, &mode, target), target & 0x7ff));
+				}
+			}
+		}
+	}
+
+	/// Indicates whether the address is in the interrupt domains.
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn contains(&self, address: u64) -> bool {
+		(APLIC_MACHINE_BASE..APLIC_MACHINE_BASE + APLIC_DOMAIN_SIZE).contains(&address) ||
+			(APLIC_SUPERVISOR_BASE..APLIC_SUPERVISOR_BASE + APLIC_DOMAIN_SIZE).contains(&address)
+	}
+
+	fn get_domain(&self, mode: &PrivilegeMode) -> &InterruptDomain {
+		match mode {
+			PrivilegeMode::Machine => &self.machine,
+			_ => &self.supervisor
+		}
+	}
+
+	fn get_mut_domain(&mut self, mode: &PrivilegeMode) -> &mut InterruptDomain {
+		match mode {
+			PrivilegeMode::Machine => &mut self.machine,
+			_ => &mut self.supervisor
+		}
+	}
+
+	fn read_register(&self, mode: &PrivilegeMode, offset: u64) -> u32 {
+		let domain = self.get_domain(mode);
+		let is_machine = matches!(mode, PrivilegeMode::Machine);
+		match offset {
+			DOMAINCFG => 0x80000000 | domain.domaincfg | DOMAINCFG_DM,
+			SOURCECFG_BASE..=SOURCECFG_END => {
+				let source = ((offset - SOURCECFG_BASE) / 4 + 1) as u32;
+				match source < APLIC_SOURCE_NUM && self.is_delegated_to(mode, source) {
+					true => domain.sourcecfg[source as usize],
+					false => 0
+				}
+			},
+			MSIADDRCFG_BASE..=MSIADDRCFG_END if is_machine => self.msiaddrcfg[((offset - MSIADDRCFG_BASE) / 4) as usize],
+			SETIP_BASE..=SETIP_END => read_array(domain.ip, offset - SETIP_BASE),
+			IN_CLRIP_BASE..=IN_CLRIP_END => read_array(domain.get_rectified_inputs(self.levels), offset - IN_CLRIP_BASE),
+			SETIE_BASE..=SETIE_END => read_array(domain.ie, offset - SETIE_BASE),
+			GENMSI => domain.genmsi,
+			TARGET_BASE..=TARGET_END => {
+				let source = (offset - TARGET_BASE) / 4 + 1;
+				match source < APLIC_SOURCE_NUM as u64 && domain.is_active(source as u32) {
+					true => domain.target[source as usize],
+					false => 0
+				}
+			},
+			_ => 0
+		}
+	}
+
+	// Whether the domain owns the source. The child domain owns
+	// the sources delegated by the root domain.
+	fn is_delegated_to(&self, mode: &PrivilegeMode, source: u32) -> bool {
+		match mode {
+			PrivilegeMode::Machine => true,
+			_ => (self.machine.sourcecfg[source as usize] & SOURCECFG_D) != 0
+		}
+	}
+
+	fn write_sourcecfg(&mut self, mode: &PrivilegeMode, source: u32, value: u32) {
+		if source >= APLIC_SOURCE_NUM || !self.is_delegated_to(mode, source) {
+			return;
+		}
+		let is_machine = matches!(mode, PrivilegeMode::Machine);
+		let value = match (value & SOURCECFG_D) != 0 {
+			// Only the root domain has a child, whose index is zero
+			true => match is_machine {
+				true => SOURCECFG_D,
+				false => SOURCE_MODE_INACTIVE
+			},
+			false => match value & 0x7 {
+				SOURCE_MODE_DETACHED | SOURCE_MODE_EDGE1 | SOURCE_MODE_EDGE0 |
+					SOURCE_MODE_LEVEL1 | SOURCE_MODE_LEVEL0 => value & 0x7,
+				_ => SOURCE_MODE_INACTIVE
+			}
+		};
+		let domain = self.get_mut_domain(mode);
+		domain.sourcecfg[source as usize] = value;
+		if !domain.is_active(source) {
+			domain.ip &= !(1 << source);
+			domain.ie &= !(1 << source);
+			domain.target[source as usize] = 0;
+		}
+		if is_machine && (value & SOURCECFG_D) == 0 {
+			// Undelegated source becomes inactive in the child domain
+			self.write_sourcecfg(&PrivilegeMode::Supervisor, source, SOURCE_MODE_INACTIVE);
+		}
+	}
+
+	fn write_register(&mut self, mode: &PrivilegeMode, offset: u64, value: u32) {
+		let levels = self.levels;
+		let is_machine = matches!(mode, PrivilegeMode::Machine);
+		match offset {
+			DOMAINCFG => self.get_mut_domain(mode).domaincfg = value & DOMAINCFG_IE,
+			SOURCECFG_BASE..=SOURCECFG_END => {
+				let source = ((offset - SOURCECFG_BASE) / 4 + 1) as u32;
+				self.write_sourcecfg(mode, source, value);
+			},
+			// The registers are read-only once locked
+			MSIADDRCFG_BASE..=MSIADDRCFG_END if is_machine && (self.msiaddrcfg[1] & MSIADDRCFGH_L) == 0 => {
+				self.msiaddrcfg[((offset - MSIADDRCFG_BASE) / 4) as usize] = value;
+			},
+			_ => {
+				let domain = self.get_mut_domain(mode);
+				let active_mask = domain.get_active_mask();
+				match offset {
+					SETIP_BASE..=SETIP_END => {
+						let bits = get_array_bits(value, offset - SETIP_BASE);
+						for source in 1..APLIC_SOURCE_NUM {
+							if ((bits >> source) & 1) == 1 {
+								domain.set_pending(source, levels);
+							}
+						}
+					},
+					SETIPNUM | SETIPNUM_LE => domain.set_pending(value, levels),
+					SETIPNUM_BE => domain.set_pending(value.swap_bytes(), levels),
+					IN_CLRIP_BASE..=IN_CLRIP_END => domain.ip &= !get_array_bits(value, offset - IN_CLRIP_BASE),
+					CLRIPNUM if value < APLIC_SOURCE_NUM => domain.ip &= !(1 << value),
+					SETIE_BASE..=SETIE_END => domain.ie |= get_array_bits(value, offset - SETIE_BASE) & active_mask,
+					SETIENUM if value < APLIC_SOURCE_NUM => domain.ie |= (1 << value) & active_mask,
+					CLRIE_BASE..=CLRIE_END => domain.ie &= !get_array_bits(value, offset - CLRIE_BASE),
+					CLRIENUM if value < APLIC_SOURCE_NUM => domain.ie &= !(1 << value),
+					GENMSI => domain.genmsi = (value & 0xfffc07ff) | GENMSI_BUSY,
+					TARGET_BASE..=TARGET_END => {
+						let source = (offset - TARGET_BASE) / 4 + 1;
+						if source < APLIC_SOURCE_NUM as u64 && domain.is_active(source as u32) {
+							domain.target[source as usize] = value & 0xfffff7ff;
+						}
+					},
+					_ => {}
+				};
+			}
+		};
+		self.needs_update = true;
+	}
+
+	fn get_mode_and_offset(address: u64) -> (PrivilegeMode, u64) {
+		match address >= APLIC_SUPERVISOR_BASE {
+			true => (PrivilegeMode::Supervisor, address - APLIC_SUPERVISOR_BASE),
+			false => (PrivilegeMode::Machine, address - APLIC_MACHINE_BASE)
+		}
+	}
+
+	/// Loads register content
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn load(&self, address: u64) -> u8 {
+		let (mode, offset) = Self::get_mode_and_offset(address);
+		let pos = (offset % 4) * 8;
+		(self.read_register(&mode, offset & !0x3) >> pos) as u8
+	}
+
+	/// Stores register content. Registers are expected to be written
+	/// in 32-bit words and the write takes effect when the highest byte
+	/// of the register is written.
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		let (mode, offset) = Self::get_mode_and_offset(address);
+		let pos = (offset % 4) * 8;
+		let domain = self.get_mut_domain(&mode);
+		domain.write_buffer = (domain.write_buffer & !(0xff << pos)) | ((value as u32) << pos);
+		if (offset % 4) == 3 {
+			let data = domain.write_buffer;
+			self.write_register(&mode, offset & !0x3, data);
+		}
+	}
+}
+
+impl Default for Aplic {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod test_aplic {
+	use super::*;
+	use cpu::{Xlen, MIP_SEIP};
+	use device::imsic::Imsic;
+
+	fn store_word(aplic: &mut Aplic, address: u64, value: u32) {
+		for i in 0..4 {
+			aplic.store(address + i, (value >> (i * 8)) as u8);
+		}
+	}
+
+	fn load_word(aplic: &Aplic, address: u64) -> u32 {
+		let mut value = 0;
+		for i in 0..4 {
+			value |= (aplic.load(address + i) as u32) << (i * 8);
+		}
+		value
+	}
+
+	// Writes the MSIs to the interrupt files as the bus does
+	fn tick(aplic: &mut Aplic, imsic: &mut Imsic) {
+		let mut msis = vec![];
+		aplic.tick(&mut msis);
+		for msi in msis {
+			assert!(imsic.contains(msi.address));
+			for i in 0..4 {
+				imsic.store(msi.address + i, (msi.data >> (i * 8)) as u8);
+			}
+		}
+	}
+
+	#[test]
+	fn forward_msi() {
+		let mut aplic = Aplic::new();
+		let mut imsic = Imsic::new();
+		let mut mip = 0;
+		let supervisor = PrivilegeMode::Supervisor;
+		imsic.write_register(&supervisor, 0x70, 1, &Xlen::Bit64); // eidelivery
+		imsic.write_register(&supervisor, 0xc0, 1 << 5, &Xlen::Bit64); // eie0
+
+		// Delegate source 1 to the supervisor-level domain
+		store_word(&mut aplic, APLIC_MACHINE_BASE + SOURCECFG_BASE, SOURCECFG_D);
+		assert_eq!(0, load_word(&aplic, APLIC_MACHINE_BASE + SETIE_BASE));
+		store_word(&mut aplic, APLIC_SUPERVISOR_BASE + SOURCECFG_BASE, SOURCE_MODE_LEVEL1);
+		assert_eq!(SOURCE_MODE_LEVEL1, load_word(&aplic, APLIC_SUPERVISOR_BASE + SOURCECFG_BASE));
+		store_word(&mut aplic, APLIC_SUPERVISOR_BASE + TARGET_BASE, 5);
+		store_word(&mut aplic, APLIC_SUPERVISOR_BASE + SETIENUM, 1);
+		store_word(&mut aplic, APLIC_SUPERVISOR_BASE + DOMAINCFG, DOMAINCFG_IE);
+		assert_eq!(0x80000104, load_word(&aplic, APLIC_SUPERVISOR_BASE + DOMAINCFG));
+
+		aplic.update_level(1, true);
+		tick(&mut aplic, &mut imsic);
+		imsic.tick(&mut mip);
+		assert_eq!(MIP_SEIP, mip);
+		assert_eq!((5 << 16) | 5, imsic.get_topei(&supervisor));
+		assert_eq!(2, load_word(&aplic, APLIC_SUPERVISOR_BASE + IN_CLRIP_BASE));
+		// Pending bit is cleared when the MSI is forwarded
+		assert_eq!(0, load_word(&aplic, APLIC_SUPERVISOR_BASE + SETIP_BASE));
+
+		imsic.claim_topei(&supervisor);
+		imsic.tick(&mut mip);
+		assert_eq!(0, mip);
+
+		// Level-sensitive source can be made pending again while the input is high
+		store_word(&mut aplic, APLIC_SUPERVISOR_BASE + SETIPNUM_LE, 1);
+		tick(&mut aplic, &mut imsic);
+		imsic.tick(&mut mip);
+		assert_eq!(MIP_SEIP, mip);
+		imsic.claim_topei(&supervisor);
+		aplic.update_level(1, false);
+		tick(&mut aplic, &mut imsic);
+		store_word(&mut aplic, APLIC_SUPERVISOR_BASE + SETIPNUM_LE, 1);
+		tick(&mut aplic, &mut imsic);
+		imsic.tick(&mut mip);
+		assert_eq!(0, mip);
+	}
+
+	#[test]
+	fn msi_address() {
+		let mut aplic = Aplic::new();
+		let mut msis = vec![];
+		for i in 0..4 {
+			aplic.store(APLIC_SUPERVISOR_BASE + GENMSI + i, (7 >> (i * 8)) as u8);
+		}
+		aplic.tick(&mut msis);
+		assert_eq!(1, msis.len());
+		assert_eq!(IMSIC_SUPERVISOR_BASE, msis[0].address);
+		assert_eq!(7, msis[0].data);
+
+		// Two harts per group (LHXW=1) and groups are 16MiB apart (HHXS=0)
+		let cfgh = (1 << 16) | (1 << 12);
+		let cfg = [(IMSIC_MACHINE_BASE >> 12) as u32, cfgh, 0, 0];
+		assert_eq!(IMSIC_MACHINE_BASE + 0x1000, get_msi_address(&cfg, &PrivilegeMode::Machine, 1 << 18));
+		assert_eq!(IMSIC_MACHINE_BASE + 0x1000000, get_msi_address(&cfg, &PrivilegeMode::Machine, 2 << 18));
+		// Guest index is ignored at machine level
+		assert_eq!(IMSIC_MACHINE_BASE, get_msi_address(&cfg, &PrivilegeMode::Machine, 1 << 12));
+	}
+}
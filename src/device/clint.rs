@@ -1,22 +1,69 @@
+use alloc::rc::Rc;
+
+use clock::{Clock, create_default_clock};
+use config::{TimerConfig, TimerSource};
 use cpu::{MIP_MSIP, MIP_MTIP};
 
+// Cycles between reads of the host clock following it. The guest time
+// and the timer interrupts are that coarse
+const HOST_CLOCK_POLL_CYCLES: u64 = 1024;
+
 /// Emulates CLINT known as Timer. Refer to the [specification](https://sifive.cdn.prismic.io/sifive%2Fc89f6e5a-cf9e-44c3-a3db-04420702dcc1_sifive+e31+manual+v19.08.pdf)
 /// for the detail.
 pub struct Clint {
 	clock: u64,
 	msip: u32,
 	mtimecmp: u64,
-	mtime: u64
+	mtime: u64,
+	source: TimerSource,
+	/// Ticks of `TimerSource::Cycles` times cycles carried to the next
+	/// cycles, less than the cycles of the ratio
+	fraction: u64,
+	/// Host clock `TimerSource::HostClock` follows
+	host_clock: Option<Rc<dyn Clock>>,
+	frequency: u32,
+	/// `mtime` minus the host clock in ticks, set when `mtime` is written
+	host_offset: u64
 }
 
 impl Clint {
-	/// Creates a new `Clint`
+	/// Creates a new `Clint` advancing `mtime` one per cycle
 	pub fn new() -> Self {
+		Self::new_with_config(&TimerConfig::default())
+	}
+
+	/// Creates a new `Clint` advancing `mtime` as configured. It follows
+	/// `SystemClock` for `TimerSource::HostClock` until `set_host_clock()`.
+	///
+	/// # Arguments
+	/// * `config`
+	pub fn new_with_config(config: &TimerConfig) -> Self {
 		Clint {
 			clock: 0,
 			msip: 0,
 			mtimecmp: 0,
-			mtime: 0 // @TODO: Should be bound to csr time register
+			mtime: 0, // @TODO: Should be bound to csr time register
+			source: config.source,
+			fraction: 0,
+			host_clock: match config.source {
+				TimerSource::HostClock => Some(create_default_clock()),
+				_ => None
+			},
+			frequency: config.frequency,
+			host_offset: 0
+		}
+	}
+
+	/// Sets the host clock `TimerSource::HostClock` follows, keeping
+	/// `mtime` continuous. Ignored for the other sources.
+	///
+	/// # Arguments
+	/// * `clock`
+	pub fn set_host_clock(&mut self, clock: Rc<dyn Clock>) {
+		if self.source == TimerSource::HostClock {
+			self.host_clock = Some(clock);
+			let mtime = self.mtime;
+			self.write_mtime(mtime);
 		}
 	}
 
@@ -26,9 +73,48 @@ impl Clint {
 	/// # Arguments
 	/// * `mip` CPU `mip` register. It can be updated if interrupt occurs.
 	pub fn tick(&mut self, mip: &mut u64) {
-		self.clock = self.clock.wrapping_add(1);
-		self.mtime = self.mtime.wrapping_add(1);
+		self.advance(1);
+		self.update_mip(mip);
+	}
 
+	/// Advances the timer by the cycles without raising interrupts.
+	/// `update_mip()` raises them afterward.
+	///
+	/// # Arguments
+	/// * `cycles`
+	pub fn advance(&mut self, cycles: u64) {
+		let previous_clock = self.clock;
+		self.clock = self.clock.wrapping_add(cycles);
+		match self.source {
+			TimerSource::Cycles { ticks: 1, cycles: 1 } => {
+				self.mtime = self.mtime.wrapping_add(cycles);
+			},
+			TimerSource::Cycles { ticks, cycles: period } => {
+				let total = self.fraction as u128 + cycles as u128 * ticks as u128;
+				self.mtime = self.mtime.wrapping_add((total / period as u128) as u64);
+				self.fraction = (total % period as u128) as u64;
+			},
+			TimerSource::HostClock => {
+				if previous_clock / HOST_CLOCK_POLL_CYCLES != self.clock / HOST_CLOCK_POLL_CYCLES {
+					self.mtime = self.get_host_ticks().wrapping_add(self.host_offset);
+				}
+			}
+		};
+	}
+
+	// Host clock in ticks of the timebase frequency
+	fn get_host_ticks(&self) -> u64 {
+		match &self.host_clock {
+			Some(clock) => (clock.now().as_nanos() * self.frequency as u128 / 1_000_000_000) as u64,
+			None => 0
+		}
+	}
+
+	/// Raises the software and timer interrupts pending now.
+	///
+	/// # Arguments
+	/// * `mip` CPU `mip` register. It can be updated if interrupt occurs.
+	pub fn update_mip(&self, mip: &mut u64) {
 		if (self.msip & 1) != 0 {
 			*mip |= MIP_MSIP;
 		}
@@ -38,6 +124,21 @@ impl Clint {
 		}
 	}
 
+	/// Returns the cycles until the timer interrupt can be raised, or
+	/// `None` if it's disabled or has been raised already. Following
+	/// the host clock, the cycles until it's read next.
+	pub fn get_cycles_to_timer(&self) -> Option<u64> {
+		match (self.source, self.mtimecmp > self.mtime) {
+			(TimerSource::HostClock, _) => Some(HOST_CLOCK_POLL_CYCLES - self.clock % HOST_CLOCK_POLL_CYCLES),
+			(TimerSource::Cycles { ticks, cycles }, true) => {
+				// Cycles until the ticks carried reach mtimecmp
+				let needed = (self.mtimecmp - self.mtime) as u128 * cycles as u128 - self.fraction as u128;
+				Some(needed.div_ceil(ticks as u128).min(u64::MAX as u128) as u64)
+			},
+			(_, false) => None
+		}
+	}
+
 	/// Loads register content.
 	///
 	/// # Arguments
@@ -175,6 +276,10 @@ impl Clint {
 			},
 			_ => {}
 		};
+		if (0x0200bff8..=0x0200bfff).contains(&address) {
+			let mtime = self.mtime;
+			self.write_mtime(mtime);
+		}
 	}
 
 	/// Reads `mtime` register content
@@ -185,5 +290,105 @@ impl Clint {
 	/// Writes to `mtime` register content
 	pub fn write_mtime(&mut self, value: u64) {
 		self.mtime = value;
+		if self.source == TimerSource::HostClock {
+			self.host_offset = value.wrapping_sub(self.get_host_ticks());
+		}
+	}
+}
+
+#[cfg(test)]
+mod test_clint {
+	use super::*;
+	use core::cell::Cell;
+	use core::time::Duration;
+
+	// Host clock the test moves by hand
+	struct ManualClock {
+		now: Cell<Duration>
+	}
+
+	impl Clock for ManualClock {
+		fn now(&self) -> Duration {
+			self.now.get()
+		}
+
+		fn unix_time(&self) -> Duration {
+			self.now.get()
+		}
+	}
+
+	fn store_mtimecmp(clint: &mut Clint, value: u64) {
+		for i in 0..8 {
+			clint.store(0x02004000 + i, (value >> (i * 8)) as u8);
+		}
+	}
+
+	#[test]
+	fn cycles_ratio() {
+		let mut clint = Clint::new_with_config(&TimerConfig {
+			source: TimerSource::Cycles {
+				ticks: 3,
+				cycles: 8
+			},
+			frequency: 10_000_000
+		});
+		store_mtimecmp(&mut clint, 10);
+		// 10 ticks need 27 cycles
+		assert_eq!(Some(27), clint.get_cycles_to_timer());
+		let mut mip = 0;
+		for _ in 0..26 {
+			clint.tick(&mut mip);
+		}
+		assert_eq!(9, clint.read_mtime());
+		assert_eq!(0, mip);
+		assert_eq!(Some(1), clint.get_cycles_to_timer());
+		clint.tick(&mut mip);
+		assert_eq!(MIP_MTIP, mip);
+		assert_eq!(None, clint.get_cycles_to_timer());
+
+		// Advancing at once carries the fraction left over, 1/8 ticks
+		clint.advance(5);
+		assert_eq!(12, clint.read_mtime());
+		store_mtimecmp(&mut clint, 13);
+		assert_eq!(Some(3), clint.get_cycles_to_timer());
+	}
+
+	#[test]
+	fn host_clock() {
+		let clock = Rc::new(ManualClock {
+			now: Cell::new(Duration::from_secs(5))
+		});
+		let mut clint = Clint::new_with_config(&TimerConfig {
+			source: TimerSource::HostClock,
+			frequency: 1000
+		});
+		clint.set_host_clock(clock.clone());
+		assert_eq!(0, clint.read_mtime());
+		store_mtimecmp(&mut clint, 20);
+
+		// The host clock is read every poll cycles, however fast
+		// the emulation runs
+		clock.now.set(Duration::from_millis(5010));
+		let mut mip = 0;
+		for _ in 0..HOST_CLOCK_POLL_CYCLES - 1 {
+			clint.tick(&mut mip);
+		}
+		assert_eq!(0, clint.read_mtime());
+		assert_eq!(Some(1), clint.get_cycles_to_timer());
+		clint.tick(&mut mip);
+		assert_eq!(10, clint.read_mtime());
+		assert_eq!(0, mip);
+
+		clock.now.set(Duration::from_millis(5020));
+		clint.advance(HOST_CLOCK_POLL_CYCLES);
+		clint.update_mip(&mut mip);
+		assert_eq!(20, clint.read_mtime());
+		assert_eq!(MIP_MTIP, mip);
+
+		// Writes move the time the host clock is counted from
+		clint.write_mtime(100);
+		clock.now.set(Duration::from_millis(5030));
+		clint.advance(HOST_CLOCK_POLL_CYCLES);
+		assert_eq!(110, clint.read_mtime());
 	}
 }
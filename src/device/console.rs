@@ -0,0 +1,89 @@
+use alloc::boxed::Box;
+
+use config::ConsoleType;
+use device::sifive_uart::{SifiveUart, SIFIVE_UART_BASE};
+use device::uart::{Uart, UART_BASE, UART_SIZE};
+use terminal::Terminal;
+
+/// Console device. Holds one of the UART models selected by
+/// [`ConsoleType`](../../config/enum.ConsoleType.html) and forwards
+/// accesses to it.
+pub enum Console {
+	Ns16550a(Uart),
+	Sifive(SifiveUart)
+}
+
+impl Console {
+	/// Creates a new `Console`.
+	///
+	/// # Arguments
+	/// * `console_type`
+	/// * `terminal`
+	pub fn new(console_type: &ConsoleType, terminal: Box<dyn Terminal>) -> Self {
+		match console_type {
+			ConsoleType::Ns16550a => Console::Ns16550a(Uart::new(terminal)),
+			ConsoleType::SifiveUart => Console::Sifive(SifiveUart::new(terminal))
+		}
+	}
+
+	/// Checks if the physical address is in the register range
+	/// of the selected UART model.
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn contains(&self, address: u64) -> bool {
+		match self {
+			Console::Ns16550a(_) => (UART_BASE..UART_BASE + UART_SIZE).contains(&address),
+			Console::Sifive(_) => (SIFIVE_UART_BASE..=SIFIVE_UART_BASE + 0xff).contains(&address)
+		}
+	}
+
+	/// Runs one cycle.
+	pub fn tick(&mut self) {
+		match self {
+			Console::Ns16550a(uart) => uart.tick(),
+			Console::Sifive(uart) => uart.tick()
+		};
+	}
+
+	/// Indicates whether the UART raises an interrupt signal. Note that
+	/// NS16550A signal is "Edge-triggered" while SiFive UART one is
+	/// "Level-triggered".
+	pub fn is_interrupting(&self) -> bool {
+		match self {
+			Console::Ns16550a(uart) => uart.is_interrupting(),
+			Console::Sifive(uart) => uart.is_interrupting()
+		}
+	}
+
+	/// Loads register content
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn load(&mut self, address: u64) -> u8 {
+		match self {
+			Console::Ns16550a(uart) => uart.load(address),
+			Console::Sifive(uart) => uart.load(address)
+		}
+	}
+
+	/// Stores register content
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		match self {
+			Console::Ns16550a(uart) => uart.store(address, value),
+			Console::Sifive(uart) => uart.store(address, value)
+		};
+	}
+
+	/// Returns mutable reference to `Terminal`.
+	pub fn get_mut_terminal(&mut self) -> &mut Box<dyn Terminal> {
+		match self {
+			Console::Ns16550a(uart) => uart.get_mut_terminal(),
+			Console::Sifive(uart) => uart.get_mut_terminal()
+		}
+	}
+}
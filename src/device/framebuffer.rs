@@ -0,0 +1,115 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Base address of `Framebuffer` pixel buffer
+pub const FRAMEBUFFER_BASE: u64 = 0x30000000;
+
+/// Bytes per pixel. Pixel format is a8r8g8b8, stored in little endian
+/// so that bytes are in blue, green, red, alpha order.
+pub const FRAMEBUFFER_BYTES_PER_PIXEL: u32 = 4;
+
+// The host is notified of updates at most once in this period
+// not to slow down the emulation with frequent pixel writes.
+const UPDATE_INTERVAL: u64 = 0x100000;
+
+/// Callback invoked with pixel buffer when the guest has updated the framebuffer
+pub type FramebufferUpdateCallback = Box<dyn FnMut(&[u8])>;
+
+/// Emulates a dumb linear framebuffer described as `simple-framebuffer`
+/// in the device tree. The guest draws by writing pixels to the memory
+/// mapped buffer, and the host fetches it with `get_pixels()` or is notified
+/// of updates via the callback registered with `set_update_callback()`.
+pub struct Framebuffer {
+	width: u32,
+	height: u32,
+	pixels: Vec<u8>,
+	/// Whether pixels have been written since the last notification
+	dirty: bool,
+	clock: u64,
+	update_callback: Option<FramebufferUpdateCallback>
+}
+
+impl Framebuffer {
+	/// Creates a new `Framebuffer`. Zero size framebuffer is seen as
+	/// no device.
+	///
+	/// # Arguments
+	/// * `width` in pixels
+	/// * `height` in pixels
+	pub fn new(width: u32, height: u32) -> Self {
+		Framebuffer {
+			width,
+			height,
+			pixels: vec![0; (width * height * FRAMEBUFFER_BYTES_PER_PIXEL) as usize],
+			dirty: false,
+			clock: 0,
+			update_callback: None
+		}
+	}
+
+	/// Returns width in pixels.
+	pub fn get_width(&self) -> u32 {
+		self.width
+	}
+
+	/// Returns height in pixels.
+	pub fn get_height(&self) -> u32 {
+		self.height
+	}
+
+	/// Returns bytes per line.
+	pub fn get_stride(&self) -> u32 {
+		self.width * FRAMEBUFFER_BYTES_PER_PIXEL
+	}
+
+	/// Returns pixel buffer in a8r8g8b8 format.
+	pub fn get_pixels(&self) -> &[u8] {
+		&self.pixels
+	}
+
+	/// Registers a callback invoked with pixel buffer when the guest
+	/// has updated the framebuffer.
+	///
+	/// # Arguments
+	/// * `callback`
+	pub fn set_update_callback(&mut self, callback: FramebufferUpdateCallback) {
+		self.update_callback = Some(callback);
+	}
+
+	/// Indicates whether the address is in the pixel buffer.
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn contains(&self, address: u64) -> bool {
+		address >= FRAMEBUFFER_BASE && address < FRAMEBUFFER_BASE + self.pixels.len() as u64
+	}
+
+	/// Runs one cycle. The host can be notified of updates.
+	pub fn tick(&mut self) {
+		if self.dirty && self.clock.is_multiple_of(UPDATE_INTERVAL) {
+			self.dirty = false;
+			if let Some(callback) = self.update_callback.as_mut() {
+				callback(&self.pixels);
+			}
+		}
+		self.clock = self.clock.wrapping_add(1);
+	}
+
+	/// Loads a byte of pixel buffer
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn load(&self, address: u64) -> u8 {
+		self.pixels[(address - FRAMEBUFFER_BASE) as usize]
+	}
+
+	/// Stores a byte to pixel buffer
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		self.pixels[(address - FRAMEBUFFER_BASE) as usize] = value;
+		self.dirty = true;
+	}
+}
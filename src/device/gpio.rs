@@ -0,0 +1,325 @@
+// Based on SiFive FU540-C000 Manual, Chapter 12 GPIO
+// https://sifive.cdn.prismic.io/sifive/d3ed5cd0-6e74-46b2-a12d-72b06706513e_fu540-c000-manual-v1p4.pdf
+use alloc::boxed::Box;
+
+use error::DeviceError;
+
+/// Base address of `Gpio` registers
+pub const GPIO_BASE: u64 = 0x10060000;
+
+/// The number of pins `Gpio` has
+pub const GPIO_PIN_NUM: u32 = 32;
+
+const INPUT_VAL: u64 = 0x00;
+const INPUT_EN: u64 = 0x04;
+const OUTPUT_EN: u64 = 0x08;
+const OUTPUT_VAL: u64 = 0x0c;
+const PUE: u64 = 0x10;
+const DS: u64 = 0x14;
+const RISE_IE: u64 = 0x18;
+const RISE_IP: u64 = 0x1c;
+const FALL_IE: u64 = 0x20;
+const FALL_IP: u64 = 0x24;
+const HIGH_IE: u64 = 0x28;
+const HIGH_IP: u64 = 0x2c;
+const LOW_IE: u64 = 0x30;
+const LOW_IP: u64 = 0x34;
+const IOF_EN: u64 = 0x38;
+const IOF_SEL: u64 = 0x3c;
+const OUT_XOR: u64 = 0x40;
+
+/// Emulates SiFive GPIO controller. Pins are driven by the host via
+/// `set_input()` and the host is notified of output pin changes via
+/// the callback registered with `set_output_callback()`, so buttons and
+/// LEDs can be simulated against the guest.
+pub struct Gpio {
+	/// Pin levels driven by the host
+	pins: u32,
+	input_val: u32,
+	input_en: u32,
+	output_en: u32,
+	output_val: u32,
+	pue: u32,
+	ds: u32,
+	rise_ie: u32,
+	rise_ip: u32,
+	fall_ie: u32,
+	fall_ip: u32,
+	high_ie: u32,
+	high_ip: u32,
+	low_ie: u32,
+	low_ip: u32,
+	iof_en: u32,
+	iof_sel: u32,
+	out_xor: u32,
+	/// Output pin levels the host has been notified of
+	output_cache: u32,
+	output_callback: Option<Box<dyn FnMut(u32, bool)>>
+}
+
+impl Gpio {
+	/// Creates a new `Gpio`.
+	pub fn new() -> Self {
+		Gpio {
+			pins: 0,
+			input_val: 0,
+			input_en: 0,
+			output_en: 0,
+			output_val: 0,
+			pue: 0,
+			ds: 0,
+			rise_ie: 0,
+			rise_ip: 0,
+			fall_ie: 0,
+			fall_ip: 0,
+			high_ie: 0,
+			high_ip: 0,
+			low_ie: 0,
+			low_ip: 0,
+			iof_en: 0,
+			iof_sel: 0,
+			out_xor: 0,
+			output_cache: 0,
+			output_callback: None
+		}
+	}
+
+	/// Runs one cycle. Samples the input pins and updates
+	/// interrupt pending bits.
+	pub fn tick(&mut self) {
+		let input_val = self.pins & self.input_en;
+		let rise = input_val & !self.input_val;
+		let fall = !input_val & self.input_val & self.input_en;
+		self.input_val = input_val;
+
+		self.rise_ip |= rise;
+		self.fall_ip |= fall;
+		// High and low pending bits stay asserted while the level holds
+		self.high_ip |= input_val;
+		self.low_ip |= !input_val & self.input_en;
+	}
+
+	/// Indicates whether `Gpio` raises an interrupt signal.
+	/// The signal is "Level-triggered", it keeps asserted until the guest
+	/// clears the pending bits.
+	pub fn is_interrupting(&self) -> bool {
+		((self.rise_ip & self.rise_ie) |
+			(self.fall_ip & self.fall_ie) |
+			(self.high_ip & self.high_ie) |
+			(self.low_ip & self.low_ie)) != 0
+	}
+
+	/// Drives an input pin from the host. Returns `Err` if the pin is
+	/// out of range.
+	///
+	/// # Arguments
+	/// * `pin` Must be less than [`GPIO_PIN_NUM`](constant.GPIO_PIN_NUM.html)
+	/// * `level` `true` for high
+	pub fn set_input(&mut self, pin: u32, level: bool) -> Result<(), DeviceError> {
+		if pin >= GPIO_PIN_NUM {
+			return Err(DeviceError::NoGpioPin(pin));
+		}
+		match level {
+			true => self.pins |= 1 << pin,
+			false => self.pins &= !(1 << pin)
+		};
+		Ok(())
+	}
+
+	/// Returns the current output pin levels. A bit is one if the pin
+	/// is output-enabled and driven high.
+	pub fn get_output(&self) -> u32 {
+		(self.output_val ^ self.out_xor) & self.output_en
+	}
+
+	/// Registers a callback invoked with pin number and new level
+	/// whenever an output pin level changes.
+	///
+	/// # Arguments
+	/// * `callback`
+	pub fn set_output_callback(&mut self, callback: Box<dyn FnMut(u32, bool)>) {
+		self.output_callback = Some(callback);
+	}
+
+	fn notify_output(&mut self) {
+		let output = self.get_output();
+		let changed = output ^ self.output_cache;
+		self.output_cache = output;
+		if changed == 0 {
+			return;
+		}
+		if let Some(callback) = self.output_callback.as_mut() {
+			for pin in 0..GPIO_PIN_NUM {
+				if ((changed >> pin) & 1) == 1 {
+					callback(pin, ((output >> pin) & 1) == 1);
+				}
+			}
+		}
+	}
+
+	fn read_register(&self, offset: u64) -> u32 {
+		match offset {
+			INPUT_VAL => self.input_val,
+			INPUT_EN => self.input_en,
+			OUTPUT_EN => self.output_en,
+			OUTPUT_VAL => self.output_val,
+			PUE => self.pue,
+			DS => self.ds,
+			RISE_IE => self.rise_ie,
+			RISE_IP => self.rise_ip,
+			FALL_IE => self.fall_ie,
+			FALL_IP => self.fall_ip,
+			HIGH_IE => self.high_ie,
+			HIGH_IP => self.high_ip,
+			LOW_IE => self.low_ie,
+			LOW_IP => self.low_ip,
+			IOF_EN => self.iof_en,
+			IOF_SEL => self.iof_sel,
+			OUT_XOR => self.out_xor,
+			_ => 0
+		}
+	}
+
+	/// Loads register content
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn load(&self, address: u64) -> u8 {
+		let offset = address - GPIO_BASE;
+		let pos = (offset % 4) * 8;
+		(self.read_register(offset & !0x3) >> pos) as u8
+	}
+
+	/// Stores register content
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		let offset = address - GPIO_BASE;
+		let pos = (offset % 4) * 8;
+		let mask = 0xff << pos;
+		let data = (value as u32) << pos;
+		let merge = |register: u32| (register & !mask) | data;
+		match offset & !0x3 {
+			INPUT_EN => self.input_en = merge(self.input_en),
+			OUTPUT_EN => self.output_en = merge(self.output_en),
+			OUTPUT_VAL => self.output_val = merge(self.output_val),
+			PUE => self.pue = merge(self.pue),
+			DS => self.ds = merge(self.ds),
+			RISE_IE => self.rise_ie = merge(self.rise_ie),
+			FALL_IE => self.fall_ie = merge(self.fall_ie),
+			HIGH_IE => self.high_ie = merge(self.high_ie),
+			LOW_IE => self.low_ie = merge(self.low_ie),
+			// Pending bits are cleared by writing one
+			RISE_IP => self.rise_ip &= !data,
+			FALL_IP => self.fall_ip &= !data,
+			HIGH_IP => self.high_ip &= !data,
+			LOW_IP => self.low_ip &= !data,
+			IOF_EN => self.iof_en = merge(self.iof_en),
+			IOF_SEL => self.iof_sel = merge(self.iof_sel),
+			OUT_XOR => self.out_xor = merge(self.out_xor),
+			_ => {}
+		};
+		self.notify_output();
+	}
+}
+
+impl Default for Gpio {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod test_gpio {
+	use core::cell::RefCell;
+	use alloc::rc::Rc;
+	use super::*;
+
+	fn store_word(gpio: &mut Gpio, offset: u64, value: u32) {
+		for i in 0..4 {
+			gpio.store(GPIO_BASE + offset + i, (value >> (i * 8)) as u8);
+		}
+	}
+
+	fn load_word(gpio: &Gpio, offset: u64) -> u32 {
+		(0..4).fold(0, |word, i| word | ((gpio.load(GPIO_BASE + offset + i) as u32) << (i * 8)))
+	}
+
+	#[test]
+	fn input_val() {
+		let mut gpio = Gpio::new();
+		assert_eq!(Ok(()), gpio.set_input(1, true));
+		assert_eq!(Ok(()), gpio.set_input(2, true));
+		gpio.tick();
+		// Only the input enabled pins are sampled
+		assert_eq!(0, load_word(&gpio, INPUT_VAL));
+		store_word(&mut gpio, INPUT_EN, 0x6);
+		gpio.tick();
+		assert_eq!(0x6, load_word(&gpio, INPUT_VAL));
+		// Read-only
+		store_word(&mut gpio, INPUT_VAL, 0);
+		assert_eq!(0x6, load_word(&gpio, INPUT_VAL));
+		assert_eq!(Ok(()), gpio.set_input(1, false));
+		gpio.tick();
+		assert_eq!(0x4, load_word(&gpio, INPUT_VAL));
+		assert_eq!(Err(DeviceError::NoGpioPin(32)), gpio.set_input(32, true));
+	}
+
+	#[test]
+	fn output_val() {
+		let mut gpio = Gpio::new();
+		let changes = Rc::new(RefCell::new(vec![]));
+		let changes_clone = changes.clone();
+		gpio.set_output_callback(Box::new(move |pin, level| changes_clone.borrow_mut().push((pin, level))));
+		store_word(&mut gpio, OUTPUT_VAL, 0x5);
+		// The pins aren't output enabled
+		assert_eq!(0x5, load_word(&gpio, OUTPUT_VAL));
+		assert_eq!(0, gpio.get_output());
+		assert!(changes.borrow().is_empty());
+		store_word(&mut gpio, OUTPUT_EN, 0x1);
+		assert_eq!(0x1, gpio.get_output());
+		// out_xor inverts the levels
+		store_word(&mut gpio, OUT_XOR, 0x1);
+		assert_eq!(0, gpio.get_output());
+		// Writes not changing the levels aren't notified
+		store_word(&mut gpio, OUTPUT_VAL, 0x7);
+		assert_eq!(vec![(0, true), (0, false)], *changes.borrow());
+	}
+
+	#[test]
+	fn interrupt_pending() {
+		let mut gpio = Gpio::new();
+		store_word(&mut gpio, INPUT_EN, 0x3);
+		gpio.tick();
+		assert_eq!(0x3, load_word(&gpio, LOW_IP));
+		// Pending bits interrupt only if enabled
+		assert!(!gpio.is_interrupting());
+		store_word(&mut gpio, RISE_IE, 0x1);
+		assert_eq!(Ok(()), gpio.set_input(0, true));
+		gpio.tick();
+		assert_eq!(0x1, load_word(&gpio, RISE_IP));
+		assert_eq!(0x1, load_word(&gpio, HIGH_IP));
+		assert!(gpio.is_interrupting());
+		assert_eq!(Ok(()), gpio.set_input(0, false));
+		gpio.tick();
+		assert_eq!(0x1, load_word(&gpio, FALL_IP));
+
+		// Writing one clears the pending bits
+		store_word(&mut gpio, RISE_IP, 0x1);
+		store_word(&mut gpio, FALL_IP, 0x1);
+		store_word(&mut gpio, HIGH_IP, 0x1);
+		store_word(&mut gpio, LOW_IP, 0x2);
+		assert_eq!(0, load_word(&gpio, RISE_IP));
+		assert_eq!(0, load_word(&gpio, FALL_IP));
+		assert_eq!(0, load_word(&gpio, HIGH_IP));
+		assert_eq!(0x1, load_word(&gpio, LOW_IP));
+		assert!(!gpio.is_interrupting());
+		// Edges don't pend again, while the levels which hold do
+		gpio.tick();
+		assert_eq!(0, load_word(&gpio, RISE_IP));
+		assert_eq!(0, load_word(&gpio, HIGH_IP));
+		assert_eq!(0x3, load_word(&gpio, LOW_IP));
+	}
+}
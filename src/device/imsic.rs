@@ -0,0 +1,264 @@
+use cpu::{PrivilegeMode, Xlen, MIP_MEIP, MIP_SEIP};
+
+// Based on The RISC-V Advanced Interrupt Architecture, Chapter 3
+// https://github.com/riscv/riscv-aia
+
+/// Base address of machine-level `Imsic` interrupt files
+pub const IMSIC_MACHINE_BASE: u64 = 0x24000000;
+
+/// Base address of supervisor-level `Imsic` interrupt files
+pub const IMSIC_SUPERVISOR_BASE: u64 = 0x28000000;
+
+/// Size of an interrupt file memory region. Each hart has its own file.
+pub const IMSIC_FILE_SIZE: u64 = 0x1000;
+
+/// The number of interrupt identities an interrupt file supports.
+/// Identity 0 is not used so the valid identities are 1-63.
+pub const IMSIC_ID_NUM: u32 = 64;
+
+const SETEIPNUM_LE: u64 = 0x0;
+const SETEIPNUM_BE: u64 = 0x4;
+
+// Interrupt file registers accessed via *iselect and *ireg CSRs
+const EIDELIVERY: u64 = 0x70;
+const EITHRESHOLD: u64 = 0x72;
+const EIP0: u64 = 0x80;
+const EIP63: u64 = 0xbf;
+const EIE0: u64 = 0xc0;
+const EIE63: u64 = 0xff;
+
+/// Interrupt file of a privilege level. It records MSIs as pending
+/// interrupt identities and signals an external interrupt to the hart
+/// while an enabled identity is pending.
+struct InterruptFile {
+	eidelivery: u32,
+	eithreshold: u32,
+	eip: u64,
+	eie: u64,
+	/// seteipnum registers content being written byte by byte
+	seteipnum: u32
+}
+
+impl InterruptFile {
+	fn new() -> Self {
+		InterruptFile {
+			eidelivery: 0,
+			eithreshold: 0,
+			eip: 0,
+			eie: 0,
+			seteipnum: 0
+		}
+	}
+
+	fn set_pending(&mut self, id: u32) {
+		if id > 0 && id < IMSIC_ID_NUM {
+			self.eip |= 1 << id;
+		}
+	}
+
+	// The smaller identity has the higher priority. Identities equal to
+	// or greater than non-zero threshold are masked.
+	fn get_topei(&self) -> u32 {
+		let interrupts = self.eip & self.eie;
+		for id in 1..IMSIC_ID_NUM {
+			if self.eithreshold != 0 && id >= self.eithreshold {
+				break;
+			}
+			if ((interrupts >> id) & 1) == 1 {
+				return id;
+			}
+		}
+		0
+	}
+
+	fn is_interrupting(&self) -> bool {
+		self.eidelivery == 1 && self.get_topei() != 0
+	}
+}
+
+/// Reads a 32-bit unit of the bit array register. In 64-bit mode
+/// a register has 64 bits then odd number registers don't exist.
+fn read_array(bits: u64, index: u64, xlen: &Xlen) -> u64 {
+	match xlen {
+		Xlen::Bit32 => match index {
+			0 => bits & 0xffffffff,
+			1 => bits >> 32,
+			_ => 0
+		},
+		Xlen::Bit64 => match index {
+			0 => bits,
+			_ => 0
+		}
+	}
+}
+
+fn write_array(bits: &mut u64, index: u64, value: u64, xlen: &Xlen) {
+	match xlen {
+		Xlen::Bit32 => match index {
+			0 => *bits = (*bits & !0xffffffff) | (value & 0xffffffff),
+			1 => *bits = (*bits & 0xffffffff) | (value << 32),
+			_ => {}
+		},
+		Xlen::Bit64 => if index == 0 {
+			*bits = value;
+		}
+	};
+	// Identity 0 doesn't exist
+	*bits &= !1;
+}
+
+/// Emulates Incoming MSI Controller (IMSIC) of AIA. The hart has
+/// machine-level and supervisor-level interrupt files. Devices and
+/// `Aplic` send MSIs by writing interrupt identities to the files, and
+/// the hart handles them via `mtopei` and `stopei` CSRs.
+pub struct Imsic {
+	machine: InterruptFile,
+	supervisor: InterruptFile
+}
+
+impl Imsic {
+	/// Creates a new `Imsic`.
+	pub fn new() -> Self {
+		Imsic {
+			machine: InterruptFile::new(),
+			supervisor: InterruptFile::new()
+		}
+	}
+
+	fn get_file(&self, mode: &PrivilegeMode) -> &InterruptFile {
+		match mode {
+			PrivilegeMode::Machine => &self.machine,
+			_ => &self.supervisor
+		}
+	}
+
+	fn get_mut_file(&mut self, mode: &PrivilegeMode) -> &mut InterruptFile {
+		match mode {
+			PrivilegeMode::Machine => &mut self.machine,
+			_ => &mut self.supervisor
+		}
+	}
+
+	/// Runs one cycle. Raises or clears machine and supervisor external
+	/// interrupt bits of `mip` depending on the interrupt files.
+	///
+	/// # Arguments
+	/// * `mip`
+	pub fn tick(&mut self, mip: &mut u64) {
+		match self.machine.is_interrupting() {
+			true => *mip |= MIP_MEIP,
+			false => *mip &= !MIP_MEIP
+		};
+		match self.supervisor.is_interrupting() {
+			true => *mip |= MIP_SEIP,
+			false => *mip &= !MIP_SEIP
+		};
+	}
+
+	/// Returns `*topei` CSR value, the highest priority pending and enabled
+	/// interrupt identity in both the identity and priority fields.
+	///
+	/// # Arguments
+	/// * `mode` Machine or Supervisor
+	pub fn get_topei(&self, mode: &PrivilegeMode) -> u64 {
+		let id = self.get_file(mode).get_topei() as u64;
+		(id << 16) | id
+	}
+
+	/// Claims the highest priority pending and enabled interrupt identity.
+	/// Called when `*topei` CSR is written.
+	///
+	/// # Arguments
+	/// * `mode` Machine or Supervisor
+	pub fn claim_topei(&mut self, mode: &PrivilegeMode) {
+		let file = self.get_mut_file(mode);
+		let id = file.get_topei();
+		file.eip &= !(1 << id);
+	}
+
+	/// Reads an interrupt file register selected by `*iselect` CSR.
+	/// Unsupported registers read zero.
+	///
+	/// # Arguments
+	/// * `mode` Machine or Supervisor
+	/// * `select` `*iselect` CSR value
+	/// * `xlen`
+	pub fn read_register(&self, mode: &PrivilegeMode, select: u64, xlen: &Xlen) -> u64 {
+		let file = self.get_file(mode);
+		match select {
+			EIDELIVERY => file.eidelivery as u64,
+			EITHRESHOLD => file.eithreshold as u64,
+			EIP0..=EIP63 => read_array(file.eip, select - EIP0, xlen),
+			EIE0..=EIE63 => read_array(file.eie, select - EIE0, xlen),
+			_ => 0
+		}
+	}
+
+	/// Writes an interrupt file register selected by `*iselect` CSR.
+	/// Writes to unsupported registers are ignored.
+	///
+	/// # Arguments
+	/// * `mode` Machine or Supervisor
+	/// * `select` `*iselect` CSR value
+	/// * `value`
+	/// * `xlen`
+	pub fn write_register(&mut self, mode: &PrivilegeMode, select: u64, value: u64, xlen: &Xlen) {
+		let file = self.get_mut_file(mode);
+		match select {
+			EIDELIVERY => file.eidelivery = (value & 1) as u32,
+			EITHRESHOLD => file.eithreshold = (value as u32) & (IMSIC_ID_NUM - 1),
+			EIP0..=EIP63 => write_array(&mut file.eip, select - EIP0, value, xlen),
+			EIE0..=EIE63 => write_array(&mut file.eie, select - EIE0, value, xlen),
+			_ => {}
+		};
+	}
+
+	/// Indicates whether the address is in the interrupt files of the hart.
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn contains(&self, address: u64) -> bool {
+		(IMSIC_MACHINE_BASE..IMSIC_MACHINE_BASE + IMSIC_FILE_SIZE).contains(&address) ||
+			(IMSIC_SUPERVISOR_BASE..IMSIC_SUPERVISOR_BASE + IMSIC_FILE_SIZE).contains(&address)
+	}
+
+	/// Loads register content. seteipnum registers always read zero.
+	///
+	/// # Arguments
+	/// * `_address`
+	pub fn load(&self, _address: u64) -> u8 {
+		0
+	}
+
+	/// Stores register content. Writing an interrupt identity to
+	/// seteipnum register sends an MSI. The identity is taken when
+	/// the highest byte of the register is written.
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		let (mode, offset) = match address >= IMSIC_SUPERVISOR_BASE {
+			true => (PrivilegeMode::Supervisor, address - IMSIC_SUPERVISOR_BASE),
+			false => (PrivilegeMode::Machine, address - IMSIC_MACHINE_BASE)
+		};
+		let pos = (offset % 4) * 8;
+		let file = self.get_mut_file(&mode);
+		file.seteipnum = (file.seteipnum & !(0xff << pos)) | ((value as u32) << pos);
+		if (offset % 4) != 3 {
+			return;
+		}
+		let id = match offset & !0x3 {
+			SETEIPNUM_LE => file.seteipnum,
+			SETEIPNUM_BE => file.seteipnum.swap_bytes(),
+			_ => return
+		};
+		file.set_pending(id);
+	}
+}
+
+impl Default for Imsic {
+	fn default() -> Self {
+		Self::new()
+	}
+}
@@ -1,4 +1,28 @@
+pub mod aplic;
 pub mod clint;
+pub mod console;
+pub mod framebuffer;
+pub mod gpio;
+pub mod imsic;
+pub mod msi;
 pub mod plic;
+pub mod pwm;
+pub mod shared_memory;
+pub mod sifive_uart;
+pub mod sswi;
+pub mod test_finisher;
 pub mod uart;
+#[cfg(feature = "virtio")]
+pub mod virtio_balloon;
+#[cfg(feature = "virtio")]
+pub mod virtio_console;
+#[cfg(feature = "virtio")]
 pub mod virtio_block_disk;
+#[cfg(feature = "virtio")]
+pub mod virtio_mmio;
+#[cfg(feature = "virtio")]
+pub mod virtio_net;
+#[cfg(feature = "virtio")]
+pub mod virtio_snd;
+#[cfg(feature = "virtio")]
+pub mod virtqueue;
@@ -0,0 +1,22 @@
+/// Message-signaled interrupt. A device signals an interrupt by writing
+/// `data` to `address` on the bus instead of driving an interrupt line.
+/// The address is typically a doorbell register of an `Imsic` interrupt
+/// file and the data is the interrupt identity.
+pub struct Msi {
+	pub address: u64,
+	pub data: u32
+}
+
+impl Msi {
+	/// Creates a new `Msi`.
+	///
+	/// # Arguments
+	/// * `address` Physical address the message is written to
+	/// * `data` 32-bit data written
+	pub fn new(address: u64, data: u32) -> Self {
+		Msi {
+			address,
+			data
+		}
+	}
+}
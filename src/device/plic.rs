@@ -14,12 +14,25 @@ pub struct Plic {
 	ips: [u8; 1024],
 	priorities: [u32; 1024],
 	needs_update_irq: bool,
-	virtio_ip_cache: bool
+	virtio_ip_cache: bool,
+	level_ip_cache: u64
 }
 
 // @TODO: IRQ numbers should be configurable with device tree
-const VIRTIO_IRQ: u32 = 1;
-const UART_IRQ: u32 = 10;
+pub const VIRTIO_IRQ: u32 = 1;
+pub const VIRTIO_NET_IRQ: u32 = 2;
+pub const GPIO_IRQ: u32 = 3;
+pub const SIFIVE_UART_IRQ: u32 = 4;
+pub const VIRTIO_SND_IRQ: u32 = 5;
+pub const VIRTIO_BALLOON_IRQ: u32 = 6;
+pub const SHARED_MEMORY_IRQ: u32 = 7;
+pub const VIRTIO_CONSOLE_IRQ: u32 = 8;
+pub const UART_IRQ: u32 = 10;
+pub const SERIAL_PORT_IRQ_BASE: u32 = 11; // One per serial port besides the console
+pub const PWM_IRQ_BASE: u32 = 42; // One per comparator
+
+/// Only first 64 interrupt sources are supported so far.
+pub const MAX_IRQ: u32 = 64;
 
 impl Plic {
 	/// Creates a new `Plic`.
@@ -32,7 +45,8 @@ impl Plic {
 			priorities: [0; 1024],
 			ips: [0; 1024],
 			needs_update_irq: false,
-			virtio_ip_cache: false
+			virtio_ip_cache: false,
+			level_ip_cache: 0
 		}
 	}
 
@@ -72,53 +86,58 @@ impl Plic {
 		}
 	}
 
-	fn update_irq(&mut self, mip: &mut u64) {
-		// Hardcoded VirtIO and UART
-		// @TODO: Should be configurable with device tree
-
-		let virtio_ip = ((self.ips[(VIRTIO_IRQ >> 3) as usize] >> (VIRTIO_IRQ & 7)) & 1) == 1;
-		let uart_ip = ((self.ips[(UART_IRQ >> 3) as usize] >> (UART_IRQ & 7)) & 1) == 1;
-
-		// Which should be prioritized, virtio or uart?
-
-		let virtio_priority = self.priorities[VIRTIO_IRQ as usize];
-		let uart_priority = self.priorities[UART_IRQ as usize];
-
-		let virtio_enabled = ((self.enabled >> VIRTIO_IRQ) & 1) == 1;
-		let uart_enabled = ((self.enabled >> UART_IRQ) & 1) == 1;
-
-		let ips = [virtio_ip, uart_ip];
-		let enables = [virtio_enabled, uart_enabled];
-		let priorities = [virtio_priority, uart_priority];
-		let irqs = [VIRTIO_IRQ, UART_IRQ];
+	/// Takes a "Level-triggered" interrupt signal from a device other than
+	/// VirtIO and UART. `Plic` caches the level and detects the rise edge
+	/// as it does for VirtIO, and makes the source pending again when
+	/// the interrupt completes while the level is still high, like
+	/// the PLIC gateway. Expected to be called before `tick()`.
+	///
+	/// # Arguments
+	/// * `irq` Must be less than 64
+	/// * `level`
+	pub fn update_level(&mut self, irq: u32, level: bool) {
+		debug_assert!(irq > 0 && irq < MAX_IRQ, "irq must be 1-63. {}", irq);
+		let cached = ((self.level_ip_cache >> irq) & 1) == 1;
+		if cached != level {
+			if level {
+				self.set_ip(irq);
+			}
+			self.level_ip_cache ^= 1 << irq;
+		}
+	}
 
+	fn update_irq(&mut self, mip: &mut u64) {
+		// The smaller IRQ number wins if priorities are the same.
 		let mut irq = 0;
 		let mut priority = 0;
-		for i in 0..2 {
-			if ips[i] && enables[i] &&
-				priorities[i] > self.threshold &&
-				priorities[i] > priority {
-					irq = irqs[i];
-					priority = priorities[i];
+		for i in 1..MAX_IRQ {
+			let ip = ((self.ips[(i >> 3) as usize] >> (i & 7)) & 1) == 1;
+			let enabled = ((self.enabled >> i) & 1) == 1;
+			let p = self.priorities[i as usize];
+			if ip && enabled && p > self.threshold && p > priority {
+				irq = i;
+				priority = p;
 			}
 		}
 
+		if irq != self.irq {
+			trace!(target: "plic", "IRQ:{:x}", irq);
+		}
 		self.irq = irq;
 		if self.irq != 0 {
-			//println!("IRQ: {:X}", self.irq);
 			*mip |= MIP_SEIP;
 		}
 	}
 
 	fn set_ip(&mut self, irq: u32) {
 		let index = (irq >> 3) as usize;
-		self.ips[index] = self.ips[index] | (1 << irq);
+		self.ips[index] |= 1 << (irq & 7);
 		self.needs_update_irq = true;
 	}
 
 	fn clear_ip(&mut self, irq: u32) {
 		let index = (irq >> 3) as usize;
-		self.ips[index] = self.ips[index] & !(1 << irq);
+		self.ips[index] &= !(1 << (irq & 7));
 		self.needs_update_irq = true;
 	}
 
@@ -218,7 +237,12 @@ impl Plic {
 			0x0c201004 => {
 				// Assuming written data is a byte so far
 				// @TODO: Should be four bytes.
-				self.clear_ip(value as u32);
+				let irq = value as u32;
+				self.clear_ip(irq);
+				// A level-triggered source still asserted is pending again
+				if irq < MAX_IRQ && ((self.level_ip_cache >> irq) & 1) == 1 {
+					self.set_ip(irq);
+				}
 			},
 			_ => {}
 		};
@@ -0,0 +1,252 @@
+// Based on SiFive FU540-C000 Manual, Chapter 14 Pulse Width Modulator
+// https://sifive.cdn.prismic.io/sifive/d3ed5cd0-6e74-46b2-a12d-72b06706513e_fu540-c000-manual-v1p4.pdf
+
+/// Base address of `Pwm` registers
+pub const PWM_BASE: u64 = 0x10020000;
+
+/// The number of comparators `Pwm` has
+pub const PWM_CMP_NUM: usize = 4;
+
+// Comparator precision
+const CMP_WIDTH: u32 = 16;
+const CMP_MASK: u32 = (1 << CMP_WIDTH) - 1;
+// pwmcount is (CMP_WIDTH + 15) bits
+const COUNT_MASK: u32 = (1 << (CMP_WIDTH + 15)) - 1;
+
+const PWMCFG: u64 = 0x00;
+const PWMCOUNT: u64 = 0x08;
+const PWMS: u64 = 0x10;
+const PWMCMP0: u64 = 0x20;
+const PWMCMP3: u64 = 0x2c;
+
+const CFG_SCALE_MASK: u32 = 0xf;
+const CFG_STICKY: u32 = 1 << 8;
+const CFG_ZEROCMP: u32 = 1 << 9;
+const CFG_ENALWAYS: u32 = 1 << 12;
+const CFG_ENONESHOT: u32 = 1 << 13;
+const CFG_CMP_IP_SHIFT: u32 = 28;
+
+/// Emulates SiFive PWM. The counter runs while `pwmenalways` or
+/// `pwmenoneshot` is set and each comparator raises its own interrupt.
+/// Besides driving PWM outputs it is often used as a secondary timer.
+pub struct Pwm {
+	cfg: u32,
+	count: u32,
+	cmps: [u32; PWM_CMP_NUM]
+}
+
+impl Pwm {
+	/// Creates a new `Pwm`.
+	pub fn new() -> Self {
+		Pwm {
+			cfg: 0,
+			count: 0,
+			cmps: [0; PWM_CMP_NUM]
+		}
+	}
+
+	fn get_scaled_count(&self) -> u32 {
+		(self.count >> (self.cfg & CFG_SCALE_MASK)) & CMP_MASK
+	}
+
+	fn update_ips(&mut self) {
+		let pwms = self.get_scaled_count();
+		for i in 0..PWM_CMP_NUM {
+			let bit = 1 << (CFG_CMP_IP_SHIFT + i as u32);
+			if pwms >= self.cmps[i] {
+				self.cfg |= bit;
+			} else if (self.cfg & CFG_STICKY) == 0 {
+				self.cfg &= !bit;
+			}
+		}
+	}
+
+	/// Runs one cycle. Increments the counter if it's enabled and
+	/// updates comparator interrupt pending bits.
+	pub fn tick(&mut self) {
+		if (self.cfg & (CFG_ENALWAYS | CFG_ENONESHOT)) == 0 {
+			return;
+		}
+		self.count = self.count.wrapping_add(1) & COUNT_MASK;
+		let pwms = self.get_scaled_count();
+		let reset = match (self.cfg & CFG_ZEROCMP) != 0 {
+			true => pwms >= self.cmps[0],
+			false => self.count == 0
+		};
+		self.update_ips();
+		if reset {
+			self.count = 0;
+			// One-shot mode runs only one cycle of the counter
+			self.cfg &= !CFG_ENONESHOT;
+		}
+	}
+
+	/// Indicates whether the comparator raises an interrupt signal.
+	/// The signal is "Level-triggered".
+	///
+	/// # Arguments
+	/// * `cmp` Comparator index. Must be less than [`PWM_CMP_NUM`](constant.PWM_CMP_NUM.html)
+	pub fn is_interrupting(&self, cmp: usize) -> bool {
+		((self.cfg >> (CFG_CMP_IP_SHIFT + cmp as u32)) & 1) == 1
+	}
+
+	/// Returns the duty cycles of comparator outputs in the range
+	/// of 0.0 to 1.0. Output pins are driven by the `pwmcmpXip` bits so
+	/// an output is high while the scaled counter is equal to or greater
+	/// than the comparator value. If `pwmzerocmp` is set comparator 0
+	/// defines the period.
+	pub fn get_duty_cycles(&self) -> [f64; PWM_CMP_NUM] {
+		let period = match (self.cfg & CFG_ZEROCMP) != 0 {
+			true => self.cmps[0] as f64 + 1.0,
+			false => (CMP_MASK as f64) + 1.0
+		};
+		let mut duty_cycles = [0.0; PWM_CMP_NUM];
+		for (duty_cycle, cmp) in duty_cycles.iter_mut().zip(self.cmps.iter()) {
+			let low = (*cmp as f64).min(period);
+			*duty_cycle = (period - low) / period;
+		}
+		duty_cycles
+	}
+
+	fn read_register(&self, offset: u64) -> u32 {
+		match offset {
+			PWMCFG => self.cfg,
+			PWMCOUNT => self.count,
+			PWMS => self.get_scaled_count(),
+			PWMCMP0..=PWMCMP3 => self.cmps[((offset - PWMCMP0) / 4) as usize],
+			_ => 0
+		}
+	}
+
+	/// Loads register content
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn load(&self, address: u64) -> u8 {
+		let offset = address - PWM_BASE;
+		let pos = (offset % 4) * 8;
+		(self.read_register(offset & !0x3) >> pos) as u8
+	}
+
+	/// Stores register content
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		let offset = address - PWM_BASE;
+		let pos = (offset % 4) * 8;
+		let mask = 0xff << pos;
+		let data = (value as u32) << pos;
+		match offset & !0x3 {
+			PWMCFG => self.cfg = (self.cfg & !mask) | data,
+			PWMCOUNT => self.count = ((self.count & !mask) | data) & COUNT_MASK,
+			PWMCMP0..=PWMCMP3 => {
+				let index = ((offset - PWMCMP0) / 4) as usize;
+				self.cmps[index] = ((self.cmps[index] & !mask) | data) & CMP_MASK;
+			},
+			_ => {}
+		};
+	}
+}
+
+impl Default for Pwm {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod test_pwm {
+	use super::*;
+
+	fn store_word(pwm: &mut Pwm, offset: u64, value: u32) {
+		for i in 0..4 {
+			pwm.store(PWM_BASE + offset + i, (value >> (i * 8)) as u8);
+		}
+	}
+
+	fn load_word(pwm: &Pwm, offset: u64) -> u32 {
+		(0..4).fold(0, |word, i| word | ((pwm.load(PWM_BASE + offset + i) as u32) << (i * 8)))
+	}
+
+	// Comparator 0 resets the counter and the others are out of reach
+	fn create_pwm(cmp0: u32, cfg: u32) -> Pwm {
+		let mut pwm = Pwm::new();
+		store_word(&mut pwm, PWMCMP0, cmp0);
+		for i in 1..PWM_CMP_NUM as u64 {
+			store_word(&mut pwm, PWMCMP0 + i * 4, CMP_MASK);
+		}
+		store_word(&mut pwm, PWMCFG, cfg);
+		pwm
+	}
+
+	#[test]
+	fn duty_cycles() {
+		let mut pwm = create_pwm(0x4000, 0);
+		assert_eq!(0.75, pwm.get_duty_cycles()[0]);
+		// Comparator 0 defines the period with pwmzerocmp
+		store_word(&mut pwm, PWMCMP0, 99);
+		store_word(&mut pwm, PWMCMP0 + 4, 25);
+		store_word(&mut pwm, PWMCMP0 + 8, 200);
+		store_word(&mut pwm, PWMCFG, CFG_ZEROCMP);
+		assert_eq!([0.01, 0.75, 0.0], pwm.get_duty_cycles()[..3]);
+	}
+
+	#[test]
+	fn comparator_interrupts() {
+		let mut pwm = create_pwm(5, CFG_ENALWAYS | CFG_ZEROCMP);
+		store_word(&mut pwm, PWMCMP0 + 4, 3);
+		pwm.tick();
+		pwm.tick();
+		assert!((0..PWM_CMP_NUM).all(|i| !pwm.is_interrupting(i)));
+		pwm.tick();
+		assert_eq!(0x2, load_word(&pwm, PWMCFG) >> CFG_CMP_IP_SHIFT);
+		assert!(pwm.is_interrupting(1));
+		// The counter is reset when it reaches comparator 0
+		pwm.tick();
+		pwm.tick();
+		assert_eq!(0x3, load_word(&pwm, PWMCFG) >> CFG_CMP_IP_SHIFT);
+		assert_eq!(0, load_word(&pwm, PWMCOUNT));
+		// The pending bits follow the comparisons unless sticky
+		pwm.tick();
+		assert_eq!(0, load_word(&pwm, PWMCFG) >> CFG_CMP_IP_SHIFT);
+		store_word(&mut pwm, PWMCFG, CFG_ENALWAYS | CFG_ZEROCMP | CFG_STICKY);
+		for _ in 0..4 {
+			pwm.tick();
+		}
+		assert_eq!(0x3, load_word(&pwm, PWMCFG) >> CFG_CMP_IP_SHIFT);
+		pwm.tick();
+		assert_eq!(1, load_word(&pwm, PWMCOUNT));
+		assert_eq!(0x3, load_word(&pwm, PWMCFG) >> CFG_CMP_IP_SHIFT);
+		// and cleared by the guest
+		store_word(&mut pwm, PWMCFG, CFG_ENALWAYS | CFG_ZEROCMP | CFG_STICKY);
+		assert!(!pwm.is_interrupting(0));
+	}
+
+	#[test]
+	fn scale() {
+		let mut pwm = create_pwm(CMP_MASK, CFG_ENALWAYS | 2);
+		for _ in 0..9 {
+			pwm.tick();
+		}
+		assert_eq!(9, load_word(&pwm, PWMCOUNT));
+		assert_eq!(2, load_word(&pwm, PWMS));
+	}
+
+	#[test]
+	fn oneshot() {
+		let mut pwm = create_pwm(2, CFG_ENONESHOT | CFG_ZEROCMP);
+		pwm.tick();
+		pwm.tick();
+		// One cycle of the counter clears pwmenoneshot
+		assert_eq!(0, load_word(&pwm, PWMCFG) & CFG_ENONESHOT);
+		assert!(pwm.is_interrupting(0));
+		pwm.tick();
+		assert_eq!(0, load_word(&pwm, PWMCOUNT));
+		// Runs again when set again
+		store_word(&mut pwm, PWMCFG, CFG_ENONESHOT | CFG_ZEROCMP);
+		pwm.tick();
+		assert_eq!(1, load_word(&pwm, PWMCOUNT));
+	}
+}
@@ -0,0 +1,157 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Base address of `SharedMemory` region
+pub const SHARED_MEMORY_BASE: u64 = 0x40000000;
+
+/// Maximum size of `SharedMemory` region, up to main memory
+pub const SHARED_MEMORY_MAX_SIZE: u64 = 0x40000000;
+
+/// Base address of `SharedMemory` doorbell registers
+pub const SHARED_MEMORY_REGISTERS_BASE: u64 = 0x10005000;
+
+/// Size of `SharedMemory` doorbell registers
+pub const SHARED_MEMORY_REGISTERS_SIZE: u64 = 0x100;
+
+// Registers. Similar to ivshmem of QEMU.
+// Interrupt vectors enabled as bits
+const INTERRUPT_MASK: u64 = 0x00;
+// Interrupt vectors rung by the host as bits. Writing one clears the bit.
+const INTERRUPT_STATUS: u64 = 0x04;
+// Writing a value rings the host
+const DOORBELL: u64 = 0x08;
+
+/// Callback invoked with the value the guest writes to the doorbell
+/// register and the shared memory region
+pub type DoorbellCallback = Box<dyn FnMut(u32, &mut [u8])>;
+
+/// Emulates a shared memory device like ivshmem. The host allocated region
+/// is mapped to the guest physical address space, so the host and the guest
+/// exchange data in place. With the doorbell, the host interrupts the guest
+/// with `ring()` and the guest rings the host by writing the doorbell
+/// register, which invokes the callback. Zero size region is seen as no device.
+pub struct SharedMemory {
+	data: Vec<u8>,
+	doorbell: bool,
+	interrupt_mask: u32,
+	interrupt_status: u32,
+	/// Doorbell register content being written
+	doorbell_value: u32,
+	callback: Option<DoorbellCallback>
+}
+
+impl SharedMemory {
+	/// Creates a new `SharedMemory`.
+	///
+	/// # Arguments
+	/// * `size` Region size in bytes
+	/// * `doorbell` Whether doorbell registers and interrupt exist
+	pub fn new(size: u64, doorbell: bool) -> Self {
+		debug_assert!(size <= SHARED_MEMORY_MAX_SIZE, "Shared memory size must be up to 1GiB. {:X}", size);
+		SharedMemory {
+			data: vec![0; size as usize],
+			doorbell,
+			interrupt_mask: 0,
+			interrupt_status: 0,
+			doorbell_value: 0,
+			callback: None
+		}
+	}
+
+	/// Returns the shared memory region.
+	pub fn get_data(&self) -> &[u8] {
+		&self.data
+	}
+
+	/// Returns the shared memory region to write.
+	pub fn get_mut_data(&mut self) -> &mut [u8] {
+		&mut self.data
+	}
+
+	/// Registers a callback invoked when the guest rings the doorbell.
+	///
+	/// # Arguments
+	/// * `callback`
+	pub fn set_doorbell_callback(&mut self, callback: DoorbellCallback) {
+		self.callback = Some(callback);
+	}
+
+	/// Rings the guest. The interrupt is raised if the vector is enabled
+	/// in the mask register, and kept until the guest clears the status.
+	///
+	/// # Arguments
+	/// * `vector` 0-31
+	pub fn ring(&mut self, vector: u32) {
+		self.interrupt_status |= 1 << (vector % 32);
+	}
+
+	/// Indicates whether `SharedMemory` raises an interrupt signal.
+	/// The signal is "Level-triggered".
+	pub fn is_interrupting(&self) -> bool {
+		self.doorbell && (self.interrupt_status & self.interrupt_mask) != 0
+	}
+
+	/// Indicates whether the address is in the shared memory region.
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn contains(&self, address: u64) -> bool {
+		address >= SHARED_MEMORY_BASE && address < SHARED_MEMORY_BASE + self.data.len() as u64
+	}
+
+	/// Indicates whether the address is in the doorbell registers.
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn contains_registers(&self, address: u64) -> bool {
+		self.doorbell && (SHARED_MEMORY_REGISTERS_BASE..SHARED_MEMORY_REGISTERS_BASE + SHARED_MEMORY_REGISTERS_SIZE).contains(&address)
+	}
+
+	/// Loads a byte of the region or the registers
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn load(&self, address: u64) -> u8 {
+		if address >= SHARED_MEMORY_BASE {
+			return self.data[(address - SHARED_MEMORY_BASE) as usize];
+		}
+		let offset = address - SHARED_MEMORY_REGISTERS_BASE;
+		let pos = (offset % 4) * 8;
+		let value = match offset & !0x3 {
+			INTERRUPT_MASK => self.interrupt_mask,
+			INTERRUPT_STATUS => self.interrupt_status,
+			_ => 0
+		};
+		(value >> pos) as u8
+	}
+
+	/// Stores a byte to the region or the registers
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		if address >= SHARED_MEMORY_BASE {
+			self.data[(address - SHARED_MEMORY_BASE) as usize] = value;
+			return;
+		}
+		let offset = address - SHARED_MEMORY_REGISTERS_BASE;
+		let pos = (offset % 4) * 8;
+		let mask = !(0xff << pos);
+		let data = (value as u32) << pos;
+		match offset & !0x3 {
+			INTERRUPT_MASK => self.interrupt_mask = (self.interrupt_mask & mask) | data,
+			INTERRUPT_STATUS => self.interrupt_status &= !data,
+			DOORBELL => {
+				self.doorbell_value = (self.doorbell_value & mask) | data;
+				// The host is rung when the highest byte is written
+				if (offset % 4) == 3 {
+					if let Some(callback) = self.callback.as_mut() {
+						callback(self.doorbell_value, &mut self.data);
+					}
+				}
+			},
+			_ => {}
+		};
+	}
+}
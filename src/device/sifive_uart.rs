@@ -0,0 +1,168 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use terminal::Terminal;
+
+// Based on SiFive FU540-C000 Manual, Chapter 13 UART
+// https://sifive.cdn.prismic.io/sifive/d3ed5cd0-6e74-46b2-a12d-72b06706513e_fu540-c000-manual-v1p4.pdf
+
+/// Base address of `SifiveUart` registers
+pub const SIFIVE_UART_BASE: u64 = 0x10010000;
+
+const TXDATA: u64 = 0x00;
+const RXDATA: u64 = 0x04;
+const TXCTRL: u64 = 0x08;
+const RXCTRL: u64 = 0x0c;
+const IE: u64 = 0x10;
+const IP: u64 = 0x14;
+const DIV: u64 = 0x18;
+
+const FIFO_DEPTH: usize = 8;
+
+const DATA_FULL_OR_EMPTY: u32 = 0x80000000;
+const CTRL_ENABLE: u32 = 0x1;
+const IP_TXWM: u32 = 0x1;
+const IP_RXWM: u32 = 0x2;
+
+/// Emulates SiFive UART. Input/Output data is transferred via `Terminal`
+/// at the same timing as [`Uart`](../uart/struct.Uart.html).
+pub struct SifiveUart {
+	clock: u64,
+	tx_fifo: Vec<u8>,
+	rx_fifo: Vec<u8>,
+	txctrl: u32,
+	rxctrl: u32,
+	ie: u32,
+	div: u32,
+	/// `rxdata` popped by a load of the lowest byte. Upper bytes
+	/// are read from this so that a word load sees consistent data.
+	rxdata_latch: u32,
+	terminal: Box<dyn Terminal>
+}
+
+impl SifiveUart {
+	/// Creates a new `SifiveUart`. Input/Output data is transferred via `Terminal`.
+	pub fn new(terminal: Box<dyn Terminal>) -> Self {
+		SifiveUart {
+			clock: 0,
+			tx_fifo: vec![],
+			rx_fifo: vec![],
+			txctrl: 0,
+			rxctrl: 0,
+			ie: 0,
+			div: 0,
+			rxdata_latch: DATA_FULL_OR_EMPTY,
+			terminal
+		}
+	}
+
+	/// Runs one cycle. `SifiveUart` gets/puts input/output data via `Terminal`
+	/// at certain timing.
+	pub fn tick(&mut self) {
+		self.clock = self.clock.wrapping_add(1);
+
+		// 0x38400 is just an arbitary number @TODO: Fix me
+		if self.clock.is_multiple_of(0x38400) && (self.rxctrl & CTRL_ENABLE) != 0 &&
+			self.rx_fifo.len() < FIFO_DEPTH && self.terminal.has_input() {
+			// Fills up the FIFO with the input available so far
+			let mut buffer = [0; FIFO_DEPTH];
+			let size = self.terminal.get_input_bytes(&mut buffer[..FIFO_DEPTH - self.rx_fifo.len()]);
+			self.rx_fifo.extend_from_slice(&buffer[..size]);
+		}
+
+		// Drains the FIFO at once unless the terminal can't accept output
+		// 0x10 is just an arbitary number @TODO: Fix me
+		if self.clock.is_multiple_of(0x10) && (self.txctrl & CTRL_ENABLE) != 0 &&
+			!self.tx_fifo.is_empty() && self.terminal.is_output_ready() {
+			self.terminal.put_bytes(&self.tx_fifo);
+			self.tx_fifo.clear();
+		}
+	}
+
+	fn get_ip(&self) -> u32 {
+		let txcnt = ((self.txctrl >> 16) & 0x7) as usize;
+		let rxcnt = ((self.rxctrl >> 16) & 0x7) as usize;
+		let mut ip = 0;
+		if self.tx_fifo.len() < txcnt {
+			ip |= IP_TXWM;
+		}
+		if self.rx_fifo.len() > rxcnt {
+			ip |= IP_RXWM;
+		}
+		ip
+	}
+
+	/// Indicates whether `SifiveUart` raises an interrupt signal.
+	/// Unlike [`Uart`](../uart/struct.Uart.html) the signal is "Level-triggered".
+	pub fn is_interrupting(&self) -> bool {
+		(self.get_ip() & self.ie) != 0
+	}
+
+	fn read_register(&self, offset: u64) -> u32 {
+		match offset {
+			TXDATA => match self.tx_fifo.len() >= FIFO_DEPTH {
+				true => DATA_FULL_OR_EMPTY,
+				false => 0
+			},
+			RXDATA => match self.rx_fifo.first() {
+				Some(value) => *value as u32,
+				None => DATA_FULL_OR_EMPTY
+			},
+			TXCTRL => self.txctrl,
+			RXCTRL => self.rxctrl,
+			IE => self.ie,
+			IP => self.get_ip(),
+			DIV => self.div,
+			_ => 0
+		}
+	}
+
+	/// Loads register content
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn load(&mut self, address: u64) -> u8 {
+		let offset = address - SIFIVE_UART_BASE;
+		let pos = (offset % 4) * 8;
+		match offset {
+			// Loading the lowest byte of rxdata pops the data from FIFO
+			RXDATA => {
+				self.rxdata_latch = self.read_register(RXDATA);
+				if !self.rx_fifo.is_empty() {
+					self.rx_fifo.remove(0);
+				}
+				self.rxdata_latch as u8
+			},
+			0x05..=0x07 => (self.rxdata_latch >> pos) as u8,
+			_ => (self.read_register(offset & !0x3) >> pos) as u8
+		}
+	}
+
+	/// Stores register content
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		let offset = address - SIFIVE_UART_BASE;
+		let pos = (offset % 4) * 8;
+		let mask = 0xff << pos;
+		let data = (value as u32) << pos;
+		match offset & !0x3 {
+			// Only the lowest byte holds data. Data is dropped if FIFO is full.
+			TXDATA if offset == TXDATA && self.tx_fifo.len() < FIFO_DEPTH => {
+				self.tx_fifo.push(value);
+			},
+			TXCTRL => self.txctrl = (self.txctrl & !mask) | data,
+			RXCTRL => self.rxctrl = (self.rxctrl & !mask) | data,
+			IE => self.ie = (self.ie & !mask) | data,
+			DIV => self.div = (self.div & !mask) | data,
+			_ => {}
+		};
+	}
+
+	/// Returns mutable reference to `Terminal`.
+	pub fn get_mut_terminal(&mut self) -> &mut Box<dyn Terminal> {
+		&mut self.terminal
+	}
+}
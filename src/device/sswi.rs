@@ -0,0 +1,76 @@
+use cpu::MIP_SSIP;
+
+// Based on RISC-V Advanced Core Local Interruptor Specification
+// https://github.com/riscvarchive/riscv-aclint
+
+/// Base address of `Sswi` registers
+pub const SSWI_BASE: u64 = 0x02f00000;
+
+/// Size of `Sswi` memory region. Up to 4095 harts have their own
+/// SETSSIP register but only the first hart exists so far.
+pub const SSWI_SIZE: u64 = 0x4000;
+
+/// Emulates ACLINT Supervisor-level Software Interrupt Device (SSWI).
+/// S-mode sends inter-processor interrupts by writing one to SETSSIP
+/// register of the target hart without calling SBI.
+pub struct Sswi {
+	/// Whether SETSSIP register of the hart has been written one
+	/// since the last `tick()`
+	pending: bool
+}
+
+impl Sswi {
+	/// Creates a new `Sswi`.
+	pub fn new() -> Self {
+		Sswi {
+			pending: false
+		}
+	}
+
+	/// Runs one cycle. Rises supervisor software interrupt bit of
+	/// `mip` if SETSSIP register has been written one. The bit is
+	/// edge-triggered and cleared by software.
+	///
+	/// # Arguments
+	/// * `mip`
+	pub fn tick(&mut self, mip: &mut u64) {
+		if self.pending {
+			*mip |= MIP_SSIP;
+			self.pending = false;
+		}
+	}
+
+	/// Indicates whether the address is in `Sswi` registers.
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn contains(&self, address: u64) -> bool {
+		(SSWI_BASE..SSWI_BASE + SSWI_SIZE).contains(&address)
+	}
+
+	/// Loads register content. SETSSIP registers always read zero.
+	///
+	/// # Arguments
+	/// * `_address`
+	pub fn load(&self, _address: u64) -> u8 {
+		0
+	}
+
+	/// Stores register content. Writing one to the least significant bit
+	/// of SETSSIP register of the hart raises the interrupt.
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		if address == SSWI_BASE && (value & 1) == 1 {
+			self.pending = true;
+		}
+	}
+}
+
+impl Default for Sswi {
+	fn default() -> Self {
+		Self::new()
+	}
+}
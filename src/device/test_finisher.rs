@@ -0,0 +1,99 @@
+// Compatible with the test device of QEMU virt machine, sifive,test1
+// https://github.com/qemu/qemu/blob/master/hw/misc/sifive_test.c
+
+/// Base address of `TestFinisher` register
+pub const TEST_FINISHER_BASE: u64 = 0x100000;
+
+/// Size of `TestFinisher` memory region
+pub const TEST_FINISHER_SIZE: u64 = 0x1000;
+
+/// Value of the status field telling the test has passed, also written
+/// on power off
+pub const FINISHER_PASS: u16 = 0x5555;
+
+/// Value of the status field telling the test has failed with the code
+/// in the upper 16 bits
+pub const FINISHER_FAIL: u16 = 0x3333;
+
+/// Emulates SiFive test finisher which bare metal tests and SBI firmware
+/// write to exit the machine. The lower 16 bits of the 32-bit register
+/// are the status and the upper ones are the exit code on failure. The
+/// machine powers off with status code zero on pass, and with the code
+/// on failure. Reset isn't supported so the request, 0x7777, is ignored.
+pub struct TestFinisher {
+	/// Register value being written byte by byte
+	value: u32,
+	exit_code: Option<u64>
+}
+
+impl TestFinisher {
+	/// Creates a new `TestFinisher`.
+	pub fn new() -> Self {
+		TestFinisher {
+			value: 0,
+			exit_code: None
+		}
+	}
+
+	/// Indicates whether the address is in `TestFinisher` register.
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn contains(&self, address: u64) -> bool {
+		(TEST_FINISHER_BASE..TEST_FINISHER_BASE + TEST_FINISHER_SIZE).contains(&address)
+	}
+
+	/// Loads register content. It always reads zero.
+	///
+	/// # Arguments
+	/// * `_address`
+	pub fn load(&self, _address: u64) -> u8 {
+		0
+	}
+
+	/// Stores register content. The register takes effect when the status
+	/// field has been written by a halfword or word store, whose bytes come
+	/// from the lowest one. SBI firmware writes only the status field.
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		let offset = address - TEST_FINISHER_BASE;
+		let shift = offset * 8;
+		self.value = match offset {
+			// A new store clears the code from the last one
+			0 => value as u32,
+			1..=3 => (self.value & !(0xff << shift)) | ((value as u32) << shift),
+			_ => return
+		};
+		if offset == 1 || offset == 3 {
+			let code = (self.value >> 16) as u64;
+			match self.value as u16 {
+				FINISHER_PASS => self.exit_code = Some(0),
+				FINISHER_FAIL => self.exit_code = Some(code),
+				_ => {}
+			};
+		}
+	}
+
+	/// Returns the status code if the machine has powered off through
+	/// the register.
+	pub fn get_exit_code(&self) -> Option<u64> {
+		self.exit_code
+	}
+
+	/// Sets the status code, e.g. on restoring a snapshot.
+	///
+	/// # Arguments
+	/// * `exit_code` `None` for running
+	pub fn set_exit_code(&mut self, exit_code: Option<u64>) {
+		self.exit_code = exit_code;
+	}
+}
+
+impl Default for TestFinisher {
+	fn default() -> Self {
+		Self::new()
+	}
+}
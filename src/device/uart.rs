@@ -1,5 +1,20 @@
+use alloc::boxed::Box;
+
 use terminal::Terminal;
 
+/// Base address of the console `Uart` registers
+pub const UART_BASE: u64 = 0x10000000;
+
+/// Base address of the registers of the first serial port added besides
+/// the console. Serial ports are placed every `UART_SIZE` bytes.
+pub const SERIAL_PORT_BASE: u64 = 0x10006000;
+
+/// Size of `Uart` registers
+pub const UART_SIZE: u64 = 0x100;
+
+/// Maximum number of serial ports added besides the console
+pub const MAX_SERIAL_PORT_NUM: usize = 4;
+
 const IER_RXINT_BIT: u8 = 0x1;
 const IER_THREINT_BIT: u8 = 0x2;
 
@@ -13,6 +28,7 @@ const LSR_THR_EMPTY: u8 = 0x20;
 /// Emulates UART. Refer to the [specification](http://www.ti.com/lit/ug/sprugp1/sprugp1.pdf)
 /// for the detail.
 pub struct Uart {
+	base: u64,
 	clock: u64,
 	rbr: u8, // receiver buffer register
 	thr: u8, // transmitter holding register
@@ -30,7 +46,17 @@ pub struct Uart {
 impl Uart {
 	/// Creates a new `Uart`. Input/Output data is transferred via `Terminal`.
 	pub fn new(terminal: Box<dyn Terminal>) -> Self {
+		Self::new_with_base(UART_BASE, terminal)
+	}
+
+	/// Creates a new `Uart` whose registers are placed at `base`.
+	///
+	/// # Arguments
+	/// * `base`
+	/// * `terminal`
+	pub fn new_with_base(base: u64, terminal: Box<dyn Terminal>) -> Self {
 		Uart {
+			base,
 			clock: 0,
 			rbr: 0,
 			thr: 0,
@@ -54,10 +80,10 @@ impl Uart {
 
 		// Reads input.
 		// 0x38400 is just an arbitary number @TODO: Fix me
-		if (self.clock % 0x38400) == 0 && self.rbr == 0 {
-			let value = self.terminal.get_input();
-			if value != 0 {
-				self.rbr = value;
+		if (self.clock % 0x38400) == 0 && self.rbr == 0 && self.terminal.has_input() {
+			let mut value = [0; 1];
+			if self.terminal.get_input_bytes(&mut value) > 0 {
+				self.rbr = value[0];
 				self.lsr |= LSR_DATA_AVAILABLE;
 				self.update_iir();
 				if (self.ier & IER_RXINT_BIT) != 0 {
@@ -68,7 +94,8 @@ impl Uart {
 
 		// Writes output.
 		// 0x10 is just an arbitary number @TODO: Fix me
-		if (self.clock % 0x10) == 0 && self.thr != 0 {
+		// THR is kept full while the terminal can't accept output
+		if (self.clock % 0x10) == 0 && self.thr != 0 && self.terminal.is_output_ready() {
 			self.terminal.put_byte(self.thr);
 			self.thr = 0;
 			self.lsr |= LSR_THR_EMPTY;
@@ -119,8 +146,8 @@ impl Uart {
 	/// * `address`
 	pub fn load(&mut self, address: u64) -> u8 {
 		//println!("UART Load AD:{:X}", address);
-		match address {
-			0x10000000 => match (self.lcr >> 7) == 0 {
+		match address - self.base {
+			0 => match (self.lcr >> 7) == 0 {
 				true => {
 					let rbr = self.rbr;
 					self.rbr = 0;
@@ -130,15 +157,15 @@ impl Uart {
 				},
 				false => 0 // @TODO: Implement properly
 			},
-			0x10000001 => match (self.lcr >> 7) == 0 {
+			1 => match (self.lcr >> 7) == 0 {
 				true => self.ier,
 				false => 0 // @TODO: Implement properly
 			},
-			0x10000002 => self.iir,
-			0x10000003 => self.lcr,
-			0x10000004 => self.mcr,
-			0x10000005 => self.lsr,
-			0x10000007 => self.scr,
+			2 => self.iir,
+			3 => self.lcr,
+			4 => self.mcr,
+			5 => self.lsr,
+			7 => self.scr,
 			_ => 0
 		}
 	}
@@ -150,9 +177,9 @@ impl Uart {
 	/// * `value`
 	pub fn store(&mut self, address: u64, value: u8) {
 		//println!("UART Store AD:{:X} VAL:{:X}", address, value);
-		match address {
+		match address - self.base {
 			// Transfer Holding Register
-			0x10000000 => match (self.lcr >> 7) == 0 {
+			0 => match (self.lcr >> 7) == 0 {
 				true => {
 					self.thr = value;
 					self.lsr &= !LSR_THR_EMPTY;
@@ -160,7 +187,7 @@ impl Uart {
 				},
 				false => {} // @TODO: Implement properly
 			},
-			0x10000001 => match (self.lcr >> 7) == 0 {
+			1 => match (self.lcr >> 7) == 0 {
 				true => {
 					// This bahavior isn't written in the data sheet
 					// but some drivers seem to rely on it.
@@ -174,13 +201,13 @@ impl Uart {
 				},
 				false => {} // @TODO: Implement properly
 			},
-			0x10000003 => {
+			3 => {
 				self.lcr = value;
 			},
-			0x10000004 => {
+			4 => {
 				self.mcr = value;
 			},
-			0x10000007 => {
+			7 => {
 				self.scr = value;
 			},
 			_ => {}
@@ -0,0 +1,232 @@
+use mmu::{MemoryWrapper, DRAM_BASE};
+use config::VirtioTransport;
+use device::virtio_mmio::{VirtioMmio, VirtioMmioRequest, CONFIG};
+use device::virtqueue::VIRTQ_DESC_F_WRITE;
+
+// Based on Virtual I/O Device (VIRTIO) Version 1.1, 5.5 Traditional Memory Balloon Device
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html
+
+/// Base address of `VirtioBalloon` registers
+pub const VIRTIO_BALLOON_BASE: u64 = 0x10004000;
+
+/// Size of the page the balloon counts in, regardless of the guest page size
+pub const BALLOON_PAGE_SIZE: u64 = 4096;
+
+const MAX_QUEUE_SIZE: u32 = 0x100;
+const QUEUE_NUM_TOTAL: usize = 2;
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+
+// The guest deflates the balloon under memory pressure instead of OOM kill
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 1 << 2;
+
+// Configurations
+const NUM_PAGES: u64 = CONFIG;
+const ACTUAL: u64 = CONFIG + 4;
+
+/// Emulates Virtio Memory Balloon device on [`VirtioMmio`](../virtio_mmio/struct.VirtioMmio.html)
+/// transport. The host sets the target number of pages the guest gives up
+/// and the guest inflates or deflates the balloon toward it. Host memory
+/// of the pages put in the balloon is released because main memory is
+/// allocated sparsely. Unless enabled the device is seen as an empty
+/// virtio slot.
+pub struct VirtioBalloon {
+	transport: VirtioMmio,
+	/// Number of pages the host wants in the balloon
+	num_pages: u32,
+	/// Number of pages in the balloon reported by the guest
+	actual: u32,
+	/// Queues notified by the driver as bits
+	notified_queues: u32
+}
+
+impl VirtioBalloon {
+	/// Creates a new `VirtioBalloon`.
+	///
+	/// # Arguments
+	/// * `transport` Virtio MMIO register layout
+	/// * `enabled` Whether the device is seen by the guest
+	pub fn new(transport: &VirtioTransport, enabled: bool) -> Self {
+		// 5 (Memory balloon device) or 0 (Empty slot)
+		let device_id = match enabled {
+			true => 5,
+			false => 0
+		};
+		VirtioBalloon {
+			transport: VirtioMmio::new(transport, device_id, VIRTIO_BALLOON_F_DEFLATE_ON_OOM, QUEUE_NUM_TOTAL, MAX_QUEUE_SIZE),
+			num_pages: 0,
+			actual: 0,
+			notified_queues: 0
+		}
+	}
+
+	/// Sets the number of pages the host wants the guest to give up.
+	/// The guest is notified with a configuration change interrupt.
+	///
+	/// # Arguments
+	/// * `num_pages` In `BALLOON_PAGE_SIZE` pages
+	pub fn set_num_pages(&mut self, num_pages: u32) {
+		self.num_pages = num_pages;
+		self.transport.notify_config_change();
+	}
+
+	/// Returns the number of pages the guest reports in the balloon.
+	pub fn get_actual_pages(&self) -> u32 {
+		self.actual
+	}
+
+	/// Indicates whether `VirtioBalloon` raises an interrupt signal.
+	/// The signal is "Level-triggered".
+	pub fn is_interrupting(&self) -> bool {
+		self.transport.is_interrupting()
+	}
+
+	/// Runs one cycle. Releases the pages put in the balloon.
+	///
+	/// # Arguments
+	/// * `memory`
+	pub fn tick(&mut self, memory: &mut MemoryWrapper) {
+		if self.notified_queues == 0 || !self.transport.is_driver_ok() {
+			return;
+		}
+		if (self.notified_queues & (1 << INFLATE_QUEUE)) != 0 {
+			self.handle_queue(memory, INFLATE_QUEUE);
+		}
+		// Deflated pages need nothing. They are allocated again
+		// when the guest writes.
+		if (self.notified_queues & (1 << DEFLATE_QUEUE)) != 0 {
+			self.handle_queue(memory, DEFLATE_QUEUE);
+		}
+		self.notified_queues = 0;
+	}
+
+	fn handle_queue(&mut self, memory: &mut MemoryWrapper, queue: usize) {
+		while let Some(head) = self.transport.get_mut_queue(queue).pop_avail(memory) {
+			if queue == INFLATE_QUEUE {
+				// Buffers are arrays of 32-bit page frame numbers
+				for (addr, len, flags) in self.transport.get_queue(queue).read_desc_chain(memory, head) {
+					if (flags & VIRTQ_DESC_F_WRITE) != 0 {
+						continue;
+					}
+					for i in 0..(len / 4) as u64 {
+						let address = memory.read_word(addr.wrapping_add(i * 4)) as u64 * BALLOON_PAGE_SIZE;
+						if address >= DRAM_BASE {
+							memory.release_page(address);
+						}
+					}
+				}
+			}
+			self.transport.get_mut_queue(queue).push_used(memory, head, 0);
+			self.transport.notify_used_buffer();
+		}
+	}
+
+	/// Loads register content
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn load(&self, address: u64) -> u8 {
+		let offset = address - VIRTIO_BALLOON_BASE;
+		if offset < CONFIG {
+			return self.transport.load(offset);
+		}
+		let pos = (offset % 4) * 8;
+		match offset & !0x3 {
+			NUM_PAGES => (self.num_pages >> pos) as u8,
+			ACTUAL => (self.actual >> pos) as u8,
+			_ => 0
+		}
+	}
+
+	/// Stores register content
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		let offset = address - VIRTIO_BALLOON_BASE;
+		if offset < CONFIG {
+			match self.transport.store(offset, value) {
+				Some(VirtioMmioRequest::Notify(queue)) if (queue as usize) < QUEUE_NUM_TOTAL => {
+					self.notified_queues |= 1 << queue;
+				},
+				Some(VirtioMmioRequest::Reset) => self.notified_queues = 0,
+				_ => {}
+			};
+			return;
+		}
+		// Only actual is writable by the driver
+		if (offset & !0x3) == ACTUAL {
+			let pos = (offset % 4) * 8;
+			self.actual = (self.actual & !(0xff << pos)) | ((value as u32) << pos);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test_virtio_balloon {
+	use super::*;
+	use alloc::boxed::Box;
+	use config::EmulatorConfig;
+	use cpu::Xlen;
+	use device::virtio_mmio::*;
+	use mmu::Mmu;
+	use terminal::DummyTerminal;
+
+	const QUEUE_ADDRESS: u64 = DRAM_BASE + 0x10000;
+	const BUFFER_ADDRESS: u64 = DRAM_BASE + 0x20000;
+	const PAGE_ADDRESS: u64 = DRAM_BASE + 0x40000;
+	const QUEUE_SIZE: u64 = 8;
+
+	fn store(mmu: &mut Mmu, address: u64, value: u64, size: u64) {
+		for i in 0..size {
+			mmu.store_raw(address + i, (value >> (i * 8)) as u8);
+		}
+	}
+
+	#[test]
+	fn inflate() {
+		let config = EmulatorConfig {
+			balloon: true,
+			virtio_balloon_transport: VirtioTransport::Modern,
+			..EmulatorConfig::default()
+		};
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &config);
+		mmu.init_memory(0x100000);
+		assert_eq!(5, mmu.load_word_raw(VIRTIO_BALLOON_BASE + DEVICE_ID));
+		mmu.get_mut_balloon().set_num_pages(1);
+		assert!(mmu.get_balloon().is_interrupting());
+		assert_eq!(1, mmu.load_word_raw(VIRTIO_BALLOON_BASE + NUM_PAGES));
+		store(&mut mmu, VIRTIO_BALLOON_BASE + INTERRUPT_ACK, INTERRUPT_CONFIG_CHANGE as u64, 4);
+
+		store(&mut mmu, VIRTIO_BALLOON_BASE + QUEUE_SEL, INFLATE_QUEUE as u64, 4);
+		store(&mut mmu, VIRTIO_BALLOON_BASE + QUEUE_NUM, QUEUE_SIZE, 4);
+		store(&mut mmu, VIRTIO_BALLOON_BASE + QUEUE_DESC_LOW, QUEUE_ADDRESS, 4);
+		store(&mut mmu, VIRTIO_BALLOON_BASE + QUEUE_DRIVER_LOW, QUEUE_ADDRESS + 0x100, 4);
+		store(&mut mmu, VIRTIO_BALLOON_BASE + QUEUE_DEVICE_LOW, QUEUE_ADDRESS + 0x200, 4);
+		store(&mut mmu, VIRTIO_BALLOON_BASE + QUEUE_READY, 1, 4);
+		store(&mut mmu, VIRTIO_BALLOON_BASE + STATUS, STATUS_DRIVER_OK as u64, 4);
+
+		// The guest has written the page
+		store(&mut mmu, PAGE_ADDRESS, 0x12345678, 4);
+
+		// Puts the page frame number in the inflate queue
+		store(&mut mmu, BUFFER_ADDRESS, PAGE_ADDRESS / BALLOON_PAGE_SIZE, 4);
+		store(&mut mmu, QUEUE_ADDRESS, BUFFER_ADDRESS, 8);
+		store(&mut mmu, QUEUE_ADDRESS + 8, 4, 4);
+		store(&mut mmu, QUEUE_ADDRESS + 12, 0, 4);
+		store(&mut mmu, QUEUE_ADDRESS + 0x100 + 4, 0, 2);
+		store(&mut mmu, QUEUE_ADDRESS + 0x100 + 2, 1, 2);
+		let allocated_size = mmu.get_memory_allocated_size();
+		store(&mut mmu, VIRTIO_BALLOON_BASE + QUEUE_NOTIFY, INFLATE_QUEUE as u64, 4);
+		mmu.tick(&mut 0);
+		store(&mut mmu, VIRTIO_BALLOON_BASE + ACTUAL, 1, 4);
+
+		// Used ring index
+		assert_eq!(1, mmu.load_word_raw(QUEUE_ADDRESS + 0x200 + 2) & 0xffff);
+		assert!(mmu.get_balloon().is_interrupting());
+		assert_eq!(1, mmu.get_balloon().get_actual_pages());
+		assert_eq!(0, mmu.load_word_raw(PAGE_ADDRESS));
+		assert_eq!(allocated_size - BALLOON_PAGE_SIZE, mmu.get_memory_allocated_size());
+	}
+}
@@ -1,104 +1,135 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
 use mmu::MemoryWrapper;
+use error::{DeviceError, ExecError};
+use block_backend::{BlockBackend, MemoryBlockBackend, SECTOR_SIZE};
+use config::VirtioTransport;
+use device::virtio_mmio::{VirtioMmio, VirtioMmioRequest, CONFIG};
 
 // Based on Virtual I/O Device (VIRTIO) Version 1.1
 // https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html
 
+/// Base address of `VirtioBlockDisk` registers
+pub const VIRTIO_BLOCK_BASE: u64 = 0x10001000;
+
 // 0x2000 is an arbitary number.
-const MAX_QUEUE_SIZE: u64 = 0x2000;
+const MAX_QUEUE_SIZE: u32 = 0x2000;
 
 // To simulate disk access time.
 // @TODO: Set more proper number. 500 core clocks may be too short.
 const DISK_ACCESS_DELAY: u64 = 500;
 
-const VIRTQ_DESC_F_NEXT: u16 = 1;
-
 // 0: buffer is write-only = read from disk operation
 // 1: buffer is read-only = write to disk operation
 const VIRTQ_DESC_F_WRITE: u16 = 2;
 
-const SECTOR_SIZE: u64 = 512;
+// Feature bits
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Block request type
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
 
-/// Emulates Virtio Block device. Refer to the [specification](https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html)
-/// for the detail. It follows legacy API.
+// Block request status
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// Virtio subsystem device id of block device
+const BLOCK_DEVICE_ID: u32 = 2;
+
+/// Emulates Virtio Block device on [`VirtioMmio`](../virtio_mmio/struct.VirtioMmio.html)
+/// transport. Refer to the [specification](https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html)
+/// for the detail.
 pub struct VirtioBlockDisk {
-	used_ring_index: u16,
 	clock: u64,
-	device_features: u64, // read only
-	device_features_sel: u32, // write only
-	driver_features: u32, // write only
-	_driver_features_sel: u32, // write only
-	guest_page_size: u32, // write only
-	queue_select: u32, // write only
-	queue_size: u32, // write only
-	queue_align: u32, // write only
-	queue_pfn: u32, // read and write
-	queue_notify: u32, // write only
-	interrupt_status: u32, // read only
-	status: u32, // read and write
+	transport: VirtioMmio,
 	notify_clocks: Vec::<u64>,
-	contents: Vec<u64>
+	backend: Box<dyn BlockBackend>,
+	/// Whether a disk is attached. If not, the device is seen as
+	/// an empty virtio slot whose device id is zero.
+	attached: bool
 }
 
 impl VirtioBlockDisk {
 	/// Creates a new `VirtioBlockDisk`.
-	pub fn new() -> Self {
+	///
+	/// # Arguments
+	/// * `transport` Virtio MMIO register layout
+	pub fn new(transport: &VirtioTransport) -> Self {
 		VirtioBlockDisk {
-			used_ring_index: 0,
 			clock: 0,
-			device_features: 0,
-			device_features_sel: 0,
-			driver_features: 0,
-			_driver_features_sel: 0,
-			guest_page_size: 0,
-			queue_select: 0,
-			queue_size: 0,
-			queue_align: 0x1000, // xv6 seems to expect this default value
-			queue_pfn: 0,
-			queue_notify: 0,
-			status: 0,
-			interrupt_status: 0,
+			transport: VirtioMmio::new(transport, BLOCK_DEVICE_ID, VIRTIO_BLK_F_FLUSH, 1, MAX_QUEUE_SIZE),
 			notify_clocks: Vec::new(),
-			contents: vec![]
+			backend: Box::new(MemoryBlockBackend::new(vec![])),
+			attached: true
 		}
 	}
 
 	/// Indicates whether `VirtioBlockDisk` raises an interrupt signal
 	pub fn is_interrupting(&mut self) -> bool {
-		(self.interrupt_status & 0x1) == 1
+		self.transport.is_interrupting()
 	}
 
-	/// Initializes filesystem content. The method is expected to be called
+	/// Initializes disk storage. The method is expected to be called
 	/// only up to once.
 	///
 	/// # Arguments
-	/// * `contents` filesystem content binary
-	pub fn init(&mut self, contents: Vec<u8>) {
-		// @TODO: Optimize
-		for _i in 0..((contents.len() + 7) / 8) {
-			self.contents.push(0);
-		}
-		for i in 0..contents.len() {
-			let index = (i >> 3) as usize;
-			let pos = (i % 8) * 8;
-			self.contents[index] = (self.contents[index] & !(0xff << pos)) | ((contents[i] as u64) << pos);
-		}
+	/// * `backend` Disk storage
+	pub fn init(&mut self, backend: Box<dyn BlockBackend>) {
+		self.backend = backend;
+	}
+
+	/// Attaches a disk while the guest runs, replacing the current one if
+	/// attached. The guest is notified with a configuration change interrupt.
+	///
+	/// # Arguments
+	/// * `backend` Disk storage
+	pub fn attach(&mut self, backend: Box<dyn BlockBackend>) {
+		self.init(backend);
+		self.attached = true;
+		self.transport.set_device_id(BLOCK_DEVICE_ID);
+		self.transport.notify_config_change();
+	}
+
+	/// Detaches the disk while the guest runs. The device is seen as an empty
+	/// slot afterward and requests in flight fail. If the driver is active,
+	/// the device requests reset. The guest is notified with a configuration
+	/// change interrupt.
+	pub fn detach(&mut self) {
+		let _ = self.backend.flush();
+		self.backend = Box::new(MemoryBlockBackend::new(vec![]));
+		self.attached = false;
+		self.transport.set_device_id(0);
+		self.transport.request_reset();
+	}
+
+	/// Indicates whether a disk is attached.
+	pub fn is_attached(&self) -> bool {
+		self.attached
+	}
+
+	/// Makes sure data written by the guest reaches the disk storage.
+	pub fn flush(&mut self) -> Result<(), DeviceError> {
+		self.backend.flush()
 	}
 
 	/// Runs one cycle. Data transfer between main memory and block device
-	/// can happen depending on condition.
+	/// can happen depending on condition. Returns `Err` if the guest has
+	/// made a malformed request.
 	///
 	/// # Arguments
 	/// * `memory`
-	pub fn tick(&mut self, memory: &mut MemoryWrapper) {
-		if self.notify_clocks.len() > 0 && (self.clock == self.notify_clocks[0] + DISK_ACCESS_DELAY) {
-			// bit 0 in interrupt_status register indicates
-			// the interrupt was asserted because the device has used a buffer
-			// in at least one of the active virtual queues.
-			self.interrupt_status |= 0x1;
-			self.handle_disk_access(memory);
+	pub fn tick(&mut self, memory: &mut MemoryWrapper) -> Result<(), ExecError> {
+		let mut result = Ok(());
+		if !self.notify_clocks.is_empty() && (self.clock == self.notify_clocks[0] + DISK_ACCESS_DELAY) {
+			result = self.handle_disk_access(memory);
 			self.notify_clocks.remove(0);
 		}
 		self.clock = self.clock.wrapping_add(1);
+		result
 	}
 
 	/// Loads register content
@@ -106,56 +137,14 @@ impl VirtioBlockDisk {
 	/// # Arguments
 	/// * `address`
 	pub fn load(&mut self, address: u64) -> u8 {
-		//println!("Disk Load AD:{:X}", address);
-		match address {
-			// Magic number: 0x74726976
-			0x10001000 => 0x76,
-			0x10001001 => 0x69,
-			0x10001002 => 0x72,
-			0x10001003 => 0x74,
-			// Device version: 1 (Legacy device)
-			0x10001004 => 1,
-			// Virtio Subsystem Device id: 2 (Block device)
-			0x10001008 => 2,
-			// Virtio Subsystem Vendor id: 0x554d4551
-			0x1000100c => 0x51,
-			0x1000100d => 0x45,
-			0x1000100e => 0x4d,
-			0x1000100f => 0x55,
-			// Flags representing features the device supports
-			0x10001010 => ((self.device_features >> (self.device_features_sel * 32)) & 0xff) as u8,
-			0x10001011 => (((self.device_features >> (self.device_features_sel * 32)) >> 8) & 0xff) as u8,
-			0x10001012 => (((self.device_features >> (self.device_features_sel * 32)) >> 16) & 0xff) as u8,
-			0x10001013 => (((self.device_features >> (self.device_features_sel * 32)) >> 24) & 0xff) as u8,
-			// Maximum virtual queue size
-			0x10001034 => MAX_QUEUE_SIZE as u8,
-			0x10001035 => (MAX_QUEUE_SIZE >> 8) as u8,
-			0x10001036 => (MAX_QUEUE_SIZE >> 16) as u8,
-			0x10001037 => (MAX_QUEUE_SIZE >> 24) as u8,
-			// Guest physical page number of the virtual queue
-			0x10001040 => self.queue_pfn as u8,
-			0x10001041 => (self.queue_pfn >> 8) as u8,
-			0x10001042 => (self.queue_pfn >> 16) as u8,
-			0x10001043 => (self.queue_pfn >> 24) as u8,
-			// Interrupt status
-			0x10001060 => self.interrupt_status as u8,
-			0x10001061 => (self.interrupt_status >> 8) as u8,
-			0x10001062 => (self.interrupt_status >> 16) as u8,
-			0x10001063 => (self.interrupt_status >> 24) as u8,
-			// Device status
-			0x10001070 => self.status as u8,
-			0x10001071 => (self.status >> 8) as u8,
-			0x10001072 => (self.status >> 16) as u8,
-			0x10001073 => (self.status >> 24) as u8,
-			// Configurations @TODO: Implement properly
-			0x10001100 => 0x00,
-			0x10001101 => 0x20,
-			0x10001102 => 0x03,
-			0x10001103 => 0,
-			0x10001104 => 0,
-			0x10001105 => 0,
-			0x10001106 => 0,
-			0x10001107 => 0,
+		let offset = address - VIRTIO_BLOCK_BASE;
+		if offset < CONFIG {
+			return self.transport.load(offset);
+		}
+		match offset {
+			// Configurations: Capacity in sectors
+			// @TODO: Implement the other fields
+			CONFIG..=0x107 => (self.backend.get_sector_num() >> ((offset - CONFIG) * 8)) as u8,
 			_ => 0
 		}
 	}
@@ -166,357 +155,248 @@ impl VirtioBlockDisk {
 	/// * `address`
 	/// * `value`
 	pub fn store(&mut self, address: u64, value: u8) {
-		//println!("Disk Store AD:{:X} VAL:{:X}", address, value);
-		match address {
-			0x10001014 => {
-				self.device_features_sel = (self.device_features_sel & !0xff) | (value as u32);
-			},
-			0x10001015 => {
-				self.device_features_sel = (self.device_features_sel & !(0xff << 8)) | ((value as u32) << 8);
-			},
-			0x10001016 => {
-				self.device_features_sel = (self.device_features_sel & !(0xff << 16)) | ((value as u32) << 16);			
-			},
-			0x10001017 => {
-				self.device_features_sel = (self.device_features_sel & !(0xff << 24)) | ((value as u32) << 24);
-			},
-			0x10001020 => {
-				self.driver_features = (self.driver_features & !0xff) | (value as u32);
-			},
-			0x10001021 => {
-				self.driver_features = (self.driver_features & !(0xff << 8)) | ((value as u32) << 8);
-			},
-			0x10001022 => {
-				self.driver_features = (self.driver_features & !(0xff << 16)) | ((value as u32) << 16);			
-			},
-			0x10001023 => {
-				self.driver_features = (self.driver_features & !(0xff << 24)) | ((value as u32) << 24);
-			},
-			0x10001028 => {
-				self.guest_page_size = (self.guest_page_size & !0xff) | (value as u32);
-			},
-			0x10001029 => {
-				self.guest_page_size = (self.guest_page_size & !(0xff << 8)) | ((value as u32) << 8);
-			},
-			0x1000102a => {
-				self.guest_page_size = (self.guest_page_size & !(0xff << 16)) | ((value as u32) << 16);			
-			},
-			0x1000102b => {
-				self.guest_page_size = (self.guest_page_size & !(0xff << 24)) | ((value as u32) << 24);
-			},
-			0x10001030 => {
-				self.queue_select = (self.queue_select & !0xff) | (value as u32);
-			},
-			0x10001031 => {
-				self.queue_select = (self.queue_select & !(0xff << 8)) | ((value as u32) << 8);
-			},
-			0x10001032 => {
-				self.queue_select = (self.queue_select & !(0xff << 16)) | ((value as u32) << 16);			
-			},
-			0x10001033 => {
-				self.queue_select = (self.queue_select & !(0xff << 24)) | ((value as u32) << 24);
-				if self.queue_select != 0 {
-					panic!("Virtio: No multi queue support yet.");
-				}
-			},
-			0x10001038 => {
-				self.queue_size = (self.queue_size & !0xff) | (value as u32);
-			},
-			0x10001039 => {
-				self.queue_size = (self.queue_size & !(0xff << 8)) | ((value as u32) << 8);
-			},
-			0x1000103a => {
-				self.queue_size = (self.queue_size & !(0xff << 16)) | ((value as u32) << 16);
-			},
-			0x1000103b => {
-				self.queue_size = (self.queue_size & !(0xff << 24)) | ((value as u32) << 24);
-			},
-			0x1000103c => {
-				self.queue_align = (self.queue_align & !0xff) | (value as u32);
-			},
-			0x1000103d => {
-				self.queue_align = (self.queue_align & !(0xff << 8)) | ((value as u32) << 8);
-			},
-			0x1000103e => {
-				self.queue_align = (self.queue_align & !(0xff << 16)) | ((value as u32) << 16);			
-			},
-			0x1000103f => {
-				self.queue_align = (self.queue_align & !(0xff << 24)) | ((value as u32) << 24);
-			},
-			0x10001040 => {
-				self.queue_pfn = (self.queue_pfn & !0xff) | (value as u32);
-			},
-			0x10001041 => {
-				self.queue_pfn = (self.queue_pfn & !(0xff << 8)) | ((value as u32) << 8);
-			},
-			0x10001042 => {
-				self.queue_pfn = (self.queue_pfn & !(0xff << 16)) | ((value as u32) << 16);			
-			},
-			0x10001043 => {
-				self.queue_pfn = (self.queue_pfn & !(0xff << 24)) | ((value as u32) << 24);
-			},
-			// @TODO: Queue request support
-			0x10001050 => {
-				self.queue_notify = (self.queue_notify & !0xff) | (value as u32);
-			},
-			0x10001051 => {
-				self.queue_notify = (self.queue_notify & !(0xff << 8)) | ((value as u32) << 8);
-			},
-			0x10001052 => {
-				self.queue_notify = (self.queue_notify & !(0xff << 16)) | ((value as u32) << 16);			
-			},
-			0x10001053 => {
-				self.queue_notify = (self.queue_notify & !(0xff << 24)) | ((value as u32) << 24);
-				self.notify_clocks.push(self.clock);
-			},
-			0x10001064 => {
-				// interrupt ack
-				if (value & 0x1) == 1 {
-					self.interrupt_status &= !0x1;
-				} else {
-					panic!("Unknown ack {:X}", value);
-				}
-			},
-			0x10001070 => {
-				self.status = (self.status & !0xff) | (value as u32);
-			},
-			0x10001071 => {
-				self.status = (self.status & !(0xff << 8)) | ((value as u32) << 8);
-			},
-			0x10001072 => {
-				self.status = (self.status & !(0xff << 16)) | ((value as u32) << 16);			
-			},
-			0x10001073 => {
-				self.status = (self.status & !(0xff << 24)) | ((value as u32) << 24);
-			},
+		let offset = address - VIRTIO_BLOCK_BASE;
+		if offset >= CONFIG {
+			return;
+		}
+		match self.transport.store(offset, value) {
+			Some(VirtioMmioRequest::Notify(0)) => self.notify_clocks.push(self.clock),
+			Some(VirtioMmioRequest::Reset) => self.notify_clocks.clear(),
 			_ => {}
 		};
 	}
 
-	/// Fast path of transferring the data from disk to memory.
+	/// Transfers the data read from disk to memory.
 	///
 	/// # Arguments
 	/// * `memory`
-	/// * `mem_addresss` Physical address. Must be eight-byte aligned.
-	/// * `disk_address` Must be eight-byte aligned.
-	/// * `length` Must be eight-byte aligned.
-	fn transfer_from_disk(&mut self, memory: &mut MemoryWrapper, mem_address: u64, disk_address: u64, length: u64) {
-		debug_assert!((mem_address % 8) == 0, "Memory address should be eight-byte aligned. {:X}", mem_address);
-		debug_assert!((disk_address % 8) == 0, "Disk address should be eight-byte aligned. {:X}", disk_address);
-		debug_assert!((length % 8) == 0, "Length should be eight-byte aligned. {:X}", length);
-		for i in 0..(length / 8) {
-			let disk_index = ((disk_address + i * 8) >> 3) as usize;
-			memory.write_doubleword(mem_address + i * 8, self.contents[disk_index]);
+	/// * `mem_addresss` Physical address
+	/// * `data`
+	fn transfer_to_memory(memory: &mut MemoryWrapper, mem_address: u64, data: &[u8]) {
+		if mem_address.is_multiple_of(8) && data.len().is_multiple_of(8) {
+			// Enter fast path if possible
+			for (i, chunk) in data.chunks(8).enumerate() {
+				let mut value = 0;
+				for (j, byte) in chunk.iter().enumerate() {
+					value |= (*byte as u64) << (j * 8);
+				}
+				memory.write_doubleword(mem_address + i as u64 * 8, value);
+			}
+		} else {
+			for (i, byte) in data.iter().enumerate() {
+				memory.write_byte(mem_address + i as u64, *byte);
+			}
 		}
 	}
 
-	/// Fast path of transferring the data from memory to disk.
+	/// Transfers the data written to disk from memory.
 	///
 	/// # Arguments
 	/// * `memory`
-	/// * `mem_addresss` Physical address. Must be eight-byte aligned.
-	/// * `disk_address` Must be eight-byte aligned.
-	/// * `length` Must be eight-byte aligned.
-	fn transfer_to_disk(&mut self, memory: &mut MemoryWrapper, mem_address: u64, disk_address: u64, length: u64) {
-		debug_assert!((mem_address % 8) == 0, "Memory address should be eight-byte aligned. {:X}", mem_address);
-		debug_assert!((disk_address % 8) == 0, "Disk address should be eight-byte aligned. {:X}", disk_address);
-		debug_assert!((length % 8) == 0, "Length should be eight-byte aligned. {:X}", length);
-		for i in 0..(length / 8) {
-			let disk_index = ((disk_address + i * 8) >> 3) as usize;
-			self.contents[disk_index] = memory.read_doubleword(mem_address + i * 8);
+	/// * `mem_addresss` Physical address
+	/// * `data`
+	fn transfer_from_memory(memory: &mut MemoryWrapper, mem_address: u64, data: &mut [u8]) {
+		if mem_address.is_multiple_of(8) && data.len().is_multiple_of(8) {
+			// Enter fast path if possible
+			for (i, chunk) in data.chunks_mut(8).enumerate() {
+				let value = memory.read_doubleword(mem_address + i as u64 * 8);
+				for (j, byte) in chunk.iter_mut().enumerate() {
+					*byte = (value >> (j * 8)) as u8;
+				}
+			}
+		} else {
+			for (i, byte) in data.iter_mut().enumerate() {
+				*byte = memory.read_byte(mem_address + i as u64);
+			}
 		}
 	}
 
-	/// Reads a byte from disk.
-	///
-	/// # Arguments
-	/// * `addresss` Address in disk
-	fn read_from_disk(&mut self, address: u64) -> u8 {
-		let index = (address >> 3) as usize;
-		let pos = (address % 8) * 8;
-		(self.contents[index] >> pos) as u8
-	}
-
-	/// Writes a byte to disk.
-	///
-	/// # Arguments
-	/// * `addresss` Address in disk
-	/// * `value` Data written to disk
-	fn write_to_disk(&mut self, address: u64, value: u8) {
-		let index = (address >> 3) as usize;
-		let pos = (address % 8) * 8;
-		self.contents[index] = (self.contents[index] & !(0xff << pos)) | ((value as u64) << pos);
-	}
-
-	fn get_page_address(&self) -> u64 {
-		self.queue_pfn as u64 * self.guest_page_size as u64
-	}
-
-	// Virtqueue layout: Starting at page address
-	//
-	// struct virtq {
-	//   struct virtq_desc desc[queue_size]; // queue_size * 16bytes
-	//   struct virtq_avail avail;           // 2 * 2bytes + queue_size * 2bytes
-	//   uint8 pad[padding];                 // until queue_align
-	//   struct virtq_used used;             // 2 * 2bytes + queue_size * 8bytes
-	// }
-	//
-	// struct virtq_desc {
-	//   uint64 addr;
-	//   uint32 len;
-	//   uint16 flags;
-	//   uint16 next;
-	// }
-	//
-	// struct virtq_avail {
-	//   uint16 flags;
-	//   uint16 idx;
-	//   uint16 ring[queue_size];
-	// }
-	//
-	// struct virtq_used {
-	//   uint16 flags;
-	//   uint16 idx;
-	//   struct virtq_used_elem ring[queue_size];
-	// }
-	//
-	// struct virtq_used_elem {
-	//   uint32 id;
-	//   uint32 len;
-	// }
-
-	fn get_base_desc_address(&self) -> u64 {
-		self.get_page_address()
-	}
-
-	fn get_base_avail_address(&self) -> u64 {
-		self.get_base_desc_address() + self.queue_size as u64 * 16
+	// @TODO: Follow the virtio block specification more propertly.
+	fn handle_disk_access(&mut self, memory: &mut MemoryWrapper) -> Result<(), ExecError> {
+		let mut result = Ok(());
+		while let Some(head) = self.transport.get_mut_queue(0).pop_avail(memory) {
+			// Descriptor chain: The first descriptor is the request header, the last one is
+			// the result status, and the ones in between are data buffers.
+			let descs = self.transport.get_queue(0).read_desc_chain(memory, head);
+			// A malformed request is returned unprocessed not to stall the queue
+			let written = match self.handle_request(memory, &descs) {
+				Ok(written) => written,
+				Err(message) => {
+					result = result.and(Err(ExecError::Device(DeviceError::InvalidRequest {
+						device: "virtio_block",
+						message
+					})));
+					0
+				}
+			};
+			self.transport.get_mut_queue(0).push_used(memory, head, written);
+			// Interrupt is asserted because the device has used a buffer
+			// in at least one of the active virtual queues.
+			self.transport.notify_used_buffer();
+		}
+		result
 	}
 
-	fn get_base_used_address(&self) -> u64 {
-		let align = self.queue_align as u64;
-		let queue_size = self.queue_size as u64;
-		((self.get_base_avail_address() + 4 + queue_size * 2 + align - 1) / align) * align
-	}
+	// Handles a block request and returns the length written to memory,
+	// or `Err` with the reason if the request is malformed
+	fn handle_request(&mut self, memory: &mut MemoryWrapper, descs: &[(u64, u32, u16)]) -> Result<u32, &'static str> {
+		if descs.len() < 2 {
+			return Err("Descriptor chain length should be two or more");
+		}
+		let (status_addr, status_len, status_flags) = descs[descs.len() - 1];
+		if (status_flags & VIRTQ_DESC_F_WRITE) == 0 {
+			return Err("Status descriptor should be write");
+		}
+		if status_len != 1 {
+			return Err("Status descriptor length should be one");
+		}
 
-	// @TODO: Follow the virtio block specification more propertly.
-	fn handle_disk_access(&mut self, memory: &mut MemoryWrapper) {
-		let base_desc_address = self.get_base_desc_address();
-		let base_avail_address = self.get_base_avail_address();
-		let base_used_address = self.get_base_used_address();
-		let queue_size = self.queue_size as u64;
-
-		let _avail_flag = memory.read_halfword(base_avail_address) as u64;
-		let _avail_index = memory.read_halfword(base_avail_address.wrapping_add(2)) as u64;
-		let desc_index_address = base_avail_address.wrapping_add(4).wrapping_add((self.used_ring_index as u64 % queue_size) * 2);
-		let desc_head_index = (memory.read_halfword(desc_index_address) as u64) % queue_size;
-
-		/*
-		println!("Desc AD:{:X}", base_desc_address);
-		println!("Avail AD:{:X}", base_avail_address);
-		println!("Used AD:{:X}", base_used_address);
-		println!("Avail flag:{:X}", _avail_flag);
-		println!("Avail index:{:X}", _avail_index);
-		println!("Used ring index:{:X}", self.used_ring_index);
-		println!("Desc head index:{:X}", desc_head_index);
-		*/
-
-		let mut _blk_type = 0;
-		let mut _blk_reserved = 0;
-		let mut blk_sector = 0;
-		let mut desc_num = 0;
-		let mut desc_next = desc_head_index;
-		loop {
-			let desc_element_address = base_desc_address + 16 * desc_next;
-			let desc_addr = memory.read_doubleword(desc_element_address);
-			let desc_len = memory.read_word(desc_element_address.wrapping_add(8));
-			let desc_flags = memory.read_halfword(desc_element_address.wrapping_add(12));
-			desc_next = (memory.read_halfword(desc_element_address.wrapping_add(14)) as u64) % queue_size;
-
-			/*
-			println!("Desc addr:{:X}", desc_addr);
-			println!("Desc len:{:X}", desc_len);
-			println!("Desc flags:{:X}", desc_flags);
-			println!("Desc next:{:X}", desc_next);
-			*/
-
-			// Assuming address in memory equals to or greater than DRAM_BASE.
-			match desc_num {
-				0 => {
-					// First descriptor: Block description
-					// struct virtio_blk_req {
-					//   uint32 type;
-					//   uint32 reserved;
-					//   uint64 sector;
-					// }
-
-					// Read/Write operation can be distinguished with the second descriptor flags
-					// so we can ignore blk_type?
-					_blk_type = memory.read_word(desc_addr);
-					_blk_reserved = memory.read_word(desc_addr.wrapping_add(4));
-					blk_sector = memory.read_doubleword(desc_addr.wrapping_add(8));
-					/*
-					println!("Blk type:{:X}", _blk_type);
-					println!("Blk reserved:{:X}", _blk_reserved);
-					println!("Blk sector:{:X}", blk_sector);
-					*/
-				},
-				1 => {
-					// Second descriptor: Read/Write disk
-					match (desc_flags & VIRTQ_DESC_F_WRITE) == 0 {
-						true => { // write to disk
-							if (desc_addr % 8) == 0 && ((blk_sector * SECTOR_SIZE) % 8) == 0 &&
-								(desc_len % 8) == 0 {
-								// Enter fast path if possible
-								self.transfer_to_disk(memory, desc_addr, blk_sector * SECTOR_SIZE, desc_len as u64);
-							} else {
-								for i in 0..desc_len as u64 {
-									let data = memory.read_byte(desc_addr + i);
-									self.write_to_disk(blk_sector * SECTOR_SIZE + i, data);
-								}
-							}
-						},
-						false => { // read from disk
-							if (desc_addr % 8) == 0 && ((blk_sector * SECTOR_SIZE) % 8) == 0 &&
-								(desc_len % 8) == 0 {
-								// Enter fast path if possible
-								self.transfer_from_disk(memory, desc_addr, blk_sector * SECTOR_SIZE, desc_len as u64);
-							} else {
-								for i in 0..desc_len as u64 {
-									let data = self.read_from_disk(blk_sector * SECTOR_SIZE + i);
-									memory.write_byte(desc_addr + i, data);
-								}
-							}
-						}
-					};
+		// First descriptor: Block description
+		// struct virtio_blk_req {
+		//   uint32 type;
+		//   uint32 reserved;
+		//   uint64 sector;
+		// }
+		let header_addr = descs[0].0;
+		let blk_type = memory.read_word(header_addr);
+		let _blk_reserved = memory.read_word(header_addr.wrapping_add(4));
+		let blk_sector = memory.read_doubleword(header_addr.wrapping_add(8));
+
+		// Middle descriptors: Read/Write disk
+		// Out of range access fails, e.g. after the disk is detached
+		let mut result = Ok(());
+		let mut sector = blk_sector;
+		let mut written = 0;
+		for &(desc_addr, desc_len, _desc_flags) in &descs[1..descs.len() - 1] {
+			let mut buffer = vec![0; desc_len as usize];
+			result = match blk_type {
+				VIRTIO_BLK_T_IN => self.backend.read(sector, &mut buffer)
+					.map(|_| Self::transfer_to_memory(memory, desc_addr, &buffer)),
+				VIRTIO_BLK_T_OUT => {
+					Self::transfer_from_memory(memory, desc_addr, &mut buffer);
+					self.backend.write(sector, &buffer)
 				},
-				2 => {
-					// Third descriptor: Result status
-					if (desc_flags & VIRTQ_DESC_F_WRITE) == 0 {
-						panic!("Third descriptor should be write.");
-					}
-					if desc_len != 1 {
-						panic!("Third descriptor length should be one.");
-					}
-					memory.write_byte(desc_addr, 0); // 0 means succeeded
-				},
-				_ => {}
+				_ => Ok(())
 			};
-
-			desc_num += 1;
-
-			if (desc_flags & VIRTQ_DESC_F_NEXT) == 0 {
+			if result.is_err() {
 				break;
 			}
+			if blk_type == VIRTIO_BLK_T_IN {
+				written += desc_len;
+			}
+			sector += desc_len as u64 / SECTOR_SIZE;
 		}
 
-		if desc_num != 3 {
-			panic!("Descript chain length should be three.");
+		let blk_status = match blk_type {
+			VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => match result {
+				Ok(()) => VIRTIO_BLK_S_OK,
+				Err(_) => VIRTIO_BLK_S_IOERR
+			},
+			VIRTIO_BLK_T_FLUSH => match self.backend.flush() {
+				Ok(()) => VIRTIO_BLK_S_OK,
+				Err(_) => VIRTIO_BLK_S_IOERR
+			},
+			_ => VIRTIO_BLK_S_UNSUPP
+		};
+
+		// Last descriptor: Result status
+		memory.write_byte(status_addr, blk_status);
+		Ok(written + 1)
+	}
+}
+
+impl Drop for VirtioBlockDisk {
+	// Guest writes cached in the storage survive the emulator shutdown.
+	fn drop(&mut self) {
+		let _ = self.backend.flush();
+	}
+}
+
+#[cfg(test)]
+mod test_virtio_block_disk {
+	use super::*;
+	use config::EmulatorConfig;
+	use cpu::Xlen;
+	use device::virtio_mmio::*;
+	use device::virtqueue::VIRTQ_DESC_F_NEXT;
+	use mmu::{Mmu, DRAM_BASE};
+	use terminal::DummyTerminal;
+
+	const QUEUE_ADDRESS: u64 = DRAM_BASE + 0x10000;
+	const HEADER_ADDRESS: u64 = DRAM_BASE + 0x20000;
+	const BUFFER_ADDRESS: u64 = DRAM_BASE + 0x21000;
+	const STATUS_ADDRESS: u64 = DRAM_BASE + 0x22000;
+
+	fn store(mmu: &mut Mmu, address: u64, value: u64, size: u64) {
+		for i in 0..size {
+			mmu.store_raw(address + i, (value >> (i * 8)) as u8);
 		}
+	}
 
-		memory.write_word(base_used_address.wrapping_add(4).wrapping_add((self.used_ring_index as u64 % queue_size) * 8), desc_head_index as u32);
+	fn store_desc(mmu: &mut Mmu, index: u64, address: u64, len: u64, flags: u16) {
+		let desc_address = QUEUE_ADDRESS + index * 16;
+		store(mmu, desc_address, address, 8);
+		store(mmu, desc_address + 8, len, 4);
+		store(mmu, desc_address + 12, flags as u64, 2);
+		store(mmu, desc_address + 14, index + 1, 2);
+	}
 
-		self.used_ring_index = self.used_ring_index.wrapping_add(1);
-		memory.write_halfword(base_used_address.wrapping_add(2), self.used_ring_index);
+	#[test]
+	fn hotplug() {
+		let config = EmulatorConfig {
+			virtio_block_transport: VirtioTransport::Modern,
+			..EmulatorConfig::default()
+		};
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &config);
+		mmu.init_memory(0x100000);
+		mmu.init_disk(Box::new(MemoryBlockBackend::new(vec![0xaa; SECTOR_SIZE as usize * 4])));
+		assert_eq!(BLOCK_DEVICE_ID, mmu.load_word_raw(VIRTIO_BLOCK_BASE + DEVICE_ID));
+		assert_eq!(4, mmu.load_word_raw(VIRTIO_BLOCK_BASE + CONFIG));
+
+		store(&mut mmu, VIRTIO_BLOCK_BASE + QUEUE_SEL, 0, 4);
+		store(&mut mmu, VIRTIO_BLOCK_BASE + QUEUE_NUM, 8, 4);
+		store(&mut mmu, VIRTIO_BLOCK_BASE + QUEUE_DESC_LOW, QUEUE_ADDRESS, 4);
+		store(&mut mmu, VIRTIO_BLOCK_BASE + QUEUE_DRIVER_LOW, QUEUE_ADDRESS + 0x100, 4);
+		store(&mut mmu, VIRTIO_BLOCK_BASE + QUEUE_DEVICE_LOW, QUEUE_ADDRESS + 0x200, 4);
+		store(&mut mmu, VIRTIO_BLOCK_BASE + QUEUE_READY, 1, 4);
+		store(&mut mmu, VIRTIO_BLOCK_BASE + STATUS, STATUS_DRIVER_OK as u64, 4);
+
+		// Reads sector 1
+		store(&mut mmu, HEADER_ADDRESS, VIRTIO_BLK_T_IN as u64, 4);
+		store(&mut mmu, HEADER_ADDRESS + 8, 1, 8);
+		store(&mut mmu, STATUS_ADDRESS, 0xff, 1);
+		store_desc(&mut mmu, 0, HEADER_ADDRESS, 16, VIRTQ_DESC_F_NEXT);
+		store_desc(&mut mmu, 1, BUFFER_ADDRESS, SECTOR_SIZE, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+		store_desc(&mut mmu, 2, STATUS_ADDRESS, 1, VIRTQ_DESC_F_WRITE);
+		store(&mut mmu, QUEUE_ADDRESS + 0x100 + 4, 0, 2);
+		store(&mut mmu, QUEUE_ADDRESS + 0x100 + 2, 1, 2);
+		store(&mut mmu, VIRTIO_BLOCK_BASE + QUEUE_NOTIFY, 0, 4);
+
+		// The disk is unplugged while the request is in flight
+		mmu.get_mut_disk().detach();
+		assert!(!mmu.get_mut_disk().is_attached());
+		assert_eq!(0, mmu.load_word_raw(VIRTIO_BLOCK_BASE + DEVICE_ID));
+		assert_eq!(0, mmu.load_word_raw(VIRTIO_BLOCK_BASE + CONFIG));
+		assert_eq!(STATUS_DRIVER_OK | STATUS_DEVICE_NEEDS_RESET, mmu.load_word_raw(VIRTIO_BLOCK_BASE + STATUS));
+		assert_eq!(INTERRUPT_CONFIG_CHANGE, mmu.load_word_raw(VIRTIO_BLOCK_BASE + INTERRUPT_STATUS));
+		for _ in 0..=DISK_ACCESS_DELAY {
+			mmu.tick(&mut 0);
+		}
+		assert_eq!(VIRTIO_BLK_S_IOERR as u32, mmu.load_word_raw(STATUS_ADDRESS) & 0xff);
+		assert_eq!(0, mmu.load_word_raw(BUFFER_ADDRESS));
+		assert_eq!(INTERRUPT_CONFIG_CHANGE | INTERRUPT_USED_BUFFER, mmu.load_word_raw(VIRTIO_BLOCK_BASE + INTERRUPT_STATUS));
+		store(&mut mmu, VIRTIO_BLOCK_BASE + INTERRUPT_ACK, (INTERRUPT_CONFIG_CHANGE | INTERRUPT_USED_BUFFER) as u64, 4);
+		assert!(!mmu.get_mut_disk().is_interrupting());
+
+		// A larger disk is plugged
+		let generation = mmu.load_word_raw(VIRTIO_BLOCK_BASE + CONFIG_GENERATION);
+		mmu.get_mut_disk().attach(Box::new(MemoryBlockBackend::new(vec![0; SECTOR_SIZE as usize * 8])));
+		assert!(mmu.get_mut_disk().is_attached());
+		assert_eq!(BLOCK_DEVICE_ID, mmu.load_word_raw(VIRTIO_BLOCK_BASE + DEVICE_ID));
+		assert_eq!(8, mmu.load_word_raw(VIRTIO_BLOCK_BASE + CONFIG));
+		assert_eq!(generation + 1, mmu.load_word_raw(VIRTIO_BLOCK_BASE + CONFIG_GENERATION));
+		assert_eq!(INTERRUPT_CONFIG_CHANGE, mmu.load_word_raw(VIRTIO_BLOCK_BASE + INTERRUPT_STATUS));
+		store(&mut mmu, VIRTIO_BLOCK_BASE + INTERRUPT_ACK, INTERRUPT_CONFIG_CHANGE as u64, 4);
+		assert!(!mmu.get_mut_disk().is_interrupting());
 	}
 }
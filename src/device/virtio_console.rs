@@ -0,0 +1,250 @@
+use alloc::boxed::Box;
+
+use mmu::MemoryWrapper;
+use terminal::Terminal;
+use config::VirtioTransport;
+use device::virtio_mmio::{VirtioMmio, VirtioMmioRequest, CONFIG};
+use device::virtqueue::VIRTQ_DESC_F_WRITE;
+
+// Based on Virtual I/O Device (VIRTIO) Version 1.1, 5.3 Console Device
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html
+
+/// Base address of `VirtioConsole` registers
+pub const VIRTIO_CONSOLE_BASE: u64 = 0x10007000;
+
+/// Window size reported until the terminal or the host tells
+pub const DEFAULT_COLS: u16 = 80;
+pub const DEFAULT_ROWS: u16 = 24;
+
+const MAX_QUEUE_SIZE: u32 = 0x100;
+const QUEUE_NUM_TOTAL: usize = 2;
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+// The device reports the window size in the configuration
+const VIRTIO_CONSOLE_F_SIZE: u64 = 1 << 0;
+
+// Configurations
+const COLS: u64 = CONFIG;
+const ROWS: u64 = CONFIG + 2;
+
+// Interval to poll input from the terminal
+// @TODO: Set more proper number.
+const RX_POLL_INTERVAL: u64 = 0x1000;
+
+// Interval to poll the window size of the terminal. Resizing is rare.
+const WINDOW_SIZE_POLL_INTERVAL: u64 = 0x100000;
+
+/// Emulates Virtio Console device on [`VirtioMmio`](../virtio_mmio/struct.VirtioMmio.html)
+/// transport, an `hvc` device in Linux. Unlike the UART console it tells the guest
+/// the window size of the terminal, so full screen programs render
+/// correctly. The size follows `Terminal::get_window_size()` or is pushed
+/// by the host with `set_window_size()`. Unless enabled the device is seen
+/// as an empty virtio slot.
+pub struct VirtioConsole {
+	clock: u64,
+	transport: VirtioMmio,
+	terminal: Box<dyn Terminal>,
+	cols: u16,
+	rows: u16,
+	/// Whether the guest has been told the size since the driver is ready
+	size_notified: bool,
+	tx_notified: bool
+}
+
+impl VirtioConsole {
+	/// Creates a new `VirtioConsole`.
+	///
+	/// # Arguments
+	/// * `transport` Virtio MMIO register layout
+	/// * `enabled` Whether the device is seen by the guest
+	/// * `terminal`
+	pub fn new(transport: &VirtioTransport, enabled: bool, terminal: Box<dyn Terminal>) -> Self {
+		// 3 (Console device) or 0 (Empty slot)
+		let device_id = match enabled {
+			true => 3,
+			false => 0
+		};
+		VirtioConsole {
+			clock: 0,
+			transport: VirtioMmio::new(transport, device_id, VIRTIO_CONSOLE_F_SIZE, QUEUE_NUM_TOTAL, MAX_QUEUE_SIZE),
+			terminal,
+			cols: DEFAULT_COLS,
+			rows: DEFAULT_ROWS,
+			size_notified: false,
+			tx_notified: false
+		}
+	}
+
+	/// Sets the window size. The guest is notified with a configuration
+	/// change interrupt if it changes.
+	///
+	/// # Arguments
+	/// * `cols`
+	/// * `rows`
+	pub fn set_window_size(&mut self, cols: u16, rows: u16) {
+		if (cols, rows) == (self.cols, self.rows) {
+			return;
+		}
+		self.cols = cols;
+		self.rows = rows;
+		self.transport.notify_config_change();
+	}
+
+	/// Returns the window size as (columns, rows).
+	pub fn get_window_size(&self) -> (u16, u16) {
+		(self.cols, self.rows)
+	}
+
+	/// Indicates whether `VirtioConsole` raises an interrupt signal.
+	/// The signal is "Level-triggered".
+	pub fn is_interrupting(&self) -> bool {
+		self.transport.is_interrupting()
+	}
+
+	/// Runs one cycle. Outputs the data in transmit queue if notified and
+	/// gets input and the window size from `Terminal` at certain timing.
+	///
+	/// # Arguments
+	/// * `memory`
+	pub fn tick(&mut self, memory: &mut MemoryWrapper) {
+		self.clock = self.clock.wrapping_add(1);
+		if !self.transport.is_driver_ok() {
+			return;
+		}
+		// Linux reads the size only on configuration change interrupts
+		if !self.size_notified {
+			self.size_notified = true;
+			self.transport.notify_config_change();
+		}
+		if self.tx_notified && self.terminal.is_output_ready() {
+			self.tx_notified = false;
+			self.handle_tx(memory);
+		}
+		if self.clock.is_multiple_of(RX_POLL_INTERVAL) && self.terminal.has_input() {
+			self.handle_rx(memory);
+		}
+		if self.clock.is_multiple_of(WINDOW_SIZE_POLL_INTERVAL) {
+			if let Some((cols, rows)) = self.terminal.get_window_size() {
+				self.set_window_size(cols, rows);
+			}
+		}
+	}
+
+	fn handle_tx(&mut self, memory: &mut MemoryWrapper) {
+		while let Some(head) = self.transport.get_mut_queue(TX_QUEUE).pop_avail(memory) {
+			// Holds the rest while the terminal can't accept output
+			if !self.terminal.is_output_ready() {
+				self.transport.get_mut_queue(TX_QUEUE).unpop_avail();
+				self.tx_notified = true;
+				return;
+			}
+			let mut data = vec![];
+			for (addr, len, flags) in self.transport.get_queue(TX_QUEUE).read_desc_chain(memory, head) {
+				if (flags & VIRTQ_DESC_F_WRITE) != 0 {
+					continue;
+				}
+				for i in 0..len as u64 {
+					data.push(memory.read_byte(addr.wrapping_add(i)));
+				}
+			}
+			self.terminal.put_bytes(&data);
+			self.transport.get_mut_queue(TX_QUEUE).push_used(memory, head, 0);
+			self.transport.notify_used_buffer();
+		}
+	}
+
+	fn handle_rx(&mut self, memory: &mut MemoryWrapper) {
+		while let Some(head) = self.transport.get_mut_queue(RX_QUEUE).pop_avail(memory) {
+			let mut written = 0;
+			for (addr, len, flags) in self.transport.get_queue(RX_QUEUE).read_desc_chain(memory, head) {
+				if (flags & VIRTQ_DESC_F_WRITE) == 0 {
+					continue;
+				}
+				let mut buffer = vec![0; len as usize];
+				let size = self.terminal.get_input_bytes(&mut buffer);
+				for (i, value) in buffer[..size].iter().enumerate() {
+					memory.write_byte(addr.wrapping_add(i as u64), *value);
+				}
+				written += size;
+				if size < buffer.len() {
+					break;
+				}
+			}
+			if written == 0 {
+				self.transport.get_mut_queue(RX_QUEUE).unpop_avail();
+				return;
+			}
+			self.transport.get_mut_queue(RX_QUEUE).push_used(memory, head, written as u32);
+			self.transport.notify_used_buffer();
+		}
+	}
+
+	/// Loads register content
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn load(&self, address: u64) -> u8 {
+		let offset = address - VIRTIO_CONSOLE_BASE;
+		if offset < CONFIG {
+			return self.transport.load(offset);
+		}
+		let pos = (offset % 2) * 8;
+		match offset & !0x1 {
+			COLS => (self.cols >> pos) as u8,
+			ROWS => (self.rows >> pos) as u8,
+			_ => 0
+		}
+	}
+
+	/// Stores register content
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		let offset = address - VIRTIO_CONSOLE_BASE;
+		if offset >= CONFIG {
+			return;
+		}
+		match self.transport.store(offset, value) {
+			// Input is delivered when the driver provides buffers
+			Some(VirtioMmioRequest::Notify(queue)) if queue as usize == TX_QUEUE => self.tx_notified = true,
+			Some(VirtioMmioRequest::Reset) => {
+				self.size_notified = false;
+				self.tx_notified = false;
+			},
+			_ => {}
+		};
+	}
+
+	/// Returns mutable reference to `Terminal`.
+	pub fn get_mut_terminal(&mut self) -> &mut Box<dyn Terminal> {
+		&mut self.terminal
+	}
+}
+
+#[cfg(test)]
+mod test_virtio_console {
+	use super::*;
+	use config::EmulatorConfig;
+	use cpu::Xlen;
+	use device::virtio_mmio::*;
+	use mmu::Mmu;
+	use terminal::DummyTerminal;
+
+	#[test]
+	fn window_size() {
+		let config = EmulatorConfig {
+			virtio_console: true,
+			..EmulatorConfig::default()
+		};
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &config);
+		mmu.init_memory(0x100000);
+		assert_eq!(3, mmu.load_word_raw(VIRTIO_CONSOLE_BASE + DEVICE_ID));
+		assert_eq!(((DEFAULT_ROWS as u32) << 16) | DEFAULT_COLS as u32, mmu.load_word_raw(VIRTIO_CONSOLE_BASE + COLS));
+		mmu.get_mut_virtio_console().set_window_size(132, 43);
+		assert!(mmu.get_mut_virtio_console().is_interrupting());
+		assert_eq!((43 << 16) | 132, mmu.load_word_raw(VIRTIO_CONSOLE_BASE + COLS));
+	}
+}
@@ -0,0 +1,427 @@
+use alloc::vec::Vec;
+
+use config::VirtioTransport;
+use device::virtqueue::Virtqueue;
+
+// Based on Virtual I/O Device (VIRTIO) Version 1.1, 4.2 Virtio Over MMIO
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html
+
+pub const MAGIC_VALUE: u64 = 0x000;
+pub const VERSION: u64 = 0x004;
+pub const DEVICE_ID: u64 = 0x008;
+pub const VENDOR_ID: u64 = 0x00c;
+pub const DEVICE_FEATURES: u64 = 0x010;
+pub const DEVICE_FEATURES_SEL: u64 = 0x014;
+pub const DRIVER_FEATURES: u64 = 0x020;
+pub const DRIVER_FEATURES_SEL: u64 = 0x024;
+pub const GUEST_PAGE_SIZE: u64 = 0x028; // Legacy only
+pub const QUEUE_SEL: u64 = 0x030;
+pub const QUEUE_NUM_MAX: u64 = 0x034;
+pub const QUEUE_NUM: u64 = 0x038;
+pub const QUEUE_ALIGN: u64 = 0x03c; // Legacy only
+pub const QUEUE_PFN: u64 = 0x040; // Legacy only
+pub const QUEUE_READY: u64 = 0x044; // Modern only
+pub const QUEUE_NOTIFY: u64 = 0x050;
+pub const INTERRUPT_STATUS: u64 = 0x060;
+pub const INTERRUPT_ACK: u64 = 0x064;
+pub const STATUS: u64 = 0x070;
+pub const QUEUE_DESC_LOW: u64 = 0x080; // Modern only
+pub const QUEUE_DESC_HIGH: u64 = 0x084; // Modern only
+pub const QUEUE_DRIVER_LOW: u64 = 0x090; // Modern only
+pub const QUEUE_DRIVER_HIGH: u64 = 0x094; // Modern only
+pub const QUEUE_DEVICE_LOW: u64 = 0x0a0; // Modern only
+pub const QUEUE_DEVICE_HIGH: u64 = 0x0a4; // Modern only
+pub const CONFIG_GENERATION: u64 = 0x0fc; // Modern only
+/// Device specific configuration space starts here
+pub const CONFIG: u64 = 0x100;
+
+/// Feature bit indicating compliance with the specification version 1.
+/// The modern interface requires it.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Interrupt status bits
+pub const INTERRUPT_USED_BUFFER: u32 = 0x1;
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 0x2;
+
+// Device status bits
+pub const STATUS_DRIVER_OK: u32 = 0x4;
+pub const STATUS_FEATURES_OK: u32 = 0x8;
+pub const STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+
+/// Requests from the driver the device is expected to handle
+#[derive(Debug, PartialEq)]
+pub enum VirtioMmioRequest {
+	/// The driver has made buffers available in the queue
+	Notify(u32),
+	/// The driver has reset the device by writing zero to status
+	Reset
+}
+
+/// Virtio over MMIO transport shared by the Virtio devices. It emulates
+/// the registers below the device specific configuration space in either
+/// the legacy (version 1) or the modern (version 2) layout and holds
+/// the virtqueues. The modern interface always offers VIRTIO_F_VERSION_1
+/// and the device refuses FEATURES_OK unless the driver accepts it.
+pub struct VirtioMmio {
+	transport: VirtioTransport,
+	device_id: u32,
+	device_features: u64,
+	device_features_sel: u32,
+	driver_features: u64,
+	driver_features_sel: u32,
+	guest_page_size: u32,
+	queue_select: u32,
+	max_queue_size: u32,
+	queues: Vec<Virtqueue>,
+	interrupt_status: u32,
+	status: u32,
+	config_generation: u32
+}
+
+impl VirtioMmio {
+	/// Creates a new `VirtioMmio`.
+	///
+	/// # Arguments
+	/// * `transport`
+	/// * `device_id` Zero for an empty slot
+	/// * `device_features` Feature bits the device offers
+	/// * `queue_num` Number of virtqueues
+	/// * `max_queue_size`
+	pub fn new(transport: &VirtioTransport, device_id: u32, device_features: u64, queue_num: usize, max_queue_size: u32) -> Self {
+		let device_features = match transport {
+			VirtioTransport::Legacy => device_features,
+			VirtioTransport::Modern => device_features | VIRTIO_F_VERSION_1
+		};
+		VirtioMmio {
+			transport: transport.clone(),
+			device_id,
+			device_features,
+			device_features_sel: 0,
+			driver_features: 0,
+			driver_features_sel: 0,
+			guest_page_size: 0,
+			queue_select: 0,
+			max_queue_size,
+			queues: (0..queue_num).map(|_| Virtqueue::new()).collect(),
+			interrupt_status: 0,
+			status: 0,
+			config_generation: 0
+		}
+	}
+
+	/// Sets device id. Zero makes the device seen as an empty slot.
+	///
+	/// # Arguments
+	/// * `device_id`
+	pub fn set_device_id(&mut self, device_id: u32) {
+		self.device_id = device_id;
+	}
+
+	/// Indicates whether the feature is offered by the device and
+	/// accepted by the driver.
+	///
+	/// # Arguments
+	/// * `feature` Feature bit
+	pub fn has_feature(&self, feature: u64) -> bool {
+		(self.device_features & self.driver_features & feature) != 0
+	}
+
+	/// Indicates whether the driver has set DRIVER_OK status.
+	pub fn is_driver_ok(&self) -> bool {
+		(self.status & STATUS_DRIVER_OK) != 0
+	}
+
+	/// Indicates whether the device raises an interrupt signal.
+	/// The signal is "Level-triggered".
+	pub fn is_interrupting(&self) -> bool {
+		self.interrupt_status != 0
+	}
+
+	/// Raises an interrupt notifying the driver that the device
+	/// has used buffers.
+	pub fn notify_used_buffer(&mut self) {
+		self.interrupt_status |= INTERRUPT_USED_BUFFER;
+	}
+
+	/// Raises an interrupt notifying the driver that the device
+	/// specific configuration has changed.
+	pub fn notify_config_change(&mut self) {
+		self.config_generation = self.config_generation.wrapping_add(1);
+		self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+	}
+
+	/// Sets DEVICE_NEEDS_RESET status if the driver is active and
+	/// notifies the driver of it.
+	pub fn request_reset(&mut self) {
+		if self.is_driver_ok() {
+			self.status |= STATUS_DEVICE_NEEDS_RESET;
+		}
+		self.notify_config_change();
+	}
+
+	/// Returns immutable reference to a virtqueue.
+	///
+	/// # Arguments
+	/// * `index`
+	pub fn get_queue(&self, index: usize) -> &Virtqueue {
+		&self.queues[index]
+	}
+
+	/// Returns mutable reference to a virtqueue.
+	///
+	/// # Arguments
+	/// * `index`
+	pub fn get_mut_queue(&mut self, index: usize) -> &mut Virtqueue {
+		&mut self.queues[index]
+	}
+
+	fn reset(&mut self) {
+		for queue in self.queues.iter_mut() {
+			*queue = Virtqueue::new();
+		}
+		self.device_features_sel = 0;
+		self.driver_features = 0;
+		self.driver_features_sel = 0;
+		self.queue_select = 0;
+		self.interrupt_status = 0;
+	}
+
+	fn is_legacy(&self) -> bool {
+		self.transport == VirtioTransport::Legacy
+	}
+
+	// The modern interface requires VIRTIO_F_VERSION_1 and
+	// the driver can't accept features the device doesn't offer.
+	fn are_features_acceptable(&self) -> bool {
+		(self.driver_features & !self.device_features) == 0 &&
+			(self.is_legacy() || (self.driver_features & VIRTIO_F_VERSION_1) != 0)
+	}
+
+	fn read_register(&self, offset: u64) -> u32 {
+		let legacy = self.is_legacy();
+		let queue = self.queues.get(self.queue_select as usize);
+		match offset {
+			MAGIC_VALUE => 0x74726976,
+			VERSION => match legacy {
+				true => 1,
+				false => 2
+			},
+			DEVICE_ID => self.device_id,
+			VENDOR_ID => 0x554d4551,
+			DEVICE_FEATURES => match self.device_features_sel {
+				0 => self.device_features as u32,
+				1 => (self.device_features >> 32) as u32,
+				_ => 0
+			},
+			// Zero means the queue is unavailable
+			QUEUE_NUM_MAX => match queue {
+				Some(_) => self.max_queue_size,
+				None => 0
+			},
+			QUEUE_PFN if legacy => queue.map_or(0, |queue| queue.pfn),
+			QUEUE_READY if !legacy => queue.map_or(0, |queue| queue.ready as u32),
+			INTERRUPT_STATUS => self.interrupt_status,
+			STATUS => self.status,
+			CONFIG_GENERATION if !legacy => self.config_generation,
+			_ => 0
+		}
+	}
+
+	/// Loads register content
+	///
+	/// # Arguments
+	/// * `offset` Offset from the base address, less than `CONFIG`
+	pub fn load(&self, offset: u64) -> u8 {
+		let pos = (offset % 4) * 8;
+		(self.read_register(offset & !0x3) >> pos) as u8
+	}
+
+	/// Stores register content and returns the request from the driver
+	/// if the store makes one.
+	///
+	/// # Arguments
+	/// * `offset` Offset from the base address, less than `CONFIG`
+	/// * `value`
+	pub fn store(&mut self, offset: u64, value: u8) -> Option<VirtioMmioRequest> {
+		let pos = (offset % 4) * 8;
+		let mask = !(0xff << pos);
+		let data = (value as u32) << pos;
+		// Side effects happen when the highest byte is written
+		let completed = (offset % 4) == 3;
+		let legacy = self.is_legacy();
+		let guest_page_size = self.guest_page_size;
+		let queue = self.queues.get_mut(self.queue_select as usize);
+		match offset & !0x3 {
+			DEVICE_FEATURES_SEL => self.device_features_sel = (self.device_features_sel & mask) | data,
+			DRIVER_FEATURES if self.driver_features_sel < 2 => {
+				let pos = pos + self.driver_features_sel as u64 * 32;
+				self.driver_features = (self.driver_features & !(0xff << pos)) | ((value as u64) << pos);
+			},
+			DRIVER_FEATURES_SEL => self.driver_features_sel = (self.driver_features_sel & mask) | data,
+			GUEST_PAGE_SIZE if legacy => self.guest_page_size = (self.guest_page_size & mask) | data,
+			QUEUE_SEL => self.queue_select = (self.queue_select & mask) | data,
+			QUEUE_NUM => {
+				if let Some(queue) = queue {
+					queue.size = (queue.size & mask) | data;
+				}
+			},
+			QUEUE_ALIGN if legacy => {
+				if let Some(queue) = queue {
+					queue.align = (queue.align & mask) | data;
+				}
+			},
+			QUEUE_PFN if legacy => {
+				if let Some(queue) = queue {
+					queue.pfn = (queue.pfn & mask) | data;
+					if completed {
+						queue.place_legacy(guest_page_size);
+					}
+				}
+			},
+			QUEUE_READY if !legacy && offset == QUEUE_READY => {
+				if let Some(queue) = queue {
+					queue.ready = (value & 1) == 1;
+					queue.reset_indices();
+				}
+			},
+			QUEUE_DESC_LOW | QUEUE_DESC_HIGH if !legacy => {
+				if let Some(queue) = queue {
+					update_address(&mut queue.desc_address, offset - QUEUE_DESC_LOW, value);
+				}
+			},
+			QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH if !legacy => {
+				if let Some(queue) = queue {
+					update_address(&mut queue.avail_address, offset - QUEUE_DRIVER_LOW, value);
+				}
+			},
+			QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH if !legacy => {
+				if let Some(queue) = queue {
+					update_address(&mut queue.used_address, offset - QUEUE_DEVICE_LOW, value);
+				}
+			},
+			QUEUE_NOTIFY if offset == QUEUE_NOTIFY => {
+				trace!(target: "virtio", "Device {} notified queue {}", self.device_id, value);
+				return Some(VirtioMmioRequest::Notify(value as u32));
+			},
+			INTERRUPT_ACK => self.interrupt_status &= !data,
+			STATUS => {
+				self.status = (self.status & mask) | data;
+				if completed {
+					debug!(target: "virtio", "Device {} status {:x}", self.device_id, self.status);
+					if self.status == 0 {
+						self.reset();
+						return Some(VirtioMmioRequest::Reset);
+					}
+					// Unsetting FEATURES_OK tells the driver the features are refused
+					if (self.status & STATUS_FEATURES_OK) != 0 && !self.are_features_acceptable() {
+						debug!(target: "virtio", "Device {} refused the driver features", self.device_id);
+						self.status &= !STATUS_FEATURES_OK;
+					}
+				}
+			},
+			_ => {}
+		};
+		None
+	}
+}
+
+// Updates a byte of 64-bit address written as low and high 32-bit registers
+fn update_address(address: &mut u64, index: u64, value: u8) {
+	let pos = index * 8;
+	*address = (*address & !(0xff << pos)) | ((value as u64) << pos);
+}
+
+#[cfg(test)]
+mod test_virtio_mmio {
+	use super::*;
+
+	fn load_word(mmio: &VirtioMmio, offset: u64) -> u32 {
+		let mut value = 0;
+		for i in 0..4 {
+			value |= (mmio.load(offset + i) as u32) << (i * 8);
+		}
+		value
+	}
+
+	fn store_word(mmio: &mut VirtioMmio, offset: u64, value: u32) -> Option<VirtioMmioRequest> {
+		let mut request = None;
+		for i in 0..4 {
+			request = request.or(mmio.store(offset + i, (value >> (i * 8)) as u8));
+		}
+		request
+	}
+
+	#[test]
+	fn legacy() {
+		let mut mmio = VirtioMmio::new(&VirtioTransport::Legacy, 2, 1 << 9, 1, 0x100);
+		assert_eq!(0x74726976, load_word(&mmio, MAGIC_VALUE));
+		assert_eq!(1, load_word(&mmio, VERSION));
+		assert_eq!(2, load_word(&mmio, DEVICE_ID));
+		store_word(&mut mmio, DEVICE_FEATURES_SEL, 1);
+		assert_eq!(0, load_word(&mmio, DEVICE_FEATURES));
+		store_word(&mut mmio, GUEST_PAGE_SIZE, 0x1000);
+		store_word(&mut mmio, QUEUE_SEL, 0);
+		assert_eq!(0x100, load_word(&mmio, QUEUE_NUM_MAX));
+		store_word(&mut mmio, QUEUE_NUM, 8);
+		store_word(&mut mmio, QUEUE_PFN, 0x80010);
+		let queue = mmio.get_queue(0);
+		assert!(queue.ready);
+		assert_eq!(0x80010000, queue.desc_address);
+		assert_eq!(0x80010080, queue.avail_address);
+		assert_eq!(0x80011000, queue.used_address);
+		// Modern only registers are absent
+		store_word(&mut mmio, QUEUE_DESC_LOW, 0x1234);
+		assert_eq!(0x80010000, mmio.get_queue(0).desc_address);
+		// Only one queue
+		store_word(&mut mmio, QUEUE_SEL, 1);
+		assert_eq!(0, load_word(&mmio, QUEUE_NUM_MAX));
+		assert_eq!(Some(VirtioMmioRequest::Notify(0)), store_word(&mut mmio, QUEUE_NOTIFY, 0));
+	}
+
+	#[test]
+	fn modern() {
+		let mut mmio = VirtioMmio::new(&VirtioTransport::Modern, 1, 1 << 5, 2, 0x100);
+		assert_eq!(2, load_word(&mmio, VERSION));
+		assert_eq!(1 << 5, load_word(&mmio, DEVICE_FEATURES));
+		store_word(&mut mmio, DEVICE_FEATURES_SEL, 1);
+		assert_eq!(1, load_word(&mmio, DEVICE_FEATURES));
+
+		// Refuses features without VIRTIO_F_VERSION_1
+		store_word(&mut mmio, DRIVER_FEATURES, 1 << 5);
+		store_word(&mut mmio, STATUS, STATUS_FEATURES_OK);
+		assert_eq!(0, load_word(&mmio, STATUS) & STATUS_FEATURES_OK);
+		store_word(&mut mmio, DRIVER_FEATURES_SEL, 1);
+		store_word(&mut mmio, DRIVER_FEATURES, 1);
+		store_word(&mut mmio, STATUS, STATUS_FEATURES_OK);
+		assert_eq!(STATUS_FEATURES_OK, load_word(&mmio, STATUS) & STATUS_FEATURES_OK);
+		assert!(mmio.has_feature(VIRTIO_F_VERSION_1));
+		assert!(mmio.has_feature(1 << 5));
+
+		store_word(&mut mmio, QUEUE_SEL, 1);
+		store_word(&mut mmio, QUEUE_NUM, 8);
+		store_word(&mut mmio, QUEUE_DESC_LOW, 0x80010000);
+		store_word(&mut mmio, QUEUE_DESC_HIGH, 0x1);
+		store_word(&mut mmio, QUEUE_DRIVER_LOW, 0x80020000);
+		store_word(&mut mmio, QUEUE_DEVICE_LOW, 0x80030000);
+		// Legacy only registers are absent
+		store_word(&mut mmio, QUEUE_PFN, 0x80010);
+		assert_eq!(0, load_word(&mmio, QUEUE_READY));
+		store_word(&mut mmio, QUEUE_READY, 1);
+		assert_eq!(1, load_word(&mmio, QUEUE_READY));
+		let queue = mmio.get_queue(1);
+		assert!(queue.ready);
+		assert_eq!(0x180010000, queue.desc_address);
+		assert_eq!(0x80020000, queue.avail_address);
+		assert_eq!(0x80030000, queue.used_address);
+
+		mmio.notify_config_change();
+		assert_eq!(1, load_word(&mmio, CONFIG_GENERATION));
+		assert!(mmio.is_interrupting());
+		store_word(&mut mmio, INTERRUPT_ACK, INTERRUPT_CONFIG_CHANGE);
+		assert!(!mmio.is_interrupting());
+
+		assert_eq!(Some(VirtioMmioRequest::Reset), store_word(&mut mmio, STATUS, 0));
+		assert!(!mmio.get_queue(1).ready);
+		assert!(!mmio.has_feature(VIRTIO_F_VERSION_1));
+	}
+}
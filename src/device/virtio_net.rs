@@ -0,0 +1,288 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use mmu::MemoryWrapper;
+use net_backend::NetBackend;
+use config::VirtioTransport;
+use device::virtio_mmio::{VirtioMmio, VirtioMmioRequest, CONFIG, VIRTIO_F_VERSION_1};
+use device::virtqueue::VIRTQ_DESC_F_WRITE;
+
+// Based on Virtual I/O Device (VIRTIO) Version 1.1
+// https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html
+
+/// Base address of `VirtioNet` registers
+pub const VIRTIO_NET_BASE: u64 = 0x10002000;
+
+const MAX_QUEUE_SIZE: u32 = 0x100;
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+// Legacy struct virtio_net_hdr without VIRTIO_NET_F_MRG_RXBUF. The header
+// has num_buffers field additionally if VIRTIO_F_VERSION_1 is negotiated.
+const NET_HDR_SIZE: usize = 10;
+const NET_HDR_SIZE_VERSION_1: usize = 12;
+
+// Interval to poll frames from backend
+// @TODO: Set more proper number.
+const RX_POLL_INTERVAL: u64 = 0x100;
+
+// Default MAC address, in locally administered range
+const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// Emulates Virtio Network device on [`VirtioMmio`](../virtio_mmio/struct.VirtioMmio.html)
+/// transport. Ethernet frames are transferred via [`NetBackend`](../../net_backend/trait.NetBackend.html).
+/// Without backend the device is seen as an empty virtio slot.
+pub struct VirtioNet {
+	clock: u64,
+	backend: Option<Box<dyn NetBackend>>,
+	mac: [u8; 6],
+	transport: VirtioMmio,
+	tx_notified: bool
+}
+
+impl VirtioNet {
+	/// Creates a new `VirtioNet`.
+	///
+	/// # Arguments
+	/// * `transport` Virtio MMIO register layout
+	pub fn new(transport: &VirtioTransport) -> Self {
+		VirtioNet {
+			clock: 0,
+			backend: None,
+			mac: DEFAULT_MAC,
+			// Empty slot until connected to backend
+			transport: VirtioMmio::new(transport, 0, VIRTIO_NET_F_MAC, 2, MAX_QUEUE_SIZE),
+			tx_notified: false
+		}
+	}
+
+	/// Connects the device to `NetBackend`.
+	///
+	/// # Arguments
+	/// * `backend`
+	pub fn set_backend(&mut self, backend: Box<dyn NetBackend>) {
+		self.backend = Some(backend);
+		// Network device
+		self.transport.set_device_id(1);
+	}
+
+	/// Connects the device to `NetBackend` while the guest runs, replacing
+	/// the current one if connected. The guest is notified with a configuration
+	/// change interrupt.
+	///
+	/// # Arguments
+	/// * `backend`
+	pub fn attach(&mut self, backend: Box<dyn NetBackend>) {
+		self.set_backend(backend);
+		self.transport.notify_config_change();
+	}
+
+	/// Disconnects the device from `NetBackend` while the guest runs. The device
+	/// is seen as an empty slot afterward and frames in the queues are left
+	/// unprocessed. If the driver is active, the device requests reset.
+	/// The guest is notified with a configuration change interrupt.
+	pub fn detach(&mut self) {
+		self.backend = None;
+		self.tx_notified = false;
+		self.transport.set_device_id(0);
+		self.transport.request_reset();
+	}
+
+	/// Indicates whether the device is connected to `NetBackend`.
+	pub fn has_backend(&self) -> bool {
+		self.backend.is_some()
+	}
+
+	/// Sets MAC address of the device.
+	///
+	/// # Arguments
+	/// * `mac`
+	pub fn set_mac(&mut self, mac: [u8; 6]) {
+		self.mac = mac;
+	}
+
+	/// Indicates whether `VirtioNet` raises an interrupt signal.
+	/// The signal is "Level-triggered".
+	pub fn is_interrupting(&self) -> bool {
+		self.transport.is_interrupting()
+	}
+
+	/// Runs one cycle. Sends frames in transmit queue if notified and
+	/// receives frames from `NetBackend` at certain timing.
+	///
+	/// # Arguments
+	/// * `memory`
+	pub fn tick(&mut self, memory: &mut MemoryWrapper) {
+		self.clock = self.clock.wrapping_add(1);
+		if self.backend.is_none() || !self.transport.is_driver_ok() {
+			return;
+		}
+		if self.tx_notified {
+			self.tx_notified = false;
+			self.handle_tx(memory);
+		}
+		if self.clock.is_multiple_of(RX_POLL_INTERVAL) {
+			self.handle_rx(memory);
+		}
+	}
+
+	/// Loads register content
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn load(&self, address: u64) -> u8 {
+		let offset = address - VIRTIO_NET_BASE;
+		if offset < CONFIG {
+			return self.transport.load(offset);
+		}
+		match offset {
+			// Configurations: MAC address
+			CONFIG..=0x105 => self.mac[(offset - CONFIG) as usize],
+			_ => 0
+		}
+	}
+
+	/// Stores register content
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		let offset = address - VIRTIO_NET_BASE;
+		if offset >= CONFIG {
+			return;
+		}
+		match self.transport.store(offset, value) {
+			// Only transmit queue notification matters. Received frames
+			// are delivered when the driver provides buffers.
+			Some(VirtioMmioRequest::Notify(queue)) if queue as usize == TX_QUEUE => self.tx_notified = true,
+			Some(VirtioMmioRequest::Reset) => self.tx_notified = false,
+			_ => {}
+		};
+	}
+
+	fn pop_avail(&mut self, memory: &mut MemoryWrapper, queue: usize) -> Option<u16> {
+		self.transport.get_mut_queue(queue).pop_avail(memory)
+	}
+
+	fn push_used(&mut self, memory: &mut MemoryWrapper, queue: usize, head: u16, length: u32) {
+		self.transport.get_mut_queue(queue).push_used(memory, head, length);
+		self.transport.notify_used_buffer();
+	}
+
+	fn read_desc_chain(&self, memory: &mut MemoryWrapper, queue: usize, head: u16) -> Vec<(u64, u32, u16)> {
+		self.transport.get_queue(queue).read_desc_chain(memory, head)
+	}
+
+	fn get_header_size(&self) -> usize {
+		match self.transport.has_feature(VIRTIO_F_VERSION_1) {
+			true => NET_HDR_SIZE_VERSION_1,
+			false => NET_HDR_SIZE
+		}
+	}
+
+	fn handle_tx(&mut self, memory: &mut MemoryWrapper) {
+		while let Some(head) = self.pop_avail(memory, TX_QUEUE) {
+			let mut data = vec![];
+			for (addr, len, flags) in self.read_desc_chain(memory, TX_QUEUE, head) {
+				if (flags & VIRTQ_DESC_F_WRITE) != 0 {
+					continue;
+				}
+				for i in 0..len as u64 {
+					data.push(memory.read_byte(addr.wrapping_add(i)));
+				}
+			}
+			let header_size = self.get_header_size();
+			if data.len() > header_size {
+				if let Some(backend) = self.backend.as_mut() {
+					backend.send(&data[header_size..]);
+				}
+			}
+			self.push_used(memory, TX_QUEUE, head, 0);
+		}
+	}
+
+	fn handle_rx(&mut self, memory: &mut MemoryWrapper) {
+		loop {
+			// Peeks if the driver provides a buffer before polling a frame
+			let head = match self.pop_avail(memory, RX_QUEUE) {
+				Some(head) => head,
+				None => return
+			};
+			let frame = match self.backend.as_mut().and_then(|backend| backend.poll()) {
+				Some(frame) => frame,
+				None => {
+					self.transport.get_mut_queue(RX_QUEUE).unpop_avail();
+					return;
+				}
+			};
+			// Zero-filled header means no checksum offload and no GSO.
+			// num_buffers is one.
+			let mut data = vec![0; self.get_header_size()];
+			if data.len() == NET_HDR_SIZE_VERSION_1 {
+				data[10] = 1;
+			}
+			data.extend_from_slice(&frame);
+			let mut written = 0;
+			for (addr, len, flags) in self.read_desc_chain(memory, RX_QUEUE, head) {
+				if (flags & VIRTQ_DESC_F_WRITE) == 0 {
+					continue;
+				}
+				let length = (len as usize).min(data.len() - written);
+				for i in 0..length {
+					memory.write_byte(addr.wrapping_add(i as u64), data[written + i]);
+				}
+				written += length;
+				if written == data.len() {
+					break;
+				}
+			}
+			// Frame is truncated if the buffer is too small
+			self.push_used(memory, RX_QUEUE, head, written as u32);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test_virtio_net {
+	use super::*;
+	use config::EmulatorConfig;
+	use cpu::Xlen;
+	use device::virtio_mmio::*;
+	use mmu::Mmu;
+	use net_backend::LoopbackNetBackend;
+	use terminal::DummyTerminal;
+
+	fn store_word(mmu: &mut Mmu, offset: u64, value: u32) {
+		for i in 0..4 {
+			mmu.store_raw(VIRTIO_NET_BASE + offset + i, (value >> (i * 8)) as u8);
+		}
+	}
+
+	#[test]
+	fn hotplug() {
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &EmulatorConfig::default());
+		mmu.init_memory(0x100000);
+		assert_eq!(0, mmu.load_word_raw(VIRTIO_NET_BASE + DEVICE_ID));
+
+		mmu.get_mut_net().attach(Box::new(LoopbackNetBackend::new()));
+		assert!(mmu.get_net().has_backend());
+		assert_eq!(1, mmu.load_word_raw(VIRTIO_NET_BASE + DEVICE_ID));
+		assert_eq!(INTERRUPT_CONFIG_CHANGE, mmu.load_word_raw(VIRTIO_NET_BASE + INTERRUPT_STATUS));
+		assert!(mmu.get_net().is_interrupting());
+		store_word(&mut mmu, INTERRUPT_ACK, INTERRUPT_CONFIG_CHANGE);
+		assert!(!mmu.get_net().is_interrupting());
+
+		// Unplugged under the active driver
+		store_word(&mut mmu, STATUS, STATUS_DRIVER_OK);
+		mmu.get_mut_net().detach();
+		assert!(!mmu.get_net().has_backend());
+		assert_eq!(0, mmu.load_word_raw(VIRTIO_NET_BASE + DEVICE_ID));
+		assert_eq!(STATUS_DRIVER_OK | STATUS_DEVICE_NEEDS_RESET, mmu.load_word_raw(VIRTIO_NET_BASE + STATUS));
+		assert_eq!(INTERRUPT_CONFIG_CHANGE, mmu.load_word_raw(VIRTIO_NET_BASE + INTERRUPT_STATUS));
+		store_word(&mut mmu, INTERRUPT_ACK, INTERRUPT_CONFIG_CHANGE);
+		assert!(!mmu.get_net().is_interrupting());
+	}
+}
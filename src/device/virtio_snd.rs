@@ -0,0 +1,422 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use mmu::MemoryWrapper;
+use config::VirtioTransport;
+use device::virtio_mmio::{VirtioMmio, VirtioMmioRequest, CONFIG, VIRTIO_F_VERSION_1};
+use device::virtqueue::VIRTQ_DESC_F_WRITE;
+
+// Based on Virtual I/O Device (VIRTIO) Version 1.2, 5.14 Sound Device
+// https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html
+
+/// Base address of `VirtioSnd` registers
+pub const VIRTIO_SND_BASE: u64 = 0x10003000;
+
+const MAX_QUEUE_SIZE: u32 = 0x100;
+const QUEUE_NUM_TOTAL: usize = 4;
+const CONTROL_QUEUE: usize = 0;
+const _EVENT_QUEUE: usize = 1;
+const TX_QUEUE: usize = 2;
+const _RX_QUEUE: usize = 3;
+
+
+// Request codes
+const VIRTIO_SND_R_JACK_INFO: u32 = 0x0001;
+const VIRTIO_SND_R_PCM_INFO: u32 = 0x0100;
+const VIRTIO_SND_R_PCM_SET_PARAMS: u32 = 0x0101;
+const VIRTIO_SND_R_PCM_PREPARE: u32 = 0x0102;
+const VIRTIO_SND_R_PCM_RELEASE: u32 = 0x0103;
+const VIRTIO_SND_R_PCM_START: u32 = 0x0104;
+const VIRTIO_SND_R_PCM_STOP: u32 = 0x0105;
+const VIRTIO_SND_R_CHMAP_INFO: u32 = 0x0200;
+
+// Status codes
+const VIRTIO_SND_S_OK: u32 = 0x8000;
+const VIRTIO_SND_S_BAD_MSG: u32 = 0x8001;
+const VIRTIO_SND_S_NOT_SUPP: u32 = 0x8002;
+
+const VIRTIO_SND_D_OUTPUT: u8 = 0;
+
+const VIRTIO_SND_PCM_FMT_S16: u8 = 5;
+
+// Supported rates. Index is the rate code.
+const PCM_RATES: [u32; 8] = [5512, 8000, 11025, 16000, 22050, 32000, 44100, 48000];
+
+// Only one output stream
+const STREAM_NUM: u32 = 1;
+const CHANNELS_MIN: u8 = 1;
+const CHANNELS_MAX: u8 = 2;
+
+// struct virtio_snd_pcm_info
+const PCM_INFO_SIZE: usize = 32;
+// struct virtio_snd_pcm_xfer
+const PCM_XFER_SIZE: usize = 4;
+// struct virtio_snd_pcm_status
+const PCM_STATUS_SIZE: u32 = 8;
+
+/// PCM stream parameters set by the guest. Samples are signed 16-bit
+/// little endian and interleaved if multiple channels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PcmParams {
+	pub channels: u8,
+	/// Sample rate in Hz
+	pub rate: u32
+}
+
+/// Callback invoked with stream parameters and PCM data played by the guest
+pub type PcmCallback = Box<dyn FnMut(&PcmParams, &[u8])>;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+	match data.get(offset..offset + 4) {
+		Some(bytes) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+		None => 0
+	}
+}
+
+/// Emulates Virtio Sound device with one PCM output stream on
+/// [`VirtioMmio`](../virtio_mmio/struct.VirtioMmio.html) transport. The device
+/// offers version 1 feature which the Linux driver requires even on the legacy
+/// transport.
+/// PCM data played by the guest is delivered to the host callback.
+/// Without callback the device is seen as an empty virtio slot.
+///
+/// @TODO: Buffers are completed as soon as delivered so the guest plays
+/// faster than the real time. The callback should buffer or pace.
+pub struct VirtioSnd {
+	callback: Option<PcmCallback>,
+	transport: VirtioMmio,
+	/// Queues notified by the driver as bits
+	notified_queues: u32,
+	params: Option<PcmParams>,
+	running: bool
+}
+
+impl VirtioSnd {
+	/// Creates a new `VirtioSnd`.
+	///
+	/// # Arguments
+	/// * `transport` Virtio MMIO register layout
+	pub fn new(transport: &VirtioTransport) -> Self {
+		VirtioSnd {
+			callback: None,
+			// Empty slot until the callback is set
+			transport: VirtioMmio::new(transport, 0, VIRTIO_F_VERSION_1, QUEUE_NUM_TOTAL, MAX_QUEUE_SIZE),
+			notified_queues: 0,
+			params: None,
+			running: false
+		}
+	}
+
+	/// Sets the callback invoked with PCM data played by the guest.
+	///
+	/// # Arguments
+	/// * `callback`
+	pub fn set_callback(&mut self, callback: PcmCallback) {
+		self.callback = Some(callback);
+		// Sound device
+		self.transport.set_device_id(25);
+	}
+
+	/// Indicates whether the host callback is set.
+	pub fn has_callback(&self) -> bool {
+		self.callback.is_some()
+	}
+
+	/// Indicates whether the guest is playing the stream.
+	pub fn is_running(&self) -> bool {
+		self.running
+	}
+
+	/// Indicates whether `VirtioSnd` raises an interrupt signal.
+	/// The signal is "Level-triggered".
+	pub fn is_interrupting(&self) -> bool {
+		self.transport.is_interrupting()
+	}
+
+	fn reset(&mut self) {
+		self.notified_queues = 0;
+		self.params = None;
+		self.running = false;
+	}
+
+	/// Runs one cycle. Handles requests in the notified queues.
+	///
+	/// # Arguments
+	/// * `memory`
+	pub fn tick(&mut self, memory: &mut MemoryWrapper) {
+		if self.notified_queues == 0 || !self.transport.is_driver_ok() {
+			return;
+		}
+		if (self.notified_queues & (1 << CONTROL_QUEUE)) != 0 {
+			self.handle_control(memory);
+		}
+		if (self.notified_queues & (1 << TX_QUEUE)) != 0 {
+			self.handle_tx(memory);
+		}
+		// Event and receive queues are left because no event
+		// and no input stream are there.
+		self.notified_queues = 0;
+	}
+
+	/// Loads register content
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn load(&self, address: u64) -> u8 {
+		let offset = address - VIRTIO_SND_BASE;
+		if offset < CONFIG {
+			return self.transport.load(offset);
+		}
+		let pos = (offset % 4) * 8;
+		match offset & !0x3 {
+			// Configurations: jacks, streams, chmaps
+			0x104 => (STREAM_NUM >> pos) as u8,
+			_ => 0
+		}
+	}
+
+	/// Stores register content
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `value`
+	pub fn store(&mut self, address: u64, value: u8) {
+		let offset = address - VIRTIO_SND_BASE;
+		if offset >= CONFIG {
+			return;
+		}
+		match self.transport.store(offset, value) {
+			Some(VirtioMmioRequest::Notify(queue)) if (queue as usize) < QUEUE_NUM_TOTAL => {
+				self.notified_queues |= 1 << queue;
+			},
+			Some(VirtioMmioRequest::Reset) => self.reset(),
+			_ => {}
+		};
+	}
+
+	/// Reads the device readable part of the descriptor chain, and returns
+	/// it with the device writable descriptors.
+	fn read_request(&self, memory: &mut MemoryWrapper, queue: usize, head: u16) -> (Vec<u8>, Vec<(u64, u32)>) {
+		let mut request = vec![];
+		let mut response_descs = vec![];
+		for (addr, len, flags) in self.transport.get_queue(queue).read_desc_chain(memory, head) {
+			match (flags & VIRTQ_DESC_F_WRITE) != 0 {
+				true => response_descs.push((addr, len)),
+				false => {
+					for i in 0..len as u64 {
+						request.push(memory.read_byte(addr.wrapping_add(i)));
+					}
+				}
+			};
+		}
+		(request, response_descs)
+	}
+
+	/// Writes the response to the device writable descriptors and
+	/// returns the written length.
+	fn write_response(memory: &mut MemoryWrapper, descs: &[(u64, u32)], response: &[u8]) -> u32 {
+		let mut written = 0;
+		for &(addr, len) in descs {
+			let length = (len as usize).min(response.len() - written);
+			for i in 0..length {
+				memory.write_byte(addr.wrapping_add(i as u64), response[written + i]);
+			}
+			written += length;
+		}
+		written as u32
+	}
+
+	fn handle_control(&mut self, memory: &mut MemoryWrapper) {
+		while let Some(head) = self.transport.get_mut_queue(CONTROL_QUEUE).pop_avail(memory) {
+			let (request, response_descs) = self.read_request(memory, CONTROL_QUEUE, head);
+			let response = self.handle_control_request(&request);
+			let written = Self::write_response(memory, &response_descs, &response);
+			self.transport.get_mut_queue(CONTROL_QUEUE).push_used(memory, head, written);
+			self.transport.notify_used_buffer();
+		}
+	}
+
+	/// Handles a control request and returns the response
+	/// starting with status code.
+	fn handle_control_request(&mut self, request: &[u8]) -> Vec<u8> {
+		let code = read_u32(request, 0);
+		let status = match code {
+			VIRTIO_SND_R_PCM_INFO => {
+				// struct virtio_snd_query_info
+				let start_id = read_u32(request, 4);
+				let count = read_u32(request, 8);
+				let size = read_u32(request, 12) as usize;
+				if start_id.checked_add(count).is_none_or(|end| end > STREAM_NUM) || size < PCM_INFO_SIZE {
+					VIRTIO_SND_S_BAD_MSG
+				} else {
+					let mut response = VIRTIO_SND_S_OK.to_le_bytes().to_vec();
+					for _i in 0..count {
+						let mut info = vec![0; size];
+						// hda_fn_nid and features are zero
+						let formats = 1u64 << VIRTIO_SND_PCM_FMT_S16;
+						let rates = (1u64 << PCM_RATES.len()) - 1;
+						info[8..16].copy_from_slice(&formats.to_le_bytes());
+						info[16..24].copy_from_slice(&rates.to_le_bytes());
+						info[24] = VIRTIO_SND_D_OUTPUT;
+						info[25] = CHANNELS_MIN;
+						info[26] = CHANNELS_MAX;
+						response.extend_from_slice(&info);
+					}
+					return response;
+				}
+			},
+			VIRTIO_SND_R_PCM_SET_PARAMS => {
+				// struct virtio_snd_pcm_set_params
+				let stream_id = read_u32(request, 4);
+				let (channels, format, rate) = match request.get(20..23) {
+					Some(bytes) => (bytes[0], bytes[1], bytes[2] as usize),
+					None => (0, 0, PCM_RATES.len())
+				};
+				match stream_id < STREAM_NUM {
+					false => VIRTIO_SND_S_BAD_MSG,
+					true if format != VIRTIO_SND_PCM_FMT_S16 || rate >= PCM_RATES.len() ||
+						!(CHANNELS_MIN..=CHANNELS_MAX).contains(&channels) => VIRTIO_SND_S_NOT_SUPP,
+					true => {
+						self.params = Some(PcmParams {
+							channels,
+							rate: PCM_RATES[rate]
+						});
+						VIRTIO_SND_S_OK
+					}
+				}
+			},
+			VIRTIO_SND_R_PCM_PREPARE |
+			VIRTIO_SND_R_PCM_RELEASE |
+			VIRTIO_SND_R_PCM_START |
+			VIRTIO_SND_R_PCM_STOP => match read_u32(request, 4) < STREAM_NUM {
+				true => {
+					self.running = code == VIRTIO_SND_R_PCM_START;
+					VIRTIO_SND_S_OK
+				},
+				false => VIRTIO_SND_S_BAD_MSG
+			},
+			// No jacks and no channel maps
+			VIRTIO_SND_R_JACK_INFO | VIRTIO_SND_R_CHMAP_INFO => VIRTIO_SND_S_BAD_MSG,
+			_ => VIRTIO_SND_S_NOT_SUPP
+		};
+		status.to_le_bytes().to_vec()
+	}
+
+	fn handle_tx(&mut self, memory: &mut MemoryWrapper) {
+		while let Some(head) = self.transport.get_mut_queue(TX_QUEUE).pop_avail(memory) {
+			let (request, response_descs) = self.read_request(memory, TX_QUEUE, head);
+			// struct virtio_snd_pcm_xfer followed by PCM data
+			let status = match (read_u32(&request, 0) < STREAM_NUM, self.params.as_ref()) {
+				(true, Some(params)) if request.len() >= PCM_XFER_SIZE => {
+					if let Some(callback) = self.callback.as_mut() {
+						callback(params, &request[PCM_XFER_SIZE..]);
+					}
+					VIRTIO_SND_S_OK
+				},
+				_ => VIRTIO_SND_S_BAD_MSG
+			};
+			// struct virtio_snd_pcm_status. Latency is zero.
+			let mut response = status.to_le_bytes().to_vec();
+			response.extend_from_slice(&[0; 4]);
+			Self::write_response(memory, &response_descs, &response);
+			self.transport.get_mut_queue(TX_QUEUE).push_used(memory, head, PCM_STATUS_SIZE);
+			self.transport.notify_used_buffer();
+		}
+	}
+}
+
+#[cfg(test)]
+mod test_virtio_snd {
+	use super::*;
+	use device::virtio_mmio::*;
+	use core::cell::RefCell;
+	use alloc::rc::Rc;
+	use config::EmulatorConfig;
+	use cpu::Xlen;
+	use mmu::{Mmu, DRAM_BASE};
+	use terminal::DummyTerminal;
+
+	const PAGE_SIZE: u64 = 0x1000;
+	const CONTROL_QUEUE_ADDRESS: u64 = DRAM_BASE + 0x10000;
+	const TX_QUEUE_ADDRESS: u64 = DRAM_BASE + 0x20000;
+	const BUFFER_ADDRESS: u64 = DRAM_BASE + 0x30000;
+	const QUEUE_SIZE: u64 = 8;
+
+	fn store(mmu: &mut Mmu, address: u64, value: u64, size: u64) {
+		for i in 0..size {
+			mmu.store_raw(address + i, (value >> (i * 8)) as u8);
+		}
+	}
+
+	fn setup_queue(mmu: &mut Mmu, queue: u64, address: u64) {
+		store(mmu, VIRTIO_SND_BASE + QUEUE_SEL, queue, 4);
+		store(mmu, VIRTIO_SND_BASE + QUEUE_NUM, QUEUE_SIZE, 4);
+		store(mmu, VIRTIO_SND_BASE + QUEUE_PFN, address / PAGE_SIZE, 4);
+	}
+
+	// Puts the buffers as a descriptor chain to the queue and notifies the device
+	fn request(mmu: &mut Mmu, queue: u64, address: u64, buffers: &[(u64, u32, u16)]) {
+		for (i, &(addr, len, flags)) in buffers.iter().enumerate() {
+			let desc_address = address + i as u64 * 16;
+			let flags = match i + 1 < buffers.len() {
+				true => flags | 1,
+				false => flags
+			};
+			store(mmu, desc_address, addr, 8);
+			store(mmu, desc_address + 8, len as u64, 4);
+			store(mmu, desc_address + 12, flags as u64, 2);
+			store(mmu, desc_address + 14, i as u64 + 1, 2);
+		}
+		let avail_address = address + QUEUE_SIZE * 16;
+		store(mmu, avail_address + 4, 0, 2);
+		store(mmu, avail_address + 2, 1, 2);
+		store(mmu, VIRTIO_SND_BASE + QUEUE_NOTIFY, queue, 4);
+		let mut mip = 0;
+		mmu.tick(&mut mip);
+	}
+
+	#[test]
+	fn play() {
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &EmulatorConfig::default());
+		mmu.init_memory(0x100000);
+		let played = Rc::new(RefCell::new(vec![]));
+		let played_clone = played.clone();
+		mmu.get_mut_snd().set_callback(Box::new(move |params, data| {
+			played_clone.borrow_mut().push((params.clone(), data.to_vec()));
+		}));
+		assert_eq!(25, mmu.load_word_raw(VIRTIO_SND_BASE + DEVICE_ID));
+		assert_eq!(1, mmu.load_word_raw(VIRTIO_SND_BASE + 0x104));
+		store(&mut mmu, VIRTIO_SND_BASE + GUEST_PAGE_SIZE, PAGE_SIZE, 4);
+		setup_queue(&mut mmu, CONTROL_QUEUE as u64, CONTROL_QUEUE_ADDRESS);
+		setup_queue(&mut mmu, TX_QUEUE as u64, TX_QUEUE_ADDRESS);
+		store(&mut mmu, VIRTIO_SND_BASE + STATUS, STATUS_DRIVER_OK as u64, 4);
+
+		// Set params: 2 channels, S16, 48000Hz
+		store(&mut mmu, BUFFER_ADDRESS, VIRTIO_SND_R_PCM_SET_PARAMS as u64, 4);
+		store(&mut mmu, BUFFER_ADDRESS + 4, 0, 8);
+		store(&mut mmu, BUFFER_ADDRESS + 12, 0, 8);
+		store(&mut mmu, BUFFER_ADDRESS + 20, 0x070502, 4);
+		request(&mut mmu, CONTROL_QUEUE as u64, CONTROL_QUEUE_ADDRESS, &[
+			(BUFFER_ADDRESS, 24, 0),
+			(BUFFER_ADDRESS + 0x100, 4, VIRTQ_DESC_F_WRITE)
+		]);
+		assert_eq!(VIRTIO_SND_S_OK, mmu.load_word_raw(BUFFER_ADDRESS + 0x100));
+		// Used ring index
+		assert_eq!(1, mmu.load_word_raw(CONTROL_QUEUE_ADDRESS + PAGE_SIZE + 2) & 0xffff);
+		assert!(mmu.get_snd().is_interrupting());
+		store(&mut mmu, VIRTIO_SND_BASE + INTERRUPT_ACK, INTERRUPT_USED_BUFFER as u64, 4);
+		assert!(!mmu.get_snd().is_interrupting());
+
+		// Transfer PCM data
+		store(&mut mmu, BUFFER_ADDRESS + 0x200, 0, 4);
+		store(&mut mmu, BUFFER_ADDRESS + 0x300, 0x0807060504030201, 8);
+		request(&mut mmu, TX_QUEUE as u64, TX_QUEUE_ADDRESS, &[
+			(BUFFER_ADDRESS + 0x200, 4, 0),
+			(BUFFER_ADDRESS + 0x300, 8, 0),
+			(BUFFER_ADDRESS + 0x400, 8, VIRTQ_DESC_F_WRITE)
+		]);
+		assert_eq!(VIRTIO_SND_S_OK, mmu.load_word_raw(BUFFER_ADDRESS + 0x400));
+		assert_eq!(vec![(PcmParams { channels: 2, rate: 48000 }, vec![1, 2, 3, 4, 5, 6, 7, 8])], *played.borrow());
+		assert!(mmu.get_snd().is_interrupting());
+	}
+}
@@ -0,0 +1,140 @@
+use alloc::vec::Vec;
+
+use mmu::MemoryWrapper;
+
+// Split virtqueue. Refer to the Virtio specification 2.6 Split Virtqueues
+// for the layout.
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// Virtqueue placed in main memory by the driver. The queue consists of
+/// descriptor table, available ring, and used ring. The legacy interface
+/// places them contiguously at `pfn * guest_page_size` with the used ring
+/// aligned to `align` while the modern interface sets their addresses
+/// individually.
+pub struct Virtqueue {
+	pub size: u32,
+	pub align: u32,
+	pub pfn: u32,
+	pub desc_address: u64,
+	pub avail_address: u64,
+	pub used_address: u64,
+	/// Whether the driver has set up the queue
+	pub ready: bool,
+	last_avail_index: u16,
+	used_index: u16
+}
+
+impl Virtqueue {
+	/// Creates a new `Virtqueue`.
+	pub fn new() -> Self {
+		Virtqueue {
+			size: 0,
+			align: 0x1000,
+			pfn: 0,
+			desc_address: 0,
+			avail_address: 0,
+			used_address: 0,
+			ready: false,
+			last_avail_index: 0,
+			used_index: 0
+		}
+	}
+
+	/// Resets ring indices. Call when the driver sets up the queue.
+	pub fn reset_indices(&mut self) {
+		self.last_avail_index = 0;
+		self.used_index = 0;
+	}
+
+	/// Places the queue in the legacy layout at `pfn * guest_page_size`.
+	/// Call when the driver has written `pfn`. Zero `pfn` means
+	/// the driver releases the queue.
+	///
+	/// # Arguments
+	/// * `guest_page_size`
+	pub fn place_legacy(&mut self, guest_page_size: u32) {
+		let align = self.align as u64;
+		self.desc_address = self.pfn as u64 * guest_page_size as u64;
+		self.avail_address = self.desc_address + self.size as u64 * 16;
+		self.used_address = (self.avail_address + 4 + self.size as u64 * 2).div_ceil(align) * align;
+		self.ready = self.pfn != 0;
+		self.reset_indices();
+	}
+
+	/// Returns the head descriptor index of the next available buffer,
+	/// or `None` if no buffer is available.
+	///
+	/// # Arguments
+	/// * `memory`
+	pub fn pop_avail(&mut self, memory: &mut MemoryWrapper) -> Option<u16> {
+		let size = self.size as u64;
+		if !self.ready || size == 0 {
+			return None;
+		}
+		let avail_address = self.avail_address;
+		let avail_index = memory.read_halfword(avail_address.wrapping_add(2));
+		if avail_index == self.last_avail_index {
+			return None;
+		}
+		let head = memory.read_halfword(avail_address.wrapping_add(4)
+			.wrapping_add((self.last_avail_index as u64 % size) * 2));
+		self.last_avail_index = self.last_avail_index.wrapping_add(1);
+		Some(head)
+	}
+
+	/// Returns the buffer popped by the last `pop_avail()` back to
+	/// the available ring.
+	pub fn unpop_avail(&mut self) {
+		self.last_avail_index = self.last_avail_index.wrapping_sub(1);
+	}
+
+	/// Puts the used buffer to the used ring.
+	///
+	/// # Arguments
+	/// * `memory`
+	/// * `head` Head descriptor index
+	/// * `length` Bytes written to the buffer
+	pub fn push_used(&mut self, memory: &mut MemoryWrapper, head: u16, length: u32) {
+		let used_address = self.used_address;
+		let element_address = used_address.wrapping_add(4)
+			.wrapping_add((self.used_index as u64 % self.size as u64) * 8);
+		memory.write_word(element_address, head as u32);
+		memory.write_word(element_address.wrapping_add(4), length);
+		self.used_index = self.used_index.wrapping_add(1);
+		memory.write_halfword(used_address.wrapping_add(2), self.used_index);
+	}
+
+	/// Returns descriptors chained from the head as (address, length, flags).
+	///
+	/// # Arguments
+	/// * `memory`
+	/// * `head` Head descriptor index
+	pub fn read_desc_chain(&self, memory: &mut MemoryWrapper, head: u16) -> Vec<(u64, u32, u16)> {
+		let size = self.size as u64;
+		let desc_address = self.desc_address;
+		let mut descs = vec![];
+		let mut index = head as u64 % size;
+		// Limiting the chain length guards against a looped chain
+		for _i in 0..size {
+			let element_address = desc_address + 16 * index;
+			let addr = memory.read_doubleword(element_address);
+			let len = memory.read_word(element_address.wrapping_add(8));
+			let flags = memory.read_halfword(element_address.wrapping_add(12));
+			let next = memory.read_halfword(element_address.wrapping_add(14)) as u64;
+			descs.push((addr, len, flags));
+			if (flags & VIRTQ_DESC_F_NEXT) == 0 {
+				break;
+			}
+			index = next % size;
+		}
+		descs
+	}
+}
+
+impl Default for Virtqueue {
+	fn default() -> Self {
+		Self::new()
+	}
+}
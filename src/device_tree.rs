@@ -0,0 +1,1133 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use config::{ConsoleType, EmulatorConfig, InterruptControllerType, MachineType, ShadowStackConfig, DEFAULT_MEMORY_CAPACITY};
+use mmu::DRAM_BASE;
+use error::LoadError;
+use device::framebuffer::{FRAMEBUFFER_BASE, FRAMEBUFFER_BYTES_PER_PIXEL};
+use device::shared_memory::{SHARED_MEMORY_BASE, SHARED_MEMORY_REGISTERS_BASE, SHARED_MEMORY_REGISTERS_SIZE};
+use device::plic::{SERIAL_PORT_IRQ_BASE, SHARED_MEMORY_IRQ, VIRTIO_CONSOLE_IRQ};
+use device::uart::{MAX_SERIAL_PORT_NUM, SERIAL_PORT_BASE, UART_SIZE};
+use device::aplic::{APLIC_DOMAIN_SIZE, APLIC_MACHINE_BASE, APLIC_SOURCE_NUM, APLIC_SUPERVISOR_BASE};
+use device::sswi::{SSWI_BASE, SSWI_SIZE};
+use device::imsic::{IMSIC_FILE_SIZE, IMSIC_ID_NUM, IMSIC_MACHINE_BASE, IMSIC_SUPERVISOR_BASE};
+use device::test_finisher::{FINISHER_PASS, TEST_FINISHER_BASE, TEST_FINISHER_SIZE};
+
+// Based on Devicetree Specification Release v0.3, Chapter 5 Flattened Devicetree (DTB) Format
+// https://github.com/devicetree-org/devicetree-specification/releases/tag/v0.3
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+// phandles referred by nodes. Each hart has two phandles, for cpu node and
+// its interrupt controller node, from 1. The others follow them.
+fn get_cpu_phandle(hart: usize) -> u32 {
+	1 + 2 * hart as u32
+}
+
+fn get_cpu_intc_phandle(hart: usize) -> u32 {
+	2 + 2 * hart as u32
+}
+
+// The interrupt controller devices are connected to,
+// PLIC or supervisor-level APLIC domain
+fn get_plic_phandle(hart_num: usize) -> u32 {
+	1 + 2 * hart_num as u32
+}
+
+fn get_clock_phandle(hart_num: usize) -> u32 {
+	2 + 2 * hart_num as u32
+}
+
+fn get_machine_aplic_phandle(hart_num: usize) -> u32 {
+	3 + 2 * hart_num as u32
+}
+
+fn get_machine_imsic_phandle(hart_num: usize) -> u32 {
+	4 + 2 * hart_num as u32
+}
+
+fn get_supervisor_imsic_phandle(hart_num: usize) -> u32 {
+	5 + 2 * hart_num as u32
+}
+
+fn get_test_finisher_phandle(hart_num: usize) -> u32 {
+	6 + 2 * hart_num as u32
+}
+
+/// Builds Flattened Devicetree (DTB) binary. Nodes are added with
+/// `begin_node()` and `end_node()` pairs and properties are added
+/// to the current node.
+pub struct DeviceTreeBuilder {
+	structure: Vec<u8>,
+	strings: Vec<u8>,
+	depth: u32
+}
+
+impl DeviceTreeBuilder {
+	/// Creates a new `DeviceTreeBuilder`.
+	pub fn new() -> Self {
+		DeviceTreeBuilder {
+			structure: vec![],
+			strings: vec![],
+			depth: 0
+		}
+	}
+
+	fn push_u32(&mut self, value: u32) {
+		self.structure.extend_from_slice(&value.to_be_bytes());
+	}
+
+	fn align_structure(&mut self) {
+		while !self.structure.len().is_multiple_of(4) {
+			self.structure.push(0);
+		}
+	}
+
+	/// Returns the offset of the name in strings block, adding it if needed.
+	fn get_string_offset(&mut self, name: &str) -> u32 {
+		let mut offset = 0;
+		for s in self.strings.split(|c| *c == 0) {
+			if s == name.as_bytes() {
+				return offset as u32;
+			}
+			offset += s.len() + 1;
+		}
+		let offset = self.strings.len() as u32;
+		self.strings.extend_from_slice(name.as_bytes());
+		self.strings.push(0);
+		offset
+	}
+
+	/// Begins a node. The root node name is empty string.
+	///
+	/// # Arguments
+	/// * `name` Node name with unit address, e.g. "uart@10000000"
+	pub fn begin_node(&mut self, name: &str) {
+		self.push_u32(FDT_BEGIN_NODE);
+		self.structure.extend_from_slice(name.as_bytes());
+		self.structure.push(0);
+		self.align_structure();
+		self.depth += 1;
+	}
+
+	/// Ends the current node.
+	pub fn end_node(&mut self) {
+		debug_assert!(self.depth > 0, "No node to end");
+		self.push_u32(FDT_END_NODE);
+		self.depth -= 1;
+	}
+
+	/// Adds a property having raw bytes value to the current node.
+	///
+	/// # Arguments
+	/// * `name`
+	/// * `value`
+	pub fn property(&mut self, name: &str, value: &[u8]) {
+		let name_offset = self.get_string_offset(name);
+		self.push_u32(FDT_PROP);
+		self.push_u32(value.len() as u32);
+		self.push_u32(name_offset);
+		self.structure.extend_from_slice(value);
+		self.align_structure();
+	}
+
+	/// Adds an empty property, e.g. "interrupt-controller".
+	///
+	/// # Arguments
+	/// * `name`
+	pub fn property_empty(&mut self, name: &str) {
+		self.property(name, &[]);
+	}
+
+	/// Adds a null terminated string property.
+	///
+	/// # Arguments
+	/// * `name`
+	/// * `value`
+	pub fn property_string(&mut self, name: &str, value: &str) {
+		let mut bytes = value.as_bytes().to_vec();
+		bytes.push(0);
+		self.property(name, &bytes);
+	}
+
+	/// Adds a list of null terminated strings property, e.g. "compatible"
+	/// with fallbacks.
+	///
+	/// # Arguments
+	/// * `name`
+	/// * `values`
+	pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+		let mut bytes = vec![];
+		for value in values {
+			bytes.extend_from_slice(value.as_bytes());
+			bytes.push(0);
+		}
+		self.property(name, &bytes);
+	}
+
+	/// Adds a property of 32-bit cells.
+	///
+	/// # Arguments
+	/// * `name`
+	/// * `cells`
+	pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+		let mut bytes = vec![];
+		for cell in cells {
+			bytes.extend_from_slice(&cell.to_be_bytes());
+		}
+		self.property(name, &bytes);
+	}
+
+	/// Adds a "reg" property of a 64-bit address and a 64-bit size, assuming
+	/// #address-cells and #size-cells of the parent are two.
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `size`
+	pub fn property_reg(&mut self, address: u64, size: u64) {
+		self.property_cells("reg", &[
+			(address >> 32) as u32, address as u32,
+			(size >> 32) as u32, size as u32
+		]);
+	}
+
+	/// Finishes building and returns DTB binary.
+	pub fn finish(mut self) -> Vec<u8> {
+		debug_assert!(self.depth == 0, "Unclosed node remains");
+		self.push_u32(FDT_END);
+
+		// Empty memory reservation block consists of a terminating entry
+		let off_mem_rsvmap = FDT_HEADER_SIZE;
+		let off_dt_struct = off_mem_rsvmap + 16;
+		let off_dt_strings = off_dt_struct + self.structure.len();
+		let total_size = off_dt_strings + self.strings.len();
+
+		let mut dtb = vec![];
+		for value in [
+			FDT_MAGIC,
+			total_size as u32,
+			off_dt_struct as u32,
+			off_dt_strings as u32,
+			off_mem_rsvmap as u32,
+			FDT_VERSION,
+			FDT_LAST_COMP_VERSION,
+			0, // boot_cpuid_phys
+			self.strings.len() as u32,
+			self.structure.len() as u32
+		].iter() {
+			dtb.extend_from_slice(&value.to_be_bytes());
+		}
+		dtb.extend_from_slice(&[0; 16]);
+		dtb.extend_from_slice(&self.structure);
+		dtb.extend_from_slice(&self.strings);
+		dtb
+	}
+}
+
+impl Default for DeviceTreeBuilder {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Device tree node parsed from DTB binary. Used for merging
+/// overlays into a base device tree.
+pub struct DeviceTreeNode {
+	/// Node name with unit address. Empty for the root node
+	pub name: String,
+	/// Pairs of property name and raw value
+	pub properties: Vec<(String, Vec<u8>)>,
+	pub children: Vec<DeviceTreeNode>
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, LoadError> {
+	match data.get(offset..offset + 4) {
+		Some(bytes) => Ok(((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) |
+			((bytes[2] as u32) << 8) | (bytes[3] as u32)),
+		None => Err(LoadError::InvalidDtb)
+	}
+}
+
+fn read_string(data: &[u8], offset: usize) -> Result<String, LoadError> {
+	let bytes = match data.get(offset..) {
+		Some(bytes) => bytes,
+		None => return Err(LoadError::InvalidDtb)
+	};
+	match bytes.iter().position(|c| *c == 0) {
+		Some(length) => match core::str::from_utf8(&bytes[..length]) {
+			Ok(s) => Ok(s.to_string()),
+			Err(_) => Err(LoadError::InvalidDtb)
+		},
+		None => Err(LoadError::InvalidDtb)
+	}
+}
+
+fn align4(offset: usize) -> usize {
+	(offset + 3) & !3
+}
+
+impl DeviceTreeNode {
+	/// Creates a new empty `DeviceTreeNode`.
+	///
+	/// # Arguments
+	/// * `name`
+	pub fn new(name: &str) -> Self {
+		DeviceTreeNode {
+			name: name.to_string(),
+			properties: vec![],
+			children: vec![]
+		}
+	}
+
+	/// Parses DTB binary and returns the root node. Returns `Err` if
+	/// the data is broken.
+	///
+	/// # Arguments
+	/// * `data` DTB binary content
+	pub fn from_dtb(data: &[u8]) -> Result<Self, LoadError> {
+		if read_u32(data, 0)? != FDT_MAGIC {
+			return Err(LoadError::InvalidDtb);
+		}
+		let off_dt_struct = read_u32(data, 8)? as usize;
+		let off_dt_strings = read_u32(data, 12)? as usize;
+
+		let mut stack: Vec<DeviceTreeNode> = vec![];
+		let mut offset = off_dt_struct;
+		loop {
+			let token = read_u32(data, offset)?;
+			offset += 4;
+			match token {
+				FDT_BEGIN_NODE => {
+					let name = read_string(data, offset)?;
+					offset = align4(offset + name.len() + 1);
+					stack.push(DeviceTreeNode::new(&name));
+				},
+				FDT_END_NODE => {
+					let node = match stack.pop() {
+						Some(node) => node,
+						None => return Err(LoadError::InvalidDtb)
+					};
+					match stack.last_mut() {
+						Some(parent) => parent.children.push(node),
+						// Root node ends
+						None => return Ok(node)
+					};
+				},
+				FDT_PROP => {
+					let length = read_u32(data, offset)? as usize;
+					let name_offset = read_u32(data, offset + 4)? as usize;
+					offset += 8;
+					let value = match data.get(offset..offset + length) {
+						Some(value) => value.to_vec(),
+						None => return Err(LoadError::InvalidDtb)
+					};
+					offset = align4(offset + length);
+					let name = read_string(data, off_dt_strings + name_offset)?;
+					match stack.last_mut() {
+						Some(node) => node.properties.push((name, value)),
+						None => return Err(LoadError::InvalidDtb)
+					};
+				},
+				FDT_NOP => {},
+				_ => return Err(LoadError::InvalidDtb)
+			};
+		}
+	}
+
+	/// Serializes the tree whose root is this node to DTB binary.
+	pub fn to_dtb(&self) -> Vec<u8> {
+		let mut builder = DeviceTreeBuilder::new();
+		self.build(&mut builder);
+		builder.finish()
+	}
+
+	fn build(&self, builder: &mut DeviceTreeBuilder) {
+		builder.begin_node(&self.name);
+		for (name, value) in self.properties.iter() {
+			builder.property(name, value);
+		}
+		for child in self.children.iter() {
+			child.build(builder);
+		}
+		builder.end_node();
+	}
+
+	/// Returns the property value.
+	///
+	/// # Arguments
+	/// * `name`
+	pub fn get_property(&self, name: &str) -> Option<&Vec<u8>> {
+		self.properties.iter().find(|(n, _)| n == name).map(|(_, value)| value)
+	}
+
+	/// Adds the property, or overrides the value if it already exists.
+	///
+	/// # Arguments
+	/// * `name`
+	/// * `value`
+	pub fn set_property(&mut self, name: &str, value: Vec<u8>) {
+		match self.properties.iter_mut().find(|(n, _)| n == name) {
+			Some(property) => property.1 = value,
+			None => self.properties.push((name.to_string(), value))
+		};
+	}
+
+	/// Returns the descendant node at the path, e.g. "/soc/clint@2000000".
+	///
+	/// # Arguments
+	/// * `path` Absolute path from this node
+	pub fn find_node_mut(&mut self, path: &str) -> Option<&mut DeviceTreeNode> {
+		let mut node = self;
+		for name in path.split('/').filter(|name| !name.is_empty()) {
+			node = node.children.iter_mut().find(|child| child.name == name)?;
+		}
+		Some(node)
+	}
+
+	/// Merges another node into this node. Properties in `other`
+	/// override the existing ones and child nodes are merged recursively
+	/// or added if they don't exist.
+	///
+	/// # Arguments
+	/// * `other`
+	pub fn merge(&mut self, other: &DeviceTreeNode) {
+		for (name, value) in other.properties.iter() {
+			self.set_property(name, value.clone());
+		}
+		for other_child in other.children.iter() {
+			match self.children.iter_mut().find(|child| child.name == other_child.name) {
+				Some(child) => child.merge(other_child),
+				None => {
+					let mut child = DeviceTreeNode::new(&other_child.name);
+					child.merge(other_child);
+					self.children.push(child);
+				}
+			};
+		}
+	}
+
+	/// Applies an overlay to the tree whose root is this node.
+	/// The overlay is either
+	/// * a tree with `fragment@N` nodes each of which has `target-path`
+	///   property and `__overlay__` child node, as compiled from
+	///   `/plugin/` source by `dtc`, or
+	/// * a plain tree which is merged from the root.
+	///
+	/// Note that phandle references to labels (`target` property,
+	/// `__fixups__`) aren't resolved. Use `target-path` and raw
+	/// phandle values instead. Returns `Err` if a target is not found.
+	///
+	/// # Arguments
+	/// * `overlay` Root node of the overlay
+	pub fn apply_overlay(&mut self, overlay: &DeviceTreeNode) -> Result<(), LoadError> {
+		let fragments = overlay.children.iter()
+			.filter(|child| child.name.starts_with("fragment@") ||
+				child.name == "fragment")
+			.collect::<Vec<&DeviceTreeNode>>();
+		if fragments.is_empty() {
+			self.merge(overlay);
+			return Ok(());
+		}
+		for fragment in fragments {
+			let path = match fragment.get_property("target-path") {
+				Some(value) => read_string(value, 0).map_err(|_| LoadError::DeviceTreeOverlay)?,
+				None => return Err(LoadError::DeviceTreeOverlay)
+			};
+			let content = match fragment.children.iter().find(|child| child.name == "__overlay__") {
+				Some(content) => content,
+				None => return Err(LoadError::DeviceTreeOverlay)
+			};
+			match self.find_node_mut(&path) {
+				Some(target) => target.merge(content),
+				None => return Err(LoadError::DeviceTreeOverlay)
+			};
+		}
+		Ok(())
+	}
+}
+
+/// Applies DTB overlay to base DTB and returns the merged DTB binary.
+/// Returns `Err` if either DTB is broken or the overlay target is not found.
+/// See [`DeviceTreeNode::apply_overlay`](struct.DeviceTreeNode.html#method.apply_overlay).
+///
+/// # Arguments
+/// * `base` Base DTB binary
+/// * `overlay` Overlay DTB binary
+pub fn apply_dtb_overlay(base: &[u8], overlay: &[u8]) -> Result<Vec<u8>, LoadError> {
+	let mut root = DeviceTreeNode::from_dtb(base)?;
+	root.apply_overlay(&DeviceTreeNode::from_dtb(overlay)?)?;
+	Ok(root.to_dtb())
+}
+
+/// Sets the physical address range of the initial ramdisk to `/chosen`
+/// node as `linux,initrd-start` and `linux,initrd-end` and returns
+/// the DTB binary. The node is added if it doesn't exist. Returns `Err`
+/// if the DTB is broken.
+///
+/// # Arguments
+/// * `dtb` DTB binary
+/// * `start` Physical address of the initial ramdisk
+/// * `end` Physical address right after the initial ramdisk
+pub fn set_dtb_initrd(dtb: &[u8], start: u64, end: u64) -> Result<Vec<u8>, LoadError> {
+	let mut root = DeviceTreeNode::from_dtb(dtb)?;
+	let mut chosen = DeviceTreeNode::new("chosen");
+	chosen.set_property("linux,initrd-start", start.to_be_bytes().to_vec());
+	chosen.set_property("linux,initrd-end", end.to_be_bytes().to_vec());
+	let mut tree = DeviceTreeNode::new("");
+	tree.children.push(chosen);
+	root.merge(&tree);
+	Ok(root.to_dtb())
+}
+
+/// Machine properties described in device tree besides `EmulatorConfig`.
+/// They are decided at runtime, e.g. by the loaded program.
+pub struct MachineDescription {
+	/// The number of harts
+	pub hart_num: usize,
+	/// ISA string, e.g. "rv64imafdcsu"
+	pub isa: String,
+	/// Main memory size in bytes
+	pub memory_capacity: u64,
+	/// Whether virtio network device is connected to a backend
+	pub has_network: bool,
+	/// Whether virtio sound device is connected to the host
+	pub has_sound: bool
+}
+
+impl Default for MachineDescription {
+	fn default() -> Self {
+		MachineDescription {
+			hart_num: 1,
+			isa: "rv64imafdcsu".to_string(),
+			memory_capacity: DEFAULT_MEMORY_CAPACITY,
+			has_network: false,
+			has_sound: false
+		}
+	}
+}
+
+// Adds IMSIC and APLIC nodes. Machine-level APLIC domain delegates
+// all the sources to supervisor-level domain.
+fn add_aia_nodes(b: &mut DeviceTreeBuilder, hart_num: usize) {
+	let machine_aplic_phandle = get_machine_aplic_phandle(hart_num);
+	let supervisor_aplic_phandle = get_plic_phandle(hart_num);
+	let machine_imsic_phandle = get_machine_imsic_phandle(hart_num);
+	let supervisor_imsic_phandle = get_supervisor_imsic_phandle(hart_num);
+	for (base, phandle, cause) in [
+		(IMSIC_MACHINE_BASE, machine_imsic_phandle, 0xb),
+		(IMSIC_SUPERVISOR_BASE, supervisor_imsic_phandle, 0x9)
+	] {
+		b.begin_node(&format!("imsics@{:x}", base));
+		b.property_cells("phandle", &[phandle]);
+		b.property_cells("riscv,num-ids", &[IMSIC_ID_NUM - 1]);
+		b.property_reg(base, IMSIC_FILE_SIZE * hart_num as u64);
+		// External interrupt of each hart
+		let mut imsic_interrupts = vec![];
+		for hart in 0..hart_num {
+			imsic_interrupts.extend_from_slice(&[get_cpu_intc_phandle(hart), cause]);
+		}
+		b.property_cells("interrupts-extended", &imsic_interrupts);
+		b.property_empty("msi-controller");
+		b.property_empty("interrupt-controller");
+		b.property_cells("#interrupt-cells", &[0]);
+		b.property_string("compatible", "riscv,imsics");
+		b.end_node();
+	}
+	for (base, phandle, msi_parent) in [
+		(APLIC_MACHINE_BASE, machine_aplic_phandle, machine_imsic_phandle),
+		(APLIC_SUPERVISOR_BASE, supervisor_aplic_phandle, supervisor_imsic_phandle)
+	] {
+		b.begin_node(&format!("aplic@{:x}", base));
+		b.property_cells("phandle", &[phandle]);
+		b.property_cells("riscv,num-sources", &[APLIC_SOURCE_NUM - 1]);
+		b.property_reg(base, APLIC_DOMAIN_SIZE);
+		if phandle == machine_aplic_phandle {
+			b.property_cells("riscv,delegation", &[supervisor_aplic_phandle, 1, APLIC_SOURCE_NUM - 1]);
+			b.property_cells("riscv,children", &[supervisor_aplic_phandle]);
+		}
+		b.property_cells("msi-parent", &[msi_parent]);
+		b.property_empty("interrupt-controller");
+		b.property_cells("#interrupt-cells", &[2]);
+		b.property_string("compatible", "riscv,aplic");
+		b.end_node();
+	}
+}
+
+// Adds ACLINT MSWI, MTIMER, and SSWI nodes in place of CLINT node.
+// MSWI and MTIMER registers are at the same addresses as CLINT ones.
+fn add_aclint_nodes(b: &mut DeviceTreeBuilder, hart_num: usize) {
+	// The interrupt of each hart
+	let hart_interrupts = |cause: u32| {
+		let mut interrupts = vec![];
+		for hart in 0..hart_num {
+			interrupts.extend_from_slice(&[get_cpu_intc_phandle(hart), cause]);
+		}
+		interrupts
+	};
+	b.begin_node("mswi@2000000");
+	b.property_cells("interrupts-extended", &hart_interrupts(0x3));
+	b.property_reg(0x2000000, 0x4000);
+	b.property_string("compatible", "riscv,aclint-mswi");
+	b.end_node();
+	b.begin_node("mtimer@2004000");
+	b.property_cells("interrupts-extended", &hart_interrupts(0x7));
+	// mtimecmp registers and mtime register
+	b.property_cells("reg", &[0, 0x2004000, 0, 0x7ff8, 0, 0x200bff8, 0, 0x8]);
+	b.property_string("compatible", "riscv,aclint-mtimer");
+	b.end_node();
+	b.begin_node(&format!("sswi@{:x}", SSWI_BASE));
+	b.property_cells("interrupts-extended", &hart_interrupts(0x1));
+	b.property_reg(SSWI_BASE, SSWI_SIZE);
+	b.property_string("compatible", "riscv,aclint-sswi");
+	b.end_node();
+}
+
+// Adds cpus node with a node per hart
+fn add_cpu_nodes(b: &mut DeviceTreeBuilder, hart_num: usize, isa: &str, mmu_type: &str, timebase_frequency: u32) {
+	b.begin_node("cpus");
+	b.property_cells("#address-cells", &[1]);
+	b.property_cells("#size-cells", &[0]);
+	b.property_cells("timebase-frequency", &[timebase_frequency]);
+	b.begin_node("cpu-map");
+	b.begin_node("cluster0");
+	for hart in 0..hart_num {
+		b.begin_node(&format!("core{}", hart));
+		b.property_cells("cpu", &[get_cpu_phandle(hart)]);
+		b.end_node();
+	}
+	b.end_node();
+	b.end_node();
+	for hart in 0..hart_num {
+		b.begin_node(&format!("cpu@{:x}", hart));
+		b.property_cells("phandle", &[get_cpu_phandle(hart)]);
+		b.property_string("device_type", "cpu");
+		b.property_cells("reg", &[hart as u32]);
+		b.property_string("status", "okay");
+		b.property_string("compatible", "riscv");
+		b.property_string("riscv,isa", isa);
+		b.property_string("mmu-type", mmu_type);
+		b.begin_node("interrupt-controller");
+		b.property_cells("#interrupt-cells", &[1]);
+		b.property_empty("interrupt-controller");
+		b.property_string("compatible", "riscv,cpu-intc");
+		b.property_cells("phandle", &[get_cpu_intc_phandle(hart)]);
+		b.end_node();
+		b.end_node();
+	}
+	b.end_node();
+}
+
+// Adds memory node, with the shadow stack region reserved if any
+fn add_memory_node(b: &mut DeviceTreeBuilder, memory_capacity: u64, shadow_stack: Option<&ShadowStackConfig>) {
+	b.begin_node("memory@80000000");
+	b.property_string("device_type", "memory");
+	b.property_reg(DRAM_BASE, memory_capacity);
+	b.end_node();
+	if let Some(shadow_stack) = shadow_stack {
+		b.begin_node("reserved-memory");
+		b.property_cells("#address-cells", &[2]);
+		b.property_cells("#size-cells", &[2]);
+		b.property_empty("ranges");
+		b.begin_node(&format!("shadow-stack@{:x}", shadow_stack.base));
+		b.property_reg(shadow_stack.base, shadow_stack.size);
+		b.property_empty("no-map");
+		b.end_node();
+		b.end_node();
+	}
+}
+
+// Adds CLINT node, or ACLINT nodes in place of it
+fn add_clint_nodes(b: &mut DeviceTreeBuilder, aclint: bool, hart_num: usize) {
+	match aclint {
+		true => add_aclint_nodes(b, hart_num),
+		false => {
+			b.begin_node("clint@2000000");
+			// Machine software and timer interrupts of each hart
+			let mut clint_interrupts = vec![];
+			for hart in 0..hart_num {
+				clint_interrupts.extend_from_slice(&[get_cpu_intc_phandle(hart), 0x3, get_cpu_intc_phandle(hart), 0x7]);
+			}
+			b.property_cells("interrupts-extended", &clint_interrupts);
+			b.property_reg(0x2000000, 0x10000);
+			b.property_string("compatible", "riscv,clint0");
+			b.end_node();
+		}
+	};
+}
+
+/// Generates DTB binary describing the machine of the configuration.
+///
+/// # Arguments
+/// * `config`
+/// * `machine`
+pub fn generate_dtb(config: &EmulatorConfig, machine: &MachineDescription) -> Vec<u8> {
+	let hart_num = machine.hart_num;
+	let isa = &machine.isa;
+	let plic_phandle = get_plic_phandle(hart_num);
+	let clock_phandle = get_clock_phandle(hart_num);
+	let mmu_type = match isa.starts_with("rv32") {
+		true => "riscv,sv32",
+		false => "riscv,sv39"
+	};
+	let aia_enabled = config.interrupt_controller == InterruptControllerType::Aia;
+	let isa = &match aia_enabled {
+		true => format!("{}_smaia_ssaia", isa),
+		false => isa.to_string()
+	};
+	// APLIC takes the trigger type as well as the source number
+	let interrupts = |irq: u32, edge: bool| match aia_enabled {
+		true => vec![irq, match edge {
+			true => 1, // IRQ_TYPE_EDGE_RISING
+			false => 4 // IRQ_TYPE_LEVEL_HIGH
+		}],
+		false => vec![irq]
+	};
+
+	// sifive_u isn't sifive,hifive-unleashed-a00 compatible because
+	// firmware for it takes hart 0 as the E51 monitor core without MMU,
+	// which is the only hart here
+	let (compatible, model) = match config.machine {
+		MachineType::Virt => ("riscv-virtio", "riscv-virtio,qemu"),
+		MachineType::Spike => ("ucbbar,spike-bare-dev", "ucbbar,spike-bare"),
+		MachineType::SifiveU => ("riscv-emu-rust,sifive-u", "SiFive HiFive Unleashed A00")
+	};
+
+	let mut b = DeviceTreeBuilder::new();
+	b.begin_node("");
+	b.property_cells("#address-cells", &[2]);
+	b.property_cells("#size-cells", &[2]);
+	b.property_string("compatible", compatible);
+	b.property_string("model", model);
+
+	if !config.has_platform_devices() {
+		// The console is on HTIF, which SBI firmware drives
+		b.begin_node("chosen");
+		b.property_string("bootargs", "console=hvc0 earlycon=sbi");
+		b.property_string("stdout-path", "/htif");
+		b.end_node();
+		b.begin_node("htif");
+		b.property_string("compatible", "ucb,htif0");
+		b.end_node();
+		add_cpu_nodes(&mut b, hart_num, isa, mmu_type, config.timer.frequency);
+		add_memory_node(&mut b, machine.memory_capacity, config.shadow_stack.as_ref());
+		b.begin_node("soc");
+		b.property_cells("#address-cells", &[2]);
+		b.property_cells("#size-cells", &[2]);
+		b.property_string("compatible", "simple-bus");
+		b.property_empty("ranges");
+		add_clint_nodes(&mut b, config.aclint, hart_num);
+		b.end_node();
+		b.end_node();
+		return b.finish();
+	}
+
+	let (console_node, console_option) = match config.console {
+		ConsoleType::Ns16550a => ("uart@10000000", "ttyS0"),
+		ConsoleType::SifiveUart => ("serial@10010000", "console=ttySIF0")
+	};
+
+	b.begin_node("chosen");
+	b.property_string("bootargs", &format!("root=/dev/vda rw {}", console_option));
+	b.property_string("stdout-path", &format!("/{}", console_node));
+	b.end_node();
+
+	b.begin_node(console_node);
+	match config.console {
+		ConsoleType::Ns16550a => {
+			b.property_cells("interrupts", &interrupts(0xa, true));
+			b.property_cells("interrupt-parent", &[plic_phandle]);
+			b.property_cells("clock-frequency", &[0x384000]);
+			b.property_reg(0x10000000, 0x100);
+			b.property_string("compatible", "ns16550a");
+		},
+		ConsoleType::SifiveUart => {
+			b.property_cells("interrupts", &interrupts(0x4, false));
+			b.property_cells("interrupt-parent", &[plic_phandle]);
+			b.property_cells("clocks", &[clock_phandle]);
+			b.property_reg(0x10010000, 0x100);
+			b.property_string("compatible", "sifive,uart0");
+		}
+	};
+	b.end_node();
+
+	if config.console == ConsoleType::SifiveUart {
+		// SiFive UART driver requires the input clock
+		b.begin_node("hfclk");
+		b.property_cells("phandle", &[clock_phandle]);
+		b.property_cells("#clock-cells", &[0]);
+		b.property_cells("clock-frequency", &[0x384000]);
+		b.property_string("compatible", "fixed-clock");
+		b.end_node();
+	}
+
+	for i in 0..config.serial_port_num.min(MAX_SERIAL_PORT_NUM) {
+		let base = SERIAL_PORT_BASE + i as u64 * UART_SIZE;
+		b.begin_node(&format!("serial@{:x}", base));
+		b.property_cells("interrupts", &interrupts(SERIAL_PORT_IRQ_BASE + i as u32, true));
+		b.property_cells("interrupt-parent", &[plic_phandle]);
+		b.property_cells("clock-frequency", &[0x384000]);
+		b.property_reg(base, UART_SIZE);
+		b.property_string("compatible", "ns16550a");
+		b.end_node();
+	}
+
+	// Virtio devices are compiled only with virtio feature
+	if cfg!(feature = "virtio") {
+		b.begin_node("virtio_mmio@10001000");
+		b.property_cells("interrupts", &interrupts(0x1, false));
+		b.property_cells("interrupt-parent", &[plic_phandle]);
+		b.property_reg(0x10001000, 0x1000);
+		b.property_string("compatible", "virtio,mmio");
+		b.end_node();
+	}
+
+	if machine.has_network {
+		b.begin_node("virtio_mmio@10002000");
+		b.property_cells("interrupts", &interrupts(0x2, false));
+		b.property_cells("interrupt-parent", &[plic_phandle]);
+		b.property_reg(0x10002000, 0x1000);
+		b.property_string("compatible", "virtio,mmio");
+		b.end_node();
+	}
+
+	if machine.has_sound {
+		b.begin_node("virtio_mmio@10003000");
+		b.property_cells("interrupts", &interrupts(0x5, false));
+		b.property_cells("interrupt-parent", &[plic_phandle]);
+		b.property_reg(0x10003000, 0x1000);
+		b.property_string("compatible", "virtio,mmio");
+		b.end_node();
+	}
+
+	if cfg!(feature = "virtio") && config.balloon {
+		b.begin_node("virtio_mmio@10004000");
+		b.property_cells("interrupts", &interrupts(0x6, false));
+		b.property_cells("interrupt-parent", &[plic_phandle]);
+		b.property_reg(0x10004000, 0x1000);
+		b.property_string("compatible", "virtio,mmio");
+		b.end_node();
+	}
+
+	if cfg!(feature = "virtio") && config.virtio_console {
+		b.begin_node("virtio_mmio@10007000");
+		b.property_cells("interrupts", &interrupts(VIRTIO_CONSOLE_IRQ, false));
+		b.property_cells("interrupt-parent", &[plic_phandle]);
+		b.property_reg(0x10007000, 0x1000);
+		b.property_string("compatible", "virtio,mmio");
+		b.end_node();
+	}
+
+	if let Some(framebuffer) = &config.framebuffer {
+		let stride = framebuffer.width * FRAMEBUFFER_BYTES_PER_PIXEL;
+		b.begin_node(&format!("framebuffer@{:x}", FRAMEBUFFER_BASE));
+		b.property_reg(FRAMEBUFFER_BASE, (stride * framebuffer.height) as u64);
+		b.property_cells("width", &[framebuffer.width]);
+		b.property_cells("height", &[framebuffer.height]);
+		b.property_cells("stride", &[stride]);
+		b.property_string("format", "a8r8g8b8");
+		b.property_string("compatible", "simple-framebuffer");
+		b.end_node();
+	}
+
+	if let Some(shared_memory) = &config.shared_memory {
+		b.begin_node(&format!("shmem@{:x}", SHARED_MEMORY_BASE));
+		let mut reg = vec![
+			(SHARED_MEMORY_BASE >> 32) as u32, SHARED_MEMORY_BASE as u32,
+			(shared_memory.size >> 32) as u32, shared_memory.size as u32
+		];
+		if shared_memory.doorbell {
+			reg.extend_from_slice(&[
+				(SHARED_MEMORY_REGISTERS_BASE >> 32) as u32, SHARED_MEMORY_REGISTERS_BASE as u32,
+				0, SHARED_MEMORY_REGISTERS_SIZE as u32
+			]);
+			b.property_cells("interrupts", &interrupts(SHARED_MEMORY_IRQ, false));
+			b.property_cells("interrupt-parent", &[plic_phandle]);
+		}
+		b.property_cells("reg", &reg);
+		b.property_string("compatible", "riscv-emu-rust,shmem");
+		b.end_node();
+	}
+
+	let has_test_finisher = config.machine == MachineType::Virt;
+	if has_test_finisher {
+		// SBI firmware and Linux power off through the test finisher
+		b.begin_node("poweroff");
+		b.property_cells("value", &[FINISHER_PASS as u32]);
+		b.property_cells("offset", &[0]);
+		b.property_cells("regmap", &[get_test_finisher_phandle(hart_num)]);
+		b.property_string("compatible", "syscon-poweroff");
+		b.end_node();
+	}
+
+	add_cpu_nodes(&mut b, hart_num, isa, mmu_type, config.timer.frequency);
+	add_memory_node(&mut b, machine.memory_capacity, config.shadow_stack.as_ref());
+
+	b.begin_node("soc");
+	b.property_cells("#address-cells", &[2]);
+	b.property_cells("#size-cells", &[2]);
+	b.property_string("compatible", "simple-bus");
+	b.property_empty("ranges");
+	if has_test_finisher {
+		b.begin_node(&format!("test@{:x}", TEST_FINISHER_BASE));
+		b.property_cells("phandle", &[get_test_finisher_phandle(hart_num)]);
+		b.property_reg(TEST_FINISHER_BASE, TEST_FINISHER_SIZE);
+		b.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+		b.end_node();
+	}
+	match aia_enabled {
+		true => add_aia_nodes(&mut b, hart_num),
+		false => {
+			b.begin_node("interrupt-controller@c000000");
+			b.property_cells("phandle", &[plic_phandle]);
+			b.property_cells("riscv,ndev", &[0x35]);
+			b.property_reg(0xc000000, 0x4000000);
+			// Machine and supervisor external interrupts of each hart
+			let mut plic_interrupts = vec![];
+			for hart in 0..hart_num {
+				plic_interrupts.extend_from_slice(&[get_cpu_intc_phandle(hart), 0xb, get_cpu_intc_phandle(hart), 0x9]);
+			}
+			b.property_cells("interrupts-extended", &plic_interrupts);
+			b.property_empty("interrupt-controller");
+			b.property_string("compatible", "riscv,plic0");
+			b.property_cells("#interrupt-cells", &[1]);
+			b.property_cells("#address-cells", &[0]);
+			b.end_node();
+		}
+	};
+	add_clint_nodes(&mut b, config.aclint, hart_num);
+	b.end_node();
+
+	b.end_node();
+	b.finish()
+}
+
+#[cfg(test)]
+mod test_device_tree {
+	use super::*;
+	use config::{FramebufferConfig, SharedMemoryConfig};
+
+	#[test]
+	#[cfg(feature = "virtio")]
+	fn generate_default_dtb() {
+		// Default configuration should reproduce the bundled DTB
+		let dtb = generate_dtb(&EmulatorConfig::default(), &MachineDescription::default());
+		assert_eq!(include_bytes!("./device/dtb.dtb").to_vec(), dtb);
+	}
+
+	#[test]
+	fn generate_sifive_uart_dtb() {
+		let config = EmulatorConfig {
+			console: ConsoleType::SifiveUart,
+			..EmulatorConfig::default()
+		};
+		let dtb = generate_dtb(&config, &MachineDescription::default());
+		assert_eq!(&FDT_MAGIC.to_be_bytes(), &dtb[0..4]);
+		assert_eq!(&(dtb.len() as u32).to_be_bytes(), &dtb[4..8]);
+		let find = |s: &[u8]| dtb.windows(s.len()).any(|w| w == s);
+		assert!(find(b"serial@10010000\0"));
+		assert!(find(b"sifive,uart0\0"));
+		assert!(!find(b"ns16550a\0"));
+	}
+
+	#[test]
+	fn generate_machine_dtb() {
+		let config = EmulatorConfig::new_with_machine(MachineType::Spike);
+		let dtb = generate_dtb(&config, &MachineDescription::default());
+		let find = |s: &[u8]| dtb.windows(s.len()).any(|w| w == s);
+		assert!(find(b"ucbbar,spike-bare-dev\0"));
+		assert!(find(b"ucb,htif0\0"));
+		assert!(find(b"clint@2000000\0"));
+		assert!(find(b"cpu@0\0"));
+		assert!(find(b"memory@80000000\0"));
+		assert!(!find(b"uart@10000000\0"));
+		assert!(!find(b"virtio_mmio@10001000\0"));
+		assert!(!find(b"interrupt-controller@c000000\0"));
+
+		let config = EmulatorConfig::new_with_machine(MachineType::SifiveU);
+		let dtb = generate_dtb(&config, &MachineDescription::default());
+		let find = |s: &[u8]| dtb.windows(s.len()).any(|w| w == s);
+		assert!(find(b"SiFive HiFive Unleashed A00\0"));
+		assert!(find(b"sifive,uart0\0"));
+		assert!(find(b"interrupt-controller@c000000\0"));
+		assert!(!find(b"ucb,htif0\0"));
+	}
+
+	#[test]
+	fn generate_serial_ports_dtb() {
+		let config = EmulatorConfig {
+			serial_port_num: 2,
+			..EmulatorConfig::default()
+		};
+		let dtb = generate_dtb(&config, &MachineDescription::default());
+		let find = |s: &[u8]| dtb.windows(s.len()).any(|w| w == s);
+		assert!(find(b"uart@10000000\0"));
+		assert!(find(b"serial@10006000\0"));
+		assert!(find(b"serial@10006100\0"));
+		assert!(!find(b"serial@10006200\0"));
+	}
+
+	#[test]
+	fn generate_dtb_from_machine() {
+		let machine = MachineDescription {
+			hart_num: 2,
+			isa: "rv32imac".to_string(),
+			memory_capacity: 0x4000000,
+			has_network: true,
+			has_sound: true
+		};
+		let dtb = generate_dtb(&EmulatorConfig::default(), &machine);
+		let find = |s: &[u8]| dtb.windows(s.len()).any(|w| w == s);
+		assert!(find(b"cpu@0\0"));
+		assert!(find(b"cpu@1\0"));
+		assert!(!find(b"cpu@2\0"));
+		assert!(find(b"rv32imac\0"));
+		assert!(find(b"riscv,sv32\0"));
+		assert!(find(b"virtio_mmio@10002000\0"));
+		assert!(find(b"virtio_mmio@10003000\0"));
+		assert!(find(&[0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x04, 0, 0, 0]));
+	}
+
+	#[test]
+	fn generate_aia_dtb() {
+		let config = EmulatorConfig {
+			interrupt_controller: InterruptControllerType::Aia,
+			..EmulatorConfig::default()
+		};
+		let dtb = generate_dtb(&config, &MachineDescription::default());
+		let find = |s: &[u8]| dtb.windows(s.len()).any(|w| w == s);
+		assert!(find(b"imsics@24000000\0"));
+		assert!(find(b"imsics@28000000\0"));
+		assert!(find(b"aplic@c000000\0"));
+		assert!(find(b"aplic@d000000\0"));
+		assert!(find(b"rv64imafdcsu_smaia_ssaia\0"));
+		assert!(!find(b"riscv,plic0\0"));
+		let root = DeviceTreeNode::from_dtb(&dtb).unwrap();
+		assert_eq!(root.to_dtb(), dtb);
+	}
+
+	#[test]
+	fn generate_aclint_dtb() {
+		let config = EmulatorConfig {
+			aclint: true,
+			..EmulatorConfig::default()
+		};
+		let dtb = generate_dtb(&config, &MachineDescription::default());
+		let find = |s: &[u8]| dtb.windows(s.len()).any(|w| w == s);
+		assert!(find(b"riscv,aclint-mswi\0"));
+		assert!(find(b"riscv,aclint-mtimer\0"));
+		assert!(find(b"sswi@2f00000\0"));
+		assert!(!find(b"riscv,clint0\0"));
+	}
+
+	#[test]
+	fn generate_framebuffer_dtb() {
+		let config = EmulatorConfig {
+			framebuffer: Some(FramebufferConfig { width: 640, height: 480 }),
+			..EmulatorConfig::default()
+		};
+		let dtb = generate_dtb(&config, &MachineDescription::default());
+		let mut root = DeviceTreeNode::from_dtb(&dtb).unwrap();
+		let node = root.find_node_mut("/framebuffer@30000000").unwrap();
+		assert_eq!(b"simple-framebuffer\0".to_vec(), *node.get_property("compatible").unwrap());
+		assert_eq!(b"a8r8g8b8\0".to_vec(), *node.get_property("format").unwrap());
+		assert_eq!(2560u32.to_be_bytes().to_vec(), *node.get_property("stride").unwrap());
+		assert_eq!(vec![0, 0, 0, 0, 0x30, 0, 0, 0, 0, 0, 0, 0, 0, 0x12, 0xc0, 0], *node.get_property("reg").unwrap());
+	}
+
+	#[test]
+	fn generate_shared_memory_dtb() {
+		let config = EmulatorConfig {
+			shared_memory: Some(SharedMemoryConfig { size: 0x100000, doorbell: true }),
+			..EmulatorConfig::default()
+		};
+		let dtb = generate_dtb(&config, &MachineDescription::default());
+		let mut root = DeviceTreeNode::from_dtb(&dtb).unwrap();
+		let node = root.find_node_mut("/shmem@40000000").unwrap();
+		assert_eq!(b"riscv-emu-rust,shmem\0".to_vec(), *node.get_property("compatible").unwrap());
+		assert_eq!(vec![
+			0, 0, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0,
+			0, 0, 0, 0, 0x10, 0, 0x50, 0, 0, 0, 0, 0, 0, 0, 1, 0
+		], *node.get_property("reg").unwrap());
+		assert_eq!(7u32.to_be_bytes().to_vec(), *node.get_property("interrupts").unwrap());
+	}
+
+	#[test]
+	fn set_initrd() {
+		let dtb = generate_dtb(&EmulatorConfig::default(), &MachineDescription::default());
+		let mut root = DeviceTreeNode::from_dtb(&set_dtb_initrd(&dtb, 0x87000000, 0x87001234).unwrap()).unwrap();
+		let chosen = root.find_node_mut("/chosen").unwrap();
+		assert_eq!(vec![0, 0, 0, 0, 0x87, 0, 0, 0], *chosen.get_property("linux,initrd-start").unwrap());
+		assert_eq!(vec![0, 0, 0, 0, 0x87, 0, 0x12, 0x34], *chosen.get_property("linux,initrd-end").unwrap());
+		assert!(chosen.get_property("bootargs").is_some());
+
+		let mut b = DeviceTreeBuilder::new();
+		b.begin_node("");
+		b.end_node();
+		let mut root = DeviceTreeNode::from_dtb(&set_dtb_initrd(&b.finish(), 0x1000, 0x2000).unwrap()).unwrap();
+		assert!(root.find_node_mut("/chosen").unwrap().get_property("linux,initrd-end").is_some());
+		assert!(set_dtb_initrd(&dtb[4..], 0x1000, 0x2000).is_err());
+	}
+
+	#[test]
+	fn parse_dtb() {
+		let dtb = generate_dtb(&EmulatorConfig::default(), &MachineDescription::default());
+		let mut root = DeviceTreeNode::from_dtb(&dtb).unwrap();
+		assert_eq!(dtb, root.to_dtb());
+		assert_eq!(b"riscv-virtio\0".to_vec(), *root.get_property("compatible").unwrap());
+		assert!(root.find_node_mut("/soc/clint@2000000").is_some());
+		assert!(root.find_node_mut("/soc/unknown").is_none());
+		assert!(DeviceTreeNode::from_dtb(&dtb[4..]).is_err());
+		assert!(DeviceTreeNode::from_dtb(&dtb[..100]).is_err());
+	}
+
+	#[test]
+	fn apply_overlay() {
+		let base = generate_dtb(&EmulatorConfig::default(), &MachineDescription::default());
+
+		let mut b = DeviceTreeBuilder::new();
+		b.begin_node("");
+		b.begin_node("fragment@0");
+		b.property_string("target-path", "/chosen");
+		b.begin_node("__overlay__");
+		b.property_string("bootargs", "console=ttyS0 quiet");
+		b.end_node();
+		b.end_node();
+		b.begin_node("fragment@1");
+		b.property_string("target-path", "/soc");
+		b.begin_node("__overlay__");
+		b.begin_node("custom@10030000");
+		b.property_string("compatible", "custom,device");
+		b.end_node();
+		b.end_node();
+		b.end_node();
+		b.end_node();
+		let overlay = b.finish();
+
+		let mut root = DeviceTreeNode::from_dtb(&apply_dtb_overlay(&base, &overlay).unwrap()).unwrap();
+		assert_eq!(b"console=ttyS0 quiet\0".to_vec(),
+			*root.find_node_mut("/chosen").unwrap().get_property("bootargs").unwrap());
+		// Other properties remain
+		assert!(root.find_node_mut("/chosen").unwrap().get_property("stdout-path").is_some());
+		assert!(root.find_node_mut("/soc/custom@10030000").is_some());
+		assert!(root.find_node_mut("/soc/clint@2000000").is_some());
+
+		// Unknown target
+		let mut b = DeviceTreeBuilder::new();
+		b.begin_node("");
+		b.begin_node("fragment@0");
+		b.property_string("target-path", "/unknown");
+		b.begin_node("__overlay__");
+		b.end_node();
+		b.end_node();
+		b.end_node();
+		assert!(apply_dtb_overlay(&base, &b.finish()).is_err());
+	}
+}
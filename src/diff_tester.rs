@@ -0,0 +1,314 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use cpu::{StateDiff, Xlen, disassemble, get_register_name};
+use Emulator;
+
+/// Reference implementation `DiffTester` steps alongside the emulator, e.g.
+/// a binding to another simulator. It must start from the same state,
+/// the same program loaded and the PC at its entry.
+pub trait ReferenceModel {
+	/// Runs one instruction, taking a trap if it raises one.
+	fn step(&mut self);
+
+	/// Returns the program counter.
+	fn read_pc(&self) -> u64;
+
+	/// Returns the integer register. Only the lower 32 bits are compared
+	/// in 32-bit mode.
+	///
+	/// # Arguments
+	/// * `reg` 1 to 31
+	fn read_register(&self, reg: u8) -> u64;
+
+	/// Returns the floating point register in raw bits, or `None`, the
+	/// default, not to compare floating point registers.
+	///
+	/// # Arguments
+	/// * `reg`
+	fn read_f_register(&self, _reg: u8) -> Option<u64> {
+		None
+	}
+}
+
+/// The first state the emulator and the reference model disagree on,
+/// returned by `DiffTester`. Printed with `Display` as a report.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+	/// Instructions run in lockstep, including the diverging one.
+	/// Zero if the states differ before any step
+	pub steps: u64,
+	/// Virtual address of the diverging instruction
+	pub pc: u64,
+	/// Raw bits of the instruction. Lower 16 bits for compressed
+	/// instruction. `None` if fetching has faulted
+	pub word: Option<u32>,
+	/// For example `ADDI a0,a0,1`
+	pub disassembly: String,
+	/// The states which differ as (name, emulator value, reference value),
+	/// e.g. `pc`, `a0`, or `f10`
+	pub mismatches: Vec<(String, u64, u64)>,
+	/// What the instruction has changed on the emulator
+	pub diff: Option<Box<StateDiff>>
+}
+
+impl fmt::Display for Divergence {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.word {
+			Some(word) => writeln!(f, "Diverged at step {} PC:{:016x} {:08x} {}", self.steps, self.pc, word, self.disassembly)?,
+			None => writeln!(f, "Diverged at step {} PC:{:016x} (fetch fault)", self.steps, self.pc)?
+		};
+		for (name, emulator_value, reference_value) in self.mismatches.iter() {
+			writeln!(f, "  {}: emulator 0x{:x}, reference 0x{:x}", name, emulator_value, reference_value)?;
+		}
+		if let Some(diff) = &self.diff {
+			writeln!(f, "Emulator changes:")?;
+			writeln!(f, "  pc: 0x{:x} -> 0x{:x}", diff.pc.0, diff.pc.1)?;
+			for (register, old_value, new_value) in diff.x.iter() {
+				writeln!(f, "  {}: 0x{:x} -> 0x{:x}", get_register_name(*register as usize), old_value, new_value)?;
+			}
+			for (register, old_value, new_value) in diff.f.iter() {
+				writeln!(f, "  f{}: 0x{:x} -> 0x{:x}", register, old_value, new_value)?;
+			}
+			for (csr, old_value, new_value) in diff.csr.iter() {
+				writeln!(f, "  csr 0x{:03x}: 0x{:x} -> 0x{:x}", csr, old_value, new_value)?;
+			}
+			for (address, old_value, new_value) in diff.memory.iter() {
+				writeln!(f, "  memory 0x{:x}: 0x{:02x} -> 0x{:02x}", address, old_value, new_value)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Steps the emulator alongside a reference model one instruction at
+/// a time and compares the PC and the registers after every instruction,
+/// to find where the emulator goes wrong on e.g. randomized instruction
+/// streams. Interrupts depend on timing so keep them disabled on both.
+///
+/// ```ignore
+/// let mut tester = DiffTester::new(reference);
+/// if let Err(divergence) = tester.run(&mut emulator, 1000000) {
+///     println!("{}", divergence);
+/// }
+/// ```
+pub struct DiffTester<R: ReferenceModel> {
+	reference: R,
+	steps: u64
+}
+
+impl<R: ReferenceModel> DiffTester<R> {
+	/// Creates a new `DiffTester`.
+	///
+	/// # Arguments
+	/// * `reference`
+	pub fn new(reference: R) -> Self {
+		DiffTester {
+			reference,
+			steps: 0
+		}
+	}
+
+	/// Returns the reference model.
+	pub fn get_reference(&self) -> &R {
+		&self.reference
+	}
+
+	/// Returns the mutable reference model, e.g. to load the next program.
+	pub fn get_mut_reference(&mut self) -> &mut R {
+		&mut self.reference
+	}
+
+	/// Returns the number of instructions run in lockstep so far.
+	pub fn get_steps(&self) -> u64 {
+		self.steps
+	}
+
+	/// Compares the current states without stepping, e.g. before
+	/// the first step. Returns the differences as `Err`.
+	///
+	/// # Arguments
+	/// * `emulator`
+	pub fn compare(&self, emulator: &mut Emulator) -> Result<(), Divergence> {
+		let pc = emulator.get_cpu().read_pc();
+		let (word, disassembly) = get_instruction(emulator);
+		match self.get_mismatches(emulator) {
+			mismatches if mismatches.is_empty() => Ok(()),
+			mismatches => Err(Divergence {
+				steps: self.steps,
+				pc,
+				word,
+				disassembly,
+				mismatches,
+				diff: None
+			})
+		}
+	}
+
+	/// Runs one instruction on both and compares the states. Returns
+	/// the differences as `Err`.
+	///
+	/// # Arguments
+	/// * `emulator`
+	pub fn step(&mut self, emulator: &mut Emulator) -> Result<(), Divergence> {
+		let pc = emulator.get_cpu().read_pc();
+		let (word, disassembly) = get_instruction(emulator);
+		let diff = emulator.step_diff();
+		self.reference.step();
+		self.steps += 1;
+		match self.get_mismatches(emulator) {
+			mismatches if mismatches.is_empty() => Ok(()),
+			mismatches => Err(Divergence {
+				steps: self.steps,
+				pc,
+				word,
+				disassembly,
+				mismatches,
+				diff: Some(Box::new(diff))
+			})
+		}
+	}
+
+	/// Runs up to the number of instructions on both, stopping at
+	/// the first divergence. Returns the number of instructions run, or
+	/// the divergence as `Err`.
+	///
+	/// # Arguments
+	/// * `emulator`
+	/// * `max_steps`
+	pub fn run(&mut self, emulator: &mut Emulator, max_steps: u64) -> Result<u64, Divergence> {
+		for i in 0..max_steps {
+			self.step(emulator)?;
+			if emulator.get_exit_code().is_some() {
+				return Ok(i + 1);
+			}
+		}
+		Ok(max_steps)
+	}
+
+	// Returns the states which differ as (name, emulator value, reference value)
+	fn get_mismatches(&self, emulator: &Emulator) -> Vec<(String, u64, u64)> {
+		let cpu = emulator.get_cpu();
+		let mask = match cpu.get_xlen() {
+			Xlen::Bit32 => 0xffffffff,
+			Xlen::Bit64 => 0xffffffffffffffff
+		};
+		let mut mismatches = vec![];
+		let (pc, reference_pc) = (cpu.read_pc() & mask, self.reference.read_pc() & mask);
+		if pc != reference_pc {
+			mismatches.push(("pc".to_string(), pc, reference_pc));
+		}
+		for reg in 1..32 {
+			let value = cpu.read_register(reg) as u64 & mask;
+			let reference_value = self.reference.read_register(reg) & mask;
+			if value != reference_value {
+				mismatches.push((get_register_name(reg as usize).to_string(), value, reference_value));
+			}
+		}
+		for reg in 0..32 {
+			if let Some(reference_value) = self.reference.read_f_register(reg) {
+				let value = cpu.read_f_register(reg);
+				if value != reference_value {
+					mismatches.push((format!("f{}", reg), value, reference_value));
+				}
+			}
+		}
+		mismatches
+	}
+}
+
+// Returns the raw bits and the disassembly of the instruction at the PC
+fn get_instruction(emulator: &mut Emulator) -> (Option<u32>, String) {
+	let cpu = emulator.get_mut_cpu();
+	let pc = cpu.read_pc();
+	match cpu.get_next_instruction() {
+		Some(instruction) => (Some(instruction.word), disassemble(instruction.word, pc, cpu.get_xlen())),
+		None => (None, String::new())
+	}
+}
+
+#[cfg(test)]
+mod test_diff_tester {
+	use super::*;
+	use cpu::Cpu;
+	use mmu::DRAM_BASE;
+	use terminal::DummyTerminal;
+
+	const CODE: [u32; 3] = [
+		0x00150513, // loop: addi a0, a0, 1
+		0x00a585b3, // add a1, a1, a0
+		0xff9ff06f // jal zero, loop
+	];
+
+	// Another CPU, off by one after the fifth instruction if buggy
+	struct CpuModel {
+		cpu: Cpu,
+		buggy: bool,
+		steps: u64
+	}
+
+	impl ReferenceModel for CpuModel {
+		fn step(&mut self) {
+			self.cpu.tick();
+			self.steps += 1;
+			if self.buggy && self.steps == 5 {
+				self.cpu.write_register(11, self.cpu.read_register(11) + 1);
+			}
+		}
+
+		fn read_pc(&self) -> u64 {
+			self.cpu.read_pc()
+		}
+
+		fn read_register(&self, reg: u8) -> u64 {
+			self.cpu.read_register(reg) as u64
+		}
+	}
+
+	fn store_code(cpu: &mut Cpu) {
+		for (i, word) in CODE.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		cpu.write_register(11, 0);
+		cpu.update_pc(DRAM_BASE);
+	}
+
+	fn create_tester(buggy: bool) -> (Emulator, DiffTester<CpuModel>) {
+		let mut emulator = Emulator::new(Box::new(DummyTerminal::new()));
+		emulator.get_mut_cpu().get_mut_mmu().init_memory(0x100);
+		store_code(emulator.get_mut_cpu());
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x100);
+		store_code(&mut cpu);
+		(emulator, DiffTester::new(CpuModel {
+			cpu,
+			buggy,
+			steps: 0
+		}))
+	}
+
+	#[test]
+	fn run() {
+		let (mut emulator, mut tester) = create_tester(false);
+		assert_eq!(Ok(()), tester.compare(&mut emulator));
+		assert_eq!(Ok(100), tester.run(&mut emulator, 100));
+
+		let (mut emulator, mut tester) = create_tester(true);
+		let divergence = match tester.run(&mut emulator, 100) {
+			Ok(_) => panic!("Unexpectedly matched"),
+			Err(divergence) => divergence
+		};
+		assert_eq!(5, divergence.steps);
+		assert_eq!(DRAM_BASE + 4, divergence.pc);
+		assert_eq!(Some(0x00a585b3), divergence.word);
+		assert_eq!("ADD a1,a1,a0", divergence.disassembly);
+		assert_eq!(vec![("a1".to_string(), 3, 4)], divergence.mismatches);
+		assert_eq!(vec![(11, 1, 3)], divergence.diff.as_ref().unwrap().x);
+		assert!(divergence.to_string().starts_with("Diverged at step 5 PC:0000000080000004 00a585b3 ADD a1,a1,a0\n  a1: emulator 0x3, reference 0x4\n"));
+		assert_eq!(5, tester.get_steps());
+	}
+}
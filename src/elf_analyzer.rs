@@ -1,6 +1,8 @@
-extern crate fnv;
+use alloc::string::String;
+use alloc::vec::Vec;
 
-use self::fnv::FnvHashMap;
+use collections::FnvHashMap;
+use error::LoadError;
 
 /// ELF header
 pub struct Header {
@@ -14,26 +16,26 @@ pub struct Header {
 	_e_machine: u16,
 	_e_version: u32,
 	pub e_entry: u64,
-	_e_phoff: u64,
+	pub e_phoff: u64,
 	e_shoff: u64,
 	_e_flags: u32,
 	_e_ehsize: u16,
-	_e_phentsize: u16,
-	_e_phnum: u16,
+	pub e_phentsize: u16,
+	pub e_phnum: u16,
 	_e_shentsize: u16,
 	e_shnum: u16,
 	_e_shstrndx: u16
 }
 
 /// ELF program header
-pub struct _ProgramHeader {
-	_p_type: u32,
+pub struct ProgramHeader {
+	pub p_type: u32,
 	_p_flags: u32,
-	_p_offset: u64,
-	_p_vaddr: u64,
+	pub p_offset: u64,
+	pub p_vaddr: u64,
 	_p_paddr: u64,
-	_p_filesz: u64,
-	_p_memsz: u64,
+	pub p_filesz: u64,
+	pub p_memsz: u64,
 	_p_align: u64
 }
 
@@ -61,6 +63,29 @@ pub struct SymbolEntry {
 	_st_size: u64
 }
 
+/// Program data section with its content, in `ElfImage`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElfSection {
+	/// Address the section is loaded at
+	pub address: u64,
+	pub data: Vec<u8>
+}
+
+/// What the emulator loads from an ELF file, returned by `parse_elf()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElfImage {
+	/// 32 or 64
+	pub width: u8,
+	/// Entry point address
+	pub entry: u64,
+	/// Program data sections which have content in the file
+	pub sections: Vec<ElfSection>,
+	/// Function and notype symbols to their addresses
+	pub symbols: FnvHashMap<String, u64>,
+	/// Address of `.tohost` section. riscv-tests have it
+	pub tohost_addr: Option<u64>
+}
+
 /// ELF file analyzer
 pub struct ElfAnalyzer {
 	data: Vec<u8>
@@ -89,40 +114,44 @@ impl ElfAnalyzer {
 		true
 	}
 
-	/// Reads ELF header
-	pub fn read_header(&self) -> Header {
-		let e_class = self.read_byte(4);
+	/// Reads ELF header. Returns `Err` if the file isn't ELF or is
+	/// truncated.
+	pub fn read_header(&self) -> Result<Header, LoadError> {
+		if !self.validate() {
+			return Err(LoadError::InvalidElf("no ELF magic number"));
+		}
+		let e_class = self.read_byte(4)?;
 
 		let e_width = match e_class {
 			1 => 32,
 			2 => 64,
-			_ => panic!("Unknown e_class:{:X}", e_class)
+			_ => return Err(LoadError::InvalidElf("unknown class"))
 		};
 
-		let e_endian = self.read_byte(5);
-		let e_elf_version = self.read_byte(6);
-		let e_osabi = self.read_byte(7);
-		let e_abi_version = self.read_byte(8);
+		let e_endian = self.read_byte(5)?;
+		let e_elf_version = self.read_byte(6)?;
+		let e_osabi = self.read_byte(7)?;
+		let e_abi_version = self.read_byte(8)?;
 
 		let mut offset = 0x10;
 
-		let e_type = self.read_halfword(offset);
+		let e_type = self.read_halfword(offset)?;
 		offset += 2;
 
-		let e_machine = self.read_halfword(offset);
+		let e_machine = self.read_halfword(offset)?;
 		offset += 2;
 
-		let e_version = self.read_word(offset);
+		let e_version = self.read_word(offset)?;
 		offset += 4;
 
 		let e_entry = match e_width {
 			64 => {
-				let data = self.read_doubleword(offset);
+				let data = self.read_doubleword(offset)?;
 				offset += 8;
 				data
 			},
 			_ => {
-				let data = self.read_word(offset);
+				let data = self.read_word(offset)?;
 				offset += 4;
 				data as u64
 			}
@@ -130,12 +159,12 @@ impl ElfAnalyzer {
 
 		let e_phoff = match e_width {
 			64 => {
-				let data = self.read_doubleword(offset);
+				let data = self.read_doubleword(offset)?;
 				offset += 8;
 				data
 			},
 			_ => {
-				let data = self.read_word(offset);
+				let data = self.read_word(offset)?;
 				offset += 4;
 				data as u64
 			}
@@ -143,36 +172,36 @@ impl ElfAnalyzer {
 
 		let e_shoff = match e_width {
 			64 => {
-				let data = self.read_doubleword(offset);
+				let data = self.read_doubleword(offset)?;
 				offset += 8;
 				data
 			},
 			_ => {
-				let data = self.read_word(offset);
+				let data = self.read_word(offset)?;
 				offset += 4;
 				data as u64
 			}
 		};
 
-		let e_flags = self.read_word(offset);
+		let e_flags = self.read_word(offset)?;
 		offset += 4;
 
-		let e_ehsize = self.read_halfword(offset);
+		let e_ehsize = self.read_halfword(offset)?;
 		offset += 2;
 
-		let e_phentsize = self.read_halfword(offset);
+		let e_phentsize = self.read_halfword(offset)?;
 		offset += 2;
 
-		let e_phnum = self.read_halfword(offset);
+		let e_phnum = self.read_halfword(offset)?;
 		offset += 2;
 
-		let e_shentsize = self.read_halfword(offset);
+		let e_shentsize = self.read_halfword(offset)?;
 		offset += 2;
 
-		let e_shnum = self.read_halfword(offset);
+		let e_shnum = self.read_halfword(offset)?;
 		offset += 2;
 
-		let e_shstrndx = self.read_halfword(offset);
+		let e_shstrndx = self.read_halfword(offset)?;
 		//offset += 2;
 
 		/*
@@ -196,8 +225,8 @@ impl ElfAnalyzer {
 		println!("e_shstrndx:{:X}", e_shstrndx);
 		*/
 
-		Header {
-			e_width: e_width,
+		Ok(Header {
+			e_width,
 			_e_class: e_class,
 			_e_endian: e_endian,
 			_e_elf_version: e_elf_version,
@@ -206,44 +235,49 @@ impl ElfAnalyzer {
 			_e_type: e_type,
 			_e_machine: e_machine,
 			_e_version: e_version,
-			e_entry: e_entry,
-			_e_phoff: e_phoff,
-			e_shoff: e_shoff,
+			e_entry,
+			e_phoff,
+			e_shoff,
 			_e_flags: e_flags,
 			_e_ehsize: e_ehsize,
-			_e_phentsize: e_phentsize,
-			_e_phnum: e_phnum,
+			e_phentsize,
+			e_phnum,
 			_e_shentsize: e_shentsize,
-			e_shnum: e_shnum,
+			e_shnum,
 			_e_shstrndx: e_shstrndx
-		}
+		})
 	}
 
-	/// Reads ELF program headers
+	/// Reads ELF program headers. Returns `Err` if they are out of the file.
 	///
 	/// # Arguments
 	/// * `header`
-	pub fn _read_program_headers(&self, header: &Header) -> Vec<_ProgramHeader> {
+	pub fn read_program_headers(&self, header: &Header) -> Result<Vec<ProgramHeader>, LoadError> {
+		let entry_size = match header.e_width {
+			64 => 56,
+			_ => 32
+		};
+		self.check_range(header.e_phoff, header.e_phnum as u64 * entry_size)?;
 		let mut headers = Vec::new();
-		let mut offset = header._e_phoff as usize;
-		for _i in 0..header._e_phnum {
-			let p_type = self.read_word(offset);
+		let mut offset = header.e_phoff as usize;
+		for _i in 0..header.e_phnum {
+			let p_type = self.read_word(offset)?;
 			offset += 4;
 
 			let mut p_flags = 0;
 			if header.e_width == 64 {
-				p_flags = self.read_word(offset);
+				p_flags = self.read_word(offset)?;
 				offset += 4;
 			}
 
 			let p_offset = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
@@ -252,12 +286,12 @@ impl ElfAnalyzer {
 
 			let p_vaddr = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
@@ -266,12 +300,12 @@ impl ElfAnalyzer {
 
 			let p_paddr = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
@@ -280,12 +314,12 @@ impl ElfAnalyzer {
 
 			let p_filesz = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
@@ -294,12 +328,12 @@ impl ElfAnalyzer {
 
 			let p_memsz = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
@@ -307,18 +341,18 @@ impl ElfAnalyzer {
 			};
 
 			if header.e_width == 32 {
-				p_flags = self.read_word(offset);
+				p_flags = self.read_word(offset)?;
 				offset += 4;
 			}
 
 			let p_align = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
@@ -339,43 +373,48 @@ impl ElfAnalyzer {
 			println!("p_align:{:X}", p_align);
 			*/
 
-			headers.push(_ProgramHeader{
-				_p_type: p_type,
+			headers.push(ProgramHeader{
+				p_type,
 				_p_flags: p_flags,
-				_p_offset: p_offset,
-				_p_vaddr: p_vaddr,
+				p_offset,
+				p_vaddr,
 				_p_paddr: p_paddr,
-				_p_filesz: p_filesz,
-				_p_memsz: p_memsz,
+				p_filesz,
+				p_memsz,
 				_p_align: p_align
 			});
 		}
 
-		headers
+		Ok(headers)
 	}
 
-	/// Reads ELF section headers
+	/// Reads ELF section headers. Returns `Err` if they are out of the file.
 	///
 	/// # Arguments
 	/// * `header`
-	pub fn read_section_headers(&self, header: &Header) -> Vec<SectionHeader> {
+	pub fn read_section_headers(&self, header: &Header) -> Result<Vec<SectionHeader>, LoadError> {
+		let entry_size = match header.e_width {
+			64 => 64,
+			_ => 40
+		};
+		self.check_range(header.e_shoff, header.e_shnum as u64 * entry_size)?;
 		let mut headers = Vec::new();
 		let mut offset = header.e_shoff as usize;
 		for _i in 0..header.e_shnum {
-			let sh_name = self.read_word(offset);
+			let sh_name = self.read_word(offset)?;
 			offset += 4;
 
-			let sh_type = self.read_word(offset);
+			let sh_type = self.read_word(offset)?;
 			offset += 4;
 
 			let sh_flags = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
@@ -384,12 +423,12 @@ impl ElfAnalyzer {
 
 			let sh_addr = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
@@ -398,12 +437,12 @@ impl ElfAnalyzer {
 
 			let sh_offset = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
@@ -412,32 +451,32 @@ impl ElfAnalyzer {
 
 			let sh_size = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
 				_ => panic!("Not happen")
 			};
 
-			let sh_link = self.read_word(offset);
+			let sh_link = self.read_word(offset)?;
 			offset += 4;
 
-			let sh_info = self.read_word(offset);
+			let sh_info = self.read_word(offset)?;
 			offset += 4;
 
 			let sh_addralign = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
@@ -446,12 +485,12 @@ impl ElfAnalyzer {
 
 			let sh_entsize = match header.e_width {
 				64 => {
-					let data = self.read_doubleword(offset);
+					let data = self.read_doubleword(offset)?;
 					offset += 8;
 					data
 				},
 				32 => {
-					let data = self.read_word(offset);
+					let data = self.read_word(offset)?;
 					offset += 4;
 					data as u64
 				},
@@ -487,21 +526,23 @@ impl ElfAnalyzer {
 			});
 		}
 
-		headers
+		Ok(headers)
 	}
 
-	/// Reads symbol entries of symbol table sections
+	/// Reads symbol entries of symbol table sections. Returns `Err` if
+	/// the sections are out of the file.
 	///
 	/// # Arguments
 	/// * `Terminal`
 	/// * `symbol_table_section_headers`
 	pub fn read_symbol_entries(&self, header: &Header,
 		symbol_table_section_headers: &Vec<&SectionHeader>)
-		-> Vec<SymbolEntry> {
+		-> Result<Vec<SymbolEntry>, LoadError> {
 		let mut entries = Vec::new();
 		for i in 0..symbol_table_section_headers.len() {
 			let sh_offset = symbol_table_section_headers[i].sh_offset;
 			let sh_size = symbol_table_section_headers[i].sh_size;
+			self.check_range(sh_offset, sh_size)?;
 
 			let mut offset = sh_offset as usize;
 
@@ -521,41 +562,41 @@ impl ElfAnalyzer {
 
 				match header.e_width {
 					64 => {
-						st_name = self.read_word(offset);
+						st_name = self.read_word(offset)?;
 						offset += 4;
 
-						st_info = self.read_byte(offset);
+						st_info = self.read_byte(offset)?;
 						offset += 1;
 
-						_st_other = self.read_byte(offset);
+						_st_other = self.read_byte(offset)?;
 						offset += 1;
 
-						_st_shndx = self.read_halfword(offset);
+						_st_shndx = self.read_halfword(offset)?;
 						offset += 2;
 
-						st_value = self.read_doubleword(offset);
+						st_value = self.read_doubleword(offset)?;
 						offset += 8;
 
-						_st_size = self.read_doubleword(offset);
+						_st_size = self.read_doubleword(offset)?;
 						offset += 8;
 					},
 					32 => {
-						st_name = self.read_word(offset);
+						st_name = self.read_word(offset)?;
 						offset += 4;
 
-						st_value = self.read_word(offset) as u64;
+						st_value = self.read_word(offset)? as u64;
 						offset += 4;
 
-						_st_size = self.read_word(offset) as u64;
+						_st_size = self.read_word(offset)? as u64;
 						offset += 4;
 
-						st_info = self.read_byte(offset);
+						st_info = self.read_byte(offset)?;
 						offset += 1;
 
-						_st_other = self.read_byte(offset);
+						_st_other = self.read_byte(offset)?;
 						offset += 1;
 
-						_st_shndx = self.read_halfword(offset);
+						_st_shndx = self.read_halfword(offset)?;
 						offset += 2;
 					},
 					_ => panic!("No happen")
@@ -582,7 +623,7 @@ impl ElfAnalyzer {
 				});
 			}
 		}
-		entries
+		Ok(entries)
 	}
 
 	/// Reads strings from a string table section
@@ -596,11 +637,14 @@ impl ElfAnalyzer {
 		let mut pos = 0;
 		let mut symbol = String::new();
 		loop {
-			let addr = sh_offset + index + pos;
-			if addr >= sh_offset + sh_size {
+			let addr = sh_offset.saturating_add(index).saturating_add(pos);
+			if addr >= sh_offset.saturating_add(sh_size) {
 				break;
 			}
-			let value = self.read_byte(addr as usize);
+			let value = match self.read_byte(addr as usize) {
+				Ok(value) => value,
+				Err(_) => break
+			};
 			if value == 0 {
 				break;
 			}
@@ -660,8 +704,8 @@ impl ElfAnalyzer {
 				let sh_size = string_table_section_headers[j].sh_size;
 				let mut found = true;
 				for k in 0..tohost_values.len() as u64 {
-					let addr = sh_offset + sh_name + k;
-					if addr >= sh_offset + sh_size || self.read_byte(addr as usize) != tohost_values[k as usize] {
+					let addr = sh_offset.saturating_add(sh_name).saturating_add(k);
+					if addr >= sh_offset.saturating_add(sh_size) || self.read_byte(addr as usize) != Ok(tohost_values[k as usize]) {
 						found = false;
 						break;
 					}
@@ -674,47 +718,157 @@ impl ElfAnalyzer {
 		None
 	}
 
-	/// Reads a byte from ELF file content
+	/// Reads a byte from ELF file content. Returns `Err` if the offset is
+	/// out of the file.
+	///
+	/// # Arguments
+	/// * `offset`
+	pub fn read_byte(&self, offset: usize) -> Result<u8, LoadError> {
+		match self.data.get(offset) {
+			Some(value) => Ok(*value),
+			None => Err(LoadError::InvalidElf("truncated"))
+		}
+	}
+
+	/// Reads bytes from ELF file content. Returns `Err` if they are out of
+	/// the file.
 	///
 	/// # Arguments
 	/// * `offset`
-	pub fn read_byte(&self, offset: usize) -> u8 {
-		self.data[offset]
+	/// * `size`
+	pub fn read_bytes(&self, offset: u64, size: u64) -> Result<&[u8], LoadError> {
+		self.check_range(offset, size)?;
+		Ok(&self.data[offset as usize..(offset + size) as usize])
+	}
+
+	// Returns `Err` unless the bytes are in the file. Checking a table
+	// before reading it keeps the offsets from overflowing.
+	fn check_range(&self, offset: u64, size: u64) -> Result<(), LoadError> {
+		match offset.checked_add(size) {
+			Some(end) if end <= self.data.len() as u64 => Ok(()),
+			_ => Err(LoadError::InvalidElf("truncated"))
+		}
 	}
 
 	/// Reads two bytes from ELF file content
 	///
 	/// # Arguments
 	/// * `offset`
-	fn read_halfword(&self, offset: usize) -> u16 {
+	fn read_halfword(&self, offset: usize) -> Result<u16, LoadError> {
 		let mut data = 0;
 		for i in 0..2 {
-			data |= (self.read_byte(offset + i) as u16) << (8 * i);
+			data |= (self.read_byte(offset.saturating_add(i))? as u16) << (8 * i);
 		}
-		data
+		Ok(data)
 	}
 
 	/// Reads four bytes from ELF file content
 	///
 	/// # Arguments
 	/// * `offset`
-	fn read_word(&self, offset: usize) -> u32 {
+	fn read_word(&self, offset: usize) -> Result<u32, LoadError> {
 		let mut data = 0;
 		for i in 0..4 {
-			data |= (self.read_byte(offset + i) as u32) << (8 * i);
+			data |= (self.read_byte(offset.saturating_add(i))? as u32) << (8 * i);
 		}
-		data
+		Ok(data)
 	}
 
 	/// Reads eight bytes from ELF file content
 	///
 	/// # Arguments
 	/// * `offset`
-	fn read_doubleword(&self, offset: usize) -> u64 {
+	fn read_doubleword(&self, offset: usize) -> Result<u64, LoadError> {
 		let mut data = 0;
 		for i in 0..8 {
-			data |= (self.read_byte(offset + i) as u64) << (8 * i);
+			data |= (self.read_byte(offset.saturating_add(i))? as u64) << (8 * i);
 		}
-		data
+		Ok(data)
+	}
+}
+
+/// Parses an ELF file into what the emulator loads. A pure function
+/// which never panics on any input, so it can be a fuzz target.
+/// `Emulator::setup_program()` and `load_program_for_symbols()` load
+/// programs with it. Returns `Err` if the file isn't ELF or is broken.
+///
+/// # Arguments
+/// * `data` ELF file content
+pub fn parse_elf(data: &[u8]) -> Result<ElfImage, LoadError> {
+	let analyzer = ElfAnalyzer::new(data.to_vec());
+	let header = analyzer.read_header()?;
+	let section_headers = analyzer.read_section_headers(&header)?;
+
+	let mut program_data_section_headers = vec![];
+	let mut symbol_table_section_headers = vec![];
+	let mut string_table_section_headers = vec![];
+
+	for section_header in section_headers.iter() {
+		match section_header.sh_type {
+			1 => program_data_section_headers.push(section_header),
+			2 => symbol_table_section_headers.push(section_header),
+			3 => string_table_section_headers.push(section_header),
+			_ => {}
+		};
 	}
-}
\ No newline at end of file
+
+	// Find program data section named .tohost to detect if the elf file is riscv-tests
+	let tohost_addr = analyzer.find_tohost_addr(&program_data_section_headers, &string_table_section_headers);
+
+	// Creates symbol - virtual address mapping
+	let symbols = match string_table_section_headers.first() {
+		Some(string_table_section_header) => {
+			let entries = analyzer.read_symbol_entries(&header, &symbol_table_section_headers)?;
+			// Assuming symbols are in the first string table section.
+			// @TODO: What if symbol can be in the second or later string table sections?
+			analyzer.create_symbol_map(&entries, string_table_section_header)
+		},
+		None => FnvHashMap::default()
+	};
+
+	let mut sections = vec![];
+	for section_header in program_data_section_headers.iter() {
+		if section_header.sh_offset > 0 && section_header.sh_size > 0 {
+			sections.push(ElfSection {
+				address: section_header.sh_addr,
+				data: analyzer.read_bytes(section_header.sh_offset, section_header.sh_size)?.to_vec()
+			});
+		}
+	}
+
+	Ok(ElfImage {
+		width: header.e_width,
+		entry: header.e_entry,
+		sections,
+		symbols,
+		tohost_addr
+	})
+}
+
+#[cfg(test)]
+mod test_elf_analyzer {
+	use super::*;
+
+	#[test]
+	fn parse_broken_elf() {
+		assert_eq!(Err(LoadError::InvalidElf("no ELF magic number")), parse_elf(b"\x7fEL"));
+		assert_eq!(Err(LoadError::InvalidElf("unknown class")), parse_elf(b"\x7fELF\x03"));
+		assert_eq!(Err(LoadError::InvalidElf("truncated")), parse_elf(b"\x7fELF\x02\x01\x01\x00"));
+
+		// 64-bit header claiming section headers at the end of the address space
+		let mut data = vec![0; 0x40];
+		data[..5].copy_from_slice(b"\x7fELF\x02");
+		data[0x28..0x30].copy_from_slice(&u64::MAX.to_le_bytes());
+		data[0x3c] = 1;
+		assert_eq!(Err(LoadError::InvalidElf("truncated")), parse_elf(&data));
+
+		// No sections
+		data[0x28..0x30].copy_from_slice(&0u64.to_le_bytes());
+		data[0x18] = 0x80;
+		let image = parse_elf(&data).unwrap();
+		assert_eq!(64, image.width);
+		assert_eq!(0x80, image.entry);
+		assert!(image.sections.is_empty());
+		assert_eq!(None, image.tohost_addr);
+	}
+}
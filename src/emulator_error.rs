@@ -0,0 +1,3 @@
+/// Former name of `ExecError`, kept for compatibility.
+#[deprecated(note = "Use error::ExecError")]
+pub type EmulatorError = ::error::ExecError;
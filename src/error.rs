@@ -0,0 +1,272 @@
+// Errors of the emulator by the phase they happen in. Fallible APIs
+// return one of them so embedders can match on the cause instead of
+// parsing messages. Errors caused by another carry it as the source,
+// e.g. `LoadError::Initrd` by `MemoryError`, so reports can print the
+// chain with `core::error::Error::source()`.
+
+#[cfg(feature = "host")]
+extern crate regex_lite;
+
+use alloc::string::String;
+#[cfg(feature = "host")]
+use std::io;
+
+#[cfg(feature = "host")]
+use bench::BenchError;
+use config::ConfigError;
+use thiserror::Error;
+use StopReason;
+
+/// Error accessing the guest memory.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum MemoryError {
+	/// The physical address is mapped to neither main memory nor a device
+	#[error("Unknown memory mapping {0:x}")]
+	Unmapped(u64),
+	/// The range doesn't fit in main memory
+	#[error("{size:x} bytes at {address:x} don't fit in main memory")]
+	OutOfRange {
+		address: u64,
+		size: u64
+	},
+	/// The virtual address isn't accessible through the page table in
+	/// the current privilege mode
+	#[error("Page fault at {0:x}")]
+	PageFault(u64)
+}
+
+/// Error of a device or its host backend.
+#[derive(Debug, Error)]
+pub enum DeviceError {
+	/// The device has got a request it can't handle. The request has been
+	/// returned to the guest unprocessed
+	#[error("{device}: {message}")]
+	InvalidRequest {
+		/// Device name, e.g. `virtio_block`
+		device: &'static str,
+		message: &'static str
+	},
+	/// The disk access isn't sector aligned or exceeds the disk,
+	/// e.g. after the disk is detached
+	#[error("{length:x} bytes at sector {sector:x} are out of the disk")]
+	OutOfDisk {
+		sector: u64,
+		/// Length in bytes
+		length: u64
+	},
+	/// The interrupt line is out of range or used by a built-in device
+	#[error("Interrupt line {0} isn't available")]
+	UnavailableIrq(u32),
+	/// The serial port isn't added to the machine
+	#[error("No serial port {0}")]
+	NoSerialPort(usize),
+	/// The GPIO pin is out of range
+	#[error("No GPIO pin {0}")]
+	NoGpioPin(u32),
+	/// The host backend of the device has failed, e.g. writing
+	/// a disk image file
+	#[cfg(feature = "host")]
+	#[error("Device backend failed")]
+	Backend(#[source] io::Error)
+}
+
+// io::Error is neither Clone nor PartialEq. A backend error is cloned
+// with its kind and message, and compared by its kind.
+impl Clone for DeviceError {
+	fn clone(&self) -> Self {
+		match self {
+			DeviceError::InvalidRequest { device, message } => DeviceError::InvalidRequest {
+				device,
+				message
+			},
+			DeviceError::OutOfDisk { sector, length } => DeviceError::OutOfDisk {
+				sector: *sector,
+				length: *length
+			},
+			DeviceError::UnavailableIrq(line) => DeviceError::UnavailableIrq(*line),
+			DeviceError::NoSerialPort(index) => DeviceError::NoSerialPort(*index),
+			DeviceError::NoGpioPin(pin) => DeviceError::NoGpioPin(*pin),
+			#[cfg(feature = "host")]
+			DeviceError::Backend(error) => DeviceError::Backend(io::Error::new(error.kind(), error.to_string()))
+		}
+	}
+}
+
+impl PartialEq for DeviceError {
+	fn eq(&self, other: &Self) -> bool {
+		match (self, other) {
+			(DeviceError::InvalidRequest { device, message }, DeviceError::InvalidRequest { device: other_device, message: other_message }) =>
+				device == other_device && message == other_message,
+			(DeviceError::OutOfDisk { sector, length }, DeviceError::OutOfDisk { sector: other_sector, length: other_length }) =>
+				sector == other_sector && length == other_length,
+			(DeviceError::UnavailableIrq(line), DeviceError::UnavailableIrq(other_line)) => line == other_line,
+			(DeviceError::NoSerialPort(index), DeviceError::NoSerialPort(other_index)) => index == other_index,
+			(DeviceError::NoGpioPin(pin), DeviceError::NoGpioPin(other_pin)) => pin == other_pin,
+			#[cfg(feature = "host")]
+			(DeviceError::Backend(error), DeviceError::Backend(other_error)) => error.kind() == other_error.kind(),
+			_ => false
+		}
+	}
+}
+
+/// Internal error while running the guest, which used to abort the
+/// process. The emulator recovers from it as described per variant and
+/// can keep running, so embedders decide whether to stop. Only the first
+/// error since the last check is kept. Returned by `Emulator::step()`,
+/// `run()`, and `run_program()`.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum ExecError {
+	/// The instruction isn't implemented. It has raised an illegal
+	/// instruction exception instead
+	#[error("Unknown instruction PC:{pc:x} WORD:{word:x}")]
+	UnknownInstruction {
+		/// Virtual address of the instruction
+		pc: u64,
+		/// Raw bits. Lower 16 bits for compressed instruction
+		word: u32
+	},
+	/// The guest has used a feature not implemented, e.g. `URET`. It has
+	/// been handled as described in the message
+	#[error("Not implemented: {0}")]
+	Unimplemented(&'static str),
+	/// The guest has accessed memory mapped to nothing. Loads have read
+	/// zero and stores have been dropped
+	#[error(transparent)]
+	Memory(#[from] MemoryError),
+	/// A device has failed to handle a request as described
+	#[error(transparent)]
+	Device(#[from] DeviceError)
+}
+
+/// Error setting up the machine with a program or an image.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum LoadError {
+	/// The program isn't ELF or is broken as described
+	#[error("Invalid ELF file: {0}")]
+	InvalidElf(&'static str),
+	/// The program doesn't fit in main memory
+	#[error("Failed to load the program")]
+	Program(#[source] MemoryError),
+	/// The initial ramdisk can't be placed in main memory
+	#[error("Failed to load the initial ramdisk")]
+	Initrd(#[source] MemoryError),
+	/// The device tree overlay is broken or can't be applied to the base
+	/// device tree
+	#[error("Failed to apply the device tree overlay")]
+	DeviceTreeOverlay,
+	/// The device tree blob is broken or in an unsupported version
+	#[error("Invalid device tree blob")]
+	InvalidDtb,
+	/// The symbol file isn't in `System.map` or `/proc/kallsyms` format
+	#[error("Failed to parse the symbols")]
+	SystemMap,
+	/// The machine configuration can't be read or is invalid
+	#[error("Failed to load the configuration")]
+	Config(#[from] ConfigError)
+}
+
+/// Error inspecting or controlling the guest with the debugging and
+/// analysis tools, e.g. a register name unknown or a breakpoint not set.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum DebugError {
+	/// The register name is neither an integer nor a floating point
+	/// register as expected
+	#[error("Unknown register {0}")]
+	UnknownRegister(String),
+	/// The CSR isn't implemented, is read-only for a write, or the
+	/// privilege mode can't access it
+	#[error("CSR {0:x} isn't accessible")]
+	InaccessibleCsr(u16),
+	/// No breakpoint is at the virtual address
+	#[error("No breakpoint at {0:x}")]
+	NoBreakpoint(u64),
+	/// No such watchpoint is set
+	#[error("No watchpoint of {length:x} bytes at {address:x}")]
+	NoWatchpoint {
+		address: u64,
+		length: u64
+	},
+	/// No value watch has the id
+	#[error("No value watch {0}")]
+	NoValueWatch(usize),
+	/// Checkpoints aren't set with `Emulator::set_checkpoints()`
+	#[error("Checkpoints aren't set")]
+	NoCheckpoints,
+	/// The checkpoint of the id isn't kept, or none is kept before
+	/// the instruction number
+	#[error("Checkpoint isn't kept")]
+	NoCheckpoint,
+	/// The program has exited before reaching the instruction
+	#[error("The program has exited")]
+	Exited,
+	/// No store to the range is found back to the earliest checkpoint
+	#[error("No write to {length:x} bytes at {address:x}")]
+	NoWrite {
+		address: u64,
+		length: u64
+	},
+	/// The program has stopped before reaching the expected address
+	#[error("The program has stopped: {0:?}")]
+	Stopped(StopReason),
+	/// The system call name is unknown
+	#[error("Unknown system call {0}")]
+	UnknownSyscall(String),
+	/// The benchmark name is unknown
+	#[error("Unknown benchmark {0}")]
+	UnknownBenchmark(String),
+	/// The regular expression is invalid
+	#[cfg(feature = "host")]
+	#[error("Invalid regular expression")]
+	InvalidPattern(#[from] regex_lite::Error),
+	/// The regular expression has no capture group for the score
+	#[error("The pattern has no capture group")]
+	NoCaptureGroup,
+	/// The benchmark run has failed
+	#[cfg(feature = "host")]
+	#[error(transparent)]
+	Benchmark(#[from] BenchError)
+}
+
+#[cfg(test)]
+mod test_error {
+	use super::*;
+	use alloc::string::ToString;
+	use core::error::Error;
+
+	#[test]
+	fn source() {
+		let error = LoadError::Initrd(MemoryError::OutOfRange {
+			address: 0x80000000,
+			size: 0x1000
+		});
+		assert_eq!("Failed to load the initial ramdisk", error.to_string());
+		assert_eq!("1000 bytes at 80000000 don't fit in main memory", error.source().unwrap().to_string());
+
+		let error = ExecError::from(MemoryError::Unmapped(0x1000));
+		assert_eq!("Unknown memory mapping 1000", error.to_string());
+		assert_eq!(ExecError::Memory(MemoryError::Unmapped(0x1000)), error);
+
+		let error = LoadError::from(ConfigError::Syntax(3));
+		assert_eq!("Failed to load the configuration", error.to_string());
+		assert_eq!("Syntax error at line 3", error.source().unwrap().to_string());
+	}
+
+	#[cfg(feature = "host")]
+	#[test]
+	fn benchmark() {
+		let error = DebugError::from(BenchError::Failed(1));
+		assert_eq!("Benchmark failed with status code 1", error.to_string());
+		assert_eq!(DebugError::Benchmark(BenchError::Failed(1)), error);
+	}
+
+	#[cfg(feature = "host")]
+	#[test]
+	fn backend_source() {
+		let error = DeviceError::Backend(io::Error::new(io::ErrorKind::PermissionDenied, "Read-only file system"));
+		assert_eq!("Read-only file system", error.source().unwrap().to_string());
+		// Cloned with the kind and the message
+		let error = ExecError::from(error).clone();
+		assert_eq!(ExecError::Device(DeviceError::Backend(io::Error::from(io::ErrorKind::PermissionDenied))), error);
+		assert_eq!("Read-only file system", error.source().unwrap().to_string());
+	}
+}
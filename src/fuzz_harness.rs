@@ -0,0 +1,228 @@
+use alloc::vec::Vec;
+
+use collections::FnvHashSet;
+use coverage::Coverage;
+use cpu::Exception;
+use error::{DebugError, MemoryError};
+use {Emulator, Snapshot, StopReason};
+
+// Exception codes of environment calls from U, S, and M mode, which
+// programs take in the normal course
+const ENVIRONMENT_CALL_CAUSES: [u64; 3] = [8, 9, 11];
+
+// Instructions a run may take by default
+const DEFAULT_TIMEOUT: u64 = 0x100000;
+
+/// How a run of `FuzzHarness` has ended
+#[derive(Clone, Debug, PartialEq)]
+pub enum FuzzOutcome {
+	/// The PC has reached the exit address
+	Exited(u64),
+	/// The program has exited with the status code through HTIF or
+	/// in user mode emulation
+	ProgramExited(u64),
+	/// The PC has reached the crash address, e.g. a panic handler
+	CrashAddress(u64),
+	/// The guest has taken an exception not ignored
+	Exception(Exception),
+	/// The run hasn't ended within the timeout
+	Timeout
+}
+
+impl FuzzOutcome {
+	/// Returns `true` if the input should be kept as a crash.
+	pub fn is_crash(&self) -> bool {
+		matches!(self, FuzzOutcome::CrashAddress(_) | FuzzOutcome::Exception(_))
+	}
+}
+
+/// Result of a run, returned by `FuzzHarness::run()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct FuzzReport {
+	pub outcome: FuzzOutcome,
+	/// Instructions run
+	pub instructions: u64,
+	/// Basic blocks executed in the run as (start address, size in bytes)
+	pub blocks: Vec<(u64, u64)>,
+	/// Edges between the blocks. Empty unless the coverage set to
+	/// `Emulator` tracks them.
+	pub edges: Vec<(u64, u64)>,
+	/// Number of the blocks no run before has executed
+	pub new_blocks: usize
+}
+
+/// Runs the guest repeatedly from a snapshot with different inputs, as
+/// the backend of a fuzzer. The harness takes the snapshot when the guest
+/// reaches the start address. Each run restores the snapshot, writes the
+/// input to guest memory, optionally sets its length to a register, and
+/// runs until an exit address, a crash address, an exception, the program
+/// exit, or the timeout. Exceptions other than environment calls are
+/// crashes by default. See `Emulator::take_snapshot()` for what isn't
+/// restored between the runs.
+///
+/// ```ignore
+/// let mut harness = FuzzHarness::new(&mut emulator, start, buffer, 0x1000)?;
+/// harness.set_length_register(11); // a1
+/// harness.add_exit_address(end);
+/// let report = harness.run(&mut emulator, &input)?;
+/// ```
+pub struct FuzzHarness {
+	snapshot: Snapshot,
+	input_address: u64,
+	max_input_size: usize,
+	length_register: Option<u8>,
+	exit_addresses: FnvHashSet<u64>,
+	crash_addresses: FnvHashSet<u64>,
+	ignored_causes: FnvHashSet<u64>,
+	timeout: u64,
+	// Blocks executed in all the runs so far
+	seen_blocks: FnvHashSet<(u64, u64)>
+}
+
+impl FuzzHarness {
+	/// Runs the emulator until the PC reaches the start address and creates
+	/// a new `FuzzHarness` with the snapshot taken there. Sets block coverage
+	/// to the emulator unless it already has one. Returns `Err` if the
+	/// program stops before reaching there.
+	///
+	/// # Arguments
+	/// * `emulator` Emulator the program has been set up
+	/// * `start_address` Virtual address where runs start from
+	/// * `input_address` Virtual address of the buffer the input is written to
+	/// * `max_input_size` Size of the buffer. Longer inputs are truncated
+	pub fn new(emulator: &mut Emulator, start_address: u64, input_address: u64, max_input_size: usize) -> Result<Self, DebugError> {
+		if emulator.get_cpu().read_pc() != start_address {
+			let existing_breakpoint = emulator.breakpoints.contains(&start_address);
+			emulator.add_breakpoint(start_address);
+			let stop_reason = emulator.run_program();
+			if !existing_breakpoint {
+				let _ = emulator.remove_breakpoint(start_address);
+			}
+			if stop_reason != StopReason::Breakpoint(start_address) {
+				return Err(DebugError::Stopped(stop_reason));
+			}
+		}
+		if emulator.get_mut_coverage().is_none() {
+			emulator.set_coverage(Coverage::new(false));
+		}
+		Ok(FuzzHarness {
+			snapshot: emulator.take_snapshot(),
+			input_address,
+			max_input_size,
+			length_register: None,
+			exit_addresses: FnvHashSet::default(),
+			crash_addresses: FnvHashSet::default(),
+			ignored_causes: ENVIRONMENT_CALL_CAUSES.iter().cloned().collect(),
+			timeout: DEFAULT_TIMEOUT,
+			seen_blocks: FnvHashSet::default()
+		})
+	}
+
+	/// Sets the input length to the register at the start of each run.
+	///
+	/// # Arguments
+	/// * `register` Integer register number, e.g. 11 for `a1`
+	pub fn set_length_register(&mut self, register: u8) {
+		self.length_register = Some(register);
+	}
+
+	/// Adds an address where a run ends successfully, e.g. the return
+	/// address of the function under test.
+	///
+	/// # Arguments
+	/// * `address` Virtual address
+	pub fn add_exit_address(&mut self, address: u64) {
+		self.exit_addresses.insert(address);
+	}
+
+	/// Adds an address where a run ends as a crash, e.g. a panic or
+	/// assertion failure handler.
+	///
+	/// # Arguments
+	/// * `address` Virtual address
+	pub fn add_crash_address(&mut self, address: u64) {
+		self.crash_addresses.insert(address);
+	}
+
+	/// Lets the guest handle the exception instead of ending the run
+	/// as a crash, e.g. page faults under an operating system.
+	///
+	/// # Arguments
+	/// * `cause` Exception code
+	pub fn ignore_exception(&mut self, cause: u64) {
+		self.ignored_causes.insert(cause);
+	}
+
+	/// Sets how many instructions a run may take.
+	///
+	/// # Arguments
+	/// * `instructions`
+	pub fn set_timeout(&mut self, instructions: u64) {
+		self.timeout = instructions;
+	}
+
+	/// Runs the guest from the snapshot with the input. Returns `Err` if
+	/// the input can't be written to guest memory.
+	///
+	/// # Arguments
+	/// * `emulator` Emulator the harness has been created with
+	/// * `input`
+	pub fn run(&mut self, emulator: &mut Emulator, input: &[u8]) -> Result<FuzzReport, MemoryError> {
+		emulator.restore_snapshot(&self.snapshot);
+		let input = &input[..input.len().min(self.max_input_size)];
+		for (i, value) in input.iter().enumerate() {
+			let address = self.input_address.wrapping_add(i as u64);
+			if emulator.get_mut_cpu().get_mut_mmu().store(address, *value).is_err() {
+				return Err(MemoryError::PageFault(address));
+			}
+		}
+		if let Some(register) = self.length_register {
+			emulator.get_mut_cpu().write_register(register, input.len() as i64);
+		}
+		if let Some(coverage) = emulator.get_mut_coverage() {
+			coverage.clear();
+		}
+
+		let mut instructions = 0;
+		let outcome = loop {
+			if instructions >= self.timeout {
+				break FuzzOutcome::Timeout;
+			}
+			let pc = emulator.get_cpu().read_pc();
+			if self.exit_addresses.contains(&pc) {
+				break FuzzOutcome::Exited(pc);
+			}
+			if self.crash_addresses.contains(&pc) {
+				break FuzzOutcome::CrashAddress(pc);
+			}
+			emulator.tick();
+			instructions += 1;
+			if let Some(exception) = emulator.get_mut_cpu().take_exception() {
+				if !self.ignored_causes.contains(&exception.cause) {
+					break FuzzOutcome::Exception(exception);
+				}
+			}
+			if let Some(code) = emulator.get_exit_code() {
+				break FuzzOutcome::ProgramExited(code);
+			}
+		};
+
+		let (blocks, edges) = match emulator.get_mut_coverage() {
+			Some(coverage) => (coverage.get_blocks(), coverage.get_edges()),
+			None => (vec![], vec![])
+		};
+		let mut new_blocks = 0;
+		for block in blocks.iter() {
+			if self.seen_blocks.insert(*block) {
+				new_blocks += 1;
+			}
+		}
+		Ok(FuzzReport {
+			outcome,
+			instructions,
+			blocks,
+			edges,
+			new_blocks
+		})
+	}
+}
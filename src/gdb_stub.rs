@@ -0,0 +1,545 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use cpu::Xlen;
+use mmu::{WatchpointHit, WatchpointType};
+use {Emulator, StopReason};
+
+// Cycles run between checks for an interrupt request from GDB
+const POLL_INTERVAL: u64 = 0x10000;
+
+// Largest packet GDB is told to send
+const PACKET_SIZE: usize = 0x1000;
+
+// Ctrl-C sent by GDB to stop the running program
+const INTERRUPT: u8 = 0x03;
+
+// Signal numbers in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// Register names GDB expects in org.gnu.gdb.riscv.cpu feature
+const REGISTER_NAMES: [&str; 32] = [
+	"zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+	"fp", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+	"a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+	"s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"
+];
+
+// Register number of the PC in GDB
+const PC_REGISTER: usize = 32;
+
+/// Serves GDB remote serial protocol on a connection so the guest can be
+/// debugged with `target remote`, like `-gdb` of QEMU. Supports
+/// the integer registers and the PC, memory access at virtual addresses,
+/// continue, single step, Ctrl-C, software and hardware breakpoints, and
+/// watchpoints. The target description tells GDB the XLEN.
+///
+/// ```ignore
+/// let listener = TcpListener::bind("127.0.0.1:1234")?;
+/// let (stream, _) = listener.accept()?;
+/// GdbStub::new(stream).serve(&mut emulator)?;
+/// ```
+pub struct GdbStub {
+	stream: TcpStream,
+	no_ack: bool,
+	// Reply to "?", the reason of the last stop
+	last_stop: String
+}
+
+impl GdbStub {
+	/// Creates a new `GdbStub`.
+	///
+	/// # Arguments
+	/// * `stream` Connection from GDB
+	pub fn new(stream: TcpStream) -> Self {
+		GdbStub {
+			stream,
+			no_ack: false,
+			last_stop: format!("S{:02x}", SIGTRAP)
+		}
+	}
+
+	/// Handles requests from GDB until it detaches, kills the program, or
+	/// disconnects. The program is stopped when this method is called and
+	/// runs only while GDB lets it.
+	///
+	/// # Arguments
+	/// * `emulator`
+	pub fn serve(&mut self, emulator: &mut Emulator) -> io::Result<()> {
+		loop {
+			let packet = match self.read_packet()? {
+				Some(packet) => packet,
+				None => return Ok(())
+			};
+			if !self.handle_packet(emulator, &packet)? {
+				return Ok(());
+			}
+		}
+	}
+
+	// Returns false to end the session
+	fn handle_packet(&mut self, emulator: &mut Emulator, packet: &str) -> io::Result<bool> {
+		let (command, args) = packet.split_at(packet.len().min(1));
+		let reply = match command {
+			"?" => self.last_stop.clone(),
+			"g" => (0..=PC_REGISTER).map(|reg| format_register(emulator, reg)).collect(),
+			"G" => match write_registers(emulator, args) {
+				Ok(()) => "OK".to_string(),
+				Err(()) => "E01".to_string()
+			},
+			"p" => match usize::from_str_radix(args, 16) {
+				Ok(reg) if reg <= PC_REGISTER => format_register(emulator, reg),
+				_ => "E01".to_string()
+			},
+			"P" => match write_register(emulator, args) {
+				Ok(()) => "OK".to_string(),
+				Err(()) => "E01".to_string()
+			},
+			"m" => match parse_memory_range(args).and_then(|(address, length)| read_memory(emulator, address, length)) {
+				Ok(data) => data,
+				Err(()) => "E01".to_string()
+			},
+			"M" => match write_memory(emulator, args) {
+				Ok(()) => "OK".to_string(),
+				Err(()) => "E01".to_string()
+			},
+			"c" | "s" => {
+				if let Ok(address) = u64::from_str_radix(args, 16) {
+					emulator.get_mut_cpu().update_pc(address);
+				}
+				self.last_stop = match command {
+					"c" => self.resume(emulator)?,
+					_ => step(emulator)
+				};
+				self.last_stop.clone()
+			},
+			"Z" | "z" => match update_breakpoint(emulator, command == "Z", args) {
+				Ok(true) => "OK".to_string(),
+				Ok(false) => String::new(),
+				Err(()) => "E01".to_string()
+			},
+			"D" => {
+				self.write_packet("OK")?;
+				return Ok(false);
+			},
+			"k" => return Ok(false),
+			"H" => "OK".to_string(),
+			_ => match packet {
+				_ if packet.starts_with("qSupported") =>
+					format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE),
+				"QStartNoAckMode" => {
+					self.write_packet("OK")?;
+					self.no_ack = true;
+					return Ok(true);
+				},
+				"qAttached" => "1".to_string(),
+				"qC" => "QC1".to_string(),
+				"qfThreadInfo" => "m1".to_string(),
+				"qsThreadInfo" => "l".to_string(),
+				_ if packet.starts_with("qXfer:features:read:target.xml:") =>
+					read_target_description(emulator, &packet["qXfer:features:read:target.xml:".len()..]),
+				// Unsupported, e.g. vCont
+				_ => String::new()
+			}
+		};
+		self.write_packet(&reply)?;
+		Ok(true)
+	}
+
+	// Runs the program until it stops or GDB interrupts it and returns
+	// the stop reply
+	fn resume(&mut self, emulator: &mut Emulator) -> io::Result<String> {
+		loop {
+			if let Some(reason) = emulator.run_program_cycles(POLL_INTERVAL) {
+				return Ok(get_stop_reply(&reason));
+			}
+			if self.poll_interrupt()? {
+				return Ok(format!("S{:02x}", SIGINT));
+			}
+		}
+	}
+
+	// Indicates whether GDB has sent Ctrl-C, without blocking
+	fn poll_interrupt(&mut self) -> io::Result<bool> {
+		self.stream.set_nonblocking(true)?;
+		let mut byte = [0; 1];
+		let result = self.stream.read(&mut byte);
+		self.stream.set_nonblocking(false)?;
+		match result {
+			Ok(0) => Err(io::Error::new(ErrorKind::UnexpectedEof, "GDB has disconnected")),
+			Ok(_) => Ok(byte[0] == INTERRUPT),
+			Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+			Err(error) => Err(error)
+		}
+	}
+
+	// Returns the next packet content, or None if GDB has disconnected.
+	// Acknowledgements and interrupts while stopped are skipped.
+	fn read_packet(&mut self) -> io::Result<Option<String>> {
+		loop {
+			let byte = match self.read_byte()? {
+				Some(byte) => byte,
+				None => return Ok(None)
+			};
+			if byte != b'$' {
+				continue;
+			}
+			let mut data = vec![];
+			loop {
+				match self.read_byte()? {
+					Some(b'#') => break,
+					Some(byte) => data.push(byte),
+					None => return Ok(None)
+				};
+			}
+			let mut checksum = [0; 2];
+			for digit in checksum.iter_mut() {
+				*digit = match self.read_byte()? {
+					Some(byte) => byte,
+					None => return Ok(None)
+				};
+			}
+			let valid = std::str::from_utf8(&checksum).ok()
+				.and_then(|checksum| u8::from_str_radix(checksum, 16).ok()) == Some(get_checksum(&data));
+			if !self.no_ack {
+				self.stream.write_all(match valid {
+					true => b"+",
+					false => b"-"
+				})?;
+			}
+			if valid {
+				return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+			}
+		}
+	}
+
+	fn read_byte(&mut self) -> io::Result<Option<u8>> {
+		let mut byte = [0; 1];
+		match self.stream.read(&mut byte)? {
+			0 => Ok(None),
+			_ => Ok(Some(byte[0]))
+		}
+	}
+
+	fn write_packet(&mut self, data: &str) -> io::Result<()> {
+		let packet = format!("${}#{:02x}", data, get_checksum(data.as_bytes()));
+		self.stream.write_all(packet.as_bytes())?;
+		self.stream.flush()
+	}
+}
+
+fn get_checksum(data: &[u8]) -> u8 {
+	data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn get_register_width(emulator: &Emulator) -> usize {
+	match emulator.get_cpu().get_xlen() {
+		Xlen::Bit32 => 4,
+		Xlen::Bit64 => 8
+	}
+}
+
+// Returns the register in little endian hexadecimal digits
+fn format_register(emulator: &Emulator, reg: usize) -> String {
+	let cpu = emulator.get_cpu();
+	let value = match reg {
+		PC_REGISTER => cpu.read_pc(),
+		_ => cpu.read_register(reg as u8) as u64
+	};
+	value.to_le_bytes()[..get_register_width(emulator)].iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect()
+}
+
+fn parse_register(value: &str, width: usize) -> Result<u64, ()> {
+	let bytes = parse_hex_bytes(value)?;
+	if bytes.len() != width {
+		return Err(());
+	}
+	let mut data = [0; 8];
+	data[..width].copy_from_slice(&bytes);
+	let value = u64::from_le_bytes(data);
+	// Sign-extended as the CPU holds 32-bit values
+	Ok(match width {
+		4 => value as i32 as i64 as u64,
+		_ => value
+	})
+}
+
+fn set_register(emulator: &mut Emulator, reg: usize, value: u64) {
+	let cpu = emulator.get_mut_cpu();
+	match reg {
+		0 => {},
+		PC_REGISTER => cpu.update_pc(value),
+		_ => cpu.write_register(reg as u8, value as i64)
+	};
+}
+
+// Handles "P n=r"
+fn write_register(emulator: &mut Emulator, args: &str) -> Result<(), ()> {
+	let (reg, value) = args.split_once('=').ok_or(())?;
+	let reg = usize::from_str_radix(reg, 16).map_err(|_| ())?;
+	if reg > PC_REGISTER {
+		return Err(());
+	}
+	let value = parse_register(value, get_register_width(emulator))?;
+	set_register(emulator, reg, value);
+	Ok(())
+}
+
+// Handles "G r0r1..."
+fn write_registers(emulator: &mut Emulator, args: &str) -> Result<(), ()> {
+	let digits = get_register_width(emulator) * 2;
+	if args.len() < digits * (PC_REGISTER + 1) || !args.is_ascii() {
+		return Err(());
+	}
+	let mut values = vec![];
+	for reg in 0..=PC_REGISTER {
+		values.push(parse_register(&args[reg * digits..(reg + 1) * digits], digits / 2)?);
+	}
+	for (reg, value) in values.into_iter().enumerate() {
+		set_register(emulator, reg, value);
+	}
+	Ok(())
+}
+
+fn parse_hex_bytes(value: &str) -> Result<Vec<u8>, ()> {
+	if !value.len().is_multiple_of(2) || !value.is_ascii() {
+		return Err(());
+	}
+	(0..value.len()).step_by(2)
+		.map(|i| u8::from_str_radix(&value[i..i + 2], 16).map_err(|_| ()))
+		.collect()
+}
+
+// Parses "addr,length"
+fn parse_memory_range(args: &str) -> Result<(u64, u64), ()> {
+	let (address, length) = args.split_once(',').ok_or(())?;
+	match (u64::from_str_radix(address, 16), u64::from_str_radix(length, 16)) {
+		(Ok(address), Ok(length)) => Ok((address, length)),
+		_ => Err(())
+	}
+}
+
+// Reads memory as the CPU in the current mode sees it. Watchpoints
+// caught by the access are discarded.
+fn read_memory(emulator: &mut Emulator, address: u64, length: u64) -> Result<String, ()> {
+	let mmu = emulator.get_mut_cpu().get_mut_mmu();
+	let mut data = String::new();
+	for i in 0..length.min(PACKET_SIZE as u64 / 2) {
+		let v_address = address.wrapping_add(i);
+		if mmu.validate_address(v_address) != Ok(true) {
+			break;
+		}
+		match mmu.load(v_address) {
+			Ok(value) => data.push_str(&format!("{:02x}", value)),
+			Err(_) => break
+		};
+	}
+	mmu.take_watchpoint_hit();
+	// A partial read is a success
+	match data.is_empty() && length > 0 {
+		true => Err(()),
+		false => Ok(data)
+	}
+}
+
+// Handles "M addr,length:XX..."
+fn write_memory(emulator: &mut Emulator, args: &str) -> Result<(), ()> {
+	let (range, data) = args.split_once(':').ok_or(())?;
+	let (address, length) = parse_memory_range(range)?;
+	let data = parse_hex_bytes(data)?;
+	if data.len() as u64 != length {
+		return Err(());
+	}
+	let mmu = emulator.get_mut_cpu().get_mut_mmu();
+	let mut result = Ok(());
+	for (i, byte) in data.iter().enumerate() {
+		let v_address = address.wrapping_add(i as u64);
+		if mmu.validate_address(v_address) != Ok(true) || mmu.store(v_address, *byte).is_err() {
+			result = Err(());
+			break;
+		}
+	}
+	mmu.take_watchpoint_hit();
+	result
+}
+
+// Handles "Z type,addr,kind" and "z type,addr,kind". Returns false for
+// unsupported types
+fn update_breakpoint(emulator: &mut Emulator, insert: bool, args: &str) -> Result<bool, ()> {
+	let mut fields = args.split(',');
+	let (breakpoint_type, address, kind) = match (fields.next(), fields.next(), fields.next()) {
+		(Some(breakpoint_type), Some(address), Some(kind)) => (breakpoint_type, address, kind),
+		_ => return Err(())
+	};
+	let address = u64::from_str_radix(address, 16).map_err(|_| ())?;
+	// Bytes watched for watchpoints
+	let length = u64::from_str_radix(kind, 16).map_err(|_| ())?;
+	let watchpoint_types = match breakpoint_type {
+		// Software and hardware breakpoints
+		"0" | "1" => {
+			match insert {
+				true => emulator.add_breakpoint(address),
+				// GDB may remove a breakpoint more than once
+				false => {
+					let _ = emulator.remove_breakpoint(address);
+				}
+			};
+			return Ok(true);
+		},
+		"2" => vec![WatchpointType::Write],
+		"3" => vec![WatchpointType::Read],
+		"4" => vec![WatchpointType::Read, WatchpointType::Write],
+		_ => return Ok(false)
+	};
+	for watchpoint_type in watchpoint_types {
+		match (insert, watchpoint_type) {
+			(true, WatchpointType::Read) => emulator.watch_read(address, length),
+			(true, WatchpointType::Write) => emulator.watch_write(address, length),
+			(false, watchpoint_type) => {
+				let _ = emulator.remove_watchpoint(watchpoint_type, address, length);
+			}
+		};
+	}
+	Ok(true)
+}
+
+fn step(emulator: &mut Emulator) -> String {
+	let pc = emulator.get_cpu().read_pc();
+	let result = emulator.step();
+	if let Some(hit) = emulator.get_mut_cpu().get_mut_mmu().take_watchpoint_hit() {
+		return get_stop_reply(&StopReason::Watchpoint {
+			pc,
+			hit
+		});
+	}
+	match (emulator.get_exit_code(), result) {
+		(Some(code), _) => get_stop_reply(&StopReason::Exited(code)),
+		(None, Err(error)) => get_stop_reply(&StopReason::Error(error)),
+		(None, Ok(())) => format!("S{:02x}", SIGTRAP)
+	}
+}
+
+fn get_stop_reply(reason: &StopReason) -> String {
+	match reason {
+		StopReason::Exited(code) => format!("W{:02x}", code & 0xff),
+		StopReason::Watchpoint { hit: WatchpointHit { watchpoint_type, address, .. }, .. } => {
+			let name = match watchpoint_type {
+				WatchpointType::Read => "rwatch",
+				WatchpointType::Write => "watch"
+			};
+			format!("T{:02x}{}:{:x};", SIGTRAP, name, address)
+		},
+		StopReason::Error(_) => format!("S{:02x}", SIGILL),
+		StopReason::Breakpoint(_) | StopReason::Stalled(_) => format!("S{:02x}", SIGTRAP)
+	}
+}
+
+// Handles the rest of "qXfer:features:read:target.xml:offset,length"
+fn read_target_description(emulator: &Emulator, args: &str) -> String {
+	let (offset, length) = match parse_memory_range(args) {
+		Ok((offset, length)) => (offset as usize, length as usize),
+		Err(()) => return "E01".to_string()
+	};
+	let (architecture, bits) = match emulator.get_cpu().get_xlen() {
+		Xlen::Bit32 => ("riscv:rv32", 32),
+		Xlen::Bit64 => ("riscv:rv64", 64)
+	};
+	let mut xml = format!("<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\"><architecture>{}</architecture><feature name=\"org.gnu.gdb.riscv.cpu\">", architecture);
+	for (reg, name) in REGISTER_NAMES.iter().enumerate() {
+		let register_type = match *name {
+			"sp" | "fp" => "data_ptr",
+			_ => "int"
+		};
+		xml.push_str(&format!("<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>", name, bits, register_type, reg));
+	}
+	xml.push_str(&format!("<reg name=\"pc\" bitsize=\"{}\" type=\"code_ptr\" regnum=\"{}\"/></feature></target>", bits, PC_REGISTER));
+	match xml.get(offset..) {
+		Some(rest) if rest.len() > length => format!("m{}", &rest[..length]),
+		Some(rest) => format!("l{}", rest),
+		None => "l".to_string()
+	}
+}
+
+#[cfg(test)]
+mod test_gdb_stub {
+	use super::*;
+	use std::net::TcpListener;
+	use std::thread;
+	use mmu::DRAM_BASE;
+	use terminal::DummyTerminal;
+
+	// Sends a packet and returns the reply content
+	fn request(stream: &mut TcpStream, data: &str) -> String {
+		let packet = format!("${}#{:02x}", data, get_checksum(data.as_bytes()));
+		stream.write_all(packet.as_bytes()).unwrap();
+		let mut reply = vec![];
+		let mut byte = [0; 1];
+		loop {
+			stream.read_exact(&mut byte).unwrap();
+			if byte[0] == b'#' {
+				break;
+			}
+			reply.push(byte[0]);
+		}
+		let mut checksum = [0; 2];
+		stream.read_exact(&mut checksum).unwrap();
+		stream.write_all(b"+").unwrap();
+		// Without the acknowledgement and the leading $
+		let start = reply.iter().position(|byte| *byte == b'$').unwrap();
+		String::from_utf8(reply[start + 1..].to_vec()).unwrap()
+	}
+
+	#[test]
+	fn serve() {
+		let mut emulator = Emulator::new(Box::new(DummyTerminal::new()));
+		emulator.get_mut_cpu().get_mut_mmu().init_memory(0x1000);
+		let code = [
+			0x00150513, // loop: addi a0, a0, 1
+			0x00150513, // addi a0, a0, 1
+			0x00158593, // addi a1, a1, 1
+			0xff5ff06f // jal zero, loop
+		];
+		for (i, word) in code.iter().enumerate() {
+			if emulator.get_mut_cpu().get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		emulator.get_mut_cpu().update_pc(DRAM_BASE);
+		emulator.get_mut_cpu().write_register(10, 0);
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+		let client = thread::spawn(move || {
+			let mut stream = TcpStream::connect(address).unwrap();
+			assert!(request(&mut stream, "qSupported:swbreak+").contains("qXfer:features:read+"));
+			assert_eq!("S05", request(&mut stream, "?"));
+			assert!(request(&mut stream, "qXfer:features:read:target.xml:0,1000").contains("<architecture>riscv:rv64</architecture>"));
+			assert_eq!("OK", request(&mut stream, "Z0,80000008,4"));
+			assert_eq!("S05", request(&mut stream, "c"));
+			assert_eq!("0800008000000000", request(&mut stream, "p20"));
+			assert_eq!("0200000000000000", request(&mut stream, "pa"));
+			assert_eq!(33 * 16, request(&mut stream, "g").len());
+			// Resumes from the breakpoint and stops there again
+			assert_eq!("S05", request(&mut stream, "c"));
+			assert_eq!("0400000000000000", request(&mut stream, "pa"));
+			assert_eq!("OK", request(&mut stream, "z0,80000008,4"));
+			assert_eq!("S05", request(&mut stream, "s"));
+			assert_eq!("0c00008000000000", request(&mut stream, "p20"));
+			assert_eq!("OK", request(&mut stream, "Pa=2a00000000000000"));
+			assert_eq!("2a00000000000000", request(&mut stream, "pa"));
+			assert_eq!("OK", request(&mut stream, "M80000100,4:01020304"));
+			assert_eq!("01020304", request(&mut stream, "m80000100,4"));
+			assert_eq!("E01", request(&mut stream, "m100000000000,4"));
+			assert_eq!("", request(&mut stream, "vCont?"));
+		});
+		let (stream, _) = listener.accept().unwrap();
+		let mut stub = GdbStub::new(stream);
+		assert!(stub.serve(&mut emulator).is_ok());
+		client.join().unwrap();
+		assert_eq!(0x2a, emulator.get_cpu().read_register(10));
+	}
+}
@@ -0,0 +1,358 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use cpu::Cpu;
+use mmu::Mmu;
+use syscall_proxy::{SyscallProxy, ENOMEM};
+
+// Based on the front-end server of Spike
+// https://github.com/riscv-software-src/riscv-isa-sim/tree/master/fesvr
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+// System call numbers which riscv-pk forwards. The others are
+// handled by `SyscallProxy`.
+const SYS_FACCESSAT: u64 = 48;
+const SYS_OPENAT: u64 = 56;
+const SYS_FSTATAT: u64 = 79;
+const SYS_EXIT: u64 = 93;
+const SYS_GETMAINVARS: u64 = 2011;
+
+/// Host-Target Interface (HTIF). Programs running on
+/// [`riscv-pk`](https://github.com/riscv-software-src/riscv-pk) and
+/// bare-metal benchmarks of [`riscv-tests`](https://github.com/riscv/riscv-tests)
+/// communicate with the host through `tohost` and `fromhost` words in
+/// memory. A request written to `tohost` is either a console access or
+/// a pointer to a system call which `Htif` proxies to the host file system
+/// with [`SyscallProxy`](../syscall_proxy/struct.SyscallProxy.html).
+/// The program exits with a status code through `tohost`, too.
+pub struct Htif {
+	/// Physical address of `tohost`. `Htif` is inactive if zero.
+	tohost_addr: u64,
+	/// Physical address of `fromhost`
+	fromhost_addr: u64,
+	/// Arguments passed to the program, starting with the program name
+	args: Vec<String>,
+	/// Handles system calls accessing files. Target addresses are physical.
+	proxy: SyscallProxy,
+	/// Status code the program exited with
+	exit_code: Option<u64>
+}
+
+impl Htif {
+	/// Creates a new `Htif`.
+	///
+	/// # Arguments
+	/// * `args` Arguments passed to the program. The first one is the
+	///   program name. For `riscv-pk` it's the proxy kernel itself and the
+	///   user program follows.
+	pub fn new(args: Vec<String>) -> Self {
+		Htif {
+			tohost_addr: 0,
+			fromhost_addr: 0,
+			args,
+			proxy: SyscallProxy::new(0, u64::MAX),
+			exit_code: None
+		}
+	}
+
+	/// Sets the physical addresses of `tohost` and `fromhost`, usually
+	/// taken from the symbols of the program.
+	///
+	/// # Arguments
+	/// * `tohost_addr`
+	/// * `fromhost_addr`
+	pub fn set_host_addresses(&mut self, tohost_addr: u64, fromhost_addr: u64) {
+		self.tohost_addr = tohost_addr;
+		self.fromhost_addr = fromhost_addr;
+	}
+
+	/// Returns the status code if the program has exited.
+	pub fn get_exit_code(&self) -> Option<u64> {
+		self.exit_code
+	}
+
+	/// Overwrites the exit status, e.g. to run the program again
+	/// from a snapshot.
+	///
+	/// # Arguments
+	/// * `exit_code` `None` for running
+	pub fn set_exit_code(&mut self, exit_code: Option<u64>) {
+		self.exit_code = exit_code;
+	}
+
+	/// Runs one cycle. Handles a request written to `tohost` if any.
+	/// A request waiting for input is left in `tohost` and retried in
+	/// later cycles.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn tick(&mut self, cpu: &mut Cpu) {
+		if self.tohost_addr == 0 || self.exit_code.is_some() {
+			return;
+		}
+		let tohost = cpu.get_mut_mmu().load_doubleword_raw(self.tohost_addr);
+		// The program needs to consume the previous response first
+		if tohost == 0 || cpu.get_mut_mmu().load_doubleword_raw(self.fromhost_addr) != 0 {
+			return;
+		}
+		let device = tohost >> 56;
+		let command = (tohost >> 48) & 0xff;
+		let payload = tohost & 0xffff_ffff_ffff;
+		let response = match device {
+			DEVICE_SYSCALL => match payload & 1 {
+				1 => {
+					self.exit_code = Some(payload >> 1);
+					cpu.get_mut_mmu().store_doubleword_raw(self.tohost_addr, 0);
+					return;
+				},
+				_ => match self.handle_syscall(cpu, payload) {
+					true => 1,
+					false => return
+				}
+			},
+			DEVICE_CONSOLE => match command {
+				CONSOLE_GETCHAR => match cpu.get_mut_terminal().get_input() {
+					0 => return,
+					data => data as u64
+				},
+				CONSOLE_PUTCHAR => {
+					cpu.get_mut_terminal().put_byte(payload as u8);
+					0
+				},
+				_ => 0
+			},
+			_ => 0
+		};
+		let mmu = cpu.get_mut_mmu();
+		mmu.store_doubleword_raw(self.tohost_addr, 0);
+		mmu.store_doubleword_raw(self.fromhost_addr, (device << 56) | (command << 48) | response);
+	}
+
+	/// Handles a system call described in `magic_mem`, eight words of
+	/// the system call number and the arguments. The return value is
+	/// written back to the first word. Returns `false` if the system call
+	/// waits for input.
+	fn handle_syscall(&mut self, cpu: &mut Cpu, magic_mem: u64) -> bool {
+		let mut args = [0; 8];
+		for (i, arg) in args.iter_mut().enumerate() {
+			*arg = cpu.get_mut_mmu().load_doubleword_raw(magic_mem + i as u64 * 8);
+		}
+		// riscv-pk passes the length of a path including the terminating null
+		let result = match args[0] {
+			SYS_GETMAINVARS => self.sys_getmainvars(cpu.get_mut_mmu(), args[1], args[2]),
+			SYS_OPENAT => match self.proxy.read_string(cpu.get_mut_mmu(), args[2], args[3]) {
+				Ok(path) => self.proxy.open(args[1], &path, args[4]),
+				Err(error) => error
+			},
+			SYS_FSTATAT => match self.proxy.read_string(cpu.get_mut_mmu(), args[2], args[3]) {
+				Ok(path) => self.proxy.stat(cpu.get_mut_mmu(), args[1], &path, args[4]),
+				Err(error) => error
+			},
+			SYS_FACCESSAT => match self.proxy.read_string(cpu.get_mut_mmu(), args[2], args[3]) {
+				Ok(path) => self.proxy.access(args[1], &path),
+				Err(error) => error
+			},
+			SYS_EXIT => {
+				self.exit_code = Some(args[1]);
+				0
+			},
+			_ => match self.proxy.handle_syscall(cpu, args[0], &args[1..]) {
+				Some(result) => result,
+				None => return false
+			}
+		};
+		cpu.get_mut_mmu().store_doubleword_raw(magic_mem, result as u64);
+		true
+	}
+
+	/// Writes `argc`, `argv` pointers and the argument strings to the buffer
+	/// as the program's `main()` arguments.
+	fn sys_getmainvars(&self, mmu: &mut Mmu, pbuf: u64, limit: u64) -> i64 {
+		// argc, argv[], NULL terminating argv[] and empty envp[]
+		let words_num = self.args.len() + 3;
+		let mut words = vec![0; words_num];
+		let mut strings = vec![];
+		words[0] = self.args.len() as u64;
+		for (i, arg) in self.args.iter().enumerate() {
+			words[i + 1] = pbuf + (words_num * 8 + strings.len()) as u64;
+			strings.extend_from_slice(arg.as_bytes());
+			strings.push(0);
+		}
+		if (words_num * 8 + strings.len()) as u64 > limit {
+			return -ENOMEM;
+		}
+		let mut data = vec![];
+		for word in words.iter() {
+			data.extend_from_slice(&word.to_le_bytes());
+		}
+		data.extend_from_slice(&strings);
+		match self.proxy.write_memory(mmu, pbuf, &data) {
+			Ok(()) => 0,
+			Err(error) => error
+		}
+	}
+}
+
+#[cfg(test)]
+mod test_htif {
+	use super::*;
+	use alloc::boxed::Box;
+	use alloc::string::ToString;
+	#[cfg(feature = "std")]
+	use std::env;
+	#[cfg(feature = "std")]
+	use std::fs;
+	use default_terminal::DefaultTerminal;
+	use syscall_proxy::{AT_FDCWD, EBADF, ENOENT, ENOSYS};
+
+	const SYS_CLOSE: u64 = 57;
+	const SYS_LSEEK: u64 = 62;
+	const SYS_READ: u64 = 63;
+	const SYS_WRITE: u64 = 64;
+	const SYS_PREAD: u64 = 67;
+	const SYS_FSTAT: u64 = 80;
+
+	const TOHOST: u64 = 0x80001000;
+	const FROMHOST: u64 = 0x80001040;
+	const MAGIC_MEM: u64 = 0x80002000;
+	const BUFFER: u64 = 0x80003000;
+
+	fn create_cpu() -> Cpu {
+		let mut cpu = Cpu::new(Box::new(DefaultTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x10000);
+		cpu
+	}
+
+	fn read_memory(mmu: &mut Mmu, address: u64, length: u64) -> Vec<u8> {
+		(0..length).map(|i| mmu.load_raw(address + i)).collect()
+	}
+
+	fn write_memory(mmu: &mut Mmu, address: u64, data: &[u8]) {
+		for (i, byte) in data.iter().enumerate() {
+			mmu.store_raw(address + i as u64, *byte);
+		}
+	}
+
+	fn create_htif(args: Vec<String>) -> Htif {
+		let mut htif = Htif::new(args);
+		htif.set_host_addresses(TOHOST, FROMHOST);
+		htif
+	}
+
+	// Sends a system call as riscv-pk does and returns the return value
+	fn syscall(htif: &mut Htif, cpu: &mut Cpu, args: &[u64]) -> u64 {
+		for (i, arg) in args.iter().enumerate() {
+			cpu.get_mut_mmu().store_doubleword_raw(MAGIC_MEM + i as u64 * 8, *arg);
+		}
+		cpu.get_mut_mmu().store_doubleword_raw(TOHOST, MAGIC_MEM);
+		htif.tick(cpu);
+		assert_eq!(0, cpu.get_mut_mmu().load_doubleword_raw(TOHOST));
+		assert_eq!(1, cpu.get_mut_mmu().load_doubleword_raw(FROMHOST));
+		cpu.get_mut_mmu().store_doubleword_raw(FROMHOST, 0);
+		cpu.get_mut_mmu().load_doubleword_raw(MAGIC_MEM)
+	}
+
+	#[test]
+	fn write_and_exit() {
+		let mut cpu = create_cpu();
+		let mut htif = create_htif(vec![]);
+		write_memory(cpu.get_mut_mmu(), BUFFER, b"Hi\n");
+		assert_eq!(3, syscall(&mut htif, &mut cpu, &[SYS_WRITE, 1, BUFFER, 3]));
+		let mut output = vec![];
+		assert_eq!(3, cpu.get_mut_terminal().read_available(&mut output));
+		assert_eq!(b"Hi\n".to_vec(), output);
+		assert_eq!(-ENOSYS as u64, syscall(&mut htif, &mut cpu, &[9999]));
+		assert_eq!(None, htif.get_exit_code());
+
+		// Exit code 3 encoded as riscv-tests does
+		cpu.get_mut_mmu().store_doubleword_raw(TOHOST, (3 << 1) | 1);
+		htif.tick(&mut cpu);
+		assert_eq!(Some(3), htif.get_exit_code());
+	}
+
+	#[test]
+	fn console() {
+		let mut cpu = create_cpu();
+		let mut htif = create_htif(vec![]);
+		cpu.get_mut_mmu().store_doubleword_raw(TOHOST, (DEVICE_CONSOLE << 56) | (CONSOLE_PUTCHAR << 48) | 0x41);
+		htif.tick(&mut cpu);
+		assert_eq!(b'A', cpu.get_mut_terminal().get_output());
+		assert_eq!((DEVICE_CONSOLE << 56) | (CONSOLE_PUTCHAR << 48), cpu.get_mut_mmu().load_doubleword_raw(FROMHOST));
+		cpu.get_mut_mmu().store_doubleword_raw(FROMHOST, 0);
+
+		// getchar waits for input
+		cpu.get_mut_mmu().store_doubleword_raw(TOHOST, (DEVICE_CONSOLE << 56) | (CONSOLE_GETCHAR << 48));
+		htif.tick(&mut cpu);
+		assert_eq!(0, cpu.get_mut_mmu().load_doubleword_raw(FROMHOST));
+		cpu.get_mut_terminal().put_input(b'z');
+		htif.tick(&mut cpu);
+		assert_eq!(0, cpu.get_mut_mmu().load_doubleword_raw(TOHOST));
+		assert_eq!((DEVICE_CONSOLE << 56) | (CONSOLE_GETCHAR << 48) | b'z' as u64, cpu.get_mut_mmu().load_doubleword_raw(FROMHOST));
+	}
+
+	#[test]
+	fn getmainvars() {
+		let mut cpu = create_cpu();
+		let mut htif = create_htif(vec!["pk".to_string(), "hello".to_string()]);
+		assert_eq!(-ENOMEM as u64, syscall(&mut htif, &mut cpu, &[SYS_GETMAINVARS, BUFFER, 16]));
+		assert_eq!(0, syscall(&mut htif, &mut cpu, &[SYS_GETMAINVARS, BUFFER, 0x100]));
+		let mmu = cpu.get_mut_mmu();
+		assert_eq!(2, mmu.load_doubleword_raw(BUFFER));
+		assert_eq!(BUFFER + 40, mmu.load_doubleword_raw(BUFFER + 8));
+		assert_eq!(BUFFER + 43, mmu.load_doubleword_raw(BUFFER + 16));
+		assert_eq!(0, mmu.load_doubleword_raw(BUFFER + 24));
+		assert_eq!(0, mmu.load_doubleword_raw(BUFFER + 32));
+		assert_eq!(b"pk\0hello\0".to_vec(), read_memory(mmu, BUFFER + 40, 9));
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn file() {
+		let mut cpu = create_cpu();
+		let mut htif = create_htif(vec![]);
+		let path = env::temp_dir().join(format!("htif_test_{}", std::process::id()));
+		let mut pname = path.to_str().unwrap().as_bytes().to_vec();
+		pname.push(0);
+		write_memory(cpu.get_mut_mmu(), BUFFER, &pname);
+		let len = pname.len() as u64;
+		let data = BUFFER + 0x100;
+		write_memory(cpu.get_mut_mmu(), data, b"benchmark");
+
+		let fd = syscall(&mut htif, &mut cpu, &[SYS_OPENAT, AT_FDCWD as u64, BUFFER, len, 0x242, 0o644]);
+		assert_eq!(3, fd);
+		assert_eq!(9, syscall(&mut htif, &mut cpu, &[SYS_WRITE, fd, data, 9]));
+		assert_eq!(5, syscall(&mut htif, &mut cpu, &[SYS_PREAD, fd, data + 0x100, 5, 4]));
+		assert_eq!(b"hmark".to_vec(), read_memory(cpu.get_mut_mmu(), data + 0x100, 5));
+		assert_eq!(0, syscall(&mut htif, &mut cpu, &[SYS_LSEEK, fd, 0, 0]));
+		assert_eq!(9, syscall(&mut htif, &mut cpu, &[SYS_READ, fd, data + 0x200, 0x10]));
+		assert_eq!(b"benchmark".to_vec(), read_memory(cpu.get_mut_mmu(), data + 0x200, 9));
+		assert_eq!(0, syscall(&mut htif, &mut cpu, &[SYS_FSTAT, fd, data + 0x300]));
+		assert_eq!(9, cpu.get_mut_mmu().load_doubleword_raw(data + 0x300 + 48));
+		assert_eq!(0, syscall(&mut htif, &mut cpu, &[SYS_CLOSE, fd]));
+		assert_eq!(-EBADF as u64, syscall(&mut htif, &mut cpu, &[SYS_CLOSE, fd]));
+
+		fs::remove_file(&path).unwrap();
+		assert_eq!(-ENOENT as u64, syscall(&mut htif, &mut cpu, &[SYS_FACCESSAT, AT_FDCWD as u64, BUFFER, len, 0]));
+	}
+
+	// Without std there are no host files to open
+	#[cfg(not(feature = "std"))]
+	#[test]
+	fn no_file() {
+		let mut cpu = create_cpu();
+		let mut htif = create_htif(vec![]);
+		write_memory(cpu.get_mut_mmu(), BUFFER, b"htif_test\0");
+		assert_eq!(-ENOENT as u64, syscall(&mut htif, &mut cpu, &[SYS_OPENAT, AT_FDCWD as u64, BUFFER, 10, 0x242, 0o644]));
+		assert_eq!(-ENOENT as u64, syscall(&mut htif, &mut cpu, &[SYS_FACCESSAT, AT_FDCWD as u64, BUFFER, 10, 0]));
+		assert_eq!(-EBADF as u64, syscall(&mut htif, &mut cpu, &[SYS_READ, 3, BUFFER, 0x10]));
+		assert_eq!(-EBADF as u64, syscall(&mut htif, &mut cpu, &[SYS_PREAD, 3, BUFFER, 0x10, 0]));
+		assert_eq!(-EBADF as u64, syscall(&mut htif, &mut cpu, &[SYS_LSEEK, 3, 0, 0]));
+		assert_eq!(-EBADF as u64, syscall(&mut htif, &mut cpu, &[SYS_FSTAT, 3, BUFFER]));
+		assert_eq!(-EBADF as u64, syscall(&mut htif, &mut cpu, &[SYS_CLOSE, 3]));
+	}
+}
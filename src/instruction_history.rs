@@ -0,0 +1,271 @@
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use io::{self, Write};
+
+use cpu::{Cpu, Operand, PrivilegeMode, Xlen, decode, disassemble, get_register_name};
+
+// Bit positions of rd, rs1, and rs2 in an uncompressed instruction
+const REGISTER_FIELD_SHIFTS: [u32; 3] = [7, 15, 20];
+
+// Instruction run, kept in the ring
+struct Record {
+	pc: u64,
+	// Uncompressed. None if fetching has faulted
+	word: Option<u32>,
+	length: u64,
+	privilege_mode: PrivilegeMode,
+	// Values of the registers in the rd, rs1, and rs2 fields
+	register_values: [u64; 3],
+	repeats: u64
+}
+
+/// Instruction kept in `InstructionHistory`, returned by `get_entries()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+	/// Virtual address of the instruction
+	pub pc: u64,
+	/// Instruction bits. A compressed instruction is uncompressed.
+	/// `None` if fetching has faulted
+	pub word: Option<u32>,
+	/// Length in bytes, 2 or 4. 0 if fetching has faulted
+	pub length: u64,
+	pub privilege_mode: PrivilegeMode,
+	/// For example `ADDI a0,a0,1`
+	pub disassembly: String,
+	/// Integer registers the instruction refers to and their values
+	/// before it ran, except `zero`
+	pub registers: Vec<(u8, u64)>,
+	/// Times the instruction has run in a row
+	pub repeats: u64
+}
+
+/// Keeps the latest instructions run in a bounded ring, to dump as
+/// the context when a guest wedges, e.g. on a breakpoint or a panic of
+/// the emulator. An instruction run again in a row, e.g. one faulting
+/// at the trap vector or `j .`, is kept as one entry with the count, so
+/// a trap loop doesn't flush the instructions leading to it. Such a stall
+/// is detected with `set_stall_threshold()`. Set to `Emulator` with
+/// `set_instruction_history()`.
+pub struct InstructionHistory {
+	capacity: usize,
+	records: VecDeque<Record>,
+	xlen: Xlen,
+	stall_threshold: u64,
+	// Address of the instruction stalled at since the last take_stall()
+	stall: Option<u64>
+}
+
+impl InstructionHistory {
+	/// Creates a new `InstructionHistory`.
+	///
+	/// # Arguments
+	/// * `capacity` How many latest instructions are kept
+	pub fn new(capacity: usize) -> Self {
+		InstructionHistory {
+			capacity,
+			records: VecDeque::with_capacity(capacity),
+			xlen: Xlen::Bit64,
+			stall_threshold: 0,
+			stall: None
+		}
+	}
+
+	/// Sets how many times an instruction runs in a row until it's
+	/// regarded as stalled, which `take_stall()` returns. Zero, the default,
+	/// disables the detection.
+	///
+	/// # Arguments
+	/// * `threshold`
+	pub fn set_stall_threshold(&mut self, threshold: u64) {
+		self.stall_threshold = threshold;
+	}
+
+	/// Takes the address of the instruction which has run the threshold
+	/// times in a row since the last call.
+	pub fn take_stall(&mut self) -> Option<u64> {
+		self.stall.take()
+	}
+
+	/// Records the instruction the CPU is about to run. Call this before
+	/// every `Cpu::tick()`.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn record(&mut self, cpu: &mut Cpu) {
+		if self.capacity == 0 || cpu.is_waiting_for_interrupt() {
+			return;
+		}
+		let pc = cpu.read_pc();
+		let fetched = cpu.fetch_next_instruction();
+		if let Some(last) = self.records.back_mut() {
+			if last.pc == pc && last.word == fetched.map(|(word, _length)| word) {
+				last.repeats += 1;
+				if last.repeats == self.stall_threshold {
+					self.stall = Some(pc);
+				}
+				return;
+			}
+		}
+		self.xlen = cpu.get_xlen().clone();
+		let mask = match self.xlen {
+			Xlen::Bit32 => 0xffffffff,
+			Xlen::Bit64 => 0xffffffffffffffff
+		};
+		let (word, length) = match fetched {
+			Some((word, length)) => (Some(word), length),
+			None => (None, 0)
+		};
+		let mut register_values = [0; 3];
+		if let Some(word) = word {
+			for (value, shift) in register_values.iter_mut().zip(REGISTER_FIELD_SHIFTS.iter()) {
+				*value = cpu.read_register(((word >> shift) & 0x1f) as u8) as u64 & mask;
+			}
+		}
+		if self.records.len() == self.capacity {
+			self.records.pop_front();
+		}
+		self.records.push_back(Record {
+			pc,
+			word,
+			length,
+			privilege_mode: cpu.read_privilege_mode().clone(),
+			register_values,
+			repeats: 1
+		});
+	}
+
+	/// Returns the instructions kept, the oldest first.
+	pub fn get_entries(&self) -> Vec<HistoryEntry> {
+		self.records.iter().map(|record| {
+			let (disassembly, registers) = match record.word {
+				Some(word) => (disassemble(word, record.pc, &self.xlen), self.get_registers(record, word)),
+				None => ("<fetch fault>".to_string(), vec![])
+			};
+			HistoryEntry {
+				pc: record.pc,
+				word: record.word,
+				length: record.length,
+				privilege_mode: record.privilege_mode.clone(),
+				disassembly,
+				registers,
+				repeats: record.repeats
+			}
+		}).collect()
+	}
+
+	/// Writes the instructions kept, the oldest first, a line each like
+	///
+	/// ```text
+	/// 0000000080000008 M SB a1,0(a0) a1=0x41 a0=0x10000000
+	/// ```
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+		for entry in self.get_entries() {
+			let mut line = format!("{:016x} {} {}", entry.pc, get_privilege_mode_char(&entry.privilege_mode), entry.disassembly);
+			for (register, value) in entry.registers.iter() {
+				line += &format!(" {}=0x{:x}", get_register_name(*register as usize), value);
+			}
+			if entry.repeats > 1 {
+				line += &format!(" ({} times)", entry.repeats);
+			}
+			writeln!(writer, "{}", line)?;
+		}
+		writer.flush()
+	}
+
+	/// Forgets the instructions kept so far.
+	pub fn clear(&mut self) {
+		self.records.clear();
+		self.stall = None;
+	}
+
+	// Returns the integer registers the instruction refers to and their values
+	fn get_registers(&self, record: &Record, word: u32) -> Vec<(u8, u64)> {
+		let operands = match decode(word, &self.xlen) {
+			Some(decoded) => decoded.operands,
+			None => return vec![]
+		};
+		let mut registers = vec![];
+		for operand in operands.iter() {
+			let register = match operand {
+				Operand::Register(register) if *register != 0 => *register,
+				_ => continue
+			};
+			if registers.iter().any(|(r, _)| *r == register) {
+				continue;
+			}
+			let field = REGISTER_FIELD_SHIFTS.iter()
+				.position(|shift| ((word >> shift) & 0x1f) as u8 == register);
+			if let Some(field) = field {
+				registers.push((register, record.register_values[field]));
+			}
+		}
+		registers
+	}
+}
+
+fn get_privilege_mode_char(mode: &PrivilegeMode) -> char {
+	match mode {
+		PrivilegeMode::User => 'U',
+		PrivilegeMode::Supervisor => 'S',
+		PrivilegeMode::Reserved => '?',
+		PrivilegeMode::Machine => 'M'
+	}
+}
+
+#[cfg(test)]
+mod test_instruction_history {
+	use super::*;
+	use alloc::boxed::Box;
+	use mmu::DRAM_BASE;
+	use terminal::DummyTerminal;
+
+	#[test]
+	fn record() {
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x100);
+		cpu.update_pc(DRAM_BASE);
+		let code = [
+			0x00150513, // addi a0, a0, 1
+			0x00a585b3, // add a1, a1, a0
+			0x0000006f // loop: jal zero, loop
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		cpu.write_register(11, 5);
+		let mut history = InstructionHistory::new(2);
+		history.set_stall_threshold(3);
+		for _ in 0..5 {
+			history.record(&mut cpu);
+			cpu.tick();
+			if history.records.back().map(|record| record.repeats) == Some(3) {
+				assert_eq!(Some(DRAM_BASE + 8), history.take_stall());
+			}
+			assert_eq!(None, history.take_stall());
+		}
+		let entries = history.get_entries();
+		assert_eq!(2, entries.len());
+		assert_eq!(HistoryEntry {
+			pc: DRAM_BASE + 4,
+			word: Some(0x00a585b3),
+			length: 4,
+			privilege_mode: PrivilegeMode::Machine,
+			disassembly: "ADD a1,a1,a0".to_string(),
+			registers: vec![(11, 5), (10, 1)],
+			repeats: 1
+		}, entries[0]);
+		assert_eq!((DRAM_BASE + 8, 3), (entries[1].pc, entries[1].repeats));
+
+		let mut output = vec![];
+		assert!(history.write(&mut output).is_ok());
+		assert_eq!("0000000080000004 M ADD a1,a1,a0 a1=0x5 a0=0x1\n0000000080000008 M JAL zero,80000008 (3 times)\n",
+			String::from_utf8_lossy(&output));
+	}
+}
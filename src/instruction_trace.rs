@@ -0,0 +1,370 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use io::Write;
+
+use collections::FnvHashMap;
+use cpu::{Cpu, NextInstruction, StateDiff, Xlen, get_privilege_encoding};
+use symbol_table::SymbolTable;
+
+// QEMU doesn't put more instructions in a translation block
+const MAX_BLOCK_SIZE: usize = 512;
+
+// Fake host address of the first translation block in QEMU exec format
+const BLOCK_HOST_ADDRESS_BASE: u64 = 0x7f0000000000;
+
+/// Record format of `InstructionTrace`
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceFormat {
+	/// A line per instruction with PC, raw bits, disassembly, and
+	/// optionally register writes
+	Default,
+	/// Logs of `qemu-system-riscv64 -d in_asm,exec` of QEMU 7, chunked
+	/// per translation block, so that log diffing tools built around QEMU
+	/// work unchanged. Instructions are grouped into a block up to
+	/// a branch, jump, trap, or page boundary like QEMU does.
+	/// The disassembly text and the block flags don't exactly match QEMU's.
+	Qemu {
+		/// Writes the instructions of a block the first time it runs, like `-d in_asm`
+		in_asm: bool,
+		/// Writes a `Trace` line every time a block runs, like `-d exec`
+		exec: bool
+	}
+}
+
+// Instruction run in the translation block being built
+struct BlockInstruction {
+	pc: u64,
+	instruction: NextInstruction
+}
+
+/// Streams a record per instruction to a writer, e.g. a file, like
+///
+/// ```text
+/// PC:0000000080000000 00108093 ADDI ra:0,ra:0,1 x1=0x1
+/// ```
+///
+/// with PC, raw instruction bits, disassembly, and optionally the integer
+/// and floating point registers the instruction has written. Instructions
+/// raising exceptions are recorded, too. Tracing can be started and stopped
+/// anytime and limited to PC ranges. QEMU compatible format is also
+/// available with `set_format()`. Errors on writing are ignored not to
+/// disturb the guest. Set to `Emulator` with `set_instruction_trace()`.
+pub struct InstructionTrace {
+	writer: Box<dyn Write>,
+	format: TraceFormat,
+	running: bool,
+	ranges: Vec<(u64, u64)>,
+	register_writes: bool,
+	symbol_table: Option<SymbolTable>,
+	// Disassembly of the instruction about to run in the default format
+	disassembly: String,
+	// Instruction about to run in QEMU format
+	instruction: Option<BlockInstruction>,
+	// Translation block being built in QEMU format
+	block: Vec<BlockInstruction>,
+	privilege_encoding: u8,
+	xlen: Xlen,
+	// Indices of the blocks seen so far by their start addresses
+	block_indices: FnvHashMap<u64, u64>
+}
+
+impl InstructionTrace {
+	/// Creates a new `InstructionTrace` in the default format. Tracing
+	/// starts immediately for all PCs.
+	///
+	/// # Arguments
+	/// * `writer` Where records are written
+	pub fn new(writer: Box<dyn Write>) -> Self {
+		InstructionTrace {
+			writer,
+			format: TraceFormat::Default,
+			running: true,
+			ranges: vec![],
+			register_writes: false,
+			symbol_table: None,
+			disassembly: String::new(),
+			instruction: None,
+			block: vec![],
+			privilege_encoding: 0,
+			xlen: Xlen::Bit64,
+			block_indices: FnvHashMap::default()
+		}
+	}
+
+	/// Sets the record format.
+	///
+	/// # Arguments
+	/// * `format`
+	pub fn set_format(&mut self, format: TraceFormat) {
+		self.flush_block();
+		self.format = format;
+	}
+
+	/// Returns the record format.
+	pub fn get_format(&self) -> &TraceFormat {
+		&self.format
+	}
+
+	/// Sets the symbols shown in `IN:` and `Trace` lines of QEMU format.
+	///
+	/// # Arguments
+	/// * `symbol_table`
+	pub fn set_symbol_table(&mut self, symbol_table: SymbolTable) {
+		self.symbol_table = Some(symbol_table);
+	}
+
+	/// Starts tracing.
+	pub fn start(&mut self) {
+		self.running = true;
+	}
+
+	/// Stops tracing and flushes the writer.
+	pub fn stop(&mut self) {
+		self.running = false;
+		self.flush_block();
+		let _ = self.writer.flush();
+	}
+
+	/// Returns `true` if tracing is running.
+	pub fn is_running(&self) -> bool {
+		self.running
+	}
+
+	/// Limits tracing to the instructions whose PC is in the range.
+	/// Once ranges are added, instructions out of all of them aren't traced.
+	///
+	/// # Arguments
+	/// * `start` Virtual address, inclusive
+	/// * `end` Virtual address, exclusive
+	pub fn add_range(&mut self, start: u64, end: u64) {
+		self.ranges.push((start, end));
+	}
+
+	/// Removes all ranges added with `add_range()`.
+	pub fn clear_ranges(&mut self) {
+		self.ranges.clear();
+	}
+
+	/// Enables or disables recording register writes in the default format.
+	/// It costs a copy of the architectural state per instruction.
+	///
+	/// # Arguments
+	/// * `enabled`
+	pub fn enable_register_writes(&mut self, enabled: bool) {
+		self.register_writes = enabled;
+	}
+
+	/// Returns `true` if register writes are recorded.
+	pub fn is_register_writes_enabled(&self) -> bool {
+		self.register_writes && self.format == TraceFormat::Default
+	}
+
+	/// Returns `true` if the instruction at the PC is traced.
+	///
+	/// # Arguments
+	/// * `pc` Virtual address
+	pub fn is_traced(&self, pc: u64) -> bool {
+		self.running && (self.ranges.is_empty() ||
+			self.ranges.iter().any(|(start, end)| *start <= pc && pc < *end))
+	}
+
+	/// Takes the instruction the CPU is about to run. Call this before
+	/// `Cpu::tick()` and `record_after()` after it.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn record_before(&mut self, cpu: &mut Cpu) {
+		match self.format {
+			TraceFormat::Default => self.disassembly = cpu.disassemble_next_instruction(),
+			TraceFormat::Qemu { .. } => {
+				let pc = cpu.read_pc();
+				// Another block starts if tracing has skipped instructions
+				if let Some(last) = self.block.last() {
+					if last.pc.wrapping_add(last.instruction.length) != pc {
+						self.flush_block();
+					}
+				}
+				if self.block.is_empty() {
+					self.privilege_encoding = get_privilege_encoding(cpu.read_privilege_mode());
+					self.xlen = cpu.get_xlen().clone();
+				}
+				self.instruction = cpu.get_next_instruction().map(|instruction| BlockInstruction {
+					pc,
+					instruction
+				});
+			}
+		};
+	}
+
+	/// Writes the record of the instruction the CPU has run.
+	///
+	/// # Arguments
+	/// * `cpu`
+	/// * `diff` Changes by the instruction if register writes are recorded
+	pub fn record_after(&mut self, cpu: &Cpu, diff: Option<&StateDiff>) {
+		match self.format {
+			TraceFormat::Default => {
+				let mut record = self.disassembly.trim_end().to_string();
+				if let Some(diff) = diff {
+					for (register, _, value) in diff.x.iter() {
+						record += &format!(" x{}=0x{:x}", register, value);
+					}
+					for (register, _, value) in diff.f.iter() {
+						record += &format!(" f{}=0x{:x}", register, value);
+					}
+				}
+				record.push('\n');
+				let _ = self.writer.write_all(record.as_bytes());
+			},
+			TraceFormat::Qemu { .. } => {
+				let block_instruction = match self.instruction.take() {
+					Some(block_instruction) => block_instruction,
+					// Fetching has faulted
+					None => return self.flush_block()
+				};
+				let next_pc = block_instruction.pc.wrapping_add(block_instruction.instruction.length);
+				let ends_block = is_block_end(block_instruction.instruction.name) ||
+					cpu.read_pc() != next_pc ||
+					(next_pc & !0xfff) != (self.block.first().unwrap_or(&block_instruction).pc & !0xfff);
+				self.block.push(block_instruction);
+				if ends_block || self.block.len() >= MAX_BLOCK_SIZE {
+					self.flush_block();
+				}
+			}
+		};
+	}
+
+	fn get_symbol(&self, address: u64) -> &str {
+		match self.symbol_table.as_ref().and_then(|symbol_table| symbol_table.lookup(address)) {
+			Some((name, _)) => name,
+			None => ""
+		}
+	}
+
+	// Writes the translation block built so far in QEMU format
+	fn flush_block(&mut self) {
+		let (in_asm, exec) = match self.format {
+			TraceFormat::Qemu { in_asm, exec } => (in_asm, exec),
+			TraceFormat::Default => return
+		};
+		let start = match self.block.first() {
+			Some(block_instruction) => block_instruction.pc,
+			None => return
+		};
+		let address_width = match self.xlen {
+			Xlen::Bit32 => 8,
+			Xlen::Bit64 => 16
+		};
+		let mut log = String::new();
+		let block_num = self.block_indices.len() as u64;
+		let is_new_block = !self.block_indices.contains_key(&start);
+		let index = *self.block_indices.entry(start).or_insert(block_num);
+		if in_asm && is_new_block {
+			log += &format!("----------------\nIN: {}\nPriv: {}; Virt: 0\n", self.get_symbol(start), self.privilege_encoding);
+			for block_instruction in self.block.iter() {
+				let instruction = &block_instruction.instruction;
+				let word = match instruction.length {
+					2 => format!("{:04x}    ", instruction.word),
+					_ => format!("{:08x}", instruction.word)
+				};
+				log += &format!("0x{:0width$x}:  {}          {:<24}{}\n", block_instruction.pc, word,
+					instruction.name.to_lowercase(), instruction.operands, width = address_width);
+			}
+			log.push('\n');
+		}
+		if exec {
+			log += &format!("Trace 0: 0x{:x} [{:0width$x}/{:0width$x}/{:08x}/{:08x}] {}\n",
+				BLOCK_HOST_ADDRESS_BASE + index * 0x100, 0, start, self.privilege_encoding, 0,
+				self.get_symbol(start), width = address_width);
+		}
+		self.block.clear();
+		let _ = self.writer.write_all(log.as_bytes());
+	}
+}
+
+// Indicates whether QEMU ends a translation block at the instruction
+fn is_block_end(name: &str) -> bool {
+	match name {
+		"JAL" | "JALR" | "ECALL" | "EBREAK" | "MRET" | "SRET" | "URET" | "WFI" | "FENCE.I" | "SFENCE.VMA" => true,
+		// Branches
+		_ => name.starts_with('B')
+	}
+}
+
+impl Drop for InstructionTrace {
+	fn drop(&mut self) {
+		self.flush_block();
+	}
+}
+
+#[cfg(test)]
+mod test_instruction_trace {
+	use super::*;
+	use mmu::DRAM_BASE;
+	use core::cell::RefCell;
+	use alloc::rc::Rc;
+	use io;
+	use terminal::DummyTerminal;
+
+	struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for SharedLog {
+		fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+			self.0.borrow_mut().write(data)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn is_traced() {
+		let mut trace = InstructionTrace::new(Box::new(vec![]));
+		assert!(trace.is_traced(0x80000000));
+		trace.add_range(0x1000, 0x2000);
+		trace.add_range(0x80000000, 0x80000004);
+		assert!(trace.is_traced(0x1000));
+		assert!(!trace.is_traced(0x2000));
+		assert!(trace.is_traced(0x80000000));
+		trace.stop();
+		assert!(!trace.is_traced(0x80000000));
+		trace.start();
+		trace.clear_ranges();
+		assert!(trace.is_traced(0x2000));
+	}
+
+	#[test]
+	fn qemu_format() {
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x100);
+		cpu.update_pc(DRAM_BASE);
+		// "addi a0, a0, 1", "c.nop", and "jal zero, -6" looping
+		for (i, half) in [0x0513, 0x0015, 0x0001, 0xf06f, 0xffbf].iter().enumerate() {
+			if cpu.get_mut_mmu().store_halfword(DRAM_BASE + i as u64 * 2, *half).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		let log = Rc::new(RefCell::new(vec![]));
+		let mut trace = InstructionTrace::new(Box::new(SharedLog(log.clone())));
+		trace.set_format(TraceFormat::Qemu { in_asm: true, exec: true });
+		for _ in 0..6 {
+			trace.record_before(&mut cpu);
+			cpu.tick();
+			trace.record_after(&cpu, None);
+		}
+		assert_eq!(concat!(
+			"----------------\n",
+			"IN: \n",
+			"Priv: 3; Virt: 0\n",
+			"0x0000000080000000:  00150513          addi                    a0,a0,1\n",
+			"0x0000000080000004:  0001              addi                    zero,zero,0\n",
+			"0x0000000080000006:  ffbff06f          jal                     zero,80000000\n",
+			"\n",
+			"Trace 0: 0x7f0000000000 [0000000000000000/0000000080000000/00000003/00000000] \n",
+			"Trace 0: 0x7f0000000000 [0000000000000000/0000000080000000/00000003/00000000] \n"
+		), String::from_utf8_lossy(&log.borrow()));
+	}
+}
@@ -0,0 +1,77 @@
+// Subset of `std::io` the traces and the reports write with, for builds
+// without std. Embedders implement `Write` for their own sink, e.g.
+// a buffer drained by a debug channel.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Error of a `Write` implementation.
+#[derive(Debug)]
+pub struct Error;
+
+/// Result of a `Write` method.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Byte sink like `std::io::Write`.
+pub trait Write {
+	/// Writes the data, returning how many bytes have been written.
+	///
+	/// # Arguments
+	/// * `data`
+	fn write(&mut self, data: &[u8]) -> Result<usize>;
+
+	/// Flushes buffered data.
+	fn flush(&mut self) -> Result<()>;
+
+	/// Writes the entire data. Fails if `write()` accepts no byte.
+	///
+	/// # Arguments
+	/// * `data`
+	fn write_all(&mut self, mut data: &[u8]) -> Result<()> {
+		while !data.is_empty() {
+			match self.write(data)? {
+				0 => return Err(Error),
+				size => data = &data[size..]
+			};
+		}
+		Ok(())
+	}
+
+	/// Writes formatted text, called by `write!()` and `writeln!()`.
+	///
+	/// # Arguments
+	/// * `args`
+	fn write_fmt(&mut self, args: fmt::Arguments) -> Result<()> {
+		// Adapts to fmt::Write, which write_fmt() of core formats to
+		struct Adapter<'a, W: Write + ?Sized + 'a>(&'a mut W);
+
+		impl<'a, W: Write + ?Sized> fmt::Write for Adapter<'a, W> {
+			fn write_str(&mut self, text: &str) -> fmt::Result {
+				self.0.write_all(text.as_bytes()).map_err(|_| fmt::Error)
+			}
+		}
+
+		fmt::write(&mut Adapter(self), args).map_err(|_| Error)
+	}
+}
+
+impl Write for Vec<u8> {
+	fn write(&mut self, data: &[u8]) -> Result<usize> {
+		self.extend_from_slice(data);
+		Ok(data.len())
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		Ok(())
+	}
+}
+
+impl<W: Write + ?Sized> Write for &mut W {
+	fn write(&mut self, data: &[u8]) -> Result<usize> {
+		(**self).write(data)
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		(**self).flush()
+	}
+}
@@ -0,0 +1,151 @@
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use terminal::Terminal;
+
+/// Console output of the guest split into lines, e.g. the kernel log
+/// printed while booting. Shared between `KernelLogTerminal` and the host.
+pub struct KernelLog {
+	lines: Vec<String>,
+	// Bytes of the line not terminated yet
+	partial_line: Vec<u8>
+}
+
+impl KernelLog {
+	fn new() -> Self {
+		KernelLog {
+			lines: vec![],
+			partial_line: vec![]
+		}
+	}
+
+	fn push(&mut self, values: &[u8]) {
+		for value in values.iter() {
+			match *value {
+				b'\n' => {
+					// Lines often end with CR LF
+					if self.partial_line.last() == Some(&b'\r') {
+						self.partial_line.pop();
+					}
+					self.lines.push(String::from_utf8_lossy(&self.partial_line).to_string());
+					self.partial_line.clear();
+				},
+				_ => self.partial_line.push(*value)
+			};
+		}
+	}
+
+	/// Returns the lines terminated so far, the oldest first. Line feeds
+	/// and carriage returns before them are removed.
+	pub fn get_lines(&self) -> &[String] {
+		&self.lines
+	}
+
+	/// Removes the lines terminated so far and returns them, for
+	/// harnesses checking the log as it grows.
+	pub fn take_lines(&mut self) -> Vec<String> {
+		core::mem::take(&mut self.lines)
+	}
+
+	/// Returns the line being written, not terminated yet.
+	pub fn get_partial_line(&self) -> String {
+		String::from_utf8_lossy(&self.partial_line).to_string()
+	}
+
+	/// Indicates whether a line kept, or the line being written, contains
+	/// the text, e.g. a `dmesg` message.
+	///
+	/// # Arguments
+	/// * `text`
+	pub fn contains(&self, text: &str) -> bool {
+		self.lines.iter().any(|line| line.contains(text)) || self.get_partial_line().contains(text)
+	}
+
+	/// Forgets the lines kept so far.
+	pub fn clear(&mut self) {
+		self.lines.clear();
+	}
+}
+
+/// `Terminal` wrapper which passes everything through to the inner terminal
+/// and scrapes the guest output into lines of `KernelLog`, so harnesses can
+/// assert on the kernel log whatever the console is connected to. Usually
+/// set with `Emulator::capture_kernel_log()`.
+pub struct KernelLogTerminal {
+	terminal: Box<dyn Terminal>,
+	log: Rc<RefCell<KernelLog>>
+}
+
+impl KernelLogTerminal {
+	/// Creates a new `KernelLogTerminal`.
+	///
+	/// # Arguments
+	/// * `terminal` Inner terminal
+	pub fn new(terminal: Box<dyn Terminal>) -> Self {
+		KernelLogTerminal {
+			terminal,
+			log: Rc::new(RefCell::new(KernelLog::new()))
+		}
+	}
+
+	/// Returns the handle of the lines scraped.
+	pub fn get_kernel_log(&self) -> Rc<RefCell<KernelLog>> {
+		self.log.clone()
+	}
+
+	/// Returns mutable reference to the inner `Terminal`.
+	pub fn get_mut_terminal(&mut self) -> &mut Box<dyn Terminal> {
+		&mut self.terminal
+	}
+}
+
+impl Terminal for KernelLogTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.log.borrow_mut().push(&[value]);
+		self.terminal.put_byte(value);
+	}
+
+	fn get_output(&mut self) -> u8 {
+		self.terminal.get_output()
+	}
+
+	fn put_input(&mut self, data: u8) {
+		self.terminal.put_input(data);
+	}
+
+	fn get_input(&mut self) -> u8 {
+		self.terminal.get_input()
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		self.log.borrow_mut().push(values);
+		self.terminal.put_bytes(values);
+	}
+
+	fn get_output_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.terminal.get_output_bytes(buffer)
+	}
+
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		self.terminal.put_input_bytes(data);
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.terminal.get_input_bytes(buffer)
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.terminal.has_input()
+	}
+
+	fn get_window_size(&mut self) -> Option<(u16, u16)> {
+		self.terminal.get_window_size()
+	}
+
+	fn is_output_ready(&mut self) -> bool {
+		self.terminal.is_output_ready()
+	}
+}
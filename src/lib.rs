@@ -1,22 +1,211 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
 // @TODO: temporal
 const TEST_MEMORY_CAPACITY: u64 = 1024 * 512;
-const PROGRAM_MEMORY_CAPACITY: u64 = 1024 * 1024 * 128; // big enough to run Linux and xv6
 
+// The initial ramdisk is placed at a page boundary
+const INITRD_ALIGNMENT: u64 = 0x1000;
+
+#[cfg_attr(not(any(feature = "std", test)), macro_use)]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate core;
+// Tests run on the host with std even when the crate is built without it
+#[cfg(all(test, not(feature = "std")))]
+#[macro_use]
+extern crate std;
 extern crate fnv;
+extern crate hashbrown;
+extern crate libm;
+extern crate miniz_oxide;
+#[macro_use]
+extern crate log;
+extern crate thiserror;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
-use self::fnv::FnvHashMap;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+// The traces and the reports write to `io::Write` of std, or of the crate's
+// own subset of `std::io` without std
+#[cfg(feature = "std")]
+use std::io;
 
+use collections::{FnvHashMap, FnvHashSet};
+
+#[cfg(feature = "serde")]
+#[macro_use]
+mod serde_support;
+mod collections;
+mod random;
+#[cfg(not(feature = "std"))]
+pub mod io;
 pub mod cpu;
+pub mod zipper_stack;
+pub mod cfi_statistics;
+pub mod softfloat;
+pub mod error;
+pub mod emulator_error;
 pub mod terminal;
 pub mod default_terminal;
+pub mod capture_terminal;
+#[cfg(feature = "host")]
+pub mod logging_terminal;
+#[cfg(feature = "host")]
+pub mod replay_terminal;
+#[cfg(feature = "host")]
+pub mod scripted_terminal;
+#[cfg(feature = "host")]
+pub mod batch_runner;
+#[cfg(feature = "host")]
+pub mod bench;
+#[cfg(feature = "host")]
+pub mod channel_terminal;
+#[cfg(feature = "tokio")]
+pub mod async_terminal;
+pub mod terminal_mux;
+#[cfg(feature = "host")]
+pub mod throttled_terminal;
 pub mod memory;
 pub mod mmu;
 pub mod elf_analyzer;
 pub mod device;
+pub mod config;
+pub mod device_tree;
+pub mod htif;
+pub mod instruction_trace;
+pub mod instruction_history;
+pub mod call_trace;
+pub mod attack_simulator;
+pub mod protection_overhead;
+pub mod syscall_trace;
+pub mod checkpoints;
+pub mod coverage;
+pub mod fuzz_harness;
+pub mod diff_tester;
+pub mod trap_log;
+pub mod mmio_log;
+pub mod kernel_log;
+pub mod profiler;
+pub mod speed_meter;
+pub mod clock;
+pub mod cache_stats;
+pub mod symbol_table;
+pub mod unwind;
+pub mod syscall_proxy;
+pub mod linux_user;
+#[cfg(feature = "virtio")]
+pub mod net_backend;
+#[cfg(feature = "virtio")]
+pub mod block_backend;
+#[cfg(all(feature = "host", feature = "virtio"))]
+pub mod qcow2_block_backend;
+#[cfg(all(feature = "host", feature = "virtio"))]
+pub mod user_net_backend;
+#[cfg(feature = "gdb")]
+pub mod gdb_stub;
+#[cfg(feature = "control")]
+pub mod control;
+#[cfg(all(feature = "host", feature = "virtio", target_os = "linux"))]
+pub mod tap_net_backend;
+#[cfg(all(feature = "tokio", feature = "virtio"))]
+pub mod async_net_backend;
+#[cfg(feature = "tokio")]
+pub mod async_runner;
+#[cfg(all(feature = "host", target_os = "linux"))]
+pub mod pty_terminal;
+#[cfg(all(feature = "host", target_os = "linux"))]
+pub mod raw_terminal;
+
+use cpu::{Cpu, CpuSnapshot, disassemble, Extension, PrivilegeMode, StateDiff, Xlen, get_f_register_number, get_register_number};
+use elf_analyzer::{ElfAnalyzer, parse_elf};
+use error::{DebugError, DeviceError, ExecError, LoadError, MemoryError};
+use terminal::{DummyTerminal, Terminal};
+use config::EmulatorConfig;
+#[cfg(feature = "virtio")]
+use net_backend::NetBackend;
+#[cfg(feature = "virtio")]
+use block_backend::BlockBackend;
+use device::framebuffer::FramebufferUpdateCallback;
+use device::shared_memory::DoorbellCallback;
+#[cfg(feature = "virtio")]
+use device::virtio_snd::PcmCallback;
+use device::msi::Msi;
+#[cfg(feature = "virtio")]
+use device::virtio_balloon::BALLOON_PAGE_SIZE;
+use device_tree::{MachineDescription, apply_dtb_overlay, generate_dtb, set_dtb_initrd};
+use htif::Htif;
+use instruction_trace::InstructionTrace;
+use instruction_history::InstructionHistory;
+use checkpoints::Checkpoints;
+use call_trace::CallTrace;
+use attack_simulator::AttackSimulator;
+use protection_overhead::ProtectionOverhead;
+use syscall_trace::SyscallTrace;
+use coverage::{Coverage, HotBlock};
+use profiler::Profiler;
+use speed_meter::SpeedMeter;
+use clock::{Clock, create_default_clock};
+use cache_stats::CacheStats;
+use trap_log::TrapLog;
+use cfi_statistics::CfiStatistics;
+use mmio_log::MmioLog;
+use kernel_log::{KernelLog, KernelLogTerminal};
+use symbol_table::{SymbolTable, parse_system_map};
+use unwind::StackFrame;
+use linux_user::LinuxUser;
+use memory::MemorySnapshot;
+use mmu::{DRAM_BASE, ValueWatchCallback, ValueWatchCondition, WatchpointHit, WatchpointType};
+
+/// Guest state saved by `Emulator::take_snapshot()`. With `serde` feature
+/// it's serializable, tagged with `SNAPSHOT_FORMAT_VERSION`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(remote = "Self"))]
+pub struct Snapshot {
+	cpu: CpuSnapshot,
+	memory: MemorySnapshot,
+	exit_code: Option<u64>
+}
+
+#[cfg(feature = "serde")]
+pub use serde_support::{CONFIG_FORMAT_VERSION, SNAPSHOT_FORMAT_VERSION};
+
+#[cfg(feature = "serde")]
+impl_versioned_serde!(Snapshot, "Snapshot", serde_support::SNAPSHOT_FORMAT_VERSION);
 
-use cpu::{Cpu, Xlen};
-use elf_analyzer::{ElfAnalyzer};
-use terminal::Terminal;
+/// Why `Emulator::run_program()` has returned.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+	/// The program has exited with the status code through HTIF or in
+	/// user mode emulation, or the machine has powered off through
+	/// the test finisher
+	Exited(u64),
+	/// The PC has reached the breakpoint at the virtual address.
+	/// The instruction there hasn't been run yet
+	Breakpoint(u64),
+	/// An instruction has accessed memory watched with `watch_read()` or
+	/// `watch_write()`. The instruction has completed
+	Watchpoint {
+		/// Virtual address of the instruction
+		pc: u64,
+		hit: WatchpointHit
+	},
+	/// An instruction at the virtual address has run as many times in
+	/// a row as the stall threshold of the instruction history, e.g.
+	/// one faulting at the trap vector. See
+	/// `InstructionHistory::set_stall_threshold()`
+	Stalled(u64),
+	/// The emulator has hit an internal error and recovered from it.
+	/// The instruction has completed
+	Error(ExecError)
+}
 
 /// RISC-V emulator. It emulates RISC-V CPU and peripheral devices.
 ///
@@ -25,9 +214,9 @@ use terminal::Terminal;
 /// // Creates an emulator with arbitary terminal
 /// let mut emulator = Emulator::new(Box::new(DefaultTerminal::new()));
 /// // Set up program content binary
-/// emulator.setup_program(program_content);
+/// emulator.setup_program(program_content).unwrap();
 /// // Set up Filesystem content binary
-/// emulator.setup_filesystem(fs_content);
+/// emulator.setup_filesystem(Box::new(MemoryBlockBackend::new(fs_content)));
 /// // Go!
 /// emulator.run();
 /// ```
@@ -44,7 +233,78 @@ pub struct Emulator {
 
 	/// [`riscv-tests`](https://github.com/riscv/riscv-tests) specific properties.
 	/// The address where data will be sent to terminal
-	tohost_addr: u64
+	tohost_addr: u64,
+
+	/// Machine configuration
+	config: EmulatorConfig,
+
+	/// Device tree set by `setup_dtb()`. If `None`, device tree
+	/// is generated from the machine configuration.
+	custom_dtb: Option<Vec<u8>>,
+
+	/// Device tree overlays added by `add_dtb_overlay()`. They are
+	/// applied to the base device tree in order.
+	dtb_overlays: Vec<Vec<u8>>,
+
+	/// Physical address range of the initial ramdisk loaded by
+	/// `setup_initrd()`, end exclusive
+	initrd: Option<(u64, u64)>,
+
+	/// Host-Target Interface enabled by `setup_htif()`
+	htif: Option<Htif>,
+
+	/// User mode emulation set up by `setup_linux_user_program()`
+	linux_user: Option<LinuxUser>,
+
+	/// Set by `set_clock()`. `None` for `SystemClock`
+	clock: Option<Rc<dyn Clock>>,
+
+	/// Virtual addresses where `run_program()` stops
+	breakpoints: FnvHashSet<u64>,
+
+	/// Set by `set_instruction_trace()`
+	instruction_trace: Option<InstructionTrace>,
+
+	/// Set by `set_call_trace()`
+	call_trace: Option<CallTrace>,
+
+	/// Set by `set_attack_simulator()`
+	attack_simulator: Option<AttackSimulator>,
+
+	/// Set by `set_protection_overhead()`
+	protection_overhead: Option<ProtectionOverhead>,
+
+	/// Set by `set_syscall_trace()`
+	syscall_trace: Option<SyscallTrace>,
+
+	/// Set by `set_coverage()`
+	coverage: Option<Coverage>,
+
+	/// Set by `set_profiler()`
+	profiler: Option<Profiler>,
+
+	/// Set by `set_speed_meter()`
+	speed_meter: Option<SpeedMeter>,
+
+	/// Set by `set_cache_stats()`
+	cache_stats: Option<CacheStats>,
+
+	/// Set by `set_instruction_history()`
+	instruction_history: Option<InstructionHistory>,
+
+	/// Set by `set_checkpoints()`
+	checkpoints: Option<Checkpoints>
+}
+
+// Runs CPU one cycle followed by the host side of the program
+fn tick_cpu(cpu: &mut Cpu, htif: &mut Option<Htif>, linux_user: &mut Option<LinuxUser>) {
+	cpu.tick();
+	if let Some(htif) = htif {
+		htif.tick(cpu);
+	}
+	if let Some(linux_user) = linux_user {
+		linux_user.tick(cpu);
+	}
 }
 
 impl Emulator {
@@ -54,46 +314,286 @@ impl Emulator {
 	/// # Arguments
 	/// * `terminal`
 	pub fn new(terminal: Box<dyn Terminal>) -> Self {
-		Emulator {
-			cpu: Cpu::new(terminal),
+		Self::new_with_config(terminal, EmulatorConfig::default())
+	}
+
+	/// Creates a new `Emulator` with machine configuration.
+	/// See [`EmulatorConfig`](config/struct.EmulatorConfig.html).
+	///
+	/// # Arguments
+	/// * `terminal`
+	/// * `config`
+	pub fn new_with_config(terminal: Box<dyn Terminal>, config: EmulatorConfig) -> Self {
+		let mut emulator = Emulator {
+			cpu: Cpu::new_with_config(terminal, &config),
 
 			symbol_map: FnvHashMap::default(),
 
 			// These can be updated in setup_program()
 			is_test: false,
-			tohost_addr: 0 // assuming tohost_addr is non-zero if exists
+			tohost_addr: 0, // assuming tohost_addr is non-zero if exists
+
+			config,
+			custom_dtb: None,
+			dtb_overlays: vec![],
+			initrd: None,
+			htif: None,
+			linux_user: None,
+			clock: None,
+			breakpoints: FnvHashSet::default(),
+			instruction_trace: None,
+			call_trace: None,
+			attack_simulator: None,
+			protection_overhead: None,
+			syscall_trace: None,
+			coverage: None,
+			profiler: None,
+			speed_meter: None,
+			cache_stats: None,
+			instruction_history: None,
+			checkpoints: None
+		};
+		// No overlay or initial ramdisk to apply yet
+		let dtb = emulator.get_base_dtb();
+		emulator.cpu.get_mut_mmu().init_dtb(dtb);
+		emulator
+	}
+
+	/// Returns base device tree. It's the one set by `setup_dtb()` or generated
+	/// from the machine configuration and the current CPU state.
+	fn get_base_dtb(&self) -> Vec<u8> {
+		match &self.custom_dtb {
+			Some(dtb) => dtb.clone(),
+			None => {
+				#[cfg(feature = "virtio")]
+				let (has_network, has_sound) = (self.cpu.get_mmu().get_net().has_backend(), self.cpu.get_mmu().get_snd().has_callback());
+				#[cfg(not(feature = "virtio"))]
+				let (has_network, has_sound) = (false, false);
+				let machine = MachineDescription {
+					// Only single hart is supported so far
+					hart_num: 1,
+					isa: self.cpu.get_isa_string(),
+					memory_capacity: self.get_memory_capacity(),
+					has_network,
+					has_sound
+				};
+				generate_dtb(&self.config, &machine)
+			}
+		}
+	}
+
+	/// Returns base device tree with overlays applied and the initial
+	/// ramdisk location.
+	fn build_dtb(&self) -> Result<Vec<u8>, LoadError> {
+		let mut dtb = self.get_base_dtb();
+		for overlay in self.dtb_overlays.iter() {
+			dtb = apply_dtb_overlay(&dtb, overlay)?;
 		}
+		if let Some((start, end)) = self.initrd {
+			dtb = set_dtb_initrd(&dtb, start, end)?;
+		}
+		Ok(dtb)
+	}
+
+	/// Sets base device tree with overlays applied. Returns `Err` and keeps
+	/// the current device tree if they can't be applied.
+	fn update_dtb(&mut self) -> Result<(), LoadError> {
+		let dtb = self.build_dtb()?;
+		self.cpu.get_mut_mmu().init_dtb(dtb);
+		Ok(())
 	}
 
 	/// Runs program set by `setup_program()`. Calls `run_test()` if the program
 	/// is [`riscv-tests`](https://github.com/riscv/riscv-tests).
-	/// Otherwise calls `run_program()`.
-	pub fn run(&mut self) {
+	/// Otherwise calls `run_program()` and returns `Err` if it stops at
+	/// an internal error. Calling this method again resumes the program.
+	pub fn run(&mut self) -> Result<(), ExecError> {
 		match self.is_test {
-			true => self.run_test(),
-			false => self.run_program()
-		};
+			true => {
+				self.run_test();
+				Ok(())
+			},
+			false => match self.run_program() {
+				StopReason::Error(error) => Err(error),
+				_ => Ok(())
+			}
+		}
 	}
 
-	/// Runs program set by `setup_program()`. The emulator won't stop forever.
-	pub fn run_program(&mut self) {
+	/// Runs CPU one cycle like `tick()` and returns `Err` if the emulator
+	/// has hit an internal error, e.g. an instruction not implemented.
+	/// The emulator has recovered from it so can keep running.
+	pub fn step(&mut self) -> Result<(), ExecError> {
+		self.tick();
+		match self.cpu.take_error() {
+			Some(error) => Err(error),
+			None => Ok(())
+		}
+	}
+
+	/// Runs program set by `setup_program()`. The emulator won't stop forever
+	/// unless the program exits through HTIF or in user mode emulation,
+	/// or reaches a breakpoint added with `add_breakpoint()`, or hits
+	/// an internal error. See `setup_htif()` and `setup_linux_user_program()`.
+	/// Calling this method again after a breakpoint resumes the program
+	/// from there.
+	pub fn run_program(&mut self) -> StopReason {
 		loop {
+			if let Some(reason) = self.run_program_cycles(u64::MAX) {
+				return reason;
+			}
+		}
+	}
+
+	/// Runs program like `run_program()` but up to the number of cycles,
+	/// e.g. for a debugger to check its connection in between. Returns
+	/// `None` if the program hasn't stopped in the cycles. A breakpoint
+	/// at the PC the cycles end at is reported on the return, so calling
+	/// this method again always resumes the program.
+	///
+	/// # Arguments
+	/// * `max_cycles`
+	pub fn run_program_cycles(&mut self, max_cycles: u64) -> Option<StopReason> {
+		// Doesn't stop at the breakpoint resumed from until the PC leaves it
+		let mut resumed_address = Some(self.cpu.read_pc());
+		for _ in 0..max_cycles {
+			if !self.breakpoints.is_empty() {
+				let pc = self.cpu.read_pc();
+				if resumed_address != Some(pc) {
+					resumed_address = None;
+					if self.breakpoints.contains(&pc) {
+						return Some(StopReason::Breakpoint(pc));
+					}
+				}
+			}
+			let pc = self.cpu.read_pc();
 			self.tick();
+			if let Some(hit) = self.cpu.get_mut_mmu().take_watchpoint_hit() {
+				return Some(StopReason::Watchpoint {
+					pc,
+					hit
+				});
+			}
+			if let Some(code) = self.get_exit_code() {
+				return Some(StopReason::Exited(code));
+			}
+			if let Some(address) = self.instruction_history.as_mut().and_then(|history| history.take_stall()) {
+				return Some(StopReason::Stalled(address));
+			}
+			if let Some(error) = self.cpu.take_error() {
+				return Some(StopReason::Error(error));
+			}
+		}
+		let pc = self.cpu.read_pc();
+		match resumed_address != Some(pc) && self.breakpoints.contains(&pc) {
+			true => Some(StopReason::Breakpoint(pc)),
+			false => None
+		}
+	}
+
+	/// Returns a future running program like `run_program()` in slices of
+	/// the cycles, yielding to the other tasks on the async runtime in
+	/// between, so the emulator shares the runtime with the tasks doing
+	/// its I/O, e.g. through `AsyncTerminal` and `AsyncNetBackend`,
+	/// instead of blocking a thread.
+	///
+	/// # Arguments
+	/// * `slice_cycles` Cycles run before yielding. Must be more than zero
+	#[cfg(feature = "tokio")]
+	pub fn run_program_async(&mut self, slice_cycles: u64) -> async_runner::RunProgram<'_> {
+		async_runner::RunProgram::new(self, slice_cycles)
+	}
+
+	/// Adds a breakpoint. `run_program()` stops before running the
+	/// instruction at the address, regardless of the privilege mode.
+	///
+	/// # Arguments
+	/// * `address` Virtual address
+	pub fn add_breakpoint(&mut self, address: u64) {
+		self.breakpoints.insert(address);
+	}
+
+	/// Removes a breakpoint. Returns `Err` if no breakpoint is at the address.
+	///
+	/// # Arguments
+	/// * `address` Virtual address
+	pub fn remove_breakpoint(&mut self, address: u64) -> Result<(), DebugError> {
+		match self.breakpoints.remove(&address) {
+			true => Ok(()),
+			false => Err(DebugError::NoBreakpoint(address))
 		}
 	}
 
+	/// Adds a data watchpoint for reads. `run_program()` stops after
+	/// an instruction loads data from the range, regardless of
+	/// the privilege mode.
+	///
+	/// # Arguments
+	/// * `address` Virtual address
+	/// * `length` Range length in bytes
+	pub fn watch_read(&mut self, address: u64, length: u64) {
+		self.cpu.get_mut_mmu().add_watchpoint(WatchpointType::Read, address, length);
+	}
+
+	/// Adds a data watchpoint for writes. `run_program()` stops after
+	/// an instruction stores data to the range, regardless of
+	/// the privilege mode.
+	///
+	/// # Arguments
+	/// * `address` Virtual address
+	/// * `length` Range length in bytes
+	pub fn watch_write(&mut self, address: u64, length: u64) {
+		self.cpu.get_mut_mmu().add_watchpoint(WatchpointType::Write, address, length);
+	}
+
+	/// Removes a data watchpoint added with `watch_read()` or `watch_write()`.
+	/// Returns `Err` if no such watchpoint is found.
+	///
+	/// # Arguments
+	/// * `watchpoint_type`
+	/// * `address` Virtual address
+	/// * `length` Range length in bytes
+	pub fn remove_watchpoint(&mut self, watchpoint_type: WatchpointType, address: u64, length: u64) -> Result<(), DebugError> {
+		self.cpu.get_mut_mmu().remove_watchpoint(watchpoint_type, address, length)
+	}
+
+	/// Adds a value watch calling back when a store changes the eight
+	/// bytes of main memory at the physical address and the condition
+	/// meets, e.g. when the guest writes a command to a mailbox. It's
+	/// evaluated on stores, not by polling every tick. Returns the id to
+	/// remove the watch with. See `Mmu::add_value_watch()` for details.
+	///
+	/// # Arguments
+	/// * `address` Physical address
+	/// * `condition`
+	/// * `callback` Called with the old and new values
+	pub fn add_value_watch(&mut self, address: u64, condition: ValueWatchCondition, callback: ValueWatchCallback) -> usize {
+		self.cpu.get_mut_mmu().add_value_watch(address, condition, callback)
+	}
+
+	/// Removes a value watch added with `add_value_watch()`. Returns `Err`
+	/// if no such watch is found.
+	///
+	/// # Arguments
+	/// * `id`
+	pub fn remove_value_watch(&mut self, id: usize) -> Result<(), DebugError> {
+		self.cpu.get_mut_mmu().remove_value_watch(id)
+	}
+
 	/// Method for running [`riscv-tests`](https://github.com/riscv/riscv-tests) program.
 	/// The differences from `run_program()` are
 	/// * Disassembles every instruction and dumps to terminal
 	/// * The emulator stops when the test finishes
 	/// * Displays the result message (pass/fail) to terminal
 	pub fn run_test(&mut self) {
-		// @TODO: Send this message to terminal?
-		println!("This elf file seems riscv-tests elf file. Running in test mode.");
+		info!(target: "emulator", "This elf file seems riscv-tests elf file. Running in test mode.");
 		loop {
-			let disas = self.cpu.disassemble_next_instruction();
-			self.put_bytes_to_terminal(disas.as_bytes());
-			self.put_bytes_to_terminal(&[10]); // new line
+			// Instruction trace, if set, replaces the disassembly output
+			if self.instruction_trace.is_none() {
+				let disas = self.cpu.disassemble_next_instruction();
+				self.put_bytes_to_terminal(disas.as_bytes());
+				self.put_bytes_to_terminal(&[10]); // new line
+			}
 
 			self.tick();
 
@@ -123,255 +623,1667 @@ impl Emulator {
 	/// # Arguments
 	/// * `bytes`
 	fn put_bytes_to_terminal(&mut self, bytes: &[u8]) {
-		for i in 0..bytes.len() {
-			self.cpu.get_mut_terminal().put_byte(bytes[i]);
+		self.cpu.get_mut_terminal().put_bytes(bytes);
+	}
+
+	/// Returns main memory capacity. [`riscv-tests`](https://github.com/riscv/riscv-tests)
+	/// program runs with small memory.
+	fn get_memory_capacity(&self) -> u64 {
+		match self.is_test {
+			true => TEST_MEMORY_CAPACITY,
+			false => self.config.memory_capacity
 		}
 	}
 
 	/// Runs CPU one cycle
 	pub fn tick(&mut self) {
-		self.cpu.tick();
+		let checkpoint_due = match &mut self.checkpoints {
+			Some(checkpoints) => !self.cpu.is_waiting_for_interrupt() && checkpoints.count(),
+			None => false
+		};
+		if checkpoint_due {
+			let snapshot = self.take_snapshot();
+			let pc = self.cpu.read_pc();
+			if let Some(checkpoints) = &mut self.checkpoints {
+				checkpoints.push(pc, snapshot);
+			}
+		}
+		if let Some(call_trace) = &mut self.call_trace {
+			call_trace.trace(&mut self.cpu);
+		}
+		if let Some(attack_simulator) = &mut self.attack_simulator {
+			attack_simulator.trace(&mut self.cpu);
+		}
+		if let Some(protection_overhead) = &mut self.protection_overhead {
+			protection_overhead.count(&mut self.cpu);
+		}
+		if let Some(syscall_trace) = &mut self.syscall_trace {
+			syscall_trace.trace(&mut self.cpu);
+		}
+		if let Some(coverage) = &mut self.coverage {
+			coverage.trace(&mut self.cpu);
+		}
+		if let Some(profiler) = &mut self.profiler {
+			profiler.sample(&mut self.cpu);
+		}
+		if let Some(speed_meter) = &mut self.speed_meter {
+			speed_meter.count(&self.cpu);
+		}
+		if let Some(cache_stats) = &mut self.cache_stats {
+			cache_stats.sample(&self.cpu);
+		}
+		if let Some(history) = &mut self.instruction_history {
+			history.record(&mut self.cpu);
+		}
+		let traced = match &self.instruction_trace {
+			Some(trace) => trace.is_traced(self.cpu.read_pc()) && !self.cpu.is_waiting_for_interrupt(),
+			None => false
+		};
+		match traced {
+			true => self.tick_with_trace(),
+			false => tick_cpu(&mut self.cpu, &mut self.htif, &mut self.linux_user)
+		};
 	}
 
-	/// Sets up program run by the program. This method analyzes the passed content
-	/// and configure CPU properly. If the passed contend doesn't seem ELF file,
-	/// it panics. This method is expected to be called only once.
+	/// Runs CPU up to the cycles in a batch with `Cpu::tick_batch()`,
+	/// ticking the devices, checking the interrupts, and serving HTIF or
+	/// the user mode system calls once per batch. Returns the cycles run.
+	/// Runs one cycle with `tick()` while a tracer, a profiler, or another
+	/// per-instruction hook is set. Breakpoints and watchpoints are left
+	/// to the caller, so use `run_program()` to stop at them.
 	///
 	/// # Arguments
-	/// * `data` Program binary
-	// @TODO: Make ElfAnalyzer and move the core logic there.
-	// @TODO: Returns `Err` if the passed contend doesn't seem ELF file
-	pub fn setup_program(&mut self, data: Vec<u8>) {
-		let analyzer = ElfAnalyzer::new(data);
-
-		if !analyzer.validate() {
-			panic!("This file does not seem ELF file");
+	/// * `max_cycles`
+	pub fn tick_batch(&mut self, max_cycles: u64) -> u64 {
+		if self.has_instruction_hooks() {
+			self.tick();
+			return 1;
 		}
-
-		let header = analyzer.read_header();
-		//let program_headers = analyzer._read_program_headers(&header);
-		let section_headers = analyzer.read_section_headers(&header);
-
-		let mut program_data_section_headers = vec![];
-		let mut symbol_table_section_headers = vec![];
-		let mut string_table_section_headers = vec![];
-
-		for i in 0..section_headers.len() {
-			match section_headers[i].sh_type {
-				1 => program_data_section_headers.push(&section_headers[i]),
-				2 => symbol_table_section_headers.push(&section_headers[i]),
-				3 => string_table_section_headers.push(&section_headers[i]),
-				_ => {}
-			};
+		let cycles = self.cpu.tick_batch(max_cycles);
+		if let Some(htif) = &mut self.htif {
+			htif.tick(&mut self.cpu);
+		}
+		if let Some(linux_user) = &mut self.linux_user {
+			linux_user.tick(&mut self.cpu);
 		}
+		cycles
+	}
 
-		// Find program data section named .tohost to detect if the elf file is riscv-tests
-		self.tohost_addr = match analyzer.find_tohost_addr(
-			&program_data_section_headers,
-			&string_table_section_headers) {
-			Some(address) => address,
-			None => 0
-		};
+	// Whether a hook looking at every instruction is set
+	fn has_instruction_hooks(&self) -> bool {
+		self.checkpoints.is_some() || self.call_trace.is_some() || self.attack_simulator.is_some() ||
+			self.protection_overhead.is_some() || self.syscall_trace.is_some() || self.coverage.is_some() ||
+			self.profiler.is_some() || self.speed_meter.is_some() || self.cache_stats.is_some() ||
+			self.instruction_history.is_some() || self.instruction_trace.is_some()
+	}
 
-		// Creates symbol - virtual address mapping
-		if string_table_section_headers.len() > 0 {
-			let entries = analyzer.read_symbol_entries(&header, &symbol_table_section_headers);
-			// Assuming symbols are in the first string table section.
-			// @TODO: What if symbol can be in the second or later string table sections?
-			let map = analyzer.create_symbol_map(&entries, &string_table_section_headers[0]);
-			for key in map.keys() {
-				self.symbol_map.insert(key.to_string(), *map.get(key).unwrap());
+	fn tick_with_trace(&mut self) {
+		let register_writes = match &mut self.instruction_trace {
+			Some(trace) => {
+				trace.record_before(&mut self.cpu);
+				trace.is_register_writes_enabled()
+			},
+			None => false
+		};
+		let diff = match register_writes {
+			true => Some(self.step_diff()),
+			false => {
+				tick_cpu(&mut self.cpu, &mut self.htif, &mut self.linux_user);
+				None
 			}
+		};
+		if let Some(trace) = &mut self.instruction_trace {
+			trace.record_after(&self.cpu, diff.as_ref());
 		}
+	}
 
-		// Detected whether the elf file is riscv-tests.
-		// Setting up CPU and Memory depending on it.
-
-		self.cpu.update_xlen(match header.e_width {
-			32 => Xlen::Bit32,
-			64 => Xlen::Bit64,
-			_ => panic!("No happen")
-		});
+	/// Sets the instruction trace recording the instructions run after this call.
+	/// Symbols loaded by `setup_program()` and `load_program_for_symbols()`
+	/// so far are shown in QEMU format.
+	///
+	/// # Arguments
+	/// * `trace`
+	pub fn set_instruction_trace(&mut self, mut trace: InstructionTrace) {
+		trace.set_symbol_table(SymbolTable::new(&self.symbol_map));
+		self.instruction_trace = Some(trace);
+	}
 
-		if self.tohost_addr != 0 {
-			self.is_test = true;
-			self.cpu.get_mut_mmu().init_memory(TEST_MEMORY_CAPACITY);
-		} else {
-			self.is_test = false;
-			self.cpu.get_mut_mmu().init_memory(PROGRAM_MEMORY_CAPACITY);
-		}
-
-		for i in 0..program_data_section_headers.len() {
-			let sh_addr = program_data_section_headers[i].sh_addr;
-			let sh_offset = program_data_section_headers[i].sh_offset as usize;
-			let sh_size = program_data_section_headers[i].sh_size as usize;
-			if sh_addr >= 0x80000000 && sh_offset > 0 && sh_size > 0 {
-				for j in 0..sh_size {
-					self.cpu.get_mut_mmu().store_raw(sh_addr + j as u64, analyzer.read_byte(sh_offset + j));
-				}
-			}
-		}
+	/// Returns the instruction trace to start, stop, or filter tracing.
+	pub fn get_mut_instruction_trace(&mut self) -> Option<&mut InstructionTrace> {
+		self.instruction_trace.as_mut()
+	}
 
-		self.cpu.update_pc(header.e_entry);
+	/// Removes the instruction trace and returns it.
+	pub fn take_instruction_trace(&mut self) -> Option<InstructionTrace> {
+		self.instruction_trace.take()
 	}
 
-	/// Loads symbols of program and adds them to `symbol_map`.
+	/// Sets the instruction history keeping the latest instructions run
+	/// after this call.
 	///
 	/// # Arguments
-	/// * `content` Program binary
-	pub fn load_program_for_symbols(&mut self, content: Vec<u8>) {
-		let analyzer = ElfAnalyzer::new(content);
+	/// * `history`
+	pub fn set_instruction_history(&mut self, history: InstructionHistory) {
+		self.instruction_history = Some(history);
+	}
 
-		if !analyzer.validate() {
-			panic!("This file does not seem ELF file");
-		}
+	/// Returns the instruction history to dump, e.g. when `run_program()`
+	/// stops.
+	pub fn get_mut_instruction_history(&mut self) -> Option<&mut InstructionHistory> {
+		self.instruction_history.as_mut()
+	}
 
-		let header = analyzer.read_header();
-		let section_headers = analyzer.read_section_headers(&header);
+	/// Removes the instruction history and returns it.
+	pub fn take_instruction_history(&mut self) -> Option<InstructionHistory> {
+		self.instruction_history.take()
+	}
 
-		let mut program_data_section_headers = vec![];
-		let mut symbol_table_section_headers = vec![];
-		let mut string_table_section_headers = vec![];
+	/// Sets the checkpoints taking a snapshot of the guest periodically
+	/// after this call.
+	///
+	/// # Arguments
+	/// * `checkpoints`
+	pub fn set_checkpoints(&mut self, checkpoints: Checkpoints) {
+		self.checkpoints = Some(checkpoints);
+	}
 
-		for i in 0..section_headers.len() {
-			match section_headers[i].sh_type {
-				1 => program_data_section_headers.push(&section_headers[i]),
-				2 => symbol_table_section_headers.push(&section_headers[i]),
-				3 => string_table_section_headers.push(&section_headers[i]),
-				_ => {}
-			};
-		}
+	/// Returns the checkpoints to list, e.g. when `run_program()` stops.
+	pub fn get_mut_checkpoints(&mut self) -> Option<&mut Checkpoints> {
+		self.checkpoints.as_mut()
+	}
 
-		// Creates symbol - virtual address mapping
-		if string_table_section_headers.len() > 0 {
-			let entries = analyzer.read_symbol_entries(&header, &symbol_table_section_headers);
-			// Assuming symbols are in the first string table section.
-			// @TODO: What if symbol can be in the second or later string table sections?
-			let map = analyzer.create_symbol_map(&entries, &string_table_section_headers[0]);
-			for key in map.keys() {
-				self.symbol_map.insert(key.to_string(), *map.get(key).unwrap());
-			}
-		}
+	/// Removes the checkpoints and returns them.
+	pub fn take_checkpoints(&mut self) -> Option<Checkpoints> {
+		self.checkpoints.take()
 	}
 
-	/// Sets up filesystem. Use this method if program (e.g. Linux) uses
-	/// filesystem. This method is expected to be called up to only once.
+	/// Rewinds the guest to the checkpoint taken by the checkpoints set
+	/// with `set_checkpoints()` and drops the later checkpoints, which
+	/// are taken again as the guest runs. The checkpoint is kept, so it
+	/// can be rolled back to again, e.g. to replay the window after it
+	/// with heavier tracing enabled each time. Returns `Err` if
+	/// the checkpoint isn't kept.
 	///
 	/// # Arguments
-	/// * `content` File system content binary
-	pub fn setup_filesystem(&mut self, content: Vec<u8>) {
-		self.cpu.get_mut_mmu().init_disk(content);
+	/// * `id` `CheckpointInfo::id`
+	pub fn rollback_to(&mut self, id: usize) -> Result<(), DebugError> {
+		let mut checkpoints = match self.checkpoints.take() {
+			Some(checkpoints) => checkpoints,
+			None => return Err(DebugError::NoCheckpoints)
+		};
+		let result = checkpoints.rollback(id).map(|snapshot| self.restore_snapshot(snapshot));
+		self.checkpoints = Some(checkpoints);
+		result
 	}
 
-	/// Sets up device tree. The emulator has default device tree configuration.
-	/// If you want to override it, use this method. This method is expected to
-	/// to be called up to only once.
+	/// Moves the guest to just before the instruction of the number
+	/// counted by the checkpoints set with `set_checkpoints()`, see
+	/// `Checkpoints::get_instructions()`, backward or forward. It rolls
+	/// back to the latest checkpoint before it if backward and replays
+	/// from there, so the state is the same as the first time if the guest
+	/// doesn't depend on input or devices, which checkpoints don't save.
+	/// Breakpoints are ignored, and the traces, coverage, and others set
+	/// record the replayed instructions again. Returns `Err` if no
+	/// checkpoint is kept before it, or the program exits before it.
 	///
 	/// # Arguments
-	/// * `content` DTB content binary
-	pub fn setup_dtb(&mut self, content: Vec<u8>) {
-		self.cpu.get_mut_mmu().init_dtb(content);
+	/// * `instructions`
+	pub fn goto_instruction(&mut self, instructions: u64) -> Result<(), DebugError> {
+		let current = match &self.checkpoints {
+			Some(checkpoints) => checkpoints.get_instructions(),
+			None => return Err(DebugError::NoCheckpoints)
+		};
+		if instructions < current {
+			let checkpoint = self.checkpoints.as_ref().and_then(|checkpoints| checkpoints.find_before(instructions));
+			match checkpoint {
+				Some(checkpoint) => self.rollback_to(checkpoint.id)?,
+				None => return Err(DebugError::NoCheckpoint)
+			};
+		}
+		self.replay_to(instructions)
 	}
 
-	/// Updates XLEN (the width of an integer register in bits) in CPU.
+	/// Moves the guest back to just before the last instruction storing
+	/// to the range, e.g. to find who has corrupted a variable, and returns
+	/// the number of the instruction. It replays the windows between
+	/// the checkpoints set with `set_checkpoints()` from the latest with
+	/// a write watchpoint, so the earliest checkpoint kept bounds how far
+	/// it looks back. Returns `Err` and stays at the current instruction
+	/// if no store is found. See `goto_instruction()` for the caveats.
 	///
 	/// # Arguments
-	/// * `xlen`
-	pub fn update_xlen(&mut self, xlen: Xlen) {
-		self.cpu.update_xlen(xlen);
+	/// * `address` Virtual address
+	/// * `length` Range length in bytes
+	pub fn goto_last_write(&mut self, address: u64, length: u64) -> Result<u64, DebugError> {
+		let (current, checkpoints) = match &self.checkpoints {
+			Some(checkpoints) => (checkpoints.get_instructions(), checkpoints.get_checkpoints()),
+			None => return Err(DebugError::NoCheckpoints)
+		};
+		self.cpu.get_mut_mmu().take_watchpoint_hit();
+		// An identical watchpoint the user has added is kept, not removed
+		// with the temporary one
+		let added = !self.cpu.get_mmu().has_watchpoint(WatchpointType::Write, address, length);
+		for (i, checkpoint) in checkpoints.iter().enumerate().rev() {
+			let end = checkpoints.get(i + 1).map_or(current, |next| next.instructions).min(current);
+			self.rollback_to(checkpoint.id)?;
+			if added {
+				self.watch_write(address, length);
+			}
+			let mut last_write = None;
+			let mut result = Ok(());
+			while let Some(instructions) = self.checkpoints.as_ref().map(|checkpoints| checkpoints.get_instructions()) {
+				if instructions >= end {
+					break;
+				}
+				if self.get_exit_code().is_some() {
+					result = Err(DebugError::Exited);
+					break;
+				}
+				self.tick();
+				if let Some(hit) = self.cpu.get_mut_mmu().take_watchpoint_hit() {
+					if hit.watchpoint_type == WatchpointType::Write && hit.address < address.wrapping_add(length) &&
+						hit.address.wrapping_add(hit.width) > address {
+						last_write = Some(instructions);
+					}
+				}
+			}
+			if added {
+				self.remove_watchpoint(WatchpointType::Write, address, length)?;
+			}
+			result?;
+			if let Some(instructions) = last_write {
+				self.goto_instruction(instructions)?;
+				return Ok(instructions);
+			}
+		}
+		self.goto_instruction(current)?;
+		Err(DebugError::NoWrite {
+			address,
+			length
+		})
 	}
 
-	/// Enables or disables page cache optimization.
-	/// Page cache optimization is experimental feature.
-	/// See [`Mmu`](./mmu/struct.Mmu.html) for the detail.
+	// Runs until the checkpoints count the number of instructions
+	fn replay_to(&mut self, instructions: u64) -> Result<(), DebugError> {
+		while let Some(current) = self.checkpoints.as_ref().map(|checkpoints| checkpoints.get_instructions()) {
+			if current >= instructions {
+				return Ok(());
+			}
+			if self.get_exit_code().is_some() {
+				return Err(DebugError::Exited);
+			}
+			self.tick();
+		}
+		Err(DebugError::NoCheckpoints)
+	}
+
+	/// Sets the call trace recording the function calls and returns after
+	/// this call. Function names are looked up in the symbols loaded by
+	/// `setup_program()` and `load_program_for_symbols()` so far.
 	///
 	/// # Arguments
-	/// * `enabled`
-	pub fn enable_page_cache(&mut self, enabled: bool) {
-		self.cpu.get_mut_mmu().enable_page_cache(enabled);
+	/// * `trace`
+	pub fn set_call_trace(&mut self, mut trace: CallTrace) {
+		trace.set_symbol_table(SymbolTable::new(&self.symbol_map));
+		self.call_trace = Some(trace);
 	}
 
-	/// Returns mutable reference to `Terminal`.
-	pub fn get_mut_terminal(&mut self) -> &mut Box<dyn Terminal> {
-		self.cpu.get_mut_terminal()
+	/// Returns the call trace to start or stop tracing.
+	pub fn get_mut_call_trace(&mut self) -> Option<&mut CallTrace> {
+		self.call_trace.as_mut()
 	}
 
-	/// Returns immutable reference to `Cpu`.
-	pub fn get_cpu(&self) -> &Cpu {
-		&self.cpu
+	/// Removes the call trace and returns it.
+	pub fn take_call_trace(&mut self) -> Option<CallTrace> {
+		self.call_trace.take()
 	}
 
-	/// Returns mutable reference to `Cpu`.
-	pub fn get_mut_cpu(&mut self) -> &mut Cpu {
-		&mut self.cpu
+	/// Sets the attack simulator corrupting the saved return addresses
+	/// after this call.
+	///
+	/// # Arguments
+	/// * `simulator`
+	pub fn set_attack_simulator(&mut self, simulator: AttackSimulator) {
+		self.attack_simulator = Some(simulator);
 	}
 
-	/// Returns a virtual address corresponding to symbol strings
+	/// Returns the attack simulator to read the attacks made so far.
+	pub fn get_attack_simulator(&self) -> Option<&AttackSimulator> {
+		self.attack_simulator.as_ref()
+	}
+
+	/// Removes the attack simulator and returns it.
+	pub fn take_attack_simulator(&mut self) -> Option<AttackSimulator> {
+		self.attack_simulator.take()
+	}
+
+	/// Sets the protection overhead measurement counting the instructions
+	/// run after this call.
 	///
 	/// # Arguments
-	/// * `s` Symbol strings
-	pub fn get_addredd_of_symbol(&self, s: &String) -> Option<u64> {
-		match self.symbol_map.get(s) {
-			Some(address) => Some(*address),
-			None => None
-		}
+	/// * `protection_overhead`
+	pub fn set_protection_overhead(&mut self, protection_overhead: ProtectionOverhead) {
+		self.protection_overhead = Some(protection_overhead);
 	}
-}
-
-#[cfg(test)]
-mod test_emulator {
-	use terminal::DummyTerminal;
-	use super::*;
 
-	fn create_emu() -> Emulator {
-		Emulator::new(
-			Box::new(DummyTerminal::new())
-		)
+	/// Returns the protection overhead measurement to read the report.
+	pub fn get_protection_overhead(&self) -> Option<&ProtectionOverhead> {
+		self.protection_overhead.as_ref()
 	}
 
-	#[test]
-	fn initialize() {
-		let _emu = create_emu();
+	/// Returns the mutable protection overhead measurement, e.g. to reset
+	/// the counts.
+	pub fn get_mut_protection_overhead(&mut self) -> Option<&mut ProtectionOverhead> {
+		self.protection_overhead.as_mut()
 	}
 
-	#[test]
-	#[ignore]
-	fn run() {
+	/// Removes the protection overhead measurement and returns it.
+	pub fn take_protection_overhead(&mut self) -> Option<ProtectionOverhead> {
+		self.protection_overhead.take()
 	}
 
-	#[test]
-	#[ignore]
-	fn run_program() {
+	/// Sets the system call trace recording the system calls of Linux
+	/// user programs after this call.
+	///
+	/// # Arguments
+	/// * `trace`
+	pub fn set_syscall_trace(&mut self, trace: SyscallTrace) {
+		self.syscall_trace = Some(trace);
 	}
 
-	#[test]
-	#[ignore]
-	fn run_test() {
+	/// Returns the system call trace to start or stop tracing.
+	pub fn get_mut_syscall_trace(&mut self) -> Option<&mut SyscallTrace> {
+		self.syscall_trace.as_mut()
 	}
 
-	#[test]
-	#[ignore]
-	fn tick() {
+	/// Removes the system call trace and returns it.
+	pub fn take_syscall_trace(&mut self) -> Option<SyscallTrace> {
+		self.syscall_trace.take()
 	}
 
-	#[test]
-	#[ignore]
-	fn setup_program() {
+	/// Sets the coverage tracking the basic blocks executed after this call.
+	///
+	/// # Arguments
+	/// * `coverage`
+	pub fn set_coverage(&mut self, coverage: Coverage) {
+		self.coverage = Some(coverage);
 	}
 
-	#[test]
-	#[ignore]
-	fn load_program_for_symbols() {
+	/// Returns the coverage to export or clear.
+	pub fn get_mut_coverage(&mut self) -> Option<&mut Coverage> {
+		self.coverage.as_mut()
 	}
 
-	#[test]
-	#[ignore]
-	fn setup_filesystem() {
+	/// Removes the coverage and returns it.
+	pub fn take_coverage(&mut self) -> Option<Coverage> {
+		self.coverage.take()
 	}
 
-	#[test]
-	#[ignore]
-	fn setup_dtb() {
+	/// Returns the most executed basic blocks, the most executed first,
+	/// with the disassembly to find hot code. Needs the coverage set with
+	/// `set_coverage()`, otherwise empty. The code is fetched at the time
+	/// of this call, so it can differ from what was executed if the address
+	/// space has changed since.
+	///
+	/// # Arguments
+	/// * `num` Maximum number of the blocks returned
+	pub fn get_hot_blocks(&mut self, num: usize) -> Vec<HotBlock> {
+		let blocks = match &self.coverage {
+			Some(coverage) => coverage.get_hot_blocks(num),
+			None => return vec![]
+		};
+		let xlen = self.cpu.get_xlen().clone();
+		blocks.into_iter().map(|(start, size, executions)| {
+			let mut instructions = vec![];
+			let mut address = start;
+			while address < start.wrapping_add(size) {
+				let word = match self.cpu.get_mut_mmu().fetch_word(address) {
+					Ok(word) => word,
+					Err(_e) => break
+				};
+				instructions.push((address, disassemble(word, address, &xlen)));
+				address = address.wrapping_add(match (word & 0x3) == 0x3 {
+					true => 4,
+					false => 2
+				});
+			}
+			HotBlock {
+				start,
+				size,
+				executions,
+				instructions
+			}
+		}).collect()
+	}
+
+	/// Sets the profiler sampling the guest PC after this call. Function
+	/// names are looked up in the symbols loaded by `setup_program()` and
+	/// `load_program_for_symbols()` so far.
+	///
+	/// # Arguments
+	/// * `profiler`
+	pub fn set_profiler(&mut self, mut profiler: Profiler) {
+		profiler.set_symbol_table(SymbolTable::new(&self.symbol_map));
+		self.profiler = Some(profiler);
+	}
+
+	/// Returns the profiler to start, stop, or export.
+	pub fn get_mut_profiler(&mut self) -> Option<&mut Profiler> {
+		self.profiler.as_mut()
+	}
+
+	/// Removes the profiler and returns it.
+	pub fn take_profiler(&mut self) -> Option<Profiler> {
+		self.profiler.take()
+	}
+
+	/// Sets the speed meter counting the instructions run after this call.
+	///
+	/// # Arguments
+	/// * `speed_meter`
+	pub fn set_speed_meter(&mut self, speed_meter: SpeedMeter) {
+		self.speed_meter = Some(speed_meter);
+	}
+
+	/// Returns the speed meter to read or reset.
+	pub fn get_mut_speed_meter(&mut self) -> Option<&mut SpeedMeter> {
+		self.speed_meter.as_mut()
+	}
+
+	/// Removes the speed meter and returns it.
+	pub fn take_speed_meter(&mut self) -> Option<SpeedMeter> {
+		self.speed_meter.take()
+	}
+
+	/// Sets the cache statistics sampling the decode cache and page cache
+	/// counters after this call.
+	///
+	/// # Arguments
+	/// * `cache_stats`
+	pub fn set_cache_stats(&mut self, cache_stats: CacheStats) {
+		self.cache_stats = Some(cache_stats);
+	}
+
+	/// Returns the cache statistics to look at the samples so far.
+	pub fn get_mut_cache_stats(&mut self) -> Option<&mut CacheStats> {
+		self.cache_stats.as_mut()
+	}
+
+	/// Removes the cache statistics and returns them with the period
+	/// ending now sampled, to export.
+	pub fn take_cache_stats(&mut self) -> Option<CacheStats> {
+		let mut cache_stats = self.cache_stats.take();
+		if let Some(cache_stats) = &mut cache_stats {
+			cache_stats.flush(&self.cpu);
+		}
+		cache_stats
+	}
+
+	/// Runs CPU one cycle like `tick()` and returns the registers, CSRs, and
+	/// main memory bytes the instruction has changed, including the changes
+	/// by the system calls in user mode emulation.
+	pub fn step_diff(&mut self) -> StateDiff {
+		let htif = &mut self.htif;
+		let linux_user = &mut self.linux_user;
+		self.cpu.step_diff(|cpu| tick_cpu(cpu, htif, linux_user))
+	}
+
+	/// Reads the integer register or the PC. Returns `Err` for an unknown name.
+	///
+	/// # Arguments
+	/// * `name` `pc`, or the register name like `x10` or the ABI one like `a0`
+	pub fn get_register(&self, name: &str) -> Result<u64, DebugError> {
+		if name == "pc" {
+			return Ok(self.cpu.read_pc());
+		}
+		match get_register_number(name) {
+			Some(reg) => Ok(self.cpu.read_register(reg) as u64),
+			None => Err(DebugError::UnknownRegister(name.to_string()))
+		}
+	}
+
+	/// Writes the integer register or the PC. Writes to `zero` are ignored.
+	/// Returns `Err` for an unknown name.
+	///
+	/// # Arguments
+	/// * `name` `pc`, or the register name like `x10` or the ABI one like `a0`
+	/// * `value`
+	pub fn set_register(&mut self, name: &str, value: u64) -> Result<(), DebugError> {
+		if name == "pc" {
+			self.cpu.update_pc(value);
+			return Ok(());
+		}
+		match get_register_number(name) {
+			Some(0) => Ok(()),
+			Some(reg) => {
+				self.cpu.write_register(reg, value as i64);
+				Ok(())
+			},
+			None => Err(DebugError::UnknownRegister(name.to_string()))
+		}
+	}
+
+	/// Reads the floating point register in raw bits. Returns `Err` for
+	/// an unknown name.
+	///
+	/// # Arguments
+	/// * `name` The register name like `f10` or the ABI one like `fa0`
+	pub fn get_f_register(&self, name: &str) -> Result<u64, DebugError> {
+		match get_f_register_number(name) {
+			Some(reg) => Ok(self.cpu.read_f_register(reg)),
+			None => Err(DebugError::UnknownRegister(name.to_string()))
+		}
+	}
+
+	/// Writes the floating point register in raw bits, e.g.
+	/// `1.5f64.to_bits()`. Returns `Err` for an unknown name.
+	///
+	/// # Arguments
+	/// * `name` The register name like `f10` or the ABI one like `fa0`
+	/// * `value`
+	pub fn set_f_register(&mut self, name: &str, value: u64) -> Result<(), DebugError> {
+		match get_f_register_number(name) {
+			Some(reg) => {
+				self.cpu.write_f_register(reg, value);
+				Ok(())
+			},
+			None => Err(DebugError::UnknownRegister(name.to_string()))
+		}
+	}
+
+	/// Returns the implemented CSRs as (number, name, value) in the number
+	/// order, for debugger frontends and tests.
+	pub fn dump_csrs(&self) -> Vec<(u16, &'static str, u64)> {
+		self.cpu.dump_csrs()
+	}
+
+	/// Reads the CSR. Returns `Err` if the CSR isn't implemented or
+	/// the privilege mode can't access it.
+	///
+	/// # Arguments
+	/// * `address` CSR number. See `cpu::get_csr_address()` for names
+	/// * `privilege_mode` Mode to check the permission with. `None` for
+	///   the current mode of the guest
+	pub fn read_csr(&self, address: u16, privilege_mode: Option<PrivilegeMode>) -> Result<u64, DebugError> {
+		let privilege_mode = privilege_mode.unwrap_or_else(|| self.cpu.read_privilege_mode().clone());
+		self.cpu.read_csr_as(address, &privilege_mode)
+	}
+
+	/// Writes the CSR as an instruction would. Returns `Err` if the CSR
+	/// isn't implemented, is read-only, or the privilege mode can't access it.
+	///
+	/// # Arguments
+	/// * `address` CSR number. See `cpu::get_csr_address()` for names
+	/// * `value`
+	/// * `privilege_mode` Mode to check the permission with. `None` for
+	///   the current mode of the guest
+	pub fn write_csr(&mut self, address: u16, value: u64, privilege_mode: Option<PrivilegeMode>) -> Result<(), DebugError> {
+		let privilege_mode = privilege_mode.unwrap_or_else(|| self.cpu.read_privilege_mode().clone());
+		self.cpu.write_csr_as(address, value, &privilege_mode)
+	}
+
+	/// Starts or stops counting the retired instructions by extension and
+	/// privilege mode, e.g. to find which extensions a workload needs.
+	///
+	/// # Arguments
+	/// * `enabled`
+	pub fn enable_instruction_statistics(&mut self, enabled: bool) {
+		self.cpu.enable_instruction_statistics(enabled);
+	}
+
+	/// Returns the retired instruction counts as (extension, privilege
+	/// mode, count). See `Cpu::get_instruction_statistics()`.
+	pub fn get_instruction_statistics(&self) -> Vec<(Extension, PrivilegeMode, u64)> {
+		self.cpu.get_instruction_statistics()
+	}
+
+	/// Sets the retired instruction counts to zero.
+	pub fn reset_instruction_statistics(&mut self) {
+		self.cpu.reset_instruction_statistics();
+	}
+
+	/// Returns the forward-edge CFI statistics of the landing pads, or
+	/// `None` unless `EmulatorConfig.landing_pad` is set. See
+	/// `CfiStatistics`.
+	pub fn get_cfi_statistics(&self) -> Option<&CfiStatistics> {
+		self.cpu.get_cfi_statistics()
+	}
+
+	/// Clears the forward-edge CFI statistics.
+	pub fn reset_cfi_statistics(&mut self) {
+		self.cpu.reset_cfi_statistics();
+	}
+
+	/// Sets the trap log recording the traps the CPU takes from now.
+	/// Function names are looked up in the symbols loaded so far.
+	///
+	/// # Arguments
+	/// * `trap_log`
+	pub fn set_trap_log(&mut self, mut trap_log: TrapLog) {
+		trap_log.set_symbol_table(SymbolTable::new(&self.symbol_map));
+		self.cpu.set_trap_log(trap_log);
+	}
+
+	/// Returns the mutable reference to the trap log if set.
+	pub fn get_mut_trap_log(&mut self) -> Option<&mut TrapLog> {
+		self.cpu.get_mut_trap_log()
+	}
+
+	/// Removes the trap log and returns it if set.
+	pub fn take_trap_log(&mut self) -> Option<TrapLog> {
+		self.cpu.take_trap_log()
+	}
+
+	/// Sets the log recording the device register accesses from now.
+	/// Devices are enabled with `MmioLog::set_enabled()`, also through
+	/// `get_mut_mmio_log()` while the guest runs.
+	///
+	/// # Arguments
+	/// * `mmio_log`
+	pub fn set_mmio_log(&mut self, mmio_log: MmioLog) {
+		self.cpu.get_mut_mmu().set_mmio_log(mmio_log);
+	}
+
+	/// Returns the mutable reference to the MMIO log if set.
+	pub fn get_mut_mmio_log(&mut self) -> Option<&mut MmioLog> {
+		self.cpu.get_mut_mmu().get_mut_mmio_log()
+	}
+
+	/// Removes the MMIO log and returns it if set.
+	pub fn take_mmio_log(&mut self) -> Option<MmioLog> {
+		self.cpu.get_mut_mmu().take_mmio_log()
+	}
+
+	/// Saves the CPU state and main memory so that the guest can be
+	/// rewound with `restore_snapshot()` any number of times, e.g. to run
+	/// a fuzzing input per run. Restoring the last snapshot taken copies
+	/// back only the memory pages written since, which is fast. Devices,
+	/// except the CLINT timer, and the host side of HTIF and user mode
+	/// emulation other than the exit status are not saved.
+	pub fn take_snapshot(&mut self) -> Snapshot {
+		Snapshot {
+			cpu: self.cpu.take_snapshot(),
+			memory: self.cpu.get_mut_mmu().take_memory_snapshot(),
+			exit_code: self.get_exit_code()
+		}
+	}
+
+	/// Restores the guest state saved by `take_snapshot()`.
+	///
+	/// # Arguments
+	/// * `snapshot`
+	pub fn restore_snapshot(&mut self, snapshot: &Snapshot) {
+		self.cpu.restore_snapshot(&snapshot.cpu);
+		self.cpu.get_mut_mmu().restore_memory_snapshot(&snapshot.memory);
+		if let Some(htif) = &mut self.htif {
+			htif.set_exit_code(snapshot.exit_code);
+		}
+		if let Some(linux_user) = &mut self.linux_user {
+			linux_user.set_exit_code(snapshot.exit_code);
+		}
+		self.cpu.get_mut_mmu().get_mut_test_finisher().set_exit_code(snapshot.exit_code);
+	}
+
+	/// Enables Host-Target Interface. Programs running on
+	/// [`riscv-pk`](https://github.com/riscv-software-src/riscv-pk) and
+	/// benchmarks built for Spike can print, access host files and exit
+	/// with status code through it. `tohost` and `fromhost` symbols of the
+	/// program are used so call this method before `setup_program()`.
+	/// The program isn't run in test mode even if it's riscv-tests.
+	///
+	/// # Arguments
+	/// * `args` Arguments passed to the program, starting with the program
+	///   name. For `riscv-pk` the user program and its arguments follow.
+	pub fn setup_htif(&mut self, args: Vec<String>) {
+		self.htif = Some(Htif::new(args));
+	}
+
+	/// Returns the status code if the program has exited through HTIF
+	/// or in user mode emulation, or the machine has powered off through
+	/// the test finisher.
+	pub fn get_exit_code(&self) -> Option<u64> {
+		let exit_code = match (&self.htif, &self.linux_user) {
+			(Some(htif), _) => htif.get_exit_code(),
+			(None, Some(linux_user)) => linux_user.get_exit_code(),
+			(None, None) => None
+		};
+		exit_code.or_else(|| self.cpu.get_mmu().get_test_finisher().get_exit_code())
+	}
+
+	/// Sets up program run by the program. This method analyzes the passed content
+	/// with `elf_analyzer::parse_elf()` and configure CPU properly. Returns
+	/// `Err` if the passed content isn't ELF file or is broken, or a section
+	/// doesn't fit in main memory. This method is expected to be called
+	/// only once.
+	///
+	/// # Arguments
+	/// * `data` Program binary
+	pub fn setup_program(&mut self, data: Vec<u8>) -> Result<(), LoadError> {
+		let image = parse_elf(&data)?;
+
+		// Address of .tohost section detects if the elf file is riscv-tests
+		self.tohost_addr = image.tohost_addr.unwrap_or(0);
+		self.symbol_map.extend(image.symbols);
+
+		// Detected whether the elf file is riscv-tests.
+		// Setting up CPU and Memory depending on it.
+
+		// XLEN of the ISA string in the configuration wins
+		self.cpu.update_xlen(match (self.config.get_isa_xlen(), image.width) {
+			(Some(xlen), _) => xlen,
+			(None, 32) => Xlen::Bit32,
+			_ => Xlen::Bit64
+		});
+
+		self.is_test = self.tohost_addr != 0 && self.htif.is_none();
+		if let Some(htif) = &mut self.htif {
+			// riscv-tests places fromhost next to tohost in .tohost section
+			let tohost_addr = match self.symbol_map.get("tohost") {
+				Some(address) => *address,
+				None => self.tohost_addr
+			};
+			let fromhost_addr = match self.symbol_map.get("fromhost") {
+				Some(address) => *address,
+				None => tohost_addr + 0x40
+			};
+			htif.set_host_addresses(tohost_addr, fromhost_addr);
+		}
+		let memory_capacity = self.get_memory_capacity();
+		self.cpu.get_mut_mmu().init_memory(memory_capacity);
+
+		// XLEN and memory capacity are fixed now
+		self.update_dtb()?;
+
+		for section in image.sections.iter().filter(|section| section.address >= 0x80000000) {
+			self.cpu.get_mut_mmu().write_main_memory(section.address, &section.data).map_err(LoadError::Program)?;
+		}
+
+		self.cpu.update_pc(image.entry);
+		Ok(())
+	}
+
+	/// Loads an initial ramdisk, e.g. an initramfs cpio archive, at the top of
+	/// main memory and tells Linux where it is in the device tree, also in
+	/// the one set by `setup_dtb()`. Call this after `setup_program()`, which
+	/// initializes main memory. Returns `Err` if it doesn't fit in main
+	/// memory or the device tree can't tell its location.
+	///
+	/// # Arguments
+	/// * `data` Initial ramdisk content
+	pub fn setup_initrd(&mut self, data: Vec<u8>) -> Result<(), LoadError> {
+		let memory_end = DRAM_BASE + self.get_memory_capacity();
+		let size = data.len() as u64;
+		let start = memory_end.saturating_sub(size) & !(INITRD_ALIGNMENT - 1);
+		if self.cpu.get_mut_mmu().validate_address(memory_end - 1) != Ok(true) {
+			return Err(LoadError::Initrd(MemoryError::Unmapped(memory_end - 1)));
+		}
+		if size == 0 || start < DRAM_BASE {
+			return Err(LoadError::Initrd(MemoryError::OutOfRange {
+				address: start,
+				size
+			}));
+		}
+		let initrd = self.initrd.replace((start, start + size));
+		let dtb = match self.build_dtb() {
+			Ok(dtb) => dtb,
+			Err(error) => {
+				self.initrd = initrd;
+				return Err(error);
+			}
+		};
+		if let Err(error) = self.cpu.get_mut_mmu().write_main_memory(start, &data) {
+			self.initrd = initrd;
+			return Err(LoadError::Initrd(error));
+		}
+		self.cpu.get_mut_mmu().init_dtb(dtb);
+		Ok(())
+	}
+
+	/// Sets up a static RISC-V Linux program run in User mode without kernel,
+	/// like qemu-user. The program's system calls are emulated by the host.
+	/// See [`LinuxUser`](linux_user/struct.LinuxUser.html). Use this method
+	/// instead of `setup_program()`. Returns `Err` if the passed content
+	/// isn't ELF file or the program doesn't fit in main memory. This method
+	/// is expected to be called only once.
+	///
+	/// # Arguments
+	/// * `data` Program binary
+	/// * `args` Arguments passed to the program, starting with the program name
+	pub fn setup_linux_user_program(&mut self, data: Vec<u8>, args: Vec<String>) -> Result<(), LoadError> {
+		let analyzer = ElfAnalyzer::new(data);
+		let header = analyzer.read_header()?;
+		// XLEN of the ISA string in the configuration wins
+		self.cpu.update_xlen(match (self.config.get_isa_xlen(), header.e_width) {
+			(Some(xlen), _) => xlen,
+			(None, 32) => Xlen::Bit32,
+			_ => Xlen::Bit64
+		});
+		let memory_capacity = self.config.memory_capacity;
+		self.cpu.get_mut_mmu().init_memory(memory_capacity);
+
+		let clock = match &self.clock {
+			Some(clock) => clock.clone(),
+			None => create_default_clock()
+		};
+		let mut linux_user = LinuxUser::new(self.cpu.get_xlen().clone(), memory_capacity, clock);
+		linux_user.load_program(&mut self.cpu, &analyzer, &header, &args)?;
+		self.linux_user = Some(linux_user);
+		Ok(())
+	}
+
+	/// Sets the host time source the user mode emulation reads on clock
+	/// system calls and CLINT `mtime` follows with `TimerSource::HostClock`,
+	/// `SystemClock` by default, or `StoppedClock` without std. Set one on
+	/// hosts without `std::time`, e.g. wasm32-unknown-unknown or
+	/// a bare-metal host. Otherwise the emulation of the guest
+	/// machine doesn't read host time.
+	///
+	/// # Arguments
+	/// * `clock`
+	pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+		if let Some(linux_user) = &mut self.linux_user {
+			linux_user.set_clock(clock.clone());
+		}
+		self.cpu.get_mut_mmu().get_mut_clint().set_host_clock(clock.clone());
+		self.clock = Some(clock);
+	}
+
+	/// Loads symbols of program and adds them to `symbol_map`. Returns
+	/// `Err` if the content isn't ELF file or is broken.
+	///
+	/// # Arguments
+	/// * `content` Program binary
+	pub fn load_program_for_symbols(&mut self, content: Vec<u8>) -> Result<(), LoadError> {
+		let image = parse_elf(&content)?;
+		self.symbol_map.extend(image.symbols);
+		Ok(())
+	}
+
+	/// Loads kernel symbols from `System.map` of Linux or a dump of
+	/// `/proc/kallsyms` and adds the text symbols to `symbol_map`, so
+	/// the traces, the profiler, and the trap log show kernel function
+	/// names when booting an `Image` without `vmlinux`. Like the symbols
+	/// of `load_program_for_symbols()`, they're used by the traces and
+	/// others set after this call. Returns the number of symbols loaded,
+	/// or `Err` if the content isn't in the format.
+	///
+	/// # Arguments
+	/// * `content` Text content of the file
+	pub fn load_system_map(&mut self, content: &str) -> Result<usize, LoadError> {
+		let symbols = parse_system_map(content)?;
+		let num = symbols.len();
+		self.symbol_map.extend(symbols);
+		Ok(num)
+	}
+
+	/// Sets up filesystem. Use this method if program (e.g. Linux) uses
+	/// filesystem. This method is expected to be called up to only once.
+	/// See [`BlockBackend`](block_backend/trait.BlockBackend.html).
+	///
+	/// # Arguments
+	/// * `backend` File system storage
+	#[cfg(feature = "virtio")]
+	pub fn setup_filesystem(&mut self, backend: Box<dyn BlockBackend>) {
+		self.cpu.get_mut_mmu().init_disk(backend);
+	}
+
+	/// Connects virtio network device to `NetBackend`. The device appears
+	/// in the generated device tree once connected so call this method
+	/// before running the program. Returns `Err` if the device tree overlays
+	/// can't be applied to the updated device tree, keeping the current one.
+	///
+	/// # Arguments
+	/// * `backend`
+	#[cfg(feature = "virtio")]
+	pub fn setup_network(&mut self, backend: Box<dyn NetBackend>) -> Result<(), LoadError> {
+		self.cpu.get_mut_mmu().get_mut_net().set_backend(backend);
+		self.update_dtb()
+	}
+
+	/// Connects virtio network device to `NetBackend` while the guest runs,
+	/// replacing the current one. The guest is notified with a virtio
+	/// configuration change interrupt. The guest finds the device only if
+	/// it is in the device tree, so connect it with `setup_network()`
+	/// before running the program to unplug and replug it later.
+	///
+	/// # Arguments
+	/// * `backend`
+	#[cfg(feature = "virtio")]
+	pub fn attach_network(&mut self, backend: Box<dyn NetBackend>) {
+		self.cpu.get_mut_mmu().get_mut_net().attach(backend);
+	}
+
+	/// Disconnects virtio network device from `NetBackend` while the guest
+	/// runs. The virtio slot is seen as empty afterward until `attach_network()`.
+	#[cfg(feature = "virtio")]
+	pub fn detach_network(&mut self) {
+		self.cpu.get_mut_mmu().get_mut_net().detach();
+	}
+
+	/// Connects virtio sound device to the host. The callback is invoked
+	/// with stream parameters and PCM data played by the guest. The device
+	/// appears in the generated device tree once connected so call this method
+	/// before running the program. Returns `Err` if the device tree overlays
+	/// can't be applied to the updated device tree, keeping the current one.
+	///
+	/// # Arguments
+	/// * `callback`
+	#[cfg(feature = "virtio")]
+	pub fn setup_sound(&mut self, callback: PcmCallback) -> Result<(), LoadError> {
+		self.cpu.get_mut_mmu().get_mut_snd().set_callback(callback);
+		self.update_dtb()
+	}
+
+	/// Attaches a disk while the guest runs, replacing the current one.
+	/// Use this method to test guest hotplug handling or to swap disk images
+	/// mid-run. The guest is notified with a virtio configuration change
+	/// interrupt and sees the new capacity.
+	///
+	/// # Arguments
+	/// * `backend` File system storage
+	#[cfg(feature = "virtio")]
+	pub fn attach_disk(&mut self, backend: Box<dyn BlockBackend>) {
+		self.cpu.get_mut_mmu().get_mut_disk().attach(backend);
+	}
+
+	/// Flushes data written by the guest to the disk storage, e.g. the host
+	/// image file of [`FileBlockBackend`](block_backend/struct.FileBlockBackend.html).
+	/// The disk is also flushed when the guest requests and when `Emulator` is dropped.
+	#[cfg(feature = "virtio")]
+	pub fn flush_disk(&mut self) -> Result<(), DeviceError> {
+		self.cpu.get_mut_mmu().get_mut_disk().flush()
+	}
+
+	/// Detaches the disk while the guest runs. The virtio slot is seen as
+	/// empty afterward and requests to the disk fail until `attach_disk()`.
+	#[cfg(feature = "virtio")]
+	pub fn detach_disk(&mut self) {
+		self.cpu.get_mut_mmu().get_mut_disk().detach();
+	}
+
+	/// Sets up device tree. The emulator generates device tree from the machine
+	/// configuration by default. If you want to override it, use this method. This method is expected to
+	/// to be called up to only once. Returns `Err` if the overlays or
+	/// the initial ramdisk location can't be applied to it.
+	///
+	/// # Arguments
+	/// * `content` DTB content binary
+	pub fn setup_dtb(&mut self, content: Vec<u8>) -> Result<(), LoadError> {
+		let custom_dtb = self.custom_dtb.replace(content);
+		if let Err(error) = self.update_dtb() {
+			self.custom_dtb = custom_dtb;
+			return Err(error);
+		}
+		Ok(())
+	}
+
+	/// Adds device tree overlay merged into the base device tree, the generated
+	/// one or the one set by `setup_dtb()`. Use this method to add custom
+	/// devices or to tweak the chosen node without building a full device tree.
+	/// See [`DeviceTreeNode::apply_overlay`](device_tree/struct.DeviceTreeNode.html#method.apply_overlay)
+	/// for the supported format. Returns `Err` if the overlay is broken or
+	/// can't be applied to the current base device tree.
+	///
+	/// # Arguments
+	/// * `content` DTB overlay content binary
+	pub fn add_dtb_overlay(&mut self, content: Vec<u8>) -> Result<(), LoadError> {
+		self.dtb_overlays.push(content);
+		if let Err(error) = self.update_dtb() {
+			self.dtb_overlays.pop();
+			return Err(error);
+		}
+		Ok(())
+	}
+
+	/// Updates XLEN (the width of an integer register in bits) in CPU.
+	/// Returns `Err` if the device tree overlays can't be applied to
+	/// the updated device tree, keeping the current one.
+	///
+	/// # Arguments
+	/// * `xlen`
+	pub fn update_xlen(&mut self, xlen: Xlen) -> Result<(), LoadError> {
+		self.cpu.update_xlen(xlen);
+		self.update_dtb()
+	}
+
+	/// Enables or disables page cache optimization.
+	/// Page cache optimization is experimental feature.
+	/// See [`Mmu`](./mmu/struct.Mmu.html) for the detail.
+	///
+	/// # Arguments
+	/// * `enabled`
+	pub fn enable_page_cache(&mut self, enabled: bool) {
+		self.cpu.get_mut_mmu().enable_page_cache(enabled);
+	}
+
+	/// Drives a GPIO input pin. Use this method to simulate buttons
+	/// or any external signal connected to the guest. Returns `Err` if
+	/// the pin is out of range.
+	///
+	/// # Arguments
+	/// * `pin` Must be less than 32
+	/// * `level` `true` for high
+	pub fn set_gpio_input(&mut self, pin: u32, level: bool) -> Result<(), DeviceError> {
+		self.cpu.get_mut_mmu().get_mut_gpio().set_input(pin, level)
+	}
+
+	/// Returns the current GPIO output pin levels as bits.
+	pub fn get_gpio_output(&self) -> u32 {
+		self.cpu.get_mmu().get_gpio().get_output()
+	}
+
+	/// Registers a callback invoked with pin number and new level
+	/// whenever the guest changes a GPIO output pin, e.g. LEDs.
+	///
+	/// # Arguments
+	/// * `callback`
+	pub fn set_gpio_output_callback(&mut self, callback: Box<dyn FnMut(u32, bool)>) {
+		self.cpu.get_mut_mmu().get_mut_gpio().set_output_callback(callback);
+	}
+
+	/// Returns the duty cycles of PWM comparator outputs in the range
+	/// of 0.0 to 1.0. See [`Pwm`](device/pwm/struct.Pwm.html) for the detail.
+	pub fn get_pwm_duty_cycles(&self) -> [f64; 4] {
+		self.cpu.get_mmu().get_pwm().get_duty_cycles()
+	}
+
+	/// Returns the framebuffer pixels in a8r8g8b8 format, or `None` if
+	/// the framebuffer isn't enabled in `EmulatorConfig`. Each line is
+	/// `width * 4` bytes.
+	pub fn get_framebuffer(&self) -> Option<&[u8]> {
+		match self.config.framebuffer {
+			Some(_) => Some(self.cpu.get_mmu().get_framebuffer().get_pixels()),
+			None => None
+		}
+	}
+
+	/// Registers a callback invoked with the framebuffer pixels when
+	/// the guest has updated the framebuffer. Updates are notified at
+	/// intervals, not on every pixel write.
+	///
+	/// # Arguments
+	/// * `callback`
+	pub fn set_framebuffer_update_callback(&mut self, callback: FramebufferUpdateCallback) {
+		self.cpu.get_mut_mmu().get_mut_framebuffer().set_update_callback(callback);
+	}
+
+	/// Returns the shared memory region the guest sees at 0x40000000,
+	/// or `None` if shared memory isn't enabled in `EmulatorConfig`.
+	pub fn get_shared_memory(&self) -> Option<&[u8]> {
+		match self.config.shared_memory {
+			Some(_) => Some(self.cpu.get_mmu().get_shared_memory().get_data()),
+			None => None
+		}
+	}
+
+	/// Returns the shared memory region to write, or `None` if shared
+	/// memory isn't enabled in `EmulatorConfig`. The guest sees the data
+	/// written without copies.
+	pub fn get_mut_shared_memory(&mut self) -> Option<&mut [u8]> {
+		match self.config.shared_memory {
+			Some(_) => Some(self.cpu.get_mut_mmu().get_mut_shared_memory().get_mut_data()),
+			None => None
+		}
+	}
+
+	/// Rings the shared memory doorbell of the guest. The guest is
+	/// interrupted if it enables the vector. The doorbell needs to be
+	/// enabled in `EmulatorConfig`.
+	///
+	/// # Arguments
+	/// * `vector` 0-31
+	pub fn ring_shared_memory_doorbell(&mut self, vector: u32) {
+		self.cpu.get_mut_mmu().get_mut_shared_memory().ring(vector);
+	}
+
+	/// Registers a callback invoked with the written value and
+	/// the shared memory region when the guest rings the doorbell.
+	///
+	/// # Arguments
+	/// * `callback`
+	pub fn set_shared_memory_doorbell_callback(&mut self, callback: DoorbellCallback) {
+		self.cpu.get_mut_mmu().get_mut_shared_memory().set_doorbell_callback(callback);
+	}
+
+	/// Sets the memory balloon size, the amount of memory the host wants
+	/// the guest to give up. The size is rounded down to 4 KiB pages.
+	/// The guest inflates or deflates the balloon toward the size, and host
+	/// memory of the pages in the balloon is released. The balloon device
+	/// needs to be enabled with `EmulatorConfig::balloon`.
+	///
+	/// # Arguments
+	/// * `size` In bytes
+	#[cfg(feature = "virtio")]
+	pub fn set_balloon_size(&mut self, size: u64) {
+		let num_pages = (size / BALLOON_PAGE_SIZE).min(u32::MAX as u64) as u32;
+		self.cpu.get_mut_mmu().get_mut_balloon().set_num_pages(num_pages);
+	}
+
+	/// Returns the memory balloon size the guest reports in bytes.
+	#[cfg(feature = "virtio")]
+	pub fn get_balloon_size(&self) -> u64 {
+		self.cpu.get_mmu().get_balloon().get_actual_pages() as u64 * BALLOON_PAGE_SIZE
+	}
+
+	/// Returns the size of host memory allocated for main memory in bytes.
+	/// The pages the guest has never written non-zero data to or has put
+	/// in the balloon aren't allocated.
+	pub fn get_memory_allocated_size(&self) -> u64 {
+		self.cpu.get_mmu().get_memory_allocated_size()
+	}
+
+	/// Asserts an interrupt line of the interrupt controller, `Plic`
+	/// or `Aplic`, from host code. The line stays asserted until
+	/// `lower_irq()` is called, as a "Level-triggered" interrupt.
+	/// This lets a device modeled outside of the emulator, described to
+	/// the guest with a device tree overlay, interrupt the guest driver.
+	/// Returns `Err` if `line` is not 1-63 or is used by a built-in device.
+	///
+	/// # Arguments
+	/// * `line` Interrupt source number
+	pub fn raise_irq(&mut self, line: u32) -> Result<(), DeviceError> {
+		self.cpu.get_mut_mmu().set_host_irq_level(line, true)
+	}
+
+	/// Deasserts an interrupt line asserted with `raise_irq()`.
+	/// Returns `Err` if `line` is not 1-63 or is used by a built-in device.
+	///
+	/// # Arguments
+	/// * `line` Interrupt source number
+	pub fn lower_irq(&mut self, line: u32) -> Result<(), DeviceError> {
+		self.cpu.get_mut_mmu().set_host_irq_level(line, false)
+	}
+
+	/// Injects a message-signaled interrupt from host code. `data` is
+	/// written to the physical address `address` in the next `tick()`,
+	/// typically to the seteipnum register of an `Imsic` interrupt file
+	/// with `InterruptControllerType::Aia`. Messages to unmapped addresses
+	/// are dropped.
+	///
+	/// # Arguments
+	/// * `address` Physical address the message is written to
+	/// * `data` Interrupt identity
+	pub fn send_msi(&mut self, address: u64, data: u32) {
+		self.cpu.get_mut_mmu().send_msi(Msi::new(address, data));
+	}
+
+	/// Returns mutable reference to `Terminal`.
+	pub fn get_mut_terminal(&mut self) -> &mut Box<dyn Terminal> {
+		self.cpu.get_mut_terminal()
+	}
+
+	/// Wraps the console terminal in `KernelLogTerminal` and returns
+	/// the handle of the lines the guest prints from now, e.g. the kernel
+	/// log, for harnesses asserting on them. The output still reaches
+	/// the terminal, so call this after setting up the terminal.
+	///
+	/// ```ignore
+	/// let kernel_log = emulator.capture_kernel_log();
+	/// // Run the emulator
+	/// assert!(kernel_log.borrow().contains("virtio_blk virtio0"));
+	/// ```
+	pub fn capture_kernel_log(&mut self) -> Rc<RefCell<KernelLog>> {
+		let terminal = core::mem::replace(self.get_mut_terminal(), Box::new(DummyTerminal::new()));
+		let terminal = KernelLogTerminal::new(terminal);
+		let kernel_log = terminal.get_kernel_log();
+		*self.get_mut_terminal() = Box::new(terminal);
+		kernel_log
+	}
+
+	/// Connects a serial port besides the console, added with
+	/// `EmulatorConfig::serial_port_num`, to `Terminal`. Serial ports are
+	/// connected to no terminal by default. Returns `Err` if the port
+	/// doesn't exist. Use [`TerminalMux`](terminal_mux/struct.TerminalMux.html)
+	/// to share a terminal with the console.
+	///
+	/// # Arguments
+	/// * `index` Serial port number starting with zero
+	/// * `terminal`
+	pub fn set_serial_port_terminal(&mut self, index: usize, terminal: Box<dyn Terminal>) -> Result<(), DeviceError> {
+		match self.get_mut_serial_port_terminal(index) {
+			Some(serial_port_terminal) => {
+				*serial_port_terminal = terminal;
+				Ok(())
+			},
+			None => Err(DeviceError::NoSerialPort(index))
+		}
+	}
+
+	/// Returns mutable reference to `Terminal` of a serial port besides
+	/// the console. Returns `None` if the port doesn't exist.
+	///
+	/// # Arguments
+	/// * `index` Serial port number starting with zero
+	pub fn get_mut_serial_port_terminal(&mut self, index: usize) -> Option<&mut Box<dyn Terminal>> {
+		self.cpu.get_mut_mmu().get_mut_serial_port(index).map(|uart| uart.get_mut_terminal())
+	}
+
+	/// Connects the virtio console device, added with
+	/// `EmulatorConfig::virtio_console`, to `Terminal`. It is connected to
+	/// no terminal by default.
+	///
+	/// # Arguments
+	/// * `terminal`
+	#[cfg(feature = "virtio")]
+	pub fn set_virtio_console_terminal(&mut self, terminal: Box<dyn Terminal>) {
+		*self.cpu.get_mut_mmu().get_mut_virtio_console().get_mut_terminal() = terminal;
+	}
+
+	/// Tells the guest the window size of the virtio console, for embedders
+	/// whose terminal doesn't report it with `Terminal::get_window_size()`,
+	/// e.g. a GUI widget. The guest is notified if the size changes.
+	///
+	/// # Arguments
+	/// * `cols`
+	/// * `rows`
+	#[cfg(feature = "virtio")]
+	pub fn set_virtio_console_window_size(&mut self, cols: u16, rows: u16) {
+		self.cpu.get_mut_mmu().get_mut_virtio_console().set_window_size(cols, rows);
+	}
+
+	/// Returns immutable reference to `Cpu`.
+	pub fn get_cpu(&self) -> &Cpu {
+		&self.cpu
+	}
+
+	/// Returns mutable reference to `Cpu`.
+	pub fn get_mut_cpu(&mut self) -> &mut Cpu {
+		&mut self.cpu
+	}
+
+	/// Walks the guest call stack at the current point and returns
+	/// the frames, the innermost first, with the names in the symbols
+	/// loaded so far. See `unwind::unwind_stack()` for how the frames are
+	/// found.
+	///
+	/// # Arguments
+	/// * `max_frames` Including the innermost one
+	pub fn unwind_stack(&mut self, max_frames: usize) -> Vec<StackFrame> {
+		let frames = unwind::unwind_stack(&mut self.cpu, max_frames);
+		let symbol_table = SymbolTable::new(&self.symbol_map);
+		frames.into_iter().map(|(address, method)| {
+			let (function, offset) = match symbol_table.lookup(address) {
+				Some((name, offset)) => (Some(name.to_string()), offset),
+				None => (None, 0)
+			};
+			StackFrame {
+				address,
+				function,
+				offset,
+				method
+			}
+		}).collect()
+	}
+
+	/// Returns a virtual address corresponding to symbol strings
+	///
+	/// # Arguments
+	/// * `s` Symbol strings
+	pub fn get_addredd_of_symbol(&self, s: &String) -> Option<u64> {
+		match self.symbol_map.get(s) {
+			Some(address) => Some(*address),
+			None => None
+		}
+	}
+}
+
+#[cfg(test)]
+mod test_emulator {
+	use terminal::DummyTerminal;
+	use capture_terminal::CaptureTerminal;
+	use super::*;
+	use fuzz_harness::{FuzzHarness, FuzzOutcome};
+	use mmu::DRAM_BASE;
+
+	fn create_emu() -> Emulator {
+		Emulator::new(
+			Box::new(DummyTerminal::new())
+		)
+	}
+
+	#[test]
+	fn initialize() {
+		let _emu = create_emu();
+	}
+
+	#[test]
+	#[ignore]
+	fn run() {
+	}
+
+	#[test]
+	#[ignore]
+	fn run_program() {
+	}
+
+	#[test]
+	#[ignore]
+	fn run_test() {
+	}
+
+	#[test]
+	#[ignore]
+	fn tick() {
+	}
+
+	#[test]
+	fn setup_program() {
+		let mut emu = Emulator::new_with_config(Box::new(CaptureTerminal::new()), EmulatorConfig::default());
+		assert_eq!(Err(LoadError::InvalidElf("no ELF magic number")), emu.setup_program(b"#!/bin/sh".to_vec()));
+	}
+
+	#[test]
+	#[ignore]
+	fn load_program_for_symbols() {
+	}
+
+	#[test]
+	fn get_pwm_duty_cycles() {
+		use device::pwm::PWM_BASE;
+		let mut emu = create_emu();
+		let mmu = emu.get_mut_cpu().get_mut_mmu();
+		// pwmzerocmp with a period of 100 cycles
+		assert!(mmu.store_word(PWM_BASE, 1 << 9).is_ok());
+		for (i, cmp) in [99, 75, 50, 0].iter().enumerate() {
+			assert!(mmu.store_word(PWM_BASE + 0x20 + i as u64 * 4, *cmp).is_ok());
+		}
+		assert_eq!([0.01, 0.25, 0.5, 1.0], emu.get_pwm_duty_cycles());
+	}
+
+	// addi rd, rs1, imm
+	fn addi(rd: u32, rs1: u32, imm: u32) -> u32 {
+		(imm << 20) | (rs1 << 15) | (rd << 7) | 0x13
+	}
+
+	const VADDR: u64 = 0x10000;
+	const CODE_OFFSET: u32 = 0x78; // ELF header and a program header
+	const ECALL: u32 = 0x73;
+
+	// Static Linux program writing "Hi\n" and exiting with 7
+	fn create_user_program() -> Vec<u8> {
+		let code = [
+			addi(17, 0, 64), // a7 = write
+			addi(10, 0, 1), // a0 = stdout
+			(0x10 << 12) | (11 << 7) | 0x37, // lui a1, 0x10
+			addi(11, 11, CODE_OFFSET + 4 * 9), // a1 = message
+			addi(12, 0, 3), // a2 = length
+			ECALL,
+			addi(17, 0, 93), // a7 = exit
+			addi(10, 0, 7), // a0 = status code
+			ECALL
+		];
+		let mut program = vec![];
+		for word in code.iter() {
+			program.extend_from_slice(&word.to_le_bytes());
+		}
+		program.extend_from_slice(b"Hi\n");
+		let size = CODE_OFFSET as u64 + program.len() as u64;
+
+		let mut data = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+		data.extend_from_slice(&2u16.to_le_bytes()); // e_type
+		data.extend_from_slice(&0xf3u16.to_le_bytes()); // e_machine
+		data.extend_from_slice(&1u32.to_le_bytes()); // e_version
+		data.extend_from_slice(&(VADDR + CODE_OFFSET as u64).to_le_bytes()); // e_entry
+		data.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+		data.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+		data.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+		for half in [64u16, 56, 1, 64, 0, 0].iter() {
+			data.extend_from_slice(&half.to_le_bytes());
+		}
+		data.extend_from_slice(&1u32.to_le_bytes()); // p_type
+		data.extend_from_slice(&5u32.to_le_bytes()); // p_flags
+		for word in [0, VADDR, VADDR, size, size, 0x1000u64].iter() {
+			data.extend_from_slice(&word.to_le_bytes());
+		}
+		data.extend_from_slice(&program);
+		data
+	}
+
+	fn create_user_emu(terminal: Box<dyn Terminal>) -> Emulator {
+		let config = EmulatorConfig {
+			memory_capacity: 16 * 1024 * 1024,
+			..EmulatorConfig::default()
+		};
+		let mut emu = Emulator::new_with_config(terminal, config);
+		assert_eq!(Ok(()), emu.setup_linux_user_program(create_user_program(), vec!["hello".to_string()]));
+		emu
+	}
+
+	#[test]
+	fn setup_linux_user_program() {
+		let terminal = CaptureTerminal::new();
+		let output = terminal.get_captured_output();
+		let mut emu = create_user_emu(Box::new(terminal));
+		assert_eq!(Ok(()), emu.run());
+		assert_eq!(Some(7), emu.get_exit_code());
+		assert_eq!(b"Hi\n".to_vec(), *output.borrow());
+	}
+
+	#[test]
+	fn capture_kernel_log() {
+		let terminal = CaptureTerminal::new();
+		let output = terminal.get_captured_output();
+		let mut emu = create_user_emu(Box::new(terminal));
+		let kernel_log = emu.capture_kernel_log();
+		assert_eq!(Ok(()), emu.run());
+		assert_eq!(&["Hi".to_string()], kernel_log.borrow().get_lines());
+		assert!(kernel_log.borrow().contains("Hi"));
+		// The output still reaches the terminal
+		assert_eq!(b"Hi\n".to_vec(), *output.borrow());
+		assert_eq!(vec!["Hi".to_string()], kernel_log.borrow_mut().take_lines());
+		assert!(kernel_log.borrow().get_lines().is_empty());
+	}
+
+	#[test]
+	fn breakpoint() {
+		let terminal = CaptureTerminal::new();
+		let output = terminal.get_captured_output();
+		let mut emu = create_user_emu(Box::new(terminal));
+		// The write system call and the exit one
+		let write_address = VADDR + CODE_OFFSET as u64 + 4 * 5;
+		let exit_address = VADDR + CODE_OFFSET as u64 + 4 * 8;
+		emu.add_breakpoint(write_address);
+		emu.add_breakpoint(exit_address);
+		assert_eq!(StopReason::Breakpoint(write_address), emu.run_program());
+		assert!(output.borrow().is_empty());
+		assert_eq!(StopReason::Breakpoint(exit_address), emu.run_program());
+		assert_eq!(b"Hi\n".to_vec(), *output.borrow());
+		assert_eq!(Ok(()), emu.remove_breakpoint(exit_address));
+		assert_eq!(Err(DebugError::NoBreakpoint(exit_address)), emu.remove_breakpoint(exit_address));
+		assert_eq!(StopReason::Exited(7), emu.run_program());
+	}
+
+	#[test]
+	fn registers() {
+		let mut emu = create_emu();
+		assert_eq!(Ok(()), emu.set_register("a0", 0x1234));
+		assert_eq!(Ok(0x1234), emu.get_register("x10"));
+		assert_eq!(Ok(()), emu.set_register("x8", 5));
+		assert_eq!(Ok(5), emu.get_register("fp"));
+		assert_eq!(Ok(()), emu.set_register("zero", 1));
+		assert_eq!(Ok(0), emu.get_register("x0"));
+		assert_eq!(Ok(()), emu.set_register("pc", 0x80000000));
+		assert_eq!(Ok(0x80000000), emu.get_register("pc"));
+		assert_eq!(Err(DebugError::UnknownRegister("x32".to_string())), emu.get_register("x32"));
+		assert!(emu.get_register("x01").is_err());
+		assert!(emu.set_register("fa0", 0).is_err());
+
+		assert_eq!(Ok(()), emu.set_f_register("fa0", 1.5f64.to_bits()));
+		assert_eq!(Ok(1.5f64.to_bits()), emu.get_f_register("f10"));
+		assert_eq!(Ok(0), emu.get_f_register("ft11"));
+		assert_eq!(Err(DebugError::UnknownRegister("a0".to_string())), emu.get_f_register("a0"));
+	}
+
+	#[test]
+	fn fuzz_harness() {
+		let terminal = CaptureTerminal::new();
+		let output = terminal.get_captured_output();
+		let mut emu = create_user_emu(Box::new(terminal));
+		let write_address = VADDR + CODE_OFFSET as u64 + 4 * 5;
+		let exit_address = VADDR + CODE_OFFSET as u64 + 4 * 8;
+		let message_address = VADDR + CODE_OFFSET as u64 + 4 * 9;
+		let mut harness = match FuzzHarness::new(&mut emu, write_address, message_address, 2) {
+			Ok(harness) => harness,
+			Err(error) => panic!("{}", error)
+		};
+		harness.add_exit_address(exit_address);
+
+		// The rest of the message is restored every run
+		for (input, expected) in [(&b"Q"[..], &b"Qi\n"[..]), (b"XYZ", b"XY\n"), (b"Z", b"Zi\n")].iter() {
+			output.borrow_mut().clear();
+			let report = match harness.run(&mut emu, input) {
+				Ok(report) => report,
+				Err(error) => panic!("{}", error)
+			};
+			assert_eq!(FuzzOutcome::Exited(exit_address), report.outcome);
+			assert_eq!(3, report.instructions);
+			assert_eq!(vec![(write_address, 4), (write_address + 4, 8)], report.blocks);
+			assert_eq!(match *input == b"Q" { true => 2, false => 0 }, report.new_blocks);
+			assert_eq!(expected.to_vec(), *output.borrow());
+		}
+
+		harness.add_crash_address(write_address + 4);
+		let report = harness.run(&mut emu, b"").ok();
+		assert_eq!(Some(FuzzOutcome::CrashAddress(write_address + 4)), report.map(|report| report.outcome));
+	}
+
+	#[test]
+	fn checkpoints() {
+		let terminal = CaptureTerminal::new();
+		let output = terminal.get_captured_output();
+		let mut emu = create_user_emu(Box::new(terminal));
+		let write_address = VADDR + CODE_OFFSET as u64 + 4 * 5;
+		assert_eq!(Err(DebugError::NoCheckpoints), emu.rollback_to(0));
+		emu.set_checkpoints(Checkpoints::new(2, 3));
+		assert_eq!(StopReason::Exited(7), emu.run_program());
+		let checkpoints = emu.get_mut_checkpoints().map(|checkpoints| checkpoints.get_checkpoints());
+		assert_eq!(Some(vec![(2, 4), (3, 6), (4, 8)]), checkpoints.map(|checkpoints| {
+			checkpoints.iter().map(|checkpoint| (checkpoint.id, checkpoint.instructions)).collect()
+		}));
+
+		// Replays the write system call
+		assert_eq!(Err(DebugError::NoCheckpoint), emu.rollback_to(0));
+		assert_eq!(Ok(()), emu.rollback_to(2));
+		assert_eq!(None, emu.get_exit_code());
+		assert_eq!(write_address - 4, emu.get_cpu().read_pc());
+		assert_eq!(StopReason::Exited(7), emu.run_program());
+		assert_eq!(b"Hi\nHi\n".to_vec(), *output.borrow());
+		let checkpoints = match emu.take_checkpoints() {
+			Some(checkpoints) => checkpoints,
+			None => panic!("Checkpoints are gone")
+		};
+		assert_eq!(vec![2, 5, 6], checkpoints.get_checkpoints().iter().map(|checkpoint| checkpoint.id).collect::<Vec<usize>>());
+		assert_eq!(Some(5), checkpoints.find_before(7).map(|checkpoint| checkpoint.id));
+	}
+
+	#[test]
+	#[cfg(feature = "serde")]
+	fn serialize_snapshot() {
+		let terminal = CaptureTerminal::new();
+		let output = terminal.get_captured_output();
+		let mut emu = create_user_emu(Box::new(terminal));
+		let snapshot = emu.take_snapshot();
+		assert_eq!(StopReason::Exited(7), emu.run_program());
+
+		// Restores the whole memory though the snapshot is the last one taken
+		let json = serde_json::to_string(&snapshot).unwrap();
+		let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+		emu.restore_snapshot(&snapshot);
+		assert_eq!(None, emu.get_exit_code());
+		assert_eq!(StopReason::Exited(7), emu.run_program());
+		assert_eq!(b"Hi\nHi\n".to_vec(), *output.borrow());
+
+		let json = json.replacen("\"version\":1", "\"version\":2", 1);
+		assert!(serde_json::from_str::<Snapshot>(&json).is_err());
+	}
+
+	#[test]
+	fn time_travel() {
+		let mut emu = create_emu();
+		let cpu = emu.get_mut_cpu();
+		cpu.get_mut_mmu().init_memory(0x100);
+		let code = [
+			0x00150513, // loop: addi a0, a0, 1
+			0x08a4b023, // sd a0, 0x80(s1)
+			0x00158593, // addi a1, a1, 1
+			0xff5ff06f // jal zero, loop
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		cpu.write_register(9, DRAM_BASE as i64);
+		cpu.write_register(10, 0);
+		cpu.update_pc(DRAM_BASE);
+		assert_eq!(Err(DebugError::NoCheckpoints), emu.goto_instruction(0));
+		emu.set_checkpoints(Checkpoints::new(3, 4));
+		for _ in 0..20 {
+			emu.tick();
+		}
+
+		// The fifth store. The watchpoint the user has added for the range
+		// is kept
+		emu.watch_write(DRAM_BASE + 0x84, 4);
+		assert_eq!(Ok(17), emu.goto_last_write(DRAM_BASE + 0x84, 4));
+		assert!(emu.get_cpu().get_mmu().has_watchpoint(WatchpointType::Write, DRAM_BASE + 0x84, 4));
+		assert_eq!(Ok(()), emu.remove_watchpoint(WatchpointType::Write, DRAM_BASE + 0x84, 4));
+		assert_eq!(DRAM_BASE + 4, emu.get_cpu().read_pc());
+		assert_eq!((5, Some(4)), (emu.get_cpu().read_register(10), emu.get_mut_cpu().get_mut_mmu().peek(DRAM_BASE + 0x80, 8)));
+		assert_eq!(Ok(()), emu.goto_instruction(10));
+		assert_eq!(3, emu.get_cpu().read_register(10));
+		assert_eq!(Ok(()), emu.goto_instruction(20));
+		assert_eq!((5, Some(5)), (emu.get_cpu().read_register(10), emu.get_mut_cpu().get_mut_mmu().peek(DRAM_BASE + 0x80, 8)));
+
+		assert_eq!(Err(DebugError::NoWrite {
+			address: DRAM_BASE + 0x90,
+			length: 8
+		}), emu.goto_last_write(DRAM_BASE + 0x90, 8));
+		assert_eq!(Some(20), emu.get_mut_checkpoints().map(|checkpoints| checkpoints.get_instructions()));
+		assert_eq!(5, emu.get_cpu().read_register(10));
+		// Before the oldest checkpoint kept
+		assert_eq!(Err(DebugError::NoCheckpoint), emu.goto_instruction(5));
+	}
+
+	#[test]
+	fn raise_irq() {
+		const PLIC_PENDING: u64 = 0x0c001000;
+		let mut emu = create_emu();
+		assert_eq!(Err(DeviceError::UnavailableIrq(0)), emu.raise_irq(0));
+		assert_eq!(Err(DeviceError::UnavailableIrq(64)), emu.raise_irq(64));
+		assert_eq!(Err(DeviceError::UnavailableIrq(1)), emu.raise_irq(1)); // VirtIO block disk
+		assert_eq!(Ok(()), emu.raise_irq(20));
+		emu.get_mut_cpu().get_mut_mmu().tick(&mut 0);
+		let pending = emu.get_mut_cpu().get_mut_mmu().load_word_raw(PLIC_PENDING);
+		assert_eq!(1 << 20, pending & (1 << 20));
+		assert_eq!(Ok(()), emu.lower_irq(20));
+		emu.get_mut_cpu().get_mut_mmu().tick(&mut 0);
+	}
+
+	#[test]
+	#[ignore]
+	fn setup_filesystem() {
+	}
+
+	#[test]
+	fn setup_dtb() {
+		const DTB_ADDRESS: u64 = 0x1020;
+		let mut emu = create_emu();
+		let capacity = emu.get_memory_capacity();
+		emu.get_mut_cpu().get_mut_mmu().init_memory(capacity);
+		// The initial ramdisk location can't be set in a broken device tree
+		assert_eq!(Ok(()), emu.setup_dtb(vec![1, 2, 3]));
+		assert!(emu.setup_initrd(vec![1; 16]).is_err());
+		assert!(emu.initrd.is_none());
+		assert_eq!(0x030201, emu.get_mut_cpu().get_mut_mmu().load_word_raw(DTB_ADDRESS));
+
+		// and a broken device tree doesn't replace the one telling it
+		let mut emu = create_emu();
+		emu.get_mut_cpu().get_mut_mmu().init_memory(capacity);
+		assert_eq!(Ok(()), emu.setup_initrd(vec![1; 16]));
+		assert!(emu.setup_dtb(vec![1, 2, 3]).is_err());
+		assert!(emu.custom_dtb.is_none());
+		assert_eq!(0xedfe0dd0, emu.get_mut_cpu().get_mut_mmu().load_word_raw(DTB_ADDRESS));
 	}
 
 	#[test]
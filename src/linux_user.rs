@@ -0,0 +1,567 @@
+extern crate rand;
+
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use self::rand::Rng;
+use clock::Clock;
+use cpu::{Cpu, Xlen};
+use elf_analyzer::{ElfAnalyzer, Header};
+use error::{LoadError, MemoryError};
+use mmu::DRAM_BASE;
+use random::create_rng;
+use syscall_proxy::{SyscallProxy, EINVAL, ENOMEM, ENOTTY};
+
+// Based on qemu-user and Linux kernel RISC-V port
+// https://www.qemu.org/docs/master/user/main.html
+
+// System call numbers of RISC-V Linux ABI. The others are
+// handled by `SyscallProxy`.
+const SYS_IOCTL: u64 = 29;
+const SYS_FACCESSAT: u64 = 48;
+const SYS_OPENAT: u64 = 56;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_FUTEX: u64 = 98;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_SCHED_YIELD: u64 = 124;
+const SYS_KILL: u64 = 129;
+const SYS_TKILL: u64 = 130;
+const SYS_TGKILL: u64 = 131;
+const SYS_SIGALTSTACK: u64 = 132;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MREMAP: u64 = 216;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MADVISE: u64 = 233;
+const SYS_GETRANDOM: u64 = 278;
+const SYS_CLOCK_GETTIME64: u64 = 403;
+
+const CLOCK_REALTIME: u64 = 0;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+// Exception codes in mcause
+const CAUSE_INSTRUCTION_ADDRESS_MISALIGNED: u64 = 0;
+const CAUSE_ILLEGAL_INSTRUCTION: u64 = 2;
+const CAUSE_BREAKPOINT: u64 = 3;
+const CAUSE_LOAD_ADDRESS_MISALIGNED: u64 = 4;
+const CAUSE_STORE_ADDRESS_MISALIGNED: u64 = 6;
+const CAUSE_ENVIRONMENT_CALL_FROM_U_MODE: u64 = 8;
+
+const SIGILL: u64 = 4;
+const SIGTRAP: u64 = 5;
+const SIGBUS: u64 = 7;
+const SIGSEGV: u64 = 11;
+
+// Auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_RANDOM: u64 = 25;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+
+/// Bits of the extension letters, IMAFDC
+const HWCAP: u64 = 0x112d;
+
+const PAGE_SIZE: u64 = 4096;
+const STACK_SIZE: u64 = 8 * 1024 * 1024;
+const PATH_MAX: u64 = 4096;
+
+/// The process and thread id the program sees
+const PID: i64 = 1;
+
+/// Runs a static RISC-V Linux program in User mode without kernel like
+/// qemu-user. The program's system calls trap into Machine mode and are
+/// emulated by the host. Files are proxied to the host file system by
+/// [`SyscallProxy`](../syscall_proxy/struct.SyscallProxy.html).
+///
+/// The user address space starts from zero and is mapped to main memory
+/// as is with superpages. The program, heap, memory mapped by `mmap()`,
+/// and stack share it without memory protection. The top superpage of
+/// main memory holds the page table and is not mapped.
+pub struct LinuxUser {
+	xlen: Xlen,
+	/// The size of user address space
+	memory_size: u64,
+	/// The initial program break, the end of the program data
+	brk_start: u64,
+	/// The current program break
+	brk: u64,
+	/// The lowest address mapped by `mmap()` so far. Memory is
+	/// mapped downward from the stack.
+	mmap_bottom: u64,
+	/// Handles system calls accessing files. Target addresses are virtual.
+	proxy: SyscallProxy,
+	/// Host time source of the clocks
+	clock: Rc<dyn Clock>,
+	/// Base of monotonic clock
+	start_time: Duration,
+	/// Status code the program exited with
+	exit_code: Option<u64>
+}
+
+impl LinuxUser {
+	/// Creates a new `LinuxUser`.
+	///
+	/// # Arguments
+	/// * `xlen`
+	/// * `memory_capacity` Main memory capacity
+	/// * `clock` Host time source the clock system calls read
+	pub fn new(xlen: Xlen, memory_capacity: u64, clock: Rc<dyn Clock>) -> Self {
+		let superpage_size = get_superpage_size(&xlen);
+		let memory_size = match xlen {
+			// A Sv39 second-level page table covers up to 1GiB
+			Xlen::Bit64 => (memory_capacity.min(1 << 30) / superpage_size).saturating_sub(1) * superpage_size,
+			Xlen::Bit32 => (memory_capacity / superpage_size).saturating_sub(1) * superpage_size
+		};
+		LinuxUser {
+			xlen,
+			memory_size,
+			brk_start: 0,
+			brk: 0,
+			mmap_bottom: 0,
+			proxy: SyscallProxy::new(DRAM_BASE, memory_size),
+			start_time: clock.now(),
+			clock,
+			exit_code: None
+		}
+	}
+
+	/// Returns the status code if the program has exited. It's 128 plus
+	/// the signal number if the program has been terminated by a signal.
+	pub fn get_exit_code(&self) -> Option<u64> {
+		self.exit_code
+	}
+
+	/// Overwrites the exit status, e.g. to run the program again
+	/// from a snapshot.
+	///
+	/// # Arguments
+	/// * `exit_code` `None` for running
+	pub fn set_exit_code(&mut self, exit_code: Option<u64>) {
+		self.exit_code = exit_code;
+	}
+
+	/// Replaces the host time source. The monotonic clock the program
+	/// sees keeps counting from where it is.
+	///
+	/// # Arguments
+	/// * `clock`
+	pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+		let elapsed = self.clock.now().saturating_sub(self.start_time);
+		self.start_time = clock.now().saturating_sub(elapsed);
+		self.clock = clock;
+	}
+
+	/// Loads the program, sets up the page table and the initial stack,
+	/// and makes `Cpu` enter User mode at the entry point. Main memory
+	/// must have been initialized. Returns `Err` if the program is broken
+	/// or doesn't fit in main memory.
+	///
+	/// # Arguments
+	/// * `cpu`
+	/// * `analyzer` Program
+	/// * `header` ELF header of the program
+	/// * `args` Arguments passed to the program, starting with the program name
+	pub fn load_program(&mut self, cpu: &mut Cpu, analyzer: &ElfAnalyzer, header: &Header, args: &[String]) -> Result<(), LoadError> {
+		if self.memory_size <= STACK_SIZE {
+			return Err(LoadError::Program(MemoryError::OutOfRange {
+				address: DRAM_BASE,
+				size: STACK_SIZE
+			}));
+		}
+		let program_headers = analyzer.read_program_headers(header)?;
+		let mut program_end = 0;
+		for program_header in program_headers.iter().filter(|program_header| program_header.p_type == PT_LOAD) {
+			let end = match program_header.p_vaddr.checked_add(program_header.p_memsz) {
+				Some(end) if end <= self.memory_size && program_header.p_filesz <= program_header.p_memsz => end,
+				_ => return Err(LoadError::Program(MemoryError::OutOfRange {
+					address: program_header.p_vaddr,
+					size: program_header.p_memsz
+				}))
+			};
+			let data = analyzer.read_bytes(program_header.p_offset, program_header.p_filesz)?;
+			cpu.get_mut_mmu().write_main_memory(DRAM_BASE + program_header.p_vaddr, data).map_err(LoadError::Program)?;
+			program_end = program_end.max(end);
+		}
+		self.brk_start = align_up(program_end, PAGE_SIZE);
+		self.brk = self.brk_start;
+		self.mmap_bottom = self.memory_size - STACK_SIZE;
+
+		// Program headers are usually in the first loaded segment
+		let phdr = match program_headers.iter().find(|program_header| program_header.p_type == PT_PHDR) {
+			Some(program_header) => program_header.p_vaddr,
+			None => match program_headers.iter().find(|program_header| program_header.p_type == PT_LOAD &&
+				program_header.p_offset <= header.e_phoff &&
+				header.e_phoff < program_header.p_offset + program_header.p_filesz) {
+				Some(program_header) => program_header.p_vaddr + header.e_phoff - program_header.p_offset,
+				None => 0
+			}
+		};
+		let auxv = [
+			(AT_PHDR, phdr),
+			(AT_PHENT, header.e_phentsize as u64),
+			(AT_PHNUM, header.e_phnum as u64),
+			(AT_PAGESZ, PAGE_SIZE),
+			(AT_ENTRY, header.e_entry),
+			(AT_UID, 0),
+			(AT_EUID, 0),
+			(AT_GID, 0),
+			(AT_EGID, 0),
+			(AT_HWCAP, HWCAP),
+			(AT_CLKTCK, 100)
+		];
+		let sp = self.setup_stack(cpu, args, &auxv);
+
+		cpu.update_pc(header.e_entry);
+		cpu.write_register(2, sp as i64);
+		// a0 is a function registered with atexit() by dynamic linker
+		cpu.write_register(10, 0);
+		cpu.write_register(11, 0);
+		let satp = self.setup_page_table(cpu);
+		cpu.enter_user_mode(satp);
+		Ok(())
+	}
+
+	/// Maps user address space to main memory with superpages
+	/// and returns `satp` CSR value.
+	fn setup_page_table(&self, cpu: &mut Cpu) -> u64 {
+		let superpage_size = get_superpage_size(&self.xlen);
+		let root = DRAM_BASE + self.memory_size;
+		// V, R, W, X, U, A, and D bits
+		let flags = 0xdf;
+		let mmu = cpu.get_mut_mmu();
+		match self.xlen {
+			Xlen::Bit32 => {
+				for i in 0..(self.memory_size / superpage_size) {
+					let pte = (((DRAM_BASE + i * superpage_size) >> 12) << 10) | flags;
+					for j in 0..4 {
+						mmu.store_raw(root + i * 4 + j, (pte >> (j * 8)) as u8);
+					}
+				}
+				(1 << 31) | (root >> 12)
+			},
+			Xlen::Bit64 => {
+				let table = root + PAGE_SIZE;
+				mmu.store_doubleword_raw(root, ((table >> 12) << 10) | 1);
+				for i in 0..(self.memory_size / superpage_size) {
+					let pte = (((DRAM_BASE + i * superpage_size) >> 12) << 10) | flags;
+					mmu.store_doubleword_raw(table + i * 8, pte);
+				}
+				(8 << 60) | (root >> 12)
+			}
+		}
+	}
+
+	/// Places the arguments, the auxiliary vector, and random bytes on
+	/// the stack as Linux does and returns the stack pointer.
+	fn setup_stack(&self, cpu: &mut Cpu, args: &[String], auxv: &[(u64, u64)]) -> u64 {
+		let mut sp = self.memory_size;
+		let mut arg_addresses = vec![];
+		for arg in args.iter() {
+			let mut data = arg.as_bytes().to_vec();
+			data.push(0);
+			sp -= data.len() as u64;
+			self.write_memory(cpu, sp, &data);
+			arg_addresses.push(sp);
+		}
+		let mut random = [0; 16];
+		create_rng().fill(&mut random);
+		sp -= random.len() as u64;
+		self.write_memory(cpu, sp, &random);
+		let random_address = sp;
+
+		// argc, argv[], NULL, empty envp[], and auxv[]
+		let mut words = vec![args.len() as u64];
+		words.extend_from_slice(&arg_addresses);
+		words.push(0);
+		words.push(0);
+		for (entry_type, value) in auxv.iter() {
+			words.push(*entry_type);
+			words.push(*value);
+		}
+		words.extend_from_slice(&[AT_RANDOM, random_address, AT_NULL, 0]);
+		sp = (sp - words.len() as u64 * self.get_word_size()) & !0xf;
+		self.write_memory(cpu, sp, &self.encode_words(&words));
+		sp
+	}
+
+	/// Runs one cycle. Handles a trap taken from User mode if any. A system
+	/// call waiting for input is retried in later cycles.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn tick(&mut self, cpu: &mut Cpu) {
+		if self.exit_code.is_some() {
+			return;
+		}
+		let (cause, epc) = match cpu.get_user_mode_trap() {
+			Some(trap) => trap,
+			None => return
+		};
+		let signal = match cause {
+			CAUSE_ENVIRONMENT_CALL_FROM_U_MODE => {
+				let mask = match self.xlen {
+					Xlen::Bit32 => 0xffffffff,
+					Xlen::Bit64 => 0xffffffffffffffff
+				};
+				let number = cpu.read_register(17) as u64 & mask;
+				let args = (10..16).map(|reg| cpu.read_register(reg) as u64 & mask).collect::<Vec<u64>>();
+				match self.handle_syscall(cpu, number, &args) {
+					Some(result) => {
+						cpu.write_register(10, result);
+						cpu.resume_user_mode(epc.wrapping_add(4));
+					},
+					None => cpu.resume_user_mode(epc)
+				};
+				return;
+			},
+			CAUSE_ILLEGAL_INSTRUCTION => SIGILL,
+			CAUSE_BREAKPOINT => SIGTRAP,
+			CAUSE_INSTRUCTION_ADDRESS_MISALIGNED |
+			CAUSE_LOAD_ADDRESS_MISALIGNED |
+			CAUSE_STORE_ADDRESS_MISALIGNED => SIGBUS,
+			_ => SIGSEGV
+		};
+		let message = format!("Terminated by signal {} (mcause {:X}, pc {:X})\n", signal, cause, epc);
+		cpu.get_mut_terminal().put_bytes(message.as_bytes());
+		self.exit_code = Some(128 + signal);
+	}
+
+	/// Handles a system call. Returns `None` if it waits for input.
+	fn handle_syscall(&mut self, cpu: &mut Cpu, number: u64, args: &[u64]) -> Option<i64> {
+		let result = match number {
+			SYS_OPENAT => match self.proxy.read_string(cpu.get_mut_mmu(), args[1], PATH_MAX) {
+				Ok(path) => self.proxy.open(args[0], &path, args[2]),
+				Err(error) => error
+			},
+			SYS_NEWFSTATAT => match self.proxy.read_string(cpu.get_mut_mmu(), args[1], PATH_MAX) {
+				Ok(path) => self.proxy.stat(cpu.get_mut_mmu(), args[0], &path, args[2]),
+				Err(error) => error
+			},
+			SYS_FACCESSAT => match self.proxy.read_string(cpu.get_mut_mmu(), args[1], PATH_MAX) {
+				Ok(path) => self.proxy.access(args[0], &path),
+				Err(error) => error
+			},
+			// Standard input/output are not terminals
+			SYS_IOCTL => -ENOTTY,
+			SYS_EXIT | SYS_EXIT_GROUP => {
+				self.exit_code = Some(args[0] & 0xff);
+				0
+			},
+			SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => PID,
+			SYS_GETPPID | SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => 0,
+			// No other threads and no signals are delivered
+			SYS_FUTEX | SYS_SET_ROBUST_LIST | SYS_SCHED_YIELD |
+			SYS_SIGALTSTACK | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => 0,
+			SYS_KILL => self.sys_kill(args[1]),
+			SYS_TKILL => self.sys_kill(args[1]),
+			SYS_TGKILL => self.sys_kill(args[2]),
+			SYS_CLOCK_GETTIME | SYS_CLOCK_GETTIME64 => self.sys_clock_gettime(cpu, args[0], args[1]),
+			SYS_GETTIMEOFDAY => self.sys_gettimeofday(cpu, args[0]),
+			SYS_UNAME => self.sys_uname(cpu, args[0]),
+			SYS_GETRANDOM => self.sys_getrandom(cpu, args[0], args[1]),
+			SYS_BRK => self.sys_brk(cpu, args[0]),
+			SYS_MMAP => self.sys_mmap(cpu, args[0], args[1], args[3], args[4], args[5]),
+			// No memory protection and mapped memory is never reused
+			SYS_MUNMAP | SYS_MPROTECT | SYS_MADVISE => 0,
+			SYS_MREMAP => -ENOMEM,
+			_ => return self.proxy.handle_syscall(cpu, number, args)
+		};
+		Some(result)
+	}
+
+	/// Signals to the program terminate it because no handlers run.
+	fn sys_kill(&mut self, signal: u64) -> i64 {
+		if signal != 0 {
+			self.exit_code = Some(128 + signal);
+		}
+		0
+	}
+
+	/// Writes `struct timespec` with 64-bit fields.
+	fn sys_clock_gettime(&mut self, cpu: &mut Cpu, clock_id: u64, ptp: u64) -> i64 {
+		let time = self.get_time(clock_id);
+		let mut data = vec![];
+		data.extend_from_slice(&time.as_secs().to_le_bytes());
+		data.extend_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+		match self.proxy.write_memory(cpu.get_mut_mmu(), ptp, &data) {
+			Ok(()) => 0,
+			Err(error) => error
+		}
+	}
+
+	fn sys_gettimeofday(&mut self, cpu: &mut Cpu, ptv: u64) -> i64 {
+		if ptv == 0 {
+			return 0;
+		}
+		let time = self.get_time(CLOCK_REALTIME);
+		let data = self.encode_words(&[time.as_secs(), time.subsec_micros() as u64]);
+		match self.proxy.write_memory(cpu.get_mut_mmu(), ptv, &data) {
+			Ok(()) => 0,
+			Err(error) => error
+		}
+	}
+
+	fn sys_uname(&mut self, cpu: &mut Cpu, pbuf: u64) -> i64 {
+		let machine = match self.xlen {
+			Xlen::Bit32 => "riscv32",
+			Xlen::Bit64 => "riscv64"
+		};
+		// sysname, nodename, release, version, machine, and domainname
+		let mut data = vec![];
+		for field in ["Linux", "localhost", "6.1.0", "#1", machine, "(none)"].iter() {
+			let mut bytes = field.as_bytes().to_vec();
+			bytes.resize(65, 0);
+			data.extend_from_slice(&bytes);
+		}
+		match self.proxy.write_memory(cpu.get_mut_mmu(), pbuf, &data) {
+			Ok(()) => 0,
+			Err(error) => error
+		}
+	}
+
+	fn sys_getrandom(&mut self, cpu: &mut Cpu, pbuf: u64, len: u64) -> i64 {
+		let mut data = vec![0; len.min(PAGE_SIZE * 64) as usize];
+		create_rng().fill(&mut data[..]);
+		match self.proxy.write_memory(cpu.get_mut_mmu(), pbuf, &data) {
+			Ok(()) => data.len() as i64,
+			Err(error) => error
+		}
+	}
+
+	/// Moves the program break. Returns the new one, or the current
+	/// one if it can't be moved.
+	fn sys_brk(&mut self, cpu: &mut Cpu, address: u64) -> i64 {
+		if address >= self.brk_start && address <= self.mmap_bottom {
+			// Memory freed by the previous shrink may be dirty
+			if address > self.brk {
+				self.clear_memory(cpu, self.brk, address - self.brk);
+			}
+			self.brk = address;
+		}
+		self.brk as i64
+	}
+
+	fn sys_mmap(&mut self, cpu: &mut Cpu, address: u64, length: u64, flags: u64, fd: u64, offset: u64) -> i64 {
+		if length == 0 {
+			return -EINVAL;
+		}
+		let length = align_up(length, PAGE_SIZE);
+		let address = match flags & MAP_FIXED {
+			0 => {
+				if self.mmap_bottom < self.brk + length {
+					return -ENOMEM;
+				}
+				self.mmap_bottom -= length;
+				self.mmap_bottom
+			},
+			_ => {
+				if !address.is_multiple_of(PAGE_SIZE) || address + length > self.memory_size {
+					return -EINVAL;
+				}
+				address
+			}
+		};
+		self.clear_memory(cpu, address, length);
+		if flags & MAP_ANONYMOUS == 0 {
+			let data = match self.proxy.read_file(fd, offset, length) {
+				Ok(data) => data,
+				Err(error) => return error
+			};
+			self.write_memory(cpu, address, &data);
+		}
+		address as i64
+	}
+
+	fn get_time(&self, clock_id: u64) -> Duration {
+		match clock_id {
+			CLOCK_REALTIME => self.clock.unix_time(),
+			_ => self.clock.now().saturating_sub(self.start_time)
+		}
+	}
+
+	fn get_word_size(&self) -> u64 {
+		match self.xlen {
+			Xlen::Bit32 => 4,
+			Xlen::Bit64 => 8
+		}
+	}
+
+	/// Writes memory in user address space, which the caller has checked.
+	fn write_memory(&self, cpu: &mut Cpu, address: u64, data: &[u8]) {
+		for (i, byte) in data.iter().enumerate() {
+			cpu.get_mut_mmu().store_raw(DRAM_BASE + address + i as u64, *byte);
+		}
+	}
+
+	/// Encodes words in XLEN bits
+	fn encode_words(&self, words: &[u64]) -> Vec<u8> {
+		let word_size = self.get_word_size() as usize;
+		let mut data = vec![];
+		for word in words.iter() {
+			data.extend_from_slice(&word.to_le_bytes()[..word_size]);
+		}
+		data
+	}
+
+	fn clear_memory(&self, cpu: &mut Cpu, address: u64, length: u64) {
+		let mmu = cpu.get_mut_mmu();
+		let mut i = 0;
+		while i < length {
+			match (address + i).is_multiple_of(8) && i + 8 <= length {
+				true => {
+					mmu.store_doubleword_raw(DRAM_BASE + address + i, 0);
+					i += 8;
+				},
+				false => {
+					mmu.store_raw(DRAM_BASE + address + i, 0);
+					i += 1;
+				}
+			};
+		}
+	}
+}
+
+/// Returns the size of a superpage mapped by a leaf entry of the
+/// second-level page table, 4MiB for Sv32 and 2MiB for Sv39.
+fn get_superpage_size(xlen: &Xlen) -> u64 {
+	match xlen {
+		Xlen::Bit32 => 4 * 1024 * 1024,
+		Xlen::Bit64 => 2 * 1024 * 1024
+	}
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+	value.div_ceil(alignment) * alignment
+}
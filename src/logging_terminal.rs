@@ -0,0 +1,229 @@
+use std::io::Write;
+use std::time::Instant;
+
+use terminal::Terminal;
+
+/// Direction marker of the lines the guest outputs
+pub const OUTPUT_MARKER: u8 = b'<';
+
+/// Direction marker of the lines input to the guest
+pub const INPUT_MARKER: u8 = b'>';
+
+// Bytes and when the first of them is transferred
+struct Line {
+	marker: u8,
+	data: Vec<u8>,
+	time: Option<f64>
+}
+
+impl Line {
+	fn new(marker: u8) -> Self {
+		Line {
+			marker,
+			data: vec![],
+			time: None
+		}
+	}
+}
+
+/// `Terminal` wrapper which passes everything through to the inner terminal
+/// and tees console input and output to a log, one line per entry like
+///
+/// ```text
+/// [    0.812345] < xv6 kernel is booting
+/// [    3.141592] > ls
+/// ```
+///
+/// The timestamp is seconds since the terminal is created and taken when
+/// the first byte of the line is transferred. `<` marks the guest output
+/// and `>` marks the input. Unprintable bytes are escaped. A line ends with
+/// a line feed, or also a carriage return for input. Errors on writing
+/// the log are ignored not to disturb the guest.
+pub struct LoggingTerminal {
+	terminal: Box<dyn Terminal>,
+	log: Box<dyn Write>,
+	start: Instant,
+	output_line: Line,
+	input_line: Line
+}
+
+impl LoggingTerminal {
+	/// Creates a new `LoggingTerminal`.
+	///
+	/// # Arguments
+	/// * `terminal` Inner terminal
+	/// * `log` Where log lines are written, e.g. a file
+	pub fn new(terminal: Box<dyn Terminal>, log: Box<dyn Write>) -> Self {
+		LoggingTerminal {
+			terminal,
+			log,
+			start: Instant::now(),
+			output_line: Line::new(OUTPUT_MARKER),
+			input_line: Line::new(INPUT_MARKER)
+		}
+	}
+
+	/// Returns mutable reference to the inner `Terminal`.
+	pub fn get_mut_terminal(&mut self) -> &mut Box<dyn Terminal> {
+		&mut self.terminal
+	}
+
+	fn log_output(&mut self, values: &[u8]) {
+		let time = self.start.elapsed().as_secs_f64();
+		for value in values.iter() {
+			if *value == b'\n' {
+				write_line(&mut self.log, &mut self.output_line, time);
+			} else {
+				push(&mut self.output_line, *value, time);
+			}
+		}
+	}
+
+	fn log_input(&mut self, values: &[u8]) {
+		let time = self.start.elapsed().as_secs_f64();
+		for value in values.iter() {
+			if *value == b'\n' || *value == b'\r' {
+				write_line(&mut self.log, &mut self.input_line, time);
+			} else {
+				push(&mut self.input_line, *value, time);
+			}
+		}
+	}
+}
+
+fn push(line: &mut Line, value: u8, time: f64) {
+	if line.time.is_none() {
+		line.time = Some(time);
+	}
+	line.data.push(value);
+}
+
+fn write_line(log: &mut Box<dyn Write>, line: &mut Line, now: f64) {
+	let time = match line.time.take() {
+		Some(time) => time,
+		// Empty input line such as CR LF
+		None if line.marker == INPUT_MARKER => return,
+		None => now
+	};
+	// Output lines often end with CR LF
+	if line.data.last() == Some(&b'\r') {
+		line.data.pop();
+	}
+	let mut text = vec![];
+	for value in line.data.iter() {
+		match *value {
+			b'\t' | 0x20..=0x7e => text.push(*value),
+			_ => text.extend(std::ascii::escape_default(*value))
+		};
+	}
+	let _ = write!(log, "[{:12.6}] {} ", time, line.marker as char)
+		.and_then(|_| log.write_all(&text))
+		.and_then(|_| log.write_all(b"\n"))
+		.and_then(|_| log.flush());
+	line.data.clear();
+}
+
+impl Drop for LoggingTerminal {
+	/// Writes the lines not terminated yet.
+	fn drop(&mut self) {
+		let time = self.start.elapsed().as_secs_f64();
+		if !self.output_line.data.is_empty() {
+			write_line(&mut self.log, &mut self.output_line, time);
+		}
+		if !self.input_line.data.is_empty() {
+			write_line(&mut self.log, &mut self.input_line, time);
+		}
+	}
+}
+
+impl Terminal for LoggingTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.log_output(&[value]);
+		self.terminal.put_byte(value);
+	}
+
+	fn get_output(&mut self) -> u8 {
+		self.terminal.get_output()
+	}
+
+	fn put_input(&mut self, data: u8) {
+		self.terminal.put_input(data);
+	}
+
+	fn get_input(&mut self) -> u8 {
+		let value = self.terminal.get_input();
+		if value != 0 {
+			self.log_input(&[value]);
+		}
+		value
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		self.log_output(values);
+		self.terminal.put_bytes(values);
+	}
+
+	fn get_output_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.terminal.get_output_bytes(buffer)
+	}
+
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		self.terminal.put_input_bytes(data);
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		let size = self.terminal.get_input_bytes(buffer);
+		self.log_input(&buffer[..size]);
+		size
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.terminal.has_input()
+	}
+
+	fn get_window_size(&mut self) -> Option<(u16, u16)> {
+		self.terminal.get_window_size()
+	}
+
+	fn is_output_ready(&mut self) -> bool {
+		self.terminal.is_output_ready()
+	}
+}
+
+#[cfg(test)]
+mod test_logging_terminal {
+	use super::*;
+	use default_terminal::DefaultTerminal;
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for SharedLog {
+		fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+			self.0.borrow_mut().write(data)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn tee() {
+		let log = Rc::new(RefCell::new(vec![]));
+		let mut terminal = LoggingTerminal::new(Box::new(DefaultTerminal::new()), Box::new(SharedLog(log.clone())));
+		terminal.put_bytes(b"booting\r\n$ ");
+		terminal.put_input_bytes(b"ls\r");
+		let mut buffer = [0; 8];
+		assert_eq!(3, terminal.get_input_bytes(&mut buffer));
+		terminal.put_byte(0x1b);
+		assert_eq!(b'b', terminal.get_output());
+		drop(terminal);
+
+		let log = String::from_utf8(log.borrow().clone()).unwrap();
+		let lines: Vec<&str> = log.lines().map(|line| line.split("] ").nth(1).unwrap()).collect();
+		assert_eq!(vec!["< booting", "> ls", "< $ \\x1b"], lines);
+		assert!(log.starts_with("[    0.0"));
+	}
+}
@@ -1,14 +1,50 @@
-/// Emulates main memory.
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Size of the page `Memory` allocates host memory in
+pub const MEMORY_PAGE_SIZE: u64 = 4096;
+
+const WORDS_PER_PAGE: usize = (MEMORY_PAGE_SIZE / 8) as usize;
+
+/// Emulates main memory. Host memory is allocated sparsely in pages
+/// on the first write of non-zero data, so the area the guest has never
+/// written, has only zero filled, or has released doesn't consume host
+/// memory. Such pages read zero without allocation.
 pub struct Memory {
-	/// Memory content
-	data: Vec<u64>
+	/// Memory content. `None` for the page not allocated yet, which reads zero.
+	pages: Vec<Option<Box<[u64; WORDS_PER_PAGE]>>>,
+	capacity: u64,
+	/// Id of the last snapshot taken. Zero if none.
+	snapshot_id: u64,
+	/// Whether each page has been written since the last snapshot
+	dirty: Vec<bool>,
+	/// Indices of the pages written since the last snapshot
+	dirty_pages: Vec<usize>,
+	/// Where writes out of range go, to drop them
+	out_of_range_data: u64
+}
+
+/// Memory content saved by `Memory::take_snapshot()`
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MemorySnapshot {
+	/// Id to find the snapshot `Memory` has taken last. Not serialized,
+	/// a deserialized snapshot has zero and is restored entirely.
+	#[cfg_attr(feature = "serde", serde(skip))]
+	id: u64,
+	#[cfg_attr(feature = "serde", serde(with = "::serde_support::boxed_array_pages"))]
+	pages: Vec<Option<Box<[u64; WORDS_PER_PAGE]>>>
 }
 
 impl Memory {
 	/// Creates a new `Memory`
 	pub fn new() -> Self {
 		Memory {
-			data: vec![]
+			pages: vec![],
+			capacity: 0,
+			snapshot_id: 0,
+			dirty: vec![],
+			dirty_pages: vec![],
+			out_of_range_data: 0
 		}
 	}
 
@@ -18,11 +54,116 @@ impl Memory {
 	/// # Arguments
 	/// * `capacity`
 	pub fn init(&mut self, capacity: u64) {
-		for _i in 0..((capacity + 7) / 8) {
-			self.data.push(0);
+		self.capacity = capacity;
+		// vec! of zero values gets zeroed memory from the allocator, which
+		// the host OS maps lazily too, so even the page table of a large
+		// memory costs only as much as the guest touches
+		self.pages = vec![None; capacity.div_ceil(MEMORY_PAGE_SIZE) as usize];
+		self.dirty = vec![false; self.pages.len()];
+	}
+
+	/// Saves the content. Pages written afterward are tracked, so restoring
+	/// the last snapshot taken copies back only them.
+	pub fn take_snapshot(&mut self) -> MemorySnapshot {
+		self.snapshot_id += 1;
+		self.clear_dirty_pages();
+		MemorySnapshot {
+			id: self.snapshot_id,
+			pages: self.pages.clone()
+		}
+	}
+
+	/// Restores the content saved by `take_snapshot()`.
+	///
+	/// # Arguments
+	/// * `snapshot`
+	pub fn restore_snapshot(&mut self, snapshot: &MemorySnapshot) {
+		match snapshot.id != 0 && snapshot.id == self.snapshot_id {
+			true => {
+				for index in self.dirty_pages.iter() {
+					self.pages[*index] = snapshot.pages[*index].clone();
+				}
+			},
+			false => {
+				self.pages = snapshot.pages.clone();
+				// A deserialized snapshot may come from a machine with
+				// another memory capacity
+				self.pages.resize(self.dirty.len(), None);
+				self.snapshot_id = snapshot.id;
+			}
+		};
+		self.clear_dirty_pages();
+	}
+
+	fn clear_dirty_pages(&mut self) {
+		for index in self.dirty_pages.drain(..) {
+			self.dirty[index] = false;
 		}
 	}
-	
+
+	fn mark_dirty(&mut self, page_index: usize) {
+		if self.snapshot_id != 0 && !self.dirty[page_index] {
+			self.dirty[page_index] = true;
+			self.dirty_pages.push(page_index);
+		}
+	}
+
+	/// Releases host memory of the page including the address.
+	/// The page reads zero afterward.
+	///
+	/// # Arguments
+	/// * `address`
+	pub fn release_page(&mut self, address: u64) {
+		let page_index = (address / MEMORY_PAGE_SIZE) as usize;
+		if page_index < self.pages.len() {
+			self.mark_dirty(page_index);
+			self.pages[page_index] = None;
+		}
+	}
+
+	/// Returns the capacity in bytes.
+	pub fn get_capacity(&self) -> u64 {
+		self.capacity
+	}
+
+	/// Returns the size of host memory allocated for the content in bytes.
+	pub fn get_allocated_size(&self) -> u64 {
+		self.pages.iter().filter(|page| page.is_some()).count() as u64 * MEMORY_PAGE_SIZE
+	}
+
+	// Reads eight bytes at the index of eight-byte aligned words.
+	// Zero out of range
+	fn read_data(&self, index: usize) -> u64 {
+		match self.pages.get(index / WORDS_PER_PAGE) {
+			Some(Some(page)) => page[index % WORDS_PER_PAGE],
+			_ => 0
+		}
+	}
+
+	// Writes the bits of the mask at the index of eight-byte aligned
+	// words. Zero to the page not allocated is dropped, the page reads
+	// zero already, so that zero filling memory, e.g. the guest kernel
+	// clearing the pages it hands out, doesn't allocate them
+	fn write_data(&mut self, index: usize, mask: u64, value: u64) {
+		if value == 0 && matches!(self.pages.get(index / WORDS_PER_PAGE), Some(None)) {
+			return;
+		}
+		let data = self.get_mut_data(index);
+		*data = (*data & !mask) | value;
+	}
+
+	// Returns eight bytes at the index of eight-byte aligned words
+	// to write, allocating the page if needed. Writes out of range,
+	// e.g. DMA by devices to addresses the guest has set, are dropped.
+	fn get_mut_data(&mut self, index: usize) -> &mut u64 {
+		if index / WORDS_PER_PAGE >= self.pages.len() {
+			return &mut self.out_of_range_data;
+		}
+		self.mark_dirty(index / WORDS_PER_PAGE);
+		let page = self.pages[index / WORDS_PER_PAGE].get_or_insert_with(|| Box::new([0; WORDS_PER_PAGE]));
+		&mut page[index % WORDS_PER_PAGE]
+	}
+
 	/// Reads a byte from memory.
 	///
 	/// # Arguments
@@ -30,7 +171,7 @@ impl Memory {
 	pub fn read_byte(&self, address: u64) -> u8 {
 		let index = (address >> 3) as usize;
 		let pos = ((address % 8) as u64) * 8;
-		(self.data[index] >> pos) as u8
+		(self.read_data(index) >> pos) as u8
 	}
 
 	/// Reads two bytes from memory.
@@ -41,7 +182,7 @@ impl Memory {
 		if (address % 2) == 0 {
 			let index = (address >> 3) as usize;
 			let pos = ((address % 8) as u64) * 8;
-			(self.data[index] >> pos) as u16
+			(self.read_data(index) >> pos) as u16
 		} else {
 			self.read_bytes(address, 2) as u16
 		}
@@ -55,7 +196,7 @@ impl Memory {
 		if (address % 4) == 0 {
 			let index = (address >> 3) as usize;
 			let pos = ((address % 8) as u64) * 8;
-			(self.data[index] >> pos) as u32
+			(self.read_data(index) >> pos) as u32
 		} else {
 			self.read_bytes(address, 4) as u32
 		}
@@ -68,9 +209,9 @@ impl Memory {
 	pub fn read_doubleword(&self, address: u64) -> u64 {
 		if (address % 8) == 0 {
 			let index = (address >> 3) as usize;
-			self.data[index]
+			self.read_data(index)
 		} else if (address % 4) == 0 {
-			(self.read_word(address) as u64) | ((self.read_word(address.wrapping_add(4)) as u64) << 4)
+			(self.read_word(address) as u64) | ((self.read_word(address.wrapping_add(4)) as u64) << 32)
 		} else {
 			self.read_bytes(address, 8)
 		}
@@ -97,7 +238,7 @@ impl Memory {
 	pub fn write_byte(&mut self, address: u64, value: u8) {
 		let index = (address >> 3) as usize;
 		let pos = ((address % 8) as u64) * 8;
-		self.data[index] = (self.data[index] & !(0xff << pos)) | ((value as u64) << pos);
+		self.write_data(index, 0xff << pos, (value as u64) << pos);
 	}
 
 	/// Writes two bytes to memory.
@@ -109,7 +250,7 @@ impl Memory {
 		if (address % 2) == 0 {
 			let index = (address >> 3) as usize;
 			let pos = ((address % 8) as u64) * 8;
-			self.data[index] = (self.data[index] & !(0xffff << pos)) | ((value as u64) << pos);
+			self.write_data(index, 0xffff << pos, (value as u64) << pos);
 		} else {
 			self.write_bytes(address, value as u64, 2);
 		}
@@ -124,7 +265,7 @@ impl Memory {
 		if (address % 4) == 0 {
 			let index = (address >> 3) as usize;
 			let pos = ((address % 8) as u64) * 8;
-			self.data[index] = (self.data[index] & !(0xffffffff << pos)) | ((value as u64) << pos);
+			self.write_data(index, 0xffffffff << pos, (value as u64) << pos);
 		} else {
 			self.write_bytes(address, value as u64, 4);
 		}
@@ -138,7 +279,7 @@ impl Memory {
 	pub fn write_doubleword(&mut self, address: u64, value: u64) {
 		if (address % 8) == 0 {
 			let index = (address >> 3) as usize;
-			self.data[index] = value;
+			self.write_data(index, u64::MAX, value);
 		} else if (address % 4) == 0 {
 			self.write_word(address, (value & 0xffffffff) as u32);
 			self.write_word(address.wrapping_add(4), (value >> 32) as u32);
@@ -159,11 +300,69 @@ impl Memory {
 		}
 	}
 
+	/// Writes the bytes to memory from the address, eight bytes at a time
+	/// where aligned. Bytes out of range are dropped.
+	///
+	/// # Arguments
+	/// * `address`
+	/// * `data`
+	pub fn write_slice(&mut self, address: u64, data: &[u8]) {
+		// Bytes up to the first eight-byte boundary
+		let head = (address.wrapping_neg() % 8).min(data.len() as u64) as usize;
+		for (i, byte) in data[..head].iter().enumerate() {
+			self.write_byte(address.wrapping_add(i as u64), *byte);
+		}
+		let address = address.wrapping_add(head as u64);
+		let chunks = data[head..].chunks_exact(8);
+		let tail = chunks.remainder();
+		let mut index = (address >> 3) as usize;
+		for chunk in chunks {
+			let mut bytes = [0; 8];
+			bytes.copy_from_slice(chunk);
+			self.write_data(index, u64::MAX, u64::from_le_bytes(bytes));
+			index += 1;
+		}
+		let address = (index as u64) << 3;
+		for (i, byte) in tail.iter().enumerate() {
+			self.write_byte(address.wrapping_add(i as u64), *byte);
+		}
+	}
+
 	/// Check if the address is valid memory address
 	///
 	/// # Arguments
 	/// * `address`
 	pub fn validate_address(&self, address: u64) -> bool {
-		return (address as usize) < self.data.len()
+		address < self.capacity
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test_memory {
+	use super::*;
+
+	#[test]
+	fn lazy_allocation() {
+		let mut memory = Memory::new();
+		memory.init(1024 * 1024 * 1024 * 4);
+		assert_eq!(0, memory.read_doubleword(0x3fff_fff8));
+		assert_eq!(0, memory.get_allocated_size());
+
+		// Zero filling doesn't allocate
+		memory.write_slice(0x1000, &[0; 0x3000]);
+		memory.write_doubleword(0x8000, 0);
+		memory.write_byte(0x9001, 0);
+		assert_eq!(0, memory.get_allocated_size());
+
+		// The first non-zero write does
+		memory.write_halfword(0x9002, 0x1234);
+		assert_eq!(MEMORY_PAGE_SIZE, memory.get_allocated_size());
+		assert_eq!(0x12340000, memory.read_word(0x9000));
+		memory.write_byte(0x9003, 0);
+		assert_eq!(0x340000, memory.read_word(0x9000));
+
+		memory.release_page(0x9000);
+		assert_eq!(0, memory.read_word(0x9000));
+		assert_eq!(0, memory.get_allocated_size());
+	}
+}
@@ -0,0 +1,251 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use collections::{FnvHashMap, FnvHashSet};
+
+/// Device accessed through memory mapped I/O, in `MmioAccess`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MmioDevice {
+	Clint,
+	TestFinisher,
+	Sswi,
+	Plic,
+	Aplic,
+	Imsic,
+	/// UART of the console
+	Console,
+	/// Serial port added besides the console, by index
+	SerialPort(usize),
+	VirtioBlock,
+	VirtioNet,
+	VirtioSound,
+	VirtioBalloon,
+	VirtioConsole,
+	Pwm,
+	Gpio,
+	Framebuffer,
+	/// The shared memory region and its doorbell registers
+	SharedMemory
+}
+
+impl MmioDevice {
+	/// Returns the device name, e.g. `plic`.
+	pub fn get_name(&self) -> &'static str {
+		match self {
+			MmioDevice::Clint => "clint",
+			MmioDevice::TestFinisher => "test_finisher",
+			MmioDevice::Sswi => "sswi",
+			MmioDevice::Plic => "plic",
+			MmioDevice::Aplic => "aplic",
+			MmioDevice::Imsic => "imsic",
+			MmioDevice::Console => "console",
+			MmioDevice::SerialPort(_) => "serial_port",
+			MmioDevice::VirtioBlock => "virtio_block",
+			MmioDevice::VirtioNet => "virtio_net",
+			MmioDevice::VirtioSound => "virtio_sound",
+			MmioDevice::VirtioBalloon => "virtio_balloon",
+			MmioDevice::VirtioConsole => "virtio_console",
+			MmioDevice::Pwm => "pwm",
+			MmioDevice::Gpio => "gpio",
+			MmioDevice::Framebuffer => "framebuffer",
+			MmioDevice::SharedMemory => "shared_memory"
+		}
+	}
+}
+
+/// Type of an MMIO access
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MmioAccessType {
+	Read,
+	Write
+}
+
+/// Access to a device register, recorded in `MmioLog`
+#[derive(Clone, Debug, PartialEq)]
+pub struct MmioAccess {
+	pub device: MmioDevice,
+	/// Offset from the base address of the device
+	pub offset: u64,
+	/// Access width in bytes
+	pub width: u64,
+	pub access_type: MmioAccessType,
+	/// Value read or written
+	pub value: u64,
+	/// Address of the instruction accessing the device
+	pub pc: u64
+}
+
+/// Called with every access logged
+pub type MmioAccessCallback = Box<dyn FnMut(&MmioAccess)>;
+
+/// Records the register accesses to the devices enabled, to debug guest
+/// drivers from the device's point of view. Keeps the latest accesses
+/// per device in bounded rings. Devices can be enabled and disabled at
+/// any time. Accesses the CPU splits at page boundaries are recorded per
+/// part. Set to `Emulator` with `set_mmio_log()`.
+pub struct MmioLog {
+	capacity: usize,
+	enabled_devices: FnvHashSet<MmioDevice>,
+	records: FnvHashMap<MmioDevice, VecDeque<MmioAccess>>,
+	callback: Option<MmioAccessCallback>,
+	// Address of the instruction being executed
+	pc: u64
+}
+
+impl MmioLog {
+	/// Creates a new `MmioLog` with no device enabled.
+	///
+	/// # Arguments
+	/// * `capacity` How many latest accesses are kept per device
+	pub fn new(capacity: usize) -> Self {
+		MmioLog {
+			capacity,
+			enabled_devices: FnvHashSet::default(),
+			records: FnvHashMap::default(),
+			callback: None,
+			pc: 0
+		}
+	}
+
+	/// Starts or stops logging the accesses to the device. The accesses
+	/// kept so far are kept.
+	///
+	/// # Arguments
+	/// * `device`
+	/// * `enabled`
+	pub fn set_enabled(&mut self, device: MmioDevice, enabled: bool) {
+		match enabled {
+			true => self.enabled_devices.insert(device),
+			false => self.enabled_devices.remove(&device)
+		};
+	}
+
+	/// Indicates whether the accesses to the device are logged.
+	///
+	/// # Arguments
+	/// * `device`
+	pub fn is_enabled(&self, device: MmioDevice) -> bool {
+		self.enabled_devices.contains(&device)
+	}
+
+	/// Sets the callback called with every access logged.
+	///
+	/// # Arguments
+	/// * `callback`
+	pub fn set_callback(&mut self, callback: MmioAccessCallback) {
+		self.callback = Some(callback);
+	}
+
+	/// Sets the address of the instruction being executed, which
+	/// the following accesses are recorded with. `Cpu` calls this.
+	///
+	/// # Arguments
+	/// * `pc`
+	pub fn update_pc(&mut self, pc: u64) {
+		self.pc = pc;
+	}
+
+	/// Records an access if the device is enabled. `Mmu` calls this.
+	///
+	/// # Arguments
+	/// * `device`
+	/// * `offset` Offset from the base address of the device
+	/// * `width` Access width in bytes
+	/// * `access_type`
+	/// * `value`
+	pub fn record(&mut self, device: MmioDevice, offset: u64, width: u64, access_type: MmioAccessType, value: u64) {
+		if !self.enabled_devices.contains(&device) {
+			return;
+		}
+		let access = MmioAccess {
+			device,
+			offset,
+			width,
+			access_type,
+			value,
+			pc: self.pc
+		};
+		if let Some(callback) = self.callback.as_mut() {
+			callback(&access);
+		}
+		if self.capacity == 0 {
+			return;
+		}
+		let records = self.records.entry(device).or_default();
+		if records.len() == self.capacity {
+			records.pop_front();
+		}
+		records.push_back(access);
+	}
+
+	/// Returns the latest accesses kept for the device, the oldest first.
+	///
+	/// # Arguments
+	/// * `device`
+	pub fn get_records(&self, device: MmioDevice) -> Vec<MmioAccess> {
+		match self.records.get(&device) {
+			Some(records) => records.iter().cloned().collect(),
+			None => vec![]
+		}
+	}
+
+	/// Forgets the accesses kept so far.
+	pub fn clear(&mut self) {
+		self.records.clear();
+	}
+}
+
+#[cfg(test)]
+mod test_mmio_log {
+	use super::*;
+	use cpu::Cpu;
+	use mmu::DRAM_BASE;
+	use terminal::DummyTerminal;
+
+	#[test]
+	fn record() {
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x100);
+		cpu.update_pc(DRAM_BASE);
+		let code = [
+			0x10000537, // lui a0, 0x10000
+			0x04100593, // addi a1, zero, 0x41
+			0x00b50023, // sb a1, 0(a0)
+			0x00554603, // lbu a2, 5(a0)
+			0x0c0006b7, // lui a3, 0xc000
+			0x0006a703 // lw a4, 0(a3)
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		let mut mmio_log = MmioLog::new(8);
+		mmio_log.set_enabled(MmioDevice::Console, true);
+		cpu.get_mut_mmu().set_mmio_log(mmio_log);
+		for _ in 0..code.len() {
+			cpu.tick();
+		}
+		let mmio_log = cpu.get_mut_mmu().get_mut_mmio_log().unwrap();
+		let records = mmio_log.get_records(MmioDevice::Console);
+		assert_eq!(2, records.len());
+		assert_eq!(MmioAccess {
+			device: MmioDevice::Console,
+			offset: 0,
+			width: 1,
+			access_type: MmioAccessType::Write,
+			value: 0x41,
+			pc: DRAM_BASE + 8
+		}, records[0]);
+		assert_eq!((5, MmioAccessType::Read, DRAM_BASE + 12), (records[1].offset, records[1].access_type, records[1].pc));
+		// Not enabled
+		assert!(mmio_log.get_records(MmioDevice::Plic).is_empty());
+
+		mmio_log.set_enabled(MmioDevice::Plic, true);
+		cpu.update_pc(DRAM_BASE + 20);
+		cpu.tick();
+		let records = cpu.get_mut_mmu().get_mut_mmio_log().unwrap().get_records(MmioDevice::Plic);
+		assert_eq!(vec![(0, 4, MmioAccessType::Read)], records.iter().map(|access| (access.offset, access.width, access.access_type)).collect::<Vec<_>>());
+	}
+}
@@ -4,17 +4,48 @@ pub const DRAM_BASE: u64 = 0x80000000;
 
 const DTB_SIZE: usize = 0xfe0;
 
-extern crate fnv;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
 
-use self::fnv::FnvHashMap;
+use collections::FnvHashMap;
 
-use memory::Memory;
+use memory::{Memory, MemorySnapshot};
 use cpu::{PrivilegeMode, Trap, TrapType, Xlen, get_privilege_mode};
+#[cfg(feature = "virtio")]
 use device::virtio_block_disk::VirtioBlockDisk;
-use device::plic::Plic;
+#[cfg(feature = "virtio")]
+use block_backend::BlockBackend;
+#[cfg(feature = "virtio")]
+use device::virtio_net::VirtioNet;
+#[cfg(feature = "virtio")]
+use device::virtio_snd::VirtioSnd;
+#[cfg(feature = "virtio")]
+use device::virtio_balloon::VirtioBalloon;
+#[cfg(feature = "virtio")]
+use device::virtio_console::VirtioConsole;
+use device::plic::{Plic, GPIO_IRQ, MAX_IRQ, PWM_IRQ_BASE, SERIAL_PORT_IRQ_BASE, SHARED_MEMORY_IRQ, SIFIVE_UART_IRQ, UART_IRQ, VIRTIO_BALLOON_IRQ, VIRTIO_CONSOLE_IRQ, VIRTIO_IRQ, VIRTIO_NET_IRQ, VIRTIO_SND_IRQ};
+use device::aplic::Aplic;
+use device::imsic::Imsic;
+use device::msi::Msi;
 use device::clint::Clint;
-use device::uart::Uart;
-use terminal::Terminal;
+use device::sswi::Sswi;
+use device::gpio::Gpio;
+use device::test_finisher::{TestFinisher, TEST_FINISHER_BASE};
+use device::pwm::{Pwm, PWM_CMP_NUM};
+use device::console::Console;
+use device::uart::{Uart, MAX_SERIAL_PORT_NUM, SERIAL_PORT_BASE, UART_SIZE};
+use device::framebuffer::{Framebuffer, FRAMEBUFFER_BASE};
+use device::shared_memory::{SharedMemory, SHARED_MEMORY_BASE, SHARED_MEMORY_REGISTERS_BASE};
+use device::sifive_uart::SIFIVE_UART_BASE;
+use device::uart::UART_BASE;
+use device::sswi::SSWI_BASE;
+use device::aplic::{APLIC_MACHINE_BASE, APLIC_SUPERVISOR_BASE};
+use device::imsic::{IMSIC_MACHINE_BASE, IMSIC_SUPERVISOR_BASE};
+use mmio_log::{MmioAccessType, MmioDevice, MmioLog};
+use error::{DebugError, DeviceError, ExecError, MemoryError};
+use config::{EmulatorConfig, InterruptControllerType};
+use terminal::{DummyTerminal, Terminal};
 
 /// Emulates Memory Management Unit. It holds the Main memory and peripheral
 /// devices, maps address to them, and accesses them depending on address.
@@ -29,10 +60,47 @@ pub struct Mmu {
 	privilege_mode: PrivilegeMode,
 	memory: MemoryWrapper,
 	dtb: Vec<u8>,
+	#[cfg(feature = "virtio")]
 	disk: VirtioBlockDisk,
+	#[cfg(feature = "virtio")]
+	net: VirtioNet,
+	#[cfg(feature = "virtio")]
+	snd: VirtioSnd,
+	#[cfg(feature = "virtio")]
+	balloon: VirtioBalloon,
+	#[cfg(feature = "virtio")]
+	virtio_console: VirtioConsole,
 	plic: Plic,
+	aplic: Aplic,
+	imsic: Imsic,
+	/// Whether the devices are connected to `Aplic` and `Imsic`
+	/// instead of `Plic`
+	aia_enabled: bool,
+	/// MSIs waiting to be written to the bus
+	msis: Vec<Msi>,
+	/// Interrupt lines driven by the host with `set_host_irq_level()`
+	host_irq_lines: u64,
+	/// Levels of the host driven interrupt lines
+	host_irq_levels: u64,
 	clint: Clint,
-	uart: Uart,
+	sswi: Sswi,
+	/// Whether `Sswi` is mapped
+	aclint_enabled: bool,
+	/// Whether the devices other than CLINT are mapped. See
+	/// `EmulatorConfig::has_platform_devices()`
+	platform_devices_enabled: bool,
+	console: Console,
+	/// NS16550A UARTs besides the console
+	serial_ports: Vec<Uart>,
+	gpio: Gpio,
+	test_finisher: TestFinisher,
+	pwm: Pwm,
+	framebuffer: Framebuffer,
+	shared_memory: SharedMemory,
+
+	/// Physical region only the shadow stack accesses can touch, as
+	/// (base, end)
+	shadow_stack: Option<(u64, u64)>,
 
 	/// Address translation can be affected `mstatus` (MPRV, MPP in machine mode)
 	/// then `Mmu` has copy of it.
@@ -52,9 +120,37 @@ pub struct Mmu {
 	page_cache_enabled: bool,
 	fetch_page_cache: FnvHashMap<u64, u64>,
 	load_page_cache: FnvHashMap<u64, u64>,
-	store_page_cache: FnvHashMap<u64, u64>
+	store_page_cache: FnvHashMap<u64, u64>,
+
+	/// Counters returned by `get_translation_stats()`
+	translation_stats: TranslationStats,
+
+	/// Data watchpoints added with `add_watchpoint()`
+	watchpoints: Vec<Watchpoint>,
+	/// The first access caught by the watchpoints since the last
+	/// `take_watchpoint_hit()`
+	watchpoint_hit: Option<WatchpointHit>,
+
+	/// Value watches added with `add_value_watch()`
+	value_watches: Vec<ValueWatch>,
+	next_value_watch_id: usize,
+
+	/// Main memory bytes about to be stored and their old values,
+	/// recorded between `start_store_log()` and `take_store_log()`
+	store_log: Option<Vec<(u64, u8)>>,
+
+	/// Device register accesses log set with `set_mmio_log()`
+	mmio_log: Option<MmioLog>,
+
+	/// Whether an access has taken the slow path to the devices since
+	/// `take_mmio_accessed()`
+	mmio_accessed: bool,
+
+	/// The first internal error since `take_error()`
+	error: Option<ExecError>
 }
 
+#[derive(Debug)]
 pub enum AddressingMode {
 	None,
 	SV32,
@@ -69,6 +165,81 @@ enum MemoryAccessType {
 	DontCare
 }
 
+/// Type of the data accesses a watchpoint catches
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchpointType {
+	Read,
+	Write
+}
+
+// Virtual address range watched
+struct Watchpoint {
+	watchpoint_type: WatchpointType,
+	address: u64,
+	length: u64
+}
+
+/// Data access caught by a watchpoint
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchpointHit {
+	pub watchpoint_type: WatchpointType,
+	/// Virtual address the access starts at. It can be before
+	/// the watched range if the access overlaps its beginning.
+	pub address: u64,
+	/// Bytes accessed
+	pub width: u64,
+	/// Value read or written
+	pub value: u64
+}
+
+/// Address translation counters, returned by `Mmu::get_translation_stats()`.
+/// They only increase.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TranslationStats {
+	/// Translations served by the page cache
+	pub page_cache_hits: u64,
+	/// Translations the page cache has missed. Counted only while
+	/// the page cache is enabled
+	pub page_cache_misses: u64,
+	/// Page table walks
+	pub page_walks: u64,
+	/// Page cache clears, e.g. on privilege mode changes and `satp` writes.
+	/// Counted even while the page cache is disabled
+	pub page_cache_flushes: u64
+}
+
+/// Condition a value watch calls back on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueWatchCondition {
+	/// Any change of the value
+	Changed,
+	/// Change to the value
+	Equals(u64)
+}
+
+/// Called with the old and new values when a value watch's condition meets
+pub type ValueWatchCallback = Box<dyn FnMut(u64, u64)>;
+
+// Eight bytes of main memory watched for their value
+struct ValueWatch {
+	id: usize,
+	address: u64,
+	condition: ValueWatchCondition,
+	// The latest value seen
+	value: u64,
+	callback: ValueWatchCallback
+}
+
+// Indicates whether the interrupt line is connected to a built-in device
+fn is_device_irq(irq: u32) -> bool {
+	match irq {
+		VIRTIO_IRQ | VIRTIO_NET_IRQ | GPIO_IRQ | SIFIVE_UART_IRQ | VIRTIO_SND_IRQ | VIRTIO_BALLOON_IRQ | SHARED_MEMORY_IRQ |
+		VIRTIO_CONSOLE_IRQ | UART_IRQ => true,
+		_ => (irq >= PWM_IRQ_BASE && irq < PWM_IRQ_BASE + PWM_CMP_NUM as u32) ||
+			(irq >= SERIAL_PORT_IRQ_BASE && irq < SERIAL_PORT_IRQ_BASE + MAX_SERIAL_PORT_NUM as u32)
+	}
+}
+
 fn _get_addressing_mode_name(mode: &AddressingMode) -> &'static str {
 	match mode {
 		AddressingMode::None => "None",
@@ -84,15 +255,8 @@ impl Mmu {
 	/// # Arguments
 	/// * `xlen`
 	/// * `terminal`
-	pub fn new(xlen: Xlen, terminal: Box<dyn Terminal>) -> Self {
-		let mut dtb = vec![0; DTB_SIZE];
-
-		// Load default device tree binary content
-		let content = include_bytes!("./device/dtb.dtb");
-		for i in 0..content.len() {
-			dtb[i] = content[i];
-		}
-
+	/// * `config`
+	pub fn new(xlen: Xlen, terminal: Box<dyn Terminal>, config: &EmulatorConfig) -> Self {
 		Mmu {
 			clock: 0,
 			xlen: xlen,
@@ -100,16 +264,59 @@ impl Mmu {
 			addressing_mode: AddressingMode::None,
 			privilege_mode: PrivilegeMode::Machine,
 			memory: MemoryWrapper::new(),
-			dtb: dtb,
-			disk: VirtioBlockDisk::new(),
+			dtb: vec![0; DTB_SIZE],
+			#[cfg(feature = "virtio")]
+			disk: VirtioBlockDisk::new(&config.virtio_block_transport),
+			#[cfg(feature = "virtio")]
+			net: VirtioNet::new(&config.virtio_net_transport),
+			#[cfg(feature = "virtio")]
+			snd: VirtioSnd::new(&config.virtio_snd_transport),
+			#[cfg(feature = "virtio")]
+			balloon: VirtioBalloon::new(&config.virtio_balloon_transport, config.balloon),
+			#[cfg(feature = "virtio")]
+			virtio_console: VirtioConsole::new(&config.virtio_console_transport, config.virtio_console, Box::new(DummyTerminal::new())),
 			plic: Plic::new(),
-			clint: Clint::new(),
-			uart: Uart::new(terminal),
+			aplic: Aplic::new(),
+			imsic: Imsic::new(),
+			aia_enabled: config.interrupt_controller == InterruptControllerType::Aia,
+			msis: vec![],
+			host_irq_lines: 0,
+			host_irq_levels: 0,
+			clint: Clint::new_with_config(&config.timer),
+			sswi: Sswi::new(),
+			aclint_enabled: config.aclint,
+			platform_devices_enabled: config.has_platform_devices(),
+			console: Console::new(&config.console, terminal),
+			serial_ports: (0..config.serial_port_num.min(MAX_SERIAL_PORT_NUM) as u64)
+				.map(|i| Uart::new_with_base(SERIAL_PORT_BASE + i * UART_SIZE, Box::new(DummyTerminal::new())))
+				.collect(),
+			gpio: Gpio::new(),
+			test_finisher: TestFinisher::new(),
+			pwm: Pwm::new(),
+			framebuffer: match &config.framebuffer {
+				Some(framebuffer) => Framebuffer::new(framebuffer.width, framebuffer.height),
+				None => Framebuffer::new(0, 0)
+			},
+			shared_memory: match &config.shared_memory {
+				Some(shared_memory) => SharedMemory::new(shared_memory.size, shared_memory.doorbell),
+				None => SharedMemory::new(0, false)
+			},
+			shadow_stack: config.shadow_stack.as_ref()
+				.map(|shadow_stack| (shadow_stack.base, shadow_stack.base.wrapping_add(shadow_stack.size))),
 			mstatus: 0,
 			page_cache_enabled: false,
 			fetch_page_cache: FnvHashMap::default(),
 			load_page_cache: FnvHashMap::default(),
-			store_page_cache: FnvHashMap::default()
+			store_page_cache: FnvHashMap::default(),
+			translation_stats: TranslationStats::default(),
+			watchpoints: vec![],
+			watchpoint_hit: None,
+			value_watches: vec![],
+			next_value_watch_id: 0,
+			store_log: None,
+			mmio_log: None,
+			mmio_accessed: false,
+			error: None
 		}
 	}
 
@@ -133,12 +340,14 @@ impl Mmu {
 	/// Initializes Virtio block disk. This method is expected to be called only once.
 	///
 	/// # Arguments
-	/// * `data` Filesystem binary content
-	pub fn init_disk(&mut self, data: Vec<u8>) {
-		self.disk.init(data);
+	/// * `backend` Disk storage
+	#[cfg(feature = "virtio")]
+	pub fn init_disk(&mut self, backend: Box<dyn BlockBackend>) {
+		self.disk.init(backend);
 	}
 
-	/// Overrides defalut Device tree configuration.
+	/// Initializes Device tree configuration. Note that `Emulator` generates
+	/// and sets default one from its configuration.
 	///
 	/// # Arguments
 	/// * `data` DTB binary content
@@ -160,8 +369,14 @@ impl Mmu {
 		self.clear_page_cache();
 	}
 
+	/// Returns the address translation counters.
+	pub fn get_translation_stats(&self) -> &TranslationStats {
+		&self.translation_stats
+	}
+
 	/// Clears page cache entries
 	fn clear_page_cache(&mut self) {
+		self.translation_stats.page_cache_flushes += 1;
 		self.fetch_page_cache.clear();
 		self.load_page_cache.clear();
 		self.store_page_cache.clear();
@@ -169,18 +384,170 @@ impl Mmu {
 
 	/// Runs one cycle of MMU and peripheral devices.
 	pub fn tick(&mut self, mip: &mut u64) {
-		self.clint.tick(mip);
-		self.disk.tick(&mut self.memory);
-		self.uart.tick();
-		self.plic.tick(self.disk.is_interrupting(), self.uart.is_interrupting(), mip);
+		self.clint.advance(1);
+		self.tick_devices(mip);
 		self.clock = self.clock.wrapping_add(1);
 	}
 
+	/// Advances the clock and the CLINT timer by one cycle without
+	/// running the devices, for `Cpu::tick_batch()`.
+	pub fn advance_cycle(&mut self) {
+		self.clint.advance(1);
+		self.clock = self.clock.wrapping_add(1);
+	}
+
+	/// Runs the peripheral devices one cycle and raises their interrupts
+	/// without advancing the clock, for `Cpu::tick_batch()`.
+	///
+	/// # Arguments
+	/// * `mip` CPU `mip` register
+	pub fn tick_devices(&mut self, mip: &mut u64) {
+		self.clint.update_mip(mip);
+		self.sswi.tick(mip);
+		#[cfg(feature = "virtio")]
+		self.tick_virtio();
+		self.console.tick();
+		for serial_port in self.serial_ports.iter_mut() {
+			serial_port.tick();
+		}
+		self.gpio.tick();
+		self.pwm.tick();
+		self.framebuffer.tick();
+		self.update_interrupt_level(SHARED_MEMORY_IRQ, self.shared_memory.is_interrupting());
+		self.update_interrupt_level(GPIO_IRQ, self.gpio.is_interrupting());
+		for i in 0..self.serial_ports.len() {
+			self.update_interrupt_level(SERIAL_PORT_IRQ_BASE + i as u32, self.serial_ports[i].is_interrupting());
+		}
+		for i in 0..PWM_CMP_NUM {
+			self.update_interrupt_level(PWM_IRQ_BASE + i as u32, self.pwm.is_interrupting(i));
+		}
+		// NS16550A interrupt is edge-triggered while SiFive UART one is level-triggered
+		let console_ip = self.console.is_interrupting();
+		let uart_ip = match self.console {
+			Console::Sifive(_) => {
+				self.update_interrupt_level(SIFIVE_UART_IRQ, console_ip);
+				false
+			},
+			_ => console_ip
+		};
+		if self.host_irq_lines != 0 {
+			for irq in 1..MAX_IRQ {
+				if (self.host_irq_lines >> irq) & 1 == 1 {
+					self.update_interrupt_level(irq, (self.host_irq_levels >> irq) & 1 == 1);
+				}
+			}
+		}
+		let disk_ip = self.is_disk_interrupting();
+		match self.aia_enabled {
+			true => {
+				self.aplic.update_level(VIRTIO_IRQ, disk_ip);
+				self.aplic.update_level(UART_IRQ, uart_ip);
+				self.aplic.tick(&mut self.msis);
+				self.deliver_msis();
+				self.imsic.tick(mip);
+			},
+			false => {
+				self.deliver_msis();
+				self.plic.tick(disk_ip, uart_ip, mip);
+			}
+		};
+	}
+
+	// Returns whether an access has taken the slow path to the devices
+	// since the last call, and clears it
+	pub(crate) fn take_mmio_accessed(&mut self) -> bool {
+		let accessed = self.mmio_accessed;
+		self.mmio_accessed = false;
+		accessed
+	}
+
+	// Runs one cycle of virtio devices and updates their interrupt lines
+	// but the block disk one, which the interrupt controllers take directly
+	#[cfg(feature = "virtio")]
+	fn tick_virtio(&mut self) {
+		if let Err(error) = self.disk.tick(&mut self.memory) {
+			self.record_error(error);
+		}
+		self.net.tick(&mut self.memory);
+		self.snd.tick(&mut self.memory);
+		self.balloon.tick(&mut self.memory);
+		self.virtio_console.tick(&mut self.memory);
+		self.update_interrupt_level(VIRTIO_NET_IRQ, self.net.is_interrupting());
+		self.update_interrupt_level(VIRTIO_SND_IRQ, self.snd.is_interrupting());
+		self.update_interrupt_level(VIRTIO_BALLOON_IRQ, self.balloon.is_interrupting());
+		self.update_interrupt_level(VIRTIO_CONSOLE_IRQ, self.virtio_console.is_interrupting());
+	}
+
+	#[cfg(feature = "virtio")]
+	fn is_disk_interrupting(&mut self) -> bool {
+		self.disk.is_interrupting()
+	}
+
+	#[cfg(not(feature = "virtio"))]
+	fn is_disk_interrupting(&mut self) -> bool {
+		false
+	}
+
+	/// Signals a message-signaled interrupt. The message is written to
+	/// the bus in the next `tick()`, typically to an `Imsic` interrupt
+	/// file. Devices can signal interrupts with this instead of
+	/// the interrupt lines to `Plic` or `Aplic`.
+	///
+	/// # Arguments
+	/// * `msi`
+	pub fn send_msi(&mut self, msi: Msi) {
+		self.msis.push(msi);
+	}
+
+	/// Drives an interrupt line of `Plic` or `Aplic` from the host.
+	/// The level is kept and passed to the interrupt controller in
+	/// every `tick()` as "Level-triggered" interrupt signal until
+	/// it is updated again. Returns `Err` if `irq` is out of range or
+	/// is connected to a built-in device.
+	///
+	/// # Arguments
+	/// * `irq` Must be 1-63
+	/// * `level`
+	pub fn set_host_irq_level(&mut self, irq: u32, level: bool) -> Result<(), DeviceError> {
+		if irq == 0 || irq >= MAX_IRQ || is_device_irq(irq) {
+			return Err(DeviceError::UnavailableIrq(irq));
+		}
+		self.host_irq_lines |= 1 << irq;
+		match level {
+			true => self.host_irq_levels |= 1 << irq,
+			false => self.host_irq_levels &= !(1 << irq)
+		};
+		Ok(())
+	}
+
+	// Writes the pending MSIs to the bus. Messages to unmapped
+	// addresses are dropped.
+	fn deliver_msis(&mut self) {
+		if self.msis.is_empty() {
+			return;
+		}
+		for msi in mem::take(&mut self.msis) {
+			let effective_address = self.get_physical_address(msi.address);
+			if self.is_mapped_address(effective_address) {
+				self.store_word_raw(effective_address, msi.data);
+			}
+		}
+	}
+
+	// Passes a "Level-triggered" interrupt signal to the interrupt controller
+	fn update_interrupt_level(&mut self, irq: u32, level: bool) {
+		match self.aia_enabled {
+			true => self.aplic.update_level(irq, level),
+			false => self.plic.update_level(irq, level)
+		};
+	}
+
 	/// Updates addressing mode
 	///
 	/// # Arguments
 	/// * `new_addressing_mode`
 	pub fn update_addressing_mode(&mut self, new_addressing_mode: AddressingMode) {
+		debug!(target: "mmu", "Addressing mode {:?}", new_addressing_mode);
 		self.addressing_mode = new_addressing_mode;
 		self.clear_page_cache();
 	}
@@ -200,6 +567,12 @@ impl Mmu {
 	/// # Arguments
 	/// * `mstatus`
 	pub fn update_mstatus(&mut self, mstatus: u64) {
+		// MPRV, SUM, and MXR, and MPP while MPRV is set, change
+		// the translation
+		let changed = self.mstatus ^ mstatus;
+		if (changed & 0xe0000) != 0 || ((mstatus >> 17) & 1 == 1 && (changed & 0x1800) != 0) {
+			self.clear_page_cache();
+		}
 		self.mstatus = mstatus;
 	}
 
@@ -219,23 +592,47 @@ impl Mmu {
 		}
 	}
 
+	// Physical addresses are 34 bits in RV32, which Sv32 maps 32-bit
+	// virtual addresses to
+	fn get_physical_address(&self, address: u64) -> u64 {
+		match self.xlen {
+			Xlen::Bit32 => address & 0x3ffffffff,
+			Xlen::Bit64 => address
+		}
+	}
+
 	/// Fetches an instruction byte. This method takes virtual address
 	/// and translates into physical address inside.
 	///
 	/// # Arguments
 	/// * `v_address` Virtual address
 	fn fetch(&mut self, v_address: u64) -> Result<u8, Trap> {
-		match self.translate_address(v_address, &MemoryAccessType::Execute) {
+		let effective_address = self.get_effective_address(v_address);
+		match self.translate_address(effective_address, &MemoryAccessType::Execute) {
+			Ok(p_address) if self.is_shadow_stack_address(p_address) => Err(Trap {
+				trap_type: TrapType::InstructionAccessFault,
+				value: effective_address
+			}),
 			Ok(p_address) => Ok(self.load_raw(p_address)),
-			Err(()) => return Err(Trap {
+			Err(()) => Err(Trap {
 				trap_type: TrapType::InstructionPageFault,
-				value: v_address
+				value: effective_address
 			})
 		}
 	}
 
+	// Fetches two instruction bytes, translating each address
+	fn fetch_halfword(&mut self, v_address: u64) -> Result<u16, Trap> {
+		let lower = self.fetch(v_address)?;
+		let upper = self.fetch(v_address.wrapping_add(1))?;
+		Ok((lower as u16) | ((upper as u16) << 8))
+	}
+
 	/// Fetches instruction four bytes. This method takes virtual address
-	/// and translates into physical address inside.
+	/// and translates into physical address inside. An instruction across
+	/// pages is fetched in halfwords. Only the lower one is fetched if it's
+	/// a compressed instruction, so the next page may be unmapped, and
+	/// a fault of the upper one has the address of the upper one.
 	///
 	/// # Arguments
 	/// * `v_address` Virtual address
@@ -247,6 +644,10 @@ impl Mmu {
 				// translating an address only once.
 				let effective_address = self.get_effective_address(v_address);
 				match self.translate_address(effective_address, &MemoryAccessType::Execute) {
+					Ok(p_address) if self.is_shadow_stack_address(p_address) => Err(Trap {
+						trap_type: TrapType::InstructionAccessFault,
+						value: effective_address
+					}),
 					Ok(p_address) => Ok(self.load_word_raw(p_address)),
 					Err(()) => Err(Trap {
 						trap_type: TrapType::InstructionPageFault,
@@ -255,16 +656,11 @@ impl Mmu {
 				}
 			},
 			false => {
-				let mut data = 0 as u32;
-				for i in 0..width {
-					match self.fetch(v_address.wrapping_add(i)) {
-						Ok(byte) => {
-							data |= (byte as u32) << (i * 8)
-						},
-						Err(e) => return Err(e)
-					};
+				let lower = self.fetch_halfword(v_address)?;
+				match (lower & 0x3) == 0x3 {
+					true => Ok((lower as u32) | ((self.fetch_halfword(v_address.wrapping_add(2))? as u32) << 16)),
+					false => Ok(lower as u32)
 				}
-				Ok(data)
 			}
 		}
 	}
@@ -275,8 +671,23 @@ impl Mmu {
 	/// # Arguments
 	/// * `v_address` Virtual address
 	pub fn load(&mut self, v_address: u64) -> Result<u8, Trap> {
+		match self.load_byte(v_address) {
+			Ok(data) => {
+				self.watch(WatchpointType::Read, v_address, 1, data as u64);
+				Ok(data)
+			},
+			Err(e) => Err(e)
+		}
+	}
+
+	// `load()` without watchpoints
+	fn load_byte(&mut self, v_address: u64) -> Result<u8, Trap> {
 		let effective_address = self.get_effective_address(v_address);
 		match self.translate_address(effective_address, &MemoryAccessType::Read) {
+			Ok(p_address) if self.is_shadow_stack_address(p_address) => Err(Trap {
+				trap_type: TrapType::LoadAccessFault,
+				value: v_address
+			}),
 			Ok(p_address) => Ok(self.load_raw(p_address)),
 			Err(()) => Err(Trap {
 				trap_type: TrapType::LoadPageFault,
@@ -292,10 +703,22 @@ impl Mmu {
 	/// * `v_address` Virtual address
 	/// * `width` Must be 1, 2, 4, or 8
 	fn load_bytes(&mut self, v_address: u64, width: u64) -> Result<u64, Trap> {
+		let result = self.load_bytes_without_watch(v_address, width);
+		if let Ok(data) = result {
+			self.watch(WatchpointType::Read, v_address, width, data);
+		}
+		result
+	}
+
+	fn load_bytes_without_watch(&mut self, v_address: u64, width: u64) -> Result<u64, Trap> {
 		debug_assert!(width == 1 || width == 2 || width == 4 || width == 8,
 			"Width must be 1, 2, 4, or 8. {:X}", width);
 		match (v_address & 0xfff) <= (0x1000 - width) {
 			true => match self.translate_address(v_address, &MemoryAccessType::Read) {
+				Ok(p_address) if self.is_shadow_stack_address(p_address) => Err(Trap {
+					trap_type: TrapType::LoadAccessFault,
+					value: v_address
+				}),
 				Ok(p_address) => {
 					// Fast path. All bytes fetched are in the same page so
 					// translating an address only once.
@@ -315,7 +738,7 @@ impl Mmu {
 			false => {
 				let mut data = 0 as u64;
 				for i in 0..width {
-					match self.load(v_address.wrapping_add(i)) {
+					match self.load_byte(v_address.wrapping_add(i)) {
 						Ok(byte) => {
 							data |= (byte as u64) << (i * 8)
 						},
@@ -370,7 +793,20 @@ impl Mmu {
 	/// * `v_address` Virtual address
 	/// * `value`
 	pub fn store(&mut self, v_address: u64, value: u8) -> Result<(), Trap> {
+		let result = self.store_byte(v_address, value);
+		if result.is_ok() {
+			self.watch(WatchpointType::Write, v_address, 1, value as u64);
+		}
+		result
+	}
+
+	// `store()` without watchpoints
+	fn store_byte(&mut self, v_address: u64, value: u8) -> Result<(), Trap> {
 		match self.translate_address(v_address, &MemoryAccessType::Write) {
+			Ok(p_address) if self.is_shadow_stack_address(p_address) => Err(Trap {
+				trap_type: TrapType::StoreAccessFault,
+				value: v_address
+			}),
 			Ok(p_address) => {
 				self.store_raw(p_address, value);
 				Ok(())
@@ -390,10 +826,22 @@ impl Mmu {
 	/// * `value` data written
 	/// * `width` Must be 1, 2, 4, or 8
 	fn store_bytes(&mut self, v_address: u64, value: u64, width: u64) -> Result<(), Trap> {
+		let result = self.store_bytes_without_watch(v_address, value, width);
+		if result.is_ok() {
+			self.watch(WatchpointType::Write, v_address, width, value);
+		}
+		result
+	}
+
+	fn store_bytes_without_watch(&mut self, v_address: u64, value: u64, width: u64) -> Result<(), Trap> {
 		debug_assert!(width == 1 || width == 2 || width == 4 || width == 8,
 			"Width must be 1, 2, 4, or 8. {:X}", width);
 		match (v_address & 0xfff) <= (0x1000 - width) {
 			true => match self.translate_address(v_address, &MemoryAccessType::Write) {
+				Ok(p_address) if self.is_shadow_stack_address(p_address) => Err(Trap {
+					trap_type: TrapType::StoreAccessFault,
+					value: v_address
+				}),
 				Ok(p_address) => {
 					// Fast path. All bytes fetched are in the same page so
 					// translating an address only once.
@@ -413,7 +861,7 @@ impl Mmu {
 			},
 			false => {
 				for i in 0..width {
-					match self.store(v_address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8) {
+					match self.store_byte(v_address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8) {
 						Ok(()) => {},
 						Err(e) => return Err(e)
 					}
@@ -453,27 +901,364 @@ impl Mmu {
 		self.store_bytes(v_address, value as u64, 8)
 	}
 
+	/// Indicates whether the shadow stack region is configured. The shadow
+	/// stack instructions do nothing without it.
+	pub fn has_shadow_stack(&self) -> bool {
+		self.shadow_stack.is_some()
+	}
+
+	// Whether the physical address is in the shadow stack region, which
+	// the accesses other than the shadow stack ones fault on
+	fn is_shadow_stack_address(&self, p_address: u64) -> bool {
+		match self.shadow_stack {
+			Some((base, end)) => p_address >= base && p_address < end,
+			None => false
+		}
+	}
+
+	// Translates the address of a shadow stack access of XLEN bits. The
+	// access must be aligned and in the shadow stack region. Faults are
+	// reported as store/AMO ones even on loads, as in Zicfiss
+	fn translate_shadow_stack_address(&mut self, v_address: u64, access_type: &MemoryAccessType) -> Result<u64, Trap> {
+		let effective_address = self.get_effective_address(v_address);
+		let width = match self.xlen {
+			Xlen::Bit32 => 4,
+			Xlen::Bit64 => 8
+		};
+		match self.translate_address(effective_address, access_type) {
+			Ok(p_address) if effective_address.is_multiple_of(width) && self.is_shadow_stack_address(p_address) => Ok(p_address),
+			Ok(_) => Err(Trap {
+				trap_type: TrapType::StoreAccessFault,
+				value: effective_address
+			}),
+			Err(()) => Err(Trap {
+				trap_type: TrapType::StorePageFault,
+				value: effective_address
+			})
+		}
+	}
+
+	/// Loads XLEN bits from the shadow stack, for the shadow stack
+	/// instructions. This method takes virtual address and translates
+	/// into physical address inside.
+	///
+	/// # Arguments
+	/// * `v_address` Virtual address
+	pub fn load_shadow_stack(&mut self, v_address: u64) -> Result<u64, Trap> {
+		let p_address = self.translate_shadow_stack_address(v_address, &MemoryAccessType::Read)?;
+		Ok(match self.xlen {
+			Xlen::Bit32 => self.load_word_raw(p_address) as u64,
+			Xlen::Bit64 => self.load_doubleword_raw(p_address)
+		})
+	}
+
+	/// Stores XLEN bits to the shadow stack, for the shadow stack
+	/// instructions. This method takes virtual address and translates
+	/// into physical address inside.
+	///
+	/// # Arguments
+	/// * `v_address` Virtual address
+	/// * `value` data written
+	pub fn store_shadow_stack(&mut self, v_address: u64, value: u64) -> Result<(), Trap> {
+		let p_address = self.translate_shadow_stack_address(v_address, &MemoryAccessType::Write)?;
+		match self.xlen {
+			Xlen::Bit32 => self.store_word_raw(p_address, value as u32),
+			Xlen::Bit64 => self.store_doubleword_raw(p_address, value)
+		};
+		Ok(())
+	}
+
+	/// Adds a data watchpoint. Data accesses by instructions overlapping
+	/// the virtual address range are caught and can be taken with
+	/// `take_watchpoint_hit()`. Instruction fetches and accesses by
+	/// devices aren't caught.
+	///
+	/// # Arguments
+	/// * `watchpoint_type`
+	/// * `address` Virtual address
+	/// * `length` Range length in bytes
+	pub fn add_watchpoint(&mut self, watchpoint_type: WatchpointType, address: u64, length: u64) {
+		self.watchpoints.push(Watchpoint {
+			watchpoint_type,
+			address,
+			length
+		});
+	}
+
+	/// Returns whether a data watchpoint is added with the same arguments.
+	///
+	/// # Arguments
+	/// * `watchpoint_type`
+	/// * `address` Virtual address
+	/// * `length` Range length in bytes
+	pub fn has_watchpoint(&self, watchpoint_type: WatchpointType, address: u64, length: u64) -> bool {
+		self.watchpoints.iter().any(|watchpoint| {
+			(watchpoint.watchpoint_type, watchpoint.address, watchpoint.length) == (watchpoint_type, address, length)
+		})
+	}
+
+	/// Removes the data watchpoints added with the same arguments.
+	/// Returns `Err` if no such watchpoint is found.
+	///
+	/// # Arguments
+	/// * `watchpoint_type`
+	/// * `address` Virtual address
+	/// * `length` Range length in bytes
+	pub fn remove_watchpoint(&mut self, watchpoint_type: WatchpointType, address: u64, length: u64) -> Result<(), DebugError> {
+		let num = self.watchpoints.len();
+		self.watchpoints.retain(|watchpoint| {
+			(watchpoint.watchpoint_type, watchpoint.address, watchpoint.length) != (watchpoint_type, address, length)
+		});
+		match self.watchpoints.len() < num {
+			true => Ok(()),
+			false => Err(DebugError::NoWatchpoint {
+				address,
+				length
+			})
+		}
+	}
+
+	/// Takes the first data access caught by the watchpoints since
+	/// the last call.
+	pub fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+		self.watchpoint_hit.take()
+	}
+
+	/// Adds a value watch calling back when a store changes the eight
+	/// bytes of main memory at the physical address and the condition
+	/// meets. It's evaluated on the store path rather than by polling,
+	/// e.g. for a guest signaling the host through a mailbox like
+	/// `tohost`. Stores by instructions and the host through `Mmu` are
+	/// watched but DMA by virtio devices isn't. Stores split at page
+	/// boundaries are evaluated per byte. Returns the id to remove
+	/// the watch with.
+	///
+	/// # Arguments
+	/// * `address` Physical address
+	/// * `condition`
+	/// * `callback`
+	pub fn add_value_watch(&mut self, address: u64, condition: ValueWatchCondition, callback: ValueWatchCallback) -> usize {
+		let id = self.next_value_watch_id;
+		self.next_value_watch_id += 1;
+		let value = self.read_watched_value(address);
+		self.value_watches.push(ValueWatch {
+			id,
+			address,
+			condition,
+			value,
+			callback
+		});
+		id
+	}
+
+	/// Removes the value watch. Returns `Err` if no such watch is found.
+	///
+	/// # Arguments
+	/// * `id` Returned by `add_value_watch()`
+	pub fn remove_value_watch(&mut self, id: usize) -> Result<(), DebugError> {
+		let num = self.value_watches.len();
+		self.value_watches.retain(|watch| watch.id != id);
+		match self.value_watches.len() < num {
+			true => Ok(()),
+			false => Err(DebugError::NoValueWatch(id))
+		}
+	}
+
+	// Calls back the value watches overlapping the main memory just stored
+	// whose value has changed and meets the condition
+	fn check_value_watches(&mut self, effective_address: u64, width: u64) {
+		if self.value_watches.is_empty() {
+			return;
+		}
+		let end = effective_address.wrapping_add(width);
+		for i in 0..self.value_watches.len() {
+			let address = self.value_watches[i].address;
+			if effective_address >= address.wrapping_add(8) || address >= end {
+				continue;
+			}
+			let value = self.read_watched_value(address);
+			let watch = &mut self.value_watches[i];
+			if value == watch.value {
+				continue;
+			}
+			let old_value = watch.value;
+			watch.value = value;
+			let met = match watch.condition {
+				ValueWatchCondition::Changed => true,
+				ValueWatchCondition::Equals(expected) => value == expected
+			};
+			if met {
+				(watch.callback)(old_value, value);
+			}
+		}
+	}
+
+	// Reads the eight bytes a value watch watches, zero out of main memory
+	fn read_watched_value(&mut self, address: u64) -> u64 {
+		match self.memory.contains(address, 8) {
+			true => self.memory.read_doubleword(address),
+			false => 0
+		}
+	}
+
+	/// Returns the first internal error of the MMU or devices since the last
+	/// call, if any. `Cpu::take_error()` includes this.
+	pub fn take_error(&mut self) -> Option<ExecError> {
+		self.error.take()
+	}
+
+	// Keeps the error unless another is kept
+	fn record_error(&mut self, error: ExecError) {
+		debug!(target: "mmu", "{}", error);
+		if self.error.is_none() {
+			self.error = Some(error);
+		}
+	}
+
+	// Records the access if a watchpoint catches it
+	fn watch(&mut self, watchpoint_type: WatchpointType, v_address: u64, width: u64, value: u64) {
+		if self.watchpoints.is_empty() || self.watchpoint_hit.is_some() {
+			return;
+		}
+		let end = v_address.wrapping_add(width);
+		let caught = self.watchpoints.iter().any(|watchpoint| {
+			watchpoint.watchpoint_type == watchpoint_type &&
+				v_address < watchpoint.address.wrapping_add(watchpoint.length) &&
+				watchpoint.address < end
+		});
+		if caught {
+			self.watchpoint_hit = Some(WatchpointHit {
+				watchpoint_type,
+				address: v_address,
+				width,
+				value
+			});
+		}
+	}
+
+	/// Sets the log recording the device register accesses from now.
+	///
+	/// # Arguments
+	/// * `mmio_log`
+	pub fn set_mmio_log(&mut self, mmio_log: MmioLog) {
+		self.mmio_log = Some(mmio_log);
+	}
+
+	/// Returns the mutable reference to the MMIO log if set.
+	pub fn get_mut_mmio_log(&mut self) -> Option<&mut MmioLog> {
+		self.mmio_log.as_mut()
+	}
+
+	/// Removes the MMIO log and returns it if set.
+	pub fn take_mmio_log(&mut self) -> Option<MmioLog> {
+		self.mmio_log.take()
+	}
+
+	/// Tells the MMIO log the address of the instruction being executed
+	/// if the log is set.
+	///
+	/// # Arguments
+	/// * `pc`
+	pub fn update_mmio_log_pc(&mut self, pc: u64) {
+		if let Some(mmio_log) = self.mmio_log.as_mut() {
+			mmio_log.update_pc(pc);
+		}
+	}
+
+	// Records the access to the MMIO log if it's to a device
+	fn log_mmio(&mut self, p_address: u64, width: u64, access_type: MmioAccessType, value: u64) {
+		if self.mmio_log.is_none() {
+			return;
+		}
+		let effective_address = self.get_physical_address(p_address);
+		if effective_address >= DRAM_BASE {
+			return;
+		}
+		if let Some((device, base)) = self.get_mmio_device(effective_address) {
+			if let Some(mmio_log) = self.mmio_log.as_mut() {
+				mmio_log.record(device, effective_address - base, width, access_type, value);
+			}
+		}
+	}
+
+	// Returns the device mapped to the physical address below DRAM and
+	// its base address, the same as `load_raw()` maps
+	fn get_mmio_device(&self, effective_address: u64) -> Option<(MmioDevice, u64)> {
+		match effective_address {
+			0x02000000..=0x0200ffff => Some((MmioDevice::Clint, 0x02000000)),
+			_ if !self.platform_devices_enabled => None,
+			_ if self.test_finisher.contains(effective_address) => Some((MmioDevice::TestFinisher, TEST_FINISHER_BASE)),
+			_ if self.aclint_enabled && self.sswi.contains(effective_address) => Some((MmioDevice::Sswi, SSWI_BASE)),
+			0x0C000000..=0x0fffffff if !self.aia_enabled => Some((MmioDevice::Plic, 0x0c000000)),
+			_ if self.aia_enabled && self.aplic.contains(effective_address) => Some((MmioDevice::Aplic, match effective_address >= APLIC_SUPERVISOR_BASE {
+				true => APLIC_SUPERVISOR_BASE,
+				false => APLIC_MACHINE_BASE
+			})),
+			_ if self.aia_enabled && self.imsic.contains(effective_address) => Some((MmioDevice::Imsic, match effective_address >= IMSIC_SUPERVISOR_BASE {
+				true => IMSIC_SUPERVISOR_BASE,
+				false => IMSIC_MACHINE_BASE
+			})),
+			_ if self.console.contains(effective_address) => Some((MmioDevice::Console, match self.console {
+				Console::Ns16550a(_) => UART_BASE,
+				Console::Sifive(_) => SIFIVE_UART_BASE
+			})),
+			#[cfg(feature = "virtio")]
+			0x10001000..=0x10001FFF => Some((MmioDevice::VirtioBlock, 0x10001000)),
+			#[cfg(feature = "virtio")]
+			0x10002000..=0x10002FFF => Some((MmioDevice::VirtioNet, 0x10002000)),
+			#[cfg(feature = "virtio")]
+			0x10003000..=0x10003FFF => Some((MmioDevice::VirtioSound, 0x10003000)),
+			#[cfg(feature = "virtio")]
+			0x10004000..=0x10004FFF => Some((MmioDevice::VirtioBalloon, 0x10004000)),
+			#[cfg(feature = "virtio")]
+			0x10007000..=0x10007FFF => Some((MmioDevice::VirtioConsole, 0x10007000)),
+			_ if self.is_serial_port_address(effective_address) => {
+				let index = (effective_address - SERIAL_PORT_BASE) / UART_SIZE;
+				Some((MmioDevice::SerialPort(index as usize), SERIAL_PORT_BASE + index * UART_SIZE))
+			},
+			0x10020000..=0x100200ff => Some((MmioDevice::Pwm, 0x10020000)),
+			0x10060000..=0x100600ff => Some((MmioDevice::Gpio, 0x10060000)),
+			_ if self.framebuffer.contains(effective_address) => Some((MmioDevice::Framebuffer, FRAMEBUFFER_BASE)),
+			_ if self.shared_memory.contains(effective_address) => Some((MmioDevice::SharedMemory, SHARED_MEMORY_BASE)),
+			_ if self.shared_memory.contains_registers(effective_address) => Some((MmioDevice::SharedMemory, SHARED_MEMORY_REGISTERS_BASE)),
+			_ => None
+		}
+	}
+
+	/// Starts recording the old values of main memory bytes stored by
+	/// the CPU. Stores to peripheral devices and by devices aren't recorded.
+	pub fn start_store_log(&mut self) {
+		self.store_log = Some(vec![]);
+	}
+
+	/// Stops recording and returns the physical addresses stored since
+	/// `start_store_log()` and their old values in the store order.
+	/// An address can appear more than once.
+	pub fn take_store_log(&mut self) -> Vec<(u64, u8)> {
+		self.store_log.take().unwrap_or_default()
+	}
+
+	// Records the old values of main memory bytes about to be stored
+	fn log_store(&mut self, effective_address: u64, width: u64) {
+		if let Some(log) = self.store_log.as_mut() {
+			for i in 0..width {
+				let address = effective_address.wrapping_add(i);
+				log.push((address, self.memory.read_byte(address)));
+			}
+		}
+	}
+
 	/// Loads a byte from main memory or peripheral devices depending on
 	/// physical address.
 	///
 	/// # Arguments
 	/// * `p_address` Physical address
-	fn load_raw(&mut self, p_address: u64) -> u8 {
-		let effective_address = self.get_effective_address(p_address);
-		// @TODO: Mapping should be configurable with dtb
-		match effective_address >= DRAM_BASE {
+	pub fn load_raw(&mut self, p_address: u64) -> u8 {
+		let effective_address = self.get_physical_address(p_address);
+		match self.memory.contains(effective_address, 1) {
+			// Fast path. Directly load main memory at a time.
 			true => self.memory.read_byte(effective_address),
-			false => match effective_address {
-				// I don't know why but dtb data seems to be stored from 0x1020 on Linux.
-				// It might be from self.x[0xb] initialization?
-				// And DTB size is arbitray.
-				0x00001020..=0x00001fff => self.dtb[effective_address as usize - 0x1020],
-				0x02000000..=0x0200ffff => self.clint.load(effective_address),
-				0x0C000000..=0x0fffffff => self.plic.load(effective_address),
-				0x10000000..=0x100000ff => self.uart.load(effective_address),
-				0x10001000..=0x10001FFF => self.disk.load(effective_address),
-				_ => panic!("Unknown memory mapping {:X}.", effective_address)
-			}
+			false => self.load_mmio(effective_address, 1) as u8
 		}
 	}
 
@@ -483,17 +1268,11 @@ impl Mmu {
 	/// # Arguments
 	/// * `p_address` Physical address
 	fn load_halfword_raw(&mut self, p_address: u64) -> u16 {
-		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE && effective_address.wrapping_add(1) > effective_address {
+		let effective_address = self.get_physical_address(p_address);
+		match self.memory.contains(effective_address, 2) {
 			// Fast path. Directly load main memory at a time.
 			true => self.memory.read_halfword(effective_address),
-			false => {
-				let mut data = 0 as u16;
-				for i in 0..2 {
-					data |= (self.load_raw(effective_address.wrapping_add(i)) as u16) << (i * 8)
-				}
-				data
-			}
+			false => self.load_mmio(effective_address, 2) as u16
 		}
 	}
 
@@ -503,17 +1282,11 @@ impl Mmu {
 	/// # Arguments
 	/// * `p_address` Physical address
 	pub fn load_word_raw(&mut self, p_address: u64) -> u32 {
-		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE && effective_address.wrapping_add(3) > effective_address {
+		let effective_address = self.get_physical_address(p_address);
+		match self.memory.contains(effective_address, 4) {
 			// Fast path. Directly load main memory at a time.
 			true => self.memory.read_word(effective_address),
-			false => {
-				let mut data = 0 as u32;
-				for i in 0..4 {
-					data |= (self.load_raw(effective_address.wrapping_add(i)) as u32) << (i * 8)
-				}
-				data
-			}
+			false => self.load_mmio(effective_address, 4) as u32
 		}
 	}
 
@@ -522,17 +1295,78 @@ impl Mmu {
 	///
 	/// # Arguments
 	/// * `p_address` Physical address
-	fn load_doubleword_raw(&mut self, p_address: u64) -> u64 {
-		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE && effective_address.wrapping_add(7) > effective_address {
+	pub fn load_doubleword_raw(&mut self, p_address: u64) -> u64 {
+		let effective_address = self.get_physical_address(p_address);
+		match self.memory.contains(effective_address, 8) {
 			// Fast path. Directly load main memory at a time.
 			true => self.memory.read_doubleword(effective_address),
-			false => {
-				let mut data = 0 as u64;
-				for i in 0..8 {
-					data |= (self.load_raw(effective_address.wrapping_add(i)) as u64) << (i * 8)
+			false => self.load_mmio(effective_address, 8)
+		}
+	}
+
+	// Slow path of the loads out of main memory, byte by byte through
+	// the device dispatch. Records the access to the MMIO log
+	fn load_mmio(&mut self, effective_address: u64, width: u64) -> u64 {
+		self.mmio_accessed = true;
+		let mut data = 0;
+		for i in 0..width {
+			data |= (self.load_raw_without_log(effective_address.wrapping_add(i)) as u64) << (i * 8);
+		}
+		self.log_mmio(effective_address, width, MmioAccessType::Read, data);
+		data
+	}
+
+	// Loads a byte from the device mapped to the physical address, or
+	// main memory. The slow path for MMIO without the MMIO log
+	fn load_raw_without_log(&mut self, p_address: u64) -> u8 {
+		let effective_address = self.get_physical_address(p_address);
+		// @TODO: Mapping should be configurable with dtb
+		match effective_address >= DRAM_BASE {
+			true => match self.memory.validate_address(effective_address) {
+				true => self.memory.read_byte(effective_address),
+				false => {
+					self.record_error(ExecError::Memory(MemoryError::Unmapped(effective_address)));
+					0
+				}
+			},
+			false => match effective_address {
+				// I don't know why but dtb data seems to be stored from 0x1020 on Linux.
+				// It might be from self.x[0xb] initialization?
+				// And DTB size is arbitray.
+				0x00001020..=0x00001fff => self.dtb[effective_address as usize - 0x1020],
+				0x02000000..=0x0200ffff => self.clint.load(effective_address),
+				_ if !self.platform_devices_enabled => {
+					self.record_error(ExecError::Memory(MemoryError::Unmapped(effective_address)));
+					0
+				},
+				_ if self.test_finisher.contains(effective_address) => self.test_finisher.load(effective_address),
+				_ if self.aclint_enabled && self.sswi.contains(effective_address) => self.sswi.load(effective_address),
+				0x0C000000..=0x0fffffff if !self.aia_enabled => self.plic.load(effective_address),
+				_ if self.aia_enabled && self.aplic.contains(effective_address) => self.aplic.load(effective_address),
+				_ if self.aia_enabled && self.imsic.contains(effective_address) => self.imsic.load(effective_address),
+				_ if self.console.contains(effective_address) => self.console.load(effective_address),
+				#[cfg(feature = "virtio")]
+				0x10001000..=0x10001FFF => self.disk.load(effective_address),
+				#[cfg(feature = "virtio")]
+				0x10002000..=0x10002FFF => self.net.load(effective_address),
+				#[cfg(feature = "virtio")]
+				0x10003000..=0x10003FFF => self.snd.load(effective_address),
+				#[cfg(feature = "virtio")]
+				0x10004000..=0x10004FFF => self.balloon.load(effective_address),
+				#[cfg(feature = "virtio")]
+				0x10007000..=0x10007FFF => self.virtio_console.load(effective_address),
+				_ if self.is_serial_port_address(effective_address) => {
+					self.serial_ports[((effective_address - SERIAL_PORT_BASE) / UART_SIZE) as usize].load(effective_address)
+				},
+				0x10020000..=0x100200ff => self.pwm.load(effective_address),
+				0x10060000..=0x100600ff => self.gpio.load(effective_address),
+				_ if self.framebuffer.contains(effective_address) => self.framebuffer.load(effective_address),
+				_ if self.shared_memory.contains(effective_address) ||
+					self.shared_memory.contains_registers(effective_address) => self.shared_memory.load(effective_address),
+				_ => {
+					self.record_error(ExecError::Memory(MemoryError::Unmapped(effective_address)));
+					0
 				}
-				data
 			}
 		}
 	}
@@ -544,18 +1378,16 @@ impl Mmu {
 	/// * `p_address` Physical address
 	/// * `value` data written
 	pub fn store_raw(&mut self, p_address: u64, value: u8) {
-		let effective_address = self.get_effective_address(p_address);
-		// @TODO: Mapping should be configurable with dtb
-		match effective_address >= DRAM_BASE {
-			true => self.memory.write_byte(effective_address, value),
-			false => match effective_address {
-				0x02000000..=0x0200ffff => self.clint.store(effective_address, value),
-				0x0c000000..=0x0fffffff => self.plic.store(effective_address, value),
-				0x10000000..=0x100000ff => self.uart.store(effective_address, value),
-				0x10001000..=0x10001FFF => self.disk.store(effective_address, value),
-				_ => panic!("Unknown memory mapping {:X}.", effective_address)
-			}
+		let effective_address = self.get_physical_address(p_address);
+		match self.memory.contains(effective_address, 1) {
+			// Fast path. Directly store to main memory at a time.
+			true => {
+				self.log_store(effective_address, 1);
+				self.memory.write_byte(effective_address, value);
+			},
+			false => self.store_mmio(effective_address, value as u64, 1)
 		};
+		self.check_value_watches(effective_address, 1);
 	}
 
 	/// Stores two bytes to main memory or peripheral devices depending on
@@ -565,16 +1397,16 @@ impl Mmu {
 	/// * `p_address` Physical address
 	/// * `value` data written
 	fn store_halfword_raw(&mut self, p_address: u64, value: u16) {
-		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE && effective_address.wrapping_add(1) > effective_address {
+		let effective_address = self.get_physical_address(p_address);
+		match self.memory.contains(effective_address, 2) {
 			// Fast path. Directly store to main memory at a time.
-			true => self.memory.write_halfword(effective_address, value),
-			false => {
-				for i in 0..2 {
-					self.store_raw(effective_address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8);
-				}
-			}
-		}
+			true => {
+				self.log_store(effective_address, 2);
+				self.memory.write_halfword(effective_address, value);
+			},
+			false => self.store_mmio(effective_address, value as u64, 2)
+		};
+		self.check_value_watches(effective_address, 2);
 	}
 
 	/// Stores four bytes to main memory or peripheral devices depending on
@@ -584,16 +1416,16 @@ impl Mmu {
 	/// * `p_address` Physical address
 	/// * `value` data written
 	fn store_word_raw(&mut self, p_address: u64, value: u32) {
-		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE && effective_address.wrapping_add(3) > effective_address {
+		let effective_address = self.get_physical_address(p_address);
+		match self.memory.contains(effective_address, 4) {
 			// Fast path. Directly store to main memory at a time.
-			true => self.memory.write_word(effective_address, value),
-			false => {
-				for i in 0..4 {
-					self.store_raw(effective_address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8);
-				}
-			}
-		}
+			true => {
+				self.log_store(effective_address, 4);
+				self.memory.write_word(effective_address, value);
+			},
+			false => self.store_mmio(effective_address, value as u64, 4)
+		};
+		self.check_value_watches(effective_address, 4);
 	}
 
 	/// Stores eight bytes to main memory or peripheral devices depending on
@@ -602,43 +1434,190 @@ impl Mmu {
 	/// # Arguments
 	/// * `p_address` Physical address
 	/// * `value` data written
-	fn store_doubleword_raw(&mut self, p_address: u64, value: u64) {
-		let effective_address = self.get_effective_address(p_address);
-		match effective_address >= DRAM_BASE && effective_address.wrapping_add(7) > effective_address {
+	pub fn store_doubleword_raw(&mut self, p_address: u64, value: u64) {
+		let effective_address = self.get_physical_address(p_address);
+		match self.memory.contains(effective_address, 8) {
 			// Fast path. Directly store to main memory at a time.
-			true => self.memory.write_doubleword(effective_address, value),
-			false => {
-				for i in 0..8 {
-					self.store_raw(effective_address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8);
-				}
-			}
+			true => {
+				self.log_store(effective_address, 8);
+				self.memory.write_doubleword(effective_address, value);
+			},
+			false => self.store_mmio(effective_address, value, 8)
+		};
+		self.check_value_watches(effective_address, 8);
+	}
+
+	// Slow path of the stores out of main memory, byte by byte through
+	// the device dispatch. Records the access to the MMIO log
+	fn store_mmio(&mut self, effective_address: u64, value: u64, width: u64) {
+		self.mmio_accessed = true;
+		for i in 0..width {
+			self.store_raw_without_log(effective_address.wrapping_add(i), ((value >> (i * 8)) & 0xff) as u8);
 		}
+		self.log_mmio(effective_address, width, MmioAccessType::Write, value);
+	}
+
+	// Stores a byte to the device mapped to the physical address, or
+	// main memory. The slow path for MMIO without the MMIO log
+	fn store_raw_without_log(&mut self, p_address: u64, value: u8) {
+		let effective_address = self.get_physical_address(p_address);
+		// @TODO: Mapping should be configurable with dtb
+		match effective_address >= DRAM_BASE {
+			true => match self.memory.validate_address(effective_address) {
+				true => {
+					self.log_store(effective_address, 1);
+					self.memory.write_byte(effective_address, value);
+				},
+				false => self.record_error(ExecError::Memory(MemoryError::Unmapped(effective_address)))
+			},
+			false => match effective_address {
+				0x02000000..=0x0200ffff => self.clint.store(effective_address, value),
+				_ if !self.platform_devices_enabled => self.record_error(ExecError::Memory(MemoryError::Unmapped(effective_address))),
+				_ if self.test_finisher.contains(effective_address) => self.test_finisher.store(effective_address, value),
+				_ if self.aclint_enabled && self.sswi.contains(effective_address) => self.sswi.store(effective_address, value),
+				0x0c000000..=0x0fffffff if !self.aia_enabled => self.plic.store(effective_address, value),
+				_ if self.aia_enabled && self.aplic.contains(effective_address) => self.aplic.store(effective_address, value),
+				_ if self.aia_enabled && self.imsic.contains(effective_address) => self.imsic.store(effective_address, value),
+				_ if self.console.contains(effective_address) => self.console.store(effective_address, value),
+				#[cfg(feature = "virtio")]
+				0x10001000..=0x10001FFF => self.disk.store(effective_address, value),
+				#[cfg(feature = "virtio")]
+				0x10002000..=0x10002FFF => self.net.store(effective_address, value),
+				#[cfg(feature = "virtio")]
+				0x10003000..=0x10003FFF => self.snd.store(effective_address, value),
+				#[cfg(feature = "virtio")]
+				0x10004000..=0x10004FFF => self.balloon.store(effective_address, value),
+				#[cfg(feature = "virtio")]
+				0x10007000..=0x10007FFF => self.virtio_console.store(effective_address, value),
+				_ if self.is_serial_port_address(effective_address) => {
+					self.serial_ports[((effective_address - SERIAL_PORT_BASE) / UART_SIZE) as usize].store(effective_address, value)
+				},
+				0x10020000..=0x100200ff => self.pwm.store(effective_address, value),
+				0x10060000..=0x100600ff => self.gpio.store(effective_address, value),
+				_ if self.framebuffer.contains(effective_address) => self.framebuffer.store(effective_address, value),
+				_ if self.shared_memory.contains(effective_address) ||
+					self.shared_memory.contains_registers(effective_address) => self.shared_memory.store(effective_address, value),
+				_ => self.record_error(ExecError::Memory(MemoryError::Unmapped(effective_address)))
+			}
+		};
 	}
 
 	/// Checks if passed virtual address is valid (pointing a certain device) or not.
-	/// This method can return page fault trap.
+	/// Returns `MemoryError::PageFault` if the address can't be translated.
 	///
 	/// # Arguments
 	/// * `v_address` Virtual address
-	pub fn validate_address(&mut self, v_address: u64) -> Result<bool, ()> {
+	pub fn validate_address(&mut self, v_address: u64) -> Result<bool, MemoryError> {
 		// @TODO: Support other access types?
 		let p_address = match self.translate_address(v_address, &MemoryAccessType::DontCare) {
 			Ok(address) => address,
-			Err(()) => return Err(())
+			Err(()) => return Err(MemoryError::PageFault(v_address))
 		};
-		let effective_address = self.get_effective_address(p_address);
-		let valid = match effective_address >= DRAM_BASE {
+		let effective_address = self.get_physical_address(p_address);
+		Ok(self.is_mapped_address(effective_address))
+	}
+
+	/// Reads four or eight bytes of main memory at the virtual address for
+	/// debuggers and profilers, without catching watchpoints or touching
+	/// devices. Returns `None` if the address is misaligned or isn't mapped
+	/// to main memory. Address translation can set the accessed bit of
+	/// the page table entry.
+	///
+	/// # Arguments
+	/// * `v_address` Virtual address
+	/// * `width` 4 or 8
+	pub fn peek(&mut self, v_address: u64, width: u64) -> Option<u64> {
+		if !v_address.is_multiple_of(width) {
+			return None;
+		}
+		let p_address = self.translate_address(v_address, &MemoryAccessType::DontCare).ok()?;
+		let effective_address = self.get_physical_address(p_address);
+		if !self.memory.contains(effective_address, width) {
+			return None;
+		}
+		match width {
+			4 => Some(self.memory.read_word(effective_address) as u64),
+			_ => Some(self.memory.read_doubleword(effective_address))
+		}
+	}
+
+	/// Reads main memory at the physical address for external tools,
+	/// without touching devices or catching watchpoints. Returns `Err` if
+	/// the range isn't in main memory.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `data` Buffer filled with the bytes
+	pub fn read_main_memory(&mut self, p_address: u64, data: &mut [u8]) -> Result<(), MemoryError> {
+		let size = data.len() as u64;
+		let last = p_address.checked_add(size.saturating_sub(1));
+		match last {
+			Some(last) if p_address >= DRAM_BASE && (size == 0 || self.memory.validate_address(last)) => {},
+			_ => return Err(MemoryError::OutOfRange {
+				address: p_address,
+				size
+			})
+		};
+		for (i, byte) in data.iter_mut().enumerate() {
+			*byte = self.memory.read_byte(p_address + i as u64);
+		}
+		Ok(())
+	}
+
+	/// Writes main memory at the physical address at once, e.g. to load
+	/// a program, without touching devices or catching watchpoints.
+	/// Returns `Err` without writing if the range isn't in main memory.
+	///
+	/// # Arguments
+	/// * `p_address` Physical address
+	/// * `data` Bytes written
+	pub fn write_main_memory(&mut self, p_address: u64, data: &[u8]) -> Result<(), MemoryError> {
+		let size = data.len() as u64;
+		let last = p_address.checked_add(size.saturating_sub(1));
+		match last {
+			Some(last) if p_address >= DRAM_BASE && (size == 0 || self.memory.validate_address(last)) => {},
+			_ => return Err(MemoryError::OutOfRange {
+				address: p_address,
+				size
+			})
+		};
+		self.memory.write_slice(p_address, data);
+		Ok(())
+	}
+
+	// Indicates whether main memory or a device is mapped to the physical address
+	fn is_mapped_address(&self, effective_address: u64) -> bool {
+		match effective_address >= DRAM_BASE {
 			true => self.memory.validate_address(effective_address),
 			false => match effective_address {
 				0x00001020..=0x00001fff => true,
 				0x02000000..=0x0200ffff => true,
-				0x0C000000..=0x0fffffff => true,
-				0x10000000..=0x100000ff => true,
+				_ if !self.platform_devices_enabled => false,
+				_ if self.test_finisher.contains(effective_address) => true,
+				_ if self.aclint_enabled && self.sswi.contains(effective_address) => true,
+				0x0C000000..=0x0fffffff if !self.aia_enabled => true,
+				_ if self.aia_enabled && self.aplic.contains(effective_address) => true,
+				_ if self.aia_enabled && self.imsic.contains(effective_address) => true,
+				_ if self.console.contains(effective_address) => true,
+				#[cfg(feature = "virtio")]
 				0x10001000..=0x10001FFF => true,
+				#[cfg(feature = "virtio")]
+				0x10002000..=0x10002FFF => true,
+				#[cfg(feature = "virtio")]
+				0x10003000..=0x10003FFF => true,
+				#[cfg(feature = "virtio")]
+				0x10004000..=0x10004FFF => true,
+				#[cfg(feature = "virtio")]
+				0x10007000..=0x10007FFF => true,
+				_ if self.is_serial_port_address(effective_address) => true,
+				0x10020000..=0x100200ff => true,
+				0x10060000..=0x100600ff => true,
+				_ if self.framebuffer.contains(effective_address) => true,
+				_ if self.shared_memory.contains(effective_address) => true,
+				_ if self.shared_memory.contains_registers(effective_address) => true,
 				_ => false
 			}
-		};
-		Ok(valid)
+		}
 	}
 
 	fn translate_address(&mut self, v_address: u64, access_type: &MemoryAccessType) -> Result<u64, ()> {
@@ -654,69 +1633,42 @@ impl Mmu {
 			false => None
 		};
 		match cache {
-			Some(p_page) => Ok(p_page | (address & 0xfff)),
+			Some(p_page) => {
+				self.translation_stats.page_cache_hits += 1;
+				Ok(p_page | (address & 0xfff))
+			},
 			None => {
+				if self.page_cache_enabled {
+					self.translation_stats.page_cache_misses += 1;
+				}
+				// Loads and stores of Machine mode with MPRV are translated
+				// as MPP, without changing the privilege mode
+				let privilege_mode = match (&self.privilege_mode, access_type, (self.mstatus >> 17) & 1) {
+					(PrivilegeMode::Machine, MemoryAccessType::Execute, _) | (PrivilegeMode::Machine, _, 0) => PrivilegeMode::Machine,
+					(PrivilegeMode::Machine, _, _) => get_privilege_mode((self.mstatus >> 11) & 3),
+					(privilege_mode, _, _) => privilege_mode.clone()
+				};
 				let p_address = match self.addressing_mode {
 					AddressingMode::None => Ok(address),
-					AddressingMode::SV32 => match self.privilege_mode {
-						// @TODO: Optimize
-						PrivilegeMode::Machine => match access_type {
-							MemoryAccessType::Execute => Ok(address),
-							// @TODO: Remove magic number
-							_ => match (self.mstatus >> 17) & 1 {
-								0 => Ok(address),
-								_ => {
-									let privilege_mode = get_privilege_mode((self.mstatus >> 9) & 3);
-									match privilege_mode {
-										PrivilegeMode::Machine => Ok(address),
-										_ => {
-											let current_privilege_mode = self.privilege_mode.clone();
-											self.update_privilege_mode(privilege_mode);
-											let result = self.translate_address(v_address, access_type);
-											self.update_privilege_mode(current_privilege_mode);
-											result
-										}
-									}
-								}
-							}
-						},
+					AddressingMode::SV32 => match privilege_mode {
 						PrivilegeMode::User | PrivilegeMode::Supervisor => {
 							let vpns = [(address >> 12) & 0x3ff, (address >> 22) & 0x3ff];
-							self.traverse_page(address, 2 - 1, self.ppn, &vpns, &access_type)
+							self.translation_stats.page_walks += 1;
+							self.traverse_page(address, 2 - 1, self.ppn, &vpns, access_type, &privilege_mode)
 						},
 						_ => Ok(address)
 					},
-					AddressingMode::SV39 => match self.privilege_mode {
-						// @TODO: Optimize
-						// @TODO: Remove duplicated code with SV32
-						PrivilegeMode::Machine => match access_type {
-							MemoryAccessType::Execute => Ok(address),
-							// @TODO: Remove magic number
-							_ => match (self.mstatus >> 17) & 1 {
-								0 => Ok(address),
-								_ => {
-									let privilege_mode = get_privilege_mode((self.mstatus >> 9) & 3);
-									match privilege_mode {
-										PrivilegeMode::Machine => Ok(address),
-										_ => {
-											let current_privilege_mode = self.privilege_mode.clone();
-											self.update_privilege_mode(privilege_mode);
-											let result = self.translate_address(v_address, access_type);
-											self.update_privilege_mode(current_privilege_mode);
-											result
-										}
-									}
-								}
-							}
-						},
+					AddressingMode::SV39 => match privilege_mode {
 						PrivilegeMode::User | PrivilegeMode::Supervisor => {
 							let vpns = [(address >> 12) & 0x1ff, (address >> 21) & 0x1ff, (address >> 30) & 0x1ff];
-							self.traverse_page(address, 3 - 1, self.ppn, &vpns, &access_type)
+							self.translation_stats.page_walks += 1;
+							self.traverse_page(address, 3 - 1, self.ppn, &vpns, access_type, &privilege_mode)
 						},
 						_ => Ok(address)
 					},
 					AddressingMode::SV48 => {
-						panic!("AddressingMode SV48 is not supported yet.");
+						self.record_error(ExecError::Unimplemented("Sv48 address translation. It raises page faults"));
+						Err(())
 					}
 				};
 				match self.page_cache_enabled {
@@ -740,7 +1692,7 @@ impl Mmu {
 	}
 
 	fn traverse_page(&mut self, v_address: u64, level: u8, parent_ppn: u64,
-		vpns: &[u64], access_type: &MemoryAccessType) -> Result<u64, ()> {
+		vpns: &[u64], access_type: &MemoryAccessType, privilege_mode: &PrivilegeMode) -> Result<u64, ()> {
 		let pagesize = 4096;
 		let ptesize = match self.addressing_mode {
 			AddressingMode::SV32 => 4,
@@ -764,7 +1716,7 @@ impl Mmu {
 		let d = (pte >> 7) & 1;
 		let a = (pte >> 6) & 1;
 		let _g = (pte >> 5) & 1;
-		let _u = (pte >> 4) & 1;
+		let u = (pte >> 4) & 1;
 		let x = (pte >> 3) & 1;
 		let w = (pte >> 2) & 1;
 		let r = (pte >> 1) & 1;
@@ -779,41 +1731,35 @@ impl Mmu {
 		if r == 0 && x == 0 {
 			return match level {
 				0 => Err(()),
-				_ => self.traverse_page(v_address, level - 1, ppn, vpns, access_type)
+				_ => self.traverse_page(v_address, level - 1, ppn, vpns, access_type, privilege_mode)
 			};
 		}
 
 		// Leaf page found
 
-		if a == 0 || (match access_type { MemoryAccessType::Write => d == 0, _ => false }) {
-			let new_pte = pte | (1 << 6) | (match access_type {
-				MemoryAccessType::Write => 1 << 7,
-				_ => 0
-			});
-			match self.addressing_mode {
-				AddressingMode::SV32 => self.store_word_raw(pte_address, new_pte as u32),
-				_ => self.store_doubleword_raw(pte_address, new_pte)
-			};
-		}
-
-		match access_type {
-			MemoryAccessType::Execute => {
-				if x == 0 {
-					return Err(());
-				}
+		// MXR makes executable pages readable. User mode accesses only
+		// user pages, and supervisor mode reads and writes them only with
+		// SUM and never executes them. Debugger accesses aren't checked
+		let mxr = (self.mstatus >> 19) & 1;
+		let sum = (self.mstatus >> 18) & 1;
+		let permitted = match access_type {
+			MemoryAccessType::Execute => x == 1 && match privilege_mode {
+				PrivilegeMode::User => u == 1,
+				_ => u == 0
 			},
-			MemoryAccessType::Read => {
-				if r == 0 {
-					return Err(());
-				}
+			MemoryAccessType::Read => (r == 1 || (mxr == 1 && x == 1)) && match privilege_mode {
+				PrivilegeMode::User => u == 1,
+				_ => u == 0 || sum == 1
 			},
-			MemoryAccessType::Write => {
-				if w == 0 {
-					return Err(());
-				}
+			MemoryAccessType::Write => w == 1 && match privilege_mode {
+				PrivilegeMode::User => u == 1,
+				_ => u == 0 || sum == 1
 			},
-			_ => {}
+			MemoryAccessType::DontCare => true
 		};
+		if !permitted {
+			return Err(());
+		}
 
 		let offset = v_address & 0xfff; // [11:0]
 		// @TODO: Optimize
@@ -846,6 +1792,18 @@ impl Mmu {
 			},
 		};
 
+		// Sets A and D bits only on the accesses permitted
+		if a == 0 || (match access_type { MemoryAccessType::Write => d == 0, _ => false }) {
+			let new_pte = pte | (1 << 6) | (match access_type {
+				MemoryAccessType::Write => 1 << 7,
+				_ => 0
+			});
+			match self.addressing_mode {
+				AddressingMode::SV32 => self.store_word_raw(pte_address, new_pte as u32),
+				_ => self.store_doubleword_raw(pte_address, new_pte)
+			};
+		}
+
 		// println!("PA:{:X}", p_address);
 		Ok(p_address)
 	}
@@ -860,9 +1818,150 @@ impl Mmu {
 		&mut self.clint
 	}
 
-	/// Returns mutable reference to `Uart`.
-	pub fn get_mut_uart(&mut self) -> &mut Uart {
-		&mut self.uart
+	/// Indicates whether the devices are connected to `Aplic` and `Imsic`.
+	pub fn is_aia_enabled(&self) -> bool {
+		self.aia_enabled
+	}
+
+	/// Returns immutable reference to `Imsic`.
+	pub fn get_imsic(&self) -> &Imsic {
+		&self.imsic
+	}
+
+	/// Returns mutable reference to `Imsic`.
+	pub fn get_mut_imsic(&mut self) -> &mut Imsic {
+		&mut self.imsic
+	}
+
+	/// Returns mutable reference to `VirtioBlockDisk`.
+	#[cfg(feature = "virtio")]
+	pub fn get_mut_disk(&mut self) -> &mut VirtioBlockDisk {
+		&mut self.disk
+	}
+
+	/// Returns immutable reference to `VirtioNet`.
+	#[cfg(feature = "virtio")]
+	pub fn get_net(&self) -> &VirtioNet {
+		&self.net
+	}
+
+	/// Returns mutable reference to `VirtioNet`.
+	#[cfg(feature = "virtio")]
+	pub fn get_mut_net(&mut self) -> &mut VirtioNet {
+		&mut self.net
+	}
+
+	/// Returns immutable reference to `VirtioSnd`.
+	#[cfg(feature = "virtio")]
+	pub fn get_snd(&self) -> &VirtioSnd {
+		&self.snd
+	}
+
+	/// Returns mutable reference to `VirtioSnd`.
+	#[cfg(feature = "virtio")]
+	pub fn get_mut_snd(&mut self) -> &mut VirtioSnd {
+		&mut self.snd
+	}
+
+	/// Returns immutable reference to `VirtioBalloon`.
+	#[cfg(feature = "virtio")]
+	pub fn get_balloon(&self) -> &VirtioBalloon {
+		&self.balloon
+	}
+
+	/// Returns mutable reference to `VirtioBalloon`.
+	#[cfg(feature = "virtio")]
+	pub fn get_mut_balloon(&mut self) -> &mut VirtioBalloon {
+		&mut self.balloon
+	}
+
+	/// Returns mutable reference to `VirtioConsole`.
+	#[cfg(feature = "virtio")]
+	pub fn get_mut_virtio_console(&mut self) -> &mut VirtioConsole {
+		&mut self.virtio_console
+	}
+
+	/// Returns the size of host memory allocated for main memory in bytes.
+	pub fn get_memory_allocated_size(&self) -> u64 {
+		self.memory.get_allocated_size()
+	}
+
+	/// Saves main memory content.
+	pub fn take_memory_snapshot(&mut self) -> MemorySnapshot {
+		self.memory.memory.take_snapshot()
+	}
+
+	/// Restores main memory content saved by `take_memory_snapshot()`.
+	/// Page tables may change so the page cache is cleared.
+	///
+	/// # Arguments
+	/// * `snapshot`
+	pub fn restore_memory_snapshot(&mut self, snapshot: &MemorySnapshot) {
+		self.memory.memory.restore_snapshot(snapshot);
+		self.clear_page_cache();
+	}
+
+	/// Returns mutable reference to `Console`.
+	pub fn get_mut_console(&mut self) -> &mut Console {
+		&mut self.console
+	}
+
+	fn is_serial_port_address(&self, address: u64) -> bool {
+		address >= SERIAL_PORT_BASE && address < SERIAL_PORT_BASE + self.serial_ports.len() as u64 * UART_SIZE
+	}
+
+	/// Returns mutable reference to a serial port besides the console.
+	/// Returns `None` if the port doesn't exist.
+	///
+	/// # Arguments
+	/// * `index` Starting with zero
+	pub fn get_mut_serial_port(&mut self, index: usize) -> Option<&mut Uart> {
+		self.serial_ports.get_mut(index)
+	}
+
+	/// Returns immutable reference to `TestFinisher`.
+	pub fn get_test_finisher(&self) -> &TestFinisher {
+		&self.test_finisher
+	}
+
+	/// Returns mutable reference to `TestFinisher`.
+	pub fn get_mut_test_finisher(&mut self) -> &mut TestFinisher {
+		&mut self.test_finisher
+	}
+
+	/// Returns immutable reference to `Gpio`.
+	pub fn get_gpio(&self) -> &Gpio {
+		&self.gpio
+	}
+
+	/// Returns mutable reference to `Gpio`.
+	pub fn get_mut_gpio(&mut self) -> &mut Gpio {
+		&mut self.gpio
+	}
+
+	/// Returns immutable reference to `Pwm`.
+	pub fn get_pwm(&self) -> &Pwm {
+		&self.pwm
+	}
+
+	/// Returns immutable reference to `Framebuffer`.
+	pub fn get_framebuffer(&self) -> &Framebuffer {
+		&self.framebuffer
+	}
+
+	/// Returns mutable reference to `Framebuffer`.
+	pub fn get_mut_framebuffer(&mut self) -> &mut Framebuffer {
+		&mut self.framebuffer
+	}
+
+	/// Returns immutable reference to `SharedMemory`.
+	pub fn get_shared_memory(&self) -> &SharedMemory {
+		&self.shared_memory
+	}
+
+	/// Returns mutable reference to `SharedMemory`.
+	pub fn get_mut_shared_memory(&mut self) -> &mut SharedMemory {
+		&mut self.shared_memory
 	}
 }
 
@@ -929,7 +2028,296 @@ impl MemoryWrapper {
 		self.memory.write_doubleword(p_address - DRAM_BASE, value)
 	}
 
+	pub fn write_slice(&mut self, p_address: u64, data: &[u8]) {
+		debug_assert!(p_address >= DRAM_BASE, "Memory address must equals to or bigger than DRAM_BASE. {:X}", p_address);
+		self.memory.write_slice(p_address - DRAM_BASE, data)
+	}
+
 	pub fn validate_address(&self, address: u64) -> bool {
 		self.memory.validate_address(address - DRAM_BASE)
 	}
+
+	// Whether all the bytes of an access are in main memory, checked with
+	// a subtraction and two comparisons in the fast path of every access
+	fn contains(&self, p_address: u64, width: u64) -> bool {
+		let offset = p_address.wrapping_sub(DRAM_BASE);
+		let capacity = self.memory.get_capacity();
+		offset < capacity && capacity - offset >= width
+	}
+
+	pub fn release_page(&mut self, p_address: u64) {
+		debug_assert!(p_address >= DRAM_BASE, "Memory address must equals to or bigger than DRAM_BASE. {:X}", p_address);
+		self.memory.release_page(p_address - DRAM_BASE)
+	}
+
+	fn get_allocated_size(&self) -> u64 {
+		self.memory.get_allocated_size()
+	}
+}
+
+#[cfg(test)]
+mod test_mmu {
+	use core::cell::RefCell;
+	use alloc::rc::Rc;
+	use super::*;
+
+	#[test]
+	fn write_main_memory() {
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &EmulatorConfig::default());
+		mmu.init_memory(0x2000);
+		// Unaligned head and tail around the eight-byte words, across pages
+		let data = (0..0x1001).map(|i| i as u8).collect::<Vec<u8>>();
+		assert_eq!(Ok(()), mmu.write_main_memory(DRAM_BASE + 0x3, &data));
+		let mut read = vec![0; data.len() + 2];
+		assert_eq!(Ok(()), mmu.read_main_memory(DRAM_BASE + 0x2, &mut read));
+		assert_eq!(0, read[0]);
+		assert_eq!(&data[..], &read[1..data.len() + 1]);
+		assert_eq!(0, read[data.len() + 1]);
+		assert_eq!(Ok(()), mmu.write_main_memory(DRAM_BASE + 0x11, &[0xaa, 0xbb]));
+		assert_eq!(0x10bbaa0d, mmu.load_word_raw(DRAM_BASE + 0x10));
+
+		assert_eq!(Err(MemoryError::OutOfRange {
+			address: DRAM_BASE + 0x1fff,
+			size: 2
+		}), mmu.write_main_memory(DRAM_BASE + 0x1fff, &[1, 2]));
+		assert_eq!(0, mmu.load_raw(DRAM_BASE + 0x1fff));
+	}
+
+	#[test]
+	fn main_memory_range() {
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &EmulatorConfig::default());
+		mmu.init_memory(0x1000);
+		// The last eight bytes take the fast path
+		mmu.store_doubleword_raw(DRAM_BASE + 0xff8, 0x1122334455667788);
+		assert_eq!(0x1122334455667788, mmu.load_doubleword_raw(DRAM_BASE + 0xff8));
+		assert_eq!(0x11, mmu.load_raw(DRAM_BASE + 0xfff));
+		assert_eq!(None, mmu.take_error());
+		// Below main memory goes to the devices
+		assert_eq!(0, mmu.load_word_raw(DRAM_BASE - 4));
+		assert_eq!(Some(ExecError::Memory(MemoryError::Unmapped(DRAM_BASE - 4))), mmu.take_error());
+		assert_eq!(0, mmu.load_raw(!0));
+		assert_eq!(Some(ExecError::Memory(MemoryError::Unmapped(!0))), mmu.take_error());
+	}
+
+	#[test]
+	fn sv32() {
+		let mut mmu = Mmu::new(Xlen::Bit32, Box::new(DummyTerminal::new()), &EmulatorConfig::default());
+		// Main memory reaching above 4GiB, which Sv32 can map to
+		mmu.init_memory(0x80002000);
+		let root = DRAM_BASE;
+		let table = DRAM_BASE + 0x1000;
+		// 0x00400000: user megapage at 0x100000000
+		mmu.store_word_raw(root + 4, (0x400 << 20) | 0xd7);
+		// 0x00800000: table, and 0x00803000 in it is a supervisor page
+		// at 0x100001000, readable and executable
+		mmu.store_word_raw(root + 8, ((table >> 12) << 10) as u32 | 1);
+		mmu.store_word_raw(table + 3 * 4, (0x100001 << 10) | 0xb);
+		// 0x00c00000: misaligned megapage
+		mmu.store_word_raw(root + 12, (0x401 << 10) | 0x17);
+		mmu.update_addressing_mode(AddressingMode::SV32);
+		mmu.update_ppn(root >> 12);
+		mmu.update_privilege_mode(PrivilegeMode::Supervisor);
+
+		// Supervisor mode writes user pages only with SUM
+		assert!(mmu.store_word(0x00400120, 0xdeadbeef).is_err());
+		mmu.update_mstatus(1 << 18);
+		assert!(mmu.store_word(0x00400120, 0xdeadbeef).is_ok());
+		assert_eq!(0xdeadbeef, mmu.load_word_raw(0x100000120));
+		mmu.store_word_raw(0x100001010, 0x12345678);
+		assert!(mmu.fetch_word(0x00400120).is_err());
+
+		// A bit is set on the accesses permitted only
+		assert!(mmu.store_word(0x00803010, 0).is_err());
+		assert_eq!(0xb, mmu.load_word_raw(table + 3 * 4) & 0xff);
+		assert_eq!(Some(0x12345678), mmu.fetch_word(0x00803010).ok());
+		assert_eq!(0x4b, mmu.load_word_raw(table + 3 * 4) & 0xff);
+		mmu.update_privilege_mode(PrivilegeMode::User);
+		assert!(mmu.load_word(0x00803010).is_err());
+		assert_eq!(Some(0xdeadbeef), mmu.load_word(0x00400120).ok());
+		assert!(mmu.load_word(0x00c00000).is_err());
+
+		// Machine mode loads and stores as MPP with MPRV
+		mmu.update_privilege_mode(PrivilegeMode::Machine);
+		mmu.update_mstatus((1 << 17) | (1 << 11));
+		assert!(mmu.load_word(0x00400120).is_err());
+		mmu.update_mstatus(1 << 17);
+		assert_eq!(Some(0xdeadbeef), mmu.load_word(0x00400120).ok());
+		// without flushing the page cache on every access
+		let flushes = mmu.get_translation_stats().page_cache_flushes;
+		assert_eq!(Some(0xdeadbeef), mmu.load_word(0x00400120).ok());
+		assert!(mmu.store_word(0x00400124, 0).is_ok());
+		assert_eq!(flushes, mmu.get_translation_stats().page_cache_flushes);
+		assert_eq!(PrivilegeMode::Machine, mmu.privilege_mode);
+	}
+
+	#[test]
+	fn gpio_interrupt() {
+		use cpu::MIP_SEIP;
+		use device::gpio::GPIO_BASE;
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &EmulatorConfig::default());
+		let mut mip = 0;
+		mmu.store_word_raw(0x0c000000 + GPIO_IRQ as u64 * 4, 1); // priority
+		mmu.store_word_raw(0x0c002080, 1 << GPIO_IRQ); // enable
+		mmu.store_word_raw(GPIO_BASE + 0x04, 0x3); // input_en
+		mmu.store_word_raw(GPIO_BASE + 0x18, 0x3); // rise_ie
+		assert_eq!(Ok(()), mmu.get_mut_gpio().set_input(0, true));
+		mmu.tick_devices(&mut mip);
+		assert_eq!(MIP_SEIP, mip);
+		assert_eq!(GPIO_IRQ, mmu.load_word_raw(0x0c201004));
+
+		// Another pin rises while the line is high. The line stays high
+		// after the first pin is cleared, so completing the interrupt
+		// makes it pending again
+		assert_eq!(Ok(()), mmu.get_mut_gpio().set_input(1, true));
+		mmu.tick_devices(&mut mip);
+		mmu.store_word_raw(GPIO_BASE + 0x1c, 0x1); // rise_ip
+		mmu.tick_devices(&mut mip);
+		mmu.store_word_raw(0x0c201004, GPIO_IRQ); // complete
+		mip = 0;
+		mmu.tick_devices(&mut mip);
+		assert_eq!(MIP_SEIP, mip);
+		assert_eq!(GPIO_IRQ, mmu.load_word_raw(0x0c201004));
+
+		mmu.store_word_raw(GPIO_BASE + 0x1c, 0x2);
+		mmu.tick_devices(&mut mip);
+		mmu.store_word_raw(0x0c201004, GPIO_IRQ);
+		mip = 0;
+		mmu.tick_devices(&mut mip);
+		assert_eq!(0, mip);
+		assert_eq!(0, mmu.load_word_raw(0x0c201004));
+	}
+
+	#[test]
+	fn pwm_interrupt() {
+		use cpu::MIP_SEIP;
+		use device::pwm::PWM_BASE;
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &EmulatorConfig::default());
+		let mut mip = 0;
+		// Comparator 2 interrupts with its own source
+		let irq = PWM_IRQ_BASE + 2;
+		mmu.store_word_raw(0x0c000000 + irq as u64 * 4, 1);
+		mmu.store_word_raw(0x0c002080 + 4, 1 << (irq - 32));
+		mmu.store_word_raw(PWM_BASE + 0x20, 0xffff);
+		mmu.store_word_raw(PWM_BASE + 0x24, 0xffff);
+		mmu.store_word_raw(PWM_BASE + 0x28, 2);
+		mmu.store_word_raw(PWM_BASE + 0x2c, 0xffff);
+		mmu.store_word_raw(PWM_BASE, 1 << 12); // pwmenalways
+		mmu.tick_devices(&mut mip);
+		assert_eq!(0, mip);
+		mmu.tick_devices(&mut mip);
+		assert_eq!(MIP_SEIP, mip);
+		assert_eq!(irq, mmu.load_word_raw(0x0c201004));
+	}
+
+	#[test]
+	fn fetch_across_pages() {
+		let mut mmu = Mmu::new(Xlen::Bit32, Box::new(DummyTerminal::new()), &EmulatorConfig::default());
+		mmu.init_memory(0x3000);
+		let root = DRAM_BASE;
+		let table = DRAM_BASE + 0x1000;
+		let page = DRAM_BASE + 0x2000;
+		// 0x00000000 is an executable page followed by an unmapped one
+		mmu.store_word_raw(root, ((table >> 12) << 10) as u32 | 1);
+		mmu.store_word_raw(table, ((page >> 12) << 10) as u32 | 0xcb);
+		mmu.update_addressing_mode(AddressingMode::SV32);
+		mmu.update_ppn(root >> 12);
+		mmu.update_privilege_mode(PrivilegeMode::Supervisor);
+
+		// A compressed instruction at the end of the page
+		mmu.store_halfword_raw(page + 0xffe, 0x0001); // c.nop
+		assert_eq!(Some(0x0001), mmu.fetch_word(0xffe).ok());
+		// The upper half of a 32-bit one faults at its own address
+		mmu.store_halfword_raw(page + 0xffe, 0x0013);
+		let trap = mmu.fetch_word(0xffe).err().unwrap();
+		assert!(matches!(trap.trap_type, TrapType::InstructionPageFault));
+		assert_eq!(0x1000, trap.value);
+	}
+
+	#[test]
+	fn out_of_range() {
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &EmulatorConfig::default());
+		mmu.init_memory(0x1000);
+		// Across the end of main memory
+		mmu.store_doubleword_raw(DRAM_BASE + 0xffc, 0x1122334455667788);
+		assert_eq!(Some(ExecError::Memory(MemoryError::Unmapped(DRAM_BASE + 0x1000))), mmu.take_error());
+		assert_eq!(None, mmu.take_error());
+		assert_eq!(0x55667788, mmu.load_doubleword_raw(DRAM_BASE + 0xffc));
+		assert_eq!(Some(ExecError::Memory(MemoryError::Unmapped(DRAM_BASE + 0x1000))), mmu.take_error());
+		// Only the first error is kept
+		mmu.store_raw(DRAM_BASE + 0x2000, 1);
+		mmu.store_raw(0x40000000, 1);
+		assert_eq!(Some(ExecError::Memory(MemoryError::Unmapped(DRAM_BASE + 0x2000))), mmu.take_error());
+		assert_eq!(None, mmu.take_error());
+	}
+
+	#[test]
+	fn value_watch() {
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &EmulatorConfig::default());
+		mmu.init_memory(0x10000);
+		let changes = Rc::new(RefCell::new(vec![]));
+		let changes_clone = changes.clone();
+		let changed_id = mmu.add_value_watch(DRAM_BASE + 0x100, ValueWatchCondition::Changed,
+			Box::new(move |old_value, new_value| changes_clone.borrow_mut().push((old_value, new_value))));
+		let equals = Rc::new(RefCell::new(vec![]));
+		let equals_clone = equals.clone();
+		mmu.add_value_watch(DRAM_BASE + 0x100, ValueWatchCondition::Equals(0x1234),
+			Box::new(move |old_value, new_value| equals_clone.borrow_mut().push((old_value, new_value))));
+
+		// Not overlapping
+		assert!(mmu.store_doubleword(DRAM_BASE + 0x108, 0x1234).is_ok());
+		// Overlapping the end but not changing the value
+		assert!(mmu.store_word(DRAM_BASE + 0x104, 0).is_ok());
+		assert!(changes.borrow().is_empty());
+		assert!(mmu.store_halfword(DRAM_BASE + 0x100, 0x1234).is_ok());
+		assert!(mmu.store(DRAM_BASE + 0x107, 0x56).is_ok());
+		mmu.store_doubleword_raw(DRAM_BASE + 0x100, 0x1234);
+		assert_eq!(vec![(0, 0x1234), (0x1234, 0x5600000000001234), (0x5600000000001234, 0x1234)], *changes.borrow());
+		assert_eq!(vec![(0, 0x1234), (0x5600000000001234, 0x1234)], *equals.borrow());
+
+		assert_eq!(Ok(()), mmu.remove_value_watch(changed_id));
+		assert_eq!(Err(DebugError::NoValueWatch(changed_id)), mmu.remove_value_watch(changed_id));
+		assert!(mmu.store_doubleword(DRAM_BASE + 0x100, 0).is_ok());
+		assert_eq!(3, changes.borrow().len());
+	}
+
+	#[test]
+	fn watchpoint() {
+		let mut mmu = Mmu::new(Xlen::Bit64, Box::new(DummyTerminal::new()), &EmulatorConfig::default());
+		mmu.init_memory(0x10000);
+		mmu.add_watchpoint(WatchpointType::Write, DRAM_BASE + 0x108, 4);
+		assert!(mmu.store_doubleword(DRAM_BASE + 0x100, 0x1122334455667788).is_ok());
+		assert_eq!(None, mmu.take_watchpoint_hit());
+		assert!(mmu.load_word(DRAM_BASE + 0x108).is_ok());
+		assert_eq!(None, mmu.take_watchpoint_hit());
+		// Overlapping the beginning of the range
+		assert!(mmu.store_doubleword(DRAM_BASE + 0x104, 0x1122334455667788).is_ok());
+		assert_eq!(Some(WatchpointHit {
+			watchpoint_type: WatchpointType::Write,
+			address: DRAM_BASE + 0x104,
+			width: 8,
+			value: 0x1122334455667788
+		}), mmu.take_watchpoint_hit());
+		assert_eq!(None, mmu.take_watchpoint_hit());
+
+		// Accesses across pages are caught once with the whole value
+		mmu.add_watchpoint(WatchpointType::Read, DRAM_BASE + 0x1000, 1);
+		assert!(mmu.store_word(DRAM_BASE + 0xffe, 0x12345678).is_ok());
+		assert_eq!(Some(0x12345678), mmu.load_word(DRAM_BASE + 0xffe).ok());
+		assert_eq!(Some(WatchpointHit {
+			watchpoint_type: WatchpointType::Read,
+			address: DRAM_BASE + 0xffe,
+			width: 4,
+			value: 0x12345678
+		}), mmu.take_watchpoint_hit());
+
+		assert!(mmu.has_watchpoint(WatchpointType::Read, DRAM_BASE + 0x1000, 1));
+		assert!(!mmu.has_watchpoint(WatchpointType::Write, DRAM_BASE + 0x1000, 1));
+		assert_eq!(Ok(()), mmu.remove_watchpoint(WatchpointType::Read, DRAM_BASE + 0x1000, 1));
+		assert_eq!(Err(DebugError::NoWatchpoint {
+			address: DRAM_BASE + 0x1000,
+			length: 1
+		}), mmu.remove_watchpoint(WatchpointType::Read, DRAM_BASE + 0x1000, 1));
+		assert!(mmu.load(DRAM_BASE + 0x1000).is_ok());
+		assert_eq!(None, mmu.take_watchpoint_hit());
+	}
 }
@@ -0,0 +1,45 @@
+use alloc::vec::Vec;
+
+/// Network backend which transfers Ethernet frames between the virtio
+/// network device and the outside world. Implement this trait to connect
+/// the guest to your own transport, e.g. WebSocket or WebRTC on WASM.
+pub trait NetBackend {
+	/// Sends an Ethernet frame from the guest.
+	fn send(&mut self, frame: &[u8]);
+
+	/// Polls an Ethernet frame to the guest. This method returns `None`
+	/// if no frame is available. Must not block.
+	fn poll(&mut self) -> Option<Vec<u8>>;
+}
+
+/// `NetBackend` which loops frames back to the guest. For the test or whatever.
+pub struct LoopbackNetBackend {
+	frames: Vec<Vec<u8>>
+}
+
+impl LoopbackNetBackend {
+	pub fn new() -> Self {
+		LoopbackNetBackend {
+			frames: vec![]
+		}
+	}
+}
+
+impl Default for LoopbackNetBackend {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl NetBackend for LoopbackNetBackend {
+	fn send(&mut self, frame: &[u8]) {
+		self.frames.push(frame.to_vec());
+	}
+
+	fn poll(&mut self) -> Option<Vec<u8>> {
+		match self.frames.is_empty() {
+			true => None,
+			false => Some(self.frames.remove(0))
+		}
+	}
+}
@@ -0,0 +1,213 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use io::{self, Write};
+
+use collections::FnvHashMap;
+use cpu::Cpu;
+use symbol_table::SymbolTable;
+use unwind::unwind_stack;
+
+/// Samples the guest PC every N instructions for profiling guest
+/// workloads, optionally with the callers found by walking the frame
+/// pointer chain. Samples are aggregated by function and written in
+/// the folded stack format `inferno-flamegraph` and `flamegraph.pl` take,
+/// one stack per line from the root like
+///
+/// ```text
+/// main;parse;strlen 42
+/// ```
+///
+/// The stack is walked with `unwind::unwind_stack()`, which works best
+/// with `-fno-omit-frame-pointer`. Without frame pointers it falls back
+/// to scanning the stack, which can show stale callers. Function names are looked up in
+/// the symbols loaded by the time the profiler is set to `Emulator`
+/// with `set_profiler()`.
+pub struct Profiler {
+	interval: u64,
+	stack_depth: usize,
+	running: bool,
+	// Instructions since the last sample
+	count: u64,
+	// Sampled addresses from the leaf -> samples
+	samples: FnvHashMap<Vec<u64>, u64>,
+	symbol_table: Option<SymbolTable>
+}
+
+impl Profiler {
+	/// Creates a new `Profiler`. Sampling starts immediately.
+	///
+	/// # Arguments
+	/// * `interval` Instructions between samples, e.g. 1000
+	pub fn new(interval: u64) -> Self {
+		Profiler {
+			interval: interval.max(1),
+			stack_depth: 0,
+			running: true,
+			count: 0,
+			samples: FnvHashMap::default(),
+			symbol_table: None
+		}
+	}
+
+	/// Sets how many callers are recorded per sample by walking the stack.
+	/// Zero, the default, records only the PC.
+	///
+	/// # Arguments
+	/// * `depth`
+	pub fn set_stack_depth(&mut self, depth: usize) {
+		self.stack_depth = depth;
+	}
+
+	/// Sets the symbols function names are looked up in.
+	///
+	/// # Arguments
+	/// * `symbol_table`
+	pub fn set_symbol_table(&mut self, symbol_table: SymbolTable) {
+		self.symbol_table = Some(symbol_table);
+	}
+
+	/// Starts sampling.
+	pub fn start(&mut self) {
+		self.running = true;
+	}
+
+	/// Stops sampling. The samples so far are kept.
+	pub fn stop(&mut self) {
+		self.running = false;
+	}
+
+	/// Returns `true` if sampling is running.
+	pub fn is_running(&self) -> bool {
+		self.running
+	}
+
+	/// Counts the instruction the CPU is about to run and samples it every
+	/// interval. Call this before every `Cpu::tick()`.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn sample(&mut self, cpu: &mut Cpu) {
+		if !self.running || cpu.is_waiting_for_interrupt() {
+			return;
+		}
+		self.count += 1;
+		if self.count < self.interval {
+			return;
+		}
+		self.count = 0;
+		let stack = match self.stack_depth {
+			0 => vec![cpu.read_pc()],
+			depth => unwind_stack(cpu, depth + 1).into_iter().map(|(address, _method)| address).collect()
+		};
+		*self.samples.entry(stack).or_insert(0) += 1;
+	}
+
+	/// Returns the number of samples taken so far.
+	pub fn get_sample_count(&self) -> u64 {
+		self.samples.values().sum()
+	}
+
+	/// Returns the number of samples by the function the PC was in, the
+	/// most sampled first.
+	pub fn get_function_counts(&self) -> Vec<(String, u64)> {
+		let mut counts = FnvHashMap::default();
+		for (stack, count) in self.samples.iter() {
+			*counts.entry(self.get_function_name(stack[0])).or_insert(0) += *count;
+		}
+		sort_counts(counts)
+	}
+
+	/// Writes the samples in the folded stack format, aggregated by
+	/// function.
+	///
+	/// # Arguments
+	/// * `writer`
+	pub fn write_folded(&self, writer: &mut dyn Write) -> io::Result<()> {
+		let mut counts = FnvHashMap::default();
+		for (stack, count) in self.samples.iter() {
+			let names: Vec<String> = stack.iter().rev()
+				.map(|address| self.get_function_name(*address))
+				.collect();
+			*counts.entry(names.join(";")).or_insert(0) += *count;
+		}
+		for (stack, count) in sort_counts(counts) {
+			writeln!(writer, "{} {}", stack, count)?;
+		}
+		writer.flush()
+	}
+
+	/// Forgets the samples taken so far.
+	pub fn clear(&mut self) {
+		self.samples.clear();
+		self.count = 0;
+	}
+
+	// Returns the name of the function at the address, or the address in
+	// hexadecimal if no symbol is before it
+	fn get_function_name(&self, address: u64) -> String {
+		match self.symbol_table.as_ref().and_then(|symbol_table| symbol_table.lookup(address)) {
+			Some((name, _offset)) => name.to_string(),
+			None => format!("0x{:x}", address)
+		}
+	}
+}
+
+// Sorts by count, the largest first, then by name
+fn sort_counts(counts: FnvHashMap<String, u64>) -> Vec<(String, u64)> {
+	let mut counts: Vec<(String, u64)> = counts.into_iter().collect();
+	counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+	counts
+}
+
+#[cfg(test)]
+mod test_profiler {
+	use super::*;
+	use alloc::boxed::Box;
+	use mmu::DRAM_BASE;
+	use terminal::DummyTerminal;
+
+	#[test]
+	fn write_folded() {
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x100);
+		cpu.update_pc(DRAM_BASE);
+		let code = [
+			0x00150513, // loop: addi a0, a0, 1
+			0xffdff06f // jal zero, loop
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		// main: jal ra, 0 calling loop
+		if cpu.get_mut_mmu().store_word(DRAM_BASE + 0x40, 0x000000ef).is_err() {
+			panic!("Failed to store");
+		}
+		// A frame whose return address is in main
+		if cpu.get_mut_mmu().store_doubleword(DRAM_BASE + 0x80 - 8, DRAM_BASE + 0x44).is_err() {
+			panic!("Failed to store");
+		}
+		cpu.write_register(8, (DRAM_BASE + 0x80) as i64);
+
+		let mut symbol_map = FnvHashMap::default();
+		symbol_map.insert("loop".to_string(), DRAM_BASE);
+		symbol_map.insert("main".to_string(), DRAM_BASE + 0x40);
+		let mut profiler = Profiler::new(2);
+		profiler.set_symbol_table(SymbolTable::new(&symbol_map));
+		profiler.set_stack_depth(4);
+		for _ in 0..10 {
+			profiler.sample(&mut cpu);
+			cpu.tick();
+		}
+		assert_eq!(5, profiler.get_sample_count());
+		assert_eq!(vec![("loop".to_string(), 5)], profiler.get_function_counts());
+		let mut folded = vec![];
+		assert!(profiler.write_folded(&mut folded).is_ok());
+		// The walk stops at the null frame pointer of the next frame
+		assert_eq!("main;loop 5\n", String::from_utf8_lossy(&folded));
+		profiler.clear();
+		assert_eq!(0, profiler.get_sample_count());
+	}
+}
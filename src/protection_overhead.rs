@@ -0,0 +1,249 @@
+use core::fmt;
+
+use config::MacAlgorithm;
+use cpu::Cpu;
+
+// ZIP and UNZIP of Zipper Stack, custom-0 with funct3 0 and 1
+const ZIP_MASK: u32 = 0xffffe07f;
+const ZIP_DATA: u32 = 0x0000000b;
+
+// SSPUSH, SSPOPCHK, and SSRDP of Zicfiss
+const SSPUSH_MASK: u32 = 0xfe0fffff;
+const SSPUSH_DATA: u32 = 0xce004073;
+const SSPOPCHK_MASK: u32 = 0xfff07fff;
+const SSPOPCHK_DATA: u32 = 0xcdc04073;
+const SSRDP_MASK: u32 = 0xfffff07f;
+const SSRDP_DATA: u32 = 0xcdc04073;
+
+// LPAD of Zicfilp, AUIPC x0
+const LPAD_MASK: u32 = 0xfff;
+const LPAD_DATA: u32 = 0x017;
+
+/// Cycle cost model of `ProtectionOverhead`. Every instruction takes
+/// `base_cycles`, and the protection instructions take the extra cycles
+/// of their scheme on top. The defaults are rough estimates of simple
+/// in-order hardware, to be overridden with the numbers of the target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CostModel {
+	/// Cycles of every instruction
+	pub base_cycles: u64,
+	/// Extra cycles of `ZIP` and `UNZIP` computing the MAC
+	pub mac_cycles: u64,
+	/// Extra cycles of `SSPUSH` and `SSPOPCHK` accessing the shadow stack
+	pub shadow_stack_cycles: u64,
+	/// Extra cycles of `LPAD`
+	pub landing_pad_cycles: u64
+}
+
+impl CostModel {
+	/// Returns the default cost model with the MAC latency of
+	/// the algorithm, a round per cycle: 24 Keccak-f rounds of SHA3, 6
+	/// SipRounds of SipHash-2-4, 5 rounds of the QARMA-like cipher, or 16
+	/// bytes of FNV-1a.
+	///
+	/// # Arguments
+	/// * `algorithm`
+	pub fn new(algorithm: &MacAlgorithm) -> Self {
+		CostModel {
+			base_cycles: 1,
+			mac_cycles: match algorithm {
+				MacAlgorithm::Sha3 => 24,
+				MacAlgorithm::SipHash => 6,
+				MacAlgorithm::Qarma => 5,
+				MacAlgorithm::Fnv => 16
+			},
+			shadow_stack_cycles: 1,
+			landing_pad_cycles: 0
+		}
+	}
+}
+
+/// Instructions and cycles of a run and the part of them the return
+/// address protection schemes have added, returned by
+/// `ProtectionOverhead::get_report()`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OverheadReport {
+	/// Instructions run
+	pub instructions: u64,
+	/// Cycles under the cost model
+	pub cycles: u64,
+	/// `ZIP` and `UNZIP` instructions run
+	pub zipper_stack_instructions: u64,
+	/// Shadow stack instructions run
+	pub shadow_stack_instructions: u64,
+	/// `LPAD` instructions run
+	pub landing_pad_instructions: u64,
+	/// Cycles of the protection instructions
+	pub protection_cycles: u64
+}
+
+impl OverheadReport {
+	/// Returns the protection instructions of all the schemes.
+	pub fn get_protection_instructions(&self) -> u64 {
+		self.zipper_stack_instructions + self.shadow_stack_instructions + self.landing_pad_instructions
+	}
+
+	/// Returns the instructions the protection has added relative to
+	/// the run without them, e.g. 0.05 for 5%.
+	pub fn get_instruction_overhead(&self) -> f64 {
+		get_ratio(self.get_protection_instructions() as f64, self.instructions - self.get_protection_instructions())
+	}
+
+	/// Returns the cycles the protection has added relative to the run
+	/// without the protection instructions.
+	pub fn get_cycle_overhead(&self) -> f64 {
+		get_ratio(self.protection_cycles as f64, self.cycles - self.protection_cycles)
+	}
+
+	/// Returns the instruction and cycle overhead of this run against
+	/// a run of the same workload built without the protection, for
+	/// the schemes which change more than add instructions.
+	///
+	/// # Arguments
+	/// * `baseline` Report of the run without the protection
+	pub fn get_overhead_against(&self, baseline: &OverheadReport) -> (f64, f64) {
+		(
+			get_ratio(self.instructions as f64 - baseline.instructions as f64, baseline.instructions),
+			get_ratio(self.cycles as f64 - baseline.cycles as f64, baseline.cycles)
+		)
+	}
+}
+
+impl fmt::Display for OverheadReport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "{} instructions, {} cycles", self.instructions, self.cycles)?;
+		writeln!(f, "Protection: {} zipper stack, {} shadow stack, {} landing pad instructions, {} cycles",
+			self.zipper_stack_instructions, self.shadow_stack_instructions, self.landing_pad_instructions,
+			self.protection_cycles)?;
+		write!(f, "Overhead: {:.2}% instructions, {:.2}% cycles",
+			self.get_instruction_overhead() * 100.0, self.get_cycle_overhead() * 100.0)
+	}
+}
+
+fn get_ratio(value: f64, base: u64) -> f64 {
+	match base {
+		0 => 0.0,
+		_ => value / base as f64
+	}
+}
+
+/// Measures the instruction and cycle overhead the return address
+/// protection schemes, Zipper Stack, the shadow stack, and the landing
+/// pads, add to a workload while it runs. Every instruction costs cycles
+/// under a `CostModel`, and the cost of the protection instructions is
+/// attributed to the protection. Set it to `Emulator` with
+/// `set_protection_overhead()`.
+///
+/// The protection instructions are taken as the whole overhead, so
+/// the run without the protection is the same workload without them.
+/// For a workload built twice, compare the reports of the two runs with
+/// `OverheadReport::get_overhead_against()`.
+///
+/// ```ignore
+/// emulator.set_protection_overhead(ProtectionOverhead::new(CostModel::new(&MacAlgorithm::SipHash)));
+/// emulator.run_program();
+/// println!("{}", emulator.get_protection_overhead().unwrap().get_report());
+/// ```
+pub struct ProtectionOverhead {
+	cost_model: CostModel,
+	report: OverheadReport
+}
+
+impl ProtectionOverhead {
+	/// Creates a new `ProtectionOverhead`.
+	///
+	/// # Arguments
+	/// * `cost_model`
+	pub fn new(cost_model: CostModel) -> Self {
+		ProtectionOverhead {
+			cost_model,
+			report: OverheadReport::default()
+		}
+	}
+
+	/// Returns the cost model.
+	pub fn get_cost_model(&self) -> &CostModel {
+		&self.cost_model
+	}
+
+	/// Returns the counts so far.
+	pub fn get_report(&self) -> &OverheadReport {
+		&self.report
+	}
+
+	/// Sets the counts to zero, e.g. to measure only the main part of
+	/// the workload after the boot.
+	pub fn reset(&mut self) {
+		self.report = OverheadReport::default();
+	}
+
+	/// Counts the instruction the CPU is about to run. Call this before
+	/// every `Cpu::tick()`.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn count(&mut self, cpu: &mut Cpu) {
+		if cpu.is_waiting_for_interrupt() {
+			return;
+		}
+		let word = match cpu.fetch_next_instruction() {
+			Some((word, _length)) => word,
+			None => return
+		};
+		let report = &mut self.report;
+		let extra_cycles = match word {
+			_ if word & ZIP_MASK == ZIP_DATA => {
+				report.zipper_stack_instructions += 1;
+				self.cost_model.mac_cycles
+			},
+			_ if word & SSPUSH_MASK == SSPUSH_DATA || word & SSPOPCHK_MASK == SSPOPCHK_DATA => {
+				report.shadow_stack_instructions += 1;
+				self.cost_model.shadow_stack_cycles
+			},
+			_ if word & SSRDP_MASK == SSRDP_DATA => {
+				report.shadow_stack_instructions += 1;
+				0
+			},
+			_ if word & LPAD_MASK == LPAD_DATA => {
+				report.landing_pad_instructions += 1;
+				self.cost_model.landing_pad_cycles
+			},
+			_ => {
+				report.instructions += 1;
+				report.cycles += self.cost_model.base_cycles;
+				return;
+			}
+		};
+		let cycles = self.cost_model.base_cycles + extra_cycles;
+		report.instructions += 1;
+		report.cycles += cycles;
+		report.protection_cycles += cycles;
+	}
+}
+
+#[cfg(test)]
+mod test_protection_overhead {
+	use super::*;
+
+	#[test]
+	fn report() {
+		let report = OverheadReport {
+			instructions: 110,
+			cycles: 150,
+			zipper_stack_instructions: 10,
+			shadow_stack_instructions: 0,
+			landing_pad_instructions: 0,
+			protection_cycles: 50
+		};
+		assert_eq!(0.1, report.get_instruction_overhead());
+		assert_eq!(0.5, report.get_cycle_overhead());
+		let baseline = OverheadReport {
+			instructions: 100,
+			cycles: 100,
+			..OverheadReport::default()
+		};
+		assert_eq!((0.1, 0.5), report.get_overhead_against(&baseline));
+		assert_eq!(0.0, OverheadReport::default().get_cycle_overhead());
+		assert_eq!(24, CostModel::new(&MacAlgorithm::Sha3).mac_cycles);
+	}
+}
@@ -0,0 +1,118 @@
+extern crate libc;
+
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use terminal::Terminal;
+
+/// `Terminal` connected to a host pseudo-terminal. Tools like `screen`,
+/// `minicom` or expect scripts open the slave device `get_path()` returns
+/// and talk to the guest console while the emulator runs in the background.
+/// The slave is kept open inside so that the console can be detached and
+/// attached again. Output is buffered by the kernel while nothing is attached
+/// and discarded once the buffer is full. Only available on Linux.
+pub struct PtyTerminal {
+	master: File,
+	// Never read. Held to keep the pseudo-terminal alive between attaches.
+	_slave: File,
+	path: String
+}
+
+impl PtyTerminal {
+	/// Allocates a new pseudo-terminal. The slave is set to raw mode
+	/// because the guest echoes and edits lines by itself.
+	pub fn new() -> io::Result<Self> {
+		let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK) };
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let master = unsafe { File::from_raw_fd(fd) };
+		if unsafe { libc::grantpt(fd) } < 0 || unsafe { libc::unlockpt(fd) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let mut name = [0 as libc::c_char; 128];
+		let result = unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+		if result != 0 {
+			return Err(io::Error::from_raw_os_error(result));
+		}
+		let path = unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned();
+		let slave = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.custom_flags(libc::O_NOCTTY)
+			.open(&path)?;
+		unsafe {
+			let mut termios: libc::termios = std::mem::zeroed();
+			if libc::tcgetattr(slave.as_raw_fd(), &mut termios) < 0 {
+				return Err(io::Error::last_os_error());
+			}
+			libc::cfmakeraw(&mut termios);
+			if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) < 0 {
+				return Err(io::Error::last_os_error());
+			}
+		}
+		Ok(PtyTerminal {
+			master,
+			_slave: slave,
+			path
+		})
+	}
+
+	/// Returns the path of the slave device, e.g. "/dev/pts/3".
+	pub fn get_path(&self) -> &str {
+		&self.path
+	}
+}
+
+impl Terminal for PtyTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.put_bytes(&[value]);
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		// Output is dropped on error as serial line does
+		let _ = self.master.write(values);
+	}
+
+	fn get_input(&mut self) -> u8 {
+		let mut buffer = [0; 1];
+		match self.get_input_bytes(&mut buffer) {
+			0 => 0,
+			_ => buffer[0]
+		}
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.master.read(buffer).unwrap_or(0)
+	}
+
+	fn has_input(&mut self) -> bool {
+		let mut fd = libc::pollfd {
+			fd: self.master.as_raw_fd(),
+			events: libc::POLLIN,
+			revents: 0
+		};
+		unsafe { libc::poll(&mut fd, 1, 0) > 0 && (fd.revents & libc::POLLIN) != 0 }
+	}
+
+	/// Returns the window size the attached program such as `screen` sets.
+	fn get_window_size(&mut self) -> Option<(u16, u16)> {
+		let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+		match unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCGWINSZ, &mut size) } {
+			0 if size.ws_col > 0 && size.ws_row > 0 => Some((size.ws_col, size.ws_row)),
+			_ => None
+		}
+	}
+
+	// Wasm specific methods. No use.
+
+	fn put_input(&mut self, _value: u8) {
+	}
+
+	fn get_output(&mut self) -> u8 {
+		0
+	}
+}
@@ -0,0 +1,632 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use block_backend::{check_access, BlockBackend, FileBlockBackend, SECTOR_SIZE};
+use error::DeviceError;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+
+// Based on qcow2 image format specification
+// https://gitlab.com/qemu-project/qemu/-/blob/master/docs/interop/qcow2.txt
+
+const QCOW2_MAGIC: u32 = 0x514649fb; // "QFI\xfb"
+
+const HEADER_SIZE_V2: usize = 72;
+const HEADER_SIZE_V3: usize = 104;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+// 64KiB. Default of qemu-img.
+const DEFAULT_CLUSTER_BITS: u32 = 16;
+
+// Only 16-bit refcounts are supported. Default of qemu-img.
+const REFCOUNT_ORDER: u32 = 4;
+
+// L1 and L2 table entry bits
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const FLAG_COPIED: u64 = 1 << 63;
+const FLAG_COMPRESSED: u64 = 1 << 62;
+const FLAG_ZERO: u64 = 1;
+
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+
+fn create_error(message: &str) -> io::Error {
+	io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+	let mut bytes = [0; 4];
+	bytes.copy_from_slice(&data[offset..offset + 4]);
+	u32::from_be_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+	let mut bytes = [0; 8];
+	bytes.copy_from_slice(&data[offset..offset + 8]);
+	u64::from_be_bytes(bytes)
+}
+
+/// `BlockBackend` reading and writing a qcow2 disk image, e.g. distro cloud
+/// images, without converting to raw. Unallocated clusters are read from
+/// the backing file if exists, and clusters are allocated at the end of
+/// the image on write. Compressed clusters are readable and are allocated
+/// on write.
+///
+/// Encrypted images, external data files, and refcounts other than 16-bit
+/// are not supported. Images having internal snapshots are read only.
+pub struct Qcow2BlockBackend {
+	file: File,
+	cluster_bits: u32,
+	/// Virtual disk size in bytes
+	size: u64,
+	l1_table: Vec<u64>,
+	l1_table_offset: u64,
+	refcount_table: Vec<u64>,
+	refcount_table_offset: u64,
+	has_snapshots: bool,
+	/// New clusters are allocated from here
+	next_cluster_offset: u64,
+	backing_file_name: Option<String>,
+	backing: Option<Box<dyn BlockBackend>>
+}
+
+impl Qcow2BlockBackend {
+	/// Creates a new `Qcow2BlockBackend`. Backing file isn't opened by this
+	/// method. Set it with `set_backing()` if the image has one, or use `open()`.
+	///
+	/// # Arguments
+	/// * `file` qcow2 image file. Open it with write permission to let the guest write.
+	pub fn new(mut file: File) -> io::Result<Self> {
+		let mut header = vec![0; HEADER_SIZE_V2];
+		file.seek(SeekFrom::Start(0))?;
+		file.read_exact(&mut header)?;
+		if read_u32(&header, 0) != QCOW2_MAGIC {
+			return Err(create_error("Not qcow2 image"));
+		}
+		let version = read_u32(&header, 4);
+		match version {
+			2 => {},
+			3 => {
+				header.resize(HEADER_SIZE_V3, 0);
+				file.read_exact(&mut header[HEADER_SIZE_V2..])?;
+				if read_u64(&header, 72) != 0 {
+					return Err(create_error("Unsupported qcow2 incompatible features"));
+				}
+				if read_u32(&header, 96) != REFCOUNT_ORDER {
+					return Err(create_error("Unsupported qcow2 refcount order"));
+				}
+			},
+			_ => return Err(create_error("Unsupported qcow2 version"))
+		};
+		let backing_file_offset = read_u64(&header, 8);
+		let backing_file_size = read_u32(&header, 16);
+		let cluster_bits = read_u32(&header, 20);
+		let size = read_u64(&header, 24);
+		let crypt_method = read_u32(&header, 32);
+		let l1_size = read_u32(&header, 36);
+		let l1_table_offset = read_u64(&header, 40);
+		let refcount_table_offset = read_u64(&header, 48);
+		let refcount_table_clusters = read_u32(&header, 56);
+		let nb_snapshots = read_u32(&header, 60);
+
+		if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+			return Err(create_error("Invalid qcow2 cluster size"));
+		}
+		if crypt_method != 0 {
+			return Err(create_error("Encrypted qcow2 image is not supported"));
+		}
+
+		let backing_file_name = match backing_file_offset {
+			0 => None,
+			_ => {
+				let mut name = vec![0; backing_file_size as usize];
+				file.seek(SeekFrom::Start(backing_file_offset))?;
+				file.read_exact(&mut name)?;
+				match String::from_utf8(name) {
+					Ok(name) => Some(name),
+					Err(_) => return Err(create_error("Invalid qcow2 backing file name"))
+				}
+			}
+		};
+
+		let l1_table = Self::read_table(&mut file, l1_table_offset, l1_size as usize)?;
+		let refcount_table_size = ((refcount_table_clusters as u64) << cluster_bits) / 8;
+		let refcount_table = Self::read_table(&mut file, refcount_table_offset, refcount_table_size as usize)?;
+
+		let cluster_size = 1 << cluster_bits;
+		let file_size = file.metadata()?.len();
+		Ok(Qcow2BlockBackend {
+			file,
+			cluster_bits,
+			size,
+			l1_table,
+			l1_table_offset,
+			refcount_table,
+			refcount_table_offset,
+			has_snapshots: nb_snapshots != 0,
+			next_cluster_offset: file_size.div_ceil(cluster_size) * cluster_size,
+			backing_file_name,
+			backing: None
+		})
+	}
+
+	/// Creates an empty qcow2 image. Combined with a backing file it works as
+	/// a copy-on-write overlay which keeps only the guest writes, so that many
+	/// emulator instances can share one pristine base image. Backing file
+	/// isn't opened by this method. Set it with `set_backing()`.
+	///
+	/// # Arguments
+	/// * `file` Empty file opened with read and write permission
+	/// * `size` Virtual disk size in bytes
+	/// * `backing_file_name` Backing file path recorded in the image
+	pub fn create(mut file: File, size: u64, backing_file_name: Option<&str>) -> io::Result<Self> {
+		let cluster_bits = DEFAULT_CLUSTER_BITS;
+		let cluster_size = 1u64 << cluster_bits;
+		let backing_file_name = backing_file_name.unwrap_or("");
+		if HEADER_SIZE_V3 + backing_file_name.len() > cluster_size as usize {
+			return Err(io::Error::new(ErrorKind::InvalidInput, "Too long backing file name"));
+		}
+		// Layout: header and backing file name, L1 table, refcount table, refcount block
+		let l1_size = size.div_ceil(cluster_size * (cluster_size / 8));
+		let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+		let l1_table_offset = cluster_size;
+		let refcount_table_offset = l1_table_offset + l1_clusters * cluster_size;
+		let refcount_block_offset = refcount_table_offset + cluster_size;
+		let cluster_num = refcount_block_offset / cluster_size + 1;
+
+		let mut image = vec![0; (cluster_num * cluster_size) as usize];
+		image[0..4].copy_from_slice(&QCOW2_MAGIC.to_be_bytes());
+		image[4..8].copy_from_slice(&3u32.to_be_bytes());
+		if !backing_file_name.is_empty() {
+			image[8..16].copy_from_slice(&(HEADER_SIZE_V3 as u64).to_be_bytes());
+			image[16..20].copy_from_slice(&(backing_file_name.len() as u32).to_be_bytes());
+			image[HEADER_SIZE_V3..HEADER_SIZE_V3 + backing_file_name.len()].copy_from_slice(backing_file_name.as_bytes());
+		}
+		image[20..24].copy_from_slice(&cluster_bits.to_be_bytes());
+		image[24..32].copy_from_slice(&size.to_be_bytes());
+		image[36..40].copy_from_slice(&(l1_size as u32).to_be_bytes());
+		image[40..48].copy_from_slice(&l1_table_offset.to_be_bytes());
+		image[48..56].copy_from_slice(&refcount_table_offset.to_be_bytes());
+		image[56..60].copy_from_slice(&1u32.to_be_bytes());
+		image[96..100].copy_from_slice(&REFCOUNT_ORDER.to_be_bytes());
+		image[100..104].copy_from_slice(&(HEADER_SIZE_V3 as u32).to_be_bytes());
+		let entry = refcount_table_offset as usize;
+		image[entry..entry + 8].copy_from_slice(&refcount_block_offset.to_be_bytes());
+		for i in 0..cluster_num as usize {
+			let entry = refcount_block_offset as usize + i * 2;
+			image[entry..entry + 2].copy_from_slice(&1u16.to_be_bytes());
+		}
+		file.set_len(0)?;
+		file.seek(SeekFrom::Start(0))?;
+		file.write_all(&image)?;
+		Self::new(file)
+	}
+
+	/// Opens a qcow2 image and its backing file chain. Backing files are
+	/// opened read only. Relative backing file path is resolved from
+	/// the directory of the image.
+	///
+	/// # Arguments
+	/// * `path` qcow2 image file path
+	/// * `writable` Whether the guest can write to the image
+	pub fn open<P: AsRef<Path>>(path: P, writable: bool) -> io::Result<Self> {
+		let file = OpenOptions::new().read(true).write(writable).open(path.as_ref())?;
+		let mut backend = Self::new(file)?;
+		if let Some(name) = backend.get_backing_file_name() {
+			let backing_path = match path.as_ref().parent() {
+				Some(directory) => directory.join(name),
+				None => Path::new(&name).to_path_buf()
+			};
+			backend.set_backing(open_disk_image(backing_path, false)?);
+		}
+		Ok(backend)
+	}
+
+	/// Returns the backing file name recorded in the image header.
+	pub fn get_backing_file_name(&self) -> Option<String> {
+		self.backing_file_name.clone()
+	}
+
+	/// Sets backing storage which unallocated clusters are read from.
+	///
+	/// # Arguments
+	/// * `backing`
+	pub fn set_backing(&mut self, backing: Box<dyn BlockBackend>) {
+		self.backing = Some(backing);
+	}
+
+	fn read_table(file: &mut File, offset: u64, size: usize) -> io::Result<Vec<u64>> {
+		let mut data = vec![0; size * 8];
+		file.seek(SeekFrom::Start(offset))?;
+		file.read_exact(&mut data)?;
+		Ok((0..size).map(|i| read_u64(&data, i * 8)).collect())
+	}
+
+	fn get_cluster_size(&self) -> u64 {
+		1 << self.cluster_bits
+	}
+
+	fn read_file(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+		self.file.seek(SeekFrom::Start(offset))?;
+		self.file.read_exact(buffer)
+	}
+
+	fn write_file(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+		self.file.seek(SeekFrom::Start(offset))?;
+		self.file.write_all(data)
+	}
+
+	fn read_entry(&mut self, offset: u64) -> io::Result<u64> {
+		let mut data = [0; 8];
+		self.read_file(offset, &mut data)?;
+		Ok(u64::from_be_bytes(data))
+	}
+
+	fn write_entry(&mut self, offset: u64, entry: u64) -> io::Result<()> {
+		self.write_file(offset, &entry.to_be_bytes())
+	}
+
+	/// Returns L1 table index and L2 table index of a guest offset.
+	fn get_table_indices(&self, offset: u64) -> (usize, u64) {
+		let l2_bits = self.cluster_bits - 3;
+		let l1_index = (offset >> (self.cluster_bits + l2_bits)) as usize;
+		let l2_index = (offset >> self.cluster_bits) & ((1 << l2_bits) - 1);
+		(l1_index, l2_index)
+	}
+
+	/// Returns L2 table entry of a guest offset. Zero if unallocated.
+	fn get_l2_entry(&mut self, offset: u64) -> io::Result<u64> {
+		let (l1_index, l2_index) = self.get_table_indices(offset);
+		if l1_index >= self.l1_table.len() {
+			return Ok(0);
+		}
+		match self.l1_table[l1_index] & OFFSET_MASK {
+			0 => Ok(0),
+			l2_offset => self.read_entry(l2_offset + l2_index * 8)
+		}
+	}
+
+	/// Reads a cluster stored with deflate compression.
+	///
+	/// # Arguments
+	/// * `entry` L2 table entry
+	fn read_compressed_cluster(&mut self, entry: u64) -> io::Result<Vec<u8>> {
+		let cluster_size = self.get_cluster_size();
+		let offset_bits = 62 - (self.cluster_bits - 8);
+		let host_offset = entry & ((1 << offset_bits) - 1);
+		let sector_num = ((entry & !FLAG_COMPRESSED) >> offset_bits) + 1;
+		let length = sector_num * SECTOR_SIZE - (host_offset % SECTOR_SIZE);
+		// The last compressed cluster can end before the end of sectors
+		let file_size = self.file.metadata()?.len();
+		let length = length.min(file_size.saturating_sub(host_offset));
+		let mut data = vec![0; length as usize];
+		self.read_file(host_offset, &mut data)?;
+		match decompress_to_vec_with_limit(&data, cluster_size as usize) {
+			Ok(mut contents) => {
+				contents.resize(cluster_size as usize, 0);
+				Ok(contents)
+			},
+			Err(_) => Err(create_error("Broken qcow2 compressed cluster"))
+		}
+	}
+
+	/// Reads from backing storage. Out of backing range reads zero.
+	fn read_backing(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+		let backing = match self.backing.as_mut() {
+			Some(backing) => backing,
+			None => {
+				buffer.iter_mut().for_each(|b| *b = 0);
+				return Ok(());
+			}
+		};
+		let backing_size = backing.get_sector_num() * SECTOR_SIZE;
+		let length = backing_size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+		if length > 0 && backing.read(offset / SECTOR_SIZE, &mut buffer[..length]).is_err() {
+			return Err(create_error("Failed to read qcow2 backing file"));
+		}
+		buffer[length..].iter_mut().for_each(|b| *b = 0);
+		Ok(())
+	}
+
+	/// Reads data within a cluster.
+	///
+	/// # Arguments
+	/// * `offset` Guest offset
+	/// * `buffer` Must not cross the cluster boundary
+	fn read_cluster(&mut self, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+		let entry = self.get_l2_entry(offset)?;
+		let offset_in_cluster = offset & (self.get_cluster_size() - 1);
+		if (entry & FLAG_COMPRESSED) != 0 {
+			let contents = self.read_compressed_cluster(entry)?;
+			let start = offset_in_cluster as usize;
+			buffer.copy_from_slice(&contents[start..start + buffer.len()]);
+			return Ok(());
+		}
+		if (entry & FLAG_ZERO) != 0 {
+			buffer.iter_mut().for_each(|b| *b = 0);
+			return Ok(());
+		}
+		match entry & OFFSET_MASK {
+			0 => self.read_backing(offset, buffer),
+			host_offset => self.read_file(host_offset + offset_in_cluster, buffer)
+		}
+	}
+
+	/// Sets refcount of a host cluster. Refcount block is allocated if needed.
+	fn set_refcount(&mut self, host_offset: u64, refcount: u16) -> io::Result<()> {
+		let entries_per_block = self.get_cluster_size() / 2;
+		let cluster_index = host_offset >> self.cluster_bits;
+		let table_index = (cluster_index / entries_per_block) as usize;
+		if table_index >= self.refcount_table.len() {
+			// @TODO: Grow refcount table
+			return Err(io::Error::other("qcow2 refcount table is full"));
+		}
+		let block_offset = match self.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK {
+			0 => {
+				let block_offset = self.next_cluster_offset;
+				self.next_cluster_offset += self.get_cluster_size();
+				self.write_file(block_offset, &vec![0; self.get_cluster_size() as usize])?;
+				self.refcount_table[table_index] = block_offset;
+				let entry_offset = self.refcount_table_offset + table_index as u64 * 8;
+				self.write_entry(entry_offset, block_offset)?;
+				self.set_refcount(block_offset, 1)?;
+				block_offset
+			},
+			block_offset => block_offset
+		};
+		let entry_offset = block_offset + (cluster_index % entries_per_block) * 2;
+		self.write_file(entry_offset, &refcount.to_be_bytes())
+	}
+
+	/// Allocates a zero filled host cluster at the end of the image.
+	fn allocate_cluster(&mut self) -> io::Result<u64> {
+		let host_offset = self.next_cluster_offset;
+		self.next_cluster_offset += self.get_cluster_size();
+		self.write_file(host_offset, &vec![0; self.get_cluster_size() as usize])?;
+		self.set_refcount(host_offset, 1)?;
+		Ok(host_offset)
+	}
+
+	/// Returns the host cluster offset of a guest offset which the guest
+	/// can write to in place. L2 table and cluster are allocated if needed,
+	/// and the cluster is filled with the current content.
+	fn get_writable_cluster(&mut self, offset: u64) -> io::Result<u64> {
+		if self.has_snapshots {
+			// @TODO: Support copy-on-write of clusters shared with snapshots
+			return Err(create_error("Writing to qcow2 image having snapshots is not supported"));
+		}
+		let (l1_index, l2_index) = self.get_table_indices(offset);
+		if l1_index >= self.l1_table.len() {
+			return Err(create_error("Too small qcow2 L1 table"));
+		}
+		let l2_offset = match self.l1_table[l1_index] & OFFSET_MASK {
+			0 => {
+				let l2_offset = self.allocate_cluster()?;
+				self.l1_table[l1_index] = l2_offset | FLAG_COPIED;
+				let entry_offset = self.l1_table_offset + l1_index as u64 * 8;
+				self.write_entry(entry_offset, l2_offset | FLAG_COPIED)?;
+				l2_offset
+			},
+			l2_offset => l2_offset
+		};
+		let entry = self.read_entry(l2_offset + l2_index * 8)?;
+		let host_offset = entry & OFFSET_MASK;
+		if (entry & FLAG_COMPRESSED) == 0 && host_offset != 0 {
+			if (entry & FLAG_ZERO) != 0 {
+				// Preallocated zero cluster
+				self.write_file(host_offset, &vec![0; self.get_cluster_size() as usize])?;
+				self.write_entry(l2_offset + l2_index * 8, host_offset | FLAG_COPIED)?;
+			}
+			return Ok(host_offset);
+		}
+		let cluster_offset = offset & !(self.get_cluster_size() - 1);
+		let mut contents = vec![0; self.get_cluster_size() as usize];
+		self.read_cluster(cluster_offset, &mut contents)?;
+		let host_offset = self.allocate_cluster()?;
+		self.write_file(host_offset, &contents)?;
+		self.write_entry(l2_offset + l2_index * 8, host_offset | FLAG_COPIED)?;
+		Ok(host_offset)
+	}
+
+}
+
+impl BlockBackend for Qcow2BlockBackend {
+	fn get_sector_num(&self) -> u64 {
+		self.size / SECTOR_SIZE
+	}
+
+	fn read(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), DeviceError> {
+		check_access(self.get_sector_num(), sector, buffer.len())?;
+		let mut offset = sector * SECTOR_SIZE;
+		let mut done = 0;
+		while done < buffer.len() {
+			let offset_in_cluster = offset & (self.get_cluster_size() - 1);
+			let length = ((self.get_cluster_size() - offset_in_cluster) as usize).min(buffer.len() - done);
+			self.read_cluster(offset, &mut buffer[done..done + length]).map_err(DeviceError::Backend)?;
+			offset += length as u64;
+			done += length;
+		}
+		Ok(())
+	}
+
+	fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), DeviceError> {
+		check_access(self.get_sector_num(), sector, data.len())?;
+		let mut offset = sector * SECTOR_SIZE;
+		let mut done = 0;
+		while done < data.len() {
+			let offset_in_cluster = offset & (self.get_cluster_size() - 1);
+			let length = ((self.get_cluster_size() - offset_in_cluster) as usize).min(data.len() - done);
+			self.get_writable_cluster(offset)
+				.and_then(|host_offset| self.write_file(host_offset + offset_in_cluster, &data[done..done + length]))
+				.map_err(DeviceError::Backend)?;
+			offset += length as u64;
+			done += length;
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self) -> Result<(), DeviceError> {
+		self.file.sync_data().map_err(DeviceError::Backend)
+	}
+}
+
+/// Opens a disk image file detecting its format, qcow2 or raw.
+///
+/// # Arguments
+/// * `path` Disk image file path
+/// * `writable` Whether the guest can write to the image
+pub fn open_disk_image<P: AsRef<Path>>(path: P, writable: bool) -> io::Result<Box<dyn BlockBackend>> {
+	let mut file = OpenOptions::new().read(true).write(writable).open(path.as_ref())?;
+	let mut magic = [0; 4];
+	let is_qcow2 = match file.read_exact(&mut magic) {
+		Ok(()) => u32::from_be_bytes(magic) == QCOW2_MAGIC,
+		Err(_) => false
+	};
+	match is_qcow2 {
+		true => Ok(Box::new(Qcow2BlockBackend::open(path, writable)?)),
+		false => Ok(Box::new(FileBlockBackend::new(file)?))
+	}
+}
+
+#[cfg(test)]
+mod test_qcow2_block_backend {
+	use super::*;
+	use std::env;
+	use std::fs;
+	use std::path::PathBuf;
+	use block_backend::MemoryBlockBackend;
+	use miniz_oxide::deflate::compress_to_vec;
+
+	const CLUSTER_BITS: u32 = 12;
+	const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+	// 1MiB disk
+	const DISK_SIZE: u64 = 0x100000;
+
+	// Layout: header, L1 table, refcount table, refcount block
+	fn create_image(name: &str, backing_file_name: Option<&str>) -> PathBuf {
+		let mut image = vec![0; CLUSTER_SIZE as usize * 4];
+		image[0..4].copy_from_slice(&QCOW2_MAGIC.to_be_bytes());
+		image[4..8].copy_from_slice(&3u32.to_be_bytes());
+		if let Some(backing_file_name) = backing_file_name {
+			let offset = HEADER_SIZE_V3;
+			image[8..16].copy_from_slice(&(offset as u64).to_be_bytes());
+			image[16..20].copy_from_slice(&(backing_file_name.len() as u32).to_be_bytes());
+			image[offset..offset + backing_file_name.len()].copy_from_slice(backing_file_name.as_bytes());
+		}
+		image[20..24].copy_from_slice(&CLUSTER_BITS.to_be_bytes());
+		image[24..32].copy_from_slice(&DISK_SIZE.to_be_bytes());
+		image[36..40].copy_from_slice(&1u32.to_be_bytes());
+		image[40..48].copy_from_slice(&CLUSTER_SIZE.to_be_bytes());
+		image[48..56].copy_from_slice(&(CLUSTER_SIZE * 2).to_be_bytes());
+		image[56..60].copy_from_slice(&1u32.to_be_bytes());
+		image[96..100].copy_from_slice(&REFCOUNT_ORDER.to_be_bytes());
+		image[100..104].copy_from_slice(&(HEADER_SIZE_V3 as u32).to_be_bytes());
+		let table_offset = CLUSTER_SIZE as usize * 2;
+		image[table_offset..table_offset + 8].copy_from_slice(&(CLUSTER_SIZE * 3).to_be_bytes());
+		let block_offset = CLUSTER_SIZE as usize * 3;
+		for i in 0..4 {
+			image[block_offset + i * 2..block_offset + i * 2 + 2].copy_from_slice(&1u16.to_be_bytes());
+		}
+		let path = env::temp_dir().join(format!("riscv_emu_rust_{}_{}.qcow2", name, std::process::id()));
+		fs::write(&path, image).unwrap();
+		path
+	}
+
+	#[test]
+	fn read_write() {
+		let path = create_image("read_write", None);
+		let mut backend = Qcow2BlockBackend::open(&path, true).unwrap();
+		assert_eq!(DISK_SIZE / SECTOR_SIZE, backend.get_sector_num());
+		let mut buffer = vec![0xff; 1024];
+		assert!(backend.read(100, &mut buffer).is_ok());
+		assert_eq!(vec![0; 1024], buffer);
+		// Crosses a cluster boundary
+		let data = (0..CLUSTER_SIZE * 2).map(|i| i as u8).collect::<Vec<u8>>();
+		assert!(backend.write(7, &data).is_ok());
+		assert!(backend.write(DISK_SIZE / SECTOR_SIZE, &data[0..512]).is_err());
+
+		// Written data persists in the image
+		let mut backend = Qcow2BlockBackend::open(&path, false).unwrap();
+		let mut buffer = vec![0; data.len() + 1024];
+		assert!(backend.read(6, &mut buffer).is_ok());
+		assert_eq!(vec![0; 512], buffer[0..512].to_vec());
+		assert_eq!(data, buffer[512..512 + data.len()].to_vec());
+		assert_eq!(vec![0; 512], buffer[512 + data.len()..].to_vec());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn backing() {
+		let path = create_image("backing", Some("base.img"));
+		let mut backend = Qcow2BlockBackend::new(OpenOptions::new().read(true).write(true).open(&path).unwrap()).unwrap();
+		assert_eq!(Some("base.img".to_string()), backend.get_backing_file_name());
+		// Backing is smaller than the disk
+		backend.set_backing(Box::new(MemoryBlockBackend::new(vec![0x11; CLUSTER_SIZE as usize * 2])));
+		let data = vec![0x22; 512];
+		assert!(backend.write(1, &data).is_ok());
+		let mut buffer = vec![0; CLUSTER_SIZE as usize * 3];
+		assert!(backend.read(0, &mut buffer).is_ok());
+		assert_eq!(vec![0x11; 512], buffer[0..512].to_vec());
+		assert_eq!(data, buffer[512..1024].to_vec());
+		assert_eq!(vec![0x11; CLUSTER_SIZE as usize * 2 - 1024], buffer[1024..CLUSTER_SIZE as usize * 2].to_vec());
+		assert_eq!(vec![0; CLUSTER_SIZE as usize], buffer[CLUSTER_SIZE as usize * 2..].to_vec());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn compressed_cluster() {
+		let path = create_image("compressed_cluster", None);
+		let contents = (0..CLUSTER_SIZE).map(|i| (i / 16) as u8).collect::<Vec<u8>>();
+		let compressed = compress_to_vec(&contents, 6);
+		// L2 table at cluster 4 and compressed data at cluster 5
+		let l2_offset = CLUSTER_SIZE * 4;
+		let host_offset = CLUSTER_SIZE * 5;
+		let offset_bits = 62 - (CLUSTER_BITS - 8);
+		let sector_num = (compressed.len() as u64).div_ceil(SECTOR_SIZE);
+		let entry = FLAG_COMPRESSED | ((sector_num - 1) << offset_bits) | host_offset;
+		let mut image = fs::read(&path).unwrap();
+		image.resize(CLUSTER_SIZE as usize * 5, 0);
+		image[CLUSTER_SIZE as usize..CLUSTER_SIZE as usize + 8].copy_from_slice(&(l2_offset | FLAG_COPIED).to_be_bytes());
+		image[l2_offset as usize + 8..l2_offset as usize + 16].copy_from_slice(&entry.to_be_bytes());
+		image.extend_from_slice(&compressed);
+		fs::write(&path, image).unwrap();
+
+		let mut backend = Qcow2BlockBackend::open(&path, true).unwrap();
+		let mut buffer = vec![0; CLUSTER_SIZE as usize];
+		assert!(backend.read(CLUSTER_SIZE / SECTOR_SIZE, &mut buffer).is_ok());
+		assert_eq!(contents, buffer);
+		// Write allocates a new cluster keeping the other part
+		let data = vec![0xaa; 512];
+		assert!(backend.write(CLUSTER_SIZE / SECTOR_SIZE + 1, &data).is_ok());
+		assert!(backend.read(CLUSTER_SIZE / SECTOR_SIZE, &mut buffer).is_ok());
+		assert_eq!(contents[0..512].to_vec(), buffer[0..512].to_vec());
+		assert_eq!(data, buffer[512..1024].to_vec());
+		assert_eq!(contents[1024..].to_vec(), buffer[1024..].to_vec());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn create() {
+		let base_path = env::temp_dir().join(format!("riscv_emu_rust_create_base_{}.img", std::process::id()));
+		let path = env::temp_dir().join(format!("riscv_emu_rust_create_{}.qcow2", std::process::id()));
+		fs::write(&base_path, vec![0x33; DISK_SIZE as usize]).unwrap();
+		let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+		let mut backend = Qcow2BlockBackend::create(file, DISK_SIZE, base_path.to_str()).unwrap();
+		backend.set_backing(open_disk_image(&base_path, false).unwrap());
+		assert_eq!(DISK_SIZE / SECTOR_SIZE, backend.get_sector_num());
+		let data = vec![0x44; 1024];
+		assert!(backend.write(10, &data).is_ok());
+
+		// Backing file is resolved from the recorded path
+		let mut backend = Qcow2BlockBackend::open(&path, false).unwrap();
+		let mut buffer = vec![0; 2048];
+		assert!(backend.read(9, &mut buffer).is_ok());
+		assert_eq!(vec![0x33; 512], buffer[0..512].to_vec());
+		assert_eq!(data, buffer[512..1536].to_vec());
+		assert_eq!(vec![0x33; 512], buffer[1536..].to_vec());
+		// Base image is intact
+		assert_eq!(vec![0x33; DISK_SIZE as usize], fs::read(&base_path).unwrap());
+		fs::remove_file(&path).unwrap();
+		fs::remove_file(&base_path).unwrap();
+	}
+}
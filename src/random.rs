@@ -0,0 +1,31 @@
+// Random numbers for the keys of the zipper stack and the data the user
+// mode emulation gives the program. With std they come from the thread
+// local generator seeded by the OS. Without std there's no entropy
+// source, so they come from generators seeded with a counter: they differ
+// per call but repeat every run. Set the keys in the configuration where
+// that matters.
+
+extern crate rand;
+
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(feature = "std"))]
+use self::rand::SeedableRng;
+#[cfg(not(feature = "std"))]
+use self::rand::rngs::StdRng;
+#[cfg(feature = "std")]
+use self::rand::rngs::ThreadRng;
+
+#[cfg(not(feature = "std"))]
+static SEED: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "std")]
+pub fn create_rng() -> ThreadRng {
+	rand::thread_rng()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn create_rng() -> StdRng {
+	StdRng::seed_from_u64(SEED.fetch_add(1, Ordering::Relaxed) as u64)
+}
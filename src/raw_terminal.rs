@@ -0,0 +1,167 @@
+extern crate libc;
+
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::{Mutex, Once};
+
+use terminal::Terminal;
+
+/// Input byte starting an escape sequence, Ctrl-a. Followed by `x` it
+/// requests to quit the emulator. Other sequences pass through so that
+/// [`TerminalMux`](../terminal_mux/struct.TerminalMux.html) commands
+/// can share the prefix.
+pub const RAW_TERMINAL_ESCAPE: u8 = 0x01;
+
+// Terminal settings before entering raw mode. Restored on drop and on panic.
+static ORIGINAL_TERMIOS: Mutex<Option<libc::termios>> = Mutex::new(None);
+static PANIC_HOOK: Once = Once::new();
+
+/// `Terminal` on the standard input and output of the process in raw mode.
+/// Every keystroke including Ctrl-C and Ctrl-Z goes to the guest as is,
+/// not handled by the host. Press Ctrl-a then x to quit. The host is
+/// notified through the handle `get_quit_request()` returns. The terminal
+/// settings are restored when this terminal is dropped, or on panic.
+/// Only available on Linux.
+pub struct RawTerminal {
+	quit_request: Rc<Cell<bool>>,
+	escaping: bool,
+	input_data: Vec<u8>
+}
+
+impl RawTerminal {
+	/// Creates a new `RawTerminal` and puts the standard input into
+	/// raw mode. Returns `Err` if the standard input isn't a terminal.
+	pub fn new() -> io::Result<Self> {
+		let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+		if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		*ORIGINAL_TERMIOS.lock().unwrap() = Some(termios);
+		PANIC_HOOK.call_once(|| {
+			let hook = std::panic::take_hook();
+			std::panic::set_hook(Box::new(move |info| {
+				restore_terminal();
+				hook(info);
+			}));
+		});
+		unsafe { libc::cfmakeraw(&mut termios) };
+		// Keeps LF to CR LF conversion because some guests output only LF
+		termios.c_oflag |= libc::OPOST | libc::ONLCR;
+		if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(RawTerminal {
+			quit_request: Rc::new(Cell::new(false)),
+			escaping: false,
+			input_data: vec![]
+		})
+	}
+
+	/// Returns the handle which becomes `true` when the user requests
+	/// to quit with Ctrl-a x. The host is expected to poll it and stop
+	/// running the emulator.
+	pub fn get_quit_request(&self) -> Rc<Cell<bool>> {
+		self.quit_request.clone()
+	}
+
+	/// Reads the input typed so far without blocking.
+	fn receive(&mut self) {
+		let mut fd = libc::pollfd {
+			fd: libc::STDIN_FILENO,
+			events: libc::POLLIN,
+			revents: 0
+		};
+		if unsafe { libc::poll(&mut fd, 1, 0) } <= 0 || (fd.revents & libc::POLLIN) == 0 {
+			return;
+		}
+		let mut buffer = [0u8; 64];
+		let size = unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+		for value in buffer[..size.max(0) as usize].iter() {
+			self.handle_input(*value);
+		}
+	}
+
+	fn handle_input(&mut self, value: u8) {
+		match self.escaping {
+			true => {
+				self.escaping = false;
+				match value {
+					b'x' | b'X' => self.quit_request.set(true),
+					_ => self.input_data.extend_from_slice(&[RAW_TERMINAL_ESCAPE, value])
+				};
+			},
+			false => match value {
+				RAW_TERMINAL_ESCAPE => self.escaping = true,
+				_ => self.input_data.push(value)
+			}
+		};
+	}
+}
+
+/// Restores the terminal settings changed by `RawTerminal`. Call this
+/// before `std::process::exit()`, which doesn't drop `RawTerminal`.
+/// Does nothing if the terminal is not in raw mode.
+pub fn restore_terminal() {
+	if let Ok(mut original) = ORIGINAL_TERMIOS.lock() {
+		if let Some(termios) = original.take() {
+			unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+		}
+	}
+}
+
+impl Drop for RawTerminal {
+	fn drop(&mut self) {
+		restore_terminal();
+	}
+}
+
+impl Terminal for RawTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.put_bytes(&[value]);
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		let mut out = io::stdout();
+		// Ignoring error so far
+		let _ = out.write_all(values).and_then(|_| out.flush());
+	}
+
+	fn get_input(&mut self) -> u8 {
+		self.receive();
+		match self.input_data.is_empty() {
+			true => 0,
+			false => self.input_data.remove(0)
+		}
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.receive();
+		let size = buffer.len().min(self.input_data.len());
+		buffer[..size].copy_from_slice(&self.input_data[..size]);
+		self.input_data.drain(..size);
+		size
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.receive();
+		!self.input_data.is_empty()
+	}
+
+	fn get_window_size(&mut self) -> Option<(u16, u16)> {
+		let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+		match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+			0 if size.ws_col > 0 && size.ws_row > 0 => Some((size.ws_col, size.ws_row)),
+			_ => None
+		}
+	}
+
+	// Wasm specific methods. No use.
+
+	fn put_input(&mut self, _value: u8) {
+	}
+
+	fn get_output(&mut self) -> u8 {
+		0
+	}
+}
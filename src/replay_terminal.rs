@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use terminal::Terminal;
+
+/// `Terminal` wrapper which types recorded input to the guest, e.g. the
+/// content of a file, for unattended reproduction of interactive sessions.
+/// The input is paced like a human typing because guests often drop or
+/// mishandle input arriving before they are ready, e.g. during boot.
+/// The first byte is input `delay` after the terminal is created and each
+/// following byte `interval` after the previous one. The input from
+/// the inner terminal is also handed to the guest, and the output passes
+/// through to it.
+pub struct ReplayTerminal {
+	terminal: Box<dyn Terminal>,
+	input_data: VecDeque<u8>,
+	interval: Duration,
+	// When the next byte is input
+	next_time: Instant
+}
+
+impl ReplayTerminal {
+	/// Creates a new `ReplayTerminal`. The replay starts immediately.
+	///
+	/// # Arguments
+	/// * `terminal` Inner terminal
+	/// * `data` Input bytes to replay
+	/// * `delay` Time to wait before the first byte
+	/// * `interval` Time between bytes. Zero inputs all at once after `delay`
+	pub fn new(terminal: Box<dyn Terminal>, data: Vec<u8>, delay: Duration, interval: Duration) -> Self {
+		ReplayTerminal {
+			terminal,
+			input_data: data.into_iter().collect(),
+			interval,
+			next_time: Instant::now() + delay
+		}
+	}
+
+	/// Returns `true` if all the bytes have been input.
+	pub fn is_finished(&self) -> bool {
+		self.input_data.is_empty()
+	}
+
+	/// Returns the number of the bytes ready to input now.
+	fn available(&self) -> usize {
+		if self.input_data.is_empty() || Instant::now() < self.next_time {
+			return 0;
+		}
+		match self.interval.as_nanos() {
+			0 => self.input_data.len(),
+			_ => 1
+		}
+	}
+
+	fn replay_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		let size = buffer.len().min(self.available());
+		for (i, value) in self.input_data.drain(..size).enumerate() {
+			buffer[i] = value;
+		}
+		if size > 0 {
+			self.next_time = Instant::now() + self.interval;
+		}
+		size
+	}
+}
+
+impl Terminal for ReplayTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.terminal.put_byte(value);
+	}
+
+	fn get_output(&mut self) -> u8 {
+		self.terminal.get_output()
+	}
+
+	fn put_input(&mut self, data: u8) {
+		self.terminal.put_input(data);
+	}
+
+	fn get_input(&mut self) -> u8 {
+		let mut buffer = [0; 1];
+		match self.replay_bytes(&mut buffer) {
+			0 => self.terminal.get_input(),
+			_ => buffer[0]
+		}
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		self.terminal.put_bytes(values);
+	}
+
+	fn get_output_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.terminal.get_output_bytes(buffer)
+	}
+
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		self.terminal.put_input_bytes(data);
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		match self.replay_bytes(buffer) {
+			0 => self.terminal.get_input_bytes(buffer),
+			size => size
+		}
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.available() > 0 || self.terminal.has_input()
+	}
+
+	fn get_window_size(&mut self) -> Option<(u16, u16)> {
+		self.terminal.get_window_size()
+	}
+
+	fn is_output_ready(&mut self) -> bool {
+		self.terminal.is_output_ready()
+	}
+}
+
+#[cfg(test)]
+mod test_replay_terminal {
+	use super::*;
+	use default_terminal::DefaultTerminal;
+
+	#[test]
+	fn pacing() {
+		let inner = Box::new(DefaultTerminal::new());
+		let mut terminal = ReplayTerminal::new(inner, b"ls\n".to_vec(), Duration::ZERO, Duration::ZERO);
+		let mut buffer = [0; 8];
+		assert_eq!(3, terminal.get_input_bytes(&mut buffer));
+		assert_eq!(b"ls\n", &buffer[..3]);
+		assert!(terminal.is_finished());
+		assert!(!terminal.has_input());
+
+		let inner = Box::new(DefaultTerminal::new());
+		let mut terminal = ReplayTerminal::new(inner, b"ab".to_vec(), Duration::ZERO, Duration::from_secs(60));
+		assert_eq!(b'a', terminal.get_input());
+		// The next byte waits for the interval
+		assert!(!terminal.has_input());
+		assert_eq!(0, terminal.get_input());
+		assert!(!terminal.is_finished());
+		// The input from the inner terminal isn't paced
+		terminal.put_input(b'x');
+		assert_eq!(b'x', terminal.get_input());
+	}
+}
@@ -0,0 +1,348 @@
+extern crate regex_lite;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use self::regex_lite::Regex;
+use terminal::Terminal;
+
+/// Default time to wait for an expected pattern
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Partial output line kept to match. Older data is dropped beyond this.
+const MAX_LINE_LENGTH: usize = 0x1000;
+
+/// A step of the script `ScriptedTerminal` runs.
+pub enum ScriptStep {
+	/// Waits until the guest outputs text matching the pattern
+	Expect(Regex),
+	/// Inputs the bytes to the guest
+	Send(Vec<u8>),
+	/// Fails the script whenever the guest outputs a line matching
+	/// the pattern from now on
+	Fail(Regex),
+	/// Sets the time to wait for the following `Expect` steps
+	Timeout(Duration)
+}
+
+/// Result of the script.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptResult {
+	Running,
+	/// All the steps have been done
+	Passed,
+	/// Reason why the script has failed
+	Failed(String)
+}
+
+/// Parses a script in text, one step per line. Empty lines and lines
+/// starting with `#` are ignored. Returns the line number, starting with
+/// one, of the first invalid line on error.
+///
+/// ```text
+/// timeout 30
+/// fail Kernel panic
+/// expect login:
+/// send root\r
+/// expect \$ $
+/// send ls\r
+/// expect README
+/// ```
+///
+/// `send` takes `\r`, `\n`, `\t`, `\\` and `\xNN` escapes. `expect` and
+/// `fail` take regular expressions. `timeout` takes seconds.
+///
+/// # Arguments
+/// * `text`
+pub fn parse_script(text: &str) -> Result<Vec<ScriptStep>, usize> {
+	let mut steps = vec![];
+	for (i, line) in text.lines().enumerate() {
+		let line = line.trim_end_matches('\r');
+		if line.trim().is_empty() || line.trim_start().starts_with('#') {
+			continue;
+		}
+		let (command, argument) = match line.find(' ') {
+			Some(position) => (&line[..position], &line[position + 1..]),
+			None => (line, "")
+		};
+		let step = match command {
+			"expect" => Regex::new(argument).ok().map(ScriptStep::Expect),
+			"fail" => Regex::new(argument).ok().map(ScriptStep::Fail),
+			"send" => unescape(argument).map(ScriptStep::Send),
+			"timeout" => argument.trim().parse::<u64>().ok()
+				.map(|seconds| ScriptStep::Timeout(Duration::from_secs(seconds))),
+			_ => None
+		};
+		match step {
+			Some(step) => steps.push(step),
+			None => return Err(i + 1)
+		};
+	}
+	Ok(steps)
+}
+
+fn unescape(text: &str) -> Option<Vec<u8>> {
+	let mut data = vec![];
+	let mut bytes = text.bytes();
+	while let Some(value) = bytes.next() {
+		if value != b'\\' {
+			data.push(value);
+			continue;
+		}
+		data.push(match bytes.next()? {
+			b'r' => b'\r',
+			b'n' => b'\n',
+			b't' => b'\t',
+			b'\\' => b'\\',
+			b'x' => {
+				let digits = [bytes.next()?, bytes.next()?];
+				u8::from_str_radix(std::str::from_utf8(&digits).ok()?, 16).ok()?
+			},
+			_ => return None
+		});
+	}
+	Some(data)
+}
+
+/// `Terminal` wrapper which runs a script like `expect` command against
+/// the guest console, for driving guest images in integration tests.
+/// It waits for patterns in the guest output and then inputs canned data.
+/// Patterns are matched against the current line, so they don't span
+/// lines. The output and the input pass through to the inner terminal,
+/// and the input from the inner terminal is also handed to the guest.
+/// The host knows the result through the handle `get_result()` returns.
+pub struct ScriptedTerminal {
+	terminal: Box<dyn Terminal>,
+	steps: VecDeque<ScriptStep>,
+	fail_patterns: Vec<Regex>,
+	timeout: Duration,
+	// When the current expect step times out
+	deadline: Option<Instant>,
+	line: Vec<u8>,
+	input_data: VecDeque<u8>,
+	result: Rc<RefCell<ScriptResult>>
+}
+
+impl ScriptedTerminal {
+	/// Creates a new `ScriptedTerminal`. The script starts immediately.
+	///
+	/// # Arguments
+	/// * `terminal` Inner terminal
+	/// * `steps` See `parse_script()`
+	pub fn new(terminal: Box<dyn Terminal>, steps: Vec<ScriptStep>) -> Self {
+		let mut scripted_terminal = ScriptedTerminal {
+			terminal,
+			steps: steps.into_iter().collect(),
+			fail_patterns: vec![],
+			timeout: DEFAULT_TIMEOUT,
+			deadline: None,
+			line: vec![],
+			input_data: VecDeque::new(),
+			result: Rc::new(RefCell::new(ScriptResult::Running))
+		};
+		scripted_terminal.advance();
+		scripted_terminal
+	}
+
+	/// Returns the handle of the result. It is updated as the script runs
+	/// so that the host can poll it while `Emulator` owns the terminal.
+	pub fn get_result(&self) -> Rc<RefCell<ScriptResult>> {
+		self.result.clone()
+	}
+
+	fn is_running(&self) -> bool {
+		*self.result.borrow() == ScriptResult::Running
+	}
+
+	fn finish(&mut self, result: ScriptResult) {
+		*self.result.borrow_mut() = result;
+		self.steps.clear();
+		self.deadline = None;
+	}
+
+	/// Runs the steps until an expect step which waits for output.
+	fn advance(&mut self) {
+		while let Some(step) = self.steps.front() {
+			match step {
+				ScriptStep::Expect(_) => {
+					if self.deadline.is_none() {
+						self.deadline = Some(Instant::now() + self.timeout);
+					}
+					return;
+				},
+				ScriptStep::Send(data) => self.input_data.extend(data),
+				ScriptStep::Fail(pattern) => self.fail_patterns.push(pattern.clone()),
+				ScriptStep::Timeout(timeout) => self.timeout = *timeout
+			};
+			self.steps.pop_front();
+		}
+		if self.is_running() {
+			self.finish(ScriptResult::Passed);
+		}
+	}
+
+	fn check_timeout(&mut self) {
+		if let Some(deadline) = self.deadline {
+			if Instant::now() >= deadline {
+				let message = match self.steps.front() {
+					Some(ScriptStep::Expect(pattern)) => format!("Timed out waiting for `{}`", pattern.as_str()),
+					_ => "Timed out".to_string()
+				};
+				self.finish(ScriptResult::Failed(message));
+			}
+		}
+	}
+
+	fn handle_output(&mut self, values: &[u8]) {
+		if !self.is_running() {
+			return;
+		}
+		for value in values.iter() {
+			if *value == b'\n' {
+				let line = String::from_utf8_lossy(&self.line).into_owned();
+				for pattern in self.fail_patterns.iter() {
+					if pattern.is_match(&line) {
+						let message = format!("Matched `{}`", pattern.as_str());
+						return self.finish(ScriptResult::Failed(message));
+					}
+				}
+				self.line.clear();
+				continue;
+			}
+			self.line.push(*value);
+			if self.line.len() > MAX_LINE_LENGTH {
+				self.line.remove(0);
+			}
+			// The matched text is consumed so that the next step
+			// doesn't match it again
+			let end = match self.steps.front() {
+				Some(ScriptStep::Expect(pattern)) => {
+					match pattern.find(&String::from_utf8_lossy(&self.line)) {
+						Some(found) => found.end(),
+						None => continue
+					}
+				},
+				_ => continue
+			};
+			self.line.drain(..end.min(self.line.len()));
+			self.steps.pop_front();
+			self.deadline = None;
+			self.advance();
+		}
+		self.check_timeout();
+	}
+}
+
+impl Terminal for ScriptedTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.handle_output(&[value]);
+		self.terminal.put_byte(value);
+	}
+
+	fn get_output(&mut self) -> u8 {
+		self.terminal.get_output()
+	}
+
+	fn put_input(&mut self, data: u8) {
+		self.terminal.put_input(data);
+	}
+
+	fn get_input(&mut self) -> u8 {
+		self.check_timeout();
+		match self.input_data.pop_front() {
+			Some(value) => value,
+			None => self.terminal.get_input()
+		}
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		self.handle_output(values);
+		self.terminal.put_bytes(values);
+	}
+
+	fn get_output_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.terminal.get_output_bytes(buffer)
+	}
+
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		self.terminal.put_input_bytes(data);
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.check_timeout();
+		match self.input_data.is_empty() {
+			true => self.terminal.get_input_bytes(buffer),
+			false => {
+				let size = buffer.len().min(self.input_data.len());
+				for (i, value) in self.input_data.drain(..size).enumerate() {
+					buffer[i] = value;
+				}
+				size
+			}
+		}
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.check_timeout();
+		!self.input_data.is_empty() || self.terminal.has_input()
+	}
+
+	fn get_window_size(&mut self) -> Option<(u16, u16)> {
+		self.terminal.get_window_size()
+	}
+
+	fn is_output_ready(&mut self) -> bool {
+		self.terminal.is_output_ready()
+	}
+}
+
+#[cfg(test)]
+mod test_scripted_terminal {
+	use super::*;
+	use default_terminal::DefaultTerminal;
+
+	fn read_input(terminal: &mut ScriptedTerminal) -> Vec<u8> {
+		let mut buffer = [0; 64];
+		let size = terminal.get_input_bytes(&mut buffer);
+		buffer[..size].to_vec()
+	}
+
+	#[test]
+	fn parse() {
+		let steps = parse_script("# login\ntimeout 5\nexpect login: $\nsend root\\r\\x01\n\nfail panic").unwrap();
+		assert_eq!(4, steps.len());
+		match &steps[2] {
+			ScriptStep::Send(data) => assert_eq!(b"root\r\x01".to_vec(), *data),
+			_ => panic!()
+		};
+		assert_eq!(Err(2), parse_script("expect a\nsend \\q").map(|_| ()));
+		assert_eq!(Err(1), parse_script("wait 1").map(|_| ()));
+	}
+
+	#[test]
+	fn run() {
+		let steps = parse_script("fail panic\nexpect login: $\nsend root\\r\nexpect # $").unwrap();
+		let mut terminal = ScriptedTerminal::new(Box::new(DefaultTerminal::new()), steps);
+		let result = terminal.get_result();
+		assert!(!terminal.has_input());
+		terminal.put_bytes(b"Welcome\r\nlogin: ");
+		assert_eq!(b"root\r".to_vec(), read_input(&mut terminal));
+		assert_eq!(ScriptResult::Running, *result.borrow());
+		terminal.put_bytes(b"root\r\n# ");
+		assert_eq!(ScriptResult::Passed, *result.borrow());
+		// Output passes through
+		assert_eq!(b'W', terminal.get_output());
+
+		let steps = parse_script("fail panic\nexpect never").unwrap();
+		let mut terminal = ScriptedTerminal::new(Box::new(DefaultTerminal::new()), steps);
+		terminal.put_bytes(b"Kernel panic\n");
+		assert_eq!(ScriptResult::Failed("Matched `panic`".to_string()), *terminal.get_result().borrow());
+
+		let steps = parse_script("timeout 0\nexpect never").unwrap();
+		let mut terminal = ScriptedTerminal::new(Box::new(DefaultTerminal::new()), steps);
+		terminal.has_input();
+		assert_eq!(ScriptResult::Failed("Timed out waiting for `never`".to_string()), *terminal.get_result().borrow());
+	}
+}
@@ -0,0 +1,129 @@
+// Helpers for the `serde` feature, which makes the configuration and
+// the snapshot types serializable so frontends can persist them in
+// formats of their choice.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serializer};
+
+/// Format version of serialized `EmulatorConfig`. Bump it on
+/// an incompatible change of the configuration types.
+pub const CONFIG_FORMAT_VERSION: u32 = 1;
+
+/// Format version of serialized `Snapshot`. Bump it on an incompatible
+/// change of the snapshot types, e.g. a new CPU state field.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// Implements `Serialize` and `Deserialize` of a type deriving them with
+/// `#[serde(remote = "Self")]`, putting the derived representation in
+/// `{ version, value }` envelope. Deserialization fails on the version
+/// other than the current one.
+macro_rules! impl_versioned_serde {
+	($type:ident, $name:literal, $version:expr) => {
+		impl ::serde::Serialize for $type {
+			fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+				use ::serde::ser::SerializeStruct;
+				struct Value<'a>(&'a $type);
+				impl<'a> ::serde::Serialize for Value<'a> {
+					fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+						$type::serialize(self.0, serializer)
+					}
+				}
+				let mut state = serializer.serialize_struct($name, 2)?;
+				state.serialize_field("version", &$version)?;
+				state.serialize_field("value", &Value(self))?;
+				state.end()
+			}
+		}
+
+		impl<'de> ::serde::Deserialize<'de> for $type {
+			fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+				struct Value($type);
+				impl<'de> ::serde::Deserialize<'de> for Value {
+					fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+						$type::deserialize(deserializer).map(Value)
+					}
+				}
+				#[derive(Deserialize)]
+				#[serde(rename = $name)]
+				struct Versioned {
+					version: u32,
+					value: Value
+				}
+				let versioned = Versioned::deserialize(deserializer)?;
+				match versioned.version == $version {
+					true => Ok(versioned.value.0),
+					false => Err(::serde::de::Error::custom(format!(
+						"Unsupported {} format version {}, expected {}",
+						$name, versioned.version, $version
+					)))
+				}
+			}
+		}
+	};
+}
+
+/// (De)serializes a boxed `u64` array as a sequence. Serde derives
+/// arrays only up to 32 elements.
+pub mod boxed_array {
+	use super::*;
+
+	pub fn serialize<S: Serializer, const N: usize>(array: &[u64; N], serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_seq(array.iter())
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<Box<[u64; N]>, D::Error> {
+		to_boxed_array(Vec::<u64>::deserialize(deserializer)?)
+	}
+}
+
+/// (De)serializes sparse pages, `None` for the page not allocated,
+/// as a sequence of optional sequences.
+pub mod boxed_array_pages {
+	use super::*;
+
+	type Pages<const N: usize> = Vec<Option<Box<[u64; N]>>>;
+
+	pub fn serialize<S: Serializer, const N: usize>(pages: &[Option<Box<[u64; N]>>], serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_seq(pages.iter().map(|page| page.as_ref().map(|page| &page[..])))
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<Pages<N>, D::Error> {
+		let mut pages = vec![];
+		for page in Vec::<Option<Vec<u64>>>::deserialize(deserializer)? {
+			pages.push(match page {
+				Some(page) => Some(to_boxed_array(page)?),
+				None => None
+			});
+		}
+		Ok(pages)
+	}
+}
+
+/// (De)serializes floating point registers as their bit patterns, which
+/// keeps NaN-boxing and NaN payloads in any format.
+pub mod f64_bits {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(registers: &[f64; 32], serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_seq(registers.iter().map(|value| value.to_bits()))
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[f64; 32], D::Error> {
+		let bits = <[u64; 32]>::deserialize(deserializer)?;
+		let mut registers = [0.0; 32];
+		for (register, bits) in registers.iter_mut().zip(bits.iter()) {
+			*register = f64::from_bits(*bits);
+		}
+		Ok(registers)
+	}
+}
+
+fn to_boxed_array<E: Error, const N: usize>(values: Vec<u64>) -> Result<Box<[u64; N]>, E> {
+	let length = values.len();
+	values.into_boxed_slice().try_into()
+		.map_err(|_| E::invalid_length(length, &format!("{} elements", N).as_str()))
+}
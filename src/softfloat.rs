@@ -0,0 +1,748 @@
+// IEEE 754 binary floating point arithmetic in software, with the results
+// RISC-V specifies: every rounding mode, the exception flags of fflags,
+// tininess detected after rounding, and the canonical NaN for any NaN
+// result. Values are passed in raw bits of the format, lower 32 bits for
+// single precision, so NaN payloads never go through host arithmetic.
+
+use core::cmp::Ordering;
+
+/// Inexact exception flag, in the bit position of `fflags`
+pub const FLAG_INEXACT: u8 = 0x1;
+/// Underflow exception flag
+pub const FLAG_UNDERFLOW: u8 = 0x2;
+/// Overflow exception flag
+pub const FLAG_OVERFLOW: u8 = 0x4;
+/// Divide by zero exception flag
+pub const FLAG_DIVIDE_BY_ZERO: u8 = 0x8;
+/// Invalid operation exception flag
+pub const FLAG_INVALID: u8 = 0x10;
+
+/// Binary floating point format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FloatFormat {
+	exponent_bits: u32,
+	fraction_bits: u32
+}
+
+/// Single precision of F extension
+pub const SINGLE: FloatFormat = FloatFormat {
+	exponent_bits: 8,
+	fraction_bits: 23
+};
+
+/// Double precision of D extension
+pub const DOUBLE: FloatFormat = FloatFormat {
+	exponent_bits: 11,
+	fraction_bits: 52
+};
+
+/// Rounding mode, in the encoding of `frm` and the `rm` field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoundingMode {
+	/// Round to nearest, ties to even
+	NearestEven,
+	/// Round towards zero
+	TowardZero,
+	/// Round down, towards negative infinity
+	Down,
+	/// Round up, towards positive infinity
+	Up,
+	/// Round to nearest, ties to max magnitude
+	NearestMaxMagnitude
+}
+
+/// Returns the rounding mode of the encoding, or `None` for the reserved
+/// ones and the dynamic rounding mode 7.
+///
+/// # Arguments
+/// * `encoding`
+pub fn get_rounding_mode(encoding: u64) -> Option<RoundingMode> {
+	match encoding {
+		0 => Some(RoundingMode::NearestEven),
+		1 => Some(RoundingMode::TowardZero),
+		2 => Some(RoundingMode::Down),
+		3 => Some(RoundingMode::Up),
+		4 => Some(RoundingMode::NearestMaxMagnitude),
+		_ => None
+	}
+}
+
+impl FloatFormat {
+	fn get_bias(&self) -> i32 {
+		(1 << (self.exponent_bits - 1)) - 1
+	}
+
+	// Biased exponent of infinities and NaNs
+	fn get_max_exponent(&self) -> u64 {
+		(1 << self.exponent_bits) - 1
+	}
+
+	fn get_sign_bit(&self) -> u64 {
+		1 << (self.exponent_bits + self.fraction_bits)
+	}
+
+	fn get_fraction_mask(&self) -> u64 {
+		(1 << self.fraction_bits) - 1
+	}
+
+	// Bits of the significand including the implicit one
+	fn get_precision(&self) -> i32 {
+		self.fraction_bits as i32 + 1
+	}
+
+	// Exponent of the least significant bit of subnormal numbers
+	fn get_min_lsb_exponent(&self) -> i32 {
+		1 - self.get_bias() - self.fraction_bits as i32
+	}
+
+	/// Returns the canonical NaN RISC-V returns for NaN results.
+	pub fn get_canonical_nan(&self) -> u64 {
+		(self.get_max_exponent() << self.fraction_bits) | (1 << (self.fraction_bits - 1))
+	}
+
+	fn get_sign(&self, sign: bool) -> u64 {
+		match sign {
+			true => self.get_sign_bit(),
+			false => 0
+		}
+	}
+
+	fn get_zero(&self, sign: bool) -> u64 {
+		self.get_sign(sign)
+	}
+
+	fn get_infinity(&self, sign: bool) -> u64 {
+		self.get_sign(sign) | (self.get_max_exponent() << self.fraction_bits)
+	}
+
+	fn get_max_finite(&self, sign: bool) -> u64 {
+		self.get_sign(sign) | ((self.get_max_exponent() - 1) << self.fraction_bits) | self.get_fraction_mask()
+	}
+
+	fn unpack(&self, bits: u64) -> Value {
+		let sign = (bits & self.get_sign_bit()) != 0;
+		let exponent = (bits >> self.fraction_bits) & self.get_max_exponent();
+		let fraction = bits & self.get_fraction_mask();
+		match (exponent, fraction) {
+			(0, 0) => Value::Zero(sign),
+			(0, _) => Value::Finite(sign, self.get_min_lsb_exponent(), fraction as u128),
+			_ if exponent == self.get_max_exponent() => match fraction {
+				0 => Value::Infinity(sign),
+				// The most significant fraction bit is clear in signaling NaNs
+				_ => Value::NaN((fraction >> (self.fraction_bits - 1)) == 0)
+			},
+			_ => Value::Finite(sign, exponent as i32 - self.get_bias() - self.fraction_bits as i32,
+				(fraction | (1 << self.fraction_bits)) as u128)
+		}
+	}
+
+	// Rounds sign * significand * 2^exponent to the format. The significand
+	// must not be zero
+	fn round_pack(&self, sign: bool, exponent: i32, significand: u128, rounding_mode: RoundingMode) -> (u64, u8) {
+		let precision = self.get_precision();
+		let min_normal_exponent = self.get_min_lsb_exponent() + self.fraction_bits as i32;
+		// Exponent of the leading bit
+		let leading = exponent + 127 - significand.leading_zeros() as i32;
+		let lsb = (leading - (precision - 1)).max(self.get_min_lsb_exponent());
+		let (mut kept, inexact) = round_significand(significand, lsb - exponent, sign, rounding_mode);
+		let mut lsb = lsb;
+		if (kept >> precision) != 0 {
+			kept >>= 1;
+			lsb += 1;
+		}
+		let mut flags = match inexact {
+			true => FLAG_INEXACT,
+			false => 0
+		};
+		// Tiny if the result rounded with unbounded exponent is below
+		// the smallest normal number
+		if inexact && leading < min_normal_exponent {
+			let (unbounded, _) = round_significand(significand, leading - (precision - 1) - exponent, sign, rounding_mode);
+			if leading < min_normal_exponent - 1 || (unbounded >> precision) == 0 {
+				flags |= FLAG_UNDERFLOW;
+			}
+		}
+		// Subnormal numbers and zero
+		if (kept >> (precision - 1)) == 0 {
+			return (self.get_sign(sign) | kept as u64, flags);
+		}
+		let exponent = (lsb + self.fraction_bits as i32 + self.get_bias()) as u64;
+		if exponent >= self.get_max_exponent() {
+			let to_infinity = match rounding_mode {
+				RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => true,
+				RoundingMode::TowardZero => false,
+				RoundingMode::Down => sign,
+				RoundingMode::Up => !sign
+			};
+			let result = match to_infinity {
+				true => self.get_infinity(sign),
+				false => self.get_max_finite(sign)
+			};
+			return (result, flags | FLAG_OVERFLOW | FLAG_INEXACT);
+		}
+		(self.get_sign(sign) | (exponent << self.fraction_bits) | (kept as u64 & self.get_fraction_mask()), flags)
+	}
+}
+
+#[derive(Clone, Copy)]
+enum Value {
+	/// Signaling or not
+	NaN(bool),
+	Infinity(bool),
+	Zero(bool),
+	/// sign, exponent, and significand of sign * significand * 2^exponent
+	Finite(bool, i32, u128)
+}
+
+// Drops the lower bits of the significand, rounding the rest. Returns
+// the rounded significand and whether any bit dropped is set
+fn round_significand(significand: u128, shift: i32, sign: bool, rounding_mode: RoundingMode) -> (u128, bool) {
+	if shift <= 0 {
+		return (significand << -shift, false);
+	}
+	let (kept, rest) = match shift >= 128 {
+		true => (0, significand),
+		false => (significand >> shift, significand & ((1 << shift) - 1))
+	};
+	let half = match shift > 128 {
+		true => Ordering::Less,
+		false => rest.cmp(&(1 << (shift - 1)))
+	};
+	let round_up = match rounding_mode {
+		RoundingMode::NearestEven => half == Ordering::Greater || (half == Ordering::Equal && (kept & 1) == 1),
+		RoundingMode::NearestMaxMagnitude => half != Ordering::Less,
+		RoundingMode::TowardZero => false,
+		RoundingMode::Down => sign && rest != 0,
+		RoundingMode::Up => !sign && rest != 0
+	};
+	(kept + round_up as u128, rest != 0)
+}
+
+// Shifts the significand to have the leading bit at bit 110
+fn normalize(exponent: i32, significand: u128) -> (i32, u128) {
+	let shift = significand.leading_zeros() as i32 - 17;
+	(exponent - shift, significand << shift)
+}
+
+// Returns the NaN result of the operands, invalid if any is signaling
+fn propagate_nan(format: &FloatFormat, values: &[Value]) -> (u64, u8) {
+	let signaling = values.iter().any(|value| matches!(value, Value::NaN(true)));
+	let flags = match signaling {
+		true => FLAG_INVALID,
+		false => 0
+	};
+	(format.get_canonical_nan(), flags)
+}
+
+fn is_nan(value: &Value) -> bool {
+	matches!(value, Value::NaN(_))
+}
+
+// Adds two nonzero finite numbers of up to 110 bits significands
+fn add_finite(format: &FloatFormat, a: (bool, i32, u128), b: (bool, i32, u128), rounding_mode: RoundingMode) -> (u64, u8) {
+	let (exponent_a, significand_a) = normalize(a.1, a.2);
+	let (exponent_b, significand_b) = normalize(b.1, b.2);
+	let ((sign_large, exponent_large, large), (sign_small, exponent_small, small)) = match exponent_a >= exponent_b {
+		true => ((a.0, exponent_a, significand_a), (b.0, exponent_b, significand_b)),
+		false => ((b.0, exponent_b, significand_b), (a.0, exponent_a, significand_a))
+	};
+	// Exact if the exponents are close. Otherwise the smaller one is far
+	// below the rounding position and only sticks to the lowest bit
+	let difference = exponent_large - exponent_small;
+	let (exponent, large, small) = match difference <= 16 {
+		true => (exponent_small, large << difference, small),
+		false => {
+			let shift = difference - 16;
+			let (shifted, sticky) = match shift >= 128 {
+				true => (0, true),
+				false => (small >> shift, ((small >> shift) << shift) != small)
+			};
+			(exponent_large - 16, large << 16, shifted | sticky as u128)
+		}
+	};
+	let (sign, significand) = match (sign_large == sign_small, large >= small) {
+		(true, _) => (sign_large, large + small),
+		(false, true) => (sign_large, large - small),
+		(false, false) => (sign_small, small - large)
+	};
+	match significand {
+		0 => (format.get_zero(rounding_mode == RoundingMode::Down), 0),
+		_ => format.round_pack(sign, exponent, significand, rounding_mode)
+	}
+}
+
+/// Returns `a + b` and the exception flags.
+///
+/// # Arguments
+/// * `format`
+/// * `a`
+/// * `b`
+/// * `rounding_mode`
+pub fn add(format: &FloatFormat, a: u64, b: u64, rounding_mode: RoundingMode) -> (u64, u8) {
+	let (x, y) = (format.unpack(a), format.unpack(b));
+	match (x, y) {
+		_ if is_nan(&x) || is_nan(&y) => propagate_nan(format, &[x, y]),
+		(Value::Infinity(sign_x), Value::Infinity(sign_y)) if sign_x != sign_y => (format.get_canonical_nan(), FLAG_INVALID),
+		(Value::Infinity(sign), _) | (_, Value::Infinity(sign)) => (format.get_infinity(sign), 0),
+		(Value::Zero(sign_x), Value::Zero(sign_y)) => match sign_x == sign_y {
+			true => (format.get_zero(sign_x), 0),
+			false => (format.get_zero(rounding_mode == RoundingMode::Down), 0)
+		},
+		(Value::Zero(_), _) => (b, 0),
+		(_, Value::Zero(_)) => (a, 0),
+		(Value::Finite(sign_x, exponent_x, significand_x), Value::Finite(sign_y, exponent_y, significand_y)) =>
+			add_finite(format, (sign_x, exponent_x, significand_x), (sign_y, exponent_y, significand_y), rounding_mode),
+		_ => unreachable!()
+	}
+}
+
+/// Returns `a - b` and the exception flags.
+///
+/// # Arguments
+/// * `format`
+/// * `a`
+/// * `b`
+/// * `rounding_mode`
+pub fn sub(format: &FloatFormat, a: u64, b: u64, rounding_mode: RoundingMode) -> (u64, u8) {
+	match is_nan(&format.unpack(b)) {
+		true => add(format, a, b, rounding_mode),
+		false => add(format, a, b ^ format.get_sign_bit(), rounding_mode)
+	}
+}
+
+/// Returns `a * b` and the exception flags.
+///
+/// # Arguments
+/// * `format`
+/// * `a`
+/// * `b`
+/// * `rounding_mode`
+pub fn mul(format: &FloatFormat, a: u64, b: u64, rounding_mode: RoundingMode) -> (u64, u8) {
+	let (x, y) = (format.unpack(a), format.unpack(b));
+	let sign = ((a ^ b) & format.get_sign_bit()) != 0;
+	match (x, y) {
+		_ if is_nan(&x) || is_nan(&y) => propagate_nan(format, &[x, y]),
+		(Value::Infinity(_), Value::Zero(_)) | (Value::Zero(_), Value::Infinity(_)) => (format.get_canonical_nan(), FLAG_INVALID),
+		(Value::Infinity(_), _) | (_, Value::Infinity(_)) => (format.get_infinity(sign), 0),
+		(Value::Zero(_), _) | (_, Value::Zero(_)) => (format.get_zero(sign), 0),
+		(Value::Finite(_, exponent_x, significand_x), Value::Finite(_, exponent_y, significand_y)) =>
+			format.round_pack(sign, exponent_x + exponent_y, significand_x * significand_y, rounding_mode),
+		_ => unreachable!()
+	}
+}
+
+/// Returns `a / b` and the exception flags.
+///
+/// # Arguments
+/// * `format`
+/// * `a`
+/// * `b`
+/// * `rounding_mode`
+pub fn div(format: &FloatFormat, a: u64, b: u64, rounding_mode: RoundingMode) -> (u64, u8) {
+	let (x, y) = (format.unpack(a), format.unpack(b));
+	let sign = ((a ^ b) & format.get_sign_bit()) != 0;
+	match (x, y) {
+		_ if is_nan(&x) || is_nan(&y) => propagate_nan(format, &[x, y]),
+		(Value::Infinity(_), Value::Infinity(_)) | (Value::Zero(_), Value::Zero(_)) => (format.get_canonical_nan(), FLAG_INVALID),
+		(Value::Infinity(_), _) => (format.get_infinity(sign), 0),
+		(_, Value::Zero(_)) => (format.get_infinity(sign), FLAG_DIVIDE_BY_ZERO),
+		(Value::Zero(_), _) | (_, Value::Infinity(_)) => (format.get_zero(sign), 0),
+		(Value::Finite(_, exponent_x, significand_x), Value::Finite(_, exponent_y, significand_y)) => {
+			// The dividend with the leading bit at 126 and the divisor at
+			// 60 give a quotient of more than 65 bits
+			let shift_x = significand_x.leading_zeros() as i32 - 1;
+			let (exponent_x, significand_x) = (exponent_x - shift_x, significand_x << shift_x);
+			let shift_y = significand_y.leading_zeros() as i32 - 67;
+			let (exponent_y, significand_y) = (exponent_y - shift_y, significand_y << shift_y);
+			let quotient = significand_x / significand_y;
+			let sticky = (quotient * significand_y) != significand_x;
+			format.round_pack(sign, exponent_x - exponent_y, quotient | sticky as u128, rounding_mode)
+		},
+		_ => unreachable!()
+	}
+}
+
+// Integer square root, the largest r with r * r <= n
+fn isqrt(n: u128) -> u128 {
+	if n == 0 {
+		return 0;
+	}
+	let mut x = 1 << (129 - n.leading_zeros()).div_ceil(2);
+	loop {
+		let y = (x + n / x) >> 1;
+		if y >= x {
+			return x;
+		}
+		x = y;
+	}
+}
+
+/// Returns the square root of `a` and the exception flags.
+///
+/// # Arguments
+/// * `format`
+/// * `a`
+/// * `rounding_mode`
+pub fn sqrt(format: &FloatFormat, a: u64, rounding_mode: RoundingMode) -> (u64, u8) {
+	match format.unpack(a) {
+		Value::NaN(signaling) => propagate_nan(format, &[Value::NaN(signaling)]),
+		Value::Zero(_) => (a, 0),
+		Value::Infinity(false) => (a, 0),
+		Value::Infinity(true) | Value::Finite(true, _, _) => (format.get_canonical_nan(), FLAG_INVALID),
+		Value::Finite(false, exponent, significand) => {
+			// Scales the significand to about 2^120 with an even exponent
+			// for a root of about 60 bits
+			let mut shift = 120 - (127 - significand.leading_zeros() as i32);
+			if (exponent - shift) % 2 != 0 {
+				shift += 1;
+			}
+			let scaled = significand << shift;
+			let root = isqrt(scaled);
+			let sticky = (root * root) != scaled;
+			format.round_pack(false, (exponent - shift) / 2 - 1, (root << 1) | sticky as u128, rounding_mode)
+		}
+	}
+}
+
+/// Returns `a * b + c` rounded once and the exception flags. Negate
+/// the operands by flipping their sign bits for the other fused
+/// multiply-add instructions.
+///
+/// # Arguments
+/// * `format`
+/// * `a`
+/// * `b`
+/// * `c`
+/// * `rounding_mode`
+pub fn fused_multiply_add(format: &FloatFormat, a: u64, b: u64, c: u64, rounding_mode: RoundingMode) -> (u64, u8) {
+	let (x, y, z) = (format.unpack(a), format.unpack(b), format.unpack(c));
+	let product_sign = ((a ^ b) & format.get_sign_bit()) != 0;
+	// Infinity times zero is invalid even with a quiet NaN addend
+	let invalid_product = matches!((x, y), (Value::Infinity(_), Value::Zero(_)) | (Value::Zero(_), Value::Infinity(_)));
+	if is_nan(&x) || is_nan(&y) || is_nan(&z) {
+		let (nan, flags) = propagate_nan(format, &[x, y, z]);
+		return match invalid_product {
+			true => (nan, FLAG_INVALID),
+			false => (nan, flags)
+		};
+	}
+	if invalid_product {
+		return (format.get_canonical_nan(), FLAG_INVALID);
+	}
+	let product_infinity = matches!(x, Value::Infinity(_)) || matches!(y, Value::Infinity(_));
+	let product_zero = matches!(x, Value::Zero(_)) || matches!(y, Value::Zero(_));
+	match (x, y, z) {
+		(_, _, Value::Infinity(sign)) if product_infinity && sign != product_sign => (format.get_canonical_nan(), FLAG_INVALID),
+		_ if product_infinity => (format.get_infinity(product_sign), 0),
+		(_, _, Value::Infinity(sign)) => (format.get_infinity(sign), 0),
+		(_, _, Value::Zero(sign)) if product_zero => match sign == product_sign {
+			true => (format.get_zero(sign), 0),
+			false => (format.get_zero(rounding_mode == RoundingMode::Down), 0)
+		},
+		_ if product_zero => (c, 0),
+		(Value::Finite(_, exponent_x, significand_x), Value::Finite(_, exponent_y, significand_y), Value::Zero(_)) =>
+			format.round_pack(product_sign, exponent_x + exponent_y, significand_x * significand_y, rounding_mode),
+		(Value::Finite(_, exponent_x, significand_x), Value::Finite(_, exponent_y, significand_y), Value::Finite(sign_z, exponent_z, significand_z)) =>
+			add_finite(format, (product_sign, exponent_x + exponent_y, significand_x * significand_y),
+				(sign_z, exponent_z, significand_z), rounding_mode),
+		_ => unreachable!()
+	}
+}
+
+/// Converts `a` from a format to another and returns the exception flags.
+///
+/// # Arguments
+/// * `from`
+/// * `to`
+/// * `a`
+/// * `rounding_mode`
+pub fn convert(from: &FloatFormat, to: &FloatFormat, a: u64, rounding_mode: RoundingMode) -> (u64, u8) {
+	match from.unpack(a) {
+		Value::NaN(signaling) => propagate_nan(to, &[Value::NaN(signaling)]),
+		Value::Infinity(sign) => (to.get_infinity(sign), 0),
+		Value::Zero(sign) => (to.get_zero(sign), 0),
+		Value::Finite(sign, exponent, significand) => to.round_pack(sign, exponent, significand, rounding_mode)
+	}
+}
+
+/// Converts an integer to the format and returns the exception flags.
+///
+/// # Arguments
+/// * `format`
+/// * `magnitude` Absolute value of the integer
+/// * `negative`
+/// * `rounding_mode`
+pub fn from_integer(format: &FloatFormat, magnitude: u64, negative: bool, rounding_mode: RoundingMode) -> (u64, u8) {
+	match magnitude {
+		0 => (format.get_zero(false), 0),
+		_ => format.round_pack(negative, 0, magnitude as u128, rounding_mode)
+	}
+}
+
+/// Converts `a` to a 32-bit or 64-bit integer, signed or not, and returns
+/// the exception flags. NaNs and the values out of range are invalid and
+/// saturate. A 32-bit result is sign-extended as RISC-V writes it to
+/// a register.
+///
+/// # Arguments
+/// * `format`
+/// * `a`
+/// * `bits` 32 or 64
+/// * `signed`
+/// * `rounding_mode`
+pub fn to_integer(format: &FloatFormat, a: u64, bits: u32, signed: bool, rounding_mode: RoundingMode) -> (i64, u8) {
+	let (min, max): (i128, i128) = match signed {
+		true => (-(1 << (bits - 1)), (1 << (bits - 1)) - 1),
+		false => (0, (1 << bits) - 1)
+	};
+	let (value, flags) = match format.unpack(a) {
+		Value::NaN(_) => (max, FLAG_INVALID),
+		Value::Infinity(sign) => (if sign { min } else { max }, FLAG_INVALID),
+		Value::Zero(_) => (0, 0),
+		Value::Finite(sign, exponent, significand) => {
+			let (magnitude, inexact) = match exponent > 64 {
+				true => (1 << 65, false),
+				false => round_significand(significand, -exponent, sign, rounding_mode)
+			};
+			let value = match sign {
+				true => -(magnitude as i128),
+				false => magnitude as i128
+			};
+			match (value < min, value > max, inexact) {
+				(true, _, _) => (min, FLAG_INVALID),
+				(_, true, _) => (max, FLAG_INVALID),
+				(_, _, true) => (value, FLAG_INEXACT),
+				_ => (value, 0)
+			}
+		}
+	};
+	match bits {
+		32 => (value as u32 as i32 as i64, flags),
+		_ => (value as u64 as i64, flags)
+	}
+}
+
+/// Compares `a` with `b` and returns the exception flags. `None` if
+/// either is NaN. Signaling comparisons, `FLT` and `FLE`, are invalid
+/// with any NaN, and quiet ones, `FEQ`, only with signaling NaNs.
+///
+/// # Arguments
+/// * `format`
+/// * `a`
+/// * `b`
+/// * `signaling`
+pub fn compare(format: &FloatFormat, a: u64, b: u64, signaling: bool) -> (Option<Ordering>, u8) {
+	let (x, y) = (format.unpack(a), format.unpack(b));
+	if is_nan(&x) || is_nan(&y) {
+		return match signaling {
+			true => (None, FLAG_INVALID),
+			false => (None, propagate_nan(format, &[x, y]).1)
+		};
+	}
+	// Orders the magnitudes with the signs, where both zeros are equal
+	let get_key = |bits: u64| {
+		let magnitude = (bits & !format.get_sign_bit()) as i64;
+		match (bits & format.get_sign_bit()) != 0 {
+			true => -magnitude,
+			false => magnitude
+		}
+	};
+	(Some(get_key(a).cmp(&get_key(b))), 0)
+}
+
+/// The same operations in host floating point arithmetic, faster but
+/// ignoring the rounding mode, raising only the divide by zero flag, and
+/// keeping NaN payloads as the host does.
+#[cfg(feature = "native-float")]
+pub mod native {
+	use core::cmp::Ordering;
+
+	use super::{FloatFormat, RoundingMode, DOUBLE, FLAG_DIVIDE_BY_ZERO};
+
+	fn binary(format: &FloatFormat, a: u64, b: u64, double: fn(f64, f64) -> f64, single: fn(f32, f32) -> f32) -> u64 {
+		match *format == DOUBLE {
+			true => double(f64::from_bits(a), f64::from_bits(b)).to_bits(),
+			false => single(f32::from_bits(a as u32), f32::from_bits(b as u32)).to_bits() as u64
+		}
+	}
+
+	fn to_f64(format: &FloatFormat, a: u64) -> f64 {
+		match *format == DOUBLE {
+			true => f64::from_bits(a),
+			false => f32::from_bits(a as u32) as f64
+		}
+	}
+
+	fn from_f64(format: &FloatFormat, value: f64) -> u64 {
+		match *format == DOUBLE {
+			true => value.to_bits(),
+			false => (value as f32).to_bits() as u64
+		}
+	}
+
+	pub fn add(format: &FloatFormat, a: u64, b: u64, _rounding_mode: RoundingMode) -> (u64, u8) {
+		(binary(format, a, b, |a, b| a + b, |a, b| a + b), 0)
+	}
+
+	pub fn sub(format: &FloatFormat, a: u64, b: u64, _rounding_mode: RoundingMode) -> (u64, u8) {
+		(binary(format, a, b, |a, b| a - b, |a, b| a - b), 0)
+	}
+
+	pub fn mul(format: &FloatFormat, a: u64, b: u64, _rounding_mode: RoundingMode) -> (u64, u8) {
+		(binary(format, a, b, |a, b| a * b, |a, b| a * b), 0)
+	}
+
+	pub fn div(format: &FloatFormat, a: u64, b: u64, _rounding_mode: RoundingMode) -> (u64, u8) {
+		let flags = match to_f64(format, b) == 0.0 {
+			true => FLAG_DIVIDE_BY_ZERO,
+			false => 0
+		};
+		(binary(format, a, b, |a, b| a / b, |a, b| a / b), flags)
+	}
+
+	pub fn sqrt(format: &FloatFormat, a: u64, _rounding_mode: RoundingMode) -> (u64, u8) {
+		(from_f64(format, libm::sqrt(to_f64(format, a))), 0)
+	}
+
+	pub fn fused_multiply_add(format: &FloatFormat, a: u64, b: u64, c: u64, _rounding_mode: RoundingMode) -> (u64, u8) {
+		let result = match *format == DOUBLE {
+			true => libm::fma(f64::from_bits(a), f64::from_bits(b), f64::from_bits(c)).to_bits(),
+			false => libm::fmaf(f32::from_bits(a as u32), f32::from_bits(b as u32), f32::from_bits(c as u32)).to_bits() as u64
+		};
+		(result, 0)
+	}
+
+	pub fn convert(from: &FloatFormat, to: &FloatFormat, a: u64, _rounding_mode: RoundingMode) -> (u64, u8) {
+		(from_f64(to, to_f64(from, a)), 0)
+	}
+
+	pub fn from_integer(format: &FloatFormat, magnitude: u64, negative: bool, _rounding_mode: RoundingMode) -> (u64, u8) {
+		let value = match negative {
+			true => -(magnitude as f64),
+			false => magnitude as f64
+		};
+		(from_f64(format, value), 0)
+	}
+
+	pub fn to_integer(format: &FloatFormat, a: u64, bits: u32, signed: bool, _rounding_mode: RoundingMode) -> (i64, u8) {
+		let value = to_f64(format, a);
+		let result = match (bits, signed) {
+			(32, true) => value as i32 as i64,
+			(32, false) => value as u32 as i32 as i64,
+			(_, true) => value as i64,
+			(_, false) => value as u64 as i64
+		};
+		(result, 0)
+	}
+
+	pub fn compare(format: &FloatFormat, a: u64, b: u64, _signaling: bool) -> (Option<Ordering>, u8) {
+		(to_f64(format, a).partial_cmp(&to_f64(format, b)), 0)
+	}
+}
+
+#[cfg(test)]
+mod test_softfloat {
+	use super::*;
+
+	const RNE: RoundingMode = RoundingMode::NearestEven;
+
+	fn d(value: f64) -> u64 {
+		value.to_bits()
+	}
+
+	fn s(value: f32) -> u64 {
+		value.to_bits() as u64
+	}
+
+	#[test]
+	fn arithmetic() {
+		// Agrees with the host in the default rounding mode
+		let values = [0.0, -0.0, 1.0, -1.5, 3.0, 0.1, 1e300, -1e-300, 5e-324, 2.2250738585072014e-308, f64::MAX, f64::INFINITY];
+		// NaN results are canonical instead of the host ones
+		let check = |expected: f64, (result, _): (u64, u8), operation: &str| {
+			if !expected.is_nan() {
+				assert_eq!(d(expected), result, "{}", operation);
+			}
+		};
+		for a in values.iter() {
+			for b in values.iter() {
+				check(a + b, add(&DOUBLE, d(*a), d(*b), RNE), &format!("{} + {}", a, b));
+				check(a - b, sub(&DOUBLE, d(*a), d(*b), RNE), &format!("{} - {}", a, b));
+				check(a * b, mul(&DOUBLE, d(*a), d(*b), RNE), &format!("{} * {}", a, b));
+				check(a / b, div(&DOUBLE, d(*a), d(*b), RNE), &format!("{} / {}", a, b));
+				check(a.mul_add(*b, 0.5), fused_multiply_add(&DOUBLE, d(*a), d(*b), d(0.5), RNE), &format!("{} * {} + 0.5", a, b));
+			}
+			if *a >= 0.0 {
+				assert_eq!(d(a.sqrt()), sqrt(&DOUBLE, d(*a), RNE).0, "sqrt {}", a);
+			}
+			assert_eq!(s(*a as f32), convert(&DOUBLE, &SINGLE, d(*a), RNE).0, "{} as f32", a);
+		}
+		assert_eq!(s(1.0 / 3.0), div(&SINGLE, s(1.0), s(3.0), RNE).0);
+		assert_eq!(d(2.0f32.sqrt() as f64), convert(&SINGLE, &DOUBLE, sqrt(&SINGLE, s(2.0), RNE).0, RNE).0);
+	}
+
+	#[test]
+	fn flags() {
+		assert_eq!((d(0.1 + 0.2), FLAG_INEXACT), add(&DOUBLE, d(0.1), d(0.2), RNE));
+		assert_eq!((d(3.0), 0), add(&DOUBLE, d(1.0), d(2.0), RNE));
+		assert_eq!((d(f64::INFINITY), FLAG_OVERFLOW | FLAG_INEXACT), mul(&DOUBLE, d(f64::MAX), d(2.0), RNE));
+		assert_eq!((d(f64::MAX), FLAG_OVERFLOW | FLAG_INEXACT), mul(&DOUBLE, d(f64::MAX), d(2.0), RoundingMode::TowardZero));
+		assert_eq!((d(-f64::INFINITY), FLAG_DIVIDE_BY_ZERO), div(&DOUBLE, d(-1.0), d(0.0), RNE));
+		assert_eq!((DOUBLE.get_canonical_nan(), FLAG_INVALID), div(&DOUBLE, d(0.0), d(0.0), RNE));
+		assert_eq!((DOUBLE.get_canonical_nan(), FLAG_INVALID), sqrt(&DOUBLE, d(-1.0), RNE));
+		assert_eq!((DOUBLE.get_canonical_nan(), FLAG_INVALID), add(&DOUBLE, d(f64::INFINITY), d(-f64::INFINITY), RNE));
+		// Tiny and inexact
+		assert_eq!((1, FLAG_UNDERFLOW | FLAG_INEXACT), mul(&DOUBLE, d(5e-324), d(0.75), RNE));
+		// Tininess after rounding: rounds up to the smallest normal number
+		let below_normal = d(f64::MIN_POSITIVE) - 1;
+		assert_eq!((d(f64::MIN_POSITIVE), FLAG_INEXACT), mul(&DOUBLE, below_normal, d(1.0 + f64::EPSILON), RNE));
+	}
+
+	#[test]
+	fn nan() {
+		let signaling = 0x7ff0000000000001;
+		let quiet = 0x7ff8000000000123;
+		assert_eq!((DOUBLE.get_canonical_nan(), FLAG_INVALID), add(&DOUBLE, signaling, d(1.0), RNE));
+		assert_eq!((DOUBLE.get_canonical_nan(), 0), mul(&DOUBLE, quiet, d(1.0), RNE));
+		assert_eq!((SINGLE.get_canonical_nan(), 0), convert(&DOUBLE, &SINGLE, quiet, RNE));
+		assert_eq!((0x7fc00000, 0), convert(&DOUBLE, &SINGLE, quiet, RNE));
+		assert_eq!((DOUBLE.get_canonical_nan(), FLAG_INVALID), fused_multiply_add(&DOUBLE, d(f64::INFINITY), d(0.0), quiet, RNE));
+		assert_eq!((None, 0), compare(&DOUBLE, quiet, d(1.0), false));
+		assert_eq!((None, FLAG_INVALID), compare(&DOUBLE, quiet, d(1.0), true));
+		assert_eq!((None, FLAG_INVALID), compare(&DOUBLE, signaling, d(1.0), false));
+		assert_eq!((Some(Ordering::Equal), 0), compare(&DOUBLE, d(0.0), d(-0.0), true));
+		assert_eq!((Some(Ordering::Less), 0), compare(&DOUBLE, d(-2.0), d(-1.0), true));
+	}
+
+	#[test]
+	fn rounding_modes() {
+		let modes = [RoundingMode::NearestEven, RoundingMode::TowardZero, RoundingMode::Down, RoundingMode::Up, RoundingMode::NearestMaxMagnitude];
+		let expected = [(2, -2), (2, -2), (2, -3), (3, -2), (3, -3)];
+		for (mode, (positive, negative)) in modes.iter().zip(expected.iter()) {
+			assert_eq!((*positive, FLAG_INEXACT), to_integer(&DOUBLE, d(2.5), 32, true, *mode));
+			assert_eq!((*negative, FLAG_INEXACT), to_integer(&DOUBLE, d(-2.5), 64, true, *mode));
+		}
+		assert_eq!((d(-0.0), 0), sub(&DOUBLE, d(1.0), d(1.0), RoundingMode::Down));
+		assert_eq!((d(0.0), 0), sub(&DOUBLE, d(1.0), d(1.0), RNE));
+		let (down, _) = div(&DOUBLE, d(1.0), d(3.0), RoundingMode::Down);
+		let (up, _) = div(&DOUBLE, d(1.0), d(3.0), RoundingMode::Up);
+		assert_eq!(down + 1, up);
+		// 2^53 + 1 isn't representable
+		assert_eq!((d(9007199254740992.0), FLAG_INEXACT), from_integer(&DOUBLE, (1 << 53) + 1, false, RNE));
+		assert_eq!((d(9007199254740994.0), FLAG_INEXACT), from_integer(&DOUBLE, (1 << 53) + 1, false, RoundingMode::Up));
+	}
+
+	#[test]
+	fn integer_conversion() {
+		assert_eq!((i32::MAX as i64, FLAG_INVALID), to_integer(&DOUBLE, d(3e9), 32, true, RNE));
+		assert_eq!((i32::MIN as i64, FLAG_INVALID), to_integer(&DOUBLE, d(-f64::INFINITY), 32, true, RNE));
+		assert_eq!((i32::MAX as i64, FLAG_INVALID), to_integer(&DOUBLE, DOUBLE.get_canonical_nan(), 32, true, RNE));
+		// 32-bit unsigned results are sign-extended
+		assert_eq!((-1, FLAG_INVALID), to_integer(&DOUBLE, d(1e10), 32, false, RNE));
+		assert_eq!((0, FLAG_INVALID), to_integer(&DOUBLE, d(-1.0), 64, false, RNE));
+		assert_eq!((0, FLAG_INEXACT), to_integer(&DOUBLE, d(-0.25), 64, false, RNE));
+		assert_eq!((i64::MIN, 0), to_integer(&DOUBLE, d(-9223372036854775808.0), 64, true, RNE));
+		assert_eq!((i64::MAX, FLAG_INVALID), to_integer(&DOUBLE, d(9223372036854775808.0), 64, true, RNE));
+		assert_eq!((d(-1.0), 0), from_integer(&DOUBLE, 1, true, RNE));
+	}
+}
@@ -0,0 +1,151 @@
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use core::time::Duration;
+
+use clock::{Clock, create_default_clock};
+use cpu::Cpu;
+
+// Instructions between wall-clock time checks, not to slow down emulation
+const CHECK_INTERVAL: u64 = 0x10000;
+
+// Period the current speed is measured over by default
+const DEFAULT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Emulation speed, returned by `SpeedMeter::get_speed()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmulationSpeed {
+	/// Instructions run since the meter is created or reset
+	pub instructions: u64,
+	/// Wall-clock time since the meter is created or reset
+	pub elapsed: Duration,
+	/// Million instructions per second over the last measurement period.
+	/// Zero until the first period ends.
+	pub current_mips: f64,
+	/// Million instructions per second over the whole time
+	pub average_mips: f64
+}
+
+/// Called with the speed every period
+pub type SpeedCallback = Box<dyn FnMut(&EmulationSpeed)>;
+
+/// Measures emulation speed in MIPS, million instructions per second of
+/// wall-clock time, for benchmarking the interpreter. Cycles waiting for
+/// interrupts aren't counted as instructions. The wall-clock time is
+/// checked every 65536 instructions, so periods are that coarse. Set to
+/// `Emulator` with `set_speed_meter()`.
+pub struct SpeedMeter {
+	clock: Rc<dyn Clock>,
+	start: Duration,
+	instructions: u64,
+	window: Duration,
+	window_start: Duration,
+	window_instructions: u64,
+	current_mips: f64,
+	callback: Option<SpeedCallback>
+}
+
+impl SpeedMeter {
+	/// Creates a new `SpeedMeter` on `SystemClock`, or `StoppedClock`
+	/// without std. Measurement starts immediately.
+	pub fn new() -> Self {
+		Self::new_with_clock(create_default_clock())
+	}
+
+	/// Creates a new `SpeedMeter` reading the wall-clock time from
+	/// the clock. Measurement starts immediately.
+	///
+	/// # Arguments
+	/// * `clock`
+	pub fn new_with_clock(clock: Rc<dyn Clock>) -> Self {
+		let now = clock.now();
+		SpeedMeter {
+			clock,
+			start: now,
+			instructions: 0,
+			window: DEFAULT_WINDOW,
+			window_start: now,
+			window_instructions: 0,
+			current_mips: 0.0,
+			callback: None
+		}
+	}
+
+	/// Sets the callback called with the speed every period, e.g. to show
+	/// the speed in a status bar. The period is also what the current
+	/// speed is measured over.
+	///
+	/// # Arguments
+	/// * `period` One second by default
+	/// * `callback`
+	pub fn set_callback(&mut self, period: Duration, callback: SpeedCallback) {
+		self.window = period;
+		self.callback = Some(callback);
+	}
+
+	/// Counts the instruction the CPU is about to run. Call this before
+	/// every `Cpu::tick()`.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn count(&mut self, cpu: &Cpu) {
+		if cpu.is_waiting_for_interrupt() {
+			return;
+		}
+		self.instructions += 1;
+		self.window_instructions += 1;
+		if self.window_instructions.is_multiple_of(CHECK_INTERVAL) {
+			self.check_window();
+		}
+	}
+
+	fn check_window(&mut self) {
+		let now = self.clock.now();
+		let elapsed = now.saturating_sub(self.window_start);
+		if elapsed < self.window {
+			return;
+		}
+		self.current_mips = get_mips(self.window_instructions, elapsed);
+		self.window_start = now;
+		self.window_instructions = 0;
+		if self.callback.is_some() {
+			let speed = self.get_speed();
+			if let Some(callback) = self.callback.as_mut() {
+				callback(&speed);
+			}
+		}
+	}
+
+	/// Returns the speed so far.
+	pub fn get_speed(&self) -> EmulationSpeed {
+		let elapsed = self.clock.now().saturating_sub(self.start);
+		EmulationSpeed {
+			instructions: self.instructions,
+			elapsed,
+			current_mips: self.current_mips,
+			average_mips: get_mips(self.instructions, elapsed)
+		}
+	}
+
+	/// Starts measurement over, e.g. after the guest has booted.
+	pub fn reset(&mut self) {
+		let now = self.clock.now();
+		self.start = now;
+		self.instructions = 0;
+		self.window_start = now;
+		self.window_instructions = 0;
+		self.current_mips = 0.0;
+	}
+}
+
+impl Default for SpeedMeter {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+fn get_mips(instructions: u64, elapsed: Duration) -> f64 {
+	match elapsed.as_secs_f64() > 0.0 {
+		true => instructions as f64 / elapsed.as_secs_f64() / 1_000_000.0,
+		false => 0.0
+	}
+}
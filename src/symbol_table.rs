@@ -0,0 +1,105 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use collections::FnvHashMap;
+use error::LoadError;
+
+/// Symbols sorted by address to find the function an address belongs to,
+/// used by the traces. Symbol sizes aren't known so an address belongs to
+/// the nearest symbol at or before it.
+pub struct SymbolTable {
+	// Sorted by address
+	symbols: Vec<(u64, String)>
+}
+
+impl SymbolTable {
+	/// Creates a new `SymbolTable`.
+	///
+	/// # Arguments
+	/// * `symbol_map` Mapping from symbol to virtual address
+	pub fn new(symbol_map: &FnvHashMap<String, u64>) -> Self {
+		let mut symbols: Vec<(u64, String)> = symbol_map.iter()
+			.map(|(name, address)| (*address, name.clone()))
+			.collect();
+		symbols.sort();
+		SymbolTable {
+			symbols
+		}
+	}
+
+	/// Returns the symbol the address belongs to and the offset from it,
+	/// or `None` if no symbol is at or before the address.
+	///
+	/// # Arguments
+	/// * `address` Virtual address
+	pub fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+		match self.symbols.partition_point(|(symbol_address, _)| *symbol_address <= address) {
+			0 => None,
+			index => {
+				let (symbol_address, name) = &self.symbols[index - 1];
+				Some((name, address - symbol_address))
+			}
+		}
+	}
+}
+
+/// Parses `System.map` of Linux or a dump of `/proc/kallsyms`, lines like
+/// `ffffffff80002000 T start_kernel` optionally followed by the module
+/// name, and returns the text symbols as (name, address). Data symbols
+/// are skipped not to be mistaken for the functions a PC belongs to.
+/// Returns `Err` if a line is malformed or all the addresses are zero,
+/// which `/proc/kallsyms` shows without permission.
+///
+/// # Arguments
+/// * `content`
+pub fn parse_system_map(content: &str) -> Result<Vec<(String, u64)>, LoadError> {
+	let mut symbols = vec![];
+	let mut has_address = false;
+	for line in content.lines() {
+		let mut fields = line.split_whitespace();
+		let (address, symbol_type, name) = match (fields.next(), fields.next(), fields.next()) {
+			(None, _, _) => continue,
+			(Some(address), Some(symbol_type), Some(name)) => (address, symbol_type, name),
+			_ => return Err(LoadError::SystemMap)
+		};
+		let address = match u64::from_str_radix(address, 16) {
+			Ok(address) => address,
+			Err(_) => return Err(LoadError::SystemMap)
+		};
+		has_address |= address != 0;
+		if let "T" | "t" | "W" | "w" = symbol_type {
+			symbols.push((name.to_string(), address));
+		}
+	}
+	match has_address {
+		true => Ok(symbols),
+		false => Err(LoadError::SystemMap)
+	}
+}
+
+#[cfg(test)]
+mod test_symbol_table {
+	use super::*;
+
+	#[test]
+	fn system_map() {
+		let content = "ffffffff80000000 T _start\n\
+			ffffffff80002000 T start_kernel\n\
+			ffffffff80c00000 D init_task\n\
+			ffffffff80003000 t helper [ext4]\n\
+			\n";
+		let symbols = match parse_system_map(content) {
+			Ok(symbols) => symbols,
+			Err(error) => panic!("{}", error)
+		};
+		assert_eq!(3, symbols.len());
+		let symbol_table = SymbolTable::new(&symbols.into_iter().collect());
+		assert_eq!(Some(("start_kernel", 0x10)), symbol_table.lookup(0xffffffff80002010));
+		assert_eq!(Some(("helper", 0xbfd000)), symbol_table.lookup(0xffffffff80c00000));
+		assert_eq!(None, symbol_table.lookup(0x80000000));
+
+		assert_eq!(Err(LoadError::SystemMap), parse_system_map("0000000000000000 T _start\n"));
+		assert_eq!(Err(LoadError::SystemMap), parse_system_map("ffffffff80000000 _start\n"));
+		assert_eq!(Err(LoadError::SystemMap), parse_system_map("_start T ffffffff80000000\n"));
+	}
+}
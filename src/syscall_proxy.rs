@@ -0,0 +1,586 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::env;
+#[cfg(feature = "std")]
+use std::fs::{self, File, Metadata, OpenOptions};
+#[cfg(feature = "std")]
+use std::io::{Error, Read, Seek, SeekFrom, Write};
+#[cfg(feature = "std")]
+use std::time::UNIX_EPOCH;
+
+#[cfg(feature = "std")]
+use collections::FnvHashMap;
+use cpu::{Cpu, Xlen};
+use mmu::Mmu;
+
+// System call numbers of RISC-V Linux ABI handled by `SyscallProxy`
+const SYS_GETCWD: u64 = 17;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_PREAD: u64 = 67;
+const SYS_PWRITE: u64 = 68;
+const SYS_FSTAT: u64 = 80;
+
+// Error numbers of RISC-V Linux ABI
+pub const ENOENT: i64 = 2;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const ENOMEM: i64 = 12;
+pub const EFAULT: i64 = 14;
+pub const EINVAL: i64 = 22;
+pub const ENOTTY: i64 = 25;
+pub const ENOSYS: i64 = 38;
+
+pub const AT_FDCWD: i64 = -100;
+
+// open() flags of RISC-V Linux ABI
+#[cfg(feature = "std")]
+const O_ACCMODE: u64 = 0x3;
+#[cfg(feature = "std")]
+const O_RDONLY: u64 = 0x0;
+#[cfg(feature = "std")]
+const O_WRONLY: u64 = 0x1;
+#[cfg(feature = "std")]
+const O_CREAT: u64 = 0x40;
+#[cfg(feature = "std")]
+const O_EXCL: u64 = 0x80;
+#[cfg(feature = "std")]
+const O_TRUNC: u64 = 0x200;
+#[cfg(feature = "std")]
+const O_APPEND: u64 = 0x400;
+
+const S_IFCHR: u32 = 0o020000;
+#[cfg(feature = "std")]
+const S_IFDIR: u32 = 0o040000;
+#[cfg(feature = "std")]
+const S_IFREG: u32 = 0o100000;
+
+/// The size of `struct stat` of RISC-V Linux ABI
+const STAT_SIZE: usize = 128;
+
+/// The first file descriptor assigned to opened host files.
+/// 0-2 are standard input/output/error connected to `Terminal`.
+const FIRST_FILE_FD: u64 = 3;
+
+/// Proxies file system calls of RISC-V Linux ABI made by a program to the
+/// host, like the front-end server of Spike does. Standard input/output are
+/// connected to `Terminal` and other file descriptors to host files.
+/// Without std there are no host files, so only standard input/output are
+/// available and opening a file fails with `ENOENT`. Shared
+/// by [`Htif`](../htif/struct.Htif.html) and
+/// [`LinuxUser`](../linux_user/struct.LinuxUser.html).
+///
+/// System call arguments are target addresses and errors are returned
+/// as negative error numbers.
+pub struct SyscallProxy {
+	/// Added to a target address to get the physical address
+	address_offset: u64,
+	/// Target addresses must be lower than this
+	address_limit: u64,
+	/// Host files opened by the program
+	#[cfg(feature = "std")]
+	files: FnvHashMap<u64, File>
+}
+
+impl SyscallProxy {
+	/// Creates a new `SyscallProxy`.
+	///
+	/// # Arguments
+	/// * `address_offset` Added to a target address to get the physical address
+	/// * `address_limit` Accesses to target addresses equal to or higher than
+	///   this fail with `EFAULT`
+	pub fn new(address_offset: u64, address_limit: u64) -> Self {
+		SyscallProxy {
+			address_offset,
+			address_limit,
+			#[cfg(feature = "std")]
+			files: FnvHashMap::default()
+		}
+	}
+
+	fn get_physical_address(&self, address: u64, length: u64) -> Result<u64, i64> {
+		match address.checked_add(length) {
+			Some(end) if end <= self.address_limit => Ok(address + self.address_offset),
+			_ => Err(-EFAULT)
+		}
+	}
+
+	/// Reads target memory.
+	///
+	/// # Arguments
+	/// * `mmu`
+	/// * `address` Target address
+	/// * `length`
+	pub fn read_memory(&self, mmu: &mut Mmu, address: u64, length: u64) -> Result<Vec<u8>, i64> {
+		let p_address = self.get_physical_address(address, length)?;
+		let mut data = Vec::with_capacity(length as usize);
+		for i in 0..length {
+			data.push(mmu.load_raw(p_address + i));
+		}
+		Ok(data)
+	}
+
+	/// Writes target memory.
+	///
+	/// # Arguments
+	/// * `mmu`
+	/// * `address` Target address
+	/// * `data`
+	pub fn write_memory(&self, mmu: &mut Mmu, address: u64, data: &[u8]) -> Result<(), i64> {
+		let p_address = self.get_physical_address(address, data.len() as u64)?;
+		for (i, byte) in data.iter().enumerate() {
+			mmu.store_raw(p_address + i as u64, *byte);
+		}
+		Ok(())
+	}
+
+	/// Reads a null terminated string from target memory.
+	///
+	/// # Arguments
+	/// * `mmu`
+	/// * `address` Target address
+	/// * `max_length` The string is cut at this length if no null is found
+	pub fn read_string(&self, mmu: &mut Mmu, address: u64, max_length: u64) -> Result<String, i64> {
+		let mut data = vec![];
+		for i in 0..max_length {
+			let p_address = self.get_physical_address(address + i, 1)?;
+			match mmu.load_raw(p_address) {
+				0 => break,
+				byte => data.push(byte)
+			};
+		}
+		match String::from_utf8(data) {
+			Ok(string) => Ok(string),
+			Err(_) => Err(-ENOENT)
+		}
+	}
+
+	/// Handles a system call using file descriptors. Returns `None` if
+	/// the system call waits for input, or `-ENOSYS` if it isn't handled by
+	/// `SyscallProxy`. System calls taking a path have different arguments
+	/// between ABIs, then use `open()`, `stat()`, and `access()` for them.
+	///
+	/// # Arguments
+	/// * `cpu`
+	/// * `number` System call number
+	/// * `args` System call arguments
+	pub fn handle_syscall(&mut self, cpu: &mut Cpu, number: u64, args: &[u64]) -> Option<i64> {
+		let result = match number {
+			SYS_CLOSE => self.sys_close(args[0]),
+			SYS_READ => return self.sys_read(cpu, args[0], args[1], args[2], None),
+			SYS_PREAD => return self.sys_read(cpu, args[0], args[1], args[2], Some(args[3])),
+			SYS_READV => return self.sys_readv(cpu, args[0], args[1], args[2]),
+			SYS_WRITE => self.sys_write(cpu, args[0], args[1], args[2], None),
+			SYS_PWRITE => self.sys_write(cpu, args[0], args[1], args[2], Some(args[3])),
+			SYS_WRITEV => self.sys_writev(cpu, args[0], args[1], args[2]),
+			SYS_LSEEK => self.sys_lseek(args[0], args[1], args[2]),
+			SYS_FSTAT => self.sys_fstat(cpu.get_mut_mmu(), args[0], args[1]),
+			SYS_GETCWD => self.sys_getcwd(cpu.get_mut_mmu(), args[0], args[1]),
+			_ => -ENOSYS
+		};
+		Some(result)
+	}
+
+	/// Opens a host file and returns the file descriptor.
+	///
+	/// # Arguments
+	/// * `dirfd` Only `AT_FDCWD` is supported for relative paths
+	/// * `path`
+	/// * `flags` `open()` flags of RISC-V Linux ABI
+	#[cfg(feature = "std")]
+	pub fn open(&mut self, dirfd: u64, path: &str, flags: u64) -> i64 {
+		if let Err(error) = check_dirfd(dirfd, path) {
+			return error;
+		}
+		let mut options = OpenOptions::new();
+		options.read(flags & O_ACCMODE != O_WRONLY)
+			.write(flags & O_ACCMODE != O_RDONLY)
+			.append(flags & O_APPEND != 0)
+			.truncate(flags & O_TRUNC != 0);
+		match flags & O_EXCL != 0 {
+			true => options.create_new(flags & O_CREAT != 0),
+			false => options.create(flags & O_CREAT != 0)
+		};
+		match options.open(path) {
+			Ok(file) => {
+				let mut fd = FIRST_FILE_FD;
+				while self.files.contains_key(&fd) {
+					fd += 1;
+				}
+				self.files.insert(fd, file);
+				fd as i64
+			},
+			Err(error) => get_errno(error)
+		}
+	}
+
+	#[cfg(not(feature = "std"))]
+	pub fn open(&mut self, _dirfd: u64, _path: &str, _flags: u64) -> i64 {
+		-ENOENT
+	}
+
+	/// Writes `struct stat` of a host file to target memory.
+	///
+	/// # Arguments
+	/// * `mmu`
+	/// * `dirfd` Only `AT_FDCWD` is supported for relative paths
+	/// * `path`
+	/// * `pbuf` Target address
+	#[cfg(feature = "std")]
+	pub fn stat(&mut self, mmu: &mut Mmu, dirfd: u64, path: &str, pbuf: u64) -> i64 {
+		if let Err(error) = check_dirfd(dirfd, path) {
+			return error;
+		}
+		match fs::metadata(path) {
+			Ok(metadata) => match self.write_memory(mmu, pbuf, &create_stat(&metadata)) {
+				Ok(()) => 0,
+				Err(error) => error
+			},
+			Err(error) => get_errno(error)
+		}
+	}
+
+	#[cfg(not(feature = "std"))]
+	pub fn stat(&mut self, _mmu: &mut Mmu, _dirfd: u64, _path: &str, _pbuf: u64) -> i64 {
+		-ENOENT
+	}
+
+	/// Checks if a host file exists.
+	///
+	/// # Arguments
+	/// * `dirfd` Only `AT_FDCWD` is supported for relative paths
+	/// * `path`
+	#[cfg(feature = "std")]
+	pub fn access(&mut self, dirfd: u64, path: &str) -> i64 {
+		if let Err(error) = check_dirfd(dirfd, path) {
+			return error;
+		}
+		match fs::metadata(path) {
+			Ok(_) => 0,
+			Err(error) => get_errno(error)
+		}
+	}
+
+	#[cfg(not(feature = "std"))]
+	pub fn access(&mut self, _dirfd: u64, _path: &str) -> i64 {
+		-ENOENT
+	}
+
+	/// Reads an opened host file at the offset without moving the file
+	/// position. Used to map a file to memory.
+	///
+	/// # Arguments
+	/// * `fd`
+	/// * `offset`
+	/// * `length` The data is shorter if the file ends
+	#[cfg(feature = "std")]
+	pub fn read_file(&mut self, fd: u64, offset: u64, length: u64) -> Result<Vec<u8>, i64> {
+		let file = match self.files.get_mut(&fd) {
+			Some(file) => file,
+			None => return Err(-EBADF)
+		};
+		let mut data = vec![0; length as usize];
+		let mut size = 0;
+		while size < data.len() {
+			match read_at(file, &mut data[size..], offset + size as u64) {
+				Ok(0) => break,
+				Ok(read_size) => size += read_size,
+				Err(error) => return Err(get_errno(error))
+			};
+		}
+		data.truncate(size);
+		Ok(data)
+	}
+
+	#[cfg(not(feature = "std"))]
+	pub fn read_file(&mut self, _fd: u64, _offset: u64, _length: u64) -> Result<Vec<u8>, i64> {
+		Err(-EBADF)
+	}
+
+	#[cfg(feature = "std")]
+	fn sys_close(&mut self, fd: u64) -> i64 {
+		match fd < FIRST_FILE_FD || self.files.remove(&fd).is_some() {
+			true => 0,
+			false => -EBADF
+		}
+	}
+
+	#[cfg(not(feature = "std"))]
+	fn sys_close(&mut self, fd: u64) -> i64 {
+		match fd < FIRST_FILE_FD {
+			true => 0,
+			false => -EBADF
+		}
+	}
+
+	/// Reads from standard input or a file. Returns `None` if standard
+	/// input has no data yet.
+	fn sys_read(&mut self, cpu: &mut Cpu, fd: u64, pbuf: u64, len: u64, offset: Option<u64>) -> Option<i64> {
+		if let Err(error) = self.get_physical_address(pbuf, len) {
+			return Some(error);
+		}
+		let data = match fd {
+			0 => {
+				// Reads what is available so far, up to a page at once
+				let mut data = vec![0; len.min(0x1000) as usize];
+				let size = cpu.get_mut_terminal().get_input_bytes(&mut data);
+				data.truncate(size);
+				if data.is_empty() && len > 0 {
+					return None;
+				}
+				data
+			},
+			_ => match self.read_from_file(fd, len, offset) {
+				Ok(data) => data,
+				Err(error) => return Some(error)
+			}
+		};
+		Some(match self.write_memory(cpu.get_mut_mmu(), pbuf, &data) {
+			Ok(()) => data.len() as i64,
+			Err(error) => error
+		})
+	}
+
+	#[cfg(feature = "std")]
+	fn read_from_file(&mut self, fd: u64, len: u64, offset: Option<u64>) -> Result<Vec<u8>, i64> {
+		let file = match self.files.get_mut(&fd) {
+			Some(file) => file,
+			None => return Err(-EBADF)
+		};
+		let mut data = vec![0; len as usize];
+		let result = match offset {
+			Some(offset) => read_at(file, &mut data, offset),
+			None => file.read(&mut data)
+		};
+		match result {
+			Ok(size) => data.truncate(size),
+			Err(error) => return Err(get_errno(error))
+		};
+		Ok(data)
+	}
+
+	#[cfg(not(feature = "std"))]
+	fn read_from_file(&mut self, _fd: u64, _len: u64, _offset: Option<u64>) -> Result<Vec<u8>, i64> {
+		Err(-EBADF)
+	}
+
+	/// Writes to standard output/error or a file.
+	fn sys_write(&mut self, cpu: &mut Cpu, fd: u64, pbuf: u64, len: u64, offset: Option<u64>) -> i64 {
+		let data = match self.read_memory(cpu.get_mut_mmu(), pbuf, len) {
+			Ok(data) => data,
+			Err(error) => return error
+		};
+		if fd == 1 || fd == 2 {
+			cpu.get_mut_terminal().put_bytes(&data);
+			return len as i64;
+		}
+		self.write_to_file(fd, &data, offset)
+	}
+
+	#[cfg(feature = "std")]
+	fn write_to_file(&mut self, fd: u64, data: &[u8], offset: Option<u64>) -> i64 {
+		let file = match self.files.get_mut(&fd) {
+			Some(file) => file,
+			None => return -EBADF
+		};
+		let result = match offset {
+			Some(offset) => write_at(file, data, offset),
+			None => file.write_all(data)
+		};
+		match result {
+			Ok(()) => data.len() as i64,
+			Err(error) => get_errno(error)
+		}
+	}
+
+	#[cfg(not(feature = "std"))]
+	fn write_to_file(&mut self, _fd: u64, _data: &[u8], _offset: Option<u64>) -> i64 {
+		-EBADF
+	}
+
+	/// Reads `struct iovec` array, pairs of buffer address and length.
+	fn read_iovecs(&self, cpu: &mut Cpu, piov: u64, iovcnt: u64) -> Result<Vec<(u64, u64)>, i64> {
+		let word_size = match cpu.get_xlen() {
+			Xlen::Bit32 => 4,
+			Xlen::Bit64 => 8
+		};
+		let data = self.read_memory(cpu.get_mut_mmu(), piov, iovcnt * word_size * 2)?;
+		let words = data.chunks(word_size as usize).map(|bytes| {
+			bytes.iter().rev().fold(0, |word, byte| (word << 8) | *byte as u64)
+		}).collect::<Vec<u64>>();
+		Ok(words.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+	}
+
+	fn sys_readv(&mut self, cpu: &mut Cpu, fd: u64, piov: u64, iovcnt: u64) -> Option<i64> {
+		let iovecs = match self.read_iovecs(cpu, piov, iovcnt) {
+			Ok(iovecs) => iovecs,
+			Err(error) => return Some(error)
+		};
+		let mut total = 0;
+		for (pbuf, len) in iovecs {
+			let size = match self.sys_read(cpu, fd, pbuf, len, None) {
+				Some(size) if size < 0 => return Some(size),
+				Some(size) => size,
+				// Returns what has been read so far, or waits for input
+				None => match total {
+					0 => return None,
+					_ => break
+				}
+			};
+			total += size;
+			if (size as u64) < len {
+				break;
+			}
+		}
+		Some(total)
+	}
+
+	fn sys_writev(&mut self, cpu: &mut Cpu, fd: u64, piov: u64, iovcnt: u64) -> i64 {
+		let iovecs = match self.read_iovecs(cpu, piov, iovcnt) {
+			Ok(iovecs) => iovecs,
+			Err(error) => return error
+		};
+		let mut total = 0;
+		for (pbuf, len) in iovecs {
+			match self.sys_write(cpu, fd, pbuf, len, None) {
+				size if size < 0 => return size,
+				size => total += size
+			};
+		}
+		total
+	}
+
+	#[cfg(feature = "std")]
+	fn sys_lseek(&mut self, fd: u64, offset: u64, whence: u64) -> i64 {
+		let file = match self.files.get_mut(&fd) {
+			Some(file) => file,
+			None => return -EBADF
+		};
+		let position = match whence {
+			0 => SeekFrom::Start(offset),
+			1 => SeekFrom::Current(offset as i64),
+			2 => SeekFrom::End(offset as i64),
+			_ => return -EINVAL
+		};
+		match file.seek(position) {
+			Ok(position) => position as i64,
+			Err(error) => get_errno(error)
+		}
+	}
+
+	#[cfg(not(feature = "std"))]
+	fn sys_lseek(&mut self, _fd: u64, _offset: u64, _whence: u64) -> i64 {
+		-EBADF
+	}
+
+	fn sys_fstat(&mut self, mmu: &mut Mmu, fd: u64, pbuf: u64) -> i64 {
+		let stat = match fd < FIRST_FILE_FD {
+			true => {
+				let mut stat = [0; STAT_SIZE];
+				stat[16..20].copy_from_slice(&(S_IFCHR | 0o620).to_le_bytes());
+				stat
+			},
+			#[cfg(feature = "std")]
+			false => match self.files.get(&fd).map(|file| file.metadata()) {
+				Some(Ok(metadata)) => create_stat(&metadata),
+				Some(Err(error)) => return get_errno(error),
+				None => return -EBADF
+			},
+			#[cfg(not(feature = "std"))]
+			false => return -EBADF
+		};
+		match self.write_memory(mmu, pbuf, &stat) {
+			Ok(()) => 0,
+			Err(error) => error
+		}
+	}
+
+	/// Writes the current directory path. Returns the length including
+	/// the terminating null.
+	#[cfg(feature = "std")]
+	fn sys_getcwd(&mut self, mmu: &mut Mmu, pbuf: u64, size: u64) -> i64 {
+		let path = match env::current_dir() {
+			Ok(path) => path,
+			Err(error) => return get_errno(error)
+		};
+		let mut data = path.to_string_lossy().into_owned().into_bytes();
+		data.push(0);
+		if data.len() as u64 > size {
+			return -ENOMEM;
+		}
+		match self.write_memory(mmu, pbuf, &data) {
+			Ok(()) => data.len() as i64,
+			Err(error) => error
+		}
+	}
+
+	#[cfg(not(feature = "std"))]
+	fn sys_getcwd(&mut self, _mmu: &mut Mmu, _pbuf: u64, _size: u64) -> i64 {
+		-ENOENT
+	}
+}
+
+/// Only the current directory is supported as the base of relative paths.
+#[cfg(feature = "std")]
+fn check_dirfd(dirfd: u64, path: &str) -> Result<(), i64> {
+	match dirfd as i64 == AT_FDCWD || path.starts_with('/') {
+		true => Ok(()),
+		false => Err(-EBADF)
+	}
+}
+
+/// Reads at the offset without moving the file position like `pread()`.
+#[cfg(feature = "std")]
+fn read_at(file: &mut File, data: &mut [u8], offset: u64) -> Result<usize, Error> {
+	let position = file.stream_position()?;
+	file.seek(SeekFrom::Start(offset))?;
+	let result = file.read(data);
+	file.seek(SeekFrom::Start(position))?;
+	result
+}
+
+/// Writes at the offset without moving the file position like `pwrite()`.
+#[cfg(feature = "std")]
+fn write_at(file: &mut File, data: &[u8], offset: u64) -> Result<(), Error> {
+	let position = file.stream_position()?;
+	file.seek(SeekFrom::Start(offset))?;
+	let result = file.write_all(data);
+	file.seek(SeekFrom::Start(position))?;
+	result
+}
+
+#[cfg(feature = "std")]
+fn get_errno(error: Error) -> i64 {
+	-(error.raw_os_error().map(|errno| errno as i64).unwrap_or(EIO))
+}
+
+/// Creates `struct stat` of RISC-V Linux ABI from the host file metadata.
+/// Only the fields available on any host are filled.
+#[cfg(feature = "std")]
+fn create_stat(metadata: &Metadata) -> [u8; STAT_SIZE] {
+	let mut stat = [0; STAT_SIZE];
+	let mode = match (metadata.is_dir(), metadata.permissions().readonly()) {
+		(true, _) => S_IFDIR | 0o755,
+		(false, true) => S_IFREG | 0o444,
+		(false, false) => S_IFREG | 0o644
+	};
+	let size = metadata.len();
+	let mtime = match metadata.modified().map(|time| time.duration_since(UNIX_EPOCH)) {
+		Ok(Ok(duration)) => duration.as_secs(),
+		_ => 0
+	};
+	stat[16..20].copy_from_slice(&mode.to_le_bytes()); // st_mode
+	stat[20..24].copy_from_slice(&1u32.to_le_bytes()); // st_nlink
+	stat[48..56].copy_from_slice(&size.to_le_bytes()); // st_size
+	stat[56..60].copy_from_slice(&4096u32.to_le_bytes()); // st_blksize
+	stat[64..72].copy_from_slice(&size.div_ceil(512).to_le_bytes()); // st_blocks
+	stat[72..80].copy_from_slice(&mtime.to_le_bytes()); // st_atime
+	stat[88..96].copy_from_slice(&mtime.to_le_bytes()); // st_mtime
+	stat[104..112].copy_from_slice(&mtime.to_le_bytes()); // st_ctime
+	stat
+}
@@ -0,0 +1,396 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use io::Write;
+
+use collections::FnvHashSet;
+use cpu::{Cpu, PrivilegeMode, Xlen};
+use error::DebugError;
+
+const ECALL: u32 = 0x00000073;
+const CSR_SATP_ADDRESS: u16 = 0x180;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_RT_SIGRETURN: u64 = 139;
+
+// Calls waiting for return over this are dropped, e.g. ones which never
+// return to the caller like execve
+const MAX_PENDING_SYSCALLS: usize = 256;
+
+// (number, name, number of arguments) of the RISC-V Linux system calls,
+// in the numbering of asm-generic
+const SYSCALLS: [(u64, &str, usize); 116] = [
+	(17, "getcwd", 2),
+	(19, "eventfd2", 2),
+	(20, "epoll_create1", 1),
+	(21, "epoll_ctl", 4),
+	(22, "epoll_pwait", 6),
+	(23, "dup", 1),
+	(24, "dup3", 3),
+	(25, "fcntl", 3),
+	(29, "ioctl", 3),
+	(32, "flock", 2),
+	(33, "mknodat", 4),
+	(34, "mkdirat", 3),
+	(35, "unlinkat", 3),
+	(36, "symlinkat", 3),
+	(37, "linkat", 5),
+	(39, "umount2", 2),
+	(40, "mount", 5),
+	(43, "statfs", 2),
+	(44, "fstatfs", 2),
+	(45, "truncate", 2),
+	(46, "ftruncate", 2),
+	(47, "fallocate", 4),
+	(48, "faccessat", 3),
+	(49, "chdir", 1),
+	(50, "fchdir", 1),
+	(51, "chroot", 1),
+	(52, "fchmod", 2),
+	(53, "fchmodat", 3),
+	(54, "fchownat", 5),
+	(55, "fchown", 3),
+	(56, "openat", 4),
+	(57, "close", 1),
+	(59, "pipe2", 2),
+	(61, "getdents64", 3),
+	(62, "lseek", 3),
+	(63, "read", 3),
+	(64, "write", 3),
+	(65, "readv", 3),
+	(66, "writev", 3),
+	(67, "pread64", 4),
+	(68, "pwrite64", 4),
+	(71, "sendfile", 4),
+	(72, "pselect6", 6),
+	(73, "ppoll", 5),
+	(78, "readlinkat", 4),
+	(79, "newfstatat", 4),
+	(80, "fstat", 2),
+	(81, "sync", 0),
+	(82, "fsync", 1),
+	(83, "fdatasync", 1),
+	(88, "utimensat", 4),
+	(93, "exit", 1),
+	(94, "exit_group", 1),
+	(96, "set_tid_address", 1),
+	(98, "futex", 6),
+	(99, "set_robust_list", 2),
+	(101, "nanosleep", 2),
+	(113, "clock_gettime", 2),
+	(115, "clock_nanosleep", 4),
+	(124, "sched_yield", 0),
+	(129, "kill", 2),
+	(130, "tkill", 2),
+	(131, "tgkill", 3),
+	(132, "sigaltstack", 2),
+	(134, "rt_sigaction", 4),
+	(135, "rt_sigprocmask", 4),
+	(139, "rt_sigreturn", 0),
+	(143, "setregid", 2),
+	(144, "setgid", 1),
+	(145, "setreuid", 2),
+	(146, "setuid", 1),
+	(147, "setresuid", 3),
+	(148, "getresuid", 3),
+	(149, "setresgid", 3),
+	(150, "getresgid", 3),
+	(153, "times", 1),
+	(154, "setpgid", 2),
+	(155, "getpgid", 1),
+	(156, "getsid", 1),
+	(157, "setsid", 0),
+	(160, "uname", 1),
+	(163, "getrlimit", 2),
+	(164, "setrlimit", 2),
+	(165, "getrusage", 2),
+	(166, "umask", 1),
+	(167, "prctl", 5),
+	(169, "gettimeofday", 2),
+	(172, "getpid", 0),
+	(173, "getppid", 0),
+	(174, "getuid", 0),
+	(175, "geteuid", 0),
+	(176, "getgid", 0),
+	(177, "getegid", 0),
+	(178, "gettid", 0),
+	(179, "sysinfo", 1),
+	(198, "socket", 3),
+	(199, "socketpair", 4),
+	(200, "bind", 3),
+	(201, "listen", 2),
+	(202, "accept", 3),
+	(203, "connect", 3),
+	(206, "sendto", 6),
+	(207, "recvfrom", 6),
+	(208, "setsockopt", 5),
+	(209, "getsockopt", 5),
+	(214, "brk", 1),
+	(215, "munmap", 2),
+	(216, "mremap", 5),
+	(220, "clone", 5),
+	(221, "execve", 3),
+	(222, "mmap", 6),
+	(226, "mprotect", 3),
+	(233, "madvise", 3),
+	(260, "wait4", 4),
+	(278, "getrandom", 3),
+	(435, "clone3", 2)
+];
+
+/// Returns the name of the RISC-V Linux system call, e.g. `openat` for 56.
+///
+/// # Arguments
+/// * `number`
+pub fn get_syscall_name(number: u64) -> Option<&'static str> {
+	SYSCALLS.iter().find(|(n, _, _)| *n == number).map(|(_, name, _)| *name)
+}
+
+fn get_syscall_number(name: &str) -> Option<u64> {
+	SYSCALLS.iter().find(|(_, n, _)| *n == name).map(|(number, _, _)| *number)
+}
+
+// System call made by ECALL and not returned yet
+struct PendingSyscall {
+	// The caller is identified by the address space, the return address,
+	// and the stack pointer
+	satp: u64,
+	return_address: u64,
+	sp: u64,
+	// Call without the return value, like `read(0x3, 0x3fffff00, 0x100)`
+	call: String
+}
+
+/// Streams the system calls Linux user programs make to a writer like
+/// `strace`, decoded from the guest registers per the RISC-V Linux ABI:
+/// the number in `a7`, the arguments in `a0` to `a5`, and the return
+/// value in `a0`.
+///
+/// ```text
+/// openat(0xffffff9c, 0x3fffc7a0e8, 0x80000, 0x0) = 3
+/// read(0x3, 0x3fffc7a120, 0x340) = 832
+/// exit_group(0x0) = ?
+/// ```
+///
+/// A call is detected at ECALL in user mode and written when the program
+/// resumes after it, so a blocking call appears when it returns, after
+/// calls of other processes it has waited for. Calls which never return,
+/// `exit`, `exit_group`, and `rt_sigreturn`, are written immediately with
+/// `?`. Unknown numbers are written like `syscall_123`. Works for a Linux
+/// kernel running on the emulator, not for user mode emulation. Errors
+/// on writing are ignored not to disturb the guest. Set to `Emulator`
+/// with `set_syscall_trace()`.
+pub struct SyscallTrace {
+	writer: Box<dyn Write>,
+	running: bool,
+	// System call numbers traced. None for all
+	filter: Option<FnvHashSet<u64>>,
+	pending: Vec<PendingSyscall>,
+	// Whether the CPU was in user mode at the last instruction
+	in_user_mode: bool
+}
+
+impl SyscallTrace {
+	/// Creates a new `SyscallTrace`. Tracing starts immediately.
+	///
+	/// # Arguments
+	/// * `writer` Where the trace is written
+	pub fn new(writer: Box<dyn Write>) -> Self {
+		SyscallTrace {
+			writer,
+			running: true,
+			filter: None,
+			pending: vec![],
+			in_user_mode: false
+		}
+	}
+
+	/// Traces only the system calls of the names, e.g. `openat` and
+	/// `execve`. Returns `Err` if a name is unknown.
+	///
+	/// # Arguments
+	/// * `names`
+	pub fn set_filter(&mut self, names: &[&str]) -> Result<(), DebugError> {
+		let mut filter = FnvHashSet::default();
+		for name in names.iter() {
+			match get_syscall_number(name) {
+				Some(number) => filter.insert(number),
+				None => return Err(DebugError::UnknownSyscall(name.to_string()))
+			};
+		}
+		self.filter = Some(filter);
+		Ok(())
+	}
+
+	/// Starts tracing.
+	pub fn start(&mut self) {
+		self.running = true;
+	}
+
+	/// Stops tracing and flushes the writer. Calls waiting for return
+	/// are dropped.
+	pub fn stop(&mut self) {
+		self.running = false;
+		self.pending.clear();
+		let _ = self.writer.flush();
+	}
+
+	/// Returns `true` if tracing is running.
+	pub fn is_running(&self) -> bool {
+		self.running
+	}
+
+	/// Records the instruction the CPU is about to run if it makes
+	/// a system call or resumes a program after one. Call this before
+	/// every `Cpu::tick()`.
+	///
+	/// # Arguments
+	/// * `cpu`
+	pub fn trace(&mut self, cpu: &mut Cpu) {
+		let in_user_mode = *cpu.read_privilege_mode() == PrivilegeMode::User;
+		let entered_user_mode = in_user_mode && !self.in_user_mode;
+		self.in_user_mode = in_user_mode;
+		if !self.running || !in_user_mode || cpu.is_waiting_for_interrupt() {
+			return;
+		}
+		let pc = cpu.read_pc();
+		if entered_user_mode && !self.pending.is_empty() {
+			let satp = read_satp(cpu);
+			let sp = read_register(cpu, 2);
+			let index = self.pending.iter().position(|syscall| {
+				syscall.satp == satp && syscall.return_address == pc && syscall.sp == sp
+			});
+			if let Some(index) = index {
+				let syscall = self.pending.remove(index);
+				let result = match cpu.get_xlen() {
+					Xlen::Bit32 => read_register(cpu, 10) as i32 as i64,
+					Xlen::Bit64 => read_register(cpu, 10) as i64
+				};
+				let _ = writeln!(self.writer, "{} = {}", syscall.call, result);
+			}
+		}
+		if cpu.fetch_next_instruction().map(|(word, _length)| word) != Some(ECALL) {
+			return;
+		}
+		let number = read_register(cpu, 17);
+		if let Some(filter) = &self.filter {
+			if !filter.contains(&number) {
+				return;
+			}
+		}
+		let (name, argument_num) = match SYSCALLS.iter().find(|(n, _, _)| *n == number) {
+			Some((_, name, argument_num)) => (name.to_string(), *argument_num),
+			None => (format!("syscall_{}", number), 6)
+		};
+		let arguments = (0..argument_num)
+			.map(|i| format!("0x{:x}", read_register(cpu, 10 + i as u8)))
+			.collect::<Vec<String>>()
+			.join(", ");
+		let call = format!("{}({})", name, arguments);
+		match number {
+			SYS_EXIT | SYS_EXIT_GROUP | SYS_RT_SIGRETURN => {
+				let _ = writeln!(self.writer, "{} = ?", call);
+			},
+			_ => {
+				if self.pending.len() == MAX_PENDING_SYSCALLS {
+					self.pending.remove(0);
+				}
+				self.pending.push(PendingSyscall {
+					satp: read_satp(cpu),
+					return_address: pc.wrapping_add(4),
+					sp: read_register(cpu, 2),
+					call
+				});
+			}
+		};
+	}
+}
+
+fn read_satp(cpu: &Cpu) -> u64 {
+	cpu.read_csr_as(CSR_SATP_ADDRESS, &PrivilegeMode::Machine).unwrap_or(0)
+}
+
+// Reads the integer register masked to XLEN
+fn read_register(cpu: &Cpu, register: u8) -> u64 {
+	match cpu.get_xlen() {
+		Xlen::Bit32 => cpu.read_register(register) as u64 & 0xffffffff,
+		Xlen::Bit64 => cpu.read_register(register) as u64
+	}
+}
+
+#[cfg(test)]
+mod test_syscall_trace {
+	use super::*;
+	use mmu::DRAM_BASE;
+	use core::cell::RefCell;
+	use alloc::rc::Rc;
+	use io;
+	use terminal::DummyTerminal;
+
+	struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for SharedLog {
+		fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+			self.0.borrow_mut().write(data)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	fn create_cpu() -> Cpu {
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x100);
+		cpu.update_pc(DRAM_BASE);
+		let code = [
+			0x30200073, // mret
+			0x04000893, // user: addi a7, zero, 64
+			0x00000073, // ecall
+			0x0000006f, // loop: jal zero, loop
+			0x341022f3, // handler: csrr t0, mepc
+			0x00428293, // addi t0, t0, 4
+			0x34129073, // csrw mepc, t0
+			0x00500513, // addi a0, zero, 5
+			0x30200073 // mret
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		let machine = PrivilegeMode::Machine;
+		assert_eq!(Ok(()), cpu.write_csr_as(0x341, DRAM_BASE + 4, &machine));
+		assert_eq!(Ok(()), cpu.write_csr_as(0x305, DRAM_BASE + 16, &machine));
+		for (register, value) in [(10, 1), (11, 0x2000), (12, 3)].iter() {
+			cpu.write_register(*register, *value);
+		}
+		cpu
+	}
+
+	#[test]
+	fn trace() {
+		let log = Rc::new(RefCell::new(vec![]));
+		let mut cpu = create_cpu();
+		let mut trace = SyscallTrace::new(Box::new(SharedLog(log.clone())));
+		for _ in 0..10 {
+			trace.trace(&mut cpu);
+			cpu.tick();
+		}
+		assert_eq!(DRAM_BASE + 12, cpu.read_pc());
+		assert_eq!("write(0x1, 0x2000, 0x3) = 5\n", String::from_utf8_lossy(&log.borrow()));
+
+		let log = Rc::new(RefCell::new(vec![]));
+		let mut cpu = create_cpu();
+		let mut trace = SyscallTrace::new(Box::new(SharedLog(log.clone())));
+		assert_eq!(Err(DebugError::UnknownSyscall("bogus".to_string())), trace.set_filter(&["bogus"]));
+		assert_eq!(Ok(()), trace.set_filter(&["read", "openat"]));
+		for _ in 0..10 {
+			trace.trace(&mut cpu);
+			cpu.tick();
+		}
+		assert!(log.borrow().is_empty());
+		assert_eq!(Some("openat"), get_syscall_name(56));
+	}
+}
@@ -0,0 +1,77 @@
+extern crate libc;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+use net_backend::NetBackend;
+
+const MAX_FRAME_SIZE: usize = 65536;
+
+// struct ifreq in <net/if.h>
+#[repr(C)]
+struct IfReq {
+	name: [libc::c_char; libc::IFNAMSIZ],
+	flags: libc::c_short,
+	_padding: [u8; 22]
+}
+
+/// `NetBackend` connected to a host TAP device. Frames from the guest
+/// are written to the device and frames read from the device go to the guest.
+/// The TAP device needs to be created and configured on the host beforehand,
+/// e.g. `ip tuntap add tap0 mode tap user $USER && ip link set tap0 up`.
+/// Only available on Linux.
+pub struct TapNetBackend {
+	file: File,
+	buffer: Vec<u8>
+}
+
+impl TapNetBackend {
+	/// Opens a host TAP device.
+	///
+	/// # Arguments
+	/// * `name` TAP device name, e.g. "tap0"
+	pub fn new(name: &str) -> io::Result<Self> {
+		if name.len() >= libc::IFNAMSIZ {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "Too long TAP device name"));
+		}
+		let file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.custom_flags(libc::O_NONBLOCK)
+			.open("/dev/net/tun")?;
+		let mut request = IfReq {
+			name: [0; libc::IFNAMSIZ],
+			flags: (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short,
+			_padding: [0; 22]
+		};
+		for (i, c) in name.bytes().enumerate() {
+			request.name[i] = c as libc::c_char;
+		}
+		let result = unsafe {
+			libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut request as *mut IfReq)
+		};
+		if result < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(TapNetBackend {
+			file,
+			buffer: vec![0; MAX_FRAME_SIZE]
+		})
+	}
+}
+
+impl NetBackend for TapNetBackend {
+	fn send(&mut self, frame: &[u8]) {
+		// Frames are dropped on error as physical network does
+		let _ = self.file.write(frame);
+	}
+
+	fn poll(&mut self) -> Option<Vec<u8>> {
+		match self.file.read(&mut self.buffer) {
+			Ok(length) if length > 0 => Some(self.buffer[..length].to_vec()),
+			_ => None
+		}
+	}
+}
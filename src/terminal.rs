@@ -1,5 +1,13 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
 /// Emulates terminal. It holds input/output data in buffer
 /// transferred to/from `Emulator`.
+///
+/// All the methods must not block. Devices poll the terminal from the
+/// CPU loop, so a terminal without input returns immediately. The bulk and
+/// readiness methods have default implementations on top of the byte
+/// methods. Terminals override them when they can do better.
 pub trait Terminal {
 	/// Puts an output ascii byte data to output buffer.
 	/// The data is expected to be read by user program via `get_output()`
@@ -16,10 +24,178 @@ pub trait Terminal {
 	fn put_input(&mut self, data: u8);
 
 	/// Gets an input ascii byte data from input buffer.
-	/// Used by `Emulator`.
+	/// Used by `Emulator`. This method returns zero if no input is available.
 	fn get_input(&mut self) -> u8;
+
+	/// Puts output bytes to output buffer at once.
+	///
+	/// # Arguments
+	/// * `values`
+	fn put_bytes(&mut self, values: &[u8]) {
+		for value in values.iter() {
+			self.put_byte(*value);
+		}
+	}
+
+	/// Moves as many output bytes as available to `buffer` and
+	/// returns the number of the bytes. Zero means the buffer is empty.
+	///
+	/// # Arguments
+	/// * `buffer`
+	fn get_output_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		read_bytes(buffer, || self.get_output())
+	}
+
+	/// Moves all the output bytes available to the end of `data` and
+	/// returns the number of the bytes. Unlike `get_output_bytes()` the
+	/// caller doesn't need to know the size beforehand.
+	///
+	/// # Arguments
+	/// * `data`
+	fn read_available(&mut self, data: &mut Vec<u8>) -> usize {
+		let mut buffer = [0; 256];
+		let mut total = 0;
+		loop {
+			let size = self.get_output_bytes(&mut buffer);
+			if size == 0 {
+				return total;
+			}
+			data.extend_from_slice(&buffer[..size]);
+			total += size;
+		}
+	}
+
+	/// Puts input bytes to input buffer at once.
+	///
+	/// # Arguments
+	/// * `data`
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		for value in data.iter() {
+			self.put_input(*value);
+		}
+	}
+
+	/// Moves as many input bytes as available to `buffer` and
+	/// returns the number of the bytes. Used by `Emulator`.
+	///
+	/// # Arguments
+	/// * `buffer`
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		read_bytes(buffer, || self.get_input())
+	}
+
+	/// Indicates whether input is ready to be read. Devices skip reading
+	/// while this method returns `false`. Terminals which can't tell
+	/// without reading return `true`, the default.
+	fn has_input(&mut self) -> bool {
+		true
+	}
+
+	/// Indicates whether output can be put now without being dropped or
+	/// blocking, for flow control. Devices hold output while this method
+	/// returns `false`, which the guest sees as a busy transmitter, so a
+	/// slow sink slows down the guest rather than losing data. Terminals
+	/// which always accept output return `true`, the default.
+	fn is_output_ready(&mut self) -> bool {
+		true
+	}
+
+	/// Returns the window size of the terminal as (columns, rows), which
+	/// devices such as `VirtioConsole` tell the guest. Terminals which
+	/// don't know return `None`, the default.
+	fn get_window_size(&mut self) -> Option<(u16, u16)> {
+		None
+	}
 }
 
+/// Reassembles UTF-8 text from the output bytes for frontends which
+/// display text rather than bytes. A multibyte character is often split
+/// across `put_byte()` calls, so the bytes of an incomplete character are
+/// kept until the rest arrives. Invalid sequences are replaced with
+/// U+FFFD REPLACEMENT CHARACTER.
+///
+/// ```ignore
+/// fn put_bytes(&mut self, values: &[u8]) {
+///   let window = &mut self.window;
+///   self.decoder.decode(values, |text| window.print(text));
+/// }
+/// ```
+pub struct Utf8Decoder {
+	pending_data: Vec<u8>
+}
+
+impl Utf8Decoder {
+	/// Creates a new `Utf8Decoder`.
+	pub fn new() -> Self {
+		Utf8Decoder {
+			pending_data: vec![]
+		}
+	}
+
+	/// Decodes the bytes following the ones given so far and calls
+	/// `callback` with the text completed. `callback` isn't called if
+	/// no character is completed.
+	///
+	/// # Arguments
+	/// * `values`
+	/// * `callback`
+	pub fn decode<F: FnMut(&str)>(&mut self, values: &[u8], mut callback: F) {
+		self.pending_data.extend_from_slice(values);
+		let mut text = String::new();
+		let mut start = 0;
+		while start < self.pending_data.len() {
+			let data = &self.pending_data[start..];
+			match core::str::from_utf8(data) {
+				Ok(valid_text) => {
+					text.push_str(valid_text);
+					start = self.pending_data.len();
+				},
+				Err(error) => {
+					let valid_size = error.valid_up_to();
+					text.push_str(&String::from_utf8_lossy(&data[..valid_size]));
+					match error.error_len() {
+						Some(invalid_size) => {
+							text.push(core::char::REPLACEMENT_CHARACTER);
+							start += valid_size + invalid_size;
+						},
+						// Incomplete character at the end
+						None => {
+							start += valid_size;
+							break;
+						}
+					};
+				}
+			};
+		}
+		self.pending_data.drain(..start);
+		if !text.is_empty() {
+			callback(&text);
+		}
+	}
+
+	/// Indicates whether bytes of an incomplete character are kept.
+	pub fn has_pending_data(&self) -> bool {
+		!self.pending_data.is_empty()
+	}
+}
+
+impl Default for Utf8Decoder {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+fn read_bytes<F: FnMut() -> u8>(buffer: &mut [u8], mut read: F) -> usize {
+	for (i, byte) in buffer.iter_mut().enumerate() {
+		match read() {
+			0 => return i,
+			value => *byte = value
+		};
+	}
+	buffer.len()
+}
+
+/// `Terminal` which discards output and provides no input.
 /// For the test or whatever.
 pub struct DummyTerminal {}
 
@@ -31,7 +207,32 @@ impl DummyTerminal {
 
 impl Terminal for DummyTerminal {
 	fn put_byte(&mut self, _value: u8) {}
+	fn put_bytes(&mut self, _values: &[u8]) {}
 	fn get_input(&mut self) -> u8 { 0 }
 	fn put_input(&mut self, _value: u8) {}
 	fn get_output(&mut self) -> u8 { 0 }
+	fn has_input(&mut self) -> bool { false }
+}
+
+#[cfg(test)]
+mod test_terminal {
+	use super::*;
+
+	fn decode(decoder: &mut Utf8Decoder, values: &[u8]) -> String {
+		let mut text = String::new();
+		decoder.decode(values, |decoded| text.push_str(decoded));
+		text
+	}
+
+	#[test]
+	fn utf8_decoder() {
+		let mut decoder = Utf8Decoder::new();
+		// "aあ" split in the middle of the multibyte character
+		assert_eq!("a", decode(&mut decoder, b"a\xe3\x81"));
+		assert!(decoder.has_pending_data());
+		assert_eq!("あ", decode(&mut decoder, b"\x82"));
+		assert!(!decoder.has_pending_data());
+		assert_eq!("", decode(&mut decoder, b"\xe3"));
+		assert_eq!("\u{fffd}b\u{fffd}c", decode(&mut decoder, b"b\xffc"));
+	}
 }
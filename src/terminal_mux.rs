@@ -0,0 +1,210 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use terminal::Terminal;
+
+/// Input byte starting a mux command, Ctrl-a. Followed by a port number
+/// `0`-`9` it switches the port receiving input. Pressed twice it inputs
+/// itself to the current port.
+pub const MUX_ESCAPE: u8 = 0x01;
+
+// Shared by the ports
+struct MuxState {
+	terminal: Box<dyn Terminal>,
+	prefixes: Vec<String>,
+	input_data: Vec<VecDeque<u8>>,
+	// Port receiving input
+	active_port: usize,
+	// Port which has output last
+	last_port: Option<usize>,
+	at_line_start: bool,
+	escaping: bool
+}
+
+impl MuxState {
+	/// Reads input from the terminal and dispatches it to the active port.
+	fn receive(&mut self) {
+		if !self.terminal.has_input() {
+			return;
+		}
+		let mut buffer = [0; 64];
+		loop {
+			let size = self.terminal.get_input_bytes(&mut buffer);
+			if size == 0 {
+				return;
+			}
+			for value in buffer[..size].iter() {
+				self.handle_input(*value);
+			}
+		}
+	}
+
+	fn handle_input(&mut self, value: u8) {
+		match self.escaping {
+			true => {
+				self.escaping = false;
+				match value {
+					MUX_ESCAPE => self.input_data[self.active_port].push_back(value),
+					b'0'..=b'9' if ((value - b'0') as usize) < self.prefixes.len() => {
+						self.active_port = (value - b'0') as usize;
+					},
+					_ => {}
+				};
+			},
+			false => match value {
+				MUX_ESCAPE => self.escaping = true,
+				_ => self.input_data[self.active_port].push_back(value)
+			}
+		};
+	}
+
+	fn output(&mut self, port: usize, values: &[u8]) {
+		let mut data = vec![];
+		// Output of another port starts on a new line
+		if self.last_port != Some(port) && !self.at_line_start {
+			data.extend_from_slice(b"\r\n");
+			self.at_line_start = true;
+		}
+		self.last_port = Some(port);
+		for value in values.iter() {
+			if self.at_line_start {
+				data.extend_from_slice(self.prefixes[port].as_bytes());
+			}
+			data.push(*value);
+			self.at_line_start = *value == b'\n';
+		}
+		self.terminal.put_bytes(&data);
+	}
+}
+
+/// Shares a `Terminal` with multiple character devices, e.g. the console
+/// and the serial ports added with `EmulatorConfig::serial_port_num`.
+/// Each device is connected to a port `create_port()` returns. The output
+/// of the ports is interleaved line by line with the prefix of each port.
+/// The input goes to one port at a time, switched with `MUX_ESCAPE`
+/// followed by the port number like `-serial mon:stdio` of QEMU.
+///
+/// ```ignore
+/// let mut mux = TerminalMux::new(terminal);
+/// let console = mux.create_port("");
+/// let serial_port = mux.create_port("[ttyS1] ");
+/// let mut emulator = Emulator::new_with_config(console, config);
+/// emulator.set_serial_port_terminal(0, serial_port);
+/// ```
+pub struct TerminalMux {
+	state: Rc<RefCell<MuxState>>
+}
+
+impl TerminalMux {
+	/// Creates a new `TerminalMux`.
+	///
+	/// # Arguments
+	/// * `terminal` Terminal shared by the ports
+	pub fn new(terminal: Box<dyn Terminal>) -> Self {
+		TerminalMux {
+			state: Rc::new(RefCell::new(MuxState {
+				terminal,
+				prefixes: vec![],
+				input_data: vec![],
+				active_port: 0,
+				last_port: None,
+				at_line_start: true,
+				escaping: false
+			}))
+		}
+	}
+
+	/// Creates a new port. Ports are numbered from zero in the creation
+	/// order and the first port receives input at first.
+	///
+	/// # Arguments
+	/// * `prefix` Put at the beginning of every output line of the port
+	pub fn create_port(&mut self, prefix: &str) -> Box<dyn Terminal> {
+		let mut state = self.state.borrow_mut();
+		state.prefixes.push(prefix.to_string());
+		state.input_data.push(VecDeque::new());
+		Box::new(MuxPort {
+			state: self.state.clone(),
+			index: state.prefixes.len() - 1
+		})
+	}
+}
+
+/// `Terminal` of a port of [`TerminalMux`](struct.TerminalMux.html).
+pub struct MuxPort {
+	state: Rc<RefCell<MuxState>>,
+	index: usize
+}
+
+impl Terminal for MuxPort {
+	fn put_byte(&mut self, value: u8) {
+		self.state.borrow_mut().output(self.index, &[value]);
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		self.state.borrow_mut().output(self.index, values);
+	}
+
+	fn get_input(&mut self) -> u8 {
+		let mut state = self.state.borrow_mut();
+		state.receive();
+		state.input_data[self.index].pop_front().unwrap_or(0)
+	}
+
+	fn has_input(&mut self) -> bool {
+		let mut state = self.state.borrow_mut();
+		state.receive();
+		!state.input_data[self.index].is_empty()
+	}
+
+	fn is_output_ready(&mut self) -> bool {
+		self.state.borrow_mut().terminal.is_output_ready()
+	}
+
+	fn get_window_size(&mut self) -> Option<(u16, u16)> {
+		self.state.borrow_mut().terminal.get_window_size()
+	}
+
+	/// Puts input to the shared terminal. It goes to the active port.
+	fn put_input(&mut self, data: u8) {
+		self.state.borrow_mut().terminal.put_input(data);
+	}
+
+	/// Gets output of all the ports from the shared terminal.
+	fn get_output(&mut self) -> u8 {
+		self.state.borrow_mut().terminal.get_output()
+	}
+}
+
+#[cfg(test)]
+mod test_terminal_mux {
+	use super::*;
+	use default_terminal::DefaultTerminal;
+
+	#[test]
+	fn interleave() {
+		let mut mux = TerminalMux::new(Box::new(DefaultTerminal::new()));
+		let mut console = mux.create_port("");
+		let mut serial_port = mux.create_port("[1] ");
+		console.put_bytes(b"$ ");
+		serial_port.put_bytes(b"a\nb");
+		serial_port.put_byte(b'\n');
+		console.put_byte(b'x');
+		let mut output = vec![];
+		console.read_available(&mut output);
+		assert_eq!(b"$ \r\n[1] a\n[1] b\nx".to_vec(), output);
+
+		console.put_input_bytes(b"l\x011s\x01\x01\x010");
+		assert_eq!(b'l', console.get_input());
+		assert!(!console.has_input());
+		assert_eq!(b's', serial_port.get_input());
+		assert_eq!(MUX_ESCAPE, serial_port.get_input());
+		assert_eq!(0, serial_port.get_input());
+		console.put_input(b'm');
+		assert_eq!(b'm', console.get_input());
+	}
+}
@@ -0,0 +1,112 @@
+use std::time::{Duration, Instant};
+
+use terminal::Terminal;
+
+/// `Terminal` wrapper which limits the output rate like a serial line of
+/// a certain baud rate. It reports not ready for output until the time to
+/// transfer the previous output passes, so the guest sees a transmitter as
+/// slow as the real one. The input isn't limited.
+pub struct ThrottledTerminal {
+	terminal: Box<dyn Terminal>,
+	byte_time: Duration,
+	// When the output put so far finishes transferring
+	ready_time: Instant
+}
+
+impl ThrottledTerminal {
+	/// Creates a new `ThrottledTerminal`.
+	///
+	/// # Arguments
+	/// * `terminal` Inner terminal
+	/// * `bytes_per_second` Output rate. A serial line of 115200 baud
+	///   with 8N1 framing transfers 11520 bytes per second.
+	pub fn new(terminal: Box<dyn Terminal>, bytes_per_second: u64) -> Self {
+		ThrottledTerminal {
+			terminal,
+			byte_time: Duration::from_secs(1) / bytes_per_second.max(1) as u32,
+			ready_time: Instant::now()
+		}
+	}
+
+	fn consume(&mut self, size: usize) {
+		let now = Instant::now();
+		// Idle time isn't saved up for bursts
+		if self.ready_time < now {
+			self.ready_time = now;
+		}
+		self.ready_time += self.byte_time * size as u32;
+	}
+}
+
+impl Terminal for ThrottledTerminal {
+	fn put_byte(&mut self, value: u8) {
+		self.consume(1);
+		self.terminal.put_byte(value);
+	}
+
+	fn get_output(&mut self) -> u8 {
+		self.terminal.get_output()
+	}
+
+	fn put_input(&mut self, data: u8) {
+		self.terminal.put_input(data);
+	}
+
+	fn get_input(&mut self) -> u8 {
+		self.terminal.get_input()
+	}
+
+	fn put_bytes(&mut self, values: &[u8]) {
+		self.consume(values.len());
+		self.terminal.put_bytes(values);
+	}
+
+	fn get_output_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.terminal.get_output_bytes(buffer)
+	}
+
+	fn put_input_bytes(&mut self, data: &[u8]) {
+		self.terminal.put_input_bytes(data);
+	}
+
+	fn get_input_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.terminal.get_input_bytes(buffer)
+	}
+
+	fn has_input(&mut self) -> bool {
+		self.terminal.has_input()
+	}
+
+	fn get_window_size(&mut self) -> Option<(u16, u16)> {
+		self.terminal.get_window_size()
+	}
+
+	fn is_output_ready(&mut self) -> bool {
+		Instant::now() >= self.ready_time && self.terminal.is_output_ready()
+	}
+}
+
+#[cfg(test)]
+mod test_throttled_terminal {
+	use super::*;
+	use default_terminal::DefaultTerminal;
+	use device::uart::{Uart, UART_BASE};
+
+	#[test]
+	fn backpressure() {
+		let terminal = ThrottledTerminal::new(Box::new(DefaultTerminal::new()), 1);
+		let mut uart = Uart::new(Box::new(terminal));
+		uart.store(UART_BASE, b'a');
+		for _ in 0..0x10 {
+			uart.tick();
+		}
+		assert_eq!(b'a', uart.get_mut_terminal().get_output());
+		// THR stays full until a second passes
+		uart.store(UART_BASE, b'b');
+		for _ in 0..0x20 {
+			uart.tick();
+		}
+		assert_eq!(0, uart.load(UART_BASE + 5) & 0x20);
+		assert_eq!(0, uart.get_mut_terminal().get_output());
+	}
+}
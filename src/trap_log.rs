@@ -0,0 +1,193 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use collections::FnvHashMap;
+use cpu::PrivilegeMode;
+use symbol_table::SymbolTable;
+
+/// Trap taken by `Cpu`, recorded in `TrapLog`
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrapRecord {
+	/// Whether the trap is an interrupt rather than an exception
+	pub interrupt: bool,
+	/// Exception code or interrupt number, without the interrupt bit
+	pub code: u64,
+	/// Name of the trap, e.g. `LoadPageFault`
+	pub name: &'static str,
+	/// Value written to `xepc`. The address of the instruction raising
+	/// the exception, or the one to resume after the interrupt
+	pub epc: u64,
+	/// Value written to `xtval`, e.g. the faulting address
+	pub value: u64,
+	/// Privilege mode the trap is taken from
+	pub from: PrivilegeMode,
+	/// Privilege mode handling the trap
+	pub to: PrivilegeMode,
+	/// CPU clock when the trap is taken
+	pub clock: u64
+}
+
+/// Number of traps of a cause, returned by `TrapLog::get_counts()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrapCount {
+	pub interrupt: bool,
+	pub code: u64,
+	pub name: &'static str,
+	pub count: u64
+}
+
+/// Called with every trap as it's taken
+pub type TrapCallback = Box<dyn FnMut(&TrapRecord)>;
+
+/// Records the traps the CPU takes to diagnose slow guests, e.g. a flood
+/// of page faults or timer interrupts. Counts every trap by cause and
+/// keeps the latest ones in a bounded ring. Interrupts masked or not
+/// enabled aren't taken so aren't recorded. Set to `Emulator` with
+/// `set_trap_log()`.
+pub struct TrapLog {
+	capacity: usize,
+	records: VecDeque<TrapRecord>,
+	// (interrupt, code) -> (name, count)
+	counts: FnvHashMap<(bool, u64), (&'static str, u64)>,
+	callback: Option<TrapCallback>,
+	symbol_table: Option<SymbolTable>
+}
+
+impl TrapLog {
+	/// Creates a new `TrapLog`.
+	///
+	/// # Arguments
+	/// * `capacity` How many latest traps are kept. 0 for counts only
+	pub fn new(capacity: usize) -> Self {
+		TrapLog {
+			capacity,
+			records: VecDeque::with_capacity(capacity),
+			counts: FnvHashMap::default(),
+			callback: None,
+			symbol_table: None
+		}
+	}
+
+	/// Sets the callback called with every trap as it's taken.
+	///
+	/// # Arguments
+	/// * `callback`
+	pub fn set_callback(&mut self, callback: TrapCallback) {
+		self.callback = Some(callback);
+	}
+
+	/// Sets the symbols `get_function_name()` looks up.
+	///
+	/// # Arguments
+	/// * `symbol_table`
+	pub fn set_symbol_table(&mut self, symbol_table: SymbolTable) {
+		self.symbol_table = Some(symbol_table);
+	}
+
+	/// Returns the name of the function at the address, e.g. `epc` of
+	/// a record, with the offset from it like `handle_page_fault+0x1c`,
+	/// or in hexadecimal without a symbol.
+	///
+	/// # Arguments
+	/// * `address` Virtual address
+	pub fn get_function_name(&self, address: u64) -> String {
+		match self.symbol_table.as_ref().and_then(|symbol_table| symbol_table.lookup(address)) {
+			Some((name, 0)) => name.to_string(),
+			Some((name, offset)) => format!("{}+0x{:x}", name, offset),
+			None => format!("0x{:x}", address)
+		}
+	}
+
+	/// Records a trap. `Cpu` calls this when it takes a trap.
+	///
+	/// # Arguments
+	/// * `record`
+	pub fn record(&mut self, record: TrapRecord) {
+		self.counts.entry((record.interrupt, record.code)).or_insert((record.name, 0)).1 += 1;
+		if let Some(callback) = self.callback.as_mut() {
+			callback(&record);
+		}
+		if self.capacity == 0 {
+			return;
+		}
+		if self.records.len() == self.capacity {
+			self.records.pop_front();
+		}
+		self.records.push_back(record);
+	}
+
+	/// Returns the latest traps kept, the oldest first.
+	pub fn get_records(&self) -> Vec<TrapRecord> {
+		self.records.iter().cloned().collect()
+	}
+
+	/// Returns the number of traps by cause, the most frequent first.
+	pub fn get_counts(&self) -> Vec<TrapCount> {
+		let mut counts: Vec<TrapCount> = self.counts.iter()
+			.map(|((interrupt, code), (name, count))| TrapCount {
+				interrupt: *interrupt,
+				code: *code,
+				name,
+				count: *count
+			})
+			.collect();
+		counts.sort_by(|a, b| b.count.cmp(&a.count)
+			.then(a.interrupt.cmp(&b.interrupt))
+			.then(a.code.cmp(&b.code)));
+		counts
+	}
+
+	/// Forgets the traps recorded so far.
+	pub fn clear(&mut self) {
+		self.records.clear();
+		self.counts.clear();
+	}
+}
+
+#[cfg(test)]
+mod test_trap_log {
+	use core::cell::Cell;
+	use alloc::rc::Rc;
+	use super::*;
+	use cpu::Cpu;
+	use mmu::DRAM_BASE;
+	use terminal::DummyTerminal;
+
+	#[test]
+	fn record() {
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x100);
+		cpu.update_pc(DRAM_BASE);
+		// ecall trapping to itself
+		if cpu.get_mut_mmu().store_word(DRAM_BASE, 0x00000073).is_err() {
+			panic!("Failed to store");
+		}
+		assert!(cpu.write_csr_as(0x305, DRAM_BASE, &PrivilegeMode::Machine).is_ok()); // mtvec
+
+		let called = Rc::new(Cell::new(0));
+		let called_clone = called.clone();
+		let mut trap_log = TrapLog::new(2);
+		trap_log.set_callback(Box::new(move |_record| called_clone.set(called_clone.get() + 1)));
+		cpu.set_trap_log(trap_log);
+		for _ in 0..3 {
+			cpu.tick();
+		}
+		let trap_log = cpu.get_mut_trap_log().unwrap();
+		assert_eq!(3, called.get());
+		assert_eq!(vec![TrapCount {
+			interrupt: false,
+			code: 11,
+			name: "EnvironmentCallFromMMode",
+			count: 3
+		}], trap_log.get_counts());
+		let records = trap_log.get_records();
+		assert_eq!(2, records.len());
+		assert_eq!((DRAM_BASE, PrivilegeMode::Machine, PrivilegeMode::Machine, 1),
+			(records[0].epc, records[0].from.clone(), records[0].to.clone(), records[0].clock));
+		trap_log.clear();
+		assert!(trap_log.get_records().is_empty());
+		assert!(trap_log.get_counts().is_empty());
+	}
+}
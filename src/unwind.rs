@@ -0,0 +1,200 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use cpu::{Cpu, Xlen};
+use mmu::Mmu;
+
+// sp and s0, the stack and frame pointers of the standard calling convention
+const STACK_POINTER: u8 = 2;
+const FRAME_POINTER: u8 = 8;
+
+// Stack slots looked at by the scan fallback
+const SCAN_LIMIT: u64 = 1024;
+
+const JAL_OPCODE: u32 = 0x6f;
+const JALR_OPCODE: u32 = 0x67;
+
+/// How a frame returned by `unwind_stack()` has been found
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnwindMethod {
+	/// The innermost frame, at the current PC
+	Pc,
+	/// The return address saved in the frame pointed by the frame pointer
+	/// chain. Reliable
+	FramePointer,
+	/// A value on the stack which looks like a return address because
+	/// the instruction before it is a call. It can be a stale one left
+	/// by a returned function
+	Scan
+}
+
+/// Frame of the guest call stack, returned by `Emulator::unwind_stack()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackFrame {
+	/// The current PC for the innermost frame, otherwise the return
+	/// address into the function
+	pub address: u64,
+	/// Name of the function the address belongs to, `None` without
+	/// a symbol before it
+	pub function: Option<String>,
+	/// Offset of the address from the function
+	pub offset: u64,
+	pub method: UnwindMethod
+}
+
+/// Walks the guest call stack at the current point and returns
+/// the addresses of the frames, the innermost first, up to the number
+/// of frames. The walk follows the frame pointer chain in the layout GCC
+/// and LLVM use with `-fno-omit-frame-pointer`, the return address at
+/// `s0 - XLEN/8` and the caller's frame pointer at `s0 - 2 * XLEN/8`, and
+/// ends at a null return address or frame pointer. If the chain is broken,
+/// e.g. code built without frame pointers, it falls back to scanning
+/// the stack upward for values right after call instructions. Memory is
+/// read without side effects other than the accessed bits of page table
+/// entries.
+///
+/// # Arguments
+/// * `cpu`
+/// * `max_frames` Including the innermost one
+pub fn unwind_stack(cpu: &mut Cpu, max_frames: usize) -> Vec<(u64, UnwindMethod)> {
+	let mut frames = vec![];
+	if max_frames == 0 {
+		return frames;
+	}
+	frames.push((cpu.read_pc(), UnwindMethod::Pc));
+	let (width, mask) = match cpu.get_xlen() {
+		Xlen::Bit32 => (4, 0xffffffff),
+		Xlen::Bit64 => (8, 0xffffffffffffffff)
+	};
+	let is_32bit = width == 4;
+	let stack_pointer = cpu.read_register(STACK_POINTER) as u64 & mask;
+	let mut frame_pointer = cpu.read_register(FRAME_POINTER) as u64 & mask;
+	let mmu = cpu.get_mut_mmu();
+	// The lowest stack address the scan fallback starts from
+	let mut scan_start = stack_pointer;
+	let mut broken = false;
+	while frames.len() < max_frames {
+		// Frames are above the stack pointer and callers above callees
+		if frame_pointer < scan_start {
+			broken = true;
+			break;
+		}
+		let return_address = match mmu.peek(frame_pointer.wrapping_sub(width), width) {
+			Some(0) => break,
+			Some(address) if is_return_address(mmu, address, is_32bit) => address,
+			_ => {
+				broken = true;
+				break;
+			}
+		};
+		frames.push((return_address, UnwindMethod::FramePointer));
+		scan_start = frame_pointer;
+		frame_pointer = match mmu.peek(frame_pointer.wrapping_sub(width * 2), width) {
+			Some(0) => break,
+			Some(address) => address,
+			None => {
+				broken = true;
+				break;
+			}
+		};
+	}
+	if broken {
+		let mut address = scan_start & !(width - 1);
+		for _ in 0..SCAN_LIMIT {
+			if frames.len() >= max_frames {
+				break;
+			}
+			match mmu.peek(address, width) {
+				Some(value) if is_return_address(mmu, value, is_32bit) => frames.push((value, UnwindMethod::Scan)),
+				Some(_) => {},
+				None => break
+			};
+			address = address.wrapping_add(width);
+		}
+	}
+	frames
+}
+
+// Indicates whether the instruction before the address is a call linking
+// to ra or t0
+fn is_return_address(mmu: &mut Mmu, address: u64, is_32bit: bool) -> bool {
+	if address & 1 != 0 || address < 4 {
+		return false;
+	}
+	let low = peek_halfword(mmu, address - 4);
+	let high = peek_halfword(mmu, address - 2);
+	if let (Some(low), Some(high)) = (low, high) {
+		let word = low | (high << 16);
+		let opcode = word & 0x7f;
+		let rd = (word >> 7) & 0x1f;
+		if (opcode == JAL_OPCODE || opcode == JALR_OPCODE) && (rd == 1 || rd == 5) {
+			return true;
+		}
+	}
+	match high {
+		// C.JALR, or C.JAL only in RV32
+		Some(halfword) => (halfword & 0xf07f == 0x9002 && (halfword >> 7) & 0x1f != 0) ||
+			(is_32bit && halfword & 0xe003 == 0x2001),
+		None => false
+	}
+}
+
+// peek() reads aligned words only
+fn peek_halfword(mmu: &mut Mmu, address: u64) -> Option<u32> {
+	let word = mmu.peek(address & !3, 4)?;
+	Some(((word >> ((address & 2) * 8)) & 0xffff) as u32)
+}
+
+#[cfg(test)]
+mod test_unwind {
+	use super::*;
+	use alloc::boxed::Box;
+	use mmu::DRAM_BASE;
+	use terminal::DummyTerminal;
+
+	#[test]
+	fn unwind() {
+		let mut cpu = Cpu::new(Box::new(DummyTerminal::new()));
+		cpu.get_mut_mmu().init_memory(0x1000);
+		let code = [
+			0x008000ef, // main: jal ra, foo
+			0x0000006f, // jal zero, 0
+			0x004000ef // foo: jal ra, bar
+		];
+		for (i, word) in code.iter().enumerate() {
+			if cpu.get_mut_mmu().store_word(DRAM_BASE + i as u64 * 4, *word).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		// bar's frame returning into foo, whose frame returns into main
+		let stack = [
+			(DRAM_BASE + 0xf00, DRAM_BASE + 0xf20), // bar: caller's s0
+			(DRAM_BASE + 0xf08, DRAM_BASE + 12), // bar: ra
+			(DRAM_BASE + 0xf10, 0), // foo: caller's s0
+			(DRAM_BASE + 0xf18, DRAM_BASE + 4) // foo: ra
+		];
+		for (address, value) in stack.iter() {
+			if cpu.get_mut_mmu().store_doubleword(*address, *value).is_err() {
+				panic!("Failed to store");
+			}
+		}
+		cpu.update_pc(DRAM_BASE + 12);
+		cpu.write_register(STACK_POINTER, (DRAM_BASE + 0xf00) as i64);
+		cpu.write_register(FRAME_POINTER, (DRAM_BASE + 0xf10) as i64);
+		assert_eq!(vec![
+			(DRAM_BASE + 12, UnwindMethod::Pc),
+			(DRAM_BASE + 12, UnwindMethod::FramePointer),
+			(DRAM_BASE + 4, UnwindMethod::FramePointer)
+		], unwind_stack(&mut cpu, 8));
+		assert_eq!(2, unwind_stack(&mut cpu, 2).len());
+
+		// Without frame pointers the return addresses on the stack are
+		// found, skipping the other values
+		cpu.write_register(FRAME_POINTER, 0x1234);
+		assert_eq!(vec![
+			(DRAM_BASE + 12, UnwindMethod::Pc),
+			(DRAM_BASE + 12, UnwindMethod::Scan),
+			(DRAM_BASE + 4, UnwindMethod::Scan)
+		], unwind_stack(&mut cpu, 8));
+	}
+}
@@ -0,0 +1,660 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use net_backend::NetBackend;
+
+// Virtual network layout. Same as QEMU user-mode network.
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+const DNS_IP: [u8; 4] = [10, 0, 2, 3];
+const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+const NETMASK: [u8; 4] = [255, 255, 255, 0];
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+const IP_PROTOCOL_ICMP: u8 = 1;
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+const TCP_MSS: usize = 1460;
+const TCP_WINDOW: u16 = 0xffff;
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_LEASE_TIME: u32 = 86400;
+
+const FALLBACK_DNS_SERVER: Ipv4Addr = Ipv4Addr::new(8, 8, 8, 8);
+
+struct UdpBinding {
+	socket: UdpSocket,
+	guest_port: u16
+}
+
+struct TcpConnection {
+	stream: TcpStream,
+	guest_port: u16,
+	remote_ip: [u8; 4],
+	remote_port: u16,
+	/// Next sequence number sent to the guest
+	seq: u32,
+	/// Next sequence number expected from the guest
+	ack: u32,
+	/// The latest acknowledged number from the guest
+	guest_acked: u32,
+	guest_window: u32,
+	/// Data from the guest not written to the host yet
+	to_host: Vec<u8>,
+	host_closed: bool,
+	guest_closed: bool
+}
+
+/// `NetBackend` emulating user-mode NAT network as QEMU's one. The guest
+/// gets 10.0.2.15 via DHCP and reaches the outside world through host sockets
+/// with gateway 10.0.2.2, which is also mapped to the host loopback address,
+/// and DNS server 10.0.2.3. No host privilege is required.
+///
+/// ARP, DHCP, ICMP echo to the gateway, UDP and TCP are supported.
+/// Because the virtual link never drops frames TCP doesn't retransmit.
+pub struct UserNetBackend {
+	guest_mac: [u8; 6],
+	dns_server: Ipv4Addr,
+	frames: VecDeque<Vec<u8>>,
+	udp_bindings: Vec<UdpBinding>,
+	tcp_connections: Vec<TcpConnection>,
+	ip_id: u16,
+	tcp_iss: u32
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+	((data[offset] as u16) << 8) | (data[offset + 1] as u16)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+	((read_u16(data, offset) as u32) << 16) | (read_u16(data, offset + 2) as u32)
+}
+
+fn read_ip(data: &[u8], offset: usize) -> [u8; 4] {
+	[data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]
+}
+
+/// Calculates Internet checksum of data following the initial sum.
+fn checksum(initial: u32, data: &[u8]) -> u16 {
+	let mut sum = initial;
+	for chunk in data.chunks(2) {
+		let word = match chunk.len() {
+			2 => ((chunk[0] as u32) << 8) | (chunk[1] as u32),
+			_ => (chunk[0] as u32) << 8
+		};
+		sum += word;
+	}
+	while (sum >> 16) != 0 {
+		sum = (sum & 0xffff) + (sum >> 16);
+	}
+	!(sum as u16)
+}
+
+fn pseudo_header_sum(src: [u8; 4], dst: [u8; 4], protocol: u8, length: usize) -> u32 {
+	read_u16(&src, 0) as u32 + read_u16(&src, 2) as u32 +
+		read_u16(&dst, 0) as u32 + read_u16(&dst, 2) as u32 +
+		protocol as u32 + length as u32
+}
+
+/// Reads the first name server from the host resolver configuration.
+fn get_host_dns_server() -> Option<Ipv4Addr> {
+	let content = fs::read_to_string("/etc/resolv.conf").ok()?;
+	content.lines()
+		.filter_map(|line| {
+			let mut words = line.split_whitespace();
+			match words.next() {
+				Some("nameserver") => words.next()?.parse::<Ipv4Addr>().ok(),
+				_ => None
+			}
+		})
+		.find(|address| !address.is_loopback())
+}
+
+impl UserNetBackend {
+	/// Creates a new `UserNetBackend`.
+	pub fn new() -> Self {
+		UserNetBackend {
+			guest_mac: BROADCAST_MAC,
+			dns_server: get_host_dns_server().unwrap_or(FALLBACK_DNS_SERVER),
+			frames: VecDeque::new(),
+			udp_bindings: vec![],
+			tcp_connections: vec![],
+			ip_id: 0,
+			tcp_iss: 0x10000000
+		}
+	}
+
+	/// Maps an address in the virtual network to the host one.
+	fn to_host_address(&self, ip: [u8; 4], port: u16) -> SocketAddr {
+		let address = match ip {
+			GATEWAY_IP => Ipv4Addr::LOCALHOST,
+			DNS_IP => self.dns_server,
+			_ => Ipv4Addr::from(ip)
+		};
+		SocketAddr::new(IpAddr::V4(address), port)
+	}
+
+	/// Maps a host address to the one in the virtual network.
+	fn to_guest_address(&self, address: &SocketAddr) -> Option<[u8; 4]> {
+		match address.ip() {
+			IpAddr::V4(ip) if ip.is_loopback() => Some(GATEWAY_IP),
+			IpAddr::V4(ip) if ip == self.dns_server && address.port() == 53 => Some(DNS_IP),
+			IpAddr::V4(ip) => Some(ip.octets()),
+			IpAddr::V6(_) => None
+		}
+	}
+
+	fn push_ethernet(&mut self, ethertype: u16, payload: &[u8]) {
+		let mut frame = Vec::with_capacity(14 + payload.len());
+		frame.extend_from_slice(&self.guest_mac);
+		frame.extend_from_slice(&GATEWAY_MAC);
+		frame.extend_from_slice(&ethertype.to_be_bytes());
+		frame.extend_from_slice(payload);
+		self.frames.push_back(frame);
+	}
+
+	fn push_ipv4(&mut self, src: [u8; 4], dst: [u8; 4], protocol: u8, payload: &[u8]) {
+		let mut packet = Vec::with_capacity(20 + payload.len());
+		packet.extend_from_slice(&[0x45, 0]);
+		packet.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+		packet.extend_from_slice(&self.ip_id.to_be_bytes());
+		// Don't fragment, TTL 64
+		packet.extend_from_slice(&[0x40, 0, 64, protocol, 0, 0]);
+		packet.extend_from_slice(&src);
+		packet.extend_from_slice(&dst);
+		let sum = checksum(0, &packet);
+		packet[10..12].copy_from_slice(&sum.to_be_bytes());
+		packet.extend_from_slice(payload);
+		self.ip_id = self.ip_id.wrapping_add(1);
+		self.push_ethernet(ETHERTYPE_IPV4, &packet);
+	}
+
+	fn push_udp(&mut self, src: [u8; 4], src_port: u16, dst: [u8; 4], dst_port: u16, data: &[u8]) {
+		let length = 8 + data.len();
+		let mut segment = Vec::with_capacity(length);
+		segment.extend_from_slice(&src_port.to_be_bytes());
+		segment.extend_from_slice(&dst_port.to_be_bytes());
+		segment.extend_from_slice(&(length as u16).to_be_bytes());
+		segment.extend_from_slice(&[0, 0]);
+		segment.extend_from_slice(data);
+		let sum = checksum(pseudo_header_sum(src, dst, IP_PROTOCOL_UDP, length), &segment);
+		segment[6..8].copy_from_slice(&sum.to_be_bytes());
+		self.push_ipv4(src, dst, IP_PROTOCOL_UDP, &segment);
+	}
+
+	fn push_tcp(&mut self, index: usize, flags: u8, data: &[u8]) {
+		let (src, src_port, dst_port, seq, ack) = {
+			let connection = &self.tcp_connections[index];
+			(connection.remote_ip, connection.remote_port, connection.guest_port,
+				connection.seq, connection.ack)
+		};
+		// MSS option in SYN
+		let options: &[u8] = match (flags & TCP_SYN) != 0 {
+			true => &[2, 4, (TCP_MSS >> 8) as u8, TCP_MSS as u8],
+			false => &[]
+		};
+		let header_length = 20 + options.len();
+		let mut segment = Vec::with_capacity(header_length + data.len());
+		segment.extend_from_slice(&src_port.to_be_bytes());
+		segment.extend_from_slice(&dst_port.to_be_bytes());
+		segment.extend_from_slice(&seq.to_be_bytes());
+		segment.extend_from_slice(&ack.to_be_bytes());
+		segment.extend_from_slice(&[((header_length / 4) << 4) as u8, flags]);
+		segment.extend_from_slice(&TCP_WINDOW.to_be_bytes());
+		segment.extend_from_slice(&[0, 0, 0, 0]);
+		segment.extend_from_slice(options);
+		segment.extend_from_slice(data);
+		let sum = checksum(pseudo_header_sum(src, GUEST_IP, IP_PROTOCOL_TCP, segment.len()), &segment);
+		segment[16..18].copy_from_slice(&sum.to_be_bytes());
+		self.push_ipv4(src, GUEST_IP, IP_PROTOCOL_TCP, &segment);
+
+		let connection = &mut self.tcp_connections[index];
+		connection.seq = connection.seq.wrapping_add(data.len() as u32);
+		if (flags & (TCP_SYN | TCP_FIN)) != 0 {
+			connection.seq = connection.seq.wrapping_add(1);
+		}
+	}
+
+	fn handle_arp(&mut self, packet: &[u8]) {
+		if packet.len() < 28 || read_u16(packet, 6) != 1 {
+			return;
+		}
+		let sender_mac = &packet[8..14];
+		let sender_ip = read_ip(packet, 14);
+		let target_ip = read_ip(packet, 24);
+		// Answers for any address in the virtual network other than the guest
+		let in_network = (0..4).all(|i| (target_ip[i] & NETMASK[i]) == (GATEWAY_IP[i] & NETMASK[i]));
+		if !in_network || target_ip == GUEST_IP || target_ip == sender_ip {
+			return;
+		}
+		let mut reply = vec![0, 1, 8, 0, 6, 4, 0, 2];
+		reply.extend_from_slice(&GATEWAY_MAC);
+		reply.extend_from_slice(&target_ip);
+		reply.extend_from_slice(sender_mac);
+		reply.extend_from_slice(&sender_ip);
+		self.push_ethernet(ETHERTYPE_ARP, &reply);
+	}
+
+	fn handle_ipv4(&mut self, packet: &[u8]) {
+		if packet.len() < 20 || (packet[0] >> 4) != 4 {
+			return;
+		}
+		let header_length = ((packet[0] & 0xf) as usize) * 4;
+		let total_length = (read_u16(packet, 2) as usize).min(packet.len());
+		// Fragments are not supported
+		if header_length < 20 || total_length < header_length || (read_u16(packet, 6) & 0x3fff) != 0 {
+			return;
+		}
+		let src = read_ip(packet, 12);
+		let dst = read_ip(packet, 16);
+		let payload = &packet[header_length..total_length];
+		match packet[9] {
+			IP_PROTOCOL_ICMP => self.handle_icmp(src, dst, payload),
+			IP_PROTOCOL_UDP => self.handle_udp(src, dst, payload),
+			IP_PROTOCOL_TCP => self.handle_tcp(dst, payload),
+			_ => {}
+		};
+	}
+
+	fn handle_icmp(&mut self, src: [u8; 4], dst: [u8; 4], message: &[u8]) {
+		// Only echo to the gateway is supported because ICMP to the outside
+		// world requires privileged raw sockets.
+		if message.len() < 8 || message[0] != 8 || (dst != GATEWAY_IP && dst != DNS_IP) {
+			return;
+		}
+		let mut reply = message.to_vec();
+		reply[0] = 0;
+		reply[2] = 0;
+		reply[3] = 0;
+		let sum = checksum(0, &reply);
+		reply[2..4].copy_from_slice(&sum.to_be_bytes());
+		self.push_ipv4(dst, src, IP_PROTOCOL_ICMP, &reply);
+	}
+
+	fn handle_udp(&mut self, src: [u8; 4], dst: [u8; 4], datagram: &[u8]) {
+		if datagram.len() < 8 {
+			return;
+		}
+		let src_port = read_u16(datagram, 0);
+		let dst_port = read_u16(datagram, 2);
+		let length = (read_u16(datagram, 4) as usize).min(datagram.len());
+		if length < 8 {
+			return;
+		}
+		let data = &datagram[8..length];
+		if dst_port == DHCP_SERVER_PORT {
+			self.handle_dhcp(data);
+			return;
+		}
+		if src != GUEST_IP {
+			return;
+		}
+		let address = self.to_host_address(dst, dst_port);
+		let index = match self.udp_bindings.iter().position(|binding| binding.guest_port == src_port) {
+			Some(index) => index,
+			None => {
+				let socket = match UdpSocket::bind("0.0.0.0:0") {
+					Ok(socket) => socket,
+					Err(_) => return
+				};
+				if socket.set_nonblocking(true).is_err() {
+					return;
+				}
+				self.udp_bindings.push(UdpBinding {
+					socket,
+					guest_port: src_port
+				});
+				self.udp_bindings.len() - 1
+			}
+		};
+		let _ = self.udp_bindings[index].socket.send_to(data, address);
+	}
+
+	fn handle_dhcp(&mut self, message: &[u8]) {
+		// op, htype, hlen, hops, xid, secs, flags, ciaddr, yiaddr, siaddr,
+		// giaddr, chaddr (16 bytes), sname (64 bytes), file (128 bytes), magic cookie
+		if message.len() < 240 || message[0] != 1 || message[236..240] != DHCP_MAGIC_COOKIE {
+			return;
+		}
+		let mut message_type = 0;
+		let mut offset = 240;
+		while offset + 1 < message.len() && message[offset] != 255 {
+			if message[offset] == 0 {
+				offset += 1;
+				continue;
+			}
+			let length = message[offset + 1] as usize;
+			if message[offset] == 53 && length == 1 && offset + 2 < message.len() {
+				message_type = message[offset + 2];
+			}
+			offset += 2 + length;
+		}
+		let reply_type = match message_type {
+			DHCP_DISCOVER => DHCP_OFFER,
+			DHCP_REQUEST => DHCP_ACK,
+			_ => return
+		};
+
+		let mut reply = vec![0; 240];
+		reply[0] = 2;
+		reply[1] = 1;
+		reply[2] = 6;
+		reply[4..8].copy_from_slice(&message[4..8]);
+		reply[16..20].copy_from_slice(&GUEST_IP);
+		reply[20..24].copy_from_slice(&GATEWAY_IP);
+		reply[28..44].copy_from_slice(&message[28..44]);
+		reply[236..240].copy_from_slice(&DHCP_MAGIC_COOKIE);
+		reply.extend_from_slice(&[53, 1, reply_type]);
+		reply.extend_from_slice(&[54, 4]);
+		reply.extend_from_slice(&GATEWAY_IP);
+		reply.extend_from_slice(&[51, 4]);
+		reply.extend_from_slice(&DHCP_LEASE_TIME.to_be_bytes());
+		reply.extend_from_slice(&[1, 4]);
+		reply.extend_from_slice(&NETMASK);
+		reply.extend_from_slice(&[3, 4]);
+		reply.extend_from_slice(&GATEWAY_IP);
+		reply.extend_from_slice(&[6, 4]);
+		reply.extend_from_slice(&DNS_IP);
+		reply.push(255);
+		self.push_udp(GATEWAY_IP, DHCP_SERVER_PORT, [255; 4], DHCP_CLIENT_PORT, &reply);
+	}
+
+	fn handle_tcp(&mut self, dst: [u8; 4], segment: &[u8]) {
+		if segment.len() < 20 {
+			return;
+		}
+		let src_port = read_u16(segment, 0);
+		let dst_port = read_u16(segment, 2);
+		let seq = read_u32(segment, 4);
+		let ack = read_u32(segment, 8);
+		let header_length = ((segment[12] >> 4) as usize) * 4;
+		let flags = segment[13];
+		let window = read_u16(segment, 14) as u32;
+		if header_length < 20 || header_length > segment.len() {
+			return;
+		}
+		let data = &segment[header_length..];
+
+		let found = self.tcp_connections.iter().position(|connection| {
+			connection.guest_port == src_port && connection.remote_ip == dst &&
+				connection.remote_port == dst_port
+		});
+		let index = match found {
+			Some(index) => index,
+			None => {
+				if (flags & TCP_SYN) != 0 && (flags & TCP_ACK) == 0 {
+					self.open_tcp_connection(dst, dst_port, src_port, seq, window);
+				}
+				return;
+			}
+		};
+
+		if (flags & TCP_RST) != 0 {
+			self.tcp_connections.remove(index);
+			return;
+		}
+
+		{
+			let connection = &mut self.tcp_connections[index];
+			if (flags & TCP_ACK) != 0 {
+				connection.guest_acked = ack;
+				connection.guest_window = window;
+			}
+		}
+
+		// In order data only. Otherwise acknowledges the expected sequence again.
+		let expected = self.tcp_connections[index].ack;
+		if seq != expected {
+			if !data.is_empty() || (flags & TCP_FIN) != 0 {
+				self.push_tcp(index, TCP_ACK, &[]);
+			}
+			return;
+		}
+		let mut needs_ack = false;
+		if !data.is_empty() {
+			let connection = &mut self.tcp_connections[index];
+			connection.to_host.extend_from_slice(data);
+			connection.ack = connection.ack.wrapping_add(data.len() as u32);
+			needs_ack = true;
+		}
+		if (flags & TCP_FIN) != 0 {
+			let connection = &mut self.tcp_connections[index];
+			connection.ack = connection.ack.wrapping_add(1);
+			connection.guest_closed = true;
+			needs_ack = true;
+		}
+		if needs_ack {
+			self.push_tcp(index, TCP_ACK, &[]);
+		}
+		self.flush_tcp_connection(index);
+	}
+
+	fn open_tcp_connection(&mut self, remote_ip: [u8; 4], remote_port: u16, guest_port: u16, seq: u32, window: u32) {
+		let address = self.to_host_address(remote_ip, remote_port);
+		let stream = TcpStream::connect_timeout(&address, TCP_CONNECT_TIMEOUT)
+			.and_then(|stream| stream.set_nonblocking(true).map(|_| stream));
+		let iss = self.tcp_iss;
+		self.tcp_iss = self.tcp_iss.wrapping_add(0x10000);
+		let stream = match stream {
+			Ok(stream) => stream,
+			Err(_) => {
+				// Refuses with RST
+				let mut segment = vec![];
+				segment.extend_from_slice(&remote_port.to_be_bytes());
+				segment.extend_from_slice(&guest_port.to_be_bytes());
+				segment.extend_from_slice(&0u32.to_be_bytes());
+				segment.extend_from_slice(&seq.wrapping_add(1).to_be_bytes());
+				segment.extend_from_slice(&[5 << 4, TCP_RST | TCP_ACK, 0, 0, 0, 0, 0, 0]);
+				let sum = checksum(pseudo_header_sum(remote_ip, GUEST_IP, IP_PROTOCOL_TCP, segment.len()), &segment);
+				segment[16..18].copy_from_slice(&sum.to_be_bytes());
+				self.push_ipv4(remote_ip, GUEST_IP, IP_PROTOCOL_TCP, &segment);
+				return;
+			}
+		};
+		self.tcp_connections.push(TcpConnection {
+			stream,
+			guest_port,
+			remote_ip,
+			remote_port,
+			seq: iss,
+			ack: seq.wrapping_add(1),
+			guest_acked: iss,
+			guest_window: window,
+			to_host: vec![],
+			host_closed: false,
+			guest_closed: false
+		});
+		let index = self.tcp_connections.len() - 1;
+		self.push_tcp(index, TCP_SYN | TCP_ACK, &[]);
+	}
+
+	/// Writes pending data from the guest to the host.
+	fn flush_tcp_connection(&mut self, index: usize) {
+		let connection = &mut self.tcp_connections[index];
+		while !connection.to_host.is_empty() {
+			match connection.stream.write(&connection.to_host) {
+				Ok(length) if length > 0 => {
+					connection.to_host.drain(..length);
+				},
+				_ => break
+			};
+		}
+		if connection.to_host.is_empty() && connection.guest_closed {
+			let _ = connection.stream.shutdown(Shutdown::Write);
+		}
+	}
+
+	/// Reads data from the host sockets and makes frames to the guest.
+	fn service(&mut self) {
+		let mut buffer = [0; 0x10000];
+		for i in 0..self.udp_bindings.len() {
+			while let Ok((length, address)) = self.udp_bindings[i].socket.recv_from(&mut buffer) {
+				if let Some(src) = self.to_guest_address(&address) {
+					let guest_port = self.udp_bindings[i].guest_port;
+					self.push_udp(src, address.port(), GUEST_IP, guest_port, &buffer[..length]);
+				}
+			}
+		}
+
+		let mut index = 0;
+		while index < self.tcp_connections.len() {
+			self.flush_tcp_connection(index);
+			let mut reset = false;
+			loop {
+				let allowed = {
+					let connection = &self.tcp_connections[index];
+					let in_flight = connection.seq.wrapping_sub(connection.guest_acked);
+					match connection.host_closed {
+						true => 0,
+						false => connection.guest_window.saturating_sub(in_flight).min(TCP_MSS as u32) as usize
+					}
+				};
+				if allowed == 0 {
+					break;
+				}
+				match self.tcp_connections[index].stream.read(&mut buffer[..allowed]) {
+					Ok(0) => {
+						self.tcp_connections[index].host_closed = true;
+						self.push_tcp(index, TCP_FIN | TCP_ACK, &[]);
+					},
+					Ok(length) => {
+						let data = buffer[..length].to_vec();
+						self.push_tcp(index, TCP_PSH | TCP_ACK, &data);
+					},
+					Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+					Err(_) => {
+						self.push_tcp(index, TCP_RST | TCP_ACK, &[]);
+						reset = true;
+						break;
+					}
+				};
+			}
+			// Both sides are closed and everything is acknowledged
+			let finished = reset || {
+				let connection = &self.tcp_connections[index];
+				connection.host_closed && connection.guest_closed &&
+					connection.guest_acked == connection.seq && connection.to_host.is_empty()
+			};
+			match finished {
+				true => {
+					self.tcp_connections.remove(index);
+				},
+				false => index += 1
+			};
+		}
+	}
+}
+
+impl Default for UserNetBackend {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl NetBackend for UserNetBackend {
+	fn send(&mut self, frame: &[u8]) {
+		if frame.len() < 14 {
+			return;
+		}
+		self.guest_mac.copy_from_slice(&frame[6..12]);
+		let payload = &frame[14..];
+		match read_u16(frame, 12) {
+			ETHERTYPE_ARP => self.handle_arp(payload),
+			ETHERTYPE_IPV4 => self.handle_ipv4(payload),
+			_ => {}
+		};
+	}
+
+	fn poll(&mut self) -> Option<Vec<u8>> {
+		if self.frames.is_empty() {
+			self.service();
+		}
+		self.frames.pop_front()
+	}
+}
+
+#[cfg(test)]
+mod test_user_net_backend {
+	use super::*;
+
+	const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+	fn create_frame(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+		let mut frame = BROADCAST_MAC.to_vec();
+		frame.extend_from_slice(&GUEST_MAC);
+		frame.extend_from_slice(&ethertype.to_be_bytes());
+		frame.extend_from_slice(payload);
+		frame
+	}
+
+	#[test]
+	fn arp() {
+		let mut backend = UserNetBackend::new();
+		let mut request = vec![0, 1, 8, 0, 6, 4, 0, 1];
+		request.extend_from_slice(&GUEST_MAC);
+		request.extend_from_slice(&GUEST_IP);
+		request.extend_from_slice(&[0; 6]);
+		request.extend_from_slice(&GATEWAY_IP);
+		backend.send(&create_frame(ETHERTYPE_ARP, &request));
+		let reply = backend.poll().unwrap();
+		assert_eq!(GUEST_MAC, reply[0..6]);
+		assert_eq!(GATEWAY_MAC, reply[6..12]);
+		// Operation: reply
+		assert_eq!(2, read_u16(&reply, 20));
+		assert_eq!(GATEWAY_MAC, reply[22..28]);
+		assert_eq!(GATEWAY_IP, reply[28..32]);
+		assert!(backend.poll().is_none());
+
+		// No reply for the guest address itself
+		request[24..28].copy_from_slice(&GUEST_IP);
+		backend.send(&create_frame(ETHERTYPE_ARP, &request));
+		assert!(backend.poll().is_none());
+	}
+
+	#[test]
+	fn icmp_echo() {
+		let mut backend = UserNetBackend::new();
+		let mut message = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1, 0xaa, 0xbb];
+		let sum = checksum(0, &message);
+		message[2..4].copy_from_slice(&sum.to_be_bytes());
+		let mut packet = vec![0x45, 0];
+		packet.extend_from_slice(&((20 + message.len()) as u16).to_be_bytes());
+		packet.extend_from_slice(&[0, 0, 0x40, 0, 64, IP_PROTOCOL_ICMP, 0, 0]);
+		packet.extend_from_slice(&GUEST_IP);
+		packet.extend_from_slice(&GATEWAY_IP);
+		packet.extend_from_slice(&message);
+		backend.send(&create_frame(ETHERTYPE_IPV4, &packet));
+
+		let reply = backend.poll().unwrap();
+		let ip = &reply[14..];
+		// IP header checksum is valid
+		assert_eq!(0, checksum(0, &ip[0..20]));
+		assert_eq!(GATEWAY_IP, ip[12..16]);
+		assert_eq!(GUEST_IP, ip[16..20]);
+		let icmp = &ip[20..];
+		// Echo reply with the same identifier, sequence and data
+		assert_eq!(0, icmp[0]);
+		assert_eq!(message[4..], icmp[4..]);
+		assert_eq!(0, checksum(0, icmp));
+	}
+}
@@ -0,0 +1,290 @@
+extern crate sha3;
+extern crate rand;
+
+use collections::FnvHashMap;
+use self::sha3::{Digest, Sha3_256};
+use self::rand::Rng;
+use config::{MacAlgorithm, ZipperStackConfig};
+use random::create_rng;
+
+/// Return address bits below the tag. RV64 with Sv39 uses only the lower
+/// 39 bits of addresses.
+pub const RETURN_ADDRESS_BITS: u32 = 39;
+
+/// Return address bits mask. `ZIP` places the tag above them.
+pub const RETURN_ADDRESS_MASK: u64 = (1 << RETURN_ADDRESS_BITS) - 1;
+
+/// Maximum tag width, filling the register above the return address.
+pub const MAX_TAG_WIDTH: u32 = 64 - RETURN_ADDRESS_BITS;
+
+/// `mzkeyctl` CSR bit locking the key CSRs until reset. Writes to them are
+/// ignored while it's set.
+pub const KEY_CONTROL_LOCK: u64 = 0x1;
+
+/// `mzkeyctl` CSR bit selecting the key by the privilege mode running
+/// `ZIP` and `UNZIP`.
+pub const KEY_CONTROL_PER_PRIVILEGE: u64 = 0x2;
+
+/// `mzkeyctl` CSR bit selecting the key by the ASID in `satp`.
+pub const KEY_CONTROL_PER_ASID: u64 = 0x4;
+
+/// `mzkeyctl` CSR bit which replaces the key of the slot `mzkeysel`
+/// selects with a random one when written. Reads as zero.
+pub const KEY_CONTROL_ROTATE: u64 = 0x8;
+
+/// Writable bits of `mzkeyctl` CSR kept in the register.
+pub const KEY_CONTROL_MASK: u64 = KEY_CONTROL_LOCK | KEY_CONTROL_PER_PRIVILEGE | KEY_CONTROL_PER_ASID;
+
+/// Returns the key slot of the privilege mode encoding and the ASID, the
+/// value `mzkeysel` CSR selects it with.
+///
+/// # Arguments
+/// * `privilege_encoding`
+/// * `asid`
+pub fn get_key_slot(privilege_encoding: u8, asid: u16) -> u64 {
+	((asid as u64) << 2) | (privilege_encoding as u64 & 0x3)
+}
+
+// Round constants of the toy cipher, the fractional digits of pi as in
+// QARMA
+const QARMA_ROUND_CONSTANTS: [u64; 5] = [
+	0x13198a2e03707344,
+	0xa4093822299f31d0,
+	0x082efa98ec4e6c89,
+	0x452821e638d01377,
+	0xbe5466cf34e90c6c
+];
+
+// Involutory S-box σ0 of QARMA
+const QARMA_SBOX: [u8; 16] = [0, 14, 2, 10, 9, 15, 8, 11, 6, 4, 3, 7, 13, 12, 1, 5];
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Message authentication code of return addresses which `ZIP` and `UNZIP`
+/// instructions of Zipper Stack chain. The MAC of the last tagged return
+/// address with the tag of the one before is the tag of the next, so only
+/// the top of the chain stays in the processor. The algorithm and the tag
+/// width are set by [`ZipperStackConfig`](../config/struct.ZipperStackConfig.html).
+///
+/// The keys are kept in slots by the privilege mode and the ASID, see
+/// [`get_key_slot()`](fn.get_key_slot.html), which machine mode manages
+/// with the key CSRs. Slot zero has the key of the configuration, and
+/// the slots without their own key fall back to it.
+pub struct ZipperMac {
+	algorithm: MacAlgorithm,
+	tag_width: u32,
+	key: u64,
+	// Keys of the slots other than zero
+	slot_keys: FnvHashMap<u64, u64>,
+	hasher: Sha3_256
+}
+
+impl ZipperMac {
+	/// Creates a new `ZipperMac`. The key is random unless the
+	/// configuration has one. The tag width is clamped to
+	/// `1..=MAX_TAG_WIDTH`.
+	///
+	/// # Arguments
+	/// * `config`
+	pub fn new(config: &ZipperStackConfig) -> Self {
+		ZipperMac {
+			algorithm: config.mac.clone(),
+			tag_width: config.tag_width.clamp(1, MAX_TAG_WIDTH),
+			key: match config.key {
+				Some(key) => key,
+				None => create_rng().gen()
+			},
+			slot_keys: FnvHashMap::default(),
+			hasher: Sha3_256::new()
+		}
+	}
+
+	/// Returns the algorithm.
+	pub fn get_algorithm(&self) -> &MacAlgorithm {
+		&self.algorithm
+	}
+
+	/// Returns the tag width in bits.
+	pub fn get_tag_width(&self) -> u32 {
+		self.tag_width
+	}
+
+	/// Returns the secret key of slot zero.
+	pub fn get_key(&self) -> u64 {
+		self.key
+	}
+
+	/// Sets the secret key of slot zero, e.g. to reproduce tags across
+	/// runs.
+	///
+	/// # Arguments
+	/// * `key`
+	pub fn set_key(&mut self, key: u64) {
+		self.key = key;
+	}
+
+	/// Returns the secret key of the slot.
+	///
+	/// # Arguments
+	/// * `slot`
+	pub fn get_slot_key(&self, slot: u64) -> u64 {
+		*self.slot_keys.get(&slot).unwrap_or(&self.key)
+	}
+
+	/// Sets the secret key of the slot.
+	///
+	/// # Arguments
+	/// * `slot`
+	/// * `key`
+	pub fn set_slot_key(&mut self, slot: u64, key: u64) {
+		match slot {
+			0 => self.key = key,
+			_ => {
+				self.slot_keys.insert(slot, key);
+			}
+		};
+	}
+
+	/// Replaces the secret key of the slot with a random one.
+	///
+	/// # Arguments
+	/// * `slot`
+	pub fn rotate_slot_key(&mut self, slot: u64) {
+		self.set_slot_key(slot, create_rng().gen());
+	}
+
+	/// Returns the mask of the tag bits above the return address.
+	pub fn get_tag_mask(&self) -> u64 {
+		((1 << self.tag_width) - 1) << RETURN_ADDRESS_BITS
+	}
+
+	/// Returns the tag of a tagged return address, placed at the tag bits
+	/// above the return address and the others cleared.
+	///
+	/// # Arguments
+	/// * `value` Return address with the tag of the previous one
+	pub fn compute(&mut self, value: u64) -> u64 {
+		self.compute_with_slot(0, value)
+	}
+
+	/// Returns the tag of a tagged return address like `compute()` with
+	/// the key of the slot.
+	///
+	/// # Arguments
+	/// * `slot`
+	/// * `value` Return address with the tag of the previous one
+	pub fn compute_with_slot(&mut self, slot: u64, value: u64) -> u64 {
+		let key = self.get_slot_key(slot);
+		let mac = match self.algorithm {
+			MacAlgorithm::Sha3 => self.compute_sha3(key, value),
+			MacAlgorithm::SipHash => compute_siphash(key, !key, value),
+			MacAlgorithm::Qarma => compute_qarma(key, value),
+			MacAlgorithm::Fnv => compute_fnv(key, value)
+		};
+		(mac << RETURN_ADDRESS_BITS) & self.get_tag_mask()
+	}
+
+	// The first four bytes of SHA3-256 of the key and the value in big endian
+	fn compute_sha3(&mut self, key: u64, value: u64) -> u64 {
+		self.hasher.update(key.to_be_bytes());
+		self.hasher.update(value.to_be_bytes());
+		let hash = self.hasher.finalize_reset();
+		u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) as u64
+	}
+}
+
+// SipHash-2-4 of the value in little endian as an eight-byte message
+fn compute_siphash(k0: u64, k1: u64, value: u64) -> u64 {
+	let mut v = [
+		k0 ^ 0x736f6d6570736575,
+		k1 ^ 0x646f72616e646f6d,
+		k0 ^ 0x6c7967656e657261,
+		k1 ^ 0x7465646279746573
+	];
+	let sip_round = |v: &mut [u64; 4]| {
+		v[0] = v[0].wrapping_add(v[1]);
+		v[1] = v[1].rotate_left(13) ^ v[0];
+		v[0] = v[0].rotate_left(32);
+		v[2] = v[2].wrapping_add(v[3]);
+		v[3] = v[3].rotate_left(16) ^ v[2];
+		v[0] = v[0].wrapping_add(v[3]);
+		v[3] = v[3].rotate_left(21) ^ v[0];
+		v[2] = v[2].wrapping_add(v[1]);
+		v[1] = v[1].rotate_left(17) ^ v[2];
+		v[2] = v[2].rotate_left(32);
+	};
+	// The message block, then the last one with only the length
+	for block in [value, 8 << 56].iter() {
+		v[3] ^= block;
+		sip_round(&mut v);
+		sip_round(&mut v);
+		v[0] ^= block;
+	}
+	v[2] ^= 0xff;
+	for _ in 0..4 {
+		sip_round(&mut v);
+	}
+	v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+// Toy cipher in the style of QARMA, the tweakable block cipher designed
+// for pointer authentication. Rounds of key and constant addition, the
+// S-box layer, and a rotation based linear layer, between key whitening.
+// Not analyzed at all, only to compare a cheap cipher with the hashes
+fn compute_qarma(key: u64, value: u64) -> u64 {
+	let mut state = value ^ key;
+	for constant in QARMA_ROUND_CONSTANTS.iter() {
+		state ^= key ^ constant;
+		let mut substituted = 0;
+		for i in 0..16 {
+			let nibble = (state >> (i * 4)) & 0xf;
+			substituted |= (QARMA_SBOX[nibble as usize] as u64) << (i * 4);
+		}
+		state = substituted ^ substituted.rotate_left(12) ^ substituted.rotate_left(40);
+	}
+	state ^ key.rotate_right(1) ^ (key >> 63)
+}
+
+// FNV-1a of the key and the value in little endian. Fast but not keyed
+// securely, as the baseline of the truncated hashes
+fn compute_fnv(key: u64, value: u64) -> u64 {
+	key.to_le_bytes().iter().chain(value.to_le_bytes().iter())
+		.fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+#[cfg(test)]
+mod test_zipper_stack {
+	use super::*;
+	use alloc::vec::Vec;
+
+	#[test]
+	#[allow(deprecated)]
+	fn siphash() {
+		use core::hash::{Hasher, SipHasher};
+		for (k0, k1, value) in [(0u64, 0u64, 0u64), (0x0706050403020100, 0x0f0e0d0c0b0a0908, 0x1234_5678_9abc_def0), (!0, 1, 0x80000000)].iter() {
+			let mut hasher = SipHasher::new_with_keys(*k0, *k1);
+			hasher.write(&value.to_le_bytes());
+			assert_eq!(hasher.finish(), compute_siphash(*k0, *k1, *value));
+		}
+	}
+
+	#[test]
+	fn tag_width() {
+		for algorithm in [MacAlgorithm::Sha3, MacAlgorithm::SipHash, MacAlgorithm::Qarma, MacAlgorithm::Fnv].iter() {
+			let mut mac = ZipperMac::new(&ZipperStackConfig {
+				mac: algorithm.clone(),
+				tag_width: 8,
+				key: Some(0x0123456789abcdef)
+			});
+			assert_eq!(0xff << RETURN_ADDRESS_BITS, mac.get_tag_mask());
+			let tags: Vec<u64> = (0..64).map(|value| mac.compute(0x80000000 + value * 4)).collect();
+			assert!(tags.iter().all(|tag| tag & !mac.get_tag_mask() == 0));
+			// Distinct enough not to be a constant
+			assert!(tags.iter().any(|tag| *tag != tags[0]));
+			// Depends on the key
+			mac.set_key(0xfedcba9876543210);
+			assert!((0..64).map(|value| mac.compute(0x80000000 + value * 4)).ne(tags.into_iter()));
+		}
+	}
+}
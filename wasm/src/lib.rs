@@ -3,9 +3,57 @@ extern crate riscv_emu_rust;
 
 use wasm_bindgen::prelude::*;
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
 
 use riscv_emu_rust::Emulator;
+use riscv_emu_rust::clock::Clock;
+use riscv_emu_rust::config::{EmulatorConfig, FramebufferConfig};
 use riscv_emu_rust::default_terminal::DefaultTerminal;
+use riscv_emu_rust::block_backend::MemoryBlockBackend;
+
+// Output bytes passed to the output callback at once at most
+const OUTPUT_CHUNK_SIZE: usize = 4096;
+
+#[wasm_bindgen]
+extern "C" {
+	/// JavaScript function passed as a callback
+	#[wasm_bindgen(js_name = Function)]
+	pub type JsFunction;
+
+	#[wasm_bindgen(method, js_name = call)]
+	fn call_with_bytes(this: &JsFunction, context: &JsValue, data: &[u8]);
+
+	#[wasm_bindgen(js_namespace = performance, js_name = now)]
+	fn performance_now() -> f64;
+
+	#[wasm_bindgen(js_namespace = Date, js_name = now)]
+	fn date_now() -> f64;
+}
+
+// std::time isn't available in wasm32-unknown-unknown
+struct BrowserClock;
+
+impl Clock for BrowserClock {
+	fn now(&self) -> Duration {
+		Duration::from_secs_f64(performance_now().max(0.0) / 1000.0)
+	}
+
+	fn unix_time(&self) -> Duration {
+		Duration::from_secs_f64(date_now().max(0.0) / 1000.0)
+	}
+}
+
+// Converts a8r8g8b8 pixels, bytes in blue, green, red, alpha order,
+// to the RGBA order of ImageData
+fn convert_to_rgba(pixels: &[u8], buffer: &mut [u8]) {
+	for (src, dst) in pixels.chunks_exact(4).zip(buffer.chunks_exact_mut(4)) {
+		dst[0] = src[2];
+		dst[1] = src[1];
+		dst[2] = src[0];
+		dst[3] = 0xff;
+	}
+}
 
 /// `WasmRiscv` is an interface between user JavaScript code and
 /// WebAssembly RISC-V emulator. The following code is example
@@ -50,34 +98,66 @@ use riscv_emu_rust::default_terminal::DefaultTerminal;
 /// ```
 #[wasm_bindgen]
 pub struct WasmRiscv {
-	emulator: Emulator
+	emulator: Emulator,
+	output_callback: Option<JsFunction>
+}
+
+impl Default for WasmRiscv {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
 #[wasm_bindgen]
 impl WasmRiscv {
 	/// Creates a new `WasmRiscv`.
 	pub fn new() -> Self {
+		Self::new_with_config(EmulatorConfig::default())
+	}
+
+	/// Creates a new `WasmRiscv` with a linear framebuffer the guest can
+	/// draw on, e.g. Linux with `simplefb`. See `set_framebuffer_callback()`
+	/// and `get_framebuffer_rgba()`.
+	///
+	/// # Arguments
+	/// * `width` in pixels
+	/// * `height` in pixels
+	pub fn new_with_framebuffer(width: u32, height: u32) -> Self {
+		Self::new_with_config(EmulatorConfig {
+			framebuffer: Some(FramebufferConfig {
+				width,
+				height
+			}),
+			..EmulatorConfig::default()
+		})
+	}
+
+	fn new_with_config(config: EmulatorConfig) -> Self {
+		let mut emulator = Emulator::new_with_config(Box::new(DefaultTerminal::new()), config);
+		emulator.set_clock(Rc::new(BrowserClock));
 		WasmRiscv {
-			emulator: Emulator::new(Box::new(DefaultTerminal::new()))
+			emulator,
+			output_callback: None
 		}
 	}
 
 	/// Sets up program run by the program. This method is expected to be called
-	/// only once.
+	/// only once. Throws an error if the content isn't ELF file or is broken.
 	///
 	/// # Arguments
 	/// * `content` Program binary
-	pub fn setup_program(&mut self, content: Vec<u8>) {
-		self.emulator.setup_program(content);
+	pub fn setup_program(&mut self, content: Vec<u8>) -> Result<(), JsValue> {
+		self.emulator.setup_program(content).map_err(|error| JsValue::from_str(&error.to_string()))
 	}
 
 	/// Loads symbols of program and adds them to symbol - virtual address
-	/// mapping in `Emulator`.
+	/// mapping in `Emulator`. Throws an error if the content isn't ELF file
+	/// or is broken.
 	///
 	/// # Arguments
 	/// * `content` Program binary
-	pub fn load_program_for_symbols(&mut self, content: Vec<u8>) {
-		self.emulator.load_program_for_symbols(content);
+	pub fn load_program_for_symbols(&mut self, content: Vec<u8>) -> Result<(), JsValue> {
+		self.emulator.load_program_for_symbols(content).map_err(|error| JsValue::from_str(&error.to_string()))
 	}
 
 	/// Sets up filesystem. Use this method if program (e.g. Linux) uses
@@ -86,24 +166,26 @@ impl WasmRiscv {
 	/// # Arguments
 	/// * `content` File system content binary
 	pub fn setup_filesystem(&mut self, content: Vec<u8>) {
-		self.emulator.setup_filesystem(content);
+		self.emulator.setup_filesystem(Box::new(MemoryBlockBackend::new(content)));
 	}
 
 	/// Sets up device tree. The emulator has default device tree configuration.
 	/// If you want to override it, use this method. This method is expected to
-	/// to be called up to only once.
+	/// to be called up to only once. Throws an error if the device tree
+	/// overlays can't be applied to it.
 	///
 	/// # Arguments
 	/// * `content` DTB content binary
-	pub fn setup_dtb(&mut self, content: Vec<u8>) {
-		self.emulator.setup_dtb(content);
+	pub fn setup_dtb(&mut self, content: Vec<u8>) -> Result<(), JsValue> {
+		self.emulator.setup_dtb(content).map_err(|error| JsValue::from_str(&error.to_string()))
 	}
 
 	/// Runs program set by `setup_program()`. The emulator won't stop forever
 	/// unless [`riscv-tests`](https://github.com/riscv/riscv-tests) programs.
 	/// The emulator stops if program is `riscv-tests` program and it finishes.
 	pub fn run(&mut self) {
-		self.emulator.run();
+		// Keeps running after internal errors the emulator recovers from
+		while self.emulator.run().is_err() {}
 	}
 
 	/// Runs program set by `setup_program()` in `cycles` cycles.
@@ -114,6 +196,140 @@ impl WasmRiscv {
 		for _i in 0..cycles {
 			self.emulator.tick();
 		}
+		self.flush_output();
+	}
+
+	/// Runs a batch of up to `cycles` cycles, stopping early if the program
+	/// has exited, e.g. a `riscv-tests` program or a user mode program,
+	/// and passes the console output to the callback set by
+	/// `set_output_callback()`. Returns the number of cycles run. Call this
+	/// from `requestAnimationFrame()` or `setTimeout()` so the page keeps
+	/// responding.
+	///
+	/// ```ignore
+	/// // JavaScript code
+	/// const runBatch = () => {
+	///   riscv.tick_batch(0x100000);
+	///   if (!riscv.has_exited()) {
+	///     setTimeout(runBatch, 0);
+	///   }
+	/// };
+	/// ```
+	///
+	/// # Arguments
+	/// * `cycles`
+	pub fn tick_batch(&mut self, cycles: u32) -> u32 {
+		let mut count = 0;
+		while count < cycles && self.emulator.get_exit_code().is_none() {
+			self.emulator.tick();
+			count += 1;
+		}
+		self.flush_output();
+		count
+	}
+
+	/// Indicates whether the program has exited. Only `riscv-tests` programs
+	/// and user mode programs exit.
+	pub fn has_exited(&self) -> bool {
+		self.emulator.get_exit_code().is_some()
+	}
+
+	/// Returns the status code the program has exited with, zero while
+	/// running. See `has_exited()`.
+	pub fn get_exit_code(&self) -> u64 {
+		self.emulator.get_exit_code().unwrap_or(0)
+	}
+
+	/// Sets a function called with a `Uint8Array` of console output bytes
+	/// after `run_cycles()` and `tick_batch()`, instead of polling
+	/// `get_output()`. The array is a view of the emulator memory valid
+	/// only during the call, so copy it to keep it, e.g. with
+	/// `TextDecoder.decode()`.
+	///
+	/// ```ignore
+	/// // JavaScript code
+	/// const decoder = new TextDecoder();
+	/// riscv.set_output_callback(bytes => {
+	///   terminal.write(decoder.decode(bytes, {stream: true}));
+	/// });
+	/// ```
+	///
+	/// # Arguments
+	/// * `callback`
+	pub fn set_output_callback(&mut self, callback: JsFunction) {
+		self.output_callback = Some(callback);
+	}
+
+	fn flush_output(&mut self) {
+		let callback = match &self.output_callback {
+			Some(callback) => callback,
+			None => return
+		};
+		let mut buffer = [0; OUTPUT_CHUNK_SIZE];
+		loop {
+			let length = self.emulator.get_mut_terminal().get_output_bytes(&mut buffer);
+			if length == 0 {
+				break;
+			}
+			callback.call_with_bytes(&JsValue::NULL, &buffer[..length]);
+		}
+	}
+
+	/// Returns the framebuffer width in pixels, zero without framebuffer.
+	/// See `new_with_framebuffer()`.
+	pub fn get_framebuffer_width(&self) -> u32 {
+		match self.emulator.get_framebuffer() {
+			Some(_) => self.emulator.get_cpu().get_mmu().get_framebuffer().get_width(),
+			None => 0
+		}
+	}
+
+	/// Returns the framebuffer height in pixels, zero without framebuffer.
+	pub fn get_framebuffer_height(&self) -> u32 {
+		match self.emulator.get_framebuffer() {
+			Some(_) => self.emulator.get_cpu().get_mmu().get_framebuffer().get_height(),
+			None => 0
+		}
+	}
+
+	/// Copies the framebuffer pixels to `buffer` in RGBA order, ready to be
+	/// put to `ImageData`. The buffer must hold width * height * 4 bytes.
+	/// Returns `false` without framebuffer or if the buffer is too small.
+	///
+	/// # Arguments
+	/// * `buffer`
+	pub fn get_framebuffer_rgba(&self, buffer: &mut [u8]) -> bool {
+		match self.emulator.get_framebuffer() {
+			Some(pixels) if buffer.len() >= pixels.len() => {
+				convert_to_rgba(pixels, buffer);
+				true
+			},
+			_ => false
+		}
+	}
+
+	/// Sets a function called with a `Uint8Array` of the framebuffer pixels
+	/// in RGBA order when the guest has updated the framebuffer, at most
+	/// once in 0x100000 cycles. The array is valid only during the call.
+	///
+	/// ```ignore
+	/// // JavaScript code
+	/// const image = context.createImageData(width, height);
+	/// riscv.set_framebuffer_callback(pixels => {
+	///   image.data.set(pixels);
+	///   context.putImageData(image, 0, 0);
+	/// });
+	/// ```
+	///
+	/// # Arguments
+	/// * `callback`
+	pub fn set_framebuffer_callback(&mut self, callback: JsFunction) {
+		let mut buffer = vec![];
+		self.emulator.set_framebuffer_update_callback(Box::new(move |pixels| {
+			buffer.resize(pixels.len(), 0);
+			convert_to_rgba(pixels, &mut buffer);
+			callback.call_with_bytes(&JsValue::NULL, &buffer);
+		}));
 	}
 
 	/// Runs program until breakpoints. Also known as debugger's continue command.
@@ -142,8 +358,8 @@ impl WasmRiscv {
 	/// * `max_cycles` See the above description
 	pub fn run_until_breakpoints(&mut self, breakpoints: Vec<u64>, max_cycles: u32) -> bool {
 		let mut table = HashMap::new();
-		for i in 0..breakpoints.len() {
-			table.insert(breakpoints[i], true);
+		for breakpoint in breakpoints.iter() {
+			table.insert(*breakpoint, true);
 		}
 		for _i in 0..max_cycles {
 			self.emulator.tick();
@@ -152,7 +368,7 @@ impl WasmRiscv {
 				return true;
 			}
 		}
-		return false;
+		false
 	}
 
 	/// Disassembles an instruction Program Counter points to.
@@ -160,9 +376,7 @@ impl WasmRiscv {
 	pub fn disassemble_next_instruction(&mut self) {
 		let s = self.emulator.get_mut_cpu().disassemble_next_instruction();
 		let bytes = s.as_bytes();
-		for i in 0..bytes.len() {
-			self.emulator.get_mut_terminal().put_byte(bytes[i]);
-		}
+		self.emulator.get_mut_terminal().put_bytes(bytes);
 	}
 
 	/// Loads eight-byte data from memory. Loading can cause an error or trap.
@@ -188,7 +402,7 @@ impl WasmRiscv {
 						return 0;
 					}
 				},
-				Err(()) => {
+				Err(_) => {
 					error[0] = 1;
 					return 0;
 				}
@@ -239,6 +453,16 @@ impl WasmRiscv {
 		self.emulator.get_mut_terminal().get_output()
 	}
 
+	/// Moves as many buffered output bytes as `buffer` can hold and returns
+	/// the number of the bytes. Zero means the output buffer is empty.
+	/// Faster than calling `get_output()` byte by byte.
+	///
+	/// # Arguments
+	/// * `buffer`
+	pub fn get_output_bytes(&mut self, buffer: &mut [u8]) -> usize {
+		self.emulator.get_mut_terminal().get_output_bytes(buffer)
+	}
+
 	/// Puts ascii code byte sent from terminal to the emulator.
 	///
 	/// # Arguments
@@ -247,6 +471,14 @@ impl WasmRiscv {
 		self.emulator.get_mut_terminal().put_input(data);
 	}
 
+	/// Puts ascii code bytes sent from terminal to the emulator at once.
+	///
+	/// # Arguments
+	/// * `data` Ascii code bytes
+	pub fn put_input_bytes(&mut self, data: &[u8]) {
+		self.emulator.get_mut_terminal().put_input_bytes(data);
+	}
+
 	/// Enables or disables page cache optimization.
 	/// Page cache optimization is an experimental feature.
 	/// Refer to [`Mmu`](../riscv_emu_rust/mmu/struct.Mmu.html) for the detail.